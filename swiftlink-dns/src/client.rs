@@ -4,16 +4,19 @@ use std::{
     ops::Deref,
     path::PathBuf,
     slice::Iter,
-    sync::Arc,
-    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
-use swiftlink_infra::{log::*, net::ConnectOpts};
+use swiftlink_infra::{clock, log::*, net::ConnectOpts};
 use tokio::sync::RwLock;
 
 use crate::{
     config::NameServerInfo,
     dns_url::{DnsUrl, DnsUrlParamExt},
+    doh::{DohClient, DohMethod},
     error::LookupError,
     libdns::{
         self,
@@ -21,7 +24,7 @@ use crate::{
             op::{Edns, Message, MessageType, OpCode, Query},
             rr::rdata::opt::{ClientSubnet, EdnsOption},
             rr::{Record, RecordType},
-            xfer::{DnsHandle, DnsRequest, DnsRequestOptions, FirstAnswer},
+            xfer::{DnsHandle, DnsRequest, DnsRequestOptions, DnsResponse, FirstAnswer},
         },
         resolver::{
             config::{NameServerConfig, Protocol, ResolverOpts, TlsClientConfig},
@@ -30,10 +33,10 @@ use crate::{
             IntoName, Name,
         },
     },
-    proxy::ProxyConfig,
+    proxy::{OutboundDialer, ProxyConfig, UpstreamProxy},
     resolver::{GenericResolver, GenericResolverExt, LookupOptions},
     rustls::TlsClientConfigBundle,
-    MAX_TTL,
+    MAX_PAYLOAD_LEN, MAX_TTL,
 };
 
 use bootstrap::BootstrapResolver;
@@ -47,6 +50,8 @@ pub struct DnsClientBuilder {
     ca_file: Option<PathBuf>,
     ca_path: Option<PathBuf>,
     proxies: Arc<HashMap<String, ProxyConfig>>,
+    dialer: Option<Arc<dyn OutboundDialer>>,
+    route_upstream: bool,
     client_subnet: Option<ClientSubnet>,
 }
 
@@ -76,6 +81,19 @@ impl DnsClientBuilder {
         self
     }
 
+    /// Dials the nameservers whose `-proxy` names an outbound of the app, not in the proxies.
+    pub fn with_dialer(mut self, dialer: Arc<dyn OutboundDialer>) -> Self {
+        self.dialer = Some(dialer);
+        self
+    }
+
+    /// Dials the nameservers without a `-proxy` through the routing rules of the dialer too,
+    /// rather than directly. Bootstrap queries are always sent directly.
+    pub fn with_route_upstream(mut self, enable: bool) -> Self {
+        self.route_upstream = enable;
+        self
+    }
+
     pub fn with_client_subnet<S: Into<ClientSubnet>>(mut self, subnet: S) -> Self {
         self.client_subnet = Some(subnet.into());
         self
@@ -94,6 +112,8 @@ impl DnsClientBuilder {
             ca_file,
             ca_path,
             proxies,
+            dialer,
+            route_upstream,
             client_subnet,
         } = self;
 
@@ -143,6 +163,8 @@ impl DnsClientBuilder {
                         .create_name_server_group(
                             &bootstrap_infos,
                             &Default::default(),
+                            None,
+                            false,
                             client_subnet,
                             connect_opts.clone(),
                         )
@@ -167,7 +189,14 @@ impl DnsClientBuilder {
                 debug!("initialize nameserver group {:?}", server_infos);
                 Arc::new(
                     factory
-                        .create_name_server_group(&server_infos, &proxies, client_subnet, connect_opts)
+                        .create_name_server_group(
+                            &server_infos,
+                            &proxies,
+                            dialer.as_ref(),
+                            route_upstream,
+                            client_subnet,
+                            connect_opts,
+                        )
                         .await,
                 )
             }
@@ -194,6 +223,16 @@ impl DnsClient {
     pub async fn lookup_nameserver(&self, name: Name, record_type: RecordType) -> Option<Lookup> {
         bootstrap::resolver().await.local_lookup(name, record_type).await
     }
+
+    /// Looks up `name` on the upstream of `pin`, see [`NameServerGroup::lookup_pinned`].
+    pub async fn lookup_pinned(
+        &self,
+        name: Name,
+        options: LookupOptions,
+        pin: Option<&Arc<NameServer>>,
+    ) -> (Result<Lookup, LookupError>, Option<Arc<NameServer>>) {
+        self.server_group.lookup_pinned(name, options, pin).await
+    }
 }
 
 #[async_trait::async_trait]
@@ -233,42 +272,85 @@ impl NameServerGroup {
     pub fn is_empty(&self) -> bool {
         self.servers.is_empty()
     }
-}
 
-#[async_trait::async_trait]
-impl GenericResolver for NameServerGroup {
-    fn options(&self) -> &ResolverOpts {
-        &self.resolver_opts
+    /// Looks up `name` on the upstream of `pin` only, if it's one of the group, so the lookups of
+    /// a client query, e.g. of a CNAME chain, all see the same upstream. Otherwise, or if the
+    /// pinned upstream fails, the group is raced as usual.
+    ///
+    /// Returns the upstream the result came from with it, the one to pin the following lookups
+    /// to if it answered, the one to blame if the lookup failed.
+    pub async fn lookup_pinned(
+        &self,
+        name: Name,
+        options: LookupOptions,
+        pin: Option<&Arc<NameServer>>,
+    ) -> (Result<Lookup, LookupError>, Option<Arc<NameServer>>) {
+        let pinned = pin.and_then(|pinned| self.candidates(options.record_type).find(|ns| Arc::ptr_eq(ns, pinned)));
+        if let Some(ns) = pinned {
+            match GenericResolver::lookup(ns.as_ref(), name.clone(), options.clone()).await {
+                Ok(lookup) => return (Ok(lookup), Some(ns.clone())),
+                Err(err) => debug!("pinned upstream failed to look up {}, {}", name, err),
+            }
+        }
+
+        self.race(name, options).await
     }
 
-    async fn lookup<N: IntoName + Send, O: Into<LookupOptions> + Send + Clone>(
-        &self,
-        name: N,
-        options: O,
-    ) -> Result<Lookup, LookupError> {
+    /// The servers queried for `record_type`, HTTPS and SVCB only go to the flagged servers, if
+    /// any.
+    fn candidates(&self, record_type: RecordType) -> impl Iterator<Item = &Arc<NameServer>> {
+        let svcb_only =
+            matches!(record_type, RecordType::HTTPS | RecordType::SVCB) && self.servers.iter().any(|ns| ns.opts.svcb);
+        self.servers.iter().filter(move |ns| !svcb_only || ns.opts.svcb)
+    }
+
+    /// Queries all candidates at once, returns the first answer with records, or the last
+    /// response if none has any, and the server it came from.
+    async fn race(&self, name: Name, options: LookupOptions) -> (Result<Lookup, LookupError>, Option<Arc<NameServer>>) {
         use futures_util::future::select_all;
-        let name = name.into_name()?;
+
         let mut tasks = self
-            .servers
-            .iter()
-            .map(|ns| GenericResolver::lookup(ns.as_ref(), name.clone(), options.clone()))
+            .candidates(options.record_type)
+            .map(|ns| {
+                let (ns, name, options) = (ns.clone(), name.clone(), options.clone());
+                Box::pin(async move {
+                    let res = GenericResolver::lookup(ns.as_ref(), name, options).await;
+                    (res, ns)
+                })
+            })
             .collect::<Vec<_>>();
 
         loop {
-            let (res, _idx, rest) = select_all(tasks).await;
+            let ((res, ns), _idx, rest) = select_all(tasks).await;
 
             if matches!(res.as_ref(), Ok(lookup) if !lookup.records().is_empty()) {
-                return res;
+                return (res, Some(ns));
             }
 
             if rest.is_empty() {
-                return res;
+                return (res, Some(ns));
             }
             tasks = rest;
         }
     }
 }
 
+#[async_trait::async_trait]
+impl GenericResolver for NameServerGroup {
+    fn options(&self) -> &ResolverOpts {
+        &self.resolver_opts
+    }
+
+    async fn lookup<N: IntoName + Send, O: Into<LookupOptions> + Send + Clone>(
+        &self,
+        name: N,
+        options: O,
+    ) -> Result<Lookup, LookupError> {
+        let name = name.into_name()?;
+        self.race(name, options.into()).await.0
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct NameServerFactory {
     tls_client_config: TlsClientConfigBundle,
@@ -286,30 +368,51 @@ impl NameServerFactory {
     pub async fn create(
         &self,
         url: &VerifiedDnsUrl,
-        proxy: Option<ProxyConfig>,
+        proxy: Option<UpstreamProxy>,
         resolver_opts: NameServerOpts,
         connect_opts: ConnectOpts,
     ) -> Arc<NameServer> {
-        use crate::libdns::resolver::name_server::NameServer as N;
-
-        let key = format!("{}{:?}", url.to_string(), proxy.as_ref().map(|s| s.to_string()),);
+        let key = format!(
+            "{}{:?}{}{:?}",
+            url.to_string(),
+            proxy.as_ref().map(|s| s.to_string()),
+            resolver_opts.svcb,
+            resolver_opts.http_headers
+        );
 
         if let Some(ns) = self.cache.read().await.get(&key) {
             return ns.clone();
         }
 
-        let config = Self::create_config_from_url(url, self.tls_client_config.clone());
-
-        let inner = N::<GenericConnector<TokioCustomeRuntimeProvider>>::new(
-            config,
-            resolver_opts.deref().to_owned(),
-            GenericConnector::new(TokioCustomeRuntimeProvider::new(proxy, connect_opts)),
-        );
+        let mut config = Self::create_config_from_url(url, self.tls_client_config.clone());
+
+        if proxy.as_ref().is_some_and(UpstreamProxy::is_outbound) {
+            match config.protocol {
+                // outbounds carry streams only, the queries go over TCP instead
+                Protocol::Udp => config.protocol = Protocol::Tcp,
+                Protocol::Quic => warn!(
+                    "{} can't be dialed through an outbound, querying it directly",
+                    url.to_string()
+                ),
+                _ => (),
+            }
+        }
 
-        let ns = Arc::new(NameServer {
-            opts: resolver_opts,
-            inner,
+        // hickory only POSTs to the default path without extra headers
+        let doh = (*url.proto() == Protocol::Https
+            && (url.path() != "/dns-query"
+                || url.doh_method() != DohMethod::Post
+                || !resolver_opts.http_headers.is_empty()))
+        .then(|| {
+            let tls_config = config.tls_config.as_ref().expect("https has a tls config").0.clone();
+            DohClient::new(url, tls_config, proxy.clone(), connect_opts.clone())
+                .with_headers(&resolver_opts.http_headers)
         });
+
+        let mut ns = NameServer::new(config, resolver_opts, proxy, connect_opts);
+        ns.upstream = url.to_string();
+        ns.doh = doh.map(Arc::new);
+        let ns = Arc::new(ns);
         self.cache.write().await.insert(key, ns.clone());
         ns
     }
@@ -384,6 +487,8 @@ impl NameServerFactory {
         &self,
         infos: &[NameServerInfo],
         proxies: &HashMap<String, ProxyConfig>,
+        dialer: Option<&Arc<dyn OutboundDialer>>,
+        route_upstream: bool,
         default_client_subnet: Option<ClientSubnet>,
         connect_opts: ConnectOpts,
     ) -> NameServerGroup {
@@ -421,14 +526,27 @@ impl NameServerFactory {
             let nameserver_opts = NameServerOpts::new(
                 info.edns_client_subnet.map(|x| x.into()).or(default_client_subnet),
                 resolver.options().clone(),
-            );
-
-            let proxy = info
-                .proxy
-                .as_deref()
-                .map(|n| proxies.get(n))
-                .unwrap_or_default()
-                .cloned();
+            )
+            .with_svcb(info.svcb)
+            .with_http_headers(info.http_headers.clone());
+
+            let proxy = match info.proxy.as_deref() {
+                Some(name) => {
+                    let proxy = UpstreamProxy::resolve(name, proxies, dialer);
+                    if proxy.is_none() {
+                        warn!(
+                            "proxy {} of {} is neither a proxy server nor an outbound",
+                            name,
+                            info.url.to_string()
+                        );
+                    }
+                    proxy
+                }
+                None if route_upstream => {
+                    dialer.map(|dialer| UpstreamProxy::Routed(info.url.domain().map(str::to_owned), dialer.clone()))
+                }
+                None => None,
+            };
 
             for url in verified_urls {
                 servers.push(
@@ -449,6 +567,10 @@ impl NameServerFactory {
 pub struct NameServerOpts {
     client_subnet: Option<ClientSubnet>,
     resolver_opts: ResolverOpts,
+    /// answers HTTPS and SVCB queries, see [`NameServerInfo::svcb`]
+    svcb: bool,
+    /// extra headers of DoH requests, see [`NameServerInfo::http_headers`]
+    http_headers: Vec<(String, String)>,
 }
 
 impl NameServerOpts {
@@ -457,6 +579,8 @@ impl NameServerOpts {
         Self {
             client_subnet,
             resolver_opts,
+            svcb: false,
+            http_headers: vec![],
         }
     }
 
@@ -464,6 +588,16 @@ impl NameServerOpts {
         self.resolver_opts = resolver_opts;
         self
     }
+
+    pub fn with_svcb(mut self, svcb: bool) -> Self {
+        self.svcb = svcb;
+        self
+    }
+
+    pub fn with_http_headers(mut self, http_headers: Vec<(String, String)>) -> Self {
+        self.http_headers = http_headers;
+        self
+    }
 }
 
 impl Deref for NameServerOpts {
@@ -474,34 +608,91 @@ impl Deref for NameServerOpts {
     }
 }
 
+type InnerNameServer = libdns::resolver::name_server::NameServer<GenericConnector<TokioCustomeRuntimeProvider>>;
+
 #[derive(Debug, Clone)]
 pub struct NameServer {
+    /// the configured url, e.g. `https://cloudflare-dns.com`
+    upstream: String,
     opts: NameServerOpts,
-    inner: libdns::resolver::name_server::NameServer<GenericConnector<TokioCustomeRuntimeProvider>>,
+    inner: InnerNameServer,
+    /// TCP connection to the same upstream, retrying queries whose UDP answer was truncated
+    tcp_fallback: Option<InnerNameServer>,
+    /// DoH client replacing `inner`, for upstreams with a custom path, method or headers
+    doh: Option<Arc<DohClient>>,
+    stats: Arc<NameServerStats>,
 }
 
 impl NameServer {
     pub fn new(
         config: NameServerConfig,
         opts: NameServerOpts,
-        proxy: Option<ProxyConfig>,
+        proxy: Option<UpstreamProxy>,
         connect_opts: ConnectOpts,
     ) -> NameServer {
         use crate::libdns::resolver::name_server::NameServer as N;
 
-        let inner = N::<GenericConnector<TokioCustomeRuntimeProvider>>::new(
-            config,
-            opts.resolver_opts.clone(),
-            GenericConnector::new(TokioCustomeRuntimeProvider::new(proxy, connect_opts)),
-        );
+        let connector = GenericConnector::new(TokioCustomeRuntimeProvider::new(proxy, connect_opts));
+
+        let tcp_fallback = (config.protocol == Protocol::Udp).then(|| {
+            let mut config = config.clone();
+            config.protocol = Protocol::Tcp;
+            N::new(config, opts.resolver_opts.clone(), connector.clone())
+        });
 
-        Self { opts, inner }
+        let upstream = format!("{}://{}", config.protocol, config.socket_addr);
+        let inner = N::new(config, opts.resolver_opts.clone(), connector);
+
+        Self {
+            upstream,
+            opts,
+            inner,
+            tcp_fallback,
+            doh: None,
+            stats: Default::default(),
+        }
     }
 
     #[inline]
     pub fn options(&self) -> &NameServerOpts {
         &self.opts
     }
+
+    /// The upstream queried, as configured.
+    #[inline]
+    pub fn upstream(&self) -> &str {
+        &self.upstream
+    }
+
+    #[inline]
+    pub fn stats(&self) -> &NameServerStats {
+        &self.stats
+    }
+}
+
+/// Counters of UDP answers truncated by an upstream.
+#[derive(Debug, Default)]
+pub struct NameServerStats {
+    udp_queries: AtomicU64,
+    truncated: AtomicU64,
+}
+
+impl NameServerStats {
+    pub fn udp_queries(&self) -> u64 {
+        self.udp_queries.load(Ordering::Relaxed)
+    }
+
+    pub fn truncated(&self) -> u64 {
+        self.truncated.load(Ordering::Relaxed)
+    }
+
+    /// Ratio of truncated UDP answers, between 0 and 1.
+    pub fn truncation_rate(&self) -> f64 {
+        match self.udp_queries() {
+            0 => 0.0,
+            queries => self.truncated() as f64 / queries as f64,
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -518,6 +709,11 @@ impl GenericResolver for NameServer {
         let name = name.into_name()?;
         let options: LookupOptions = options.into();
 
+        #[cfg(feature = "chaos")]
+        swiftlink_infra::chaos::inject_lookup(&name.to_string())
+            .await
+            .map_err(|err| LookupError::Io(Arc::new(err)))?;
+
         let request_options = {
             let opts = &self.options();
             let mut request_opts = DnsRequestOptions::default();
@@ -530,14 +726,39 @@ impl GenericResolver for NameServer {
 
         let client_subnet = options.client_subnet.or(self.opts.client_subnet);
 
-        let req = DnsRequest::new(build_message(query, request_options, client_subnet), request_options);
+        let message = build_message(query, request_options, client_subnet);
 
-        let ns = self.inner.clone();
+        let mut res = match self.doh.as_ref() {
+            Some(doh) => DnsResponse::from_message(doh.send(&message).await?)?,
+            None => {
+                self.inner
+                    .clone()
+                    .send(DnsRequest::new(message.clone(), request_options))
+                    .first_answer()
+                    .await?
+            }
+        };
 
-        let res = ns.send(req).first_answer().await?;
+        if let Some(tcp) = self.tcp_fallback.as_ref() {
+            self.stats.udp_queries.fetch_add(1, Ordering::Relaxed);
 
-        let valid_until =
-            Instant::now() + Duration::from_secs(res.answers().iter().map(|r| r.ttl()).min().unwrap_or(MAX_TTL) as u64);
+            if res.truncated() {
+                self.stats.truncated.fetch_add(1, Ordering::Relaxed);
+                debug!("truncated answer of {:?}, retrying over TCP", message.query());
+
+                // the truncated answer is still better than nothing
+                if let Ok(tcp_res) = tcp
+                    .clone()
+                    .send(DnsRequest::new(message, request_options))
+                    .first_answer()
+                    .await
+                {
+                    res = tcp_res;
+                }
+            }
+        }
+
+        let valid_until = clock::deadline(res.answers().iter().map(|r| r.ttl()).min().unwrap_or(MAX_TTL));
 
         Ok(Lookup::new_with_deadline(
             res.query().unwrap().clone(),
@@ -579,10 +800,6 @@ impl std::convert::TryFrom<DnsUrl> for VerifiedDnsUrl {
     }
 }
 
-/// > An EDNS buffer size of 1232 bytes will avoid fragmentation on nearly all current networks.
-/// https://dnsflagday.net/2020/
-const MAX_PAYLOAD_LEN: u16 = 1232;
-
 fn build_message(query: Query, request_options: DnsRequestOptions, client_subnet: Option<ClientSubnet>) -> Message {
     // build the message
     let mut message: Message = Message::new();
@@ -612,30 +829,38 @@ fn build_message(query: Query, request_options: DnsRequestOptions, client_subnet
 }
 
 mod connection_provider {
-    use std::{future::Future, io::Result, net::SocketAddr, pin::Pin};
-
-    use tokio::net::UdpSocket as TokioUdpSocket;
+    use std::{
+        future::Future,
+        io::Result,
+        net::SocketAddr,
+        pin::Pin,
+        task::{Context, Poll},
+    };
 
-    use swiftlink_infra::net::{udp, ConnectOpts};
+    use swiftlink_infra::net::ConnectOpts;
 
     use crate::{
         libdns::{
-            proto::{iocompat::AsyncIoTokioAsStd, TokioTime},
+            proto::{
+                iocompat::AsyncIoTokioAsStd,
+                udp::{DnsUdpSocket, QuicLocalAddr},
+                TokioTime,
+            },
             resolver::{name_server::RuntimeProvider, TokioHandle},
         },
-        proxy::{self, ProxyConfig, TcpStream},
+        proxy::{self, DnsStream, UdpSocket, UpstreamProxy},
     };
 
     /// The swiftlink dns Tokio Runtime for async execution
     #[derive(Clone)]
     pub struct TokioCustomeRuntimeProvider {
-        proxy: Option<ProxyConfig>,
+        proxy: Option<UpstreamProxy>,
         connect_opts: ConnectOpts,
         handle: TokioHandle,
     }
 
     impl TokioCustomeRuntimeProvider {
-        pub fn new(proxy: Option<ProxyConfig>, connect_opts: ConnectOpts) -> Self {
+        pub fn new(proxy: Option<UpstreamProxy>, connect_opts: ConnectOpts) -> Self {
             Self {
                 proxy,
                 connect_opts,
@@ -647,8 +872,8 @@ mod connection_provider {
     impl RuntimeProvider for TokioCustomeRuntimeProvider {
         type Handle = TokioHandle;
         type Timer = TokioTime;
-        type Udp = TokioUdpSocket;
-        type Tcp = AsyncIoTokioAsStd<TcpStream>;
+        type Udp = UdpSocket;
+        type Tcp = AsyncIoTokioAsStd<Box<dyn DnsStream>>;
 
         fn create_handle(&self) -> Self::Handle {
             self.handle.clone()
@@ -670,9 +895,28 @@ mod connection_provider {
             local_addr: SocketAddr,
             _server_addr: SocketAddr,
         ) -> Pin<Box<dyn Send + Future<Output = Result<Self::Udp>>>> {
+            let proxy_config = self.proxy.clone();
             let connect_opts = self.connect_opts.clone();
 
-            Box::pin(async move { udp::bind_udp_socket_with_opts(local_addr, &connect_opts).await })
+            Box::pin(async move { proxy::bind_udp(local_addr, proxy_config.as_ref(), &connect_opts).await })
+        }
+    }
+
+    impl DnsUdpSocket for UdpSocket {
+        type Time = TokioTime;
+
+        fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<(usize, SocketAddr)>> {
+            UdpSocket::poll_recv_from(self, cx, buf)
+        }
+
+        fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<Result<usize>> {
+            UdpSocket::poll_send_to(self, cx, buf, target)
+        }
+    }
+
+    impl QuicLocalAddr for UdpSocket {
+        fn local_addr(&self) -> Result<SocketAddr> {
+            UdpSocket::local_addr(self)
         }
     }
 }
@@ -898,6 +1142,175 @@ mod tests {
         assert!(addrs.contains("223.5.5.5") || addrs.contains("223.6.6.6"));
     }
 
+    #[tokio::test]
+    async fn test_nameserver_truncated_retry_tcp() {
+        use crate::test_util::MockDnsServer;
+
+        let upstream = MockDnsServer::start().await.unwrap();
+        upstream.answer("large.example.com", "1.2.3.4".parse().unwrap(), 60);
+        upstream.truncate(true);
+
+        let config = NameServerFactory::create_config_from_url(
+            &upstream.dns_url().try_into().unwrap(),
+            TlsClientConfigBundle::new(None, None),
+        );
+        let ns = NameServer::new(config, Default::default(), None, Default::default());
+
+        let lookup = ns.lookup("large.example.com.", RecordType::A).await.unwrap();
+        assert_eq!(
+            lookup.iter().filter_map(|r| r.ip_addr()).collect::<Vec<_>>(),
+            vec!["1.2.3.4".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(upstream.tcp_queries(), 1);
+        assert_eq!(ns.stats().truncated(), 1);
+
+        upstream.truncate(false);
+        ns.lookup("large.example.com.", RecordType::A).await.unwrap();
+        assert_eq!(upstream.tcp_queries(), 1);
+        assert_eq!(ns.stats().udp_queries(), 2);
+        assert_eq!(ns.stats().truncation_rate(), 0.5);
+    }
+
+    /// Dials directly, counting the dials through `PROXY` and the routed ones.
+    #[derive(Default)]
+    struct CountingDialer {
+        dials: AtomicU64,
+        routed: AtomicU64,
+    }
+
+    #[async_trait::async_trait]
+    impl OutboundDialer for CountingDialer {
+        fn contains(&self, outbound: &str) -> bool {
+            outbound == "PROXY"
+        }
+
+        async fn dial(&self, outbound: &str, target: SocketAddr) -> std::io::Result<Box<dyn crate::DnsStream>> {
+            assert_eq!(outbound, "PROXY");
+            self.dials.fetch_add(1, Ordering::Relaxed);
+            Ok(Box::new(tokio::net::TcpStream::connect(target).await?))
+        }
+
+        async fn dial_routed(
+            &self,
+            _host: Option<&str>,
+            target: SocketAddr,
+        ) -> std::io::Result<Box<dyn crate::DnsStream>> {
+            self.routed.fetch_add(1, Ordering::Relaxed);
+            Ok(Box::new(tokio::net::TcpStream::connect(target).await?))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nameserver_outbound_dialer() {
+        use crate::test_util::MockDnsServer;
+
+        let upstream = MockDnsServer::start().await.unwrap();
+        upstream.answer("www.example.com", "1.2.3.4".parse().unwrap(), 60);
+        let dialer = Arc::new(CountingDialer::default());
+
+        let client = DnsClient::builder()
+            .add_server(NameServerInfo::from(upstream.dns_url()).with_proxy("PROXY"))
+            .with_dialer(dialer.clone())
+            .build()
+            .await;
+
+        client.lookup("www.example.com.", RecordType::A).await.unwrap();
+        // plain UDP nameservers are queried over TCP through outbounds
+        assert_eq!(upstream.tcp_queries(), 1);
+        assert_eq!(dialer.dials.load(Ordering::Relaxed), 1);
+        assert_eq!(dialer.routed.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_nameserver_route_upstream() {
+        use crate::test_util::MockDnsServer;
+
+        let upstream = MockDnsServer::start().await.unwrap();
+        upstream.answer("www.example.com", "1.2.3.4".parse().unwrap(), 60);
+        let dialer = Arc::new(CountingDialer::default());
+
+        let client = DnsClient::builder()
+            .add_server(upstream.dns_url())
+            .with_dialer(dialer.clone())
+            .build()
+            .await;
+        client.lookup("www.example.com.", RecordType::A).await.unwrap();
+        assert_eq!(upstream.tcp_queries(), 0);
+        assert_eq!(dialer.routed.load(Ordering::Relaxed), 0);
+
+        let client = DnsClient::builder()
+            .add_server(upstream.dns_url())
+            .with_dialer(dialer.clone())
+            .with_route_upstream(true)
+            .build()
+            .await;
+        client.lookup("www.example.com.", RecordType::A).await.unwrap();
+        assert_eq!(upstream.tcp_queries(), 1);
+        assert_eq!(dialer.routed.load(Ordering::Relaxed), 1);
+        assert_eq!(dialer.dials.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_nameserver_group_svcb() {
+        use crate::test_util::MockDnsServer;
+
+        let plain = MockDnsServer::start().await.unwrap();
+        let svcb = MockDnsServer::start().await.unwrap();
+        let servers = vec![
+            NameServerInfo::from(plain.dns_url()),
+            NameServerInfo {
+                svcb: true,
+                ..svcb.dns_url().into()
+            },
+        ];
+        let client = DnsClient::builder().add_servers(servers).build().await;
+
+        _ = client.lookup("www.example.com.", RecordType::HTTPS).await;
+        assert_eq!((plain.queries(), svcb.queries()), (0, 1));
+
+        _ = client.lookup("www.example.com.", RecordType::A).await;
+        assert_eq!((plain.queries(), svcb.queries()), (1, 2));
+    }
+
+    #[tokio::test]
+    async fn test_nameserver_group_pinned() {
+        use std::time::Duration;
+
+        use crate::test_util::MockDnsServer;
+
+        let fast = MockDnsServer::start().await.unwrap();
+        let slow = MockDnsServer::start().await.unwrap();
+        fast.answer("www.example.com", "192.0.2.1".parse().unwrap(), 60);
+        slow.answer("www.example.com", "192.0.2.2".parse().unwrap(), 60);
+        slow.set_latency(Duration::from_millis(200));
+        let client = DnsClient::builder()
+            .add_servers(vec![fast.dns_url(), slow.dns_url()])
+            .build()
+            .await;
+        let name = Name::from_ascii("www.example.com.").unwrap();
+        let ips = |lookup: Lookup| lookup.iter().filter_map(|data| data.ip_addr()).collect::<Vec<_>>();
+
+        let (lookup, pin) = client.lookup_pinned(name.clone(), RecordType::A.into(), None).await;
+        assert_eq!(ips(lookup.unwrap()), ["192.0.2.1".parse::<IpAddr>().unwrap()]);
+        let pinned = pin.unwrap();
+        assert_eq!(pinned.upstream(), fast.dns_url().to_string());
+
+        // the pinned upstream answers, even though it got slower
+        fast.set_latency(Duration::from_millis(200));
+        slow.set_latency(Duration::ZERO);
+        let queries = slow.queries();
+        let (lookup, pin) = client
+            .lookup_pinned(name.clone(), RecordType::A.into(), Some(&pinned))
+            .await;
+        assert_eq!(ips(lookup.unwrap()), ["192.0.2.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(slow.queries(), queries);
+        assert!(Arc::ptr_eq(pin.as_ref().unwrap(), &pinned));
+
+        // unpinned lookups race
+        let lookup = client.lookup(name, RecordType::A).await.unwrap();
+        assert_eq!(ips(lookup), ["192.0.2.2".parse::<IpAddr>().unwrap()]);
+    }
+
     #[tokio::test]
     #[ignore = "reason"]
     async fn test_nameserver_google_tls_resolve() {
@@ -925,6 +1338,14 @@ mod tests {
         assert_alidns(&client).await;
     }
 
+    #[tokio::test]
+    async fn test_nameserver_google_https_get_resolve() {
+        let dns_url = DnsUrl::from_str("https://dns.google/dns-query?method=get").unwrap();
+        let client = DnsClient::builder().add_server(dns_url).build().await;
+        assert_google(&client).await;
+        assert_alidns(&client).await;
+    }
+
     #[tokio::test]
     #[ignore = "reason"]
     async fn test_nameserver_cloudflare_tls_resolve() {
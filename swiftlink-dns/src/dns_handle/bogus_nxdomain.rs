@@ -0,0 +1,43 @@
+use std::{collections::HashSet, net::IpAddr};
+
+use crate::{
+    dns_handle::{DnsRequestHandle, DnsRequestHandleNext},
+    libdns::proto::op::ResponseCode,
+    DnsContext, DnsError, DnsRequest, DnsResponse,
+};
+
+/// Turns answers containing one of the bogus addresses into `NXDOMAIN`, some resolvers answer
+/// missing names with the address of an ad server.
+#[derive(Debug)]
+pub struct BogusNxDomainHandle {
+    addrs: HashSet<IpAddr>,
+}
+
+impl BogusNxDomainHandle {
+    pub fn new<I: IntoIterator<Item = IpAddr>>(addrs: I) -> Self {
+        Self {
+            addrs: addrs.into_iter().collect(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl DnsRequestHandle for BogusNxDomainHandle {
+    async fn handle(
+        &self,
+        ctx: &mut DnsContext,
+        req: &DnsRequest,
+        next: DnsRequestHandleNext<'_>,
+    ) -> Result<DnsResponse, DnsError> {
+        let lookup = next.run(ctx, req).await?;
+        if lookup
+            .record_iter()
+            .filter_map(|record| record.data().and_then(|data| data.ip_addr()))
+            .any(|addr| self.addrs.contains(&addr))
+        {
+            ctx.set_no_cache();
+            return Err(ResponseCode::NXDomain.into());
+        }
+        Ok(lookup)
+    }
+}
@@ -1,11 +1,10 @@
 use std::{
     borrow::Borrow,
-    net::IpAddr,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     sync::{Arc, Mutex},
-    time::{Duration, Instant},
 };
 
-use swiftlink_infra::fakedns;
+use swiftlink_infra::{clock, fakedns};
 
 use crate::{
     dns_handle::{DnsRequestHandle, DnsRequestHandleNext},
@@ -17,19 +16,36 @@ use crate::{
                 RData, Record, RecordType,
             },
         },
-        resolver::{lookup::Lookup, Name},
+        resolver::{error::ResolveErrorKind, lookup::Lookup, Name},
     },
-    DnsContext, DnsError, DnsRequest, DnsResponse,
+    BlockedResponse, DnsContext, DnsError, DnsRequest, DnsResponse,
 };
 
+/// TTL of the `0.0.0.0` and `::` answers of blocked domains
+const BLOCKED_TTL: u32 = 60;
+
 #[derive(Debug)]
 pub struct FakeDnsHandle {
     fakedns: Arc<Mutex<fakedns::FakeDns>>,
+    blocked: Option<(Arc<fakedns::BlockedDomains>, BlockedResponse)>,
 }
 
 impl FakeDnsHandle {
     pub fn new(fakedns: Arc<Mutex<fakedns::FakeDns>>) -> Self {
-        Self { fakedns }
+        Self { fakedns, blocked: None }
+    }
+
+    /// Answers the domains of `blocked` with `response` instead of a fake ip.
+    pub fn with_blocked(mut self, blocked: Arc<fakedns::BlockedDomains>, response: BlockedResponse) -> Self {
+        self.blocked = Some((blocked, response));
+        self
+    }
+
+    fn blocked_response(&self, host: &str) -> Option<BlockedResponse> {
+        self.blocked
+            .as_ref()
+            .filter(|(blocked, _)| blocked.contains(host))
+            .map(|(_, response)| *response)
     }
 }
 
@@ -48,32 +64,161 @@ impl DnsRequestHandle for FakeDnsHandle {
             RecordType::A | RecordType::AAAA => {
                 let ipv6 = matches!(rtype, RecordType::AAAA);
                 let host = name.to_ascii().trim_end_matches('.').to_owned();
-                let fakeip = self.fakedns.lock().unwrap().lookup_ip(&host, ipv6);
+                match self.blocked_response(&host) {
+                    Some(BlockedResponse::Nxdomain) => return Err(ResponseCode::NXDomain.into()),
+                    Some(BlockedResponse::Zero) => {
+                        let query = req.query().original().clone();
+                        let name = query.name().to_owned();
+                        let rdata = if ipv6 {
+                            RData::AAAA(aaaa::AAAA::from(Ipv6Addr::UNSPECIFIED))
+                        } else {
+                            RData::A(a::A::from(Ipv4Addr::UNSPECIFIED))
+                        };
+                        let record = Record::from_rdata(name, BLOCKED_TTL, rdata);
+                        return Ok(Lookup::new_with_deadline(
+                            query,
+                            vec![record].into(),
+                            clock::deadline(BLOCKED_TTL),
+                        ));
+                    }
+                    None => {}
+                }
+                let (fakeip, fakeip6) = {
+                    let mut fakedns = self.fakedns.lock().unwrap();
+                    (fakedns.lookup_ip(&host, ipv6), fakedns.ipv6())
+                };
+                if ipv6 && !fakeip6 {
+                    // no real ip6 either, it would bypass the fake ip4
+                    return Err(ResolveErrorKind::NoRecordsFound {
+                        query: Box::new(req.query().original().clone()),
+                        soa: None,
+                        negative_ttl: Some(1),
+                        response_code: ResponseCode::NoError,
+                        trusted: true,
+                    }
+                    .into());
+                }
                 if let Some(ip) = fakeip {
                     let query = req.query().original().clone();
                     let name = query.name().to_owned();
-                    let valid_until = Instant::now() + Duration::from_secs(1);
+                    let valid_until = clock::deadline(1);
 
                     let record = match ip {
                         IpAddr::V4(ipv4) => Record::from_rdata(name, 1, RData::A(a::A::from(ipv4))),
-                        IpAddr::V6(ipv6) => {
-                            Record::from_rdata(name, 1, RData::AAAA(aaaa::AAAA::from(ipv6)))
-                        }
+                        IpAddr::V6(ipv6) => Record::from_rdata(name, 1, RData::AAAA(aaaa::AAAA::from(ipv6))),
                     };
 
-                    return Ok(Lookup::new_with_deadline(
-                        query,
-                        vec![record].into(),
-                        valid_until,
-                    ));
+                    return Ok(Lookup::new_with_deadline(query, vec![record].into(), valid_until));
                 }
             }
-            RecordType::SVCB | RecordType::HTTPS => {
-                return Err(DnsError::ResponseCode(ResponseCode::NXDomain).into())
-            }
+            RecordType::SVCB | RecordType::HTTPS => return Err(DnsError::ResponseCode(ResponseCode::NXDomain).into()),
             _ => {}
         }
 
         next.run(ctx, req).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use crate::{
+        dns_handle::DnsRequestHandlerBuilder,
+        libdns::{
+            proto::{op::Query, rr::Name},
+            server::server::Protocol,
+        },
+        DnsConfig,
+    };
+
+    use super::*;
+
+    fn create_request(name: &str, rtype: RecordType) -> DnsRequest {
+        DnsRequest {
+            id: 1,
+            query: Query::query(name.parse::<Name>().unwrap(), rtype).into(),
+            src: "127.0.0.1:53".parse::<SocketAddr>().unwrap(),
+            protocol: Protocol::Udp,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fakedns_handle_ttl() {
+        let fakedns = Arc::new(Mutex::new(fakedns::FakeDns::new(Default::default())));
+        let handler = DnsRequestHandlerBuilder::new()
+            .with(FakeDnsHandle::new(fakedns))
+            .build(Arc::new(DnsConfig::default()));
+
+        let lookup = handler
+            .search(&create_request("www.example.com.", RecordType::A))
+            .await
+            .unwrap();
+
+        assert_eq!(lookup.record_iter().next().unwrap().ttl(), 1);
+        assert!(!clock::is_expired(lookup.valid_until()));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(clock::is_expired(lookup.valid_until()));
+    }
+
+    #[tokio::test]
+    async fn test_fakedns_handle_ipv6_disabled() {
+        let config = fakedns::Config {
+            ipv6: false,
+            ..Default::default()
+        };
+        let fakedns = Arc::new(Mutex::new(fakedns::FakeDns::new(config)));
+        let handler = DnsRequestHandlerBuilder::new()
+            .with(FakeDnsHandle::new(fakedns))
+            .build(Arc::new(DnsConfig::default()));
+
+        let err = handler
+            .search(&create_request("www.example.com.", RecordType::AAAA))
+            .await
+            .unwrap_err();
+        // NOERROR without records, not handed down the stack
+        assert!(!err.is_nx_domain());
+        assert!(!err.is_soa());
+
+        let lookup = handler
+            .search(&create_request("www.example.com.", RecordType::A))
+            .await
+            .unwrap();
+        assert_eq!(lookup.record_iter().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fakedns_handle_blocked() {
+        let fakedns = Arc::new(Mutex::new(fakedns::FakeDns::new(Default::default())));
+        let blocked = Arc::new(fakedns::BlockedDomains::default());
+        blocked.insert("+.ads.example.com").unwrap();
+        let handler = DnsRequestHandlerBuilder::new()
+            .with(FakeDnsHandle::new(fakedns.clone()).with_blocked(blocked.clone(), BlockedResponse::Zero))
+            .build(Arc::new(DnsConfig::default()));
+
+        let lookup = handler
+            .search(&create_request("img.ads.example.com.", RecordType::AAAA))
+            .await
+            .unwrap();
+        let ip = lookup.record_iter().next().unwrap().data().unwrap().ip_addr();
+        assert_eq!(ip, Some(Ipv6Addr::UNSPECIFIED.into()));
+
+        // the first fake ip of the pool, none was allocated to the blocked domain
+        let first = fakedns::FakeDns::new(Default::default()).lookup_ip("www.example.com", true);
+        let lookup = handler
+            .search(&create_request("www.example.com.", RecordType::AAAA))
+            .await
+            .unwrap();
+        assert_eq!(lookup.record_iter().next().unwrap().data().unwrap().ip_addr(), first);
+
+        let handler = DnsRequestHandlerBuilder::new()
+            .with(FakeDnsHandle::new(fakedns).with_blocked(blocked, BlockedResponse::Nxdomain))
+            .build(Arc::new(DnsConfig::default()));
+        let err = handler
+            .search(&create_request("ads.example.com.", RecordType::A))
+            .await
+            .unwrap_err();
+        assert!(err.is_nx_domain());
+    }
+}
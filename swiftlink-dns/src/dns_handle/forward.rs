@@ -1,23 +1,53 @@
-use std::{borrow::Borrow, sync::Arc};
+use std::{borrow::Borrow, sync::Arc, time::Instant};
 
-use swiftlink_infra::log::debug;
+use swiftlink_infra::{
+    dns_failures::{DnsFailure, DnsFailures},
+    log::debug,
+};
 
 use crate::{
-    client::DnsClient,
+    client::{DnsClient, NameServer},
     dns_handle::{DnsRequestHandle, DnsRequestHandleNext},
-    libdns::resolver::Name,
-    resolver::{GenericResolver, LookupOptions},
+    libdns::{
+        proto::op::ResponseCode,
+        resolver::{error::ResolveErrorKind, Name},
+    },
+    resolver::{LookupOptions, NameServerPolicy},
     DnsContext, DnsError, DnsRequest, DnsResponse,
 };
 
+/// The upstream which answered the first forwarded lookup of a client query. The following lookups
+/// of the query, e.g. of the CNAME chain the static records follow, go to it as well, the answers
+/// of differently located upstreams aren't mixed.
+#[derive(Debug, Clone)]
+struct UpstreamPin(Arc<NameServer>);
+
 #[derive(Debug)]
 pub struct ForwardHandle {
     client: Arc<DnsClient>,
+    policy: NameServerPolicy,
+    failures: Option<Arc<DnsFailures>>,
 }
 
 impl ForwardHandle {
     pub fn new(client: Arc<DnsClient>) -> Self {
-        Self { client }
+        Self {
+            client,
+            policy: NameServerPolicy::default(),
+            failures: None,
+        }
+    }
+
+    /// Forwards the domains of `policy` to their own nameservers.
+    pub fn with_policy(mut self, policy: NameServerPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Records the failed lookups to `failures`.
+    pub fn with_failures(mut self, failures: Arc<DnsFailures>) -> Self {
+        self.failures = Some(failures);
+        self
     }
 }
 
@@ -32,7 +62,17 @@ impl DnsRequestHandle for ForwardHandle {
         let name: &Name = req.query().name().borrow();
         let rtype = req.query().query_type();
 
-        let client = &self.client;
+        let Some(client) = self.policy.client_of(req.query().name(), rtype, &self.client) else {
+            // AAAA queries of the group are disabled
+            return Err(ResolveErrorKind::NoRecordsFound {
+                query: Box::new(req.query().original().clone()),
+                soa: None,
+                negative_ttl: None,
+                response_code: ResponseCode::NoError,
+                trusted: true,
+            }
+            .into());
+        };
 
         // if dns request query nameserver, lookup local cache first
         if let Some(lookup) = client.lookup_nameserver(name.clone(), rtype).await {
@@ -47,16 +87,36 @@ impl DnsRequestHandle for ForwardHandle {
                     .flatten()
                     .collect::<Vec<_>>()
             );
-            ctx.no_cache = true;
+            ctx.set_no_cache();
             return Ok(lookup);
         }
 
         let lookup_options = LookupOptions {
             record_type: rtype,
-            client_subnet: None,
+            client_subnet: ctx.client_subnet(),
         };
 
-        // forward dns request
-        client.lookup(name.clone(), lookup_options).await
+        // forward dns request, to the upstream of the query's previous lookups if any
+        let pin = ctx.get::<UpstreamPin>().map(|pin| pin.0.clone());
+        let started = Instant::now();
+        let (res, upstream) = client.lookup_pinned(name.clone(), lookup_options, pin.as_ref()).await;
+        match (&res, upstream) {
+            (Ok(_), Some(upstream)) => {
+                ctx.insert(UpstreamPin(upstream));
+            }
+            (Err(err), upstream) if err.is_failure() => {
+                if let Some(failures) = &self.failures {
+                    failures.record(DnsFailure::new(
+                        &name.to_string(),
+                        &rtype.to_string(),
+                        upstream.as_deref().map(NameServer::upstream),
+                        &err.to_string(),
+                        started.elapsed(),
+                    ));
+                }
+            }
+            _ => {}
+        }
+        res
     }
 }
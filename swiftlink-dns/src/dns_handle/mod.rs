@@ -1,3 +1,37 @@
+//! Request handling middleware.
+//!
+//! A [`DnsRequestHandler`] runs a stack of [`DnsRequestHandle`]s, outermost first. Each handle
+//! answers the request itself or passes it down with [`DnsRequestHandleNext::run`], and may
+//! inspect or rewrite what the inner handles returned:
+//!
+//! ```ignore
+//! struct Blocklist(HashSet<Name>);
+//!
+//! #[async_trait::async_trait]
+//! impl DnsRequestHandle for Blocklist {
+//!     async fn handle(
+//!         &self,
+//!         ctx: &mut DnsContext,
+//!         req: &DnsRequest,
+//!         next: DnsRequestHandleNext<'_>,
+//!     ) -> Result<DnsResponse, DnsError> {
+//!         if self.0.contains(req.query().name().borrow()) {
+//!             ctx.insert(NoCache);
+//!             return Err(ResponseCode::Refused.into());
+//!         }
+//!         next.run(ctx, req).await
+//!     }
+//! }
+//!
+//! let handler = DnsRequestHandlerBuilder::new()
+//!     .with(Blocklist(names))
+//!     .with(ForwardHandle::new(client))
+//!     .build(config);
+//! let server_handle = ServerHandle::new(Arc::new(handler));
+//! ```
+//!
+//! A request falling through the whole stack fails with `SERVFAIL`.
+
 use futures_util::{future::BoxFuture, FutureExt};
 use std::{str::FromStr, sync::Arc};
 
@@ -12,14 +46,19 @@ use crate::{
     DnsConfig, DnsContext, DnsError, DnsRequest, DnsResponse, MAX_TTL,
 };
 
+pub use bogus_nxdomain::BogusNxDomainHandle;
 pub use fakedns::FakeDnsHandle;
 pub use forward::ForwardHandle;
+pub use static_records::{StaticRecordsHandle, ZoneFileError};
 
+mod bogus_nxdomain;
 mod fakedns;
 mod forward;
+mod static_records;
 
 #[async_trait::async_trait]
 pub trait DnsRequestHandle: 'static + Send + Sync {
+    /// Handles `req`, `next` runs the handles below this one.
     async fn handle(
         &self,
         ctx: &mut DnsContext,
@@ -28,6 +67,7 @@ pub trait DnsRequestHandle: 'static + Send + Sync {
     ) -> Result<DnsResponse, DnsError>;
 }
 
+/// The handles below the current one.
 #[derive(Clone)]
 pub struct DnsRequestHandleNext<'a> {
     handles: &'a [Arc<dyn DnsRequestHandle>],
@@ -38,12 +78,9 @@ impl<'a> DnsRequestHandleNext<'a> {
         Self { handles }
     }
 
+    /// Passes the request to the next handle.
     #[inline]
-    pub fn run(
-        mut self,
-        ctx: &'a mut DnsContext,
-        req: &'a DnsRequest,
-    ) -> BoxFuture<'a, Result<DnsResponse, DnsError>> {
+    pub fn run(mut self, ctx: &'a mut DnsContext, req: &'a DnsRequest) -> BoxFuture<'a, Result<DnsResponse, DnsError>> {
         if let Some((current, rest)) = self.handles.split_first() {
             self.handles = rest;
             current.handle(ctx, req, self).boxed()
@@ -76,6 +113,7 @@ impl<'a> DnsRequestHandleNext<'a> {
     }
 }
 
+#[derive(Default)]
 pub struct DnsRequestHandlerBuilder {
     handle_stack: Vec<Arc<dyn DnsRequestHandle>>,
 }
@@ -87,6 +125,7 @@ impl DnsRequestHandlerBuilder {
         }
     }
 
+    /// Pushes `handle` below the handles added so far.
     #[inline]
     pub fn with<H>(self, handle: H) -> Self
     where
@@ -123,13 +162,97 @@ impl DnsRequestHandler {
         self.execute(&mut ctx, req).await
     }
 
-    async fn execute(
-        &self,
-        ctx: &mut DnsContext,
-        req: &DnsRequest,
-    ) -> Result<DnsResponse, DnsError> {
-        DnsRequestHandleNext::new(&self.handle_stack)
-            .run(ctx, req)
-            .await
+    /// Runs the handles with a context prepared by the caller, e.g. carrying extensions.
+    pub async fn execute(&self, ctx: &mut DnsContext, req: &DnsRequest) -> Result<DnsResponse, DnsError> {
+        DnsRequestHandleNext::new(&self.handle_stack).run(ctx, req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::libdns::{
+        proto::{
+            op::Query,
+            rr::{rdata::A, RData, RecordType},
+        },
+        resolver::lookup::Lookup,
+        server::server::Protocol,
+    };
+
+    use super::*;
+
+    /// Counts the requests passing through it.
+    struct Hits(u32);
+
+    struct Counter;
+
+    #[async_trait::async_trait]
+    impl DnsRequestHandle for Counter {
+        async fn handle(
+            &self,
+            ctx: &mut DnsContext,
+            req: &DnsRequest,
+            next: DnsRequestHandleNext<'_>,
+        ) -> Result<DnsResponse, DnsError> {
+            match ctx.get_mut::<Hits>() {
+                Some(hits) => hits.0 += 1,
+                None => _ = ctx.insert(Hits(1)),
+            }
+            next.run(ctx, req).await
+        }
+    }
+
+    struct Static;
+
+    #[async_trait::async_trait]
+    impl DnsRequestHandle for Static {
+        async fn handle(
+            &self,
+            ctx: &mut DnsContext,
+            req: &DnsRequest,
+            next: DnsRequestHandleNext<'_>,
+        ) -> Result<DnsResponse, DnsError> {
+            if req.query().name().to_string() != "static.test." {
+                return next.run(ctx, req).await;
+            }
+
+            ctx.insert(crate::NoCache);
+            let record = Record::from_rdata(req.query().name().into(), 60, RData::A(A::new(10, 0, 0, 1)));
+            Ok(Lookup::new_with_max_ttl(
+                req.query().original().clone(),
+                Arc::from([record]),
+            ))
+        }
+    }
+
+    fn request(name: &str) -> DnsRequest {
+        DnsRequest {
+            id: 1,
+            query: Query::query(name.parse::<Name>().unwrap(), RecordType::A).into(),
+            src: "127.0.0.1:53".parse::<SocketAddr>().unwrap(),
+            protocol: Protocol::Udp,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_handle_chain() {
+        let handler = DnsRequestHandlerBuilder::default()
+            .with(Counter)
+            .with(Counter)
+            .with(Static)
+            .build(Arc::new(DnsConfig::default()));
+
+        let mut ctx = DnsContext::new(Arc::new(DnsConfig::default()));
+        let lookup = handler.execute(&mut ctx, &request("static.test.")).await.unwrap();
+        assert_eq!(lookup.records().len(), 1);
+        assert_eq!(ctx.get::<Hits>().map(|h| h.0), Some(2));
+        assert!(ctx.no_cache());
+        assert!(ctx.remove::<crate::NoCache>().is_some());
+        assert!(!ctx.no_cache());
+
+        let err = handler.search(&request("other.test.")).await.unwrap_err();
+        assert!(err.is_soa());
     }
 }
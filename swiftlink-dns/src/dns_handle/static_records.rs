@@ -0,0 +1,396 @@
+//! Local records loaded from RFC 1035 zone files.
+//!
+//! Names inside a loaded zone are answered from the zone alone, as its authority would: missing
+//! names are `NXDOMAIN`, missing types of existing names are answered without records, both with
+//! the SOA of the zone. Wildcards (`*.example.com.`) match names without records of their own.
+//! A CNAME pointing outside the loaded zones is resolved by the handles below.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use swiftlink_infra::clock;
+
+use crate::{
+    dns_handle::{DnsRequestHandle, DnsRequestHandleNext},
+    libdns::{
+        proto::{
+            error::ProtoError,
+            op::{Query, ResponseCode},
+            rr::{rdata::SOA, LowerName, Name, RData, Record, RecordType},
+            serialize::txt::{ParseError, Parser},
+        },
+        resolver::{error::ResolveErrorKind, lookup::Lookup},
+    },
+    DnsContext, DnsError, DnsRequest, DnsResponse,
+};
+
+/// Longest CNAME chain followed inside the loaded zones.
+const MAX_CNAME_CHAIN: usize = 8;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ZoneFileError {
+    #[error("could not read zone file {0:?}: {1}")]
+    Io(PathBuf, io::Error),
+    #[error("invalid zone file {0:?}: {1}")]
+    Parse(PathBuf, ParseError),
+    #[error("zone file {0:?} has no SOA record for {1}")]
+    MissingSoa(PathBuf, Name),
+}
+
+#[derive(Debug, Default)]
+pub struct StaticRecordsHandle {
+    /// origin and SOA of each zone, the most specific zone first
+    zones: Vec<(LowerName, Record<SOA>)>,
+    records: HashMap<LowerName, Vec<Record>>,
+}
+
+impl StaticRecordsHandle {
+    /// Loads the zone files at `paths`, `$ORIGIN` defaults to the file name (e.g. `example.com.zone`
+    /// is `example.com.`).
+    pub fn from_zone_files<P: AsRef<Path>>(paths: &[P]) -> Result<Self, ZoneFileError> {
+        let mut handle = Self::default();
+        for path in paths {
+            let path = path.as_ref();
+            let text = fs::read_to_string(path).map_err(|err| ZoneFileError::Io(path.to_owned(), err))?;
+            let origin = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| Name::from_str(stem).ok())
+                .map(|mut name| {
+                    name.set_fqdn(true);
+                    name
+                });
+            handle.add_zone(&text, Some(path), origin)?;
+        }
+        Ok(handle)
+    }
+
+    /// Adds the zone in `text`, `path` is needed for relative `$INCLUDE`s.
+    pub fn add_zone(&mut self, text: &str, path: Option<&Path>, origin: Option<Name>) -> Result<(), ZoneFileError> {
+        let file = path.map(Path::to_owned).unwrap_or_default();
+        let (origin, record_sets) = Parser::new(Cow::Borrowed(text), path.map(Path::to_owned), origin)
+            .parse()
+            .map_err(|err| ZoneFileError::Parse(file.clone(), err))?;
+
+        let mut soa = None;
+        for (key, record_set) in record_sets {
+            for record in record_set.records_without_rrsigs() {
+                if key.record_type == RecordType::SOA && key.name == LowerName::from(&origin) {
+                    soa = Record::<SOA>::try_from(record.clone()).ok();
+                }
+                self.records.entry(key.name.clone()).or_default().push(record.clone());
+            }
+        }
+        let soa = soa.ok_or_else(|| ZoneFileError::MissingSoa(file, origin.clone()))?;
+
+        self.zones.push((LowerName::from(&origin), soa));
+        self.zones
+            .sort_by_key(|(origin, _)| std::cmp::Reverse(origin.num_labels()));
+        Ok(())
+    }
+
+    /// Answers `domain` and its subdomains with `addrs`, `NXDOMAIN` if there are none.
+    pub fn add_address(&mut self, domain: &str, addrs: &[IpAddr], ttl: u32) -> Result<(), ProtoError> {
+        let mut origin = Name::from_str(domain)?;
+        origin.set_fqdn(true);
+        let wildcard = Name::from_str("*")?.append_domain(&origin)?;
+
+        let records = addrs
+            .iter()
+            .map(|addr| match addr {
+                IpAddr::V4(addr) => RData::A((*addr).into()),
+                IpAddr::V6(addr) => RData::AAAA((*addr).into()),
+            })
+            .flat_map(|rdata| {
+                [
+                    Record::from_rdata(origin.clone(), ttl, rdata.clone()),
+                    Record::from_rdata(wildcard.clone(), ttl, rdata),
+                ]
+            });
+        for record in records {
+            self.records
+                .entry(LowerName::from(record.name()))
+                .or_default()
+                .push(record);
+        }
+
+        // a zone of its own, names below it are answered from it alone
+        let rname = Name::from_str("hostmaster")?.append_domain(&origin)?;
+        let soa = SOA::new(origin.clone(), rname, 1, 3600, 600, 86400, ttl);
+        self.zones
+            .push((LowerName::from(&origin), Record::from_rdata(origin, ttl, soa)));
+        self.zones
+            .sort_by_key(|(origin, _)| std::cmp::Reverse(origin.num_labels()));
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty()
+    }
+
+    /// The zone `name` belongs to.
+    fn zone_of(&self, name: &LowerName) -> Option<&(LowerName, Record<SOA>)> {
+        self.zones.iter().find(|(origin, _)| origin.zone_of(name))
+    }
+
+    /// The records of `name`, or of the closest wildcard inside `origin` renamed to `name`.
+    fn records_of(&self, name: &LowerName, origin: &LowerName) -> Option<Cow<'_, [Record]>> {
+        if let Some(records) = self.records.get(name) {
+            return Some(Cow::Borrowed(records));
+        }
+
+        // `*.b.example.com.`, then `*.example.com.` for `a.b.example.com.`
+        let mut candidate = name.clone();
+        while !candidate.is_root() && origin.zone_of(&candidate.base_name()) {
+            let wildcard = candidate.clone().into_wildcard();
+            if let Some(records) = self.records.get(&wildcard) {
+                let renamed = records
+                    .iter()
+                    .map(|record| {
+                        let mut record = record.clone();
+                        record.set_name(name.clone().into());
+                        record
+                    })
+                    .collect::<Vec<_>>();
+                return Some(Cow::Owned(renamed));
+            }
+            candidate = candidate.base_name();
+        }
+        None
+    }
+}
+
+/// Why a name inside a loaded zone has no answer.
+enum Miss {
+    NxDomain,
+    NoData,
+}
+
+#[async_trait::async_trait]
+impl DnsRequestHandle for StaticRecordsHandle {
+    async fn handle(
+        &self,
+        ctx: &mut DnsContext,
+        req: &DnsRequest,
+        next: DnsRequestHandleNext<'_>,
+    ) -> Result<DnsResponse, DnsError> {
+        let query_type = req.query().query_type();
+        let mut name = req.query().name().clone();
+        let mut answers = Vec::new();
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            let Some((origin, soa)) = self.zone_of(&name) else {
+                // a CNAME leading outside the loaded zones
+                if answers.is_empty() {
+                    return next.run(ctx, req).await;
+                }
+                let lookup = next.run(ctx, &req.with_cname(name.into())).await?;
+                answers.extend(lookup.record_iter().cloned());
+                return Ok(lookup_of(req, answers));
+            };
+
+            let miss = match self.records_of(&name, origin) {
+                None => Miss::NxDomain,
+                Some(records) => {
+                    let matching = records
+                        .iter()
+                        .filter(|record| query_type == RecordType::ANY || record.record_type() == query_type)
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    if !matching.is_empty() {
+                        answers.extend(matching);
+                        return Ok(lookup_of(req, answers));
+                    }
+
+                    match records.iter().find_map(|record| match record.data() {
+                        Some(RData::CNAME(cname)) => Some(cname.0.clone()),
+                        _ => None,
+                    }) {
+                        Some(target) => {
+                            answers.extend(records.iter().filter(|r| r.record_type() == RecordType::CNAME).cloned());
+                            name = LowerName::from(target);
+                            continue;
+                        }
+                        None => Miss::NoData,
+                    }
+                }
+            };
+
+            if !answers.is_empty() {
+                // the CNAME chain ends at a missing name or type
+                return Ok(lookup_of(req, answers));
+            }
+            let response_code = match miss {
+                Miss::NxDomain => ResponseCode::NXDomain,
+                Miss::NoData => ResponseCode::NoError,
+            };
+            return Err(ResolveErrorKind::NoRecordsFound {
+                query: Box::new(req.query().original().clone()),
+                soa: Some(Box::new(soa.clone())),
+                negative_ttl: soa.data().map(|soa| soa.minimum()),
+                response_code,
+                trusted: true,
+            }
+            .into());
+        }
+
+        Err(ResponseCode::ServFail.into())
+    }
+}
+
+/// The answer to `req`, cached as long as its shortest TTL.
+fn lookup_of(req: &DnsRequest, records: Vec<Record>) -> Lookup {
+    let ttl = records.iter().map(|record| record.ttl()).min().unwrap_or_default();
+    let query: Query = req.query().original().clone();
+    Lookup::new_with_deadline(query, records.into(), clock::deadline(ttl))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use crate::{dns_handle::DnsRequestHandlerBuilder, libdns::server::server::Protocol, DnsConfig};
+
+    use super::*;
+
+    const ZONE: &str = r#"
+$ORIGIN example.com.
+$TTL 300
+@       IN SOA  ns.example.com. admin.example.com. ( 1 3600 900 604800 60 )
+@       IN NS   ns
+ns      IN A    192.0.2.1
+www     IN A    192.0.2.10
+        IN AAAA 2001:db8::10
+alias   IN CNAME www
+ext     IN CNAME www.example.org.
+*.dev   IN A    192.0.2.20
+"#;
+
+    fn create_request(name: &str, rtype: RecordType) -> DnsRequest {
+        DnsRequest {
+            id: 1,
+            query: Query::query(name.parse::<Name>().unwrap(), rtype).into(),
+            src: "127.0.0.1:53".parse::<SocketAddr>().unwrap(),
+            protocol: Protocol::Udp,
+        }
+    }
+
+    fn handler() -> crate::DnsRequestHandler {
+        let mut handle = StaticRecordsHandle::default();
+        handle.add_zone(ZONE, None, None).unwrap();
+        DnsRequestHandlerBuilder::new()
+            .with(handle)
+            .build(Arc::new(DnsConfig::default()))
+    }
+
+    #[tokio::test]
+    async fn test_static_records_answers() {
+        let handler = handler();
+
+        let lookup = handler
+            .search(&create_request("www.example.com.", RecordType::A))
+            .await
+            .unwrap();
+        assert_eq!(lookup.iter().next().unwrap().to_string(), "192.0.2.10");
+        assert_eq!(lookup.record_iter().next().unwrap().ttl(), 300);
+
+        let lookup = handler
+            .search(&create_request("alias.example.com.", RecordType::AAAA))
+            .await
+            .unwrap();
+        let types = lookup.record_iter().map(|r| r.record_type()).collect::<Vec<_>>();
+        assert_eq!(types, vec![RecordType::CNAME, RecordType::AAAA]);
+
+        let lookup = handler
+            .search(&create_request("api.dev.example.com.", RecordType::A))
+            .await
+            .unwrap();
+        let record = lookup.record_iter().next().unwrap();
+        assert_eq!(record.name(), &Name::from_str("api.dev.example.com.").unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_static_records_misses() {
+        let handler = handler();
+
+        let err = handler
+            .search(&create_request("missing.example.com.", RecordType::A))
+            .await
+            .unwrap_err();
+        assert!(err.is_nx_domain());
+        assert!(err.is_soa());
+
+        let err = handler
+            .search(&create_request("ns.example.com.", RecordType::AAAA))
+            .await
+            .unwrap_err();
+        assert!(!err.is_nx_domain());
+        assert!(err.is_soa());
+
+        // outside the zone, falls through the stack
+        let err = handler
+            .search(&create_request("www.example.net.", RecordType::A))
+            .await
+            .unwrap_err();
+        assert!(!err.is_nx_domain());
+    }
+
+    #[tokio::test]
+    async fn test_static_records_address() {
+        let mut handle = StaticRecordsHandle::default();
+        handle
+            .add_address("router.lan", &["192.168.1.1".parse().unwrap()], 60)
+            .unwrap();
+        handle.add_address("ads.example.com", &[], 60).unwrap();
+        let handler = DnsRequestHandlerBuilder::new()
+            .with(handle)
+            .build(Arc::new(DnsConfig::default()));
+
+        for name in ["router.lan.", "nas.router.lan."] {
+            let lookup = handler.search(&create_request(name, RecordType::A)).await.unwrap();
+            assert_eq!(lookup.iter().next().unwrap().to_string(), "192.168.1.1");
+        }
+        let err = handler
+            .search(&create_request("router.lan.", RecordType::AAAA))
+            .await
+            .unwrap_err();
+        assert!(!err.is_nx_domain());
+
+        let err = handler
+            .search(&create_request("track.ads.example.com.", RecordType::A))
+            .await
+            .unwrap_err();
+        assert!(err.is_nx_domain());
+    }
+
+    #[test]
+    fn test_zone_files() {
+        let dir = std::env::temp_dir().join("swiftlink-zone-test");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("example.com.zone");
+        fs::write(&path, ZONE.replace("$ORIGIN example.com.\n", "")).unwrap();
+
+        let handle = StaticRecordsHandle::from_zone_files(&[&path]).unwrap();
+        assert!(handle
+            .zone_of(&LowerName::from_str("www.example.com.").unwrap())
+            .is_some());
+
+        fs::write(&path, "$TTL 300\nwww IN A 192.0.2.10\n").unwrap();
+        assert!(matches!(
+            StaticRecordsHandle::from_zone_files(&[&path]),
+            Err(ZoneFileError::MissingSoa(..))
+        ));
+        assert!(matches!(
+            StaticRecordsHandle::from_zone_files(&[dir.join("missing.zone")]),
+            Err(ZoneFileError::Io(..))
+        ));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
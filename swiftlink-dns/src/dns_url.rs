@@ -7,7 +7,7 @@ use std::{
 };
 use url::{Host, Url};
 
-use crate::libdns::resolver::config::Protocol;
+use crate::{doh::DohMethod, libdns::resolver::config::Protocol};
 
 /// alias: system、google、cloudflare、quad9
 /// udp://8.8.8.8 or 8.8.8.8 or [240e:1f:1::1]  => traditional dns server
@@ -15,6 +15,7 @@ use crate::libdns::resolver::config::Protocol;
 /// tls://8.8.8.8:853                           => DOT: dns over tls
 /// quic://8.8.8.8:853                          => DOT: dns over QUIC
 /// https://1.1.1.1/dns-query                   => DOH: dns over https
+/// https://1.1.1.1/resolve?method=get          => DOH: GET queries to a custom path
 #[derive(Debug, Clone, Eq)]
 pub struct DnsUrl {
     proto: Protocol,
@@ -37,8 +38,7 @@ impl DnsUrl {
     }
 
     pub fn port(&self) -> u16 {
-        self.port
-            .unwrap_or_else(|| dns_proto_default_port(&self.proto))
+        self.port.unwrap_or_else(|| dns_proto_default_port(&self.proto))
     }
 
     pub fn is_default_port(&self) -> bool {
@@ -125,7 +125,8 @@ impl FromStr for DnsUrl {
     type Err = DnsUrlParseErr;
 
     fn from_str(url: &str) -> Result<Self, Self::Err> {
-        let mut url = url.to_lowercase();
+        // the path is case-sensitive, the rest is lowercased below
+        let mut url = url.to_string();
         if !url.contains("://") {
             url.insert_str(0, "udp://")
         }
@@ -152,7 +153,9 @@ impl FromStr for DnsUrl {
 
         let mut host = host.unwrap().to_owned();
 
-        if let Host::Domain(ref domain) = host {
+        if let Host::Domain(ref mut domain) = host {
+            // only the hosts of special schemes like https are lowercased by the parser
+            domain.make_ascii_lowercase();
             if let Ok(ip) = IpAddr::from_str(domain) {
                 host = match ip {
                     IpAddr::V4(ip) => Host::Ipv4(ip),
@@ -164,7 +167,7 @@ impl FromStr for DnsUrl {
         let params = url
             .query_pairs()
             .into_iter()
-            .map(|(n, v)| (n.into_owned(), v.into_owned()))
+            .map(|(n, v)| (n.to_lowercase(), v.to_lowercase()))
             .collect::<BTreeMap<_, _>>();
 
         Ok(Self {
@@ -284,10 +287,7 @@ impl DnsUrlParam for DnsUrl {
     }
 
     fn get_param<T: FromStr>(&self, name: &str) -> Option<T> {
-        self.params
-            .get(name)
-            .map(|v| T::from_str(v).ok())
-            .unwrap_or_default()
+        self.params.get(name).map(|v| T::from_str(v).ok()).unwrap_or_default()
     }
 
     fn set_param<T: ToString>(&mut self, name: &str, value: T) {
@@ -321,6 +321,11 @@ pub trait DnsUrlParamExt: DnsUrlParam {
     fn set_ssl_verify(&mut self, verify: bool) {
         self.set_param("ssl_verify", verify)
     }
+
+    /// The HTTP method of DoH queries, `method=get|post`.
+    fn doh_method(&self) -> DohMethod {
+        self.get_param_or_default("method")
+    }
 }
 
 impl DnsUrlParamExt for DnsUrl {}
@@ -459,6 +464,19 @@ mod tests {
         assert!(url.ip().is_none());
     }
 
+    #[test]
+    fn test_parse_https_method() {
+        let url = DnsUrl::from_str("https://Dns.Example.com/Tenant/ABC?method=GET").unwrap();
+        assert_eq!(url.host.to_string(), "dns.example.com");
+        assert_eq!(url.path(), "/Tenant/ABC");
+        assert_eq!(url.doh_method(), DohMethod::Get);
+        assert_eq!(url.to_string(), "https://dns.example.com/Tenant/ABC?method=get");
+
+        let url = DnsUrl::from_str("TLS://DNS.Google").unwrap();
+        assert_eq!(url.to_string(), "tls://dns.google");
+        assert_eq!(url.doh_method(), DohMethod::Post);
+    }
+
     #[test]
     fn test_parse_quic() {
         let url = DnsUrl::from_str("quic://dns.adguard-dns.com").unwrap();
@@ -0,0 +1,183 @@
+//! Import of the common dnsmasq options, for migrating routers.
+//!
+//! | dnsmasq                        | swiftlink                                  |
+//! |--------------------------------|--------------------------------------------|
+//! | `server=10.0.0.1#5353`         | `nameserver`                               |
+//! | `server=/corp/lan/10.0.0.1`    | `nameserver_policy`, for `corp` and `lan`  |
+//! | `server=/corp/`, `local=`      | `address` without addresses, `NXDOMAIN`    |
+//! | `address=/example.com/1.2.3.4` | `address`                                  |
+//! | `address=/example.com/#`       | `address` of `0.0.0.0` and `::`            |
+//! | `address=/example.com/`        | `address` without addresses, `NXDOMAIN`    |
+//! | `bogus-nxdomain=1.2.3.4`       | `bogus_nxdomain`                           |
+//!
+//! Other options are skipped with a warning.
+
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use swiftlink_infra::log::warn;
+
+use crate::{config::NameServerInfo, dns_url::DnsUrl};
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum DnsmasqError {
+    #[error("line {0}: invalid {1} `{2}`")]
+    Invalid(usize, &'static str, String),
+}
+
+/// The options of a dnsmasq configuration, in terms of [`DnsConfig`](crate::DnsConfig).
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Imported {
+    pub servers: Vec<NameServerInfo>,
+    pub nameserver_policy: BTreeMap<String, Vec<NameServerInfo>>,
+    pub address: BTreeMap<String, Vec<IpAddr>>,
+    pub bogus_nxdomain: Vec<IpAddr>,
+}
+
+/// Parses the text of a dnsmasq configuration file.
+pub fn parse(conf: &str) -> Result<Imported, DnsmasqError> {
+    let mut imported = Imported::default();
+
+    for (index, line) in conf.lines().enumerate() {
+        let line_no = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (option, value) = line.split_once('=').unwrap_or((line, ""));
+        let (option, value) = (option.trim(), value.trim());
+        let invalid = |what: &'static str| DnsmasqError::Invalid(line_no, what, value.to_owned());
+
+        match option {
+            "server" | "local" => {
+                let (domains, server) = split_domains(value);
+                // `@interface` or `@source` address
+                let server = server.split('@').next().unwrap_or_default();
+                if domains.is_empty() {
+                    imported
+                        .servers
+                        .push(parse_server(server).ok_or_else(|| invalid("server"))?);
+                } else if server.is_empty() {
+                    // answered locally only, there are no local names here
+                    for domain in domains {
+                        imported.address.entry(domain).or_default();
+                    }
+                } else if server == "#" {
+                    // the default servers, which the domain uses anyway
+                } else {
+                    let server = parse_server(server).ok_or_else(|| invalid("server"))?;
+                    for domain in domains {
+                        imported
+                            .nameserver_policy
+                            .entry(domain)
+                            .or_default()
+                            .push(server.clone());
+                    }
+                }
+            }
+            "address" => {
+                let (domains, addr) = split_domains(value);
+                if domains.is_empty() {
+                    return Err(invalid("address"));
+                }
+                let addrs = match addr {
+                    "" => vec![],
+                    "#" => vec![Ipv4Addr::UNSPECIFIED.into(), Ipv6Addr::UNSPECIFIED.into()],
+                    addr => vec![IpAddr::from_str(addr).map_err(|_| invalid("address"))?],
+                };
+                for domain in domains {
+                    imported
+                        .address
+                        .entry(domain)
+                        .or_default()
+                        .extend(addrs.iter().copied());
+                }
+            }
+            "bogus-nxdomain" => {
+                imported
+                    .bogus_nxdomain
+                    .push(IpAddr::from_str(value).map_err(|_| invalid("bogus-nxdomain"))?);
+            }
+            _ => warn!(
+                "dnsmasq option `{}` on line {} is not supported, skipped",
+                option, line_no
+            ),
+        }
+    }
+
+    Ok(imported)
+}
+
+/// Splits `/corp/lan/value` into the domains and the value, a value without domains as is.
+fn split_domains(value: &str) -> (Vec<String>, &str) {
+    let Some(rest) = value.strip_prefix('/') else {
+        return (vec![], value);
+    };
+    let (domains, value) = rest.rsplit_once('/').unwrap_or((rest, ""));
+    let domains = domains
+        .split('/')
+        .map(|domain| domain.trim_matches('.').to_ascii_lowercase())
+        .filter(|domain| !domain.is_empty())
+        .collect();
+    (domains, value)
+}
+
+/// Parses `10.0.0.1` or `10.0.0.1#5353`.
+fn parse_server(server: &str) -> Option<NameServerInfo> {
+    let (ip, port) = match server.split_once('#') {
+        Some((ip, port)) => (ip, port.parse().ok()?),
+        None => (server, 53),
+    };
+    let addr = SocketAddr::new(IpAddr::from_str(ip).ok()?, port);
+    DnsUrl::from_str(&addr.to_string()).ok().map(NameServerInfo::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dnsmasq() {
+        let imported = parse(
+            r#"
+            # upstream
+            server=1.1.1.1
+            server=[bad]
+            "#,
+        );
+        assert_eq!(imported, Err(DnsmasqError::Invalid(4, "server", "[bad]".to_owned())));
+
+        let imported = parse(
+            r#"
+            no-resolv
+            server=1.1.1.1
+            server=10.0.0.1#5353
+            server=/corp/lan/10.0.0.53
+            local=/home.arpa/
+            address=/ads.example.com/
+            address=/blocked.example/#
+            address=/router.lan/192.168.1.1
+            address=/router.lan/fd00::1
+            bogus-nxdomain=64.94.110.11
+            "#,
+        )
+        .unwrap();
+
+        let servers = imported.servers.iter().map(|s| s.url.to_string()).collect::<Vec<_>>();
+        assert_eq!(servers, vec!["udp://1.1.1.1", "udp://10.0.0.1:5353"]);
+        assert_eq!(imported.nameserver_policy["corp"], imported.nameserver_policy["lan"]);
+        assert_eq!(imported.nameserver_policy["corp"][0].url.to_string(), "udp://10.0.0.53");
+        assert!(imported.address["home.arpa"].is_empty());
+        assert!(imported.address["ads.example.com"].is_empty());
+        assert_eq!(imported.address["blocked.example"].len(), 2);
+        assert_eq!(
+            imported.address["router.lan"],
+            vec!["192.168.1.1".parse::<IpAddr>().unwrap(), "fd00::1".parse().unwrap()]
+        );
+        assert_eq!(imported.bogus_nxdomain, vec!["64.94.110.11".parse::<IpAddr>().unwrap()]);
+    }
+}
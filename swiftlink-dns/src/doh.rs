@@ -0,0 +1,300 @@
+//! DNS over HTTPS ([RFC 8484](https://datatracker.ietf.org/doc/html/rfc8484)) with the knobs
+//! hickory lacks.
+//!
+//! hickory always POSTs to `/dns-query` and sends no headers of its own choosing. Upstreams serving
+//! another path, answering only GET, or requiring a token in a header are queried by this client.
+
+use std::{fmt, net::SocketAddr, str::FromStr, sync::Arc};
+
+use bytes::{Bytes, BytesMut};
+use data_encoding::BASE64URL_NOPAD;
+use h2::client::SendRequest;
+use http::{header, HeaderName, HeaderValue, Method, Request, Version};
+use rustls::{ClientConfig, ServerName};
+use tokio::sync::Mutex;
+use tokio_rustls::TlsConnector;
+
+use swiftlink_infra::{log::*, net::ConnectOpts};
+
+use crate::{
+    client::VerifiedDnsUrl,
+    dns_url::DnsUrlParamExt,
+    libdns::proto::{error::ProtoError, op::Message, serialize::binary::BinDecodable},
+    proxy::{self, UpstreamProxy},
+};
+
+const MIME_APPLICATION_DNS: &str = "application/dns-message";
+
+/// The HTTP method of DoH queries, `method=get` in the nameserver url.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum DohMethod {
+    /// the query in the `dns` parameter of the url, cacheable by HTTP caches
+    Get,
+    /// the query in the body
+    #[default]
+    Post,
+}
+
+impl FromStr for DohMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "get" => Ok(Self::Get),
+            "post" => Ok(Self::Post),
+            _ => Err(format!("unknown DoH method {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for DohMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Get => "get",
+            Self::Post => "post",
+        })
+    }
+}
+
+/// A DoH upstream, queried over one reused HTTP/2 connection.
+pub struct DohClient {
+    addr: SocketAddr,
+    server_name: ServerName,
+    /// host and port of the request urls
+    authority: String,
+    path: String,
+    method: DohMethod,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    tls_config: Arc<ClientConfig>,
+    proxy: Option<UpstreamProxy>,
+    connect_opts: ConnectOpts,
+    conn: Mutex<Option<SendRequest<Bytes>>>,
+}
+
+impl DohClient {
+    pub fn new(
+        url: &VerifiedDnsUrl,
+        tls_config: Arc<ClientConfig>,
+        proxy: Option<UpstreamProxy>,
+        connect_opts: ConnectOpts,
+    ) -> Self {
+        let host = url.host().to_string();
+        let server_name = ServerName::try_from(host.trim_start_matches('[').trim_end_matches(']'))
+            .unwrap_or_else(|_| ServerName::IpAddress(url.ip()));
+        let authority = if url.is_default_port() {
+            host
+        } else {
+            format!("{}:{}", host, url.port())
+        };
+
+        Self {
+            addr: url.addr(),
+            server_name,
+            authority,
+            path: url.path().to_string(),
+            method: url.doh_method(),
+            headers: vec![],
+            tls_config,
+            proxy,
+            connect_opts,
+            conn: Default::default(),
+        }
+    }
+
+    /// Adds headers to every request, replacing the defaults of the same name. Invalid ones are
+    /// skipped, [`NameServerInfo`](crate::NameServerInfo) refuses them in the first place.
+    pub fn with_headers(mut self, headers: &[(String, String)]) -> Self {
+        self.headers = headers
+            .iter()
+            .filter_map(
+                |(name, value)| match (HeaderName::from_str(name), HeaderValue::from_str(value)) {
+                    (Ok(name), Ok(value)) => Some((name, value)),
+                    _ => {
+                        warn!("invalid DoH header {}: {}", name, value);
+                        None
+                    }
+                },
+            )
+            .collect();
+        self
+    }
+
+    /// Sends `message` and returns the answer.
+    pub async fn send(&self, message: &Message) -> Result<Message, ProtoError> {
+        // an ID of 0 makes GET answers cacheable, RFC 8484 section 4.1
+        let id = message.id();
+        let mut message = message.clone();
+        message.set_id(0);
+        let message = message.to_vec()?;
+
+        let mut conn = self.connect().await?;
+        let request = self.request(&message)?;
+        let end_of_stream = self.method == DohMethod::Get;
+
+        let (response, mut stream) = match conn.send_request(request, end_of_stream) {
+            Ok(sent) => sent,
+            Err(err) => {
+                // the connection is gone, the next query dials again
+                self.conn.lock().await.take();
+                return Err(h2_error(err));
+            }
+        };
+        if !end_of_stream {
+            stream.send_data(Bytes::from(message), true).map_err(h2_error)?;
+        }
+
+        let response = response.await.map_err(h2_error)?;
+        if !response.status().is_success() {
+            return Err(format!("DoH upstream {} answered {}", self.authority, response.status()).into());
+        }
+
+        let mut body = response.into_body();
+        let mut answer = BytesMut::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(h2_error)?;
+            if answer.len() + chunk.len() > u16::MAX as usize {
+                return Err(format!("DoH upstream {} answered more than a DNS message", self.authority).into());
+            }
+            body.flow_control().release_capacity(chunk.len()).map_err(h2_error)?;
+            answer.extend_from_slice(&chunk);
+        }
+
+        let mut answer = Message::from_bytes(&answer)?;
+        answer.set_id(id);
+        Ok(answer)
+    }
+
+    fn request(&self, message: &[u8]) -> Result<Request<()>, ProtoError> {
+        let builder = Request::builder()
+            .version(Version::HTTP_2)
+            .header(header::ACCEPT, MIME_APPLICATION_DNS);
+
+        let builder = match self.method {
+            DohMethod::Get => builder.method(Method::GET).uri(format!(
+                "https://{}{}?dns={}",
+                self.authority,
+                self.path,
+                BASE64URL_NOPAD.encode(message)
+            )),
+            DohMethod::Post => builder
+                .method(Method::POST)
+                .uri(format!("https://{}{}", self.authority, self.path))
+                .header(header::CONTENT_TYPE, MIME_APPLICATION_DNS)
+                .header(header::CONTENT_LENGTH, message.len()),
+        };
+
+        let mut request = builder
+            .body(())
+            .map_err(|err| ProtoError::from(format!("invalid DoH request, {}", err)))?;
+        for (name, value) in &self.headers {
+            request.headers_mut().insert(name.clone(), value.clone());
+        }
+        Ok(request)
+    }
+
+    async fn connect(&self) -> Result<SendRequest<Bytes>, ProtoError> {
+        let mut conn = self.conn.lock().await;
+
+        if let Some(send) = conn.as_ref() {
+            match send.clone().ready().await {
+                Ok(send) => return Ok(send),
+                Err(err) => debug!("DoH connection to {} closed, {}", self.authority, err),
+            }
+        }
+
+        let tcp = proxy::connect_tcp(self.addr, self.proxy.as_ref(), &self.connect_opts).await?;
+        let tls = TlsConnector::from(self.tls_config.clone())
+            .connect(self.server_name.clone(), tcp)
+            .await?;
+        let (send, connection) = h2::client::handshake(tls).await.map_err(h2_error)?;
+
+        let authority = self.authority.clone();
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                debug!("DoH connection to {} failed, {}", authority, err);
+            }
+        });
+
+        let send = send.ready().await.map_err(h2_error)?;
+        *conn = Some(send.clone());
+        Ok(send)
+    }
+}
+
+impl fmt::Debug for DohClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DohClient")
+            .field("addr", &self.addr)
+            .field("authority", &self.authority)
+            .field("path", &self.path)
+            .field("method", &self.method)
+            .field("headers", &self.headers.len())
+            .finish()
+    }
+}
+
+fn h2_error(err: h2::Error) -> ProtoError {
+    if err.is_io() {
+        err.into_io()
+            .map(ProtoError::from)
+            .unwrap_or_else(|| "DoH io error".into())
+    } else {
+        format!("DoH upstream failed, {}", err).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::RootCertStore;
+
+    use super::*;
+    use crate::dns_url::DnsUrl;
+
+    fn client(url: &str) -> DohClient {
+        let tls_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let url = DnsUrl::from_str(url).unwrap().try_into().unwrap();
+        DohClient::new(&url, Arc::new(tls_config), None, Default::default())
+    }
+
+    #[test]
+    fn test_doh_request_get() {
+        let client = client("https://1.1.1.1:8443/Custom/Path?method=GET")
+            .with_headers(&[("Authorization".to_owned(), "Bearer abc".to_owned())]);
+        assert_eq!(client.method, DohMethod::Get);
+
+        let request = client.request(&[0, 0, 1, 0]).unwrap();
+        assert_eq!(request.method(), Method::GET);
+        assert_eq!(request.uri(), "https://1.1.1.1:8443/Custom/Path?dns=AAABAA");
+        assert_eq!(request.headers()[header::ACCEPT], MIME_APPLICATION_DNS);
+        assert_eq!(request.headers()[header::AUTHORIZATION], "Bearer abc");
+        assert!(!request.headers().contains_key(header::CONTENT_TYPE));
+    }
+
+    #[test]
+    fn test_doh_request_post() {
+        let client = client("https://8.8.8.8/resolve").with_headers(&[
+            ("accept".to_owned(), "*/*".to_owned()),
+            ("bad header".to_owned(), "x".to_owned()),
+        ]);
+        assert_eq!(client.method, DohMethod::Post);
+        assert_eq!(client.headers.len(), 1);
+
+        let request = client.request(&[0, 0, 1, 0]).unwrap();
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.uri(), "https://8.8.8.8/resolve");
+        assert_eq!(request.headers()[header::ACCEPT], "*/*");
+        assert_eq!(request.headers()[header::CONTENT_TYPE], MIME_APPLICATION_DNS);
+        assert_eq!(request.headers()[header::CONTENT_LENGTH], "4");
+    }
+
+    #[test]
+    fn test_doh_method() {
+        assert_eq!("GET".parse::<DohMethod>(), Ok(DohMethod::Get));
+        assert_eq!("post".parse::<DohMethod>(), Ok(DohMethod::Post));
+        assert!("put".parse::<DohMethod>().is_err());
+        assert_eq!(DohMethod::Get.to_string(), "get");
+    }
+}
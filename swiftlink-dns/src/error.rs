@@ -29,7 +29,29 @@ pub enum LookupError {
 
 impl LookupError {
     pub fn is_nx_domain(&self) -> bool {
-        matches!(self, Self::ResponseCode(resc) if resc.eq(&ResponseCode::NXDomain))
+        match self {
+            Self::ResponseCode(resc) => resc.eq(&ResponseCode::NXDomain),
+            Self::ResolveError(err) => matches!(
+                err.kind(),
+                ResolveErrorKind::NoRecordsFound { response_code, .. } if response_code.eq(&ResponseCode::NXDomain)
+            ),
+            _ => false,
+        }
+    }
+
+    /// Whether no answer was had, e.g. the upstreams timed out or answered `SERVFAIL`, rather
+    /// than the name or its records not existing.
+    pub fn is_failure(&self) -> bool {
+        let answered = |code: &ResponseCode| matches!(code, ResponseCode::NoError | ResponseCode::NXDomain);
+        match self {
+            Self::NameExists => false,
+            Self::ResponseCode(code) => !answered(code),
+            Self::ResolveError(err) => match err.kind() {
+                ResolveErrorKind::NoRecordsFound { response_code, .. } => !answered(response_code),
+                _ => true,
+            },
+            Self::Io(_) => true,
+        }
     }
 
     #[inline]
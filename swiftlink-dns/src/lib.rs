@@ -1,14 +1,28 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{any::Any, net::SocketAddr, sync::Arc};
 
-pub use config::DnsConfig;
+use swiftlink_infra::extensions::Extensions;
+
+pub use config::{
+    BlockedResponse, DnsConfig, DnsConfigBuilder, DnsConfigError, DnsListenerConfig, HandleKind, NameServerGroupConfig,
+    NameServerInfo, PipelineHandle,
+};
+pub use dns_handle::{
+    BogusNxDomainHandle, DnsRequestHandle, DnsRequestHandleNext, DnsRequestHandler, DnsRequestHandlerBuilder,
+    FakeDnsHandle, ForwardHandle, StaticRecordsHandle, ZoneFileError,
+};
+pub use dns_url::DnsUrl;
 pub use libdns::server::ServerFuture;
-pub use resolver::{build_dns_resolver, DnsResolver};
+pub use proxy::{
+    open_tunnel, open_udp_association, probe_proxy, speedtest_proxy, DnsStream, OutboundDialer, ProxyConfig,
+    ProxyLatency, ProxyProtocol, ProxySpeed, ProxyStream, ProxyTls, ResolveStrategy, REDACTED,
+};
+pub use resolver::{build_dns_resolver, build_nameserver_policy, DnsResolver, NameServerPolicy};
 pub use server::{ServerHandle, ServerHandleBuilder};
 
 use crate::libdns::{
     proto::{
         op::{LowerQuery, Query},
-        rr::{Name, RecordType},
+        rr::{rdata::opt::ClientSubnet, Name, RecordType},
     },
     server::server::{Protocol, Request},
 };
@@ -17,6 +31,8 @@ mod client;
 mod config;
 mod dns_handle;
 mod dns_url;
+pub mod dnsmasq;
+mod doh;
 mod error;
 mod libdns;
 mod preset_ns;
@@ -24,11 +40,20 @@ mod proxy;
 mod resolver;
 mod rustls;
 mod server;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 
 /// Maximum TTL as defined in https://tools.ietf.org/html/rfc2181, 2147483647
 ///   Setting this to a value of 1 day, in seconds
 pub(crate) const MAX_TTL: u32 = 86400_u32;
 
+/// > An EDNS buffer size of 1232 bytes will avoid fragmentation on nearly all current networks.
+/// > https://dnsflagday.net/2020/
+pub(crate) const MAX_PAYLOAD_LEN: u16 = 1232;
+
+/// Largest UDP answer every client accepts, https://tools.ietf.org/html/rfc1035#section-2.3.4
+pub(crate) const MIN_PAYLOAD_LEN: u16 = 512;
+
 pub type DnsError = error::LookupError;
 pub type DnsResponse = libdns::resolver::lookup::Lookup;
 
@@ -109,18 +134,28 @@ impl From<Query> for DnsRequest {
     }
 }
 
+/// State of a request shared by the handles of a [`DnsRequestHandler`].
+///
+/// Besides the config, handles exchange values through its [`Extensions`], so a handle can leave
+/// a mark for the handles around it without changing this struct.
 pub struct DnsContext {
     cfg: Arc<DnsConfig>,
-    pub no_cache: bool,
-    pub background: bool,
+    extensions: Extensions,
 }
 
+/// The response must not be cached, e.g. it was answered from local data.
+#[derive(Debug, Clone, Copy)]
+pub struct NoCache;
+
+/// The request was issued in the background, e.g. to prefetch an expiring entry.
+#[derive(Debug, Clone, Copy)]
+pub struct Background;
+
 impl DnsContext {
     pub fn new(cfg: Arc<DnsConfig>) -> Self {
         DnsContext {
             cfg,
-            no_cache: false,
-            background: false,
+            extensions: Default::default(),
         }
     }
 
@@ -128,4 +163,62 @@ impl DnsContext {
     pub fn cfg(&self) -> &Arc<DnsConfig> {
         &self.cfg
     }
+
+    #[inline]
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    #[inline]
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    #[inline]
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.extensions.insert(value)
+    }
+
+    #[inline]
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.extensions.get()
+    }
+
+    #[inline]
+    pub fn get_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        self.extensions.get_mut()
+    }
+
+    #[inline]
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        self.extensions.remove()
+    }
+
+    #[inline]
+    pub fn contains<T: Any + Send + Sync>(&self) -> bool {
+        self.extensions.contains::<T>()
+    }
+
+    /// see [`NoCache`]
+    #[inline]
+    pub fn no_cache(&self) -> bool {
+        self.contains::<NoCache>()
+    }
+
+    #[inline]
+    pub fn set_no_cache(&mut self) {
+        self.insert(NoCache);
+    }
+
+    /// see [`Background`]
+    #[inline]
+    pub fn background(&self) -> bool {
+        self.contains::<Background>()
+    }
+
+    /// The client subnet to forward upstream instead of the configured one.
+    #[inline]
+    pub fn client_subnet(&self) -> Option<ClientSubnet> {
+        self.get::<ClientSubnet>().copied()
+    }
 }
@@ -1,7 +1,7 @@
-use std::sync::Arc;
+use std::{str::FromStr, sync::Arc};
 
 use enum_dispatch::enum_dispatch;
-use swiftlink_infra::net::ConnectOpts;
+use swiftlink_infra::{log::warn, net::ConnectOpts};
 
 use crate::{
     client::DnsClient,
@@ -10,11 +10,18 @@ use crate::{
         proto::{
             error::ProtoResult,
             op::Query,
-            rr::{rdata::opt::ClientSubnet, Record, RecordType},
+            rr::{
+                rdata::{opt::ClientSubnet, svcb::SvcParamValue},
+                LowerName, RData, Record, RecordType,
+            },
+        },
+        resolver::{
+            config::ResolverOpts, error::ResolveErrorKind, lookup::Lookup, lookup_ip::LookupIp, IntoName, Name,
+            TryParseIp,
         },
-        resolver::{config::ResolverOpts, lookup::Lookup, lookup_ip::LookupIp, IntoName, Name, TryParseIp},
     },
-    DnsConfig, MAX_TTL,
+    proxy::OutboundDialer,
+    DnsConfig, NameServerInfo, MAX_TTL,
 };
 
 #[derive(Clone)]
@@ -87,6 +94,10 @@ pub trait GenericResolverExt {
     /// # Arguments
     /// * `host` - string hostname, if this is an invalid hostname, an error will be returned.
     async fn lookup_ip<N: IntoName + TryParseIp + Send>(&self, host: N) -> Result<LookupIp, LookupError>;
+
+    /// Looks up the ECHConfigList `host` publishes in its HTTPS record, for TLS clients encrypting
+    /// their ClientHello. The record with the highest priority carrying an `ech` parameter wins.
+    async fn lookup_ech_config<N: IntoName + Send>(&self, host: N) -> Result<Option<Vec<u8>>, LookupError>;
 }
 
 #[async_trait::async_trait]
@@ -163,6 +174,28 @@ where
         }
         .map(|lookup| lookup.into())
     }
+
+    async fn lookup_ech_config<N: IntoName + Send>(&self, host: N) -> Result<Option<Vec<u8>>, LookupError> {
+        let lookup = self.lookup(host.into_name()?, RecordType::HTTPS).await?;
+
+        let ech_config = lookup
+            .iter()
+            .filter_map(|rdata| match rdata {
+                // priority 0 is the alias form, without service parameters
+                RData::HTTPS(https) if https.svc_priority() > 0 => Some(https),
+                _ => None,
+            })
+            .filter_map(|https| {
+                https.svc_params().iter().find_map(|(_, value)| match value {
+                    SvcParamValue::EchConfig(ech_config) => Some((https.svc_priority(), ech_config.0.clone())),
+                    _ => None,
+                })
+            })
+            .min_by_key(|(priority, _)| *priority)
+            .map(|(_, ech_config)| ech_config);
+
+        Ok(ech_config)
+    }
 }
 
 /// Abstract DNS resolver
@@ -177,21 +210,149 @@ pub struct DnsResolver {
     client: Arc<DnsClient>,
 }
 
+impl DnsResolver {
+    /// ECHConfigList published by `host`, see [`GenericResolverExt::lookup_ech_config`].
+    pub async fn lookup_ech_config(&self, host: &str) -> Result<Option<Vec<u8>>, LookupError> {
+        self.client.lookup_ech_config(host).await
+    }
+
+    /// Whether an upstream server answers a query for the root name servers, with any response
+    /// code. `false` without upstream servers.
+    pub async fn probe_upstream(&self) -> bool {
+        match self.client.lookup(Name::root(), RecordType::NS).await {
+            Ok(_) | Err(LookupError::NameExists | LookupError::ResponseCode(_)) => true,
+            Err(LookupError::ResolveError(err)) => matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. }),
+            Err(_) => false,
+        }
+    }
+}
+
 impl Into<Arc<DnsClient>> for DnsResolver {
     fn into(self) -> Arc<DnsClient> {
         self.client.to_owned()
     }
 }
 
-pub async fn build_dns_resolver(dns: &DnsConfig, connect_opts: &ConnectOpts) -> DnsResolver {
+/// Builds the resolver of the `nameserver` group. `dialer` dials the nameservers whose `-proxy`
+/// names an outbound of the app rather than one of `proxy_servers`.
+pub async fn build_dns_resolver(
+    dns: &DnsConfig,
+    connect_opts: &ConnectOpts,
+    dialer: Option<Arc<dyn OutboundDialer>>,
+) -> DnsResolver {
     if !dns.enabled() {
         let client: Arc<DnsClient> = Arc::new(DnsClient::builder().build().await);
         return DnsResolver { client };
     }
 
-    let servers = dns.servers();
-    let proxies = dns.proxies().clone();
+    let client = Arc::new(build_client(dns, dns.servers(), connect_opts, dialer.as_ref()).await);
+
+    DnsResolver { client }
+}
+
+/// How the queries of a nameserver group are sent upstream.
+#[derive(Debug, Clone)]
+struct Upstream {
+    client: Arc<DnsClient>,
+    /// client of the AAAA queries, `client` if `None`
+    aaaa: Option<Arc<DnsClient>>,
+    ipv6: bool,
+}
+
+/// Upstream clients of the domains in `nameserver_policy`, see [`ForwardHandle`](crate::ForwardHandle).
+///
+/// Also holds the AAAA options of the `nameserver` group, whose client is the one of the handle.
+#[derive(Debug)]
+pub struct NameServerPolicy {
+    /// the most specific domain first
+    groups: Vec<(LowerName, Upstream)>,
+    aaaa: Option<Arc<DnsClient>>,
+    ipv6: bool,
+}
+
+impl Default for NameServerPolicy {
+    fn default() -> Self {
+        Self {
+            groups: Vec::new(),
+            aaaa: None,
+            ipv6: true,
+        }
+    }
+}
+
+impl NameServerPolicy {
+    /// The client of `record_type` queries of `name`, `default` unless a group of the policy
+    /// matches. `None` if the queries are answered without records.
+    pub(crate) fn client_of<'a>(
+        &'a self,
+        name: &LowerName,
+        record_type: RecordType,
+        default: &'a Arc<DnsClient>,
+    ) -> Option<&'a Arc<DnsClient>> {
+        let (client, aaaa, ipv6) = match self.groups.iter().find(|(domain, _)| domain.zone_of(name)) {
+            Some((_, upstream)) => (&upstream.client, upstream.aaaa.as_ref(), upstream.ipv6),
+            None => (default, self.aaaa.as_ref(), self.ipv6),
+        };
+        match record_type {
+            RecordType::AAAA if !ipv6 => None,
+            RecordType::AAAA => Some(aaaa.unwrap_or(client)),
+            _ => Some(client),
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty() && self.aaaa.is_none() && self.ipv6
+    }
+}
+
+/// Builds the clients of `nameserver_policy` and `aaaa_nameserver`, `dialer` as in [`build_dns_resolver`].
+pub async fn build_nameserver_policy(
+    dns: &DnsConfig,
+    connect_opts: &ConnectOpts,
+    dialer: Option<Arc<dyn OutboundDialer>>,
+) -> NameServerPolicy {
+    let mut groups = Vec::new();
+    for (domain, group) in dns.nameserver_policy() {
+        let Ok(mut domain) = Name::from_str(domain) else {
+            warn!("invalid domain {} in nameserver_policy, ignored", domain);
+            continue;
+        };
+        domain.set_fqdn(true);
+        let upstream = Upstream {
+            client: Arc::new(build_client(dns, &group.nameserver, connect_opts, dialer.as_ref()).await),
+            aaaa: build_aaaa_client(dns, &group.aaaa_nameserver, connect_opts, dialer.as_ref()).await,
+            ipv6: group.ipv6,
+        };
+        groups.push((LowerName::from(domain), upstream));
+    }
+    groups.sort_by_key(|(domain, _)| std::cmp::Reverse(domain.num_labels()));
+
+    NameServerPolicy {
+        groups,
+        aaaa: build_aaaa_client(dns, dns.aaaa_servers(), connect_opts, dialer.as_ref()).await,
+        ipv6: dns.ipv6(),
+    }
+}
+
+async fn build_aaaa_client(
+    dns: &DnsConfig,
+    servers: &[NameServerInfo],
+    connect_opts: &ConnectOpts,
+    dialer: Option<&Arc<dyn OutboundDialer>>,
+) -> Option<Arc<DnsClient>> {
+    if servers.is_empty() {
+        return None;
+    }
+    Some(Arc::new(build_client(dns, servers, connect_opts, dialer).await))
+}
 
+async fn build_client(
+    dns: &DnsConfig,
+    servers: &[NameServerInfo],
+    connect_opts: &ConnectOpts,
+    dialer: Option<&Arc<dyn OutboundDialer>>,
+) -> DnsClient {
     let mut builder = DnsClient::builder();
     builder = builder.add_servers(servers.to_vec());
 
@@ -201,9 +362,108 @@ pub async fn build_dns_resolver(dns: &DnsConfig, connect_opts: &ConnectOpts) ->
         builder = builder.with_client_subnet(subnet);
     }
 
-    builder = builder.with_proxies(proxies);
+    builder = builder.with_proxies(dns.proxies().clone());
+    if let Some(dialer) = dialer {
+        builder = builder
+            .with_dialer(dialer.clone())
+            .with_route_upstream(dns.route_upstream());
+    }
 
-    let client = Arc::new(builder.build().await);
+    builder.build().await
+}
 
-    DnsResolver { client }
+#[cfg(test)]
+mod tests {
+    use crate::{
+        libdns::proto::rr::rdata::{
+            svcb::{EchConfig, SvcParamKey, SVCB},
+            HTTPS,
+        },
+        test_util::MockDnsServer,
+        NameServerGroupConfig,
+    };
+
+    use super::*;
+
+    fn https_record(priority: u16, ech_config: Option<&[u8]>) -> Record {
+        let mut params = vec![];
+        if let Some(ech_config) = ech_config {
+            params.push((
+                SvcParamKey::EchConfig,
+                SvcParamValue::EchConfig(EchConfig(ech_config.to_vec())),
+            ));
+        }
+        let svcb = SVCB::new(priority, Name::root(), params);
+        Record::from_rdata(
+            Name::from_ascii("ech.example.com.").unwrap(),
+            300,
+            RData::HTTPS(HTTPS(svcb)),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_lookup_ech_config() {
+        let upstream = MockDnsServer::start().await.unwrap();
+        upstream
+            .record(https_record(2, Some(b"fallback")))
+            .record(https_record(1, None))
+            .record(https_record(1, Some(b"preferred")));
+
+        let client = DnsClient::builder().add_server(upstream.dns_url()).build().await;
+
+        let ech_config = client.lookup_ech_config("ech.example.com.").await.unwrap();
+        assert_eq!(ech_config.as_deref(), Some(&b"preferred"[..]));
+
+        assert!(client.lookup_ech_config("none.example.com.").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_probe_upstream() {
+        let upstream = MockDnsServer::start().await.unwrap();
+        let client = Arc::new(DnsClient::builder().add_server(upstream.dns_url()).build().await);
+
+        // NXDomain is an answer
+        assert!(DnsResolver { client }.probe_upstream().await);
+
+        let client = Arc::new(DnsClient::builder().build().await);
+        assert!(!DnsResolver { client }.probe_upstream().await);
+    }
+
+    #[tokio::test]
+    async fn test_nameserver_policy_aaaa() {
+        let upstream = MockDnsServer::start().await.unwrap();
+        let aaaa_upstream = MockDnsServer::start().await.unwrap();
+        upstream.answer("www.example.com", "2001:db8::1".parse().unwrap(), 60);
+        aaaa_upstream.answer("www.example.com", "2001:db8::2".parse().unwrap(), 60);
+
+        let lan = NameServerGroupConfig {
+            nameserver: vec![upstream.dns_url().into()],
+            ipv6: false,
+            ..Default::default()
+        };
+        let dns = DnsConfig::builder()
+            .nameserver(upstream.dns_url())
+            .aaaa_nameserver(aaaa_upstream.dns_url())
+            .nameserver_group("lan", lan)
+            .build()
+            .unwrap();
+        let policy = build_nameserver_policy(&dns, &ConnectOpts::default(), None).await;
+        let default = Arc::new(DnsClient::builder().add_server(upstream.dns_url()).build().await);
+
+        let name = LowerName::from(Name::from_ascii("www.example.com.").unwrap());
+        let client = policy.client_of(&name, RecordType::A, &default).unwrap();
+        assert!(Arc::ptr_eq(client, &default));
+
+        let client = policy.client_of(&name, RecordType::AAAA, &default).unwrap();
+        let lookup = client.lookup(Name::from(name), RecordType::AAAA).await.unwrap();
+        assert_eq!(
+            lookup.iter().filter_map(|rdata| rdata.ip_addr()).collect::<Vec<_>>(),
+            vec!["2001:db8::2".parse::<std::net::IpAddr>().unwrap()]
+        );
+
+        let name = LowerName::from(Name::from_ascii("router.lan.").unwrap());
+        assert!(policy.client_of(&name, RecordType::AAAA, &default).is_none());
+        let client = policy.client_of(&name, RecordType::A, &default).unwrap();
+        assert!(!Arc::ptr_eq(client, &default));
+    }
 }
@@ -5,7 +5,7 @@ use std::{
     sync::{Arc, Mutex},
 };
 
-use swiftlink_infra::{fakedns, log::*};
+use swiftlink_infra::{dns_failures::DnsFailures, fakedns, log::*, watchdog};
 
 use crate::{
     client::DnsClient,
@@ -14,24 +14,33 @@ use crate::{
     libdns::{
         proto::{
             op::{Edns, Header, MessageType, OpCode, ResponseCode},
-            rr::Record,
+            rr::{Record, RecordType},
+            serialize::binary::BinEncoder,
         },
+        resolver::lookup::Lookup,
         server::{
             authority::{
-                AuthLookup, EmptyLookup, LookupObject, LookupOptions, MessageResponse,
-                MessageResponseBuilder, ZoneType,
+                AuthLookup, EmptyLookup, LookupObject, LookupOptions, MessageResponse, MessageResponseBuilder, ZoneType,
             },
-            server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
+            server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo},
             store::forwarder::ForwardLookup,
         },
     },
-    DnsConfig, DnsRequest,
+    resolver::NameServerPolicy,
+    DnsConfig, DnsRequest, HandleKind, MAX_PAYLOAD_LEN, MIN_PAYLOAD_LEN,
 };
 
+/// TTL of the answers of the `address` option
+const LOCAL_ADDRESS_TTL: u32 = 60;
+
 pub struct ServerHandleBuilder {
     config: Arc<DnsConfig>,
     client: Arc<DnsClient>,
     fakedns: Option<Arc<Mutex<fakedns::FakeDns>>>,
+    blocked: Option<Arc<fakedns::BlockedDomains>>,
+    static_records: Option<StaticRecordsHandle>,
+    policy: NameServerPolicy,
+    failures: Option<Arc<DnsFailures>>,
 }
 
 impl ServerHandleBuilder {
@@ -40,50 +49,175 @@ impl ServerHandleBuilder {
             config,
             client,
             fakedns: None,
+            blocked: None,
+            static_records: None,
+            policy: NameServerPolicy::default(),
+            failures: None,
         }
     }
 
+    /// Answers the names of the loaded zones, before fake ips and forwarding unless `pipeline` says
+    /// otherwise.
+    pub fn with_static_records(mut self, static_records: StaticRecordsHandle) -> Self {
+        self.static_records = Some(static_records);
+        self
+    }
+
+    /// Forwards the domains of `policy` to their own nameservers.
+    pub fn with_nameserver_policy(mut self, policy: NameServerPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
     pub fn with_fakedns(mut self, fakedns: Arc<Mutex<fakedns::FakeDns>>) -> Self {
         self.fakedns = Some(fakedns);
         self
     }
 
-    pub fn build(self) -> ServerHandle {
-        let mut builder = DnsRequestHandlerBuilder::new();
+    /// The domains the router rejects, answered as `fake_ip_blocked` says if it's set.
+    pub fn with_blocked_domains(mut self, blocked: Arc<fakedns::BlockedDomains>) -> Self {
+        self.blocked = Some(blocked);
+        self
+    }
 
-        if let Some(fakedns) = self.fakedns {
-            builder = builder.with(FakeDnsHandle::new(fakedns));
-        }
+    /// Keeps the failed forwarded lookups in `failures`.
+    pub fn with_failures(mut self, failures: Arc<DnsFailures>) -> Self {
+        self.failures = Some(failures);
+        self
+    }
 
-        let handler = Arc::new(
-            builder
-                .with(ForwardHandle::new(self.client))
-                .build(self.config),
-        );
+    pub fn build(self) -> ServerHandle {
+        let max_udp_payload = self.config.max_udp_payload();
+        let minimal_responses = self.config.minimal_responses();
+        let max_answers = self.config.max_answers();
 
-        ServerHandle { handler }
+        let mut builder = DnsRequestHandlerBuilder::new();
+        let mut static_records = self.static_records;
+        let mut fakedns = self.fakedns;
+        let mut policy = Some(self.policy);
+        for handle in self.config.pipeline() {
+            match handle.kind {
+                HandleKind::Static => {
+                    let mut static_records = static_records.take().unwrap_or_default();
+                    let ttl = handle.ttl.unwrap_or(LOCAL_ADDRESS_TTL);
+                    for (domain, addrs) in self.config.address() {
+                        if let Err(err) = static_records.add_address(domain, addrs, ttl) {
+                            warn!("invalid domain {} in address, ignored: {}", domain, err);
+                        }
+                    }
+                    if !static_records.is_empty() {
+                        builder = builder.with(static_records);
+                    }
+                }
+                HandleKind::Fakedns => {
+                    if let Some(fakedns) = fakedns.take() {
+                        let mut handle = FakeDnsHandle::new(fakedns);
+                        if let (Some(blocked), Some(response)) = (self.blocked.clone(), self.config.fakeip_blocked()) {
+                            handle = handle.with_blocked(blocked, response);
+                        }
+                        builder = builder.with(handle);
+                    }
+                }
+                HandleKind::BogusNxdomain => {
+                    if !self.config.bogus_nxdomain().is_empty() {
+                        builder = builder.with(BogusNxDomainHandle::new(self.config.bogus_nxdomain().iter().copied()));
+                    }
+                }
+                HandleKind::Forward => {
+                    if let Some(policy) = policy.take() {
+                        let mut handle = ForwardHandle::new(self.client.clone()).with_policy(policy);
+                        if let Some(failures) = self.failures.clone() {
+                            handle = handle.with_failures(failures);
+                        }
+                        builder = builder.with(handle);
+                    }
+                }
+            }
+        }
+        let handler = Arc::new(builder.build(self.config));
+
+        ServerHandle {
+            handler,
+            max_udp_payload,
+            minimal_responses,
+            max_answers,
+            tag: None,
+        }
     }
 }
 
+#[derive(Clone)]
 pub struct ServerHandle {
     handler: Arc<DnsRequestHandler>,
+    /// upper bound of the EDNS buffer size negotiated with clients
+    max_udp_payload: u16,
+    /// see `minimal_responses` of [`DnsConfig`]
+    minimal_responses: bool,
+    /// see `max_answers` of [`DnsConfig`]
+    max_answers: Option<usize>,
+    /// the inbound the queries came through, logged with them
+    tag: Option<&'static str>,
 }
 
 impl ServerHandle {
     pub fn new(handler: Arc<DnsRequestHandler>) -> Self {
-        Self { handler }
+        Self {
+            handler,
+            max_udp_payload: MAX_PAYLOAD_LEN,
+            minimal_responses: false,
+            max_answers: None,
+            tag: None,
+        }
+    }
+
+    /// Logs the queries received as coming through the inbound `tag`.
+    pub fn with_tag(mut self, tag: &'static str) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+
+    /// Drops the answers the response policies leave out, see `minimal_responses` and
+    /// `max_answers`.
+    fn trim_answers(&self, lookup: Lookup) -> Lookup {
+        let query_type = lookup.query().query_type();
+        let minimal = self.minimal_responses && query_type != RecordType::ANY;
+        let max_answers = self.max_answers.unwrap_or(usize::MAX);
+        let keep = |record: &&Record| {
+            !minimal || record.record_type() == query_type || record.record_type() == RecordType::CNAME
+        };
+        if lookup.records().len() <= max_answers && lookup.records().iter().all(|r| keep(&r)) {
+            return lookup;
+        }
+
+        let records: Vec<_> = lookup.record_iter().filter(keep).take(max_answers).cloned().collect();
+        Lookup::new_with_deadline(lookup.query().clone(), records.into(), lookup.valid_until())
     }
 }
 
 #[async_trait::async_trait]
 impl RequestHandler for ServerHandle {
-    async fn handle_request<R: ResponseHandler>(
-        &self,
-        request: &Request,
-        mut response_handle: R,
-    ) -> ResponseInfo {
+    async fn handle_request<R: ResponseHandler>(&self, request: &Request, mut response_handle: R) -> ResponseInfo {
+        // shed load while the process is short of fds or memory
+        if let Some(watchdog) = watchdog::watchdog().filter(|w| w.is_overloaded()) {
+            watchdog.reject();
+            let response = MessageResponseBuilder::from_message_request(request);
+            return response_handle
+                .send_response(response.error_msg(request.header(), ResponseCode::Refused))
+                .await
+                .unwrap_or_else(|_| ResponseInfo::serve_failed());
+        }
+
         let result = match request.message_type() {
             MessageType::Query => match request.op_code() {
+                // a forwarder has no zones to transfer, and listing them helps nobody on the LAN
+                OpCode::Query if matches!(request.query().query_type(), RecordType::AXFR | RecordType::IXFR) => {
+                    debug!("refused zone transfer {} from {}", request.query(), request.src());
+                    let response = MessageResponseBuilder::from_message_request(request);
+
+                    response_handle
+                        .send_response(response.error_msg(request.header(), ResponseCode::Refused))
+                        .await
+                }
                 OpCode::Query => {
                     let response_edns: Option<Edns>;
 
@@ -100,7 +234,7 @@ impl RequestHandler for ServerHandle {
                         // TODO: what version are we?
                         let our_version = 0;
                         resp_edns.set_dnssec_ok(true);
-                        resp_edns.set_max_payload(req_edns.max_payload().max(512));
+                        resp_edns.set_max_payload(req_edns.max_payload().clamp(MIN_PAYLOAD_LEN, self.max_udp_payload));
                         resp_edns.set_version(our_version);
                         if req_edns.version() > our_version {
                             warn!(
@@ -133,11 +267,12 @@ impl RequestHandler for ServerHandle {
                     }
 
                     debug!(
-                        "query received: {} {} {} client: {}",
+                        "query received: {} {} {} client: {}{}",
                         request.id(),
                         request.query(),
                         request.query().query_type(),
-                        request.src()
+                        request.src(),
+                        self.tag.map(|tag| format!(" inbound: {}", tag)).unwrap_or_default()
                     );
 
                     let info = async {
@@ -151,10 +286,7 @@ impl RequestHandler for ServerHandle {
                             // TODO: need remove this log?
                             // log algorithms being requested
                             if lookup_options.is_dnssec() {
-                                info!(
-                                    "request: {} lookup_options: {:?}",
-                                    request_id, lookup_options
-                                );
+                                info!("request: {} lookup_options: {:?}", request_id, lookup_options);
                             }
 
                             let mut response_header = Header::response_from_request(request_header);
@@ -169,7 +301,7 @@ impl RequestHandler for ServerHandle {
 
                                 let lookup_result: Result<Box<dyn LookupObject>, LookupError> =
                                     match self.handler.search(req).await {
-                                        Ok(lookup) => Ok(Box::new(ForwardLookup(lookup))),
+                                        Ok(lookup) => Ok(Box::new(ForwardLookup(self.trim_answers(lookup)))),
                                         Err(err) => Err(err),
                                     };
 
@@ -180,6 +312,7 @@ impl RequestHandler for ServerHandle {
                                 future,
                                 request_header,
                                 &mut response_header,
+                                self.minimal_responses,
                             )
                             .await;
 
@@ -187,6 +320,19 @@ impl RequestHandler for ServerHandle {
                         }
                         .await;
 
+                        // without EDNS hickory allows 4096 bytes UDP answers, enforce the 512 bytes
+                        // limit of RFC 1035 so old stub resolvers see the TC bit and retry over TCP
+                        let (response_header, sections) = if response_edns.is_none()
+                            && matches!(request.protocol(), Protocol::Udp)
+                            && exceeds_payload(request, response_header, &sections, MIN_PAYLOAD_LEN)
+                        {
+                            let mut response_header = response_header;
+                            response_header.set_truncated(true);
+                            (response_header, LookupSections::empty())
+                        } else {
+                            (response_header, sections)
+                        };
+
                         let response = MessageResponseBuilder::from_message_request(request).build(
                             response_header,
                             sections.answers.iter(),
@@ -195,9 +341,7 @@ impl RequestHandler for ServerHandle {
                             sections.additionals.iter(),
                         );
 
-                        let result =
-                            send_response(response_edns.clone(), response, response_handle.clone())
-                                .await;
+                        let result = send_response(response_edns.clone(), response, response_handle.clone()).await;
 
                         match result {
                             Err(e) => {
@@ -212,11 +356,17 @@ impl RequestHandler for ServerHandle {
                     Ok(info)
                 }
                 c => {
-                    warn!("unimplemented op_code: {:?}", c);
+                    // logged at debug, devices on the LAN can send these as often as they like
+                    debug!("unsupported op_code {:?} from {}", c, request.src());
+                    let code = match c {
+                        // dynamic updates are refused by policy, RFC 2136 section 3
+                        OpCode::Update => ResponseCode::Refused,
+                        _ => ResponseCode::NotImp,
+                    };
                     let response = MessageResponseBuilder::from_message_request(request);
 
                     response_handle
-                        .send_response(response.error_msg(request.header(), ResponseCode::NotImp))
+                        .send_response(response.error_msg(request.header(), code))
                         .await
                 }
             },
@@ -244,12 +394,14 @@ async fn send_forwarded_response(
     future: impl Future<Output = Result<Box<dyn LookupObject>, LookupError>>,
     request_header: &Header,
     response_header: &mut Header,
+    minimal: bool,
 ) -> LookupSections {
     response_header.set_recursion_available(true);
     response_header.set_authoritative(false);
 
     // Don't perform the recursive query if this is disabled...
-    let answers = if !request_header.recursion_desired() {
+    let mut soa: Box<dyn LookupObject> = Box::<AuthLookup>::default();
+    let answers: Box<dyn LookupObject> = if !request_header.recursion_desired() {
         // cancel the future??
         // future.cancel();
         drop(future);
@@ -267,15 +419,14 @@ async fn send_forwarded_response(
                     response_header.set_response_code(ResponseCode::NXDomain);
                 }
 
-                let res: Box<dyn LookupObject> = match e.as_soa() {
-                    Some(soa) => Box::new(ForwardLookup(soa)),
-                    None => {
-                        debug!("error resolving: {}", e);
-                        Box::new(EmptyLookup)
-                    }
-                };
+                // the SOA of negative answers goes to the authority section
+                match e.as_soa() {
+                    Some(lookup) if !minimal => soa = Box::new(ForwardLookup(lookup)),
+                    Some(_) => {}
+                    None => debug!("error resolving: {}", e),
+                }
 
-                res
+                Box::new(EmptyLookup)
             }
             Ok(rsp) => rsp,
         }
@@ -284,7 +435,7 @@ async fn send_forwarded_response(
     LookupSections {
         answers,
         ns: Box::<AuthLookup>::default(),
-        soa: Box::<AuthLookup>::default(),
+        soa,
         additionals: Box::<AuthLookup>::default(),
     }
 }
@@ -296,9 +447,39 @@ struct LookupSections {
     additionals: Box<dyn LookupObject>,
 }
 
+impl LookupSections {
+    fn empty() -> Self {
+        Self {
+            answers: Box::new(EmptyLookup),
+            ns: Box::<AuthLookup>::default(),
+            soa: Box::<AuthLookup>::default(),
+            additionals: Box::<AuthLookup>::default(),
+        }
+    }
+}
+
+/// Whether the response doesn't fit into `max_payload` bytes.
+fn exceeds_payload(request: &Request, response_header: Header, sections: &LookupSections, max_payload: u16) -> bool {
+    let mut buffer = Vec::with_capacity(max_payload as usize);
+    let mut encoder = BinEncoder::new(&mut buffer);
+    encoder.set_max_size(max_payload);
+
+    MessageResponseBuilder::from_message_request(request)
+        .build(
+            response_header,
+            sections.answers.iter(),
+            sections.ns.iter(),
+            sections.soa.iter(),
+            sections.additionals.iter(),
+        )
+        .destructive_emit(&mut encoder)
+        .map(|info| info.truncated())
+        .unwrap_or_default()
+}
+
 async fn send_response<'a, R: ResponseHandler>(
-    _response_edns: Option<Edns>,
-    response: MessageResponse<
+    response_edns: Option<Edns>,
+    mut response: MessageResponse<
         '_,
         'a,
         impl Iterator<Item = &'a Record> + Send + 'a,
@@ -308,22 +489,28 @@ async fn send_response<'a, R: ResponseHandler>(
     >,
     mut response_handle: R,
 ) -> io::Result<ResponseInfo> {
-    #[cfg(feature = "dnssec")]
-    if let Some(mut resp_edns) = response_edns {
-        // set edns DAU and DHU
-        // send along the algorithms which are supported by this authority
-        let mut algorithms = SupportedAlgorithms::default();
-        algorithms.set(Algorithm::RSASHA256);
-        algorithms.set(Algorithm::ECDSAP256SHA256);
-        algorithms.set(Algorithm::ECDSAP384SHA384);
-        algorithms.set(Algorithm::ED25519);
-
-        let dau = EdnsOption::DAU(algorithms);
-        let dhu = EdnsOption::DHU(algorithms);
-
-        resp_edns.options_mut().insert(dau);
-        resp_edns.options_mut().insert(dhu);
+    if let Some(resp_edns) = response_edns {
+        #[cfg(feature = "dnssec")]
+        let resp_edns = {
+            let mut resp_edns = resp_edns;
+
+            // set edns DAU and DHU
+            // send along the algorithms which are supported by this authority
+            let mut algorithms = SupportedAlgorithms::default();
+            algorithms.set(Algorithm::RSASHA256);
+            algorithms.set(Algorithm::ECDSAP256SHA256);
+            algorithms.set(Algorithm::ECDSAP384SHA384);
+            algorithms.set(Algorithm::ED25519);
+
+            let dau = EdnsOption::DAU(algorithms);
+            let dhu = EdnsOption::DHU(algorithms);
+
+            resp_edns.options_mut().insert(dau);
+            resp_edns.options_mut().insert(dhu);
+            resp_edns
+        };
 
+        // hickory limits UDP answers to the max payload of the response EDNS
         response.set_edns(resp_edns);
     }
 
@@ -353,7 +540,7 @@ fn lookup_options_for_edns(edns: Option<&Edns>) -> LookupOptions {
     }
 }
 
-trait ServeFaild {
+pub(crate) trait ServeFaild {
     fn serve_failed() -> Self;
 }
 
@@ -364,3 +551,207 @@ impl ServeFaild for ResponseInfo {
         header.into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use tokio::net::UdpSocket;
+
+    use crate::{
+        libdns::{
+            proto::{
+                op::{Message, Query},
+                rr::{rdata, Name, RData},
+            },
+            server::ServerFuture,
+        },
+        test_util::MockDnsServer,
+        PipelineHandle,
+    };
+
+    use super::*;
+
+    async fn start_server(config: DnsConfig, upstream: &MockDnsServer) -> SocketAddr {
+        let client = DnsClient::builder().add_server(upstream.dns_url()).build().await;
+        let handle = ServerHandleBuilder::new(Arc::new(config), Arc::new(client)).build();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let mut server = ServerFuture::new(handle);
+        server.register_socket(socket);
+        tokio::spawn(async move {
+            let _ = server.block_until_done().await;
+        });
+
+        addr
+    }
+
+    async fn query(server: SocketAddr, max_payload: Option<u16>) -> Message {
+        let mut message = Message::new();
+        message
+            .add_query(Query::query(
+                Name::from_ascii("large.example.com.").unwrap(),
+                RecordType::A,
+            ))
+            .set_id(1)
+            .set_recursion_desired(true);
+        if let Some(max_payload) = max_payload {
+            message
+                .extensions_mut()
+                .get_or_insert_with(Edns::new)
+                .set_max_payload(max_payload);
+        }
+
+        exchange(server, &message).await
+    }
+
+    async fn exchange(server: SocketAddr, message: &Message) -> Message {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(&message.to_vec().unwrap(), server).await.unwrap();
+
+        let mut buf = [0u8; 4096];
+        let (n, _) = socket.recv_from(&mut buf).await.unwrap();
+        Message::from_vec(&buf[..n]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_server_udp_payload_negotiation() {
+        let upstream = MockDnsServer::start().await.unwrap();
+        // 40 A records don't fit into 512 bytes
+        for i in 1..=40 {
+            upstream.answer("large.example.com", IpAddr::from([10, 0, 0, i]), 60);
+        }
+
+        let server = start_server(DnsConfig::default(), &upstream).await;
+
+        let res = query(server, None).await;
+        assert!(res.truncated());
+        assert!(res.answers().is_empty());
+
+        let res = query(server, Some(4096)).await;
+        assert!(!res.truncated());
+        assert_eq!(res.answers().len(), 40);
+        assert_eq!(res.extensions().as_ref().unwrap().max_payload(), 1232);
+
+        let config = DnsConfig::builder().max_udp_payload(512).build().unwrap();
+        let server = start_server(config, &upstream).await;
+
+        let res = query(server, Some(4096)).await;
+        assert!(res.truncated());
+        assert_eq!(res.extensions().as_ref().unwrap().max_payload(), 512);
+    }
+
+    #[tokio::test]
+    async fn test_server_refuses_transfers_and_updates() {
+        let upstream = MockDnsServer::start().await.unwrap();
+        let server = start_server(DnsConfig::default(), &upstream).await;
+
+        let mut message = Message::new();
+        message
+            .add_query(Query::query(
+                Name::from_ascii("example.com.").unwrap(),
+                RecordType::AXFR,
+            ))
+            .set_id(1);
+        assert_eq!(exchange(server, &message).await.response_code(), ResponseCode::Refused);
+
+        message.queries_mut()[0].set_query_type(RecordType::A);
+        message.set_op_code(OpCode::Update);
+        assert_eq!(exchange(server, &message).await.response_code(), ResponseCode::Refused);
+        message.set_op_code(OpCode::Notify);
+        assert_eq!(exchange(server, &message).await.response_code(), ResponseCode::NotImp);
+
+        // nothing was forwarded
+        assert_eq!(upstream.queries(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_server_max_answers() {
+        let upstream = MockDnsServer::start().await.unwrap();
+        for i in 1..=40 {
+            upstream.answer("large.example.com", IpAddr::from([10, 0, 0, i]), 60);
+        }
+
+        let config = DnsConfig::builder().max_answers(10).build().unwrap();
+        let server = start_server(config, &upstream).await;
+
+        let res = query(server, Some(4096)).await;
+        assert!(!res.truncated());
+        assert_eq!(res.answers().len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_server_pipeline() {
+        let upstream = MockDnsServer::start().await.unwrap();
+        upstream.answer("large.example.com", IpAddr::from([10, 0, 0, 1]), 60);
+        let local = IpAddr::from([192, 168, 1, 1]);
+        let answer = |res: &Message| match res.answers()[0].data() {
+            Some(RData::A(a)) => (IpAddr::V4(a.0), res.answers()[0].ttl()),
+            data => panic!("unexpected answer {:?}", data),
+        };
+
+        let config = DnsConfig::builder()
+            .address("large.example.com", vec![local])
+            .build()
+            .unwrap();
+        let server = start_server(config, &upstream).await;
+        assert_eq!(answer(&query(server, None).await), (local, LOCAL_ADDRESS_TTL));
+
+        let config = DnsConfig::builder()
+            .address("large.example.com", vec![local])
+            .pipeline([
+                PipelineHandle {
+                    kind: HandleKind::Static,
+                    ttl: Some(300),
+                },
+                HandleKind::Forward.into(),
+            ])
+            .build()
+            .unwrap();
+        let server = start_server(config, &upstream).await;
+        assert_eq!(answer(&query(server, None).await), (local, 300));
+        assert_eq!(upstream.queries(), 0);
+
+        // without static, the address option is left out
+        let config = DnsConfig::builder()
+            .address("large.example.com", vec![local])
+            .pipeline([HandleKind::Forward.into()])
+            .build()
+            .unwrap();
+        let server = start_server(config, &upstream).await;
+        assert_eq!(answer(&query(server, None).await).0, IpAddr::from([10, 0, 0, 1]));
+    }
+
+    #[test]
+    fn test_trim_answers() {
+        let name = Name::from_ascii("www.example.com.").unwrap();
+        let records = vec![
+            Record::from_rdata(
+                name.clone(),
+                60,
+                RData::CNAME(rdata::CNAME(Name::from_ascii("cdn.example.net.").unwrap())),
+            ),
+            Record::from_rdata(name.clone(), 60, RData::A(rdata::A::from(Ipv4Addr::new(10, 0, 0, 1)))),
+            Record::from_rdata(name.clone(), 60, RData::A(rdata::A::from(Ipv4Addr::new(10, 0, 0, 2)))),
+            Record::from_rdata(name.clone(), 60, RData::TXT(rdata::TXT::new(vec!["v=spf1".to_owned()]))),
+        ];
+        let lookup = Lookup::new_with_max_ttl(Query::query(name, RecordType::A), records.into());
+
+        let handler = DnsRequestHandlerBuilder::new().build(Arc::new(DnsConfig::default()));
+        let mut handle = ServerHandle::new(Arc::new(handler));
+        assert_eq!(handle.trim_answers(lookup.clone()).records().len(), 4);
+
+        handle.minimal_responses = true;
+        let types: Vec<_> = handle
+            .trim_answers(lookup.clone())
+            .record_iter()
+            .map(|r| r.record_type())
+            .collect();
+        assert_eq!(types, [RecordType::CNAME, RecordType::A, RecordType::A]);
+
+        handle.max_answers = Some(2);
+        assert_eq!(handle.trim_answers(lookup).records().len(), 2);
+    }
+}
@@ -0,0 +1,272 @@
+//! In-process dns upstream for hermetic tests, enabled by the `test-util` feature.
+//!
+//! ```ignore
+//! let upstream = MockDnsServer::start().await?;
+//! upstream.answer("www.example.com", "1.2.3.4".parse()?, 60);
+//! upstream.fail("blocked.example.com", ResponseCode::Refused);
+//! upstream.set_latency(Duration::from_millis(200));
+//! upstream.truncate(true);
+//!
+//! let client = DnsClient::builder().add_server(upstream.dns_url()).build().await;
+//! ```
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    libdns::{
+        proto::{
+            op::{Edns, Header},
+            rr::{rdata, Name, RData, Record},
+        },
+        server::{
+            authority::MessageResponseBuilder,
+            server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo},
+            ServerFuture,
+        },
+    },
+    server::ServeFaild,
+};
+
+pub use crate::{dns_url::DnsUrl, libdns::proto::op::ResponseCode};
+
+/// A scripted dns upstream listening on a random local port, over both UDP and TCP.
+///
+/// Names without a script are answered with `NXDomain`. The server stops when dropped.
+pub struct MockDnsServer {
+    addr: SocketAddr,
+    state: Arc<MockState>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct MockState {
+    scripts: Mutex<HashMap<Name, Script>>,
+    latency: Mutex<Duration>,
+    truncate: AtomicBool,
+    queries: AtomicUsize,
+    tcp_queries: AtomicUsize,
+}
+
+#[derive(Clone)]
+enum Script {
+    Answer(Vec<Record>),
+    Fail(ResponseCode),
+}
+
+impl MockDnsServer {
+    pub async fn start() -> io::Result<Self> {
+        let (socket, listener) = bind_udp_and_tcp().await?;
+        let addr = socket.local_addr()?;
+        let state = Arc::new(MockState::default());
+
+        let mut server = ServerFuture::new(MockHandler(state.clone()));
+        server.register_socket(socket);
+        server.register_listener(listener, Duration::from_secs(5));
+
+        let task = tokio::spawn(async move {
+            let _ = server.block_until_done().await;
+        });
+
+        Ok(Self { addr, state, task })
+    }
+
+    #[inline]
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The url to point a `DnsClient` at this server.
+    pub fn dns_url(&self) -> DnsUrl {
+        format!("udp://{}", self.addr)
+            .parse()
+            .expect("mock server address is a valid dns url")
+    }
+
+    /// Answers A/AAAA queries for `name` with `ip`, appending to the previous answers.
+    pub fn answer(&self, name: &str, ip: IpAddr, ttl: u32) -> &Self {
+        let rdata = match ip {
+            IpAddr::V4(ip) => RData::A(rdata::A::from(ip)),
+            IpAddr::V6(ip) => RData::AAAA(rdata::AAAA::from(ip)),
+        };
+        self.record(Record::from_rdata(fqdn(name), ttl, rdata))
+    }
+
+    /// Answers queries for the name and type of `record` with it, appending to the previous answers.
+    pub fn record(&self, record: Record) -> &Self {
+        let mut scripts = self.state.scripts.lock().unwrap();
+        match scripts
+            .entry(record.name().to_lowercase())
+            .or_insert_with(|| Script::Answer(vec![]))
+        {
+            Script::Answer(records) => records.push(record),
+            script => *script = Script::Answer(vec![record]),
+        }
+        self
+    }
+
+    /// Answers every query for `name` with `code`.
+    pub fn fail(&self, name: &str, code: ResponseCode) -> &Self {
+        self.state
+            .scripts
+            .lock()
+            .unwrap()
+            .insert(fqdn(name), Script::Fail(code));
+        self
+    }
+
+    /// Delays every response by `latency`.
+    pub fn set_latency(&self, latency: Duration) -> &Self {
+        *self.state.latency.lock().unwrap() = latency;
+        self
+    }
+
+    /// Answers UDP queries with the TC bit set and no records, as if they didn't fit.
+    pub fn truncate(&self, truncate: bool) -> &Self {
+        self.state.truncate.store(truncate, Ordering::Release);
+        self
+    }
+
+    /// Drops all scripted answers and failures.
+    pub fn reset(&self) {
+        self.state.scripts.lock().unwrap().clear();
+        self.state.truncate.store(false, Ordering::Release);
+    }
+
+    /// Number of queries received so far.
+    pub fn queries(&self) -> usize {
+        self.state.queries.load(Ordering::Acquire)
+    }
+
+    /// Number of queries received over TCP so far.
+    pub fn tcp_queries(&self) -> usize {
+        self.state.tcp_queries.load(Ordering::Acquire)
+    }
+}
+
+/// Binds a UDP socket and a TCP listener sharing the same random port.
+async fn bind_udp_and_tcp() -> io::Result<(tokio::net::UdpSocket, tokio::net::TcpListener)> {
+    let mut last_err = None;
+    for _ in 0..16 {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        match tokio::net::TcpListener::bind(socket.local_addr()?).await {
+            Ok(listener) => return Ok((socket, listener)),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::ErrorKind::AddrInUse.into()))
+}
+
+impl Drop for MockDnsServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn fqdn(name: &str) -> Name {
+    let mut name = Name::from_ascii(name).expect("invalid mock dns name");
+    name.set_fqdn(true);
+    name.to_lowercase()
+}
+
+struct MockHandler(Arc<MockState>);
+
+#[async_trait::async_trait]
+impl RequestHandler for MockHandler {
+    async fn handle_request<R: ResponseHandler>(&self, request: &Request, mut response_handle: R) -> ResponseInfo {
+        self.0.queries.fetch_add(1, Ordering::AcqRel);
+        if matches!(request.protocol(), Protocol::Tcp) {
+            self.0.tcp_queries.fetch_add(1, Ordering::AcqRel);
+        }
+
+        let latency = *self.0.latency.lock().unwrap();
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+
+        let query = request.query();
+        let name: Name = query.name().into();
+        let script = self.0.scripts.lock().unwrap().get(&name.to_lowercase()).cloned();
+
+        let mut header = Header::response_from_request(request.header());
+        header.set_recursion_available(true);
+
+        let truncated = matches!(request.protocol(), Protocol::Udp) && self.0.truncate.load(Ordering::Acquire);
+
+        let answers = match script {
+            _ if truncated => {
+                header.set_truncated(true);
+                vec![]
+            }
+            Some(Script::Answer(records)) => records
+                .into_iter()
+                .filter(|r| r.record_type() == query.query_type())
+                .map(|mut r| {
+                    // echo the case of the query name
+                    r.set_name(name.clone());
+                    r
+                })
+                .collect::<Vec<_>>(),
+            Some(Script::Fail(code)) => {
+                header.set_response_code(code);
+                vec![]
+            }
+            None => {
+                header.set_response_code(ResponseCode::NXDomain);
+                vec![]
+            }
+        };
+
+        let mut response =
+            MessageResponseBuilder::from_message_request(request).build(header, answers.iter(), [], [], []);
+
+        // hickory sizes UDP answers by the response EDNS, keep them within the client's buffer
+        // (512 bytes without EDNS) so large answers are truncated like a real upstream does
+        let mut edns = Edns::new();
+        edns.set_max_payload(request.edns().map_or(512, |edns| edns.max_payload().max(512)));
+        response.set_edns(edns);
+
+        response_handle
+            .send_response(response)
+            .await
+            .unwrap_or_else(|_| ResponseInfo::serve_failed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        client::DnsClient,
+        libdns::proto::rr::RecordType,
+        resolver::{GenericResolver, GenericResolverExt},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_dns_server() {
+        let upstream = MockDnsServer::start().await.unwrap();
+        upstream.answer("www.example.com", "1.2.3.4".parse().unwrap(), 60);
+        upstream.fail("blocked.example.com", ResponseCode::Refused);
+
+        let client = DnsClient::builder().add_server(upstream.dns_url()).build().await;
+
+        let lookup = client.lookup("www.example.com.", RecordType::A).await.unwrap();
+        assert_eq!(
+            lookup.iter().filter_map(|r| r.ip_addr()).collect::<Vec<_>>(),
+            vec!["1.2.3.4".parse::<IpAddr>().unwrap()]
+        );
+
+        assert!(client.lookup_ip("blocked.example.com.").await.is_err());
+        assert!(client.lookup_ip("unknown.example.com.").await.is_err());
+        assert!(upstream.queries() >= 3);
+    }
+}
@@ -6,6 +6,15 @@ pub struct AuthUser {
     passwd: String,
 }
 
+impl AuthUser {
+    pub fn new<U: Into<String>, P: Into<String>>(uname: U, passwd: P) -> Self {
+        Self {
+            uname: uname.into(),
+            passwd: passwd.into(),
+        }
+    }
+}
+
 pub struct Authenticator {
     storage: HashMap<String, String>,
 }
@@ -22,4 +31,28 @@ impl Authenticator {
     pub fn verify(&self, user: &str, pass: &str) -> bool {
         self.storage.get(user).is_some_and(|real| real.eq(pass))
     }
+
+    /// Finds the user owning `pass`, for protocols authenticating by password only (trojan,
+    /// shadowsocks). Passwords are expected to be unique among users.
+    pub fn user_by_password(&self, pass: &str) -> Option<&str> {
+        self.storage
+            .iter()
+            .find(|(_, real)| real.as_str() == pass)
+            .map(|(user, _)| user.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authenticator() {
+        let auth = Authenticator::new(vec![AuthUser::new("alice", "secret1"), AuthUser::new("bob", "secret2")]);
+
+        assert!(auth.verify("alice", "secret1"));
+        assert!(!auth.verify("alice", "secret2"));
+        assert_eq!(auth.user_by_password("secret2"), Some("bob"));
+        assert_eq!(auth.user_by_password("secret3"), None);
+    }
 }
@@ -3,10 +3,14 @@ use std::{fmt::Debug, fs, io, net::IpAddr, path::Path, sync::Arc};
 use once_cell::sync::OnceCell;
 use rocksdb::{BoundColumnFamily, MultiThreaded, Transaction, TransactionDB};
 
+use crate::delay::DelayRecord;
+
 #[rustfmt::skip]
 mod cf {
     pub const FAKEIP:  &str = "fakeip";
     pub const FAKEIP6: &str = "fakeip6";
+    pub const SELECTED: &str = "selected";
+    pub const DELAYS:   &str = "delays";
 }
 
 static INSTANCE: OnceCell<CacheFile> = OnceCell::new();
@@ -41,17 +45,18 @@ impl CacheFile {
 
             let cfs = rocksdb::DB::list_cf(&opts, &cache_dir).unwrap_or(vec![]);
 
-            let db =
-                TransactionDB::open_cf(&opts, &txn_db_opts, &cache_dir, cfs).map_err(|err| {
-                    io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("Failed to open Cachefile(rocksdb): {}", err),
-                    )
-                })?;
+            let db = TransactionDB::open_cf(&opts, &txn_db_opts, &cache_dir, cfs).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Failed to open Cachefile(rocksdb): {}", err),
+                )
+            })?;
 
             // prepare column families
             _ = db.create_cf(cf::FAKEIP, &opts);
             _ = db.create_cf(cf::FAKEIP6, &opts);
+            _ = db.create_cf(cf::SELECTED, &opts);
+            _ = db.create_cf(cf::DELAYS, &opts);
 
             Ok(CacheFile { db })
         })
@@ -74,19 +79,33 @@ impl CacheFile {
         None
     }
 
+    /// Returns the fake ips with their host, the pairs are stored both ways.
+    pub fn fakeips(&self, ipv6: bool) -> Vec<(IpAddr, String)> {
+        let cf_name = if ipv6 { cf::FAKEIP6 } else { cf::FAKEIP };
+        let Some(cf) = self.inner_get_cf_handle(cf_name) else {
+            return Vec::new();
+        };
+        self.db
+            .iterator_cf(&cf, rocksdb::IteratorMode::Start)
+            .filter_map(Result::ok)
+            .filter_map(|(key, value)| {
+                let ip = std::str::from_utf8(&key).ok()?.parse().ok()?;
+                Some((ip, String::from_utf8(value.into_vec()).ok()?))
+            })
+            .collect()
+    }
+
     pub fn put_fakeip(&self, host: String, ip: IpAddr) -> io::Result<()> {
         let ipv6 = ip.is_ipv6();
         let cf_name = if ipv6 { cf::FAKEIP6 } else { cf::FAKEIP };
         if let Some(cf) = self.inner_get_cf_handle(cf_name) {
             let txn_db = self.db.transaction();
-            let put_kvpair = |k1: &[u8],
-                              k2: &[u8],
-                              txn: &Transaction<TransactionDB<MultiThreaded>>|
-             -> Result<(), rocksdb::Error> {
-                txn.put_cf(&cf, k1, k2)?;
-                txn.put_cf(&cf, k2, k1)?;
-                Ok(())
-            };
+            let put_kvpair =
+                |k1: &[u8], k2: &[u8], txn: &Transaction<TransactionDB<MultiThreaded>>| -> Result<(), rocksdb::Error> {
+                    txn.put_cf(&cf, k1, k2)?;
+                    txn.put_cf(&cf, k2, k1)?;
+                    Ok(())
+                };
 
             let k1 = host.clone();
             let k2 = match ip {
@@ -104,10 +123,7 @@ impl CacheFile {
             txn_db.commit().map_err(|err| {
                 io::Error::new(
                     io::ErrorKind::Other,
-                    format!(
-                        "Failed to commit transaction for saving fakeip pair: {}",
-                        err
-                    ),
+                    format!("Failed to commit transaction for saving fakeip pair: {}", err),
                 )
             })?;
 
@@ -125,14 +141,12 @@ impl CacheFile {
         let cf_name = if ipv6 { cf::FAKEIP6 } else { cf::FAKEIP };
         if let Some(cf) = self.inner_get_cf_handle(cf_name) {
             let txn_db = self.db.transaction();
-            let delete_kvpair = |k1: &[u8],
-                                 k2: &[u8],
-                                 txn: &Transaction<TransactionDB<MultiThreaded>>|
-             -> Result<(), rocksdb::Error> {
-                txn.delete_cf(&cf, k1)?;
-                txn.delete_cf(&cf, k2)?;
-                Ok(())
-            };
+            let delete_kvpair =
+                |k1: &[u8], k2: &[u8], txn: &Transaction<TransactionDB<MultiThreaded>>| -> Result<(), rocksdb::Error> {
+                    txn.delete_cf(&cf, k1)?;
+                    txn.delete_cf(&cf, k2)?;
+                    Ok(())
+                };
 
             let k1 = host.clone();
             let k2 = match ip {
@@ -143,26 +157,53 @@ impl CacheFile {
             delete_kvpair(&k1.as_bytes(), &k2.as_bytes(), &txn_db).map_err(|err| {
                 io::Error::new(
                     io::ErrorKind::Other,
-                    format!(
-                        "Failed to delete fakeip pair {:?} <-> {:?}: {}",
-                        host, ip, err
-                    ),
+                    format!("Failed to delete fakeip pair {:?} <-> {:?}: {}", host, ip, err),
                 )
             })?;
 
             txn_db.commit().map_err(|err| {
                 io::Error::new(
                     io::ErrorKind::Other,
-                    format!(
-                        "Failed to commit transaction for deleting fakeip pair: {}",
-                        err
-                    ),
+                    format!("Failed to commit transaction for deleting fakeip pair: {}", err),
                 )
             })?;
         }
 
         Ok(())
     }
+
+    /// The member last selected in the proxy group `group`.
+    pub fn get_selected(&self, group: &str) -> Option<String> {
+        let cf = self.inner_get_cf_handle(cf::SELECTED)?;
+        let member = self.db.get_cf(&cf, group).ok()??;
+        String::from_utf8(member.to_vec()).ok()
+    }
+
+    pub fn put_selected(&self, group: &str, member: &str) -> io::Result<()> {
+        let cf = self
+            .inner_get_cf_handle(cf::SELECTED)
+            .ok_or(io::Error::other("Failed to get selected column family"))?;
+        self.db
+            .put_cf(&cf, group, member)
+            .map_err(|err| io::Error::other(format!("Failed to put selected member of {:?}: {}", group, err)))
+    }
+
+    /// The delay history of the proxy `proxy` when it was last saved, oldest first.
+    pub fn get_delays(&self, proxy: &str) -> Option<Vec<DelayRecord>> {
+        let cf = self.inner_get_cf_handle(cf::DELAYS)?;
+        let records = self.db.get_cf(&cf, proxy).ok()??;
+        serde_json::from_slice(&records).ok()
+    }
+
+    pub fn put_delays(&self, proxy: &str, records: &[DelayRecord]) -> io::Result<()> {
+        let cf = self
+            .inner_get_cf_handle(cf::DELAYS)
+            .ok_or(io::Error::other("Failed to get delays column family"))?;
+        let records = serde_json::to_vec(records)?;
+        self.db
+            .put_cf(&cf, proxy, records)
+            .map_err(|err| io::Error::other(format!("Failed to put delays of {:?}: {}", proxy, err)))
+    }
 }
 
 impl Debug for CacheFile {
@@ -194,17 +235,37 @@ mod tests {
         cachefile.put_fakeip(host.into(), ip4).unwrap();
         cachefile.put_fakeip(host.into(), ip6).unwrap();
 
-        assert_eq!(
-            cachefile.get_fakeip(ip4.to_string(), false).unwrap(),
-            host.as_bytes()
-        );
-        assert_eq!(
-            cachefile.get_fakeip(ip6.to_string(), true).unwrap(),
-            host.as_bytes()
-        );
+        assert_eq!(cachefile.get_fakeip(ip4.to_string(), false).unwrap(), host.as_bytes());
+        assert_eq!(cachefile.get_fakeip(ip6.to_string(), true).unwrap(), host.as_bytes());
 
         _ = cachefile.delete_fakeip(host.into(), ip4);
         assert!(cachefile.get_fakeip(host, false).is_none());
         assert!(cachefile.get_fakeip(host, true).is_some());
+        assert!(cachefile.fakeips(true).contains(&(ip6, host.to_owned())));
+        assert!(!cachefile.fakeips(false).contains(&(ip4, host.to_owned())));
+    }
+
+    #[test]
+    fn test_cf_selected() {
+        let cache_dir = temp_cache_dir();
+        let cachefile = CacheFile::with_cache_dir(&cache_dir).expect("Failed to create cachefile");
+
+        cachefile.put_selected("cachefile-test-proxy", "hk").unwrap();
+        cachefile.put_selected("cachefile-test-proxy", "jp").unwrap();
+        assert_eq!(cachefile.get_selected("cachefile-test-proxy").as_deref(), Some("jp"));
+    }
+
+    #[test]
+    fn test_cf_delays() {
+        let cache_dir = temp_cache_dir();
+        let cachefile = CacheFile::with_cache_dir(&cache_dir).expect("Failed to create cachefile");
+
+        let records = [
+            DelayRecord::new(Some(std::time::Duration::from_millis(80))),
+            DelayRecord::new(None),
+        ];
+        cachefile.put_delays("cachefile-test-proxy", &records).unwrap();
+        assert_eq!(cachefile.get_delays("cachefile-test-proxy").unwrap(), records);
+        assert_eq!(cachefile.get_delays("cachefile-test-unknown"), None);
     }
 }
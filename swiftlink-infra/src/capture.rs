@@ -0,0 +1,361 @@
+//! Capture of relayed payloads into pcapng files.
+//!
+//! Payloads are captured after decryption, so there are no real packets to dump. Each payload is
+//! wrapped into synthesized IP and TCP headers with consistent sequence numbers, which lets
+//! Wireshark reassemble and dissect the inner protocol ("Follow TCP Stream" works as usual).
+//!
+//! ```ignore
+//! let mut capture = TcpCapture::create("conn-42.pcapng", client_addr, remote_addr)?;
+//! capture.record(Direction::Outbound, &request)?;
+//! capture.record(Direction::Inbound, &response)?;
+//! capture.finish()?;
+//! ```
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// https://www.tcpdump.org/linktypes.html, raw IPv4/IPv6 packets
+const LINKTYPE_RAW: u16 = 101;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// Largest payload of a synthesized segment, leaving room for the IP and TCP headers.
+const MAX_SEGMENT: usize = 65535 - 60 - 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// client to remote
+    Outbound,
+    /// remote to client
+    Inbound,
+}
+
+/// A single relayed connection written as a TCP stream.
+pub struct TcpCapture<W: Write> {
+    writer: W,
+    client: SocketAddr,
+    remote: SocketAddr,
+    /// next sequence number sent by the client and by the remote
+    client_seq: u32,
+    remote_seq: u32,
+    ip_id: u16,
+}
+
+impl TcpCapture<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, client: SocketAddr, remote: SocketAddr) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), client, remote)
+    }
+}
+
+impl<W: Write> TcpCapture<W> {
+    /// Writes the pcapng headers and a synthesized three-way handshake.
+    pub fn new(mut writer: W, client: SocketAddr, remote: SocketAddr) -> io::Result<Self> {
+        // both ends must have the same IP version
+        let (client, remote) = match (client, remote) {
+            (SocketAddr::V4(c), SocketAddr::V6(_)) => {
+                (SocketAddr::new(IpAddr::V6(c.ip().to_ipv6_mapped()), c.port()), remote)
+            }
+            (SocketAddr::V6(_), SocketAddr::V4(r)) => {
+                (client, SocketAddr::new(IpAddr::V6(r.ip().to_ipv6_mapped()), r.port()))
+            }
+            _ => (client, remote),
+        };
+
+        write_section_header(&mut writer)?;
+        write_interface_description(&mut writer)?;
+
+        let mut capture = Self {
+            writer,
+            client,
+            remote,
+            client_seq: 0,
+            remote_seq: 0,
+            ip_id: 0,
+        };
+
+        capture.segment(Direction::Outbound, TCP_SYN, &[])?;
+        capture.client_seq = 1;
+        capture.segment(Direction::Inbound, TCP_SYN | TCP_ACK, &[])?;
+        capture.remote_seq = 1;
+        capture.segment(Direction::Outbound, TCP_ACK, &[])?;
+
+        Ok(capture)
+    }
+
+    /// Records a payload relayed in `direction`.
+    pub fn record(&mut self, direction: Direction, payload: &[u8]) -> io::Result<()> {
+        for chunk in payload.chunks(MAX_SEGMENT) {
+            self.segment(direction, TCP_PSH | TCP_ACK, chunk)?;
+            match direction {
+                Direction::Outbound => self.client_seq = self.client_seq.wrapping_add(chunk.len() as u32),
+                Direction::Inbound => self.remote_seq = self.remote_seq.wrapping_add(chunk.len() as u32),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a synthesized connection close and flushes the file.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.segment(Direction::Outbound, TCP_FIN | TCP_ACK, &[])?;
+        self.client_seq = self.client_seq.wrapping_add(1);
+        self.segment(Direction::Inbound, TCP_FIN | TCP_ACK, &[])?;
+        self.remote_seq = self.remote_seq.wrapping_add(1);
+        self.segment(Direction::Outbound, TCP_ACK, &[])?;
+
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn segment(&mut self, direction: Direction, flags: u8, payload: &[u8]) -> io::Result<()> {
+        let (src, dst, seq, ack) = match direction {
+            Direction::Outbound => (self.client, self.remote, self.client_seq, self.remote_seq),
+            Direction::Inbound => (self.remote, self.client, self.remote_seq, self.client_seq),
+        };
+        // no ACK number before the peer's SYN was seen
+        let ack = if flags & TCP_ACK == 0 { 0 } else { ack };
+
+        self.ip_id = self.ip_id.wrapping_add(1);
+        let packet = build_packet(src, dst, self.ip_id, seq, ack, flags, payload);
+        write_enhanced_packet(&mut self.writer, &packet)
+    }
+}
+
+fn write_block<W: Write>(w: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let padding = (4 - body.len() % 4) % 4;
+    let total_len = (12 + body.len() + padding) as u32;
+
+    w.write_all(&block_type.to_le_bytes())?;
+    w.write_all(&total_len.to_le_bytes())?;
+    w.write_all(body)?;
+    w.write_all(&[0u8; 3][..padding])?;
+    w.write_all(&total_len.to_le_bytes())
+}
+
+fn write_section_header<W: Write>(w: &mut W) -> io::Result<()> {
+    let mut body = Vec::with_capacity(16);
+    body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes()); // major version
+    body.extend_from_slice(&0u16.to_le_bytes()); // minor version
+    body.extend_from_slice(&(-1i64).to_le_bytes()); // section length not specified
+    write_block(w, BLOCK_SECTION_HEADER, &body)
+}
+
+fn write_interface_description<W: Write>(w: &mut W) -> io::Result<()> {
+    let mut body = Vec::with_capacity(8);
+    body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes()); // reserved
+    body.extend_from_slice(&0u32.to_le_bytes()); // no snap length limit
+    write_block(w, BLOCK_INTERFACE_DESCRIPTION, &body)
+}
+
+fn write_enhanced_packet<W: Write>(w: &mut W, packet: &[u8]) -> io::Result<()> {
+    // default timestamp resolution is microseconds
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+
+    let mut body = Vec::with_capacity(20 + packet.len());
+    body.extend_from_slice(&0u32.to_le_bytes()); // interface id
+    body.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(ts as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // captured length
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // original length
+    body.extend_from_slice(packet);
+    write_block(w, BLOCK_ENHANCED_PACKET, &body)
+}
+
+fn build_packet(
+    src: SocketAddr,
+    dst: SocketAddr,
+    ip_id: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let tcp_len = 20 + payload.len();
+
+    let mut tcp = Vec::with_capacity(tcp_len);
+    tcp.extend_from_slice(&src.port().to_be_bytes());
+    tcp.extend_from_slice(&dst.port().to_be_bytes());
+    tcp.extend_from_slice(&seq.to_be_bytes());
+    tcp.extend_from_slice(&ack.to_be_bytes());
+    tcp.push(5 << 4); // data offset, no options
+    tcp.push(flags);
+    tcp.extend_from_slice(&u16::MAX.to_be_bytes()); // window
+    tcp.extend_from_slice(&[0, 0]); // checksum
+    tcp.extend_from_slice(&[0, 0]); // urgent pointer
+    tcp.extend_from_slice(payload);
+
+    let mut packet = Vec::with_capacity(40 + tcp_len);
+    let pseudo_header = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            let mut ip = [0u8; 20];
+            ip[0] = 0x45;
+            ip[2..4].copy_from_slice(&((20 + tcp_len) as u16).to_be_bytes());
+            ip[4..6].copy_from_slice(&ip_id.to_be_bytes());
+            ip[6] = 0x40; // don't fragment
+            ip[8] = 64; // ttl
+            ip[9] = 6; // tcp
+            ip[12..16].copy_from_slice(&s.octets());
+            ip[16..20].copy_from_slice(&d.octets());
+            let checksum = checksum(&[&ip]);
+            ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.extend_from_slice(&ip);
+
+            let mut pseudo = Vec::with_capacity(12);
+            pseudo.extend_from_slice(&s.octets());
+            pseudo.extend_from_slice(&d.octets());
+            pseudo.extend_from_slice(&[0, 6]);
+            pseudo.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+            pseudo
+        }
+        (s, d) => {
+            let s = to_ipv6(s);
+            let d = to_ipv6(d);
+
+            packet.extend_from_slice(&0x6000_0000u32.to_be_bytes());
+            packet.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+            packet.push(6); // tcp
+            packet.push(64); // hop limit
+            packet.extend_from_slice(&s);
+            packet.extend_from_slice(&d);
+
+            let mut pseudo = Vec::with_capacity(40);
+            pseudo.extend_from_slice(&s);
+            pseudo.extend_from_slice(&d);
+            pseudo.extend_from_slice(&(tcp_len as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, 6]);
+            pseudo
+        }
+    };
+
+    let checksum = checksum(&[&pseudo_header, &tcp]);
+    tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&tcp);
+    packet
+}
+
+fn to_ipv6(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+/// Internet checksum (RFC 1071) over the concatenation of `parts`, each of even length but the last.
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        let mut chunks = part.chunks_exact(2);
+        for chunk in &mut chunks {
+            sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+        }
+        if let [last] = chunks.remainder() {
+            sum += (*last as u32) << 8;
+        }
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Splits a pcapng file into (block type, body) pairs.
+    fn blocks(data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = vec![];
+        let mut rest = data;
+        while !rest.is_empty() {
+            let block_type = u32::from_le_bytes(rest[0..4].try_into().unwrap());
+            let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(&rest[len - 4..len], &rest[4..8]);
+            blocks.push((block_type, &rest[8..len - 4]));
+            rest = &rest[len..];
+        }
+        blocks
+    }
+
+    /// The packet data of an enhanced packet block body, without padding.
+    fn packet_data(body: &[u8]) -> &[u8] {
+        let len = u32::from_le_bytes(body[12..16].try_into().unwrap()) as usize;
+        &body[20..20 + len]
+    }
+
+    #[test]
+    fn test_capture_ipv4() {
+        let client = "192.168.1.2:50000".parse().unwrap();
+        let remote = "1.2.3.4:443".parse().unwrap();
+
+        let mut capture = TcpCapture::new(vec![], client, remote).unwrap();
+        capture.record(Direction::Outbound, b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        capture.record(Direction::Inbound, b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+        let data = capture.finish().unwrap();
+
+        let blocks = blocks(&data);
+        assert_eq!(blocks[0].0, BLOCK_SECTION_HEADER);
+        assert_eq!(blocks[1].0, BLOCK_INTERFACE_DESCRIPTION);
+        // handshake, 2 payloads, close
+        assert_eq!(blocks.len(), 2 + 3 + 2 + 3);
+
+        let packet = packet_data(blocks[5].1);
+        assert_eq!(packet[0], 0x45);
+        assert_eq!(checksum(&[&packet[..20]]), 0);
+        assert_eq!(&packet[12..16], &[192, 168, 1, 2]);
+
+        let tcp = &packet[20..];
+        assert_eq!(u16::from_be_bytes([tcp[0], tcp[1]]), 50000);
+        assert_eq!(u32::from_be_bytes(tcp[4..8].try_into().unwrap()), 1);
+        assert_eq!(tcp[13], TCP_PSH | TCP_ACK);
+        assert_eq!(&tcp[20..], b"GET / HTTP/1.1\r\n\r\n");
+
+        // the response acknowledges the request
+        let tcp = &packet_data(blocks[6].1)[20..];
+        assert_eq!(u32::from_be_bytes(tcp[8..12].try_into().unwrap()), 1 + 18);
+    }
+
+    #[test]
+    fn test_capture_mixed_families() {
+        let client = "127.0.0.1:50000".parse().unwrap();
+        let remote = "[2001:db8::1]:443".parse().unwrap();
+
+        let mut capture = TcpCapture::new(vec![], client, remote).unwrap();
+        capture.record(Direction::Outbound, &[0u8; MAX_SEGMENT + 1]).unwrap();
+        let data = capture.finish().unwrap();
+
+        let blocks = blocks(&data);
+        // the payload is split into two segments
+        assert_eq!(blocks.len(), 2 + 3 + 2 + 3);
+
+        let packet = packet_data(blocks[2].1);
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(
+            &packet[8..24],
+            &"::ffff:127.0.0.1".parse::<std::net::Ipv6Addr>().unwrap().octets()
+        );
+    }
+
+    #[test]
+    fn test_checksum() {
+        // https://tools.ietf.org/html/rfc1071 example
+        assert_eq!(checksum(&[&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]]), !0xddf2);
+    }
+}
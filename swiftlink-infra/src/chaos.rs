@@ -0,0 +1,199 @@
+//! Fault injection into outbound dials and DNS lookups, the `chaos` feature.
+//!
+//! Fallback groups and timeouts only prove themselves when an upstream misbehaves. While faults are
+//! set, dials and lookups are delayed, dropped or refused at the configured probabilities, so the
+//! recovery paths run against healthy upstreams. Meant for development builds only.
+
+use std::{io, net::SocketAddr, sync::RwLock, time::Duration};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::log::*;
+
+/// A dropped dial or lookup fails with `TimedOut` after this long, unless the caller gives up first.
+pub const DROP_TIMEOUT: Duration = Duration::from_secs(30);
+
+static FAULTS: RwLock<Option<Faults>> = RwLock::new(None);
+
+/// Probabilities of the injected faults, each between 0 and 1.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Faults {
+    /// dials and lookups delayed by `latency_ms`
+    pub latency_rate: f64,
+    pub latency_ms: u64,
+    /// dials and lookups never answered
+    pub drop_rate: f64,
+    /// dials refused as if the handshake failed
+    pub failure_rate: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("{0} must be between 0 and 1")]
+pub struct InvalidRate(pub &'static str);
+
+/// What happens to one dial or lookup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fault {
+    Drop,
+    Fail,
+}
+
+impl Faults {
+    fn validate(&self) -> Result<(), InvalidRate> {
+        let rates = [
+            ("latency_rate", self.latency_rate),
+            ("drop_rate", self.drop_rate),
+            ("failure_rate", self.failure_rate),
+        ];
+        match rates.into_iter().find(|(_, rate)| !(0.0..=1.0).contains(rate)) {
+            Some((name, _)) => Err(InvalidRate(name)),
+            None => Ok(()),
+        }
+    }
+
+    /// The delay and fault of one dial, or of one lookup if `!dial`, which is never refused.
+    fn roll<R: Rng>(&self, dial: bool, rng: &mut R) -> (Option<Duration>, Option<Fault>) {
+        let delay = rng
+            .gen_bool(self.latency_rate)
+            .then(|| Duration::from_millis(self.latency_ms));
+        let fault = if rng.gen_bool(self.drop_rate) {
+            Some(Fault::Drop)
+        } else if dial && rng.gen_bool(self.failure_rate) {
+            Some(Fault::Fail)
+        } else {
+            None
+        };
+        (delay, fault)
+    }
+}
+
+/// Starts injecting `faults`, replacing the previous ones.
+pub fn set(faults: Faults) -> Result<(), InvalidRate> {
+    faults.validate()?;
+    warn!("chaos mode injecting {:?}", faults);
+    *FAULTS.write().unwrap() = Some(faults);
+    Ok(())
+}
+
+/// Stops injecting faults.
+pub fn clear() {
+    if FAULTS.write().unwrap().take().is_some() {
+        info!("chaos mode stopped");
+    }
+}
+
+/// The faults being injected, if any.
+pub fn current() -> Option<Faults> {
+    *FAULTS.read().unwrap()
+}
+
+/// Delays, drops or refuses a dial to `target` before it starts.
+pub async fn inject_dial(target: SocketAddr) -> io::Result<()> {
+    inject(true, &target.to_string()).await
+}
+
+/// Delays or drops a lookup of `name` before it's sent upstream.
+pub async fn inject_lookup(name: &str) -> io::Result<()> {
+    inject(false, name).await
+}
+
+async fn inject(dial: bool, target: &str) -> io::Result<()> {
+    let Some(faults) = current() else {
+        return Ok(());
+    };
+    let (delay, fault) = faults.roll(dial, &mut rand::thread_rng());
+
+    if let Some(delay) = delay {
+        debug!("chaos: delaying {} by {:?}", target, delay);
+        tokio::time::sleep(delay).await;
+    }
+    match fault {
+        Some(Fault::Drop) => {
+            debug!("chaos: dropping {}", target);
+            tokio::time::sleep(DROP_TIMEOUT).await;
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("chaos: {} dropped", target),
+            ))
+        }
+        Some(Fault::Fail) => {
+            debug!("chaos: refusing {}", target);
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("chaos: handshake with {} failed", target),
+            ))
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_faults_roll() {
+        let mut rng = rand::thread_rng();
+        let never = Faults::default();
+        assert_eq!(never.roll(true, &mut rng), (None, None));
+
+        let always = Faults {
+            latency_rate: 1.0,
+            latency_ms: 200,
+            failure_rate: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            always.roll(true, &mut rng),
+            (Some(Duration::from_millis(200)), Some(Fault::Fail))
+        );
+        // lookups have no handshake to fail
+        assert_eq!(always.roll(false, &mut rng), (Some(Duration::from_millis(200)), None));
+
+        let drop = Faults {
+            drop_rate: 1.0,
+            failure_rate: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(drop.roll(false, &mut rng), (None, Some(Fault::Drop)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_inject() {
+        let target: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        assert!(inject_dial(target).await.is_ok());
+
+        let invalid = Faults {
+            drop_rate: 1.5,
+            ..Default::default()
+        };
+        assert_eq!(set(invalid), Err(InvalidRate("drop_rate")));
+        assert_eq!(current(), None);
+
+        set(Faults {
+            latency_rate: 1.0,
+            latency_ms: 200,
+            failure_rate: 1.0,
+            ..Default::default()
+        })
+        .unwrap();
+        let start = tokio::time::Instant::now();
+        let err = inject_dial(target).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+        assert!(inject_lookup("example.com").await.is_ok());
+
+        set(Faults {
+            drop_rate: 1.0,
+            ..Default::default()
+        })
+        .unwrap();
+        let err = inject_lookup("example.com").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        clear();
+        assert!(inject_dial(target).await.is_ok());
+    }
+}
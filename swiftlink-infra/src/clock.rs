@@ -0,0 +1,47 @@
+//! Clock for TTL and expiry logic.
+//!
+//! Backed by `tokio::time`, so tests running on a paused runtime
+//! (`#[tokio::test(start_paused = true)]`) move it forward with `tokio::time::advance` instead of
+//! sleeping in real time. Outside of a paused runtime it is the monotonic system clock.
+
+use std::time::Duration;
+
+pub use std::time::Instant;
+
+/// Returns the current instant.
+#[inline]
+pub fn now() -> Instant {
+    tokio::time::Instant::now().into_std()
+}
+
+/// Returns the instant a record with `ttl` seconds received now expires.
+#[inline]
+pub fn deadline(ttl: u32) -> Instant {
+    now() + Duration::from_secs(ttl as u64)
+}
+
+/// Returns `true` if `deadline` has passed.
+#[inline]
+pub fn is_expired(deadline: Instant) -> bool {
+    now() >= deadline
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clock_paused() {
+        let start = now();
+        let valid_until = deadline(30);
+        assert_eq!(valid_until - start, Duration::from_secs(30));
+        assert!(!is_expired(valid_until));
+
+        tokio::time::advance(Duration::from_secs(29)).await;
+        assert!(!is_expired(valid_until));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(is_expired(valid_until));
+        assert_eq!(now() - start, Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,384 @@
+//! Why relayed connections ended.
+//!
+//! A relay records each connection it closes with [`ConnectionHistory::record`], which writes the
+//! access log line and keeps the most recent ones for the `/connections` history.
+//!
+//! ```ignore
+//! let reason = match relay(&mut client, &mut remote).await {
+//!     Ok(Side::Client) => CloseReason::ClientEof,
+//!     Ok(Side::Remote) => CloseReason::ServerEof,
+//!     Err(err) => CloseReason::from_relay_error(&err),
+//! };
+//! history.record(ClosedConnection { reason, ..conn });
+//! ```
+
+use std::{
+    collections::VecDeque,
+    fmt, io,
+    net::SocketAddr,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tracing::field;
+
+use crate::{log::*, net::loop_guard};
+
+/// Default number of closed connections kept by [`ConnectionHistory`]
+pub const DEFAULT_HISTORY_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// the client closed its side first
+    ClientEof,
+    /// the remote server closed its side first
+    ServerEof,
+    /// the remote server or the outbound refused the connection
+    DialRefused,
+    /// connecting or the handshake with the outbound didn't finish in time
+    HandshakeTimeout,
+    /// no payload was relayed for the idle timeout
+    IdleTimeout,
+    /// a rule rejected the connection
+    PolicyReject,
+    /// the destination is a listener of swiftlink itself
+    LoopDetected,
+    /// the server shut down
+    Shutdown,
+    /// any other error, see [`ClosedConnection::error`]
+    Error,
+}
+
+/// The error of a dial a routing rule refused, by the text of the rule.
+#[derive(Debug, Clone, thiserror::Error, PartialEq, Eq)]
+#[error("rejected by rule {0}")]
+pub struct Rejected(pub String);
+
+impl CloseReason {
+    /// Classifies an error of dialing the remote or of the outbound handshake.
+    pub fn from_dial_error(err: &io::Error) -> Self {
+        if loop_guard::is_loop(err) {
+            return Self::LoopDetected;
+        }
+        if err.get_ref().is_some_and(|err| err.is::<Rejected>()) {
+            return Self::PolicyReject;
+        }
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => Self::DialRefused,
+            io::ErrorKind::TimedOut => Self::HandshakeTimeout,
+            _ => Self::Error,
+        }
+    }
+
+    /// Classifies an error of relaying an established connection.
+    pub fn from_relay_error(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::TimedOut => Self::IdleTimeout,
+            _ => Self::Error,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ClientEof => "client_eof",
+            Self::ServerEof => "server_eof",
+            Self::DialRefused => "dial_refused",
+            Self::HandshakeTimeout => "handshake_timeout",
+            Self::IdleTimeout => "idle_timeout",
+            Self::PolicyReject => "policy_reject",
+            Self::LoopDetected => "loop_detected",
+            Self::Shutdown => "shutdown",
+            Self::Error => "error",
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for CloseReason {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Self::ClientEof,
+            Self::ServerEof,
+            Self::DialRefused,
+            Self::HandshakeTimeout,
+            Self::IdleTimeout,
+            Self::PolicyReject,
+            Self::LoopDetected,
+            Self::Shutdown,
+            Self::Error,
+        ]
+        .into_iter()
+        .find(|reason| reason.as_str() == s)
+        .ok_or_else(|| format!("unknown close reason {}", s))
+    }
+}
+
+/// How the outbound reached the destination, to tell which stage makes a route slow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Dialed {
+    /// the address of the destination which connected
+    pub remote: SocketAddr,
+    /// resolving the destination, `0` for addresses
+    pub dns_ms: u64,
+    /// connecting to `remote`, including the addresses which failed before it
+    pub connect_ms: u64,
+}
+
+impl Dialed {
+    pub fn new(remote: SocketAddr, dns: Duration, connect: Duration) -> Self {
+        Self {
+            remote,
+            dns_ms: dns.as_millis() as u64,
+            connect_ms: connect.as_millis() as u64,
+        }
+    }
+}
+
+/// A relayed connection which ended.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClosedConnection {
+    pub id: u64,
+    /// `tcp` or `udp`
+    pub network: &'static str,
+    /// tag of the inbound which accepted the connection
+    pub inbound: String,
+    pub source: SocketAddr,
+    /// the requested destination, `host:port`
+    pub destination: String,
+    /// the rule which matched, `None` if rejected before one did
+    pub rule: Option<String>,
+    /// tag of the outbound, `None` if rejected before a rule matched
+    pub outbound: Option<String>,
+    /// `None` if the destination wasn't dialed or no address connected
+    pub dialed: Option<Dialed>,
+    /// bytes sent by the client
+    pub upload: u64,
+    /// bytes received by the client
+    pub download: u64,
+    /// milliseconds since the unix epoch
+    pub started_at: u64,
+    pub duration_ms: u64,
+    pub reason: CloseReason,
+    /// the error behind [`CloseReason::Error`] and the timeouts
+    pub error: Option<String>,
+}
+
+impl ClosedConnection {
+    /// Milliseconds since the unix epoch of `time`, for [`ClosedConnection::started_at`].
+    pub fn unix_millis(time: SystemTime) -> u64 {
+        time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+    }
+
+    #[inline]
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+}
+
+/// Selects connections of a [`ConnectionHistory`], all of them by default.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryQuery {
+    pub reason: Option<CloseReason>,
+    /// part of the destination, e.g. the domain
+    pub destination: Option<String>,
+    /// at most this many, the most recently closed
+    pub limit: Option<usize>,
+}
+
+impl HistoryQuery {
+    fn matches(&self, conn: &ClosedConnection) -> bool {
+        self.reason.is_none_or(|reason| conn.reason == reason)
+            && self
+                .destination
+                .as_deref()
+                .is_none_or(|destination| conn.destination.contains(destination))
+    }
+}
+
+/// The most recently closed connections, oldest dropped first.
+///
+/// Live connection listings miss short-lived connections, which are the failing ones most of
+/// the time, this keeps them for a while.
+#[derive(Debug)]
+pub struct ConnectionHistory {
+    capacity: usize,
+    closed: Mutex<VecDeque<ClosedConnection>>,
+}
+
+impl Default for ConnectionHistory {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_SIZE)
+    }
+}
+
+impl ConnectionHistory {
+    /// A `capacity` of `0` only writes the access log.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            closed: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Writes the access log line of `conn` and keeps it.
+    pub fn record(&self, conn: ClosedConnection) {
+        info!(
+            target: "access",
+            id = conn.id,
+            network = conn.network,
+            inbound = %conn.inbound,
+            source = %conn.source,
+            destination = %conn.destination,
+            rule = conn.rule.as_deref().unwrap_or("-"),
+            outbound = conn.outbound.as_deref().unwrap_or("-"),
+            remote = conn.dialed.map(|dialed| field::display(dialed.remote)),
+            dns_ms = conn.dialed.map(|dialed| dialed.dns_ms),
+            connect_ms = conn.dialed.map(|dialed| dialed.connect_ms),
+            upload = conn.upload,
+            download = conn.download,
+            duration_ms = conn.duration_ms,
+            reason = conn.reason.as_str(),
+            error = conn.error.as_deref().unwrap_or("-"),
+            "connection closed"
+        );
+
+        if self.capacity == 0 {
+            return;
+        }
+        let mut closed = self.closed.lock().unwrap();
+        if closed.len() == self.capacity {
+            closed.pop_front();
+        }
+        closed.push_back(conn);
+    }
+
+    /// The kept connections, most recently closed first.
+    pub fn recent(&self) -> Vec<ClosedConnection> {
+        self.query(&HistoryQuery::default())
+    }
+
+    /// The kept connections `query` selects, most recently closed first.
+    pub fn query(&self, query: &HistoryQuery) -> Vec<ClosedConnection> {
+        self.closed
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|conn| query.matches(conn))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn closed(id: u64, reason: CloseReason) -> ClosedConnection {
+        ClosedConnection {
+            id,
+            network: "tcp",
+            inbound: "socks".to_owned(),
+            source: "127.0.0.1:50000".parse().unwrap(),
+            destination: "example.com:443".to_owned(),
+            rule: Some("DOMAIN-SUFFIX,example.com".to_owned()),
+            outbound: Some("direct".to_owned()),
+            dialed: Some(Dialed::new(
+                "93.184.215.14:443".parse().unwrap(),
+                Duration::from_millis(12),
+                Duration::from_millis(85),
+            )),
+            upload: 100,
+            download: 1000,
+            started_at: 0,
+            duration_ms: 1500,
+            reason,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_close_reason() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        let timed_out = io::Error::from(io::ErrorKind::TimedOut);
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+
+        assert_eq!(CloseReason::from_dial_error(&refused), CloseReason::DialRefused);
+        assert_eq!(CloseReason::from_dial_error(&timed_out), CloseReason::HandshakeTimeout);
+        assert_eq!(CloseReason::from_relay_error(&timed_out), CloseReason::IdleTimeout);
+        assert_eq!(CloseReason::from_relay_error(&reset), CloseReason::Error);
+        let looped = io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            loop_guard::LoopDetected("127.0.0.1:7890".parse().unwrap()),
+        );
+        assert_eq!(CloseReason::from_dial_error(&looped), CloseReason::LoopDetected);
+        let rejected = io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            Rejected("DOMAIN-SUFFIX,ads.example.com,REJECT".to_owned()),
+        );
+        assert_eq!(CloseReason::from_dial_error(&rejected), CloseReason::PolicyReject);
+
+        assert_eq!("idle_timeout".parse(), Ok(CloseReason::IdleTimeout));
+        assert!("timeout".parse::<CloseReason>().is_err());
+        assert_eq!(
+            serde_json::to_string(&CloseReason::PolicyReject).unwrap(),
+            format!("\"{}\"", CloseReason::PolicyReject)
+        );
+    }
+
+    #[test]
+    fn test_connection_history() {
+        let history = ConnectionHistory::new(2);
+        history.record(closed(1, CloseReason::ClientEof));
+        history.record(closed(2, CloseReason::ServerEof));
+        history.record(closed(3, CloseReason::Shutdown));
+
+        let recent = history.recent();
+        assert_eq!(recent.iter().map(|c| c.id).collect::<Vec<_>>(), vec![3, 2]);
+        assert_eq!(recent[0].duration(), Duration::from_millis(1500));
+
+        let json = serde_json::to_value(&recent[0]).unwrap();
+        assert_eq!(json["reason"], "shutdown");
+        assert_eq!(json["outbound"], "direct");
+        assert_eq!(json["dialed"]["remote"], "93.184.215.14:443");
+        assert_eq!(json["dialed"]["dns_ms"], 12);
+        assert_eq!(json["dialed"]["connect_ms"], 85);
+
+        let query = HistoryQuery {
+            reason: Some(CloseReason::ServerEof),
+            destination: Some("example.com".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(history.query(&query).len(), 1);
+        let query = HistoryQuery {
+            limit: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(history.query(&query)[0].id, 3);
+        let query = HistoryQuery {
+            destination: Some("example.org".to_owned()),
+            ..Default::default()
+        };
+        assert!(history.query(&query).is_empty());
+
+        let history = ConnectionHistory::new(0);
+        history.record(closed(1, CloseReason::ClientEof));
+        assert!(history.recent().is_empty());
+    }
+}
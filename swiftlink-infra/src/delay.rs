@@ -0,0 +1,158 @@
+//! Delay history of proxies in the Clash API format.
+//!
+//! Clash dashboards draw latency charts from the `history` array of `/proxies`:
+//!
+//! ```json
+//! "history": [{ "time": "2024-01-02T03:04:05.678+08:00", "delay": 123 }]
+//! ```
+//!
+//! where `delay` is in milliseconds and `0` marks a failed health check.
+
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+use chrono::{DateTime, FixedOffset, Local, SecondsFormat};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Measurements kept per proxy, the same as Clash.
+pub const DEFAULT_HISTORY_LEN: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DelayRecord {
+    #[serde(serialize_with = "serialize_time", deserialize_with = "deserialize_time")]
+    pub time: DateTime<FixedOffset>,
+    /// milliseconds, `0` if the check failed
+    pub delay: u16,
+}
+
+impl DelayRecord {
+    pub fn new(delay: Option<Duration>) -> Self {
+        Self {
+            time: Local::now().fixed_offset(),
+            // a successful check never reports 0
+            delay: delay.map_or(0, |d| d.as_millis().clamp(1, u16::MAX as u128) as u16),
+        }
+    }
+
+    #[inline]
+    pub fn is_alive(&self) -> bool {
+        self.delay > 0
+    }
+
+    /// The delay as a health check result of the group selectors, `None` if the check failed.
+    pub fn latency(&self) -> Option<Duration> {
+        Some(Duration::from_millis(self.delay as u64)).filter(|_| self.is_alive())
+    }
+}
+
+/// The latest health checks of a proxy, oldest first.
+#[derive(Debug)]
+pub struct DelayHistory {
+    records: Mutex<VecDeque<DelayRecord>>,
+    capacity: usize,
+}
+
+impl Default for DelayHistory {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_LEN)
+    }
+}
+
+impl DelayHistory {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            records: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    /// Records a health check, `None` if it failed.
+    pub fn record(&self, delay: Option<Duration>) -> DelayRecord {
+        let record = DelayRecord::new(delay);
+        self.push(record);
+        record
+    }
+
+    /// Restores records, e.g. loaded from the cache file, dropping the oldest above capacity.
+    pub fn restore<I: IntoIterator<Item = DelayRecord>>(&self, records: I) {
+        for record in records {
+            self.push(record);
+        }
+    }
+
+    fn push(&self, record: DelayRecord) {
+        let mut records = self.records.lock().unwrap();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    pub fn history(&self) -> Vec<DelayRecord> {
+        self.records.lock().unwrap().iter().copied().collect()
+    }
+
+    pub fn last(&self) -> Option<DelayRecord> {
+        self.records.lock().unwrap().back().copied()
+    }
+
+    /// The delay of the latest check, `0` if it failed or there is none.
+    pub fn last_delay(&self) -> u16 {
+        self.last().map_or(0, |r| r.delay)
+    }
+
+    /// A proxy is alive until a health check fails.
+    pub fn is_alive(&self) -> bool {
+        self.last().is_none_or(|r| r.is_alive())
+    }
+}
+
+impl Serialize for DelayHistory {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.history().serialize(serializer)
+    }
+}
+
+pub(crate) fn serialize_time<S: Serializer>(time: &DateTime<FixedOffset>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+}
+
+fn deserialize_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<FixedOffset>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    DateTime::parse_from_rfc3339(&s).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_history() {
+        let history = DelayHistory::new(2);
+        assert!(history.is_alive());
+        assert_eq!(history.last_delay(), 0);
+
+        history.record(Some(Duration::from_millis(120)));
+        history.record(None);
+        assert!(!history.is_alive());
+
+        history.record(Some(Duration::from_micros(10)));
+        let records = history.history();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].delay, 0);
+        assert_eq!(records[1].delay, 1);
+        assert!(history.is_alive());
+    }
+
+    #[test]
+    fn test_delay_history_clash_format() {
+        let json =
+            r#"[{"time":"2024-01-02T03:04:05.678+08:00","delay":123},{"time":"2024-01-02T03:05:05Z","delay":0}]"#;
+        let records: Vec<DelayRecord> = serde_json::from_str(json).unwrap();
+
+        let history = DelayHistory::default();
+        history.restore(records);
+        assert_eq!(history.last_delay(), 0);
+        assert_eq!(serde_json::to_string(&history).unwrap(), json);
+    }
+}
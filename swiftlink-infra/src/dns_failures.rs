@@ -0,0 +1,154 @@
+//! The latest failed DNS lookups, with the upstream which failed them.
+//!
+//! A lookup failed when no upstream answered it, e.g. a timeout, or an upstream answered
+//! `SERVFAIL` or `REFUSED`. Names which don't exist aren't failures. The buffer answers why a
+//! domain didn't resolve a while ago without keeping debug logs on.
+
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::log::*;
+
+/// Failures kept by default.
+pub const DEFAULT_FAILURES_SIZE: usize = 128;
+
+/// A lookup which failed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DnsFailure {
+    /// milliseconds since the unix epoch
+    pub at: u64,
+    pub name: String,
+    /// e.g. `AAAA`
+    pub record_type: String,
+    /// the upstream whose answer was the last one, `None` if no upstream was queried
+    pub upstream: Option<String>,
+    pub error: String,
+    pub duration_ms: u64,
+}
+
+impl DnsFailure {
+    /// A failure of now, after `duration`.
+    pub fn new(name: &str, record_type: &str, upstream: Option<&str>, error: &str, duration: Duration) -> Self {
+        Self {
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            name: name.to_owned(),
+            record_type: record_type.to_owned(),
+            upstream: upstream.map(str::to_owned),
+            error: error.to_owned(),
+            duration_ms: duration.as_millis() as u64,
+        }
+    }
+}
+
+/// The most recent failures, oldest dropped first.
+#[derive(Debug)]
+pub struct DnsFailures {
+    capacity: usize,
+    failures: Mutex<VecDeque<DnsFailure>>,
+}
+
+impl Default for DnsFailures {
+    fn default() -> Self {
+        Self::new(DEFAULT_FAILURES_SIZE)
+    }
+}
+
+impl DnsFailures {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            failures: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Logs `failure` at debug level and keeps it.
+    pub fn record(&self, failure: DnsFailure) {
+        debug!(
+            "lookup of {} {} failed after {} ms, upstream {}, {}",
+            failure.name,
+            failure.record_type,
+            failure.duration_ms,
+            failure.upstream.as_deref().unwrap_or("-"),
+            failure.error
+        );
+
+        if self.capacity == 0 {
+            return;
+        }
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() == self.capacity {
+            failures.pop_front();
+        }
+        failures.push_back(failure);
+    }
+
+    /// At most `limit` of the kept failures, of the names containing `name` if it's set, most
+    /// recent first.
+    pub fn recent(&self, name: Option<&str>, limit: usize) -> Vec<DnsFailure> {
+        let name = name.map(|name| name.trim_end_matches('.').to_ascii_lowercase());
+        self.failures
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|failure| {
+                name.as_deref()
+                    .is_none_or(|name| failure.name.to_ascii_lowercase().contains(name))
+            })
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(name: &str, upstream: Option<&str>) -> DnsFailure {
+        DnsFailure::new(name, "A", upstream, "request timed out", Duration::from_millis(5000))
+    }
+
+    #[test]
+    fn test_dns_failures() {
+        let failures = DnsFailures::new(2);
+        failures.record(failure("www.example.com.", Some("udp://8.8.8.8:53")));
+        failures.record(failure("cdn.example.net.", Some("https://1.1.1.1/dns-query")));
+        failures.record(failure("www.example.org.", None));
+
+        let recent = failures.recent(None, 10);
+        assert_eq!(
+            recent.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(),
+            ["www.example.org.", "cdn.example.net."]
+        );
+        assert_eq!(recent[0].duration_ms, 5000);
+        assert!(recent[0].at > 0);
+        assert_eq!(failures.recent(None, 1).len(), 1);
+
+        let found = failures.recent(Some("CDN.example.net."), 10);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].upstream.as_deref(), Some("https://1.1.1.1/dns-query"));
+        assert!(failures.recent(Some("example.com"), 10).is_empty());
+
+        let json = serde_json::to_value(&found[0]).unwrap();
+        assert_eq!(json["record_type"], "A");
+        assert_eq!(serde_json::from_value::<DnsFailure>(json).unwrap(), found[0]);
+
+        let disabled = DnsFailures::new(0);
+        disabled.record(failure("www.example.com.", None));
+        assert!(disabled.recent(None, 10).is_empty());
+    }
+}
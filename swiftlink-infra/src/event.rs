@@ -0,0 +1,159 @@
+//! Broadcast of what happens inside the engine.
+//!
+//! Subsystems publish [`Event`]s to the [`EventBus`] instead of holding references to whoever is
+//! interested in them: API websockets, metrics and the log each subscribe on their own. A
+//! subscriber which falls behind by more than the capacity of the bus misses the oldest events.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{connection::ClosedConnection, log::*};
+
+/// Events kept per subscriber until it receives them.
+pub const DEFAULT_EVENT_CAPACITY: usize = 1024;
+
+/// A connection relayed to its outbound.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OpenedConnection {
+    pub id: u64,
+    /// `tcp` or `udp`
+    pub network: &'static str,
+    /// tag of the inbound which accepted the connection
+    pub inbound: String,
+    pub source: SocketAddr,
+    /// the requested destination, `host:port`
+    pub destination: String,
+    pub rule: String,
+    pub outbound: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    ConnectionOpened(OpenedConnection),
+    /// every accepted connection, also the ones rejected before they were relayed
+    ConnectionClosed(Arc<ClosedConnection>),
+    /// a proxy answered its first check after failing, or failed after answering
+    ProxyHealthChanged {
+        proxy: String,
+        alive: bool,
+        /// the delay of the check, `None` if it failed
+        delay_ms: Option<u64>,
+    },
+    ConfigReloaded,
+    /// a rule or proxy provider loaded a new version
+    ProviderUpdated {
+        provider: String,
+        entries: usize,
+    },
+    /// the network requires a login on a captive portal, or was logged in to
+    CaptivePortal {
+        detected: bool,
+        /// the page the portal redirects to, if it told
+        login_url: Option<String>,
+    },
+}
+
+impl Event {
+    pub fn proxy_health_changed(proxy: &str, delay: Option<Duration>) -> Self {
+        Self::ProxyHealthChanged {
+            proxy: proxy.to_owned(),
+            alive: delay.is_some(),
+            delay_ms: delay.map(|delay| delay.as_millis() as u64),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(DEFAULT_EVENT_CAPACITY)
+    }
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity.max(1));
+        Self { tx }
+    }
+
+    /// Sends `event` to the current subscribers, it's dropped if there are none.
+    pub fn publish(&self, event: Event) {
+        _ = self.tx.send(event);
+    }
+
+    /// Receives the events published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+
+    #[inline]
+    pub fn subscriber_count(&self) -> usize {
+        self.tx.receiver_count()
+    }
+
+    /// Whether anyone listens, so publishers can skip building events nobody receives.
+    #[inline]
+    pub fn has_subscribers(&self) -> bool {
+        self.subscriber_count() > 0
+    }
+
+    /// Logs the events until the bus is dropped, connections at trace level.
+    pub fn spawn_logger(&self) -> tokio::task::JoinHandle<()> {
+        let mut rx = self.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event @ (Event::ConnectionOpened(_) | Event::ConnectionClosed(_))) => trace!("{:?}", event),
+                    Ok(event) => debug!("{:?}", event),
+                    Err(RecvError::Lagged(missed)) => debug!("event log missed {} events", missed),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_event_bus() {
+        let bus = EventBus::new(2);
+        // nobody listens
+        bus.publish(Event::ConfigReloaded);
+        assert!(!bus.has_subscribers());
+
+        let mut rx = bus.subscribe();
+        bus.publish(Event::proxy_health_changed("hk", Some(Duration::from_millis(120))));
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            Event::ProxyHealthChanged { proxy, alive: true, delay_ms: Some(120) } if proxy == "hk"
+        ));
+
+        bus.publish(Event::ConfigReloaded);
+        bus.publish(Event::proxy_health_changed("hk", None));
+        bus.publish(Event::ProviderUpdated {
+            provider: "ads".to_owned(),
+            entries: 100,
+        });
+        assert!(matches!(rx.recv().await, Err(RecvError::Lagged(1))));
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            Event::ProxyHealthChanged { alive: false, .. }
+        ));
+
+        let event = serde_json::to_value(rx.recv().await.unwrap()).unwrap();
+        assert_eq!(
+            event,
+            serde_json::json!({"type": "provider_updated", "provider": "ads", "entries": 100})
+        );
+    }
+}
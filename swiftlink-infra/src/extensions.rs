@@ -0,0 +1,105 @@
+//! Typed map of request scoped values.
+//!
+//! Subsystems attach their data (sniffed host, authenticated user, matched rule, ...) keyed by
+//! its type, so the structs carrying a request don't grow a field per subsystem.
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+};
+
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    /// Stores `value` in its slot, returns the previous value.
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|prev| prev.downcast().ok().map(|prev| *prev))
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>()).and_then(|v| v.downcast_ref())
+    }
+
+    pub fn get_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        self.map.get_mut(&TypeId::of::<T>()).and_then(|v| v.downcast_mut())
+    }
+
+    /// Returns the value of type `T`, inserting `f()` first if there is none.
+    pub fn get_or_insert_with<T: Any + Send + Sync, F: FnOnce() -> T>(&mut self, f: F) -> &mut T {
+        self.map
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(f()))
+            .downcast_mut()
+            .expect("extension slot holds its own type")
+    }
+
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|v| v.downcast().ok().map(|v| *v))
+    }
+
+    #[inline]
+    pub fn contains<T: Any + Send + Sync>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions").field("len", &self.map.len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct User(&'static str);
+
+    #[derive(Debug, PartialEq)]
+    struct Hits(u32);
+
+    #[test]
+    fn test_extensions() {
+        let mut ext = Extensions::default();
+        assert!(ext.is_empty());
+
+        assert_eq!(ext.insert(User("alice")), None);
+        assert_eq!(ext.insert(User("bob")), Some(User("alice")));
+        assert_eq!(ext.get::<User>(), Some(&User("bob")));
+        assert_eq!(ext.get::<Hits>(), None);
+
+        ext.get_or_insert_with(|| Hits(0)).0 += 1;
+        ext.get_or_insert_with(|| Hits(0)).0 += 1;
+        assert_eq!(ext.get::<Hits>(), Some(&Hits(2)));
+        assert_eq!(ext.len(), 2);
+
+        assert_eq!(ext.remove::<User>(), Some(User("bob")));
+        assert!(!ext.contains::<User>());
+
+        ext.clear();
+        assert!(ext.is_empty());
+    }
+}
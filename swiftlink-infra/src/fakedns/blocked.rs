@@ -0,0 +1,62 @@
+use std::sync::RwLock;
+
+use crate::trie::domain_trie::{DomainTrie, DomainTrieError};
+
+/// Domains the router rejects, shared with the dns server so their queries are answered without
+/// allocating a fake ip from the pool. Patterns are those of [`DomainTrie`], e.g. `+.ads.example`.
+#[derive(Debug, Default)]
+pub struct BlockedDomains {
+    domains: RwLock<DomainTrie<()>>,
+}
+
+impl BlockedDomains {
+    pub fn insert(&self, pattern: &str) -> Result<(), DomainTrieError> {
+        self.domains.write().unwrap().insert(pattern.to_ascii_lowercase(), ())
+    }
+
+    /// Replaces all domains, e.g. after the rules were reloaded. Invalid patterns are skipped and
+    /// returned.
+    pub fn replace<'a, I: IntoIterator<Item = &'a str>>(&self, patterns: I) -> Vec<DomainTrieError> {
+        let mut domains = DomainTrie::new();
+        let errors = patterns
+            .into_iter()
+            .filter_map(|pattern| domains.insert(pattern.to_ascii_lowercase(), ()).err())
+            .collect();
+        *self.domains.write().unwrap() = domains;
+        errors
+    }
+
+    pub fn contains(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        self.domains.read().unwrap().search(domain).is_some()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.domains.read().unwrap().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocked_domains() {
+        let blocked = BlockedDomains::default();
+        assert!(blocked.is_empty());
+        assert!(!blocked.contains("ads.example.com"));
+
+        blocked.insert("+.ads.example.com").unwrap();
+        blocked.insert("tracker.example.org").unwrap();
+        assert!(blocked.contains("ads.example.com"));
+        assert!(blocked.contains("Img.Ads.example.com."));
+        assert!(blocked.contains("tracker.example.org"));
+        assert!(!blocked.contains("www.example.com"));
+
+        let errors = blocked.replace(["*.example.net", "example..com"]);
+        assert_eq!(errors.len(), 1);
+        assert!(blocked.contains("a.example.net"));
+        assert!(!blocked.contains("ads.example.com"));
+    }
+}
@@ -11,10 +11,8 @@ pub struct CacheFileStore {
 
 impl CacheFileStore {
     pub fn new() -> io::Result<CacheFileStore> {
-        let cachefile = CacheFile::instance().ok_or(io::Error::new(
-            io::ErrorKind::Other,
-            "get cachefile instance fails",
-        ))?;
+        let cachefile =
+            CacheFile::instance().ok_or(io::Error::new(io::ErrorKind::Other, "get cachefile instance fails"))?;
 
         Ok(Self { cachefile })
     }
@@ -39,4 +37,10 @@ impl IFakeIPStore for CacheFileStore {
             IpAddr::V6(ip) => self.cachefile.get_fakeip(ip.to_string(), true).is_some(),
         }
     }
+
+    fn mappings(&mut self) -> Vec<(IpAddr, String)> {
+        let mut mappings = self.cachefile.fakeips(false);
+        mappings.extend(self.cachefile.fakeips(true));
+        mappings
+    }
 }
@@ -88,10 +88,15 @@ impl IFakeIPStore for MemoryStore {
         Ok(())
     }
 
-    fn delete_fakeip(&mut self, host: &str, _ip: IpAddr) -> io::Result<()> {
-        _ = self.host2ip4.remove_by_left(host);
-        _ = self.host2ip6.remove_by_left(host);
-        _ = self.host_lru.pop(host);
+    fn delete_fakeip(&mut self, host: &str, ip: IpAddr) -> io::Result<()> {
+        match ip {
+            IpAddr::V4(_) => _ = self.host2ip4.remove_by_left(host),
+            IpAddr::V6(_) => _ = self.host2ip6.remove_by_left(host),
+        };
+        // forget the host once it has no fake ip left
+        if !self.host2ip4.contains_left(host) && !self.host2ip6.contains_left(host) {
+            _ = self.host_lru.pop(host);
+        }
 
         Ok(())
     }
@@ -102,6 +107,12 @@ impl IFakeIPStore for MemoryStore {
             IpAddr::V6(ip) => self.host2ip6.get_by_right(&ip).is_some(),
         }
     }
+
+    fn mappings(&mut self) -> Vec<(IpAddr, String)> {
+        let ip4 = self.host2ip4.iter().map(|(host, ip)| (IpAddr::V4(*ip), host.clone()));
+        let ip6 = self.host2ip6.iter().map(|(host, ip)| (IpAddr::V6(*ip), host.clone()));
+        ip4.chain(ip6).collect()
+    }
 }
 
 impl Debug for MemoryStore {
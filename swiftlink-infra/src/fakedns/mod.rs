@@ -6,13 +6,16 @@ use std::{
 };
 
 use enum_dispatch::enum_dispatch;
+use ipnet::IpNet;
 
 use crate::log::*;
 use crate::trie::domain_trie::DomainTrie;
 
+pub use blocked::BlockedDomains;
 use cachefile::CacheFileStore;
 use memory::MemoryStore;
 
+mod blocked;
 mod cachefile;
 mod memory;
 
@@ -26,6 +29,8 @@ pub trait IFakeIPStore {
     fn delete_fakeip(&mut self, host: &str, ip: IpAddr) -> io::Result<()>;
     // exists returns if fake ip mapping exists.
     fn exists(&mut self, ip: IpAddr) -> bool;
+    // mappings returns every fake ip mapping in store, in no particular order.
+    fn mappings(&mut self) -> Vec<(IpAddr, String)>;
 }
 
 #[enum_dispatch(IFakeIPStore)]
@@ -42,6 +47,9 @@ pub struct Config {
     // IPNet6 is the ip6 range that will be returned fake ip.
     pub ipnet6: ipnet::Ipv6Net,
 
+    // IPv6 enables fake ip6, AAAA queries are answered without records if disabled.
+    pub ipv6: bool,
+
     // Whitelist is a domain list that will be skipped return fake ip.
     pub whitelist: Option<DomainTrie<()>>,
 
@@ -59,6 +67,7 @@ impl Default for Config {
         Self {
             ipnet: "198.18.0.0/15".parse().unwrap(),
             ipnet6: "2001:db8::/32".parse().unwrap(),
+            ipv6: true,
             whitelist: None,
             size: 65536,
             persist: false,
@@ -69,21 +78,27 @@ impl Default for Config {
 pub struct FakeDns {
     offset: u32,
     total: u32,
+    // ip6 is allocated on its own, the pool is usually much larger than the ip4 one.
+    offset6: u128,
+    total6: u128,
     ipnet: ipnet::Ipv4Net,
     ipnet6: ipnet::Ipv6Net,
+    ipv6: bool,
     whitelist: Option<DomainTrie<()>>,
     store: FakeIPStore,
 }
 
 impl FakeDns {
     pub fn new(config: Config) -> Self {
-        // generally ipv4 less than ipv6, so we choose the smaller one to set the total of available fake ip.
-        let mut total = 1 << (config.ipnet.max_prefix_len() - config.ipnet.prefix_len());
-        if total <= 2 {
+        let total = pool_size(config.ipnet.max_prefix_len() - config.ipnet.prefix_len());
+        if total == 0 {
             panic!("ipnet is too small");
         }
-        // reserve 2 for gateway and broadcast
-        total = total - 2;
+
+        let total6 = pool_size(config.ipnet6.max_prefix_len() - config.ipnet6.prefix_len());
+        if config.ipv6 && total6 == 0 {
+            panic!("ipnet6 is too small");
+        }
 
         let store = if config.persist {
             match CacheFileStore::new() {
@@ -100,8 +115,11 @@ impl FakeDns {
         let fakedns = FakeDns {
             offset: 0,
             total: total as u32,
+            offset6: 0,
+            total6,
             ipnet: config.ipnet,
             ipnet6: config.ipnet6,
+            ipv6: config.ipv6,
             whitelist: config.whitelist,
             store,
         };
@@ -111,24 +129,28 @@ impl FakeDns {
         fakedns
     }
 
+    /// returns the fake ip of host, allocated on first use. `None` for ip6 if fake ip6 is disabled.
     pub fn lookup_ip(&mut self, host: &str, ipv6: bool) -> Option<IpAddr> {
+        if ipv6 && !self.ipv6 {
+            return None;
+        }
+
         let entity = self.store.get_fakeip(host.as_bytes(), ipv6);
         match entity {
             Some(entity) => {
                 let ip = String::from_utf8(entity).unwrap_or_default();
                 IpAddr::from_str(ip.as_str()).ok()
             }
-            None => {
-                let (ip4, ip6) = self.get(host);
-                Some(if !ipv6 {
-                    IpAddr::V4(ip4)
-                } else {
-                    IpAddr::V6(ip6)
-                })
-            }
+            None if ipv6 => Some(IpAddr::V6(self.get6(host))),
+            None => Some(IpAddr::V4(self.get4(host))),
         }
     }
 
+    /// whether AAAA queries are answered with fake ip6
+    pub fn ipv6(&self) -> bool {
+        self.ipv6
+    }
+
     pub fn lookup_host(&mut self, ip: IpAddr) -> Option<String> {
         let ipv6 = ip.is_ipv6();
         self.store
@@ -136,6 +158,13 @@ impl FakeDns {
             .map(|entity| String::from_utf8(entity).unwrap_or_default())
     }
 
+    /// Returns every fake ip with its host, ordered by ip.
+    pub fn mappings(&mut self) -> Vec<(IpAddr, String)> {
+        let mut mappings = self.store.mappings();
+        mappings.sort_unstable();
+        mappings
+    }
+
     /// check if ip is already in fakeip mapping
     pub fn exist(&mut self, ip: IpAddr) -> bool {
         self.store.exists(ip)
@@ -158,37 +187,62 @@ impl FakeDns {
         }
     }
 
-    /// allocates a fake ip4 and ip6 for host.
-    fn get(&mut self, host: &str) -> (Ipv4Addr, Ipv6Addr) {
+    /// allocates a fake ip4 for host.
+    fn get4(&mut self, host: &str) -> Ipv4Addr {
         let current = self.offset;
         loop {
             let ip4 = gen_next_ipv4(&self.ipnet, self.offset);
-            let ip6 = gen_next_ipv6(&self.ipnet6, self.offset);
-            if !self.store.exists(ip4.into()) && !self.store.exists(ip6.into()) {
+            if !self.store.exists(ip4.into()) {
                 break;
             }
 
             self.offset = (self.offset + 1) % self.total;
             // if offset is equal to current, it means that all fake ip is used.
             if self.offset == current {
-                self.offset = (self.offset + 1) % (self.total);
-                let ip4 = gen_next_ipv4(&self.ipnet, self.offset);
-                let ip6 = gen_next_ipv6(&self.ipnet6, self.offset);
-                _ = self.store.delete_fakeip(host, ip4.into());
-                _ = self.store.delete_fakeip(host, ip6.into());
+                self.offset = (self.offset + 1) % self.total;
+                self.evict(gen_next_ipv4(&self.ipnet, self.offset).into());
                 break;
             }
         }
 
         let ip4 = gen_next_ipv4(&self.ipnet, self.offset);
-        let ip6 = gen_next_ipv6(&self.ipnet6, self.offset);
-
         _ = self.store.put_fakeip(host, ip4.into());
+
+        trace!("allocated fake ip mapping: {} -> {}", host, ip4);
+
+        ip4
+    }
+
+    /// allocates a fake ip6 for host, independent of its ip4.
+    fn get6(&mut self, host: &str) -> Ipv6Addr {
+        let current = self.offset6;
+        loop {
+            let ip6 = gen_next_ipv6(&self.ipnet6, self.offset6);
+            if !self.store.exists(ip6.into()) {
+                break;
+            }
+
+            self.offset6 = (self.offset6 + 1) % self.total6;
+            if self.offset6 == current {
+                self.offset6 = (self.offset6 + 1) % self.total6;
+                self.evict(gen_next_ipv6(&self.ipnet6, self.offset6).into());
+                break;
+            }
+        }
+
+        let ip6 = gen_next_ipv6(&self.ipnet6, self.offset6);
         _ = self.store.put_fakeip(host, ip6.into());
 
-        trace!("allocated fake ip mapping: {} -> ({}, {})", host, ip4, ip6);
+        trace!("allocated fake ip mapping: {} -> {}", host, ip6);
+
+        ip6
+    }
 
-        (ip4, ip6)
+    /// removes the mapping of ip, its host keeps the fake ip of the other family.
+    fn evict(&mut self, ip: IpAddr) {
+        if let Some(host) = self.lookup_host(ip) {
+            _ = self.store.delete_fakeip(&host, ip);
+        }
     }
 }
 
@@ -197,27 +251,85 @@ impl Debug for FakeDns {
         f.debug_struct("FakeDns")
             .field("ipnet", &self.ipnet)
             .field("ipnet6", &self.ipnet6)
+            .field("ipv6", &self.ipv6)
             .field("store", &self.store)
             .finish()
     }
 }
 
+/// Number of fake ips in a range with `host_bits` host bits.
+///
+/// The network address and the gateway at the start, and the broadcast at the end are reserved.
+/// The same applies to ip6, where the last address of a subnet is a reserved anycast address.
+fn pool_size(host_bits: u8) -> u128 {
+    // 2^host_bits - 1, a /0 of ip6 doesn't overflow
+    let last = u128::MAX.checked_shr(128 - host_bits as u32).unwrap_or(0);
+    last.saturating_sub(2)
+}
+
+/// returns the fake ip at offset, `offset < pool_size`.
 fn gen_next_ipv4(ipnet: &ipnet::Ipv4Net, offset: u32) -> Ipv4Addr {
     let mut ip: u32 = ipnet.network().into();
-    // skip gateway and broadcast
+    // skip network and gateway
     ip += 2;
     ip += offset;
+    debug_assert!(ip < u32::from(ipnet.broadcast()));
     ip.into()
 }
 
-fn gen_next_ipv6(ipnet: &ipnet::Ipv6Net, offset: u32) -> Ipv6Addr {
+/// returns the fake ip6 at offset, `offset < pool_size`.
+fn gen_next_ipv6(ipnet: &ipnet::Ipv6Net, offset: u128) -> Ipv6Addr {
     let mut ip: u128 = ipnet.network().into();
-    // skip gateway and broadcast
+    // skip network and gateway
     ip += 2;
-    ip += offset as u128;
+    ip += offset;
+    debug_assert!(ip < u128::from(ipnet.broadcast()));
     ip.into()
 }
 
+/// Special-use ranges of RFC 6890 which are likely in use on the LAN, fake ips there would shadow
+/// real hosts. The benchmarking and documentation ranges, e.g. the default 198.18.0.0/15, aren't.
+const SPECIAL_USE_RANGES: &[(&str, &str)] = &[
+    ("0.0.0.0/8", "this network"),
+    ("10.0.0.0/8", "private-use"),
+    ("100.64.0.0/10", "shared address space"),
+    ("127.0.0.0/8", "loopback"),
+    ("169.254.0.0/16", "link-local"),
+    ("172.16.0.0/12", "private-use"),
+    ("192.168.0.0/16", "private-use"),
+    ("224.0.0.0/4", "multicast"),
+    ("240.0.0.0/4", "reserved and broadcast"),
+    ("::/127", "unspecified and loopback"),
+    ("::ffff:0:0/96", "IPv4-mapped"),
+    ("fd00::/8", "unique local"),
+    ("fe80::/10", "link-local"),
+    ("ff00::/8", "multicast"),
+];
+
+/// An unusable fake ip range.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum RangeError {
+    #[error("{0} is too small to hold any fake ip")]
+    TooSmall(IpNet),
+    #[error("{0} overlaps the {2} range {1}, which may be in use on the LAN")]
+    SpecialUse(IpNet, IpNet, &'static str),
+}
+
+/// Checks a fake ip range before it is used, see [`SPECIAL_USE_RANGES`].
+pub fn check_range(ipnet: IpNet) -> Result<(), RangeError> {
+    if pool_size(ipnet.max_prefix_len() - ipnet.prefix_len()) == 0 {
+        return Err(RangeError::TooSmall(ipnet));
+    }
+
+    for (special, name) in SPECIAL_USE_RANGES {
+        let special: IpNet = special.parse().unwrap();
+        if special.contains(&ipnet.network()) || ipnet.contains(&special.network()) {
+            return Err(RangeError::SpecialUse(ipnet, special, name));
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -232,14 +344,50 @@ mod tests {
     fn test_gen_next_ip() {
         let ipnet: ipnet::Ipv4Net = "198.18.0.0/15".parse().unwrap();
         let ipnet6: ipnet::Ipv6Net = "2001:db8::/32".parse().unwrap();
+        assert_eq!(gen_next_ipv4(&ipnet, 0), "198.18.0.2".parse::<Ipv4Addr>().unwrap());
+        assert_eq!(gen_next_ipv6(&ipnet6, 0), "2001:db8::2".parse::<Ipv6Addr>().unwrap());
+
+        // the broadcast is never allocated, whatever the prefix length
+        for prefix in [16, 23, 29, 30] {
+            let ipnet = Ipv4Net::new(Ipv4Addr::new(198, 18, 0, 0), prefix).unwrap();
+            let total = pool_size(32 - prefix) as u32;
+            let last = gen_next_ipv4(&ipnet, total - 1);
+            assert_eq!(u32::from(last) + 1, u32::from(ipnet.broadcast()));
+        }
+        assert_eq!(pool_size(2), 1);
+        assert_eq!(pool_size(1), 0);
+        assert_eq!(pool_size(0), 0);
+        assert_eq!(pool_size(128), u128::MAX - 2);
+        let ipnet6: ipnet::Ipv6Net = "2001:db8::/125".parse().unwrap();
         assert_eq!(
-            gen_next_ipv4(&ipnet, 0),
-            "198.18.0.2".parse::<Ipv4Addr>().unwrap()
+            gen_next_ipv6(&ipnet6, pool_size(3) - 1),
+            "2001:db8::6".parse::<Ipv6Addr>().unwrap()
         );
+    }
+
+    #[test]
+    fn test_check_range() {
+        let check = |net: &str| check_range(net.parse().unwrap());
+        assert_eq!(check("198.18.0.0/15"), Ok(()));
+        assert_eq!(check("2001:db8::/32"), Ok(()));
+        assert_eq!(check("fc00::/18"), Ok(()));
+        assert_eq!(check("198.18.0.0/30"), Ok(()));
+
         assert_eq!(
-            gen_next_ipv6(&ipnet6, 0),
-            "2001:db8::2".parse::<Ipv6Addr>().unwrap()
+            check("198.18.0.0/31"),
+            Err(RangeError::TooSmall("198.18.0.0/31".parse().unwrap()))
         );
+        assert_eq!(
+            check("192.168.100.0/24").unwrap_err().to_string(),
+            "192.168.100.0/24 overlaps the private-use range 192.168.0.0/16, which may be in use on the LAN"
+        );
+        // a range containing a special one
+        assert!(matches!(
+            check("8.0.0.0/5"),
+            Err(RangeError::SpecialUse(_, _, "private-use"))
+        ));
+        assert!(check("fd12:3456::/32").is_err());
+        assert!(check("::/0").is_err());
     }
 
     fn create_fakedns() -> FakeDns {
@@ -293,10 +441,7 @@ mod tests {
 
         assert_eq!(fakedns.should_skipped(String::from("example.com")), true);
         assert_eq!(fakedns.should_skipped(String::from("foo.bar")), false);
-        assert_eq!(
-            fakedns.is_fake_ip(Ipv4Addr::new(198, 18, 0, 2).into()),
-            true
-        );
+        assert_eq!(fakedns.is_fake_ip(Ipv4Addr::new(198, 18, 0, 2).into()), true);
         assert_eq!(
             fakedns.is_fake_ip(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2).into()),
             true
@@ -317,19 +462,29 @@ mod tests {
 
         assert_eq!(fakedns.exist(foobar), true);
         assert_eq!(fakedns.exist(foobar6), true);
+
+        assert_eq!(
+            fakedns.mappings(),
+            vec![
+                (foobar, "foo.bar".to_owned()),
+                (barfoo, "bar.foo".to_owned()),
+                (foobar6, "foo.bar".to_owned()),
+                (barfoo6, "bar.foo".to_owned()),
+            ]
+        );
     }
 
     #[test]
     #[tracing_test::traced_test]
     fn test_fakedns_cycle() {
         let mut fakedns = create_fakedns();
+        // a /29 holds 5 fake ips
         let hosts = [
             "test1.example.com",
             "test2.example.com",
             "test3.example.com",
             "test4.example.com",
             "test5.example.com",
-            "test6.example.com",
         ];
 
         hosts.iter().for_each(|host| {
@@ -341,6 +496,54 @@ mod tests {
         assert_eq!(first, cycled);
     }
 
+    #[test]
+    fn test_fakedns_independent_ipv6() {
+        let config = Config {
+            ipnet: "198.18.0.0/29".parse().unwrap(),
+            ipnet6: "2001:db8::/120".parse().unwrap(),
+            size: 16,
+            ..Default::default()
+        };
+        let mut fakedns = FakeDns::new(config);
+
+        // ip6 only hosts don't take ip4 from the pool
+        let ip6 = fakedns.lookup_ip("v6only.example.com", true).unwrap();
+        assert_eq!(ip6, "2001:db8::2".parse::<Ipv6Addr>().unwrap());
+        let first = fakedns.lookup_ip("test1.example.com", false).unwrap();
+        assert_eq!(first, Ipv4Addr::new(198, 18, 0, 2));
+
+        // the ip4 pool wraps around, ip6 is still unique
+        let mut ip6s = vec![ip6];
+        for i in 1..=8 {
+            let host = format!("test{}.example.com", i);
+            fakedns.lookup_ip(&host, false).unwrap();
+            let ip6 = fakedns.lookup_ip(&host, true).unwrap();
+            assert!(!ip6s.contains(&ip6));
+            ip6s.push(ip6);
+        }
+        assert_eq!(fakedns.lookup_host(first), Some("test6.example.com".into()));
+        // the evicted host keeps its ip6
+        assert_eq!(fakedns.lookup_host(ip6s[1]), Some("test1.example.com".into()));
+        assert_eq!(fakedns.lookup_host(ip6s[0]), Some("v6only.example.com".into()));
+    }
+
+    #[test]
+    fn test_fakedns_ipv6_disabled() {
+        let config = Config {
+            ipnet6: "2001:db8::/128".parse().unwrap(),
+            ipv6: false,
+            ..Default::default()
+        };
+        let mut fakedns = FakeDns::new(config);
+
+        assert!(!fakedns.ipv6());
+        assert_eq!(fakedns.lookup_ip("foo.bar", true), None);
+        assert_eq!(
+            fakedns.lookup_ip("foo.bar", false),
+            Some(Ipv4Addr::new(198, 18, 0, 2).into())
+        );
+    }
+
     #[test]
     fn test_fakedns_max_cache_size() {
         let mut fakedns = create_fakedns_with_minsize();
@@ -0,0 +1,143 @@
+//! MaxMind database lookups backing the `GEOIP` and `IP-ASN` rules.
+//!
+//! Country and ASN databases are loaded the same way: memory mapped from disk and swapped in
+//! place by [`GeoIpDb::reload`] after the file was updated, so lookups never block on I/O.
+//! The answers of the latest lookups are cached, a reload clears them.
+
+use std::{
+    io,
+    net::IpAddr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+};
+
+use lru::LruCache;
+use maxminddb::{geoip2, MaxMindDBError, Mmap, Reader};
+
+use crate::log::*;
+
+/// Addresses whose countries and autonomous systems are cached.
+const CACHE_SIZE: usize = 4096;
+
+pub struct GeoIpDb {
+    path: PathBuf,
+    reader: RwLock<Reader<Mmap>>,
+    countries: Mutex<LruCache<IpAddr, Option<String>>>,
+    asns: Mutex<LruCache<IpAddr, Option<u32>>>,
+}
+
+impl GeoIpDb {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let reader = open_reader(&path)?;
+        info!("loaded {} database from {:?}", reader.metadata.database_type, path);
+
+        Ok(Self {
+            path,
+            reader: RwLock::new(reader),
+            countries: Mutex::new(LruCache::new(NonZeroUsize::new(CACHE_SIZE).unwrap())),
+            asns: Mutex::new(LruCache::new(NonZeroUsize::new(CACHE_SIZE).unwrap())),
+        })
+    }
+
+    /// Checks that `path` is a database without loading it, e.g. a download before it replaces
+    /// the loaded one.
+    pub fn check<P: AsRef<Path>>(path: P) -> io::Result<()> {
+        open_reader(path.as_ref()).map(|_| ())
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reopens the database file, the loaded database is kept if the new one is invalid.
+    pub fn reload(&self) -> io::Result<()> {
+        let reader = open_reader(&self.path)?;
+        info!(
+            "reloaded {} database from {:?}",
+            reader.metadata.database_type, self.path
+        );
+        // cleared under the write lock, a lookup of the old database can't cache its answer after
+        let mut loaded = self.reader.write().unwrap();
+        *loaded = reader;
+        self.countries.lock().unwrap().clear();
+        self.asns.lock().unwrap().clear();
+        Ok(())
+    }
+
+    /// Returns the ISO 3166 country code of `ip`, e.g. `CN`.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        if let Some(country) = self.countries.lock().unwrap().get(&ip) {
+            return country.clone();
+        }
+        let reader = self.reader.read().unwrap();
+        let country: Option<geoip2::Country> = reader.lookup(ip).ok();
+        let country = country
+            .and_then(|c| c.country)
+            .and_then(|c| c.iso_code)
+            .map(|s| s.to_owned());
+        self.countries.lock().unwrap().put(ip, country.clone());
+        country
+    }
+
+    /// Returns the autonomous system number of `ip`, e.g. `13335`.
+    pub fn asn(&self, ip: IpAddr) -> Option<u32> {
+        if let Some(asn) = self.asns.lock().unwrap().get(&ip) {
+            return *asn;
+        }
+        let reader = self.reader.read().unwrap();
+        let asn: Option<geoip2::Asn> = reader.lookup(ip).ok();
+        let asn = asn.and_then(|asn| asn.autonomous_system_number);
+        self.asns.lock().unwrap().put(ip, asn);
+        asn
+    }
+
+    /// Returns the organization owning the autonomous system of `ip`.
+    pub fn asn_organization(&self, ip: IpAddr) -> Option<String> {
+        let reader = self.reader.read().unwrap();
+        let asn: geoip2::Asn = reader.lookup(ip).ok()?;
+        asn.autonomous_system_organization.map(|s| s.to_owned())
+    }
+}
+
+fn open_reader(path: &Path) -> io::Result<Reader<Mmap>> {
+    Reader::open_mmap(path).map_err(|err| match err {
+        MaxMindDBError::IoError(msg) => io::Error::new(io::ErrorKind::NotFound, format!("{:?}: {}", path, msg)),
+        err => io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", path, err)),
+    })
+}
+
+/// Parses the payload of an `IP-ASN` rule, with or without the `AS` prefix.
+pub fn parse_asn(s: &str) -> Option<u32> {
+    let s = s.trim();
+    let s = s.strip_prefix("AS").or_else(|| s.strip_prefix("as")).unwrap_or(s);
+    s.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_asn() {
+        assert_eq!(parse_asn("13335"), Some(13335));
+        assert_eq!(parse_asn("AS13335"), Some(13335));
+        assert_eq!(parse_asn(" as4134 "), Some(4134));
+        assert_eq!(parse_asn("cloudflare"), None);
+        assert_eq!(parse_asn(""), None);
+    }
+
+    #[test]
+    fn test_open_invalid_database() {
+        let err = GeoIpDb::open("/nonexistent/GeoLite2-ASN.mmdb").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let path = std::env::temp_dir().join("swiftlink-invalid.mmdb");
+        std::fs::write(&path, b"not a maxmind database").unwrap();
+        let err = GeoIpDb::open(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(GeoIpDb::check(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,315 @@
+//! v2ray `geosite.dat` domain lists backing the `GEOSITE` rules.
+//!
+//! The file is the protobuf encoded `GeoSiteList` of domain-list-community, one `GeoSite` of
+//! domains per category. Only the categories the rules name are loaded, each into a
+//! [`DomainTrie`] and a list of keywords:
+//!
+//! ```text
+//! GeoSiteList { repeated GeoSite entry = 1; }
+//! GeoSite     { string country_code = 1; repeated Domain domain = 2; }
+//! Domain      { Type type = 1; string value = 2; repeated Attribute attribute = 3; }
+//! Attribute   { string key = 1; ... }
+//! Type        { Plain = 0; Regex = 1; RootDomain = 2; Full = 3; }
+//! ```
+//!
+//! A category of `google@cn` has the domains of `google` with the `cn` attribute. Regex domains
+//! aren't supported, they're skipped.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::Path,
+};
+
+use crate::{log::*, trie::domain_trie::DomainTrie};
+
+/// The domains of a category.
+#[derive(Debug, Default)]
+struct DomainSet {
+    domains: DomainTrie<()>,
+    keywords: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct GeoSite {
+    categories: HashMap<String, DomainSet>,
+}
+
+impl GeoSite {
+    /// Loads `categories` of the `geosite.dat` at `path`, e.g. `google` or `google@cn`, the ones
+    /// the file doesn't have are left out.
+    pub fn load<P: AsRef<Path>>(path: P, categories: &[&str]) -> io::Result<Self> {
+        let path = path.as_ref();
+        let with_path = |err: io::Error| io::Error::new(err.kind(), format!("{:?}: {}", path, err));
+        let data = std::fs::read(path).map_err(with_path)?;
+        let geosite = Self::parse(&data, categories).map_err(with_path)?;
+        info!("loaded {} geosite categories from {:?}", geosite.categories.len(), path);
+        Ok(geosite)
+    }
+
+    /// Parses `categories` of a `geosite.dat`.
+    pub fn parse(data: &[u8], categories: &[&str]) -> io::Result<Self> {
+        // category name -> the attributes wanted of it, `None` for all of its domains
+        let mut wanted: HashMap<String, Vec<Option<String>>> = HashMap::new();
+        for category in categories {
+            let category = category.to_ascii_lowercase();
+            let (name, attr) = match category.split_once('@') {
+                Some((name, attr)) => (name.to_owned(), Some(attr.to_owned())),
+                None => (category, None),
+            };
+            wanted.entry(name).or_default().push(attr);
+        }
+
+        let mut geosite = GeoSite::default();
+        for field in Fields(data) {
+            let Value::Bytes(entry) = field_of(field?, 1) else {
+                continue;
+            };
+            let Some(name) = Fields(entry).find_map(|field| match field {
+                Ok((1, Value::Bytes(name))) => Some(String::from_utf8_lossy(name).to_ascii_lowercase()),
+                _ => None,
+            }) else {
+                continue;
+            };
+            let Some(attrs) = wanted.get(&name) else {
+                continue;
+            };
+            for attr in attrs {
+                let category = match attr {
+                    Some(attr) => format!("{}@{}", name, attr),
+                    None => name.clone(),
+                };
+                let set = parse_category(entry, attr.as_deref(), &category)?;
+                geosite.categories.insert(category, set);
+            }
+        }
+        Ok(geosite)
+    }
+
+    /// Whether the category was loaded.
+    #[inline]
+    pub fn contains(&self, category: &str) -> bool {
+        self.categories.contains_key(category)
+    }
+
+    /// Whether `domain`, lowercase and without the trailing dot, is one of `category`.
+    pub fn matches(&self, category: &str, domain: &str) -> bool {
+        let Some(set) = self.categories.get(category) else {
+            return false;
+        };
+        set.domains.search(domain.to_owned()).is_some()
+            || set.keywords.iter().any(|keyword| domain.contains(keyword.as_str()))
+    }
+}
+
+/// Collects the domains of a `GeoSite`, the ones with `attr` only if it's set.
+fn parse_category(entry: &[u8], attr: Option<&str>, category: &str) -> io::Result<DomainSet> {
+    let mut set = DomainSet::default();
+    let mut regexes = 0;
+    for field in Fields(entry) {
+        let Value::Bytes(domain) = field_of(field?, 2) else {
+            continue;
+        };
+
+        let mut tp = 0;
+        let mut value = None;
+        let mut attrs = HashSet::new();
+        for field in Fields(domain) {
+            match field? {
+                (1, Value::Varint(v)) => tp = v,
+                (2, Value::Bytes(v)) => value = Some(String::from_utf8_lossy(v).to_ascii_lowercase()),
+                (3, Value::Bytes(v)) => {
+                    for field in Fields(v) {
+                        if let (1, Value::Bytes(key)) = field? {
+                            attrs.insert(String::from_utf8_lossy(key).to_ascii_lowercase());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let Some(value) = value.filter(|value| !value.is_empty()) else {
+            continue;
+        };
+        if attr.is_some_and(|attr| !attrs.contains(attr)) {
+            continue;
+        }
+
+        let inserted = match tp {
+            0 => {
+                set.keywords.push(value);
+                Ok(())
+            }
+            1 => {
+                regexes += 1;
+                Ok(())
+            }
+            2 => set.domains.insert(format!("+.{}", value), ()),
+            3 => set.domains.insert(value, ()),
+            _ => continue,
+        };
+        if let Err(err) = inserted {
+            debug!("ignore domain of geosite {}, {}", category, err);
+        }
+    }
+    if regexes > 0 {
+        debug!("skipped {} regex domains of geosite {}", regexes, category);
+    }
+    Ok(set)
+}
+
+fn field_of(field: (u64, Value<'_>), number: u64) -> Value<'_> {
+    match field {
+        (n, value) if n == number => value,
+        _ => Value::Skipped,
+    }
+}
+
+/// A protobuf field value, fixed-size ones are skipped.
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Skipped,
+}
+
+/// The fields of a protobuf message, as field number and value.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.0.split_first().ok_or_else(truncated)?;
+            self.0 = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::InvalidData, "varint too long"))
+    }
+
+    fn take(&mut self, len: u64) -> io::Result<&'a [u8]> {
+        let len = usize::try_from(len).map_err(|_| truncated())?;
+        if self.0.len() < len {
+            return Err(truncated());
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn field(&mut self) -> io::Result<(u64, Value<'a>)> {
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            0 => Value::Varint(self.varint()?),
+            1 => self.take(8).map(|_| Value::Skipped)?,
+            2 => {
+                let len = self.varint()?;
+                Value::Bytes(self.take(len)?)
+            }
+            5 => self.take(4).map(|_| Value::Skipped)?,
+            wire_type => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported protobuf wire type {}", wire_type),
+                ))
+            }
+        };
+        Ok((key >> 3, value))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = io::Result<(u64, Value<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let field = self.field();
+        if field.is_err() {
+            // nothing can be read after a malformed field
+            self.0 = &[];
+        }
+        Some(field)
+    }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "truncated protobuf message")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn len_field(number: u64, data: &[u8]) -> Vec<u8> {
+        let mut field = vec![(number << 3 | 2) as u8, data.len() as u8];
+        field.extend_from_slice(data);
+        field
+    }
+
+    fn domain(tp: u8, value: &str, attrs: &[&str]) -> Vec<u8> {
+        let mut domain = vec![1 << 3, tp];
+        domain.extend(len_field(2, value.as_bytes()));
+        for attr in attrs {
+            // an attribute with a bool value
+            let mut attr = len_field(1, attr.as_bytes());
+            attr.extend([2 << 3, 1]);
+            domain.extend(len_field(3, &attr));
+        }
+        len_field(2, &domain)
+    }
+
+    fn site(name: &str, domains: &[Vec<u8>]) -> Vec<u8> {
+        let mut site = len_field(1, name.as_bytes());
+        for domain in domains {
+            site.extend_from_slice(domain);
+        }
+        len_field(1, &site)
+    }
+
+    #[test]
+    fn test_geosite() {
+        let mut data = site(
+            "GOOGLE",
+            &[
+                domain(2, "google.com", &[]),
+                domain(3, "www.google.cn", &["cn"]),
+                domain(0, "googleapis", &[]),
+                domain(1, "^ads[0-9]+\\.google\\.com$", &[]),
+            ],
+        );
+        data.extend(site("CN", &[domain(2, "cn", &[])]));
+
+        let geosite = GeoSite::parse(&data, &["google", "Google@cn", "netflix"]).unwrap();
+        assert!(!geosite.contains("cn"));
+        assert!(!geosite.contains("netflix"));
+
+        assert!(geosite.matches("google", "google.com"));
+        assert!(geosite.matches("google", "mail.google.com"));
+        assert!(geosite.matches("google", "www.google.cn"));
+        assert!(!geosite.matches("google", "google.cn"));
+        assert!(geosite.matches("google", "fonts.googleapis.net"));
+        assert!(!geosite.matches("google", "ads1.google.org"));
+        assert!(!geosite.matches("google", "example.com"));
+
+        assert!(geosite.matches("google@cn", "www.google.cn"));
+        assert!(!geosite.matches("google@cn", "google.com"));
+        assert!(!geosite.matches("cn", "www.example.cn"));
+    }
+
+    #[test]
+    fn test_geosite_invalid() {
+        let mut data = site("GOOGLE", &[domain(2, "google.com", &[])]);
+        data.truncate(data.len() - 3);
+        assert_eq!(
+            GeoSite::parse(&data, &["google"]).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let err = GeoSite::load("/nonexistent/geosite.dat", &["google"]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,241 @@
+//! The `adaptive` strategy, also known as `smart`.
+//!
+//! Each member is scored by its moving averages of latency, failure rate and throughput:
+//!
+//! ```text
+//! score = latency_ms * (1 + failure_penalty * failure_rate) / (1 + throughput / throughput_scale)
+//! ```
+//!
+//! The member with the lowest score wins, but replaces the current one only if it scores at
+//! least `hysteresis` better, two similar proxies don't take turns with every health check.
+
+use std::{sync::Mutex, time::Duration};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveParams {
+    /// weight of a new measurement in the moving averages, `0..=1`
+    pub alpha: f64,
+    /// fraction a member must score better than the current one to replace it
+    pub hysteresis: f64,
+    /// latency factor of a member which always fails, on top of its latency
+    pub failure_penalty: f64,
+    /// bytes per second of throughput which halve the score
+    pub throughput_scale: f64,
+}
+
+impl Default for AdaptiveParams {
+    fn default() -> Self {
+        Self {
+            alpha: 0.3,
+            hysteresis: 0.2,
+            failure_penalty: 10.0,
+            throughput_scale: 1024.0 * 1024.0,
+        }
+    }
+}
+
+/// Moving averages of a member, `None` until measured.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MemberScore {
+    pub latency_ms: Option<f64>,
+    /// share of failed checks, `0..=1`
+    pub failure_rate: f64,
+    /// bytes per second
+    pub throughput: Option<f64>,
+}
+
+impl MemberScore {
+    /// The score of the member, lower is better, `None` until its latency was measured.
+    pub fn score(&self, params: &AdaptiveParams) -> Option<f64> {
+        let latency = self.latency_ms?;
+        let throughput = self.throughput.unwrap_or_default();
+        Some(
+            latency * (1.0 + params.failure_penalty * self.failure_rate) / (1.0 + throughput / params.throughput_scale),
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct AdaptiveSelector {
+    params: AdaptiveParams,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    members: Vec<(String, MemberScore)>,
+    current: Option<usize>,
+}
+
+impl AdaptiveSelector {
+    pub fn new<I: IntoIterator<Item = String>>(members: I, params: AdaptiveParams) -> Self {
+        Self {
+            params,
+            state: Mutex::new(State {
+                members: members.into_iter().map(|name| (name, MemberScore::default())).collect(),
+                current: None,
+            }),
+        }
+    }
+
+    /// Records a health check of `member`, `None` if it failed.
+    pub fn record_check(&self, member: &str, latency: Option<Duration>) {
+        let alpha = self.params.alpha;
+        self.update(member, |score| match latency {
+            Some(latency) => {
+                let latency = latency.as_secs_f64() * 1000.0;
+                score.latency_ms = Some(score.latency_ms.map_or(latency, |avg| ewma(avg, latency, alpha)));
+                score.failure_rate = ewma(score.failure_rate, 0.0, alpha);
+            }
+            None => score.failure_rate = ewma(score.failure_rate, 1.0, alpha),
+        });
+    }
+
+    /// Records the throughput of a connection or a speed test through `member`.
+    pub fn record_throughput(&self, member: &str, bytes_per_sec: u64) {
+        let alpha = self.params.alpha;
+        let bytes_per_sec = bytes_per_sec as f64;
+        self.update(member, |score| {
+            score.throughput = Some(
+                score
+                    .throughput
+                    .map_or(bytes_per_sec, |avg| ewma(avg, bytes_per_sec, alpha)),
+            );
+        });
+    }
+
+    fn update<F: FnOnce(&mut MemberScore)>(&self, member: &str, f: F) {
+        let mut state = self.state.lock().unwrap();
+        if let Some((_, score)) = state.members.iter_mut().find(|(name, _)| name == member) {
+            f(score);
+        }
+    }
+
+    /// Returns the member for the next connection, `None` if the group is empty.
+    ///
+    /// Members which were never measured are only used while no member was.
+    pub fn select(&self) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        let scores = state
+            .members
+            .iter()
+            .map(|(_, score)| score.score(&self.params))
+            .collect::<Vec<_>>();
+
+        let best = scores
+            .iter()
+            .enumerate()
+            .filter_map(|(index, score)| score.map(|score| (index, score)))
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        let current = state
+            .current
+            .and_then(|index| scores[index].map(|score| (index, score)));
+
+        let selected = match (current, best) {
+            (Some((current, score)), Some((_, best))) if best >= score * (1.0 - self.params.hysteresis) => current,
+            (_, Some((best, _))) => best,
+            (_, None) => state.current.unwrap_or(0),
+        };
+        let name = state.members.get(selected)?.0.clone();
+        state.current = Some(selected);
+        Some(name)
+    }
+
+    /// Makes `member` the current one, e.g. the one selected before a restart. It's kept until
+    /// another member scores better by the hysteresis. Returns `false` if it isn't a member.
+    pub fn restore(&self, member: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(index) = state.members.iter().position(|(name, _)| name == member) else {
+            return false;
+        };
+        state.current = Some(index);
+        true
+    }
+
+    /// The member last returned by [`select`](Self::select) or restored.
+    pub fn current(&self) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state.current.map(|index| state.members[index].0.clone())
+    }
+
+    /// The members with their moving averages, in the configured order.
+    pub fn scores(&self) -> Vec<(String, MemberScore)> {
+        self.state.lock().unwrap().members.clone()
+    }
+}
+
+#[inline]
+fn ewma(avg: f64, value: f64, alpha: f64) -> f64 {
+    avg + alpha * (value - avg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selector() -> AdaptiveSelector {
+        AdaptiveSelector::new(["hk".to_owned(), "jp".to_owned()], AdaptiveParams::default())
+    }
+
+    #[test]
+    fn test_adaptive_select() {
+        assert_eq!(AdaptiveSelector::new([], AdaptiveParams::default()).select(), None);
+
+        let group = selector();
+        assert_eq!(group.select().as_deref(), Some("hk"));
+
+        group.record_check("hk", Some(Duration::from_millis(100)));
+        group.record_check("jp", Some(Duration::from_millis(50)));
+        assert_eq!(group.select().as_deref(), Some("jp"));
+
+        // slightly faster isn't worth switching
+        for _ in 0..3 {
+            group.record_check("hk", Some(Duration::from_millis(10)));
+        }
+        assert!(group.scores()[0].1.latency_ms.unwrap() < 45.0);
+        assert_eq!(group.select().as_deref(), Some("jp"));
+
+        // much faster is
+        group.record_check("hk", Some(Duration::from_millis(10)));
+        assert_eq!(group.select().as_deref(), Some("hk"));
+    }
+
+    #[test]
+    fn test_adaptive_failures_and_throughput() {
+        let group = selector();
+        group.record_check("hk", Some(Duration::from_millis(50)));
+        group.record_check("jp", Some(Duration::from_millis(80)));
+        assert_eq!(group.select().as_deref(), Some("hk"));
+
+        group.record_check("hk", None);
+        let hk = group.scores()[0].1;
+        assert_eq!(hk.latency_ms, Some(50.0));
+        assert!((hk.failure_rate - 0.3).abs() < 1e-9);
+        assert_eq!(group.select().as_deref(), Some("jp"));
+
+        // recovering, but jp carries much more
+        group.record_check("hk", Some(Duration::from_millis(50)));
+        group.record_throughput("jp", 8 * 1024 * 1024);
+        group.record_throughput("unknown", 8 * 1024 * 1024);
+        let jp = group.scores()[1].1;
+        assert_eq!(jp.score(&AdaptiveParams::default()), Some(80.0 / 9.0));
+        assert_eq!(group.select().as_deref(), Some("jp"));
+    }
+
+    #[test]
+    fn test_adaptive_restore() {
+        let group = selector();
+        assert!(!group.restore("sg"));
+        assert_eq!(group.current(), None);
+        assert!(group.restore("jp"));
+        assert_eq!(group.select().as_deref(), Some("jp"));
+
+        // kept while not much worse
+        group.record_check("hk", Some(Duration::from_millis(90)));
+        group.record_check("jp", Some(Duration::from_millis(100)));
+        assert_eq!(group.select().as_deref(), Some("jp"));
+        group.record_check("hk", Some(Duration::from_millis(10)));
+        assert_eq!(group.select().as_deref(), Some("hk"));
+        assert_eq!(group.current().as_deref(), Some("hk"));
+    }
+}
@@ -0,0 +1,72 @@
+//! The `fallback` strategy, the first member in the configured order which is alive.
+//!
+//! A member is alive until a health check of it fails, and again once one succeeds. While all
+//! members are down, the first one is used, none is better than another.
+
+use std::{sync::Mutex, time::Duration};
+
+#[derive(Debug)]
+pub struct FallbackSelector {
+    /// name and whether the last check succeeded, in the configured order
+    members: Mutex<Vec<(String, bool)>>,
+}
+
+impl FallbackSelector {
+    pub fn new<I: IntoIterator<Item = String>>(members: I) -> Self {
+        Self {
+            members: Mutex::new(members.into_iter().map(|name| (name, true)).collect()),
+        }
+    }
+
+    /// Records a health check of `member`, `None` if it failed.
+    pub fn record_check(&self, member: &str, latency: Option<Duration>) {
+        let mut members = self.members.lock().unwrap();
+        if let Some((_, alive)) = members.iter_mut().find(|(name, _)| name == member) {
+            *alive = latency.is_some();
+        }
+    }
+
+    /// Whether the last check of `member` succeeded, `false` if it isn't a member.
+    pub fn is_alive(&self, member: &str) -> bool {
+        let members = self.members.lock().unwrap();
+        members.iter().any(|(name, alive)| name == member && *alive)
+    }
+
+    /// Returns the member for the next connection, `None` if the group is empty.
+    pub fn select(&self) -> Option<String> {
+        let members = self.members.lock().unwrap();
+        members
+            .iter()
+            .find(|(_, alive)| *alive)
+            .or_else(|| members.first())
+            .map(|(name, _)| name.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fallback_select() {
+        assert_eq!(FallbackSelector::new([]).select(), None);
+
+        let group = FallbackSelector::new(["hk".to_owned(), "jp".to_owned(), "sg".to_owned()]);
+        assert_eq!(group.select().as_deref(), Some("hk"));
+
+        group.record_check("hk", None);
+        group.record_check("jp", None);
+        assert!(!group.is_alive("hk"));
+        assert!(group.is_alive("sg"));
+        assert_eq!(group.select().as_deref(), Some("sg"));
+
+        // back in front as soon as it recovered
+        group.record_check("hk", Some(Duration::from_millis(300)));
+        assert_eq!(group.select().as_deref(), Some("hk"));
+
+        group.record_check("hk", None);
+        group.record_check("sg", None);
+        assert_eq!(group.select().as_deref(), Some("hk"));
+        assert!(!group.is_alive("unknown"));
+    }
+}
@@ -0,0 +1,201 @@
+//! The `load-balance` strategy, connections spread over the alive members.
+//!
+//! Consistent hashing picks the member by weighted rendezvous hashing of the destination host: a
+//! site sees the same exit across connections, and a member going down moves only its own sites.
+//! Round robin rotates through the members, each as often as its weight says. While all members
+//! are down, all of them are balanced over.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::Mutex,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// How a [`LoadBalancer`] picks the member of a connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Balance {
+    /// Hash the destination host, so a site always sees the same member
+    #[default]
+    ConsistentHashing,
+    /// Rotate through the members
+    RoundRobin,
+}
+
+/// A member and its share of the connections.
+#[derive(Debug)]
+struct Member {
+    name: String,
+    weight: u32,
+    /// whether the last check succeeded
+    alive: bool,
+    /// the credit of the smooth weighted round robin
+    current: i64,
+}
+
+#[derive(Debug)]
+pub struct LoadBalancer {
+    strategy: Balance,
+    /// in the configured order
+    members: Mutex<Vec<Member>>,
+}
+
+impl LoadBalancer {
+    /// Balances over `members` evenly.
+    pub fn new<I: IntoIterator<Item = String>>(members: I, strategy: Balance) -> Self {
+        Self::weighted(members.into_iter().map(|name| (name, 1)), strategy)
+    }
+
+    /// Balances over `members` in proportion to their weights, e.g. `70` and `30`. A weight of
+    /// `0` counts as `1`.
+    pub fn weighted<I: IntoIterator<Item = (String, u32)>>(members: I, strategy: Balance) -> Self {
+        let members = members
+            .into_iter()
+            .map(|(name, weight)| Member {
+                name,
+                weight: weight.max(1),
+                alive: true,
+                current: 0,
+            })
+            .collect();
+        Self {
+            strategy,
+            members: Mutex::new(members),
+        }
+    }
+
+    #[inline]
+    pub fn strategy(&self) -> Balance {
+        self.strategy
+    }
+
+    /// Records a health check of `member`, `None` if it failed.
+    pub fn record_check(&self, member: &str, latency: Option<Duration>) {
+        let mut members = self.members.lock().unwrap();
+        if let Some(member) = members.iter_mut().find(|m| m.name == member) {
+            member.alive = latency.is_some();
+        }
+    }
+
+    /// Whether the last check of `member` succeeded, `false` if it isn't a member.
+    pub fn is_alive(&self, member: &str) -> bool {
+        let members = self.members.lock().unwrap();
+        members.iter().any(|m| m.name == member && m.alive)
+    }
+
+    /// Returns the member of a connection to `host`, `None` if the group is empty.
+    pub fn select(&self, host: &str) -> Option<String> {
+        let mut members = self.members.lock().unwrap();
+        let any_alive = members.iter().any(|member| member.alive);
+        let mut candidates: Vec<&mut Member> = members.iter_mut().filter(|member| member.alive || !any_alive).collect();
+
+        let member = match self.strategy {
+            Balance::ConsistentHashing => candidates
+                .into_iter()
+                .map(|member| (rendezvous_score(host, member), member))
+                .max_by(|a, b| a.0.total_cmp(&b.0))
+                .map(|(_, member)| member),
+            // nginx's smooth weighted round robin, a 70/30 split doesn't send 7 in a row
+            Balance::RoundRobin => {
+                let total: i64 = candidates.iter().map(|member| member.weight as i64).sum();
+                for member in candidates.iter_mut() {
+                    member.current += member.weight as i64;
+                }
+                let member = candidates
+                    .into_iter()
+                    .reduce(|a, b| if b.current > a.current { b } else { a })?;
+                member.current -= total;
+                Some(member)
+            }
+        };
+        member.map(|member| member.name.clone())
+    }
+}
+
+/// The weighted rendezvous score of `member` for `host`, the member of the highest one carries
+/// the connection.
+fn rendezvous_score(host: &str, member: &Member) -> f64 {
+    let mut hasher = DefaultHasher::new();
+    (host, &member.name).hash(&mut hasher);
+    // uniform in (0, 1)
+    let unit = ((hasher.finish() >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+    -(member.weight as f64) / unit.ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members() -> [String; 3] {
+        ["hk".to_owned(), "jp".to_owned(), "sg".to_owned()]
+    }
+
+    #[test]
+    fn test_load_balance_round_robin() {
+        assert_eq!(LoadBalancer::new([], Balance::RoundRobin).select("example.com"), None);
+
+        let group = LoadBalancer::new(members(), Balance::RoundRobin);
+        let picked: Vec<_> = (0..4).map(|_| group.select("example.com").unwrap()).collect();
+        assert_eq!(picked, ["hk", "jp", "sg", "hk"]);
+
+        group.record_check("jp", None);
+        assert!(!group.is_alive("jp"));
+        assert!((0..6).all(|_| group.select("example.com").as_deref() != Some("jp")));
+    }
+
+    #[test]
+    fn test_load_balance_consistent_hashing() {
+        assert_eq!(
+            LoadBalancer::new([], Balance::ConsistentHashing).select("example.com"),
+            None
+        );
+
+        let group = LoadBalancer::new(members(), Balance::ConsistentHashing);
+        let hosts: Vec<_> = (0..64).map(|i| format!("site{}.example.com", i)).collect();
+        let before: Vec<_> = hosts.iter().map(|host| group.select(host).unwrap()).collect();
+        assert_eq!(group.select(&hosts[0]), Some(before[0].clone()));
+        for member in members() {
+            assert!(before.contains(&member), "{} carries no site", member);
+        }
+
+        // only the sites of the dead member move
+        group.record_check("jp", None);
+        for (host, member) in hosts.iter().zip(before.iter()) {
+            let now = group.select(host).unwrap();
+            match member.as_str() {
+                "jp" => assert_ne!(now, "jp"),
+                _ => assert_eq!(now, *member),
+            }
+        }
+
+        // all down, all balanced over
+        group.record_check("hk", None);
+        group.record_check("sg", None);
+        assert_eq!(group.select(&hosts[0]), Some(before[0].clone()));
+    }
+
+    #[test]
+    fn test_load_balance_weighted() {
+        let weighted = || [("hk".to_owned(), 70), ("jp".to_owned(), 30)];
+
+        let group = LoadBalancer::weighted(weighted(), Balance::RoundRobin);
+        let picked: Vec<_> = (0..10).map(|_| group.select("example.com").unwrap()).collect();
+        assert_eq!(picked, ["hk", "jp", "hk", "hk", "hk", "jp", "hk", "hk", "jp", "hk"]);
+        group.record_check("hk", None);
+        assert!((0..3).all(|_| group.select("example.com").as_deref() == Some("jp")));
+
+        let group = LoadBalancer::weighted(weighted(), Balance::ConsistentHashing);
+        let hk = (0..1000)
+            .filter(|i| group.select(&format!("site{}.example.com", i)).as_deref() == Some("hk"))
+            .count();
+        assert!((620..780).contains(&hk), "{} of 1000 sites on hk", hk);
+
+        // a weight of 0 is a weight of 1
+        let group = LoadBalancer::weighted([("hk".to_owned(), 0), ("jp".to_owned(), 1)], Balance::RoundRobin);
+        let picked: Vec<_> = (0..2).map(|_| group.select("example.com").unwrap()).collect();
+        assert_eq!(picked, ["hk", "jp"]);
+    }
+}
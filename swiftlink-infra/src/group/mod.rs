@@ -0,0 +1,26 @@
+//! Member selection of proxy groups.
+//!
+//! A group knows its members by name, feeds their measurements into a selector and asks it which
+//! member carries the next connection. Its selection is remembered across restarts with
+//! [`selection`].
+//!
+//! - `select`: [`Selector`], the member the user chose
+//! - `fallback`: [`FallbackSelector`], the first member which is alive
+//! - `url-test`: [`UrlTestSelector`], the member of the lowest latency
+//! - `load-balance`: [`LoadBalancer`], by destination host or round robin, see [`Balance`]
+//! - `adaptive`: [`AdaptiveSelector`], by latency, failures and throughput
+
+pub use adaptive::{AdaptiveParams, AdaptiveSelector, MemberScore};
+pub use fallback::FallbackSelector;
+pub use load_balance::{Balance, LoadBalancer};
+pub use select::Selector;
+pub use sticky::{Sticky, StickySessions, DEFAULT_STICKY_TTL};
+pub use url_test::{UrlTestSelector, DEFAULT_TOLERANCE};
+
+mod adaptive;
+mod fallback;
+mod load_balance;
+mod select;
+pub mod selection;
+mod sticky;
+mod url_test;
@@ -0,0 +1,76 @@
+//! The `select` strategy, the member the user chose, e.g. on a dashboard.
+
+use std::sync::Mutex;
+
+use super::selection;
+
+#[derive(Debug)]
+pub struct Selector {
+    group: String,
+    members: Vec<String>,
+    /// index of the selected member
+    selected: Mutex<usize>,
+}
+
+impl Selector {
+    /// The group `group` of `members`. The member it selected before a restart is selected, the
+    /// first one otherwise.
+    pub fn new<I: IntoIterator<Item = String>>(group: &str, members: I) -> Self {
+        let members: Vec<String> = members.into_iter().collect();
+        let selected = selection::recall(group)
+            .and_then(|member| members.iter().position(|name| *name == member))
+            .unwrap_or(0);
+        Self {
+            group: group.to_owned(),
+            members,
+            selected: Mutex::new(selected),
+        }
+    }
+
+    /// The member for the next connection, `None` if the group is empty.
+    pub fn selected(&self) -> Option<String> {
+        self.members.get(*self.selected.lock().unwrap()).cloned()
+    }
+
+    /// Selects `member` and remembers it across restarts. Returns `false` if it isn't a member.
+    pub fn select(&self, member: &str) -> bool {
+        let Some(index) = self.members.iter().position(|name| name == member) else {
+            return false;
+        };
+        *self.selected.lock().unwrap() = index;
+        selection::remember(&self.group, member);
+        true
+    }
+
+    #[inline]
+    pub fn members(&self) -> &[String] {
+        &self.members
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cachefile::CacheFile;
+
+    use super::*;
+
+    #[test]
+    fn test_selector() {
+        CacheFile::with_cache_dir(std::env::temp_dir().join("swiftlink").join("cachedb")).unwrap();
+        let members = || ["hk".to_owned(), "jp".to_owned()];
+
+        assert_eq!(Selector::new("selector-test-empty", []).selected(), None);
+        let group = Selector::new("selector-test-proxy", members());
+        assert_eq!(group.selected().as_deref(), Some("hk"));
+        assert!(!group.select("sg"));
+        assert!(group.select("jp"));
+        assert_eq!(group.selected().as_deref(), Some("jp"));
+
+        // restarted
+        let group = Selector::new("selector-test-proxy", members());
+        assert_eq!(group.selected().as_deref(), Some("jp"));
+        // the member was removed from the configuration
+        let group = Selector::new("selector-test-proxy", ["hk".to_owned()]);
+        assert_eq!(group.selected().as_deref(), Some("hk"));
+    }
+}
@@ -0,0 +1,40 @@
+//! Selections of proxy groups across restarts.
+//!
+//! The member the user chose in a `select` group, or the one an `adaptive` group settled on, is
+//! kept in the cache file. A group recalls it when it's created, rebooting the router doesn't
+//! revert everyone to the first member.
+
+use crate::{cachefile::CacheFile, log::*};
+
+/// The member `group` selected before, `None` without cache file.
+pub fn recall(group: &str) -> Option<String> {
+    CacheFile::instance()?.get_selected(group)
+}
+
+/// Keeps `member` as the selection of `group`, if the cache file is open.
+pub fn remember(group: &str, member: &str) {
+    let Some(cachefile) = CacheFile::instance() else {
+        return;
+    };
+    if cachefile.get_selected(group).as_deref() == Some(member) {
+        return;
+    }
+    if let Err(err) = cachefile.put_selected(group, member) {
+        warn!("selection {} of group {} not saved, {}", member, group, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remember_selection() {
+        CacheFile::with_cache_dir(std::env::temp_dir().join("swiftlink").join("cachedb")).unwrap();
+
+        remember("selection-test-proxy", "hk");
+        remember("selection-test-proxy", "jp");
+        assert_eq!(recall("selection-test-proxy").as_deref(), Some("jp"));
+        assert_eq!(recall("selection-test-unknown"), None);
+    }
+}
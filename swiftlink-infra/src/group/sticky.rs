@@ -0,0 +1,149 @@
+//! Sticky sessions of load-balance groups.
+//!
+//! Many sites bind a login to the address it came from. With `sticky = "source-ip"` all
+//! connections of a LAN client go through the same member, until the client was idle for the
+//! TTL or the member died.
+
+use std::{collections::HashMap, net::IpAddr, sync::Mutex, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{self, Instant};
+
+/// Sessions idle this long pick a member anew.
+pub const DEFAULT_STICKY_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Sessions kept before expired ones are purged.
+const PURGE_THRESHOLD: usize = 1024;
+
+/// What connections of one session have in common.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Sticky {
+    /// The address of the client
+    SourceIp,
+}
+
+/// The members of the sessions of a group.
+#[derive(Debug)]
+pub struct StickySessions {
+    ttl: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// member and expiry by client address
+    sessions: HashMap<IpAddr, (String, Instant)>,
+    next_purge: usize,
+}
+
+impl Default for StickySessions {
+    fn default() -> Self {
+        Self::new(DEFAULT_STICKY_TTL)
+    }
+}
+
+impl StickySessions {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            state: Mutex::new(State {
+                sessions: HashMap::new(),
+                next_purge: PURGE_THRESHOLD,
+            }),
+        }
+    }
+
+    /// Returns the member of the session of `source`, extending the session.
+    ///
+    /// A new session, or one whose member isn't `alive` anymore, gets the member `pick` returns.
+    pub fn member<A, P>(&self, source: IpAddr, alive: A, pick: P) -> Option<String>
+    where
+        A: Fn(&str) -> bool,
+        P: FnOnce() -> Option<String>,
+    {
+        let now = clock::now();
+        let mut state = self.state.lock().unwrap();
+
+        if let Some((member, expires)) = state.sessions.get_mut(&source) {
+            if now < *expires && alive(member) {
+                *expires = now + self.ttl;
+                return Some(member.clone());
+            }
+        }
+
+        let Some(member) = pick() else {
+            state.sessions.remove(&source);
+            return None;
+        };
+        state.sessions.insert(source, (member.clone(), now + self.ttl));
+
+        if state.sessions.len() >= state.next_purge {
+            state.sessions.retain(|_, (_, expires)| now < *expires);
+            state.next_purge = (state.sessions.len() * 2).max(PURGE_THRESHOLD);
+        }
+        Some(member)
+    }
+
+    /// Number of sessions, including expired ones not purged yet.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().sessions.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sticky_sessions() {
+        let sessions = StickySessions::new(Duration::from_secs(60));
+        let alice: IpAddr = "192.168.1.10".parse().unwrap();
+        let bob: IpAddr = "192.168.1.11".parse().unwrap();
+        let alive = |_: &str| true;
+
+        assert_eq!(
+            sessions.member(alice, alive, || Some("hk".to_owned())).as_deref(),
+            Some("hk")
+        );
+        assert_eq!(
+            sessions.member(bob, alive, || Some("jp".to_owned())).as_deref(),
+            Some("jp")
+        );
+
+        // used within the TTL, the session lives on
+        tokio::time::advance(Duration::from_secs(50)).await;
+        assert_eq!(
+            sessions.member(alice, alive, || Some("sg".to_owned())).as_deref(),
+            Some("hk")
+        );
+        tokio::time::advance(Duration::from_secs(50)).await;
+        assert_eq!(
+            sessions.member(alice, alive, || Some("sg".to_owned())).as_deref(),
+            Some("hk")
+        );
+        assert_eq!(
+            sessions.member(bob, alive, || Some("sg".to_owned())).as_deref(),
+            Some("sg")
+        );
+
+        // the member died
+        let dead = |member: &str| member != "hk";
+        assert_eq!(
+            sessions.member(alice, dead, || Some("jp".to_owned())).as_deref(),
+            Some("jp")
+        );
+
+        assert_eq!(sessions.member(alice, |_| false, || None), None);
+        assert_eq!(sessions.len(), 1);
+
+        let json = serde_json::to_string(&Sticky::SourceIp).unwrap();
+        assert_eq!(json, "\"source-ip\"");
+    }
+}
@@ -0,0 +1,128 @@
+//! The `url-test` strategy, the member whose last health check was the fastest.
+//!
+//! The current member is replaced only by one faster by more than the tolerance, two proxies of
+//! about the same latency don't take turns with every check.
+
+use std::{sync::Mutex, time::Duration};
+
+/// Latency difference which isn't worth switching members for.
+pub const DEFAULT_TOLERANCE: Duration = Duration::from_millis(50);
+
+#[derive(Debug)]
+pub struct UrlTestSelector {
+    tolerance: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// name and the latency of its last check, `None` if it failed or didn't run yet
+    members: Vec<(String, Option<Duration>)>,
+    current: Option<usize>,
+}
+
+impl UrlTestSelector {
+    pub fn new<I: IntoIterator<Item = String>>(members: I, tolerance: Duration) -> Self {
+        Self {
+            tolerance,
+            state: Mutex::new(State {
+                members: members.into_iter().map(|name| (name, None)).collect(),
+                current: None,
+            }),
+        }
+    }
+
+    /// Records a health check of `member`, `None` if it failed.
+    pub fn record_check(&self, member: &str, latency: Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some((_, last)) = state.members.iter_mut().find(|(name, _)| name == member) {
+            *last = latency;
+        }
+    }
+
+    /// Returns the member for the next connection, `None` if the group is empty.
+    ///
+    /// While no check succeeded, the current member or the first one is kept.
+    pub fn select(&self) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        let fastest = state
+            .members
+            .iter()
+            .enumerate()
+            .filter_map(|(index, (_, latency))| latency.map(|latency| (index, latency)))
+            .min_by_key(|(_, latency)| *latency);
+        let current = state
+            .current
+            .and_then(|index| state.members[index].1.map(|latency| (index, latency)));
+
+        let selected = match (current, fastest) {
+            (Some((current, latency)), Some((_, fastest))) if latency <= fastest + self.tolerance => current,
+            (_, Some((fastest, _))) => fastest,
+            (_, None) => state.current.unwrap_or(0),
+        };
+        let name = state.members.get(selected)?.0.clone();
+        state.current = Some(selected);
+        Some(name)
+    }
+
+    /// Makes `member` the current one, e.g. the one selected before a restart. Returns `false` if
+    /// it isn't a member.
+    pub fn restore(&self, member: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(index) = state.members.iter().position(|(name, _)| name == member) else {
+            return false;
+        };
+        state.current = Some(index);
+        true
+    }
+
+    /// The members with the latency of their last check, in the configured order.
+    pub fn latencies(&self) -> Vec<(String, Option<Duration>)> {
+        self.state.lock().unwrap().members.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Option<Duration> {
+        Some(Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn test_url_test_select() {
+        assert_eq!(UrlTestSelector::new([], DEFAULT_TOLERANCE).select(), None);
+
+        let group = UrlTestSelector::new(["hk".to_owned(), "jp".to_owned()], DEFAULT_TOLERANCE);
+        assert_eq!(group.select().as_deref(), Some("hk"));
+
+        group.record_check("hk", ms(200));
+        group.record_check("jp", ms(100));
+        assert_eq!(group.select().as_deref(), Some("jp"));
+
+        // within the tolerance
+        group.record_check("hk", ms(60));
+        assert_eq!(group.select().as_deref(), Some("jp"));
+        group.record_check("hk", ms(40));
+        assert_eq!(group.select().as_deref(), Some("hk"));
+
+        // the current member failed
+        group.record_check("hk", None);
+        assert_eq!(group.select().as_deref(), Some("jp"));
+        group.record_check("jp", None);
+        assert_eq!(group.select().as_deref(), Some("jp"));
+        assert_eq!(group.latencies(), [("hk".to_owned(), None), ("jp".to_owned(), None)]);
+    }
+
+    #[test]
+    fn test_url_test_restore() {
+        let group = UrlTestSelector::new(["hk".to_owned(), "jp".to_owned()], DEFAULT_TOLERANCE);
+        assert!(!group.restore("sg"));
+        assert!(group.restore("jp"));
+        assert_eq!(group.select().as_deref(), Some("jp"));
+
+        group.record_check("hk", ms(100));
+        assert_eq!(group.select().as_deref(), Some("hk"));
+    }
+}
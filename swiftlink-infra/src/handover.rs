@@ -0,0 +1,240 @@
+//! Listener handover to a new swiftlink binary, for upgrades without downtime.
+//!
+//! The running process spawns the (replaced) executable with its own arguments and passes the
+//! listening sockets over a unix socket pair (`SCM_RIGHTS`). The successor serves on the passed
+//! sockets instead of binding them, then reports ready. Only then the old process shuts down, so
+//! no connection is refused in between.
+
+use std::{
+    env, io,
+    io::{Read, Write},
+    mem,
+    os::unix::{
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        net::UnixStream,
+    },
+    process::{Child, Command},
+    ptr,
+    time::Duration,
+};
+
+use socket2::Socket;
+
+use crate::log::*;
+
+/// Environment variable holding the successor's end of the handover socket pair
+pub const HANDOVER_FD_ENV: &str = "SWIFTLINK_HANDOVER_FD";
+
+/// Most sockets passed in one handover
+const MAX_FDS: usize = 64;
+
+/// Time the successor gets to start serving
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Spawns the current executable with the current arguments and hands `fds` over to it.
+///
+/// Blocks until the successor is ready to serve. If it fails to start, it is killed and the
+/// caller should keep serving.
+pub fn spawn_successor(fds: &[RawFd]) -> io::Result<Child> {
+    let (mut parent, child_end) = UnixStream::pair()?;
+    set_cloexec(child_end.as_raw_fd(), false)?;
+
+    let exe = env::current_exe()?;
+    info!("spawning {:?} to take over {} sockets", exe, fds.len());
+    let mut child = Command::new(exe)
+        .args(env::args_os().skip(1))
+        .env(HANDOVER_FD_ENV, child_end.as_raw_fd().to_string())
+        .spawn()?;
+    drop(child_end);
+
+    let ready = send_fds(&parent, fds).and_then(|_| {
+        parent.set_read_timeout(Some(READY_TIMEOUT))?;
+        let mut ready = [0u8; 1];
+        parent.read_exact(&mut ready)
+    });
+
+    if let Err(err) = ready {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(io::Error::new(
+            err.kind(),
+            format!("successor failed to take over, {}", err),
+        ));
+    }
+
+    Ok(child)
+}
+
+/// The process a successor took its sockets from.
+#[derive(Debug)]
+pub struct Predecessor {
+    stream: UnixStream,
+}
+
+impl Predecessor {
+    /// Receives the sockets if this process was spawned by [`spawn_successor`].
+    pub fn take_from_env() -> io::Result<Option<(Predecessor, Vec<Socket>)>> {
+        let Some(fd) = env::var(HANDOVER_FD_ENV).ok() else {
+            return Ok(None);
+        };
+        env::remove_var(HANDOVER_FD_ENV);
+
+        let fd: RawFd = fd
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid {}", HANDOVER_FD_ENV)))?;
+        set_cloexec(fd, true)?;
+        let stream = unsafe { UnixStream::from_raw_fd(fd) };
+
+        let sockets = recv_fds(&stream)?.into_iter().map(Socket::from).collect::<Vec<_>>();
+        info!("took over {} sockets from the previous process", sockets.len());
+
+        Ok(Some((Predecessor { stream }, sockets)))
+    }
+
+    /// Tells the predecessor to shut down, all listeners are serving.
+    pub fn ready(mut self) -> io::Result<()> {
+        self.stream.write_all(&[1])
+    }
+}
+
+/// Sends `fds` as one `SCM_RIGHTS` message, prefixed with their count.
+pub fn send_fds(stream: &UnixStream, fds: &[RawFd]) -> io::Result<()> {
+    if fds.len() > MAX_FDS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("at most {} sockets can be handed over", MAX_FDS),
+        ));
+    }
+
+    let count = (fds.len() as u32).to_le_bytes();
+    let mut iov = libc::iovec {
+        iov_base: count.as_ptr() as *mut libc::c_void,
+        iov_len: count.len(),
+    };
+
+    let fds_len = mem::size_of_val(fds);
+    let mut cmsg_buf = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len as u32) } as usize];
+
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+
+        if !fds.is_empty() {
+            msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = cmsg_buf.len() as _;
+
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len as u32) as _;
+            ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+        }
+
+        if libc::sendmsg(stream.as_raw_fd(), &msg, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Receives the file descriptors sent by [`send_fds`].
+pub fn recv_fds(stream: &UnixStream) -> io::Result<Vec<OwnedFd>> {
+    let mut count = [0u8; 4];
+    let mut iov = libc::iovec {
+        iov_base: count.as_mut_ptr() as *mut libc::c_void,
+        iov_len: count.len(),
+    };
+
+    let mut cmsg_buf = vec![0u8; unsafe { libc::CMSG_SPACE((MAX_FDS * mem::size_of::<RawFd>()) as u32) } as usize];
+
+    let mut fds = Vec::new();
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = cmsg_buf.len() as _;
+
+        let n = libc::recvmsg(stream.as_raw_fd(), &mut msg, 0);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / mem::size_of::<RawFd>();
+                for i in 0..len {
+                    let fd = ptr::read_unaligned(data.add(i));
+                    set_cloexec(fd, true)?;
+                    fds.push(OwnedFd::from_raw_fd(fd));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        if n as usize != count.len() || msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated handover message"));
+        }
+    }
+
+    if fds.len() != u32::from_le_bytes(count) as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "handover sockets mismatch"));
+    }
+
+    Ok(fds)
+}
+
+fn set_cloexec(fd: RawFd, cloexec: bool) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = if cloexec {
+            flags | libc::FD_CLOEXEC
+        } else {
+            flags & !libc::FD_CLOEXEC
+        };
+        if libc::fcntl(fd, libc::F_SETFD, flags) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Duplicates the file descriptor of a listener for [`spawn_successor`].
+pub fn dup_listener<S: AsRawFd>(socket: &S) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 3) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_recv_fds() {
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let udp_fd = dup_listener(&udp).unwrap();
+        let (a, b) = UnixStream::pair().unwrap();
+        send_fds(&a, &[udp_fd.as_raw_fd(), tcp.as_raw_fd()]).unwrap();
+
+        let fds = recv_fds(&b).unwrap();
+        assert_eq!(fds.len(), 2);
+
+        let received = std::net::UdpSocket::from(fds.into_iter().next().unwrap());
+        assert_eq!(received.local_addr().unwrap(), udp.local_addr().unwrap());
+
+        send_fds(&a, &[]).unwrap();
+        assert!(recv_fds(&b).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,378 @@
+//! Port knocking, the gate in front of proxies exposed to the internet.
+//!
+//! A client knocks with the TOTP code of a shared secret (RFC 6238: HMAC-SHA1, 30 second steps,
+//! 6 digits), sent as the payload of a UDP datagram:
+//!
+//! ```sh
+//! echo -n 492039 | nc -u -w1 proxy.example.com 7000
+//! ```
+//!
+//! Its address is then admitted for a while, other addresses see their connections closed before
+//! the proxy handshake. The secret is base32, the form authenticator apps take. The codes of the
+//! previous and next steps are accepted too for clocks apart, each code admits a single address so
+//! a sniffed one can't be replayed.
+//!
+//! Guessing is bounded per address: the knocks of an address which sent 5 wrong codes within a
+//! minute aren't evaluated for 15 minutes. Only wrong codes count, datagrams which aren't a code at
+//! all, e.g. of scanners, are dropped. There is no lockout of all addresses, spoofed knocks would
+//! lock the clients out with it.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use sha1::{Digest, Sha1};
+use tokio::net::UdpSocket;
+
+use crate::{
+    clock::{self, Instant},
+    log::*,
+};
+
+/// Seconds a code is valid for.
+const STEP: u64 = 30;
+
+const DIGITS: u32 = 6;
+
+/// Shortest secret accepted, RFC 4226 section 4.
+const MIN_SECRET_LEN: usize = 16;
+
+/// Wrong codes of an address within [`FAILURE_WINDOW`] before its knocks are ignored.
+const MAX_SOURCE_FAILURES: u32 = 5;
+
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+/// How long the knocks of an address which failed too often are ignored.
+const SOURCE_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Addresses whose wrong codes are counted at most, one which isn't locked out makes room for
+/// another.
+const MAX_TRACKED_SOURCES: usize = 4096;
+
+/// The secret of the knocks isn't valid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("knock secret must be base32 of at least {MIN_SECRET_LEN} bytes")]
+pub struct InvalidSecret;
+
+/// The addresses admitted by a knock.
+#[derive(Debug)]
+pub struct KnockGate {
+    secret: Vec<u8>,
+    open_for: Duration,
+    /// address to the end of its admission
+    admitted: Mutex<HashMap<IpAddr, Instant>>,
+    /// the steps whose code admitted an address
+    used: Mutex<HashSet<u64>>,
+    /// the wrong codes of each address
+    failures: Mutex<HashMap<IpAddr, FailureCount>>,
+}
+
+/// Wrong codes counted in windows of [`FAILURE_WINDOW`], and the lockout they caused.
+#[derive(Debug, Default, Clone, Copy)]
+struct FailureCount {
+    count: u32,
+    window_end: Option<Instant>,
+    locked_until: Option<Instant>,
+}
+
+impl FailureCount {
+    fn is_locked(&self) -> bool {
+        self.locked_until.is_some_and(|until| !clock::is_expired(until))
+    }
+
+    fn is_stale(&self) -> bool {
+        !self.is_locked() && self.window_end.is_none_or(clock::is_expired)
+    }
+
+    /// Counts a wrong code, locks out for [`SOURCE_LOCKOUT`] at the [`MAX_SOURCE_FAILURES`]th of a
+    /// window. Returns whether it did.
+    fn add(&mut self) -> bool {
+        if self.window_end.is_none_or(clock::is_expired) {
+            self.count = 0;
+            self.window_end = Some(clock::now() + FAILURE_WINDOW);
+        }
+        self.count += 1;
+        if self.count < MAX_SOURCE_FAILURES {
+            return false;
+        }
+        self.count = 0;
+        self.window_end = None;
+        self.locked_until = Some(clock::now() + SOURCE_LOCKOUT);
+        true
+    }
+}
+
+impl KnockGate {
+    /// A gate admitting an address for `open_for` after a knock with the code of `secret`.
+    pub fn new(secret: &str, open_for: Duration) -> Result<Self, InvalidSecret> {
+        let secret = base32_decode(secret)
+            .filter(|secret| secret.len() >= MIN_SECRET_LEN)
+            .ok_or(InvalidSecret)?;
+        Ok(Self {
+            secret,
+            open_for,
+            admitted: Mutex::new(HashMap::new()),
+            used: Mutex::new(HashSet::new()),
+            failures: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Whether connections of `ip` are let through, loopback always is.
+    pub fn is_admitted(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        ip.is_loopback()
+            || self
+                .admitted
+                .lock()
+                .unwrap()
+                .get(&ip)
+                .is_some_and(|until| !clock::is_expired(*until))
+    }
+
+    /// Admits `from` if `payload` is a valid code, returns whether it did. The knocks of locked out
+    /// addresses aren't evaluated.
+    pub fn knock(&self, from: IpAddr, payload: &[u8]) -> bool {
+        let unix_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        self.knock_at(from, payload, unix_time)
+    }
+
+    fn knock_at(&self, from: IpAddr, payload: &[u8], unix_time: u64) -> bool {
+        let from = from.to_canonical();
+        // not a code, nothing was guessed
+        let Some(code) = parse_code(payload) else {
+            return false;
+        };
+        {
+            let failures = self.failures.lock().unwrap();
+            if failures.get(&from).is_some_and(FailureCount::is_locked) {
+                return false;
+            }
+        }
+
+        if !self.check_code(code, unix_time) {
+            self.fail(from);
+            return false;
+        }
+        self.failures.lock().unwrap().remove(&from);
+
+        let mut admitted = self.admitted.lock().unwrap();
+        admitted.retain(|_, until| !clock::is_expired(*until));
+        admitted.insert(from, clock::now() + self.open_for);
+        true
+    }
+
+    /// Whether `code` is the unused code of the step of `unix_time` or of those around it, the step
+    /// of the code is used then.
+    fn check_code(&self, code: u32, unix_time: u64) -> bool {
+        let step = unix_time / STEP;
+        let mut used = self.used.lock().unwrap();
+        used.retain(|used| *used + 1 >= step);
+        let Some(step) = [step, step.saturating_sub(1), step + 1]
+            .into_iter()
+            .find(|step| !used.contains(step) && totp(&self.secret, *step) == code)
+        else {
+            return false;
+        };
+        used.insert(step);
+        true
+    }
+
+    /// Counts a wrong code of `from`.
+    fn fail(&self, from: IpAddr) {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_TRACKED_SOURCES && !failures.contains_key(&from) {
+            failures.retain(|_, count| !count.is_stale());
+            // all counting, the first one not locked out makes room
+            if failures.len() >= MAX_TRACKED_SOURCES {
+                let Some(unlocked) = failures.iter().find(|(_, count)| !count.is_locked()).map(|(ip, _)| *ip) else {
+                    return;
+                };
+                failures.remove(&unlocked);
+            }
+        }
+        if failures.entry(from).or_default().add() {
+            warn!(
+                "{} knocked {} wrong codes, its knocks are ignored for {}s",
+                from,
+                MAX_SOURCE_FAILURES,
+                SOURCE_LOCKOUT.as_secs()
+            );
+        }
+    }
+
+    /// Takes the knocks received on `socket` until it fails.
+    pub async fn serve(&self, socket: UdpSocket) -> std::io::Result<()> {
+        let mut buf = [0u8; 64];
+        loop {
+            let (n, from) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                // ICMP port unreachable of an earlier datagram, reported by some platforms
+                Err(err) if err.kind() == std::io::ErrorKind::ConnectionReset => continue,
+                Err(err) => return Err(err),
+            };
+            if self.knock(from.ip(), &buf[..n]) {
+                info!("{} knocked, admitted for {}s", from.ip(), self.open_for.as_secs());
+            } else {
+                debug!("invalid knock from {}", from);
+            }
+        }
+    }
+}
+
+/// The code a knock of `payload` sends, `None` if it isn't one.
+fn parse_code(payload: &[u8]) -> Option<u32> {
+    std::str::from_utf8(payload)
+        .ok()
+        .map(str::trim)
+        .filter(|code| code.len() == DIGITS as usize && code.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|code| code.parse().ok())
+}
+
+/// The code of `step`, RFC 4226 section 5.
+fn totp(secret: &[u8], step: u64) -> u32 {
+    let mac = hmac_sha1(secret, &step.to_be_bytes());
+    let offset = (mac[19] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([mac[offset], mac[offset + 1], mac[offset + 2], mac[offset + 3]]) & 0x7fff_ffff;
+    binary % 10u32.pow(DIGITS)
+}
+
+/// HMAC-SHA1, RFC 2104.
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    const BLOCK_LEN: usize = 64;
+    let mut block = [0u8; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block[..20].copy_from_slice(&Sha1::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha1::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha1::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+/// Decodes RFC 4648 base32, case insensitive, padding and spaces ignored.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len() * 5 / 8);
+    let (mut bits, mut len) = (0u32, 0);
+    for c in s.bytes().filter(|c| !matches!(c, b'=' | b' ')) {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        bits = (bits << 5) | value as u32;
+        len += 5;
+        if len >= 8 {
+            len -= 8;
+            bytes.push((bits >> len) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `12345678901234567890`, the secret of the RFC 6238 test vectors
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn test_totp() {
+        let secret = base32_decode(SECRET).unwrap();
+        assert_eq!(secret, b"12345678901234567890");
+        assert_eq!(base32_decode("gezd gnbv gy3t qojq===="), Some(b"1234567890".to_vec()));
+        assert_eq!(base32_decode("GEZ1"), None);
+
+        // RFC 6238 appendix B, the last 6 of the 8 digits
+        assert_eq!(totp(&secret, 59 / STEP), 287082);
+        assert_eq!(totp(&secret, 1111111109 / STEP), 81804);
+        assert_eq!(totp(&secret, 2000000000 / STEP), 279037);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_knock() {
+        assert_eq!(
+            KnockGate::new("GEZDGNBV", Duration::from_secs(60)).unwrap_err(),
+            InvalidSecret
+        );
+        let gate = KnockGate::new(SECRET, Duration::from_secs(60)).unwrap();
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        let other: IpAddr = "198.51.100.1".parse().unwrap();
+        assert!(gate.is_admitted("127.0.0.1".parse().unwrap()));
+        assert!(!gate.is_admitted(client));
+
+        assert!(!gate.knock_at(client, b"123456", 59));
+        assert!(!gate.knock_at(client, b"not a code", 59));
+        assert!(!gate.is_admitted(client));
+
+        // the code of the previous step, then replayed by another address
+        assert!(gate.knock_at(client, b"287082\n", 59 + STEP));
+        assert!(gate.is_admitted(client));
+        assert!(gate.is_admitted("::ffff:203.0.113.7".parse().unwrap()));
+        assert!(!gate.knock_at(other, b"287082", 59 + STEP));
+        assert!(!gate.is_admitted(other));
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert!(!gate.is_admitted(client));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_knock_lockout() {
+        let gate = KnockGate::new(SECRET, Duration::from_secs(60)).unwrap();
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        let other: IpAddr = "198.51.100.1".parse().unwrap();
+
+        // a flood of wrong codes, then the right one isn't evaluated either
+        for _ in 0..MAX_SOURCE_FAILURES {
+            assert!(!gate.knock_at(client, b"000000", 59));
+        }
+        assert!(!gate.knock_at(client, b"287082", 59));
+        assert!(!gate.is_admitted(client));
+        // the code wasn't used
+        assert!(gate.knock_at(other, b"287082", 59));
+
+        tokio::time::advance(SOURCE_LOCKOUT).await;
+        assert!(gate.knock_at(client, b"081804", 1111111109));
+        assert!(gate.is_admitted(client));
+
+        // wrong codes of many addresses, spoofed or not, lock only them out
+        for i in 0..MAX_SOURCE_FAILURES * 10 {
+            let source = IpAddr::from([192, 0, 2, i as u8]);
+            assert!(!gate.knock_at(source, b"000000", 2000000000));
+        }
+        assert!(gate.knock_at(other, b"279037", 2000000000));
+
+        // datagrams which aren't codes don't count
+        let scanner: IpAddr = "192.0.2.200".parse().unwrap();
+        for _ in 0..MAX_SOURCE_FAILURES {
+            assert!(!gate.knock_at(scanner, b"GET / HTTP/1.1", 59));
+            assert!(!gate.knock_at(scanner, b"+12345", 59));
+        }
+        assert!(gate.knock_at(scanner, b"287082", 59));
+    }
+
+    #[test]
+    fn test_knock_tracked_sources() {
+        let gate = KnockGate::new(SECRET, Duration::from_secs(60)).unwrap();
+        for i in 0..MAX_TRACKED_SOURCES as u32 {
+            gate.fail(IpAddr::from(i.to_be_bytes()));
+        }
+        // a new address makes room for itself
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        gate.fail(client);
+        let failures = gate.failures.lock().unwrap();
+        assert_eq!(failures.len(), MAX_TRACKED_SOURCES);
+        assert_eq!(failures[&client].count, 1);
+    }
+}
@@ -4,11 +4,42 @@ pub use listener::*;
 
 pub mod auth;
 pub mod cachefile;
+pub mod capture;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod clock;
+pub mod connection;
+pub mod delay;
+pub mod dns_failures;
+pub mod event;
+pub mod extensions;
 pub mod fakedns;
 pub mod file_mode;
+pub mod geoip;
+pub mod geosite;
+pub mod group;
+#[cfg(unix)]
+pub mod handover;
+pub mod knock;
 pub mod log;
 pub mod mapped_file;
 pub mod net;
 pub mod parse;
+#[cfg(unix)]
+pub mod privilege;
+pub mod process;
+pub mod proxy_stats;
+pub mod rule_hits;
+pub mod ruleset;
+pub mod sandbox;
 pub mod signal;
+pub mod sni;
+#[cfg(unix)]
+pub mod systemd;
+pub mod talkers;
+pub mod traffic;
 pub mod trie;
+#[cfg(target_os = "linux")]
+pub mod tun;
+pub mod watchdog;
+pub mod websocket;
@@ -1,20 +1,25 @@
 use std::{
-    io,
+    fmt, io,
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     str::FromStr,
 };
 
-use serde_with::DeserializeFromStr;
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use socket2::{Domain, Protocol, Socket, Type};
 
 use crate::{log::*, parse};
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, DeserializeFromStr)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, DeserializeFromStr, SerializeDisplay)]
 pub struct Listener {
     sock_addr: SocketAddr,
     device: Option<String>,
 }
 
 impl Listener {
+    pub fn new(sock_addr: SocketAddr, device: Option<String>) -> Self {
+        Self { sock_addr, device }
+    }
+
     pub fn sock_addr(&self) -> SocketAddr {
         self.sock_addr
     }
@@ -22,6 +27,10 @@ impl Listener {
     pub fn device(&self) -> Option<&str> {
         self.device.as_deref()
     }
+
+    pub fn set_port(&mut self, port: u16) {
+        self.sock_addr.set_port(port)
+    }
 }
 
 impl Default for Listener {
@@ -33,6 +42,16 @@ impl Default for Listener {
     }
 }
 
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.sock_addr)?;
+        if let Some(device) = self.device.as_deref() {
+            write!(f, "@{}", device)?;
+        }
+        Ok(())
+    }
+}
+
 impl FromStr for Listener {
     type Err = std::io::Error;
 
@@ -72,14 +91,8 @@ pub fn bind_to<T>(
     })
 }
 
-pub fn tcp(
-    sock_addr: SocketAddr,
-    bind_device: Option<&str>,
-    bind_type: &str,
-) -> io::Result<tokio::net::TcpListener> {
-    let device_note = bind_device
-        .map(|device| format!("@{device}"))
-        .unwrap_or_default();
+pub fn tcp(sock_addr: SocketAddr, bind_device: Option<&str>, bind_type: &str) -> io::Result<tokio::net::TcpListener> {
+    let device_note = bind_device.map(|device| format!("@{device}")).unwrap_or_default();
 
     debug!("binding {} to {:?}{}", bind_type, sock_addr, device_note);
 
@@ -105,23 +118,15 @@ pub fn tcp(
     info!(
         "listening for {} on {:?}{}",
         bind_type,
-        tcp_listener
-            .local_addr()
-            .expect("could not lookup local address"),
+        tcp_listener.local_addr().expect("could not lookup local address"),
         device_note
     );
 
     Ok(tcp_listener)
 }
 
-pub fn udp(
-    sock_addr: SocketAddr,
-    bind_device: Option<&str>,
-    bind_type: &str,
-) -> io::Result<tokio::net::UdpSocket> {
-    let device_note = bind_device
-        .map(|device| format!("@{device}"))
-        .unwrap_or_default();
+pub fn udp(sock_addr: SocketAddr, bind_device: Option<&str>, bind_type: &str) -> io::Result<tokio::net::UdpSocket> {
+    let device_note = bind_device.map(|device| format!("@{device}")).unwrap_or_default();
 
     debug!("binding {} to {:?}{}", bind_type, sock_addr, device_note);
     let udp_socket = std::net::UdpSocket::bind(sock_addr)?;
@@ -147,11 +152,118 @@ pub fn udp(
     info!(
         "listening for {} on {:?}{}",
         bind_type,
-        udp_socket
-            .local_addr()
-            .expect("could not lookup local address"),
+        udp_socket.local_addr().expect("could not lookup local address"),
         device_note
     );
 
     Ok(udp_socket)
 }
+
+/// Binds a UDP socket with `SO_REUSEPORT` set before `bind()`, so that several sockets can
+/// share one address and the kernel balances incoming datagrams across them.
+pub fn udp_reuse_port(
+    sock_addr: SocketAddr,
+    bind_device: Option<&str>,
+    bind_type: &str,
+) -> io::Result<tokio::net::UdpSocket> {
+    let device_note = bind_device.map(|device| format!("@{device}")).unwrap_or_default();
+
+    debug!("binding {} to {:?}{} (reuse port)", bind_type, sock_addr, device_note);
+
+    let socket = Socket::new(Domain::for_address(sock_addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_nonblocking(true)?;
+    socket.set_reuse_address(true)?;
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    if let Some(device) = bind_device {
+        socket.bind_device(Some(device.as_bytes()))?;
+    }
+
+    socket.bind(&sock_addr.into())?;
+
+    let udp_socket = tokio::net::UdpSocket::from_std(socket.into())?;
+
+    info!(
+        "listening for {} on {:?}{}",
+        bind_type,
+        udp_socket.local_addr().expect("could not lookup local address"),
+        device_note
+    );
+
+    Ok(udp_socket)
+}
+
+/// Binds a TCP listener with `SO_REUSEPORT` set before `bind()`, so that several listeners can
+/// share one address and the kernel balances new connections across them.
+pub fn tcp_reuse_port(
+    sock_addr: SocketAddr,
+    bind_device: Option<&str>,
+    bind_type: &str,
+) -> io::Result<tokio::net::TcpListener> {
+    let device_note = bind_device.map(|device| format!("@{device}")).unwrap_or_default();
+
+    debug!("binding {} to {:?}{} (reuse port)", bind_type, sock_addr, device_note);
+
+    let socket = Socket::new(Domain::for_address(sock_addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_nonblocking(true)?;
+    socket.set_reuse_address(true)?;
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    if let Some(device) = bind_device {
+        socket.bind_device(Some(device.as_bytes()))?;
+    }
+
+    socket.bind(&sock_addr.into())?;
+    socket.listen(1024)?;
+
+    let tcp_listener = tokio::net::TcpListener::from_std(socket.into())?;
+
+    info!(
+        "listening for {} on {:?}{}",
+        bind_type,
+        tcp_listener.local_addr().expect("could not lookup local address"),
+        device_note
+    );
+
+    Ok(tcp_listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listener_parse_device() {
+        let listener: Listener = "0.0.0.0:53@eth0".parse().unwrap();
+        assert_eq!(listener.sock_addr(), "0.0.0.0:53".parse().unwrap());
+        assert_eq!(listener.device(), Some("eth0"));
+        assert_eq!(listener.to_string(), "0.0.0.0:53@eth0");
+    }
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    #[tokio::test]
+    async fn test_udp_reuse_port_shards() {
+        let first = udp_reuse_port("127.0.0.1:0".parse().unwrap(), None, "UDP").unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = udp_reuse_port(addr, None, "UDP").unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+    }
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    #[tokio::test]
+    async fn test_tcp_reuse_port_shards() {
+        let first = tcp_reuse_port("127.0.0.1:0".parse().unwrap(), None, "TCP").unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = tcp_reuse_port(addr, None, "TCP").unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+
+        // a listener without SO_REUSEPORT can't join
+        assert!(tcp(addr, None, "TCP").is_err());
+    }
+}
@@ -1,18 +1,78 @@
-use std::{env, io, path::Path};
+use std::{env, fmt, io, path::Path, sync::OnceLock};
 
+use tokio::sync::broadcast;
 use tracing::{
     dispatcher::{set_default, set_global_default},
+    field::{Field, Visit},
     subscriber::DefaultGuard,
-    Dispatch,
+    Dispatch, Event, Subscriber,
 };
 use tracing_subscriber::{
     fmt::{writer::MakeWriterExt, MakeWriter},
+    layer::{Context, Layer},
     prelude::__tracing_subscriber_SubscriberExt,
     EnvFilter,
 };
 
 pub use tracing::{debug, error, info, trace, warn, Level};
 
+/// Log lines kept per subscriber of [`log_lines`] until it receives them.
+const LOG_LINES_CAPACITY: usize = 256;
+
+static LOG_LINES: OnceLock<broadcast::Sender<LogLine>> = OnceLock::new();
+
+/// A logged event, see [`log_lines`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogLine {
+    pub level: Level,
+    /// the message followed by the other fields, `key=value`
+    pub message: String,
+}
+
+/// Receives the lines logged from now on, which pass the log filter, e.g. for the logs of the API.
+pub fn log_lines() -> broadcast::Receiver<LogLine> {
+    log_lines_sender().subscribe()
+}
+
+fn log_lines_sender() -> &'static broadcast::Sender<LogLine> {
+    LOG_LINES.get_or_init(|| broadcast::channel(LOG_LINES_CAPACITY).0)
+}
+
+/// Sends the events to the subscribers of [`log_lines`], if there are any.
+struct LogLinesLayer;
+
+impl<S: Subscriber> Layer<S> for LogLinesLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let sender = log_lines_sender();
+        if sender.receiver_count() == 0 {
+            return;
+        }
+
+        let mut message = LineVisitor(String::new());
+        event.record(&mut message);
+        _ = sender.send(LogLine {
+            level: *event.metadata().level(),
+            message: message.0,
+        });
+    }
+}
+
+struct LineVisitor(String);
+
+impl Visit for LineVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        use std::fmt::Write;
+
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        _ = match field.name() {
+            "message" => write!(self.0, "{:?}", value),
+            name => write!(self.0, "{}={:?}", name, value),
+        };
+    }
+}
+
 type MappedFile = crate::mapped_file::MutexMappedFile;
 
 pub fn init_global_default<P: AsRef<Path>>(
@@ -25,29 +85,18 @@ pub fn init_global_default<P: AsRef<Path>>(
 ) -> DefaultGuard {
     let file = MappedFile::open(path.as_ref(), size, Some(num as usize), mode);
 
-    let writable = file
-        .0
-        .lock()
-        .unwrap()
-        .touch()
-        .map(|_| true)
-        .unwrap_or_else(|err| {
-            warn!("{:?}, {:?}", path.as_ref(), err);
-            false
-        });
+    let writable = file.0.lock().unwrap().touch().map(|_| true).unwrap_or_else(|err| {
+        warn!("{:?}, {:?}", path.as_ref(), err);
+        false
+    });
 
     let console_level = console_level();
     let console_writer = io::stdout.with_max_level(console_level);
 
     let dispatch = if writable {
-        let file_writer =
-            MappedFile::open(path.as_ref(), size, Some(num as usize), mode).with_max_level(level);
-
-        make_dispatch(
-            level.max(console_level),
-            filter,
-            file_writer.and(console_writer),
-        )
+        let file_writer = MappedFile::open(path.as_ref(), size, Some(num as usize), mode).with_max_level(level);
+
+        make_dispatch(level.max(console_level), filter, file_writer.and(console_writer))
     } else {
         make_dispatch(console_level, filter, console_writer)
     };
@@ -74,13 +123,12 @@ fn make_dispatch<W: for<'writer> MakeWriter<'writer> + 'static + Send + Sync>(
         // .with_thread_ids(true)
         .with_file(true)
         .with_line_number(true);
-    let layer = tracing_subscriber::fmt::layer()
-        .event_format(fmt)
-        .with_writer(writer);
+    let layer = tracing_subscriber::fmt::layer().event_format(fmt).with_writer(writer);
 
     Dispatch::from(
         tracing_subscriber::registry()
             .with(layer)
+            .with(LogLinesLayer)
             .with(make_filter(level, filter)),
     )
 }
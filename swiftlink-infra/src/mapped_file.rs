@@ -62,9 +62,7 @@ impl MappedFile {
         if self.len > 0 || self.file.is_some() {
             self.len
         } else {
-            fs::metadata(self.path.as_path())
-                .map(|m| m.len())
-                .unwrap_or_default()
+            fs::metadata(self.path.as_path()).map(|m| m.len()).unwrap_or_default()
         }
     }
 
@@ -84,16 +82,16 @@ impl MappedFile {
 
     pub fn mapped_files(&self) -> io::Result<Vec<PathBuf>> {
         match (
-            self.path
-                .file_stem()
-                .map(|s| s.to_str().map(|s| s.to_string())),
+            self.path.file_stem().map(|s| s.to_str().map(|s| s.to_string())),
             self.path.parent(),
         ) {
             (Some(Some(base_name)), Some(parent)) => {
                 let mut files = fs::read_dir(parent)?
                     .filter_map(|o| o.ok())
                     .filter_map(|o| {
-                        if self.path.extension() == o.path().extension() && matches!(o.file_name().to_str(), Some(s) if s.starts_with(base_name.as_str())) {
+                        if self.path.extension() == o.path().extension()
+                            && matches!(o.file_name().to_str(), Some(s) if s.starts_with(base_name.as_str()))
+                        {
                             Some(o.path())
                         } else {
                             None
@@ -0,0 +1,180 @@
+//! Host to IP affinity of outbound dials.
+//!
+//! Sites with many A records are dialed on the address which connected last time first, instead
+//! of in resolver order, where the first address might be the one which times out. The address is
+//! remembered per outbound, a proxy reaches other addresses than the direct route does, and
+//! forgotten when a dial to it fails or after the TTL.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::Duration,
+};
+
+use lru::LruCache;
+use tokio::net::TcpStream;
+
+use crate::{
+    clock::{self, Instant},
+    log::*,
+    net::{tcp::crate_tcp_stream_with_opts, ConnectOpts},
+};
+
+/// Hosts remembered per cache, the least recently dialed are forgotten first.
+pub const DEFAULT_DIAL_CACHE_SIZE: usize = 4096;
+
+/// An address is dialed first this long after it connected.
+pub const DEFAULT_DIAL_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug)]
+pub struct DialCache {
+    ttl: Duration,
+    /// the address which connected last and its expiry, by outbound and host
+    entries: Mutex<LruCache<(String, String), (IpAddr, Instant)>>,
+}
+
+impl Default for DialCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_DIAL_CACHE_SIZE, DEFAULT_DIAL_CACHE_TTL)
+    }
+}
+
+impl DialCache {
+    pub fn new(size: usize, ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(LruCache::new(NonZeroUsize::new(size.max(1)).unwrap())),
+        }
+    }
+
+    /// The address of `host` which connected last through `outbound`, if not expired.
+    pub fn preferred(&self, outbound: &str, host: &str) -> Option<IpAddr> {
+        let mut entries = self.entries.lock().unwrap();
+        let key = (outbound.to_owned(), host.to_ascii_lowercase());
+        match entries.get(&key) {
+            Some((ip, expires)) if !clock::is_expired(*expires) => Some(*ip),
+            Some(_) => {
+                entries.pop(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Remembers that `ip` of `host` connected through `outbound`.
+    pub fn succeeded(&self, outbound: &str, host: &str, ip: IpAddr) {
+        let key = (outbound.to_owned(), host.to_ascii_lowercase());
+        self.entries.lock().unwrap().put(key, (ip, clock::now() + self.ttl));
+    }
+
+    /// Forgets `ip` of `host` after a failed dial, unless another address took its place.
+    pub fn failed(&self, outbound: &str, host: &str, ip: IpAddr) {
+        let mut entries = self.entries.lock().unwrap();
+        let key = (outbound.to_owned(), host.to_ascii_lowercase());
+        if matches!(entries.peek(&key), Some((cached, _)) if *cached == ip) {
+            debug!("forgetting {} of {} through {}", ip, host, outbound);
+            entries.pop(&key);
+        }
+    }
+
+    /// Moves the preferred address of `host` to the front of `addrs`, the rest keep their order.
+    pub fn sort(&self, outbound: &str, host: &str, addrs: &mut [SocketAddr]) {
+        if let Some(ip) = self.preferred(outbound, host) {
+            if let Some(index) = addrs.iter().position(|addr| addr.ip() == ip) {
+                addrs[..=index].rotate_right(1);
+            }
+        }
+    }
+
+    /// Dials the addresses of `host` one after another, the preferred one first, and returns the
+    /// first stream which connected. Fails with the error of the last address.
+    pub async fn dial(
+        &self,
+        outbound: &str,
+        host: &str,
+        mut addrs: Vec<SocketAddr>,
+        opts: &ConnectOpts,
+    ) -> io::Result<TcpStream> {
+        self.sort(outbound, host, &mut addrs);
+
+        let mut last_err = None;
+        for addr in addrs {
+            match crate_tcp_stream_with_opts(addr, opts).await {
+                Ok(stream) => {
+                    self.succeeded(outbound, host, addr.ip());
+                    return Ok(stream);
+                }
+                Err(err) => {
+                    debug!("dial {} of {} failed, {}", addr, host, err);
+                    self.failed(outbound, host, addr.ip());
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", host))))
+    }
+
+    /// Number of hosts remembered, including expired ones.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(ips: &[&str]) -> Vec<SocketAddr> {
+        ips.iter().map(|ip| SocketAddr::new(ip.parse().unwrap(), 443)).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dial_cache_affinity() {
+        let cache = DialCache::new(16, Duration::from_secs(60));
+        let mut dialed = addrs(&["192.0.2.1", "192.0.2.2", "192.0.2.3"]);
+        cache.sort("direct", "example.com", &mut dialed);
+        assert_eq!(dialed, addrs(&["192.0.2.1", "192.0.2.2", "192.0.2.3"]));
+
+        cache.succeeded("direct", "Example.com", "192.0.2.3".parse().unwrap());
+        cache.sort("direct", "example.com", &mut dialed);
+        assert_eq!(dialed, addrs(&["192.0.2.3", "192.0.2.1", "192.0.2.2"]));
+        assert_eq!(cache.preferred("proxy", "example.com"), None);
+
+        // a failure of another address keeps the preferred one
+        cache.failed("direct", "example.com", "192.0.2.1".parse().unwrap());
+        assert_eq!(cache.preferred("direct", "example.com"), "192.0.2.3".parse().ok());
+        cache.failed("direct", "example.com", "192.0.2.3".parse().unwrap());
+        assert_eq!(cache.preferred("direct", "example.com"), None);
+
+        cache.succeeded("direct", "example.com", "192.0.2.2".parse().unwrap());
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(cache.preferred("direct", "example.com"), None);
+        assert!(cache.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dial_cache_dial() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        // the listener is bound to 127.0.0.1 only
+        let closed = SocketAddr::new("127.0.0.2".parse().unwrap(), open.port());
+
+        let cache = DialCache::default();
+        let opts = ConnectOpts::default();
+        cache
+            .dial("direct", "localhost", vec![closed, open], &opts)
+            .await
+            .unwrap();
+        assert_eq!(cache.preferred("direct", "localhost"), Some(open.ip()));
+
+        let err = cache.dial("direct", "localhost", vec![], &opts).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
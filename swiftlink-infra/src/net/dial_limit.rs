@@ -0,0 +1,177 @@
+//! Global cap on simultaneous outbound dials.
+//!
+//! A burst of new connections (e.g. a browser waking up and reopening hundreds of sockets) is
+//! admitted `max_dials` at a time. Up to `max_queue` further dials wait for a free slot, anything
+//! beyond that fails immediately instead of piling up file descriptors.
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::log::*;
+
+static LIMITER: RwLock<Option<Arc<DialLimiter>>> = RwLock::new(None);
+
+/// Installs the process wide dial limiter admitting `(max_dials, max_queue)`, `None` removes it.
+///
+/// It replaces the limiter installed before, e.g. by another instance. Dials waiting for a slot of
+/// the previous one keep waiting for it.
+pub fn init(limits: Option<(usize, usize)>) {
+    let limiter = limits.map(|(max_dials, max_queue)| Arc::new(DialLimiter::new(max_dials, max_queue)));
+    *LIMITER.write().unwrap_or_else(|err| err.into_inner()) = limiter;
+}
+
+/// Returns the process wide dial limiter, if any.
+pub fn limiter() -> Option<Arc<DialLimiter>> {
+    LIMITER.read().unwrap_or_else(|err| err.into_inner()).clone()
+}
+
+#[derive(Debug)]
+pub struct DialLimiter {
+    permits: Semaphore,
+    max_dials: usize,
+    max_queue: usize,
+    queued: AtomicUsize,
+    rejected: AtomicU64,
+}
+
+/// Snapshot of the limiter saturation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DialStats {
+    /// dials currently holding a slot
+    pub in_flight: usize,
+    /// dials waiting for a slot
+    pub queued: usize,
+    /// dials rejected because the wait queue was full
+    pub rejected: u64,
+}
+
+/// A dial slot, released on drop.
+#[derive(Debug)]
+pub struct DialPermit<'a> {
+    _permit: SemaphorePermit<'a>,
+}
+
+impl DialLimiter {
+    pub fn new(max_dials: usize, max_queue: usize) -> Self {
+        let max_dials = max_dials.max(1);
+        Self {
+            permits: Semaphore::new(max_dials),
+            max_dials,
+            max_queue,
+            queued: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Waits for a dial slot, fails with `WouldBlock` if the wait queue is full.
+    pub async fn acquire(&self) -> io::Result<DialPermit<'_>> {
+        if let Ok(permit) = self.permits.try_acquire() {
+            return Ok(DialPermit { _permit: permit });
+        }
+
+        if self.queued.fetch_add(1, Ordering::AcqRel) >= self.max_queue {
+            self.queued.fetch_sub(1, Ordering::AcqRel);
+            let rejected = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+            // a burst rejects dials by the thousand, log the 1st, 2nd, 4th, ... only
+            if rejected.is_power_of_two() {
+                warn!(
+                    "dial queue is full ({} in flight, {} queued), rejected {} dials so far",
+                    self.max_dials, self.max_queue, rejected
+                );
+            }
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "dial queue is full"));
+        }
+
+        // leave the queue even if the waiting future is dropped
+        struct Dequeue<'a>(&'a AtomicUsize);
+
+        impl Drop for Dequeue<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+
+        let _dequeue = Dequeue(&self.queued);
+
+        let permit = self.permits.acquire().await.map_err(io::Error::other)?;
+
+        Ok(DialPermit { _permit: permit })
+    }
+
+    pub fn stats(&self) -> DialStats {
+        DialStats {
+            in_flight: self.max_dials - self.permits.available_permits(),
+            queued: self.queued.load(Ordering::Acquire),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dial_limiter_queue_and_reject() {
+        let limiter = DialLimiter::new(1, 1);
+
+        let first = limiter.acquire().await.unwrap();
+        assert_eq!(limiter.stats().in_flight, 1);
+
+        let queued = limiter.acquire();
+        tokio::pin!(queued);
+        assert!(tokio::time::timeout(Duration::from_millis(10), &mut queued)
+            .await
+            .is_err());
+        assert_eq!(limiter.stats().queued, 1);
+
+        let err = limiter.acquire().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(limiter.stats().rejected, 1);
+
+        drop(first);
+        let _second = queued.await.unwrap();
+        assert_eq!(
+            limiter.stats(),
+            DialStats {
+                in_flight: 1,
+                queued: 0,
+                rejected: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_dial_limiter_init() {
+        init(Some((2, 8)));
+        let first = limiter().unwrap();
+        assert_eq!(first.max_dials, 2);
+
+        init(Some((4, 16)));
+        let second = limiter().unwrap();
+        assert_eq!((second.max_dials, second.max_queue), (4, 16));
+
+        init(None);
+        assert!(limiter().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_dial_limiter_cancelled_waiter() {
+        let limiter = DialLimiter::new(1, 4);
+        let _first = limiter.acquire().await.unwrap();
+
+        assert!(tokio::time::timeout(Duration::from_millis(10), limiter.acquire())
+            .await
+            .is_err());
+        assert_eq!(limiter.stats().queued, 0);
+    }
+}
@@ -0,0 +1,124 @@
+//! Source address pools for hosts with more than one public IP.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::AddrFamily;
+
+/// How an [`EgressPool`] picks the source address of a connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EgressStrategy {
+    /// Rotate through the addresses
+    #[default]
+    RoundRobin,
+    /// Hash the destination, so a destination always sees the same source address
+    Hash,
+}
+
+/// Local addresses outbound sockets are bound to, one per connection.
+#[derive(Debug)]
+pub struct EgressPool {
+    v4: Vec<IpAddr>,
+    v6: Vec<IpAddr>,
+    strategy: EgressStrategy,
+    next: AtomicUsize,
+}
+
+impl EgressPool {
+    pub fn new(addrs: Vec<IpAddr>, strategy: EgressStrategy) -> Self {
+        let (v4, v6) = addrs.into_iter().partition(|ip| ip.is_ipv4());
+        Self {
+            v4,
+            v6,
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn strategy(&self) -> EgressStrategy {
+        self.strategy
+    }
+
+    /// Picks a source address of the family of `target`, `None` if the pool has none.
+    pub fn select(&self, target: SocketAddr) -> Option<IpAddr> {
+        self.pick(AddrFamily::from(&target), Some(target.ip()))
+    }
+
+    /// Picks a source address of family `af` for a socket without a single destination, e.g. an
+    /// unconnected UDP socket. Always rotates, there is nothing to hash.
+    pub fn select_family(&self, af: AddrFamily) -> Option<IpAddr> {
+        self.pick(af, None)
+    }
+
+    fn pick(&self, af: AddrFamily, target: Option<IpAddr>) -> Option<IpAddr> {
+        let addrs = match af {
+            AddrFamily::IPv4 => &self.v4,
+            AddrFamily::IPv6 => &self.v6,
+        };
+        if addrs.is_empty() {
+            return None;
+        }
+
+        let index = match (self.strategy, target) {
+            (EgressStrategy::Hash, Some(ip)) => {
+                let mut hasher = DefaultHasher::new();
+                ip.hash(&mut hasher);
+                hasher.finish() as usize
+            }
+            _ => self.next.fetch_add(1, Ordering::Relaxed),
+        };
+
+        Some(addrs[index % addrs.len()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_egress_pool_round_robin() {
+        let pool = EgressPool::new(
+            vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+            EgressStrategy::RoundRobin,
+        );
+        let target = "1.1.1.1:443".parse().unwrap();
+
+        let first = pool.select(target).unwrap();
+        let second = pool.select(target).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(pool.select(target), Some(first));
+
+        // no address of the family
+        assert_eq!(pool.select("[2606:4700::1111]:443".parse().unwrap()), None);
+        assert_eq!(pool.select_family(AddrFamily::IPv6), None);
+        assert!(pool.select_family(AddrFamily::IPv4).is_some());
+    }
+
+    #[test]
+    fn test_egress_pool_hash() {
+        let pool = EgressPool::new(
+            vec![
+                "2001:db8::1".parse().unwrap(),
+                "2001:db8::2".parse().unwrap(),
+                "2001:db8::3".parse().unwrap(),
+            ],
+            EgressStrategy::Hash,
+        );
+
+        let target: SocketAddr = "[2606:4700::1111]:443".parse().unwrap();
+        let selected = pool.select(target);
+        assert!(selected.is_some());
+        for port in [80, 8443, 53] {
+            assert_eq!(pool.select(SocketAddr::new(target.ip(), port)), selected);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! Idle upstream connections kept for reuse.
+//!
+//! A plain HTTP proxy request names its origin in every request. Keep-alive clients send many of
+//! them to the same origin, each one may be served on the upstream connection of the previous
+//! one instead of a new dial. Connections idle for the timeout, closed by the origin or beyond the
+//! per origin bound are dropped.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::Mutex,
+    time::Duration,
+};
+
+use tokio::net::TcpStream;
+
+use crate::clock::{self, Instant};
+
+/// Idle connections kept per origin.
+pub const DEFAULT_MAX_IDLE_PER_ORIGIN: usize = 4;
+
+/// Connections idle this long are closed.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+#[derive(Debug)]
+pub struct IdlePool<C> {
+    max_idle_per_origin: usize,
+    idle_timeout: Duration,
+    /// the idle connections and their expiry by origin, the most recently used last
+    origins: Mutex<HashMap<String, VecDeque<(C, Instant)>>>,
+}
+
+impl<C> Default for IdlePool<C> {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_IDLE_PER_ORIGIN, DEFAULT_IDLE_TIMEOUT)
+    }
+}
+
+impl<C> IdlePool<C> {
+    pub fn new(max_idle_per_origin: usize, idle_timeout: Duration) -> Self {
+        Self {
+            max_idle_per_origin,
+            idle_timeout,
+            origins: Default::default(),
+        }
+    }
+
+    /// Takes the most recently used idle connection to `origin`, e.g. `example.com:80`, which is
+    /// not expired and still `usable`. Others found on the way are dropped.
+    pub fn checkout<F>(&self, origin: &str, usable: F) -> Option<C>
+    where
+        F: Fn(&C) -> bool,
+    {
+        let mut origins = self.origins.lock().unwrap();
+        let idle = origins.get_mut(origin)?;
+        let now = clock::now();
+
+        let mut found = None;
+        while let Some((conn, expires)) = idle.pop_back() {
+            if now < expires && usable(&conn) {
+                found = Some(conn);
+                break;
+            }
+        }
+        if idle.is_empty() {
+            origins.remove(origin);
+        }
+        found
+    }
+
+    /// Returns `conn` to the pool after a complete response. It's dropped, and `false` returned,
+    /// if `origin` has the maximum of idle connections already.
+    pub fn checkin(&self, origin: &str, conn: C) -> bool {
+        let mut origins = self.origins.lock().unwrap();
+        let now = clock::now();
+
+        let idle = origins.entry(origin.to_owned()).or_default();
+        idle.retain(|(_, expires)| now < *expires);
+        if idle.len() >= self.max_idle_per_origin {
+            return false;
+        }
+        idle.push_back((conn, now + self.idle_timeout));
+        true
+    }
+
+    /// Drops the expired connections of all origins.
+    pub fn purge(&self) {
+        let now = clock::now();
+        let mut origins = self.origins.lock().unwrap();
+        origins.retain(|_, idle| {
+            idle.retain(|(_, expires)| now < *expires);
+            !idle.is_empty()
+        });
+    }
+
+    /// Number of idle connections, including expired ones not purged yet.
+    pub fn len(&self) -> usize {
+        self.origins.lock().unwrap().values().map(VecDeque::len).sum()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Whether an idle `stream` can carry another request: neither closed by the peer nor holding
+/// unread bytes, which would be mistaken for the next response.
+pub fn is_reusable(stream: &TcpStream) -> bool {
+    let mut buf = [0u8; 1];
+    matches!(stream.try_read(&mut buf), Err(err) if err.kind() == io::ErrorKind::WouldBlock)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_pool() {
+        let pool = IdlePool::new(2, Duration::from_secs(60));
+        assert_eq!(pool.checkout("example.com:80", |_| true), None);
+
+        assert!(pool.checkin("example.com:80", 1));
+        assert!(pool.checkin("example.com:80", 2));
+        assert!(!pool.checkin("example.com:80", 3));
+        assert!(pool.checkin("example.org:80", 4));
+        assert_eq!(pool.len(), 3);
+
+        // the most recently used first, unusable ones are dropped
+        assert_eq!(pool.checkout("example.com:80", |_| true), Some(2));
+        assert_eq!(pool.checkout("example.com:80", |conn| *conn != 1), None);
+        assert_eq!(pool.len(), 1);
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(pool.checkout("example.org:80", |_| true), None);
+
+        assert!(pool.checkin("example.org:80", 5));
+        tokio::time::advance(Duration::from_secs(60)).await;
+        pool.purge();
+        assert!(pool.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_is_reusable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        assert!(is_reusable(&client));
+
+        server.write_all(b"HTTP/1.1").await.unwrap();
+        client.readable().await.unwrap();
+        assert!(!is_reusable(&client));
+
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        drop(listener.accept().await.unwrap());
+        client.readable().await.unwrap();
+        assert!(!is_reusable(&client));
+    }
+}
@@ -0,0 +1,93 @@
+//! Network interfaces and default routes of the host.
+//!
+//! Interfaces are listed with `getifaddrs(3)` on unix. The default routes are read from the main
+//! routing table with netlink on Linux and Android, other platforms don't support them yet.
+
+use std::{io, net::IpAddr};
+
+use cfg_if::cfg_if;
+use ipnet::IpNet;
+
+use super::AddrFamily;
+
+/// A network interface and its addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    pub name: String,
+    /// index of the interface, `0` if unknown
+    pub index: u32,
+    /// the addresses and their prefix
+    pub addrs: Vec<IpNet>,
+    /// administratively up
+    pub up: bool,
+    pub loopback: bool,
+}
+
+/// The route of the destinations without a more specific one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DefaultRoute {
+    /// the interface the route goes out of
+    pub interface: String,
+    pub index: u32,
+    /// the next hop, `None` on point-to-point interfaces
+    pub gateway: Option<IpAddr>,
+}
+
+/// Lists the network interfaces in the order of the system.
+pub fn interfaces() -> io::Result<Vec<Interface>> {
+    cfg_if! {
+        if #[cfg(unix)] {
+            super::sys::interfaces_impl()
+        } else {
+            Err(unsupported())
+        }
+    }
+}
+
+/// The interface named `name`, `None` if there is no such interface.
+pub fn interface(name: &str) -> io::Result<Option<Interface>> {
+    Ok(interfaces()?.into_iter().find(|iface| iface.name == name))
+}
+
+/// The default route of `family`, the one of the lowest metric. `None` if there is none, i.e.
+/// the destinations of `family` are unreachable.
+pub fn default_route(family: AddrFamily) -> io::Result<Option<DefaultRoute>> {
+    cfg_if! {
+        if #[cfg(any(target_os = "linux", target_os = "android"))] {
+            super::sys::default_route_impl(family)
+        } else {
+            let _ = family;
+            Err(unsupported())
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "network interfaces can't be read on this platform",
+    )
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interfaces() {
+        let lo = interface("lo").unwrap().unwrap();
+        assert!(lo.loopback && lo.up);
+        assert_ne!(lo.index, 0);
+        assert!(lo.addrs.contains(&"127.0.0.1/8".parse().unwrap()));
+        assert_eq!(interface("swiftlink-none0").unwrap(), None);
+
+        // the host may be offline, the route must go out of one of its interfaces though
+        let names: Vec<_> = interfaces().unwrap().into_iter().map(|iface| iface.name).collect();
+        for family in [AddrFamily::IPv4, AddrFamily::IPv6] {
+            if let Some(route) = default_route(family).unwrap() {
+                assert!(names.contains(&route.interface), "{:?}", route);
+            }
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! Refuses dials to the listeners of this process.
+//!
+//! A route or a backend pointing at one of swiftlink's own listeners makes every connection open
+//! another one through the same listener, until the file descriptors run out. Listeners register
+//! their bound address as long as they serve, dials to it fail with [`LoopDetected`] before a
+//! socket is created.
+//!
+//! A listener on the unspecified address also catches dials to any address of the machine with
+//! its port, e.g. the LAN address of the host.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use super::iface;
+use crate::log::*;
+
+/// The addresses of the machine are listed again after this time, e.g. for a new DHCP lease.
+const LOCAL_ADDRS_TTL: Duration = Duration::from_secs(30);
+
+/// Registered addresses, once per [`Registration`].
+static LISTENERS: RwLock<Vec<SocketAddr>> = RwLock::new(Vec::new());
+
+/// The addresses of the machine and when they were listed, `None` if they can't be listed.
+static LOCAL_ADDRS: Mutex<Option<(Instant, Option<Vec<IpAddr>>)>> = Mutex::new(None);
+
+/// The error of a dial to a listener of this process.
+#[derive(Debug, Clone, Copy, thiserror::Error, PartialEq, Eq)]
+#[error("{0} is a listener of swiftlink, refusing to connect to itself")]
+pub struct LoopDetected(pub SocketAddr);
+
+/// The registration of a listener, it's unregistered when this is dropped.
+#[derive(Debug)]
+#[must_use = "the listener is unregistered when the registration is dropped"]
+pub struct Registration(SocketAddr);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut listeners = LISTENERS.write().unwrap();
+        if let Some(i) = listeners.iter().position(|addr| *addr == self.0) {
+            listeners.swap_remove(i);
+        }
+        if !listeners.contains(&self.0) {
+            debug!("loop guard stopped watching {}", self.0);
+        }
+    }
+}
+
+/// Registers the bound address of a TCP listener, until the returned [`Registration`] is dropped.
+pub fn register(addr: SocketAddr) -> Registration {
+    let mut listeners = LISTENERS.write().unwrap();
+    if !listeners.contains(&addr) {
+        debug!("loop guard watching {}", addr);
+    }
+    listeners.push(addr);
+    Registration(addr)
+}
+
+/// Fails with [`LoopDetected`] if `target` is a registered listener.
+pub fn check(target: SocketAddr) -> io::Result<()> {
+    let listeners = LISTENERS.read().unwrap();
+    if listeners.iter().any(|listener| targets(*listener, target)) {
+        warn!("refused a connection to {}, it would loop back into swiftlink", target);
+        return Err(io::Error::new(io::ErrorKind::ConnectionRefused, LoopDetected(target)));
+    }
+    Ok(())
+}
+
+/// Whether `err` was caused by a dial to a listener of this process.
+pub fn is_loop(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|err| err.is::<LoopDetected>())
+}
+
+fn targets(listener: SocketAddr, target: SocketAddr) -> bool {
+    if listener.port() != target.port() {
+        return false;
+    }
+    let ip = canonical(target.ip());
+    if canonical(listener.ip()) == ip {
+        return true;
+    }
+    listener.ip().is_unspecified() && (ip.is_loopback() || ip.is_unspecified() || is_local(ip))
+}
+
+#[inline]
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+/// Whether `ip` is an address of one of the interfaces, listed at most every
+/// [`LOCAL_ADDRS_TTL`]. Where they can't be listed, whether `ip` can be bound, only the addresses
+/// of the machine can.
+fn is_local(ip: IpAddr) -> bool {
+    let mut local_addrs = LOCAL_ADDRS.lock().unwrap();
+    let (_, addrs) = match local_addrs.as_mut() {
+        Some(cached) if cached.0.elapsed() < LOCAL_ADDRS_TTL => cached,
+        _ => {
+            let addrs = iface::interfaces()
+                .map_err(|err| debug!("loop guard can't list the local addresses, {}", err))
+                .ok()
+                .map(|ifaces| {
+                    ifaces
+                        .iter()
+                        .flat_map(|iface| iface.addrs.iter().map(|net| canonical(net.addr())))
+                        .collect()
+                });
+            local_addrs.insert((Instant::now(), addrs))
+        }
+    };
+    match addrs {
+        Some(addrs) => addrs.contains(&ip),
+        None => std::net::UdpSocket::bind(SocketAddr::new(ip, 0)).is_ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loop_guard() {
+        let _registrations = [
+            register("127.0.0.1:7891".parse().unwrap()),
+            register("0.0.0.0:7892".parse().unwrap()),
+            register("[::]:7893".parse().unwrap()),
+        ];
+
+        let err = check("127.0.0.1:7891".parse().unwrap()).unwrap_err();
+        assert!(is_loop(&err));
+        assert_eq!(
+            err.to_string(),
+            "127.0.0.1:7891 is a listener of swiftlink, refusing to connect to itself"
+        );
+        assert!(check("[::ffff:127.0.0.1]:7891".parse().unwrap()).is_err());
+        assert!(check("127.0.0.2:7891".parse().unwrap()).is_ok());
+        assert!(check("127.0.0.1:7890".parse().unwrap()).is_ok());
+
+        assert!(check("127.0.0.2:7892".parse().unwrap()).is_err());
+        assert!(check("0.0.0.0:7892".parse().unwrap()).is_err());
+        assert!(check("[::1]:7893".parse().unwrap()).is_err());
+        // TEST-NET-3 is never assigned to the machine
+        assert!(check("203.0.113.1:7892".parse().unwrap()).is_ok());
+
+        assert!(!is_loop(&io::Error::from(io::ErrorKind::ConnectionRefused)));
+    }
+
+    #[test]
+    fn test_loop_guard_unregister() {
+        let first = register("127.0.0.1:7894".parse().unwrap());
+        let second = register("127.0.0.1:7894".parse().unwrap());
+        drop(first);
+        assert!(check("127.0.0.1:7894".parse().unwrap()).is_err());
+        drop(second);
+        assert!(check("127.0.0.1:7894".parse().unwrap()).is_ok());
+    }
+}
@@ -1,5 +1,11 @@
 //! Network utilities for the swiftlink.
 
+pub mod dial_cache;
+pub mod dial_limit;
+mod egress;
+pub mod idle_pool;
+mod iface;
+pub mod loop_guard;
 mod sys;
 pub mod tcp;
 mod timeout_stream;
@@ -8,10 +14,12 @@ pub mod udp;
 mod options;
 use std::net::SocketAddr;
 
-pub use options::{ConnectOpts, TcpSocketOpts};
+pub use egress::{EgressPool, EgressStrategy};
+pub use iface::{default_route, interface, interfaces, DefaultRoute, Interface};
+pub use options::{ConnectOpts, PortRange, TcpSocketOpts, UdpNatPolicy, UdpSocketOpts};
 
 /// Address family `AF_INET`, `AF_INET6`
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub enum AddrFamily {
     /// `AF_INET`
     IPv4,
@@ -1,6 +1,11 @@
 //! Options for connecting to server
 
-use std::{net::IpAddr, time::Duration};
+use std::{fmt, net::IpAddr, num::ParseIntError, str::FromStr, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+
+use super::EgressPool;
 
 /// Options for connecting to TCP remote server
 #[derive(Debug, Clone, Default)]
@@ -30,6 +35,82 @@ pub struct TcpSocketOpts {
     pub mptcp: bool,
 }
 
+/// Options for outbound UDP sockets
+#[derive(Debug, Clone, Default)]
+pub struct UdpSocketOpts {
+    /// Local port range, a random free port of it is bound. Any port if `None`
+    pub port_range: Option<PortRange>,
+
+    /// How a relayed UDP session allocates its outbound sockets
+    pub nat_policy: UdpNatPolicy,
+}
+
+/// Outbound socket allocation of a relayed UDP session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UdpNatPolicy {
+    /// One socket per session, every destination sees the same source port (endpoint independent mapping)
+    #[default]
+    PerSession,
+    /// One socket per destination, every destination sees its own source port
+    PerDestination,
+}
+
+/// An inclusive port range, written as `20000-30000` or a single port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DeserializeFromStr, SerializeDisplay)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn new(start: u16, end: u16) -> Self {
+        Self {
+            start: start.min(end),
+            end: start.max(end),
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+
+    /// Number of ports in the range
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        (self.end - self.start) as usize + 1
+    }
+
+    pub fn random(&self) -> u16 {
+        rand::Rng::gen_range(&mut rand::thread_rng(), self.start..=self.end)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('-') {
+            Some((start, end)) => Ok(Self::new(start.trim().parse()?, end.trim().parse()?)),
+            None => {
+                let port = s.trim().parse()?;
+                Ok(Self::new(port, port))
+            }
+        }
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct ConnectOpts {
     /// Linux mark based routing, going to set by `setsockopt` with `SO_MARK` option
@@ -47,12 +128,41 @@ pub struct ConnectOpts {
     /// It only affects sockets that trying to connect to addresses with the same family
     pub bind_local_addr: Option<IpAddr>,
 
+    /// socket binds to an address of this pool, selected per connection
+    ///
+    /// It takes precedence over `bind_local_addr` if it has an address of the destination's family
+    pub egress_pool: Option<Arc<EgressPool>>,
+
     /// socket binds to interface
     pub bind_interface: Option<String>,
 
     /// TCP options
     pub tcp: TcpSocketOpts,
 
+    /// UDP options
+    pub udp: UdpSocketOpts,
+
     /// tcp connect timeout
     pub connect_timeout: Option<Duration>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_port_range_parse() {
+        let range: PortRange = "30000-20000".parse().unwrap();
+        assert_eq!(range, PortRange::new(20000, 30000));
+        assert_eq!(range.to_string(), "20000-30000");
+        assert_eq!(range.len(), 10001);
+        assert!(range.contains(range.random()));
+
+        let range: PortRange = " 5353 ".parse().unwrap();
+        assert_eq!(range.len(), 1);
+        assert_eq!(range.to_string(), "5353");
+
+        assert!("1-65536".parse::<PortRange>().is_err());
+        assert!("a-b".parse::<PortRange>().is_err());
+    }
+}
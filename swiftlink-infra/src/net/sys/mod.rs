@@ -1,13 +1,20 @@
 use std::{
+    future::Future,
     io::{self, ErrorKind},
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 };
 
 use cfg_if::cfg_if;
 use socket2::{SockAddr, Socket};
-use tokio::net::TcpSocket;
+use tokio::net::{TcpSocket, UdpSocket};
 
-use crate::{log::*, net::options::ConnectOpts};
+use crate::{
+    log::*,
+    net::{options::ConnectOpts, AddrFamily},
+};
+
+/// Ports of `udp.port_range` tried before giving up on a busy range
+const MAX_UDP_PORT_ATTEMPTS: usize = 16;
 
 cfg_if! {
     if #[cfg(unix)] {
@@ -21,7 +28,12 @@ cfg_if! {
 
 fn set_common_sockopt_for_connect(addr: SocketAddr, socket: &TcpSocket, conn_opts: &ConnectOpts) -> io::Result<()> {
     // Binds to IP address
-    if let Some(ip) = conn_opts.bind_local_addr {
+    let bind_local_addr = conn_opts
+        .egress_pool
+        .as_ref()
+        .and_then(|pool| pool.select(addr))
+        .or(conn_opts.bind_local_addr);
+    if let Some(ip) = bind_local_addr {
         match (ip, addr.ip()) {
             (IpAddr::V4(..), IpAddr::V4(..)) => {
                 socket.bind(SocketAddr::new(ip, 0))?;
@@ -46,6 +58,47 @@ fn set_common_sockopt_for_connect(addr: SocketAddr, socket: &TcpSocket, conn_opt
     Ok(())
 }
 
+/// Binds an outbound UDP socket of family `af` with `bind`.
+///
+/// The local address is taken from `egress_pool` or `bind_local_addr` of the same family, or
+/// unspecified. With a
+/// `udp.port_range` random ports of it are tried until a free one is found.
+async fn bind_outbound_udp_socket<F, Fut>(af: AddrFamily, conn_opts: &ConnectOpts, bind: F) -> io::Result<UdpSocket>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<UdpSocket>>,
+{
+    let bind_local_addr = conn_opts
+        .egress_pool
+        .as_ref()
+        .and_then(|pool| pool.select_family(af))
+        .or(conn_opts.bind_local_addr);
+    let ip = match (af, bind_local_addr) {
+        (AddrFamily::IPv4, Some(ip @ IpAddr::V4(..))) => ip,
+        (AddrFamily::IPv6, Some(ip @ IpAddr::V6(..))) => ip,
+        (AddrFamily::IPv4, _) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        (AddrFamily::IPv6, _) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    let Some(range) = conn_opts.udp.port_range else {
+        return bind(SocketAddr::new(ip, 0)).await;
+    };
+
+    let mut attempts = range.len().min(MAX_UDP_PORT_ATTEMPTS);
+    loop {
+        match bind(SocketAddr::new(ip, range.random())).await {
+            Err(err) if err.kind() == ErrorKind::AddrInUse && attempts > 1 => attempts -= 1,
+            Err(err) if err.kind() == ErrorKind::AddrInUse => {
+                return Err(io::Error::new(
+                    ErrorKind::AddrInUse,
+                    format!("no free udp port in {}", range),
+                ))
+            }
+            result => return result,
+        }
+    }
+}
+
 /// Try to call `bind()` with dual-stack enabled.
 ///
 /// Users have to ensure that `addr` is a dual-stack inbound address (`::`) when `ipv6_only` is `false`.
@@ -60,7 +113,7 @@ where
 
     let sock = unsafe { Socket::from_raw_fd(fd) };
     let result = socket_bind_dual_stack_inner(&sock, addr, ipv6_only);
-    sock.into_raw_fd();
+    let _ = sock.into_raw_fd();
 
     result
 }
@@ -3,7 +3,7 @@ use std::{
     collections::HashMap,
     io::{self, ErrorKind},
     mem,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    net::SocketAddr,
     os::unix::io::{AsRawFd, FromRawFd, IntoRawFd},
     ptr,
     time::{Duration, Instant},
@@ -15,7 +15,10 @@ use tokio::net::{TcpSocket, TcpStream, UdpSocket};
 use crate::{
     log::*,
     net::{
-        sys::{set_common_sockopt_after_connect, set_common_sockopt_for_connect, socket_bind_dual_stack},
+        sys::{
+            bind_outbound_udp_socket, set_common_sockopt_after_connect, set_common_sockopt_for_connect,
+            socket_bind_dual_stack,
+        },
         AddrFamily, ConnectOpts,
     },
 };
@@ -157,12 +160,8 @@ pub(crate) async fn bind_udp_socket_impl(bind_addr: &SocketAddr, conn_opts: &Con
 }
 
 pub(crate) async fn create_udp_socket_impl(af: AddrFamily, conn_opts: &ConnectOpts) -> io::Result<UdpSocket> {
-    let bind_addr = match (af, conn_opts.bind_local_addr) {
-        (AddrFamily::IPv4, Some(IpAddr::V4(ip))) => SocketAddr::new(ip.into(), 0),
-        (AddrFamily::IPv6, Some(IpAddr::V6(ip))) => SocketAddr::new(ip.into(), 0),
-        (AddrFamily::IPv4, _) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
-        (AddrFamily::IPv6, _) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
-    };
-
-    bind_udp_socket_impl(&bind_addr, conn_opts).await
+    bind_outbound_udp_socket(af, conn_opts, |bind_addr| async move {
+        bind_udp_socket_impl(&bind_addr, conn_opts).await
+    })
+    .await
 }
@@ -1,23 +1,26 @@
 use cfg_if::cfg_if;
 use socket2::Socket;
+use socket2::{Domain, Protocol, Type};
 use std::{
-    io, mem,
-    net::SocketAddr,
+    ffi::CStr,
+    io::{self, Read, Write},
+    mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     os::unix::io::{AsRawFd, FromRawFd, IntoRawFd},
 };
-use tokio::net::{TcpSocket, TcpStream};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
 
 use crate::{
     log,
-    net::{sys::set_common_sockopt_for_connect, ConnectOpts},
+    net::{
+        sys::{bind_outbound_udp_socket, set_common_sockopt_for_connect, socket_bind_dual_stack},
+        AddrFamily, ConnectOpts, DefaultRoute,
+    },
 };
 
 use super::set_common_sockopt_after_connect;
 
-pub(crate) async fn connect_tcp_with_opts_impl(
-    addr: SocketAddr,
-    opts: &ConnectOpts,
-) -> io::Result<TcpStream> {
+pub(crate) async fn create_tcp_stream_impl(addr: SocketAddr, opts: &ConnectOpts) -> io::Result<TcpStream> {
     let socket = if opts.tcp.mptcp {
         create_mptcp_socket(&addr)?
     } else {
@@ -37,12 +40,7 @@ pub(crate) async fn connect_tcp_with_opts_impl(
         if let Some(ref path) = opts.vpn_protect_path {
             // RPC calls to `VpnService.protect()`
             // Timeout in 3 seconds like shadowsocks-libev
-            match time::timeout(
-                Duration::from_secs(3),
-                vpn_protect(path, socket.as_raw_fd()),
-            )
-            .await
-            {
+            match time::timeout(Duration::from_secs(3), vpn_protect(path, socket.as_raw_fd())).await {
                 Ok(Ok(..)) => {}
                 Ok(Err(err)) => return Err(err),
                 Err(..) => return Err(io::Error::new(ErrorKind::TimedOut, "protect() timeout")),
@@ -50,23 +48,8 @@ pub(crate) async fn connect_tcp_with_opts_impl(
         }
     }
 
-    // Set SO_MARK for mark-based routing on Linux (since 2.6.25)
-    // NOTE: This will require CAP_NET_ADMIN capability (root in most cases)
     if let Some(mark) = opts.fwmark {
-        let ret = unsafe {
-            libc::setsockopt(
-                socket.as_raw_fd(),
-                libc::SOL_SOCKET,
-                libc::SO_MARK,
-                &mark as *const _ as *const _,
-                mem::size_of_val(&mark) as libc::socklen_t,
-            )
-        };
-        if ret != 0 {
-            let err = io::Error::last_os_error();
-            log::error!("set SO_MARK error: {}", err);
-            return Err(err);
-        }
+        set_fwmark(&socket, mark)?;
     }
 
     // Set SO_BINDTODEVICE for binding to a specific interface
@@ -83,6 +66,60 @@ pub(crate) async fn connect_tcp_with_opts_impl(
     Ok(stream)
 }
 
+pub(crate) async fn bind_udp_socket_impl(bind_addr: &SocketAddr, opts: &ConnectOpts) -> io::Result<UdpSocket> {
+    let af: AddrFamily = From::from(bind_addr);
+
+    let socket = if af != AddrFamily::IPv6 {
+        UdpSocket::bind(bind_addr).await?
+    } else {
+        let socket = Socket::new(Domain::for_address(*bind_addr), Type::DGRAM, Some(Protocol::UDP))?;
+        socket_bind_dual_stack(&socket, bind_addr, false)?;
+
+        // UdpSocket::from_std requires socket to be non-blocking
+        socket.set_nonblocking(true)?;
+        UdpSocket::from_std(socket.into())?
+    };
+
+    if let Some(mark) = opts.fwmark {
+        set_fwmark(&socket, mark)?;
+    }
+
+    if let Some(ref iface) = opts.bind_interface {
+        set_bindtodevice(&socket, iface)?;
+    }
+
+    Ok(socket)
+}
+
+pub(crate) async fn create_udp_socket_impl(af: AddrFamily, opts: &ConnectOpts) -> io::Result<UdpSocket> {
+    bind_outbound_udp_socket(af, opts, |bind_addr| async move {
+        bind_udp_socket_impl(&bind_addr, opts).await
+    })
+    .await
+}
+
+/// Sets SO_MARK for mark-based routing on Linux (since 2.6.25)
+///
+/// NOTE: This will require CAP_NET_ADMIN capability (root in most cases)
+fn set_fwmark<S: AsRawFd>(socket: &S, mark: u32) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            &mark as *const _ as *const _,
+            mem::size_of_val(&mark) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        let err = io::Error::last_os_error();
+        log::error!("set SO_MARK error: {}", err);
+        return Err(err);
+    }
+
+    Ok(())
+}
+
 fn create_mptcp_socket(bind_addr: &SocketAddr) -> io::Result<TcpSocket> {
     unsafe {
         let family = match bind_addr {
@@ -118,6 +155,132 @@ fn set_bindtodevice<S: AsRawFd>(socket: &S, iface: &str) -> io::Result<()> {
     Ok(())
 }
 
+/// Length of `struct nlmsghdr`
+const NLMSG_HDR_LEN: usize = 16;
+
+/// Length of `struct rtmsg`
+const RTMSG_LEN: usize = 12;
+
+/// Finds the default route of `af` in the main routing table with a `RTM_GETROUTE` dump.
+pub fn default_route_impl(af: AddrFamily) -> io::Result<Option<DefaultRoute>> {
+    let family = match af {
+        AddrFamily::IPv4 => libc::AF_INET,
+        AddrFamily::IPv6 => libc::AF_INET6,
+    };
+    let socket = Socket::new(
+        Domain::from(libc::AF_NETLINK),
+        Type::RAW,
+        Some(Protocol::from(libc::NETLINK_ROUTE)),
+    )?;
+
+    let mut request = Vec::with_capacity(NLMSG_HDR_LEN + RTMSG_LEN);
+    request.extend_from_slice(&((NLMSG_HDR_LEN + RTMSG_LEN) as u32).to_ne_bytes());
+    request.extend_from_slice(&libc::RTM_GETROUTE.to_ne_bytes());
+    request.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+    // sequence number and port id, the kernel fills in the latter
+    request.extend_from_slice(&[0; 8]);
+    // family, then lengths, tos, table, protocol, scope, type and flags left unspecified
+    request.extend_from_slice(&[family as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    (&socket).write_all(&request)?;
+
+    // the routes and their metric, the lowest wins
+    let mut best: Option<(u32, u32, Option<IpAddr>)> = None;
+    let mut buf = vec![0u8; 32 * 1024];
+    loop {
+        let n = (&socket).read(&mut buf)?;
+        let mut messages = &buf[..n];
+        while messages.len() >= NLMSG_HDR_LEN {
+            let len = u32::from_ne_bytes(messages[..4].try_into().unwrap()) as usize;
+            if len < NLMSG_HDR_LEN || len > messages.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated netlink message"));
+            }
+            let (message, rest) = messages.split_at(len);
+            messages = rest.get(align4(len) - len..).unwrap_or_default();
+
+            let payload = &message[NLMSG_HDR_LEN..];
+            match u16::from_ne_bytes(message[4..6].try_into().unwrap()) as libc::c_int {
+                libc::NLMSG_DONE => {
+                    return Ok(best.and_then(|(_, index, gateway)| {
+                        Some(DefaultRoute {
+                            interface: interface_name(index)?,
+                            index,
+                            gateway,
+                        })
+                    }))
+                }
+                libc::NLMSG_ERROR if payload.len() >= 4 => {
+                    let errno = i32::from_ne_bytes(payload[..4].try_into().unwrap());
+                    if errno != 0 {
+                        return Err(io::Error::from_raw_os_error(-errno));
+                    }
+                }
+                ty if ty == libc::RTM_NEWROUTE as libc::c_int => {
+                    if let Some((metric, index, gateway)) = parse_default_route(payload) {
+                        if best.is_none_or(|(best, ..)| metric < best) {
+                            best = Some((metric, index, gateway));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The metric, output interface and gateway of `payload`, a `RTM_NEWROUTE`, if it's a default
+/// unicast route of the main table.
+fn parse_default_route(payload: &[u8]) -> Option<(u32, u32, Option<IpAddr>)> {
+    let rtmsg = payload.get(..RTMSG_LEN)?;
+    let (dst_len, mut table, ty) = (rtmsg[1], rtmsg[4] as u32, rtmsg[7]);
+    if dst_len != 0 || ty != libc::RTN_UNICAST {
+        return None;
+    }
+
+    let (mut metric, mut index, mut gateway) = (0, None, None);
+    let mut attrs = &payload[RTMSG_LEN..];
+    while attrs.len() >= 4 {
+        let len = u16::from_ne_bytes([attrs[0], attrs[1]]) as usize;
+        if len < 4 || len > attrs.len() {
+            break;
+        }
+        let value = &attrs[4..len];
+        match u16::from_ne_bytes([attrs[2], attrs[3]]) {
+            libc::RTA_TABLE if value.len() == 4 => table = u32::from_ne_bytes(value.try_into().unwrap()),
+            libc::RTA_PRIORITY if value.len() == 4 => metric = u32::from_ne_bytes(value.try_into().unwrap()),
+            libc::RTA_OIF if value.len() == 4 => index = Some(u32::from_ne_bytes(value.try_into().unwrap())),
+            libc::RTA_GATEWAY => {
+                gateway = match value.len() {
+                    4 => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(value).unwrap()))),
+                    16 => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(value).unwrap()))),
+                    _ => None,
+                }
+            }
+            _ => {}
+        }
+        attrs = attrs.get(align4(len)..).unwrap_or_default();
+    }
+
+    if table != libc::RT_TABLE_MAIN as u32 {
+        return None;
+    }
+    // multipath routes have no single interface, they are skipped
+    Some((metric, index?, gateway))
+}
+
+#[inline]
+fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// The name of the interface `index`.
+fn interface_name(index: u32) -> Option<String> {
+    let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+    if unsafe { libc::if_indextoname(index, name.as_mut_ptr()) }.is_null() {
+        return None;
+    }
+    Some(unsafe { CStr::from_ptr(name.as_ptr()) }.to_string_lossy().into_owned())
+}
+
 cfg_if! {
     if #[cfg(target_os = "android")] {
         use std::{
@@ -132,7 +295,8 @@ cfg_if! {
         ///
         /// https://developer.android.com/reference/android/net/VpnService#protect(java.net.Socket)
         ///
-        /// More detail could be found in [shadowsocks-android](https://github.com/shadowsocks/shadowsocks-android) project.
+        /// More detail could be found in
+        /// [shadowsocks-android](https://github.com/shadowsocks/shadowsocks-android) project.
         async fn vpn_protect<P: AsRef<Path>>(protect_path: P, fd: RawFd) -> io::Result<()> {
             let mut stream = UnixStream::connect(protect_path).await?;
 
@@ -1,12 +1,15 @@
 use std::{
+    ffi::CStr,
     io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     os::unix::io::{AsRawFd, FromRawFd, IntoRawFd},
 };
 
 use cfg_if::cfg_if;
+use ipnet::IpNet;
 use socket2::{Socket, TcpKeepalive};
 
-use crate::net::{options::TcpSocketOpts, ConnectOpts};
+use crate::net::{options::TcpSocketOpts, ConnectOpts, Interface};
 
 cfg_if! {
     if #[cfg(any(target_os = "linux", target_os = "android"))] {
@@ -52,7 +55,7 @@ fn set_tcp_keepalive(socket: &Socket, tcp: &TcpSocketOpts) -> io::Result<()> {
                 // https://github.com/multipath-tcp/mptcp_net-next/issues/383
                 // https://github.com/multipath-tcp/mptcp_net-next/issues/353
                 if let Err(err) = socket.set_tcp_keepalive(&keepalive) {
-                    log::debug!("set TCP keep-alive with time & interval failed with error: {:?}", err);
+                    crate::log::debug!("set TCP keep-alive with time & interval failed with error: {:?}", err);
 
                     // Try again without time & interval
                     let keepalive = TcpKeepalive::new();
@@ -88,3 +91,63 @@ fn set_common_sockopt_after_connect_impl(socket: &Socket, opts: &ConnectOpts) ->
 
     Ok(())
 }
+
+/// Lists the interfaces with `getifaddrs(3)`, which returns an entry per address.
+pub fn interfaces_impl() -> io::Result<Vec<Interface>> {
+    let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifap) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut interfaces: Vec<Interface> = Vec::new();
+    let mut next = ifap;
+    while let Some(ifa) = unsafe { next.as_ref() } {
+        next = ifa.ifa_next;
+        let name = unsafe { CStr::from_ptr(ifa.ifa_name) }.to_string_lossy();
+        let position = match interfaces.iter().position(|iface| iface.name == name) {
+            Some(position) => position,
+            None => {
+                interfaces.push(Interface {
+                    name: name.into_owned(),
+                    index: unsafe { libc::if_nametoindex(ifa.ifa_name) },
+                    addrs: Vec::new(),
+                    up: ifa.ifa_flags & libc::IFF_UP as libc::c_uint != 0,
+                    loopback: ifa.ifa_flags & libc::IFF_LOOPBACK as libc::c_uint != 0,
+                });
+                interfaces.len() - 1
+            }
+        };
+
+        let Some(addr) = (unsafe { sockaddr_ip(ifa.ifa_addr) }) else {
+            continue;
+        };
+        let prefix_len = match unsafe { sockaddr_ip(ifa.ifa_netmask) } {
+            Some(IpAddr::V4(mask)) => u32::from(mask).count_ones(),
+            Some(IpAddr::V6(mask)) => u128::from(mask).count_ones(),
+            // some systems leave out the netmask of point-to-point interfaces
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        if let Ok(net) = IpNet::new(addr, prefix_len as u8) {
+            interfaces[position].addrs.push(net);
+        }
+    }
+
+    unsafe { libc::freeifaddrs(ifap) };
+    Ok(interfaces)
+}
+
+/// The IP address of `sa`, `None` if null or of another family.
+unsafe fn sockaddr_ip(sa: *const libc::sockaddr) -> Option<IpAddr> {
+    match sa.as_ref()?.sa_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = &*(sa as *const libc::sockaddr_in);
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr))))
+        }
+        libc::AF_INET6 => {
+            let sin6 = &*(sa as *const libc::sockaddr_in6);
+            Some(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)))
+        }
+        _ => None,
+    }
+}
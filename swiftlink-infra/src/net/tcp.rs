@@ -2,9 +2,23 @@ use std::{io, net::SocketAddr};
 
 use tokio::net::TcpStream;
 
-use crate::net::{sys::create_tcp_stream_impl, ConnectOpts};
+use crate::net::{dial_limit, loop_guard, sys::create_tcp_stream_impl, ConnectOpts};
 
 /// Dials a TCP stream with the given options
+///
+/// The dial waits for a slot of the global dial limiter if one is installed. Dials to a listener of
+/// this process fail, see [`loop_guard`].
 pub async fn crate_tcp_stream_with_opts(server_addr: SocketAddr, conn_opts: &ConnectOpts) -> io::Result<TcpStream> {
+    loop_guard::check(server_addr)?;
+
+    #[cfg(feature = "chaos")]
+    crate::chaos::inject_dial(server_addr).await?;
+
+    let limiter = dial_limit::limiter();
+    let _permit = match &limiter {
+        Some(limiter) => Some(limiter.acquire().await?),
+        None => None,
+    };
+
     create_tcp_stream_impl(server_addr, conn_opts).await
 }
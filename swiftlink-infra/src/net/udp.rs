@@ -1,10 +1,10 @@
-use std::{io, net::SocketAddr};
+use std::{collections::HashMap, io, net::SocketAddr, sync::Arc};
 
 use tokio::net::UdpSocket;
 
 use crate::net::{
     sys::{bind_udp_socket_impl, create_udp_socket_impl},
-    ConnectOpts,
+    AddrFamily, ConnectOpts, UdpNatPolicy,
 };
 
 /// Creates a UDP socket
@@ -19,3 +19,85 @@ pub async fn connect_udp_socket_with_opts(server_addr: SocketAddr, conn_opts: &C
 pub async fn bind_udp_socket_with_opts(bind_addr: SocketAddr, conn_opts: &ConnectOpts) -> io::Result<UdpSocket> {
     bind_udp_socket_impl(&bind_addr, conn_opts).await
 }
+
+/// Creates an unconnected UDP socket of family `af` for sending to any destination
+pub async fn create_udp_socket_with_opts(af: AddrFamily, conn_opts: &ConnectOpts) -> io::Result<UdpSocket> {
+    create_udp_socket_impl(af, conn_opts).await
+}
+
+/// Outbound sockets of one relayed UDP session, allocated by `conn_opts.udp.nat_policy`.
+pub struct UdpSessionSockets {
+    conn_opts: ConnectOpts,
+    sockets: HashMap<(AddrFamily, Option<SocketAddr>), Arc<UdpSocket>>,
+}
+
+impl UdpSessionSockets {
+    pub fn new(conn_opts: ConnectOpts) -> Self {
+        Self {
+            conn_opts,
+            sockets: HashMap::new(),
+        }
+    }
+
+    /// Returns the socket for sending to `target`, created on first use.
+    pub async fn socket_for(&mut self, target: SocketAddr) -> io::Result<Arc<UdpSocket>> {
+        let af = AddrFamily::from(&target);
+        let key = match self.conn_opts.udp.nat_policy {
+            UdpNatPolicy::PerSession => (af, None),
+            UdpNatPolicy::PerDestination => (af, Some(target)),
+        };
+
+        if let Some(socket) = self.sockets.get(&key) {
+            return Ok(socket.clone());
+        }
+
+        let socket = Arc::new(create_udp_socket_impl(af, &self.conn_opts).await?);
+        self.sockets.insert(key, socket.clone());
+        Ok(socket)
+    }
+
+    /// All sockets of the session, to receive replies from.
+    pub fn sockets(&self) -> impl Iterator<Item = &Arc<UdpSocket>> {
+        self.sockets.values()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.sockets.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.sockets.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::PortRange;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_udp_session_sockets() {
+        let mut opts = ConnectOpts::default();
+        opts.udp.port_range = Some(PortRange::new(41000, 41999));
+
+        let a: SocketAddr = "127.0.0.1:5301".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:5302".parse().unwrap();
+
+        let mut session = UdpSessionSockets::new(opts.clone());
+        let sa = session.socket_for(a).await.unwrap();
+        let sb = session.socket_for(b).await.unwrap();
+        assert!(Arc::ptr_eq(&sa, &sb));
+        assert!(opts.udp.port_range.unwrap().contains(sa.local_addr().unwrap().port()));
+
+        opts.udp.nat_policy = UdpNatPolicy::PerDestination;
+        let mut session = UdpSessionSockets::new(opts);
+        let sa = session.socket_for(a).await.unwrap();
+        let sb = session.socket_for(b).await.unwrap();
+        assert_ne!(sa.local_addr().unwrap(), sb.local_addr().unwrap());
+        assert!(Arc::ptr_eq(&sa, &session.socket_for(a).await.unwrap()));
+        assert_eq!(session.len(), 2);
+    }
+}
@@ -35,10 +35,7 @@ mod tests {
             parse_sock_addrs(":123"),
             Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 123))
         );
-        assert_eq!(
-            parse_sock_addrs("[::1]:123"),
-            "[::1]:123".parse::<SocketAddr>()
-        );
+        assert_eq!(parse_sock_addrs("[::1]:123"), "[::1]:123".parse::<SocketAddr>());
         assert_eq!(
             parse_sock_addrs("[::]:123"),
             Ok(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 123))
@@ -0,0 +1,200 @@
+//! Dropping root privileges once the listeners are bound.
+
+use std::{ffi::CString, io, mem, ptr};
+
+use crate::log::*;
+
+/// Numeric ids of a user and group resolved by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+impl Credentials {
+    /// Resolves `user` and `group`, the group defaults to the primary group of the user.
+    pub fn lookup(user: &str, group: Option<&str>) -> io::Result<Self> {
+        let (uid, primary_gid) = lookup_user(user)?;
+        let gid = match group {
+            Some(group) => lookup_group(group)?,
+            None => primary_gid,
+        };
+        Ok(Self { uid, gid })
+    }
+}
+
+/// Switches the process to `credentials`.
+///
+/// With `keep_net_admin` the process retains `CAP_NET_ADMIN` (Linux only), which setting
+/// `SO_MARK` requires. All other capabilities are lost.
+pub fn drop_privileges(credentials: Credentials, keep_net_admin: bool) -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if keep_net_admin {
+        // keep the permitted capabilities over setuid()
+        if unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    if keep_net_admin {
+        warn!("CAP_NET_ADMIN can't be retained on this platform");
+    }
+
+    unsafe {
+        if libc::setgroups(1, &credentials.gid) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::setgid(credentials.gid) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::setuid(credentials.uid) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if keep_net_admin {
+        linux::retain_net_admin()?;
+    }
+
+    info!("dropped privileges to uid {} gid {}", credentials.uid, credentials.gid);
+    Ok(())
+}
+
+/// Returns `true` if the process runs as root.
+#[inline]
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Returns `true` if `CAP_NET_ADMIN`, which setting `SO_MARK` requires, is effective.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn has_net_admin() -> io::Result<bool> {
+    linux::has_net_admin()
+}
+
+fn lookup_user(user: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let name = CString::new(user).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid user name"))?;
+    let mut buf = vec![0u8; 4096];
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+
+    let ret = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut pwd,
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown user {}", user),
+        ));
+    }
+
+    Ok((pwd.pw_uid, pwd.pw_gid))
+}
+
+fn lookup_group(group: &str) -> io::Result<libc::gid_t> {
+    let name = CString::new(group).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid group name"))?;
+    let mut buf = vec![0u8; 4096];
+    let mut grp: libc::group = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+
+    let ret = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown group {}", group),
+        ));
+    }
+
+    Ok(grp.gr_gid)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod linux {
+    use std::io;
+
+    /// `_LINUX_CAPABILITY_VERSION_3`, 64 bit capability sets
+    const CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+    const CAP_NET_ADMIN: u32 = 12;
+
+    #[repr(C)]
+    struct CapHeader {
+        version: u32,
+        pid: libc::c_int,
+    }
+
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    struct CapData {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+
+    /// Reduces the permitted and effective capabilities to `CAP_NET_ADMIN`.
+    pub(super) fn retain_net_admin() -> io::Result<()> {
+        let header = CapHeader {
+            version: CAPABILITY_VERSION_3,
+            pid: 0,
+        };
+        let mut data = [CapData::default(); 2];
+        data[0].effective = 1 << CAP_NET_ADMIN;
+        data[0].permitted = 1 << CAP_NET_ADMIN;
+
+        if unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub(super) fn has_net_admin() -> io::Result<bool> {
+        let header = CapHeader {
+            version: CAPABILITY_VERSION_3,
+            pid: 0,
+        };
+        let mut data = [CapData::default(); 2];
+
+        if unsafe { libc::syscall(libc::SYS_capget, &header, data.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(data[0].effective & (1 << CAP_NET_ADMIN) != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credentials_lookup() {
+        let root = Credentials::lookup("root", None).unwrap();
+        assert_eq!(root.uid, 0);
+        assert_eq!(root.gid, 0);
+
+        assert_eq!(
+            Credentials::lookup("swiftlink-no-such-user", None).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(Credentials::lookup("root", Some("swiftlink-no-such-group")).is_err());
+    }
+}
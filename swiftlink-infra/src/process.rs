@@ -0,0 +1,156 @@
+//! The local process which owns the client side of a connection, for `PROCESS-NAME` and
+//! `PROCESS-PATH` rules.
+//!
+//! On Linux the socket bound to the source address is looked up in `/proc/net/{tcp,udp}{,6}`,
+//! then the process holding a descriptor of its inode in `/proc/<pid>/fd`. Only connections of
+//! this host have an owner, and only processes of the same user are visible unless running as
+//! root. Other platforms have no lookup yet, their connections have no owner.
+
+use std::{net::SocketAddr, path::PathBuf};
+
+/// A process owning a socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Process {
+    pub pid: u32,
+    /// the file name of the executable, e.g. `curl`
+    pub name: String,
+    /// the path of the executable, e.g. `/usr/bin/curl`
+    pub path: PathBuf,
+}
+
+/// Finds the process owning the `tcp` or `udp` socket bound to `source`, the address the
+/// connection came from. `None` if the process is not local, gone or not visible.
+pub fn find(network: &str, source: SocketAddr) -> Option<Process> {
+    #[cfg(target_os = "linux")]
+    {
+        linux::find(network, source)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (network, source);
+        None
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        fs,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+        path::PathBuf,
+    };
+
+    use super::Process;
+    use crate::log::*;
+
+    pub(super) fn find(network: &str, source: SocketAddr) -> Option<Process> {
+        let tables: &[&str] = match network {
+            "tcp" => &["/proc/net/tcp", "/proc/net/tcp6"],
+            "udp" => &["/proc/net/udp", "/proc/net/udp6"],
+            _ => return None,
+        };
+        let inode = tables.iter().find_map(|table| {
+            let table = fs::read_to_string(table)
+                .map_err(|err| debug!("read {} failed, {}", table, err))
+                .ok()?;
+            socket_inode(&table, source)
+        })?;
+        owner(inode)
+    }
+
+    /// The inode of the socket of `table` whose local address is `source`.
+    pub(super) fn socket_inode(table: &str, source: SocketAddr) -> Option<u64> {
+        let source = SocketAddr::new(source.ip().to_canonical(), source.port());
+        // sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode
+        table.lines().skip(1).find_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let local = parse_address(fields.get(1)?)?;
+            let inode = fields.get(9)?.parse().ok()?;
+            (local == source && inode != 0).then_some(inode)
+        })
+    }
+
+    /// Parses `0100007F:1F90`, the address as the kernel stores it, in host byte order words,
+    /// and the port.
+    fn parse_address(s: &str) -> Option<SocketAddr> {
+        let (ip, port) = s.split_once(':')?;
+        let port = u16::from_str_radix(port, 16).ok()?;
+        let word = |i: usize| {
+            let word = ip.get(i * 8..i * 8 + 8)?;
+            u32::from_str_radix(word, 16).ok().map(u32::to_ne_bytes)
+        };
+        let ip = match ip.len() {
+            8 => IpAddr::V4(Ipv4Addr::from(word(0)?)),
+            32 => {
+                let mut octets = [0u8; 16];
+                for i in 0..4 {
+                    octets[i * 4..i * 4 + 4].copy_from_slice(&word(i)?);
+                }
+                Ipv6Addr::from(octets).to_canonical()
+            }
+            _ => return None,
+        };
+        Some(SocketAddr::new(ip, port))
+    }
+
+    /// The process with a descriptor of the socket `inode`.
+    fn owner(inode: u64) -> Option<Process> {
+        let link = PathBuf::from(format!("socket:[{}]", inode));
+        let pid = fs::read_dir("/proc").ok()?.flatten().find_map(|entry| {
+            let pid: u32 = entry.file_name().to_str()?.parse().ok()?;
+            // processes of other users can't be read, they're skipped
+            let fds = fs::read_dir(entry.path().join("fd")).ok()?;
+            fds.flatten()
+                .any(|fd| fs::read_link(fd.path()).is_ok_and(|target| target == link))
+                .then_some(pid)
+        })?;
+
+        let path = fs::read_link(format!("/proc/{}/exe", pid)).ok()?;
+        let name = path.file_name()?.to_string_lossy().into_owned();
+        Some(Process { pid, name, path })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn hex(octets: &[u8]) -> String {
+            octets
+                .chunks(4)
+                .map(|word| format!("{:08X}", u32::from_ne_bytes(word.try_into().unwrap())))
+                .collect()
+        }
+
+        #[test]
+        fn test_socket_inode() {
+            let v4 = hex(&[127, 0, 0, 1]);
+            let v6 = hex(&"::ffff:192.168.1.2".parse::<Ipv6Addr>().unwrap().octets());
+            let table = format!(
+                "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n\
+                 0: {v4}:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 4242 1\n\
+                 1: {v6}:C350 {v4}:1F90 01 00000000:00000000 00:00000000 00000000  1000        0 4343 1\n\
+                 2: {v4}:C351 {v4}:1F90 06 00000000:00000000 00:00000000 00000000     0        0 0 1\n"
+            );
+            assert_eq!(socket_inode(&table, "127.0.0.1:8080".parse().unwrap()), Some(4242));
+            assert_eq!(socket_inode(&table, "192.168.1.2:50000".parse().unwrap()), Some(4343));
+            assert_eq!(
+                socket_inode(&table, "[::ffff:192.168.1.2]:50000".parse().unwrap()),
+                Some(4343)
+            );
+            // a socket in TIME_WAIT has no inode
+            assert_eq!(socket_inode(&table, "127.0.0.1:50001".parse().unwrap()), None);
+            assert_eq!(socket_inode(&table, "127.0.0.1:8081".parse().unwrap()), None);
+        }
+
+        #[test]
+        fn test_find() {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+            let process = find("tcp", client.local_addr().unwrap()).unwrap();
+            assert_eq!(process.pid, std::process::id());
+            assert_eq!(process.path, std::env::current_exe().unwrap());
+            assert!(find("udp", client.local_addr().unwrap()).is_none());
+        }
+    }
+}
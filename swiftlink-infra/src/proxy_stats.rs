@@ -0,0 +1,183 @@
+//! Measurements of the configured proxies, kept for the lifetime of the process.
+//!
+//! Latency alone doesn't tell the usable bandwidth, each proxy keeps its delay history and the
+//! result of its latest speed test side by side.
+//!
+//! The delay histories are also kept in the cache file. Restored at startup, they tell which
+//! proxies were down before the restart until the first round of health checks is done, the
+//! selectors of the groups can be seeded with them right away.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use chrono::{DateTime, FixedOffset, Local};
+use serde::Serialize;
+
+use crate::{
+    cachefile::CacheFile,
+    delay::{self, DelayHistory},
+    log::*,
+};
+
+/// Result of a download speed test through a proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SpeedRecord {
+    #[serde(serialize_with = "delay::serialize_time")]
+    pub time: DateTime<FixedOffset>,
+    /// bytes downloaded
+    pub bytes: u64,
+    pub duration_ms: u64,
+    pub bytes_per_sec: u64,
+}
+
+impl SpeedRecord {
+    pub fn new(bytes: u64, duration: Duration) -> Self {
+        let bytes_per_sec = match duration.as_micros() {
+            0 => 0,
+            micros => (bytes as u128 * 1_000_000 / micros) as u64,
+        };
+        Self {
+            time: Local::now().fixed_offset(),
+            bytes,
+            duration_ms: duration.as_millis() as u64,
+            bytes_per_sec,
+        }
+    }
+}
+
+/// The measurements of a single proxy.
+#[derive(Debug, Default, Serialize)]
+pub struct ProxyStats {
+    pub history: DelayHistory,
+    speed: Mutex<Option<SpeedRecord>>,
+}
+
+impl ProxyStats {
+    /// Records a speed test, replacing the previous one.
+    pub fn record_speed(&self, bytes: u64, duration: Duration) -> SpeedRecord {
+        let record = SpeedRecord::new(bytes, duration);
+        *self.speed.lock().unwrap() = Some(record);
+        record
+    }
+
+    /// The latest speed test, `None` if the proxy wasn't tested yet.
+    pub fn speed(&self) -> Option<SpeedRecord> {
+        *self.speed.lock().unwrap()
+    }
+}
+
+/// The [`ProxyStats`] of all proxies, by name.
+#[derive(Debug, Default)]
+pub struct ProxyStatsMap {
+    proxies: RwLock<HashMap<String, Arc<ProxyStats>>>,
+}
+
+impl ProxyStatsMap {
+    /// Returns the stats of `name`, created on first use.
+    pub fn proxy(&self, name: &str) -> Arc<ProxyStats> {
+        if let Some(stats) = self.proxies.read().unwrap().get(name) {
+            return stats.clone();
+        }
+
+        self.proxies
+            .write()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<ProxyStats>> {
+        self.proxies.read().unwrap().get(name).cloned()
+    }
+
+    /// Restores the delay histories of `names` saved in the cache file, returns the number of
+    /// proxies restored. Proxies which were measured already are left alone.
+    pub fn warm_start<'a, I: IntoIterator<Item = &'a str>>(&self, names: I) -> usize {
+        let Some(cachefile) = CacheFile::instance() else {
+            return 0;
+        };
+        let mut restored = 0;
+        for name in names {
+            let stats = self.proxy(name);
+            match cachefile.get_delays(name) {
+                Some(records) if stats.history.last().is_none() => {
+                    stats.history.restore(records);
+                    restored += 1;
+                }
+                _ => {}
+            }
+        }
+        restored
+    }
+
+    /// Saves the delay history of `name` to the cache file, if it's open.
+    pub fn persist(&self, name: &str) {
+        let (Some(cachefile), Some(stats)) = (CacheFile::instance(), self.get(name)) else {
+            return;
+        };
+        if let Err(err) = cachefile.put_delays(name, &stats.history.history()) {
+            warn!("delay history of proxy {} not saved, {}", name, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_stats() {
+        let proxies = ProxyStatsMap::default();
+        assert!(proxies.get("mysocks5").is_none());
+
+        let stats = proxies.proxy("mysocks5");
+        assert_eq!(stats.speed(), None);
+        stats.history.record(Some(Duration::from_millis(80)));
+
+        let record = stats.record_speed(10 * 1024 * 1024, Duration::from_secs(4));
+        assert_eq!(record.bytes_per_sec, 2621440);
+        assert_eq!(SpeedRecord::new(1024, Duration::ZERO).bytes_per_sec, 0);
+
+        let stats = proxies.get("mysocks5").unwrap();
+        assert_eq!(stats.speed(), Some(record));
+        let json = serde_json::to_value(&*stats).unwrap();
+        assert_eq!(json["history"][0]["delay"], 80);
+        assert_eq!(json["speed"]["bytes_per_sec"], 2621440);
+    }
+
+    #[test]
+    fn test_warm_start() {
+        CacheFile::with_cache_dir(std::env::temp_dir().join("swiftlink").join("cachedb")).unwrap();
+
+        let before = ProxyStatsMap::default();
+        before
+            .proxy("warm-start-hk")
+            .history
+            .record(Some(Duration::from_millis(80)));
+        before.proxy("warm-start-jp").history.record(None);
+        before.persist("warm-start-hk");
+        before.persist("warm-start-jp");
+        // never measured, nothing saved
+        before.persist("warm-start-unknown");
+
+        let after = ProxyStatsMap::default();
+        after
+            .proxy("warm-start-jp")
+            .history
+            .record(Some(Duration::from_millis(50)));
+        let names = ["warm-start-hk", "warm-start-jp", "warm-start-unknown"];
+        assert_eq!(after.warm_start(names), 1);
+
+        let hk = after.get("warm-start-hk").unwrap().history.last().unwrap();
+        assert_eq!(hk.latency(), Some(Duration::from_millis(80)));
+        // measured since the restart
+        let jp = after.get("warm-start-jp").unwrap().history.history();
+        assert_eq!(jp.len(), 1);
+        assert!(jp[0].is_alive());
+        assert!(after.get("warm-start-unknown").unwrap().history.last().is_none());
+    }
+}
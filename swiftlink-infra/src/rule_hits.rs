@@ -0,0 +1,104 @@
+//! Match counters of the routing rules, kept for the lifetime of the process.
+//!
+//! Large rule lists are matched in order, every connection pays for the rules before the one
+//! which matches. The counters tell the rules which never match, candidates for removal, and the
+//! hot ones, candidates for moving up.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::connection::ClosedConnection;
+
+/// The matches of one rule.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleHit {
+    pub rule: String,
+    pub hits: u64,
+    /// unix time in milliseconds of the last match, `None` if it never matched
+    pub last_hit: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Counter {
+    hits: AtomicU64,
+    /// unix time in milliseconds, 0 if never
+    last_hit: AtomicU64,
+}
+
+/// The counters of the rules, in their matching order.
+#[derive(Debug, Default)]
+pub struct RuleHits {
+    rules: RwLock<Vec<(String, Arc<Counter>)>>,
+}
+
+impl RuleHits {
+    /// Sets the rules, e.g. after the rules were reloaded. Rules which are kept keep their counts.
+    pub fn set_rules<'a, I: IntoIterator<Item = &'a str>>(&self, rules: I) {
+        let mut current = self.rules.write().unwrap();
+        let mut previous: HashMap<_, _> = current.drain(..).collect();
+        *current = rules
+            .into_iter()
+            .map(|rule| {
+                let counter = previous.remove(rule).unwrap_or_default();
+                (rule.to_owned(), counter)
+            })
+            .collect();
+    }
+
+    /// Counts a match of `rule`, rules which aren't set are ignored.
+    pub fn hit(&self, rule: &str) {
+        let rules = self.rules.read().unwrap();
+        if let Some((_, counter)) = rules.iter().find(|(r, _)| r == rule) {
+            counter.hits.fetch_add(1, Ordering::Relaxed);
+            let now = ClosedConnection::unix_millis(SystemTime::now());
+            counter.last_hit.store(now, Ordering::Relaxed);
+        }
+    }
+
+    /// The counts of all rules, in their matching order.
+    pub fn snapshot(&self) -> Vec<RuleHit> {
+        self.rules
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(rule, counter)| RuleHit {
+                rule: rule.clone(),
+                hits: counter.hits.load(Ordering::Relaxed),
+                last_hit: Some(counter.last_hit.load(Ordering::Relaxed)).filter(|&time| time > 0),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_hits() {
+        let hits = RuleHits::default();
+        hits.set_rules(["www.example.com", "*.example.com", "*"]);
+        hits.hit("*.example.com");
+        hits.hit("*.example.com");
+        hits.hit("*.example.org");
+
+        let snapshot = hits.snapshot();
+        let counts: Vec<_> = snapshot.iter().map(|hit| (hit.rule.as_str(), hit.hits)).collect();
+        assert_eq!(counts, [("www.example.com", 0), ("*.example.com", 2), ("*", 0)]);
+        assert!(snapshot[0].last_hit.is_none());
+        assert!(snapshot[1].last_hit.is_some());
+
+        // reloaded, the counts of the kept rules survive
+        hits.set_rules(["*", "*.example.com"]);
+        let counts: Vec<_> = hits.snapshot().into_iter().map(|hit| (hit.rule, hit.hits)).collect();
+        assert_eq!(counts, [("*".to_owned(), 0), ("*.example.com".to_owned(), 2)]);
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    io::{self, Read, Write},
+    net::IpAddr,
+};
+
+use ipnet::IpNet;
+
+/// A set of IP CIDRs, stored as sorted and merged address ranges so a lookup is a binary search
+/// and the compiled form is two flat arrays.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IpCidrSet {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpCidrSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, net: IpNet) {
+        match net {
+            IpNet::V4(net) => self.v4.push((net.network().into(), net.broadcast().into())),
+            IpNet::V6(net) => self.v6.push((net.network().into(), net.broadcast().into())),
+        }
+    }
+
+    /// Sorts and merges the ranges, must be called after the last insert.
+    pub fn build(mut self) -> Self {
+        merge(&mut self.v4);
+        merge(&mut self.v6);
+        self
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => search(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => search(&self.v4, u32::from(ip)),
+                None => search(&self.v6, u128::from(ip)),
+            },
+        }
+    }
+
+    /// Returns the number of merged ranges.
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+
+    /// Writes `[v4 ranges: u32 le][start, end: u32 le]... [v6 ranges: u32 le][start, end: u128 le]...`.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&(self.v4.len() as u32).to_le_bytes())?;
+        for (start, end) in &self.v4 {
+            w.write_all(&start.to_le_bytes())?;
+            w.write_all(&end.to_le_bytes())?;
+        }
+        w.write_all(&(self.v6.len() as u32).to_le_bytes())?;
+        for (start, end) in &self.v6 {
+            w.write_all(&start.to_le_bytes())?;
+            w.write_all(&end.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Reads a set written by [`IpCidrSet::write_to`].
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut set = Self::new();
+
+        let mut buf = [0u8; 16];
+        r.read_exact(&mut buf[..4])?;
+        let n = u32::from_le_bytes(buf[..4].try_into().unwrap());
+        for _ in 0..n {
+            r.read_exact(&mut buf[..4])?;
+            let start = u32::from_le_bytes(buf[..4].try_into().unwrap());
+            r.read_exact(&mut buf[..4])?;
+            let end = u32::from_le_bytes(buf[..4].try_into().unwrap());
+            set.v4.push((start, end));
+        }
+
+        r.read_exact(&mut buf[..4])?;
+        let n = u32::from_le_bytes(buf[..4].try_into().unwrap());
+        for _ in 0..n {
+            r.read_exact(&mut buf)?;
+            let start = u128::from_le_bytes(buf);
+            r.read_exact(&mut buf)?;
+            let end = u128::from_le_bytes(buf);
+            set.v6.push((start, end));
+        }
+
+        if !is_sorted(&set.v4) || !is_sorted(&set.v6) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unsorted ip cidr ranges"));
+        }
+
+        Ok(set)
+    }
+}
+
+fn merge<T: Ord + Copy + num::CheckedNext>(ranges: &mut Vec<(T, T)>) {
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            // overlapping or adjacent
+            Some(last) if start <= last.1 || last.1.checked_next() == Some(start) => {
+                last.1 = last.1.max(end);
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+
+    *ranges = merged;
+}
+
+fn search<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    let idx = ranges.partition_point(|(start, _)| *start <= ip);
+    idx > 0 && ranges[idx - 1].1 >= ip
+}
+
+fn is_sorted<T: Ord>(ranges: &[(T, T)]) -> bool {
+    ranges.windows(2).all(|w| w[0].1 < w[1].0) && ranges.iter().all(|(start, end)| start <= end)
+}
+
+mod num {
+    pub trait CheckedNext: Sized {
+        fn checked_next(self) -> Option<Self>;
+    }
+
+    impl CheckedNext for u32 {
+        fn checked_next(self) -> Option<Self> {
+            self.checked_add(1)
+        }
+    }
+
+    impl CheckedNext for u128 {
+        fn checked_next(self) -> Option<Self> {
+            self.checked_add(1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(nets: &[&str]) -> IpCidrSet {
+        let mut set = IpCidrSet::new();
+        for net in nets {
+            set.insert(net.parse().unwrap());
+        }
+        set.build()
+    }
+
+    #[test]
+    fn test_ip_cidr_set_merge() {
+        let set = build(&[
+            "10.0.0.0/9",
+            "10.128.0.0/9",
+            "10.1.0.0/16",
+            "192.168.0.0/16",
+            "fc00::/7",
+        ]);
+
+        assert_eq!(set.len(), 3);
+        assert!(set.contains("10.200.1.1".parse().unwrap()));
+        assert!(set.contains("192.168.255.255".parse().unwrap()));
+        assert!(set.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(set.contains("fd00::1".parse().unwrap()));
+        assert!(!set.contains("11.0.0.0".parse().unwrap()));
+        assert!(!set.contains("9.255.255.255".parse().unwrap()));
+        assert!(!set.contains("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_ip_cidr_set_binary() {
+        let set = build(&["0.0.0.0/0", "2001:db8::/32"]);
+
+        let mut buf = vec![];
+        set.write_to(&mut buf).unwrap();
+        assert_eq!(IpCidrSet::read_from(&mut buf.as_slice()).unwrap(), set);
+        assert!(IpCidrSet::read_from(&mut &buf[..buf.len() - 1]).is_err());
+    }
+}
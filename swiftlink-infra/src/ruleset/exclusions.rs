@@ -0,0 +1,98 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use crate::trie::domain_trie::DomainTrie;
+
+use super::IpCidrSet;
+
+/// Destinations a proxy or group must never handle, e.g. the domain of the proxy server itself,
+/// which would loop back into the proxy.
+///
+/// Entries are domains (`proxy.example.com`, `+.example.com`, `*.example.com`), IP addresses or
+/// CIDRs. A rule whose target excludes the destination doesn't match, the next rule is tried.
+#[derive(Debug, Default)]
+pub struct Exclusions {
+    domains: DomainTrie<()>,
+    cidrs: IpCidrSet,
+    len: usize,
+}
+
+impl Exclusions {
+    /// Parses the entries, fails on the first invalid one.
+    pub fn parse<I, S>(entries: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut this = Self::default();
+        for entry in entries {
+            let entry = entry.as_ref().trim();
+            match entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+            {
+                Ok(net) => this.cidrs.insert(net),
+                Err(_) => this
+                    .domains
+                    .insert(entry.to_ascii_lowercase(), ())
+                    .map_err(|err| err.to_string())?,
+            }
+            this.len += 1;
+        }
+        this.cidrs = this.cidrs.build();
+        Ok(this)
+    }
+
+    /// Whether `host`, a domain or an IP address, is excluded.
+    pub fn excludes(&self, host: &str) -> bool {
+        match host.parse::<IpAddr>() {
+            Ok(ip) => self.excludes_ip(ip),
+            Err(_) => {
+                let host = host.trim_end_matches('.').to_ascii_lowercase();
+                self.domains.search(host).is_some()
+            }
+        }
+    }
+
+    #[inline]
+    pub fn excludes_ip(&self, ip: IpAddr) -> bool {
+        self.cidrs.contains(ip)
+    }
+
+    /// Number of entries.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exclusions() {
+        let exclusions =
+            Exclusions::parse(["proxy.example.com", "+.example.org", "203.0.113.0/24", "2001:db8::1"]).unwrap();
+        assert_eq!(exclusions.len(), 4);
+
+        assert!(exclusions.excludes("proxy.example.com"));
+        assert!(exclusions.excludes("Proxy.Example.com."));
+        assert!(!exclusions.excludes("www.example.com"));
+        assert!(exclusions.excludes("example.org"));
+        assert!(exclusions.excludes("cdn.example.org"));
+        assert!(exclusions.excludes("203.0.113.7"));
+        assert!(!exclusions.excludes("203.0.114.7"));
+        assert!(exclusions.excludes("2001:db8::1"));
+        assert!(exclusions.excludes_ip("::ffff:203.0.113.1".parse().unwrap()));
+
+        assert!(Exclusions::parse(["bad..domain"]).is_err());
+        assert!(Exclusions::parse(Vec::<String>::new()).unwrap().is_empty());
+    }
+}
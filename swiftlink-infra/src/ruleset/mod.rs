@@ -0,0 +1,294 @@
+//! Rule sets, either plain text lists or precompiled binary files.
+//!
+//! A text rule set has one domain (`google.com`, `+.google.com`, `*.example.com`) or one CIDR
+//! (`10.0.0.0/8`) per line, `#` starts a comment. Parsing hundreds of thousands of such lines is
+//! slow on low-end routers, so they can be compiled once into the binary layout:
+//!
+//! ```text
+//! [magic: "SLRS"][version: u8][behavior: u8][entries: u32 le][payload]
+//! ```
+//!
+//! where the payload is a [`DomainTrie::write_to`] or [`IpCidrSet::write_to`] dump.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::IpAddr,
+    path::Path,
+    str::FromStr,
+};
+
+use ipnet::IpNet;
+
+use crate::{log::*, trie::domain_trie::DomainTrie};
+
+pub use cidr::IpCidrSet;
+pub use exclusions::Exclusions;
+
+mod cidr;
+mod exclusions;
+
+const MAGIC: &[u8; 4] = b"SLRS";
+const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Behavior {
+    Domain,
+    IpCidr,
+}
+
+impl Behavior {
+    fn as_u8(self) -> u8 {
+        match self {
+            Behavior::Domain => 0,
+            Behavior::IpCidr => 1,
+        }
+    }
+}
+
+impl FromStr for Behavior {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "domain" => Ok(Behavior::Domain),
+            "ipcidr" => Ok(Behavior::IpCidr),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown rule set behavior {}, expect domain or ipcidr", s),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Behavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Behavior::Domain => "domain",
+            Behavior::IpCidr => "ipcidr",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum RuleSet {
+    Domain(DomainTrie<()>),
+    IpCidr(IpCidrSet),
+}
+
+impl RuleSet {
+    /// Loads a rule set file, binary files are detected by their header.
+    pub fn load<P: AsRef<Path>>(path: P, behavior: Behavior) -> io::Result<Self> {
+        let mut r = BufReader::new(File::open(path.as_ref())?);
+
+        let mut magic = [0u8; 4];
+        let n = read_full(&mut r, &mut magic)?;
+        let rule_set = if n == magic.len() && &magic == MAGIC {
+            Self::read_binary_body(&mut r)?
+        } else {
+            let mut text = String::from_utf8_lossy(&magic[..n]).into_owned();
+            r.read_to_string(&mut text)?;
+            Self::parse_text(&text, behavior)?
+        };
+
+        if rule_set.behavior() != behavior {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{:?} is a {} rule set, expect {}",
+                    path.as_ref(),
+                    rule_set.behavior(),
+                    behavior
+                ),
+            ));
+        }
+
+        Ok(rule_set)
+    }
+
+    /// Parses a text rule set, invalid lines are skipped with a warning.
+    pub fn parse_text(text: &str, behavior: Behavior) -> io::Result<Self> {
+        let lines = text
+            .lines()
+            .map(|l| l.split('#').next().unwrap_or_default().trim())
+            .filter(|l| !l.is_empty());
+
+        match behavior {
+            Behavior::Domain => {
+                let mut trie = DomainTrie::new();
+                for line in lines {
+                    if let Err(err) = trie.insert(line.to_owned(), ()) {
+                        warn!("ignore rule set line {}, {}", line, err);
+                    }
+                }
+                Ok(RuleSet::Domain(trie))
+            }
+            Behavior::IpCidr => {
+                let mut set = IpCidrSet::new();
+                for line in lines {
+                    match line
+                        .parse::<IpNet>()
+                        .or_else(|_| line.parse::<IpAddr>().map(IpNet::from))
+                    {
+                        Ok(net) => set.insert(net),
+                        Err(err) => warn!("ignore rule set line {}, {}", line, err),
+                    }
+                }
+                Ok(RuleSet::IpCidr(set.build()))
+            }
+        }
+    }
+
+    /// Reads a binary rule set written by [`RuleSet::write_binary`].
+    pub fn read_binary<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a binary rule set"));
+        }
+        Self::read_binary_body(r)
+    }
+
+    fn read_binary_body<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut header = [0u8; 6];
+        r.read_exact(&mut header)?;
+
+        if header[0] != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported rule set version {}", header[0]),
+            ));
+        }
+
+        let entries = u32::from_le_bytes(header[2..6].try_into().unwrap()) as usize;
+
+        let rule_set = match header[1] {
+            0 => RuleSet::Domain(DomainTrie::read_from(r)?),
+            1 => RuleSet::IpCidr(IpCidrSet::read_from(r)?),
+            b => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown rule set behavior {}", b),
+                ))
+            }
+        };
+
+        if rule_set.len() != entries {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "rule set entries mismatch"));
+        }
+
+        Ok(rule_set)
+    }
+
+    pub fn write_binary<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&[VERSION, self.behavior().as_u8()])?;
+        w.write_all(&(self.len() as u32).to_le_bytes())?;
+        match self {
+            RuleSet::Domain(trie) => trie.write_to(w),
+            RuleSet::IpCidr(set) => set.write_to(w),
+        }
+    }
+
+    pub fn behavior(&self) -> Behavior {
+        match self {
+            RuleSet::Domain(_) => Behavior::Domain,
+            RuleSet::IpCidr(_) => Behavior::IpCidr,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            RuleSet::Domain(trie) => trie.len(),
+            RuleSet::IpCidr(set) => set.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains_domain(&self, domain: &str) -> bool {
+        match self {
+            RuleSet::Domain(trie) => trie.search(domain.to_owned()).is_some(),
+            RuleSet::IpCidr(_) => false,
+        }
+    }
+
+    pub fn contains_ip(&self, ip: IpAddr) -> bool {
+        match self {
+            RuleSet::Domain(_) => false,
+            RuleSet::IpCidr(set) => set.contains(ip),
+        }
+    }
+}
+
+/// Compiles the text rule set `input` into the binary rule set `output`, returns its entries.
+pub fn convert<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q, behavior: Behavior) -> io::Result<usize> {
+    let rule_set = RuleSet::load(input, behavior)?;
+
+    let mut w = BufWriter::new(File::create(output)?);
+    rule_set.write_binary(&mut w)?;
+    w.flush()?;
+
+    Ok(rule_set.len())
+}
+
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..])? {
+            0 => break,
+            m => n += m,
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_set_domain() {
+        let text = "# comment\n+.google.com\n\n*.example.com # trailing\nlocalhost\n..invalid\n";
+        let rule_set = RuleSet::parse_text(text, Behavior::Domain).unwrap();
+
+        let mut buf = vec![];
+        rule_set.write_binary(&mut buf).unwrap();
+        let rule_set = RuleSet::read_binary(&mut buf.as_slice()).unwrap();
+
+        assert_eq!(rule_set.behavior(), Behavior::Domain);
+        assert!(rule_set.contains_domain("www.google.com"));
+        assert!(rule_set.contains_domain("google.com"));
+        assert!(rule_set.contains_domain("a.example.com"));
+        assert!(!rule_set.contains_domain("example.com"));
+        assert!(!rule_set.contains_ip("127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_rule_set_convert() {
+        let dir = std::env::temp_dir().join("swiftlink-ruleset-test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("lan.txt");
+        let output = dir.join("lan.srs");
+        std::fs::write(&input, "10.0.0.0/8\n192.168.0.0/16\n::1\n").unwrap();
+
+        assert_eq!(convert(&input, &output, Behavior::IpCidr).unwrap(), 3);
+
+        let rule_set = RuleSet::load(&output, Behavior::IpCidr).unwrap();
+        assert!(rule_set.contains_ip("10.1.2.3".parse().unwrap()));
+        assert!(rule_set.contains_ip("::1".parse().unwrap()));
+        assert!(!rule_set.contains_ip("8.8.8.8".parse().unwrap()));
+
+        let text = RuleSet::load(&input, Behavior::IpCidr).unwrap();
+        assert_eq!(text.len(), rule_set.len());
+
+        assert!(RuleSet::load(&output, Behavior::Domain).is_err());
+
+        let mut data = std::fs::read(&output).unwrap();
+        data[4] = VERSION + 1;
+        assert!(RuleSet::read_binary(&mut data.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,340 @@
+//! Landlock rules and seccomp filter.
+
+use std::{collections::BTreeMap, io, path::Path};
+
+use landlock::{
+    make_bitflags, Access, AccessFs, BitFlags, PathBeneath, PathFd, Ruleset, RulesetAttr, RulesetCreatedAttr,
+    RulesetStatus, ABI,
+};
+use seccompiler::{BpfProgram, SeccompAction, SeccompFilter, TargetArch};
+
+use super::{FsRules, SandboxMode};
+use crate::log::*;
+
+/// The newest Landlock version handled, older kernels get a best effort subset.
+const LANDLOCK_ABI: ABI = ABI::V3;
+
+impl FsRules {
+    fn rules(&self) -> impl Iterator<Item = (&Path, BitFlags<AccessFs>)> {
+        let read = make_bitflags!(AccessFs::{ReadFile | ReadDir});
+        let execute = make_bitflags!(AccessFs::{ReadFile | ReadDir | Execute});
+
+        self.read_write
+            .iter()
+            .map(|p| (p.as_path(), AccessFs::from_all(LANDLOCK_ABI)))
+            .chain(self.read_only.iter().map(move |p| (p.as_path(), read)))
+            .chain(self.execute.iter().map(move |p| (p.as_path(), execute)))
+    }
+}
+
+/// Whether the process inherited a seccomp filter, e.g. the successor of a sandboxed process
+/// started on an upgrade. The Landlock rules and the filter of the predecessor still apply to it,
+/// and they can't be set up again: the filter doesn't allow the syscalls for it.
+pub fn inherited() -> bool {
+    // 2 is SECCOMP_MODE_FILTER
+    unsafe { libc::prctl(libc::PR_GET_SECCOMP) == 2 }
+}
+
+/// Restricts file access of the calling thread, and the threads it spawns later, to `rules`.
+///
+/// Does nothing if the sandbox is [`inherited`].
+pub fn restrict_fs(rules: &FsRules, mode: SandboxMode) -> io::Result<()> {
+    if inherited() {
+        info!("sandbox inherited from the previous process, its file access rules apply");
+        return Ok(());
+    }
+    if mode == SandboxMode::Log {
+        for (path, access) in rules.rules() {
+            info!("sandbox would allow {:?} on {:?}", access, path);
+        }
+        warn!("sandbox in log mode, file access is not restricted");
+        return Ok(());
+    }
+
+    let mut ruleset = Ruleset::default()
+        .handle_access(AccessFs::from_all(LANDLOCK_ABI))
+        .and_then(|r| r.create())
+        .map_err(io::Error::other)?;
+
+    for (path, access) in rules.rules() {
+        // paths like /usr/share/zoneinfo don't exist everywhere
+        let fd = match PathFd::new(path) {
+            Ok(fd) => fd,
+            Err(err) => {
+                debug!("sandbox skips {:?}, {}", path, err);
+                continue;
+            }
+        };
+        ruleset = ruleset
+            .add_rule(PathBeneath::new(fd, access))
+            .map_err(io::Error::other)?;
+    }
+
+    let status = ruleset.restrict_self().map_err(io::Error::other)?;
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => info!("sandbox restricts file access"),
+        RulesetStatus::PartiallyEnforced => info!("sandbox restricts file access, partially supported by the kernel"),
+        RulesetStatus::NotEnforced => warn!("Landlock is not supported by the kernel, file access is not restricted"),
+    }
+
+    Ok(())
+}
+
+/// Restricts the syscalls of all threads of the process to networking and file IO.
+///
+/// Does nothing if the sandbox is [`inherited`].
+pub fn restrict_syscalls(mode: SandboxMode) -> io::Result<()> {
+    if inherited() {
+        info!("sandbox inherited from the previous process, its syscall filter applies");
+        return Ok(());
+    }
+    let filter = build_filter(mode)?;
+    seccompiler::apply_filter_all_threads(&filter).map_err(io::Error::other)?;
+
+    match mode {
+        SandboxMode::Enforce => info!("sandbox restricts syscalls"),
+        SandboxMode::Log => warn!("sandbox in log mode, forbidden syscalls are logged to the audit log"),
+    }
+    Ok(())
+}
+
+fn build_filter(mode: SandboxMode) -> io::Result<BpfProgram> {
+    let arch = TargetArch::try_from(std::env::consts::ARCH).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "seccomp sandbox is not supported on this arch",
+        )
+    })?;
+
+    let rules = ALLOWED_SYSCALLS
+        .iter()
+        .map(|&nr| (nr, vec![]))
+        .collect::<BTreeMap<_, _>>();
+    let violation = match mode {
+        SandboxMode::Enforce => SeccompAction::KillProcess,
+        SandboxMode::Log => SeccompAction::Log,
+    };
+
+    let filter = SeccompFilter::new(rules, violation, SeccompAction::Allow, arch).map_err(io::Error::other)?;
+    BpfProgram::try_from(filter).map_err(io::Error::other)
+}
+
+/// Syscalls of the async runtime, sockets, file IO of the cache and log files, and `execve` of
+/// the successor on upgrades.
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    // io
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_preadv,
+    libc::SYS_pwritev,
+    libc::SYS_close,
+    libc::SYS_lseek,
+    libc::SYS_ioctl,
+    libc::SYS_fcntl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_eventfd2,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    // files
+    libc::SYS_openat,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_getdents64,
+    libc::SYS_readlinkat,
+    libc::SYS_getcwd,
+    libc::SYS_mkdirat,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_ftruncate,
+    libc::SYS_fallocate,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_flock,
+    // memory
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_msync,
+    libc::SYS_mincore,
+    // sockets
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept4,
+    libc::SYS_connect,
+    libc::SYS_shutdown,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    // threads and time
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettimeofday,
+    libc::SYS_getrandom,
+    libc::SYS_getrusage,
+    libc::SYS_prlimit64,
+    libc::SYS_uname,
+    // process
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_execve,
+    libc::SYS_wait4,
+    libc::SYS_kill,
+    libc::SYS_tgkill,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_getppid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_prctl,
+    libc::SYS_set_tid_address,
+    libc::SYS_sigaltstack,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    // legacy variants still used by the libc on x86_64
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_access,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_dup2,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_arch_prctl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_getrlimit,
+];
+
+#[cfg(test)]
+mod tests {
+    use std::{env, os::unix::io::AsRawFd, process::Command};
+
+    use super::*;
+    use crate::handover::{self, Predecessor};
+
+    /// Set in the processes [`test_sandboxed_upgrade`] starts
+    const UPGRADE_TEST_ENV: &str = "SWIFTLINK_SANDBOX_UPGRADE_TEST";
+
+    #[test]
+    fn test_build_filter() {
+        let enforce = build_filter(SandboxMode::Enforce).unwrap();
+        let log = build_filter(SandboxMode::Log).unwrap();
+        assert!(enforce.len() > ALLOWED_SYSCALLS.len());
+        assert_ne!(
+            enforce.iter().map(|f| f.k).collect::<Vec<_>>(),
+            log.iter().map(|f| f.k).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_fs_rules() {
+        let rules = FsRules::system()
+            .read_write("/var/lib/swiftlink")
+            .read_only("/etc/swiftlink")
+            .execute("/usr/bin/swiftlink");
+        let access = |path: &str| {
+            rules
+                .rules()
+                .find(|(p, _)| *p == Path::new(path))
+                .map(|(_, access)| access)
+                .unwrap()
+        };
+
+        assert!(access("/var/lib/swiftlink").contains(AccessFs::WriteFile | AccessFs::MakeDir));
+        assert!(access("/etc/swiftlink").contains(AccessFs::ReadFile));
+        assert!(!access("/etc/swiftlink").intersects(AccessFs::WriteFile | AccessFs::Execute));
+        assert!(access("/usr/bin/swiftlink").contains(AccessFs::Execute));
+        assert!(!access("/usr/bin/swiftlink").contains(AccessFs::WriteFile));
+    }
+    /// Sandboxes a process, which hands a listener over to its successor like on an upgrade. The
+    /// successor sets up the sandbox again like any start, and must not be killed for it.
+    #[test]
+    fn test_sandboxed_upgrade() {
+        let exe = env::current_exe().unwrap();
+        let sandbox = || {
+            restrict_fs(&FsRules::system().execute(&exe), SandboxMode::Enforce).unwrap();
+            restrict_syscalls(SandboxMode::Enforce).unwrap();
+        };
+
+        if env::var_os(UPGRADE_TEST_ENV).is_none() {
+            let output = Command::new(&exe)
+                .args([
+                    "--exact",
+                    "sandbox::linux::tests::test_sandboxed_upgrade",
+                    "--nocapture",
+                ])
+                .env(UPGRADE_TEST_ENV, "1")
+                .output()
+                .unwrap();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(output.status.success(), "{}", stdout);
+            assert!(stdout.contains("successor took over 1 sockets"), "{}", stdout);
+            return;
+        }
+
+        match Predecessor::take_from_env().unwrap() {
+            // the successor, sandboxed by the filter it inherited
+            Some((predecessor, sockets)) => {
+                assert!(inherited());
+                sandbox();
+                predecessor.ready().unwrap();
+                println!("successor took over {} sockets", sockets.len());
+            }
+            None => {
+                assert!(!inherited());
+                let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+                sandbox();
+                assert!(inherited());
+                let mut successor = handover::spawn_successor(&[listener.as_raw_fd()]).unwrap();
+                assert!(successor.wait().unwrap().success());
+            }
+        }
+    }
+}
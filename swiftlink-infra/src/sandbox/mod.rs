@@ -0,0 +1,82 @@
+//! Landlock and seccomp sandbox of the process, Linux only.
+//!
+//! Landlock limits the files the process can open to the few directories it works in, seccomp
+//! limits the syscalls to what networking and file IO need. A remote code execution can then
+//! neither read the host's files nor, say, load a kernel module.
+//!
+//! Both are inherited by threads created afterwards. Landlock only restricts the calling thread,
+//! so [`restrict_fs`] must run before any other thread is spawned. The seccomp filter is
+//! synchronized to all threads, [`restrict_syscalls`] runs once startup is done and syscalls like
+//! `setuid` are not needed anymore.
+//!
+//! The successor started on an upgrade inherits both and keeps them, see `inherited`.
+//!
+//! Other platforms only get the configuration types.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(target_os = "linux")]
+mod linux;
+
+#[cfg(target_os = "linux")]
+pub use linux::*;
+
+/// What the sandbox does on a violation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SandboxMode {
+    /// Deny file access and kill the process on a forbidden syscall
+    #[default]
+    Enforce,
+    /// Log forbidden syscalls to the audit log (`dmesg`, `journalctl -k`) and allow them.
+    ///
+    /// Landlock can't log, file access is not restricted in this mode.
+    Log,
+}
+
+/// Paths the process may access once sandboxed, everything else is denied.
+#[derive(Debug, Clone, Default)]
+pub struct FsRules {
+    read_write: Vec<PathBuf>,
+    read_only: Vec<PathBuf>,
+    execute: Vec<PathBuf>,
+}
+
+impl FsRules {
+    /// Rules for the system files a process reads at runtime: `/etc` (resolv.conf, hosts, CA
+    /// certificates, time zone), `/proc/self`, the random and null devices, and the dynamic
+    /// loader and libraries to start the successor on upgrades.
+    pub fn system() -> Self {
+        Self::default()
+            .execute("/lib")
+            .execute("/lib64")
+            .execute("/usr/lib")
+            .execute("/usr/lib64")
+            .read_only("/etc")
+            .read_only("/usr/share/zoneinfo")
+            .read_only("/usr/share/ca-certificates")
+            .read_only("/proc/self")
+            .read_only("/sys/fs/cgroup")
+            .read_only("/sys/devices/system/cpu")
+            .read_only("/dev/urandom")
+            .read_write("/dev/null")
+    }
+
+    pub fn read_write<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.read_write.push(path.into());
+        self
+    }
+
+    pub fn read_only<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.read_only.push(path.into());
+        self
+    }
+
+    /// Allows executing `path`, e.g. the binary itself for upgrades, and reading it.
+    pub fn execute<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.execute.push(path.into());
+        self
+    }
+}
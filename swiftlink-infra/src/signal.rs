@@ -3,6 +3,13 @@ pub async fn shutdown() {
     imp::shutdown().await
 }
 
+/// Returns a `Future` that completes when an upgrade to a new binary is requested (`SIGUSR2`).
+///
+/// Never completes on platforms without signals.
+pub async fn upgrade() {
+    imp::upgrade().await
+}
+
 #[cfg(unix)]
 mod imp {
     use crate::log::info;
@@ -15,13 +22,18 @@ mod imp {
         };
     }
 
-    async fn sig(kind: SignalKind, name: &'static str) {
-        // Create a Future that completes the first
-        // time the process receives 'sig'.
-        signal(kind)
+    pub(super) async fn upgrade() {
+        signal(SignalKind::user_defined2())
             .expect("Failed to register signal handler")
             .recv()
             .await;
+        info!(target: "swiftlink::signal", "received SIGUSR2, starting upgrade");
+    }
+
+    async fn sig(kind: SignalKind, name: &'static str) {
+        // Create a Future that completes the first
+        // time the process receives 'sig'.
+        signal(kind).expect("Failed to register signal handler").recv().await;
         info!(
             // use target to remove 'imp' from output
             target: "swiftlink::signal",
@@ -35,6 +47,10 @@ mod imp {
 mod imp {
     use crate::log::info;
 
+    pub(super) async fn upgrade() {
+        std::future::pending().await
+    }
+
     pub(super) async fn shutdown() {
         // On Windows, we don't have all the signals, but Windows also
         // isn't our expected deployment target. This implementation allows
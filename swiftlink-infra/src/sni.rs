@@ -0,0 +1,208 @@
+//! Server name of a TLS ClientHello, for dispatching TLS connections without terminating them.
+
+/// Maximum length of a TLS record payload
+pub const MAX_RECORD_LEN: usize = 16384;
+
+/// Length of a TLS record header
+pub const RECORD_HEADER_LEN: usize = 5;
+
+/// Alert description of a server which can't complete the handshake for reasons unrelated to
+/// the client, e.g. overload
+pub const ALERT_INTERNAL_ERROR: u8 = 80;
+
+const CONTENT_TYPE_ALERT: u8 = 0x15;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const ALERT_LEVEL_FATAL: u8 = 2;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+#[derive(Debug, Clone, Copy, thiserror::Error, PartialEq, Eq)]
+pub enum SniError {
+    /// The first record isn't complete yet, read more
+    #[error("incomplete TLS record")]
+    Incomplete,
+    #[error("not a TLS handshake")]
+    NotTls,
+    #[error("malformed TLS ClientHello")]
+    Malformed,
+}
+
+/// Returns the server name of the ClientHello in the first TLS record of `buf`, `None` if the
+/// client didn't send one, e.g. when connecting to an IP address.
+///
+/// Only the first record is looked at, a ClientHello fragmented over several records is
+/// [`SniError::Malformed`].
+pub fn server_name(buf: &[u8]) -> Result<Option<&str>, SniError> {
+    if buf.len() < RECORD_HEADER_LEN {
+        // fail early on plain text protocols
+        if buf.first().is_some_and(|&t| t != CONTENT_TYPE_HANDSHAKE) {
+            return Err(SniError::NotTls);
+        }
+        return Err(SniError::Incomplete);
+    }
+    if buf[0] != CONTENT_TYPE_HANDSHAKE || buf[1] != 0x03 {
+        return Err(SniError::NotTls);
+    }
+    let record_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    if record_len > MAX_RECORD_LEN {
+        return Err(SniError::NotTls);
+    }
+    let record = buf
+        .get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + record_len)
+        .ok_or(SniError::Incomplete)?;
+
+    let mut reader = Reader(record);
+    if reader.u8()? != HANDSHAKE_TYPE_CLIENT_HELLO {
+        return Err(SniError::NotTls);
+    }
+    let len = reader.u24()?;
+    let mut hello = Reader(reader.bytes(len)?);
+    // version and random
+    hello.bytes(2 + 32)?;
+    // session id, cipher suites and compression methods
+    let len = hello.u8()? as usize;
+    hello.bytes(len)?;
+    let len = hello.u16()? as usize;
+    hello.bytes(len)?;
+    let len = hello.u8()? as usize;
+    hello.bytes(len)?;
+    if hello.0.is_empty() {
+        // no extensions
+        return Ok(None);
+    }
+
+    let len = hello.u16()? as usize;
+    let mut extensions = Reader(hello.bytes(len)?);
+    while !extensions.0.is_empty() {
+        let extension = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let data = extensions.bytes(len)?;
+        if extension != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = Reader(data);
+        let len = names.u16()? as usize;
+        let mut names = Reader(names.bytes(len)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let len = names.u16()? as usize;
+            let name = names.bytes(len)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return std::str::from_utf8(name).map(Some).map_err(|_| SniError::Malformed);
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// A fatal alert record, which a server may send in place of its first handshake message.
+///
+/// The record version is TLS 1.2, as clients of any version accept before the version is agreed.
+pub fn fatal_alert(description: u8) -> [u8; 7] {
+    [
+        CONTENT_TYPE_ALERT,
+        0x03,
+        0x03,
+        0x00,
+        0x02,
+        ALERT_LEVEL_FATAL,
+        description,
+    ]
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], SniError> {
+        if self.0.len() < len {
+            return Err(SniError::Malformed);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, SniError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SniError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Result<usize, SniError> {
+        let bytes = self.bytes(3)?;
+        Ok(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a minimal ClientHello record, with a server name extension if `name` is set.
+    fn client_hello(name: Option<&str>) -> Vec<u8> {
+        let mut extensions = vec![];
+        // supported versions, before the server name
+        extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        if let Some(name) = name {
+            let len = name.len() as u16;
+            extensions.extend_from_slice(&EXTENSION_SERVER_NAME.to_be_bytes());
+            extensions.extend_from_slice(&(len + 5).to_be_bytes());
+            extensions.extend_from_slice(&(len + 3).to_be_bytes());
+            extensions.push(NAME_TYPE_HOST_NAME);
+            extensions.extend_from_slice(&len.to_be_bytes());
+            extensions.extend_from_slice(name.as_bytes());
+        }
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0x5a; 32]);
+        // session id, one cipher suite, null compression
+        hello.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![HANDSHAKE_TYPE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_server_name() {
+        let hello = client_hello(Some("www.example.com"));
+        assert_eq!(server_name(&hello), Ok(Some("www.example.com")));
+        assert_eq!(server_name(&client_hello(None)), Ok(None));
+
+        // followed by the next record
+        let mut two = hello.clone();
+        two.extend_from_slice(&[0x14, 0x03, 0x03, 0x00, 0x01, 0x01]);
+        assert_eq!(server_name(&two), Ok(Some("www.example.com")));
+
+        assert_eq!(server_name(&hello[..3]), Err(SniError::Incomplete));
+        assert_eq!(server_name(&hello[..hello.len() - 1]), Err(SniError::Incomplete));
+        assert_eq!(server_name(b"GET / HTTP/1.1\r\n"), Err(SniError::NotTls));
+        assert_eq!(server_name(b"G"), Err(SniError::NotTls));
+
+        // the handshake claims more than the record holds
+        let mut truncated = hello.clone();
+        truncated[RECORD_HEADER_LEN + 3] += 1;
+        assert_eq!(server_name(&truncated), Err(SniError::Malformed));
+    }
+
+    #[test]
+    fn test_fatal_alert() {
+        assert_eq!(
+            fatal_alert(ALERT_INTERNAL_ERROR),
+            [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x50]
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! systemd socket activation.
+//!
+//! With a `.socket` unit systemd binds the (privileged) listen addresses itself and passes the
+//! sockets to swiftlink as file descriptors `3..3 + LISTEN_FDS`, see sd_listen_fds(3). The
+//! sockets outlive swiftlink restarts, so clients don't see refused connections in between.
+//!
+//! Passed sockets are matched to the configured listeners by their local address and type,
+//! listeners without a passed socket are bound as usual.
+
+use std::{
+    env,
+    net::SocketAddr,
+    os::unix::io::{FromRawFd, IntoRawFd},
+    sync::Mutex,
+};
+
+use once_cell::sync::Lazy;
+use socket2::{Socket, Type};
+
+use crate::log::*;
+
+/// The first passed file descriptor, `SD_LISTEN_FDS_START`
+const LISTEN_FDS_START: i32 = 3;
+
+static ACTIVATED: Lazy<ActivatedSockets> = Lazy::new(ActivatedSockets::from_env);
+
+/// Returns the sockets passed by systemd to this process.
+pub fn activated() -> &'static ActivatedSockets {
+    &ACTIVATED
+}
+
+/// Sockets passed by systemd, or by the previous process on an upgrade, and not claimed by a
+/// listener yet.
+#[derive(Debug, Default)]
+pub struct ActivatedSockets {
+    sockets: Mutex<Vec<Socket>>,
+}
+
+impl ActivatedSockets {
+    /// Takes the sockets announced by `LISTEN_PID` and `LISTEN_FDS`.
+    ///
+    /// The variables are removed, so child processes don't take the sockets as well.
+    fn from_env() -> Self {
+        let count = listen_fds(
+            env::var("LISTEN_PID").ok().as_deref(),
+            env::var("LISTEN_FDS").ok().as_deref(),
+            std::process::id(),
+        );
+
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+
+        if count > 0 {
+            info!("received {} sockets from systemd", count);
+        }
+
+        let sockets = (LISTEN_FDS_START..LISTEN_FDS_START + count as i32)
+            .map(|fd| unsafe { Socket::from_raw_fd(fd) })
+            .filter_map(|socket| match socket.set_cloexec(true) {
+                Ok(_) => Some(socket),
+                Err(err) => {
+                    warn!("ignore invalid socket passed by systemd, {}", err);
+                    // not ours to close
+                    let _ = socket.into_raw_fd();
+                    None
+                }
+            })
+            .collect();
+
+        Self::from_sockets(sockets)
+    }
+
+    pub fn from_sockets(sockets: Vec<Socket>) -> Self {
+        Self {
+            sockets: Mutex::new(sockets),
+        }
+    }
+
+    /// Adds sockets received otherwise, e.g. from [`crate::handover::Predecessor`].
+    pub fn adopt(&self, sockets: Vec<Socket>) {
+        self.sockets.lock().unwrap().extend(sockets);
+    }
+
+    /// Claims the passed UDP socket bound to `addr`.
+    pub fn take_udp(&self, addr: SocketAddr) -> Option<std::net::UdpSocket> {
+        self.take(addr, Type::DGRAM).map(Into::into)
+    }
+
+    /// Claims the passed TCP listener bound to `addr`.
+    pub fn take_tcp(&self, addr: SocketAddr) -> Option<std::net::TcpListener> {
+        self.take(addr, Type::STREAM).map(Into::into)
+    }
+
+    fn take(&self, addr: SocketAddr, ty: Type) -> Option<Socket> {
+        let mut sockets = self.sockets.lock().unwrap();
+        let index = sockets.iter().position(|socket| {
+            socket.r#type().ok() == Some(ty) && socket.local_addr().ok().and_then(|a| a.as_socket()) == Some(addr)
+        })?;
+        Some(sockets.swap_remove(index))
+    }
+
+    /// Addresses of the sockets no listener claimed.
+    pub fn unclaimed(&self) -> Vec<SocketAddr> {
+        self.sockets
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|socket| socket.local_addr().ok().and_then(|a| a.as_socket()))
+            .collect()
+    }
+}
+
+/// Number of passed sockets, 0 if they are meant for another process.
+fn listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> usize {
+    if listen_pid.and_then(|p| p.parse::<u32>().ok()) != Some(pid) {
+        return 0;
+    }
+
+    listen_fds.and_then(|n| n.parse().ok()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42), 2);
+        assert_eq!(listen_fds(Some("41"), Some("2"), 42), 0);
+        assert_eq!(listen_fds(None, Some("2"), 42), 0);
+        assert_eq!(listen_fds(Some("42"), None, 42), 0);
+        assert_eq!(listen_fds(Some("42"), Some("x"), 42), 0);
+    }
+
+    #[test]
+    fn test_activated_sockets_take() {
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let udp_addr = udp.local_addr().unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+
+        let activated = ActivatedSockets::from_sockets(vec![udp.into(), tcp.into()]);
+
+        // wrong type
+        assert!(activated.take_tcp(udp_addr).is_none());
+
+        let udp = activated.take_udp(udp_addr).unwrap();
+        assert_eq!(udp.local_addr().unwrap(), udp_addr);
+        assert!(activated.take_udp(udp_addr).is_none());
+
+        assert_eq!(activated.unclaimed(), vec![tcp_addr]);
+        assert!(activated.take_tcp(tcp_addr).is_some());
+        assert!(activated.unclaimed().is_empty());
+    }
+}
@@ -0,0 +1,315 @@
+//! Traffic per destination host and per client, what the bandwidth goes to.
+//!
+//! Closed connections are counted in one minute buckets of the last hour, a query sums the
+//! buckets of its window. A connection counts in the minute it closed, with all of its bytes, so
+//! long-lived connections show up late.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    clock::{self, Instant},
+    connection::ClosedConnection,
+    event::{Event, EventBus},
+    log::*,
+};
+
+const BUCKET_SECS: u64 = 60;
+
+/// Longest window of a query, older buckets are dropped.
+pub const MAX_WINDOW: Duration = Duration::from_secs(3600);
+
+/// Distinct destinations or clients a bucket counts, the further ones are counted as [`OTHERS`].
+const MAX_KEYS: usize = 10_000;
+
+/// Key of the traffic past [`MAX_KEYS`].
+pub const OTHERS: &str = "(others)";
+
+/// The traffic of a destination host or a client.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Talker {
+    /// the host of the destination or the address of the client
+    pub key: String,
+    pub connections: u64,
+    /// bytes sent by the clients
+    pub upload: u64,
+    /// bytes received by the clients
+    pub download: u64,
+}
+
+impl Talker {
+    #[inline]
+    pub fn bytes(&self) -> u64 {
+        self.upload + self.download
+    }
+}
+
+/// The talkers with the most traffic in a window, most first.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopTalkers {
+    pub window_secs: u64,
+    pub destinations: Vec<Talker>,
+    pub sources: Vec<Talker>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Usage {
+    connections: u64,
+    upload: u64,
+    download: u64,
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.connections += other.connections;
+        self.upload += other.upload;
+        self.download += other.download;
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    minute: u64,
+    destinations: HashMap<String, Usage>,
+    sources: HashMap<String, Usage>,
+}
+
+/// The traffic of the last hour.
+#[derive(Debug)]
+pub struct TalkerStats {
+    started: Instant,
+    /// oldest first
+    buckets: Mutex<VecDeque<Bucket>>,
+}
+
+impl Default for TalkerStats {
+    fn default() -> Self {
+        Self {
+            started: clock::now(),
+            buckets: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+impl TalkerStats {
+    /// Counts `conn` in the current minute.
+    pub fn record(&self, conn: &ClosedConnection) {
+        let usage = Usage {
+            connections: 1,
+            upload: conn.upload,
+            download: conn.download,
+        };
+        let minute = self.minute();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.back().is_none_or(|bucket| bucket.minute != minute) {
+            let minutes = MAX_WINDOW.as_secs() / BUCKET_SECS;
+            buckets.retain(|bucket| bucket.minute + minutes > minute);
+            buckets.push_back(Bucket {
+                minute,
+                destinations: HashMap::new(),
+                sources: HashMap::new(),
+            });
+        }
+        let bucket = buckets.back_mut().unwrap();
+        count(&mut bucket.destinations, host(&conn.destination), &usage);
+        count(&mut bucket.sources, &source(conn.source.ip()), &usage);
+    }
+
+    /// The `limit` destinations and clients with the most traffic in the last `window`, which is
+    /// rounded up to whole minutes and at most [`MAX_WINDOW`].
+    pub fn top(&self, window: Duration, limit: usize) -> TopTalkers {
+        let minutes = window
+            .as_secs()
+            .div_ceil(BUCKET_SECS)
+            .clamp(1, MAX_WINDOW.as_secs() / BUCKET_SECS);
+        let oldest = (self.minute() + 1).saturating_sub(minutes);
+
+        let mut destinations = HashMap::new();
+        let mut sources = HashMap::new();
+        let buckets = self.buckets.lock().unwrap();
+        for bucket in buckets.iter().filter(|bucket| bucket.minute >= oldest) {
+            for (key, usage) in bucket.destinations.iter() {
+                destinations
+                    .entry(key.as_str())
+                    .or_insert_with(Usage::default)
+                    .add(usage);
+            }
+            for (key, usage) in bucket.sources.iter() {
+                sources.entry(key.as_str()).or_insert_with(Usage::default).add(usage);
+            }
+        }
+
+        TopTalkers {
+            window_secs: minutes * BUCKET_SECS,
+            destinations: ranked(destinations, limit),
+            sources: ranked(sources, limit),
+        }
+    }
+
+    /// Records the connections closed on `events` until the bus is dropped.
+    pub fn spawn_collector(self: &Arc<Self>, events: &EventBus) -> tokio::task::JoinHandle<()> {
+        let stats = self.clone();
+        let mut rx = events.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(Event::ConnectionClosed(conn)) => stats.record(&conn),
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => debug!("traffic stats missed {} events", missed),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+
+    fn minute(&self) -> u64 {
+        (clock::now() - self.started).as_secs() / BUCKET_SECS
+    }
+}
+
+fn count(usages: &mut HashMap<String, Usage>, key: &str, usage: &Usage) {
+    let key = if usages.len() < MAX_KEYS || usages.contains_key(key) {
+        key
+    } else {
+        OTHERS
+    };
+    match usages.get_mut(key) {
+        Some(counted) => counted.add(usage),
+        None => {
+            usages.insert(key.to_owned(), *usage);
+        }
+    }
+}
+
+/// The host of `destination`, `host:port`.
+fn host(destination: &str) -> &str {
+    match destination.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host.trim_start_matches('[').trim_end_matches(']'),
+        _ => destination,
+    }
+}
+
+fn source(ip: IpAddr) -> String {
+    ip.to_canonical().to_string()
+}
+
+fn ranked(usages: HashMap<&str, Usage>, limit: usize) -> Vec<Talker> {
+    let mut talkers: Vec<_> = usages
+        .into_iter()
+        .map(|(key, usage)| Talker {
+            key: key.to_owned(),
+            connections: usage.connections,
+            upload: usage.upload,
+            download: usage.download,
+        })
+        .collect();
+    talkers.sort_by(|a, b| {
+        (b.bytes(), b.connections)
+            .cmp(&(a.bytes(), a.connections))
+            .then_with(|| a.key.cmp(&b.key))
+    });
+    talkers.truncate(limit);
+    talkers
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connection::CloseReason;
+
+    use super::*;
+
+    fn closed(source: &str, destination: &str, upload: u64, download: u64) -> ClosedConnection {
+        ClosedConnection {
+            id: 1,
+            network: "tcp",
+            inbound: "socks".to_owned(),
+            source: source.parse().unwrap(),
+            destination: destination.to_owned(),
+            rule: None,
+            outbound: Some("direct".to_owned()),
+            dialed: None,
+            upload,
+            download,
+            started_at: 0,
+            duration_ms: 0,
+            reason: CloseReason::ClientEof,
+            error: None,
+        }
+    }
+
+    fn keys(talkers: &[Talker]) -> Vec<(&str, u64, u64)> {
+        talkers
+            .iter()
+            .map(|talker| (talker.key.as_str(), talker.connections, talker.bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn test_host() {
+        assert_eq!(host("www.example.com:443"), "www.example.com");
+        assert_eq!(host("[2001:db8::1]:443"), "2001:db8::1");
+        assert_eq!(host("www.example.com"), "www.example.com");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_top_talkers() {
+        let stats = TalkerStats::default();
+        stats.record(&closed("192.168.1.2:50000", "video.example.com:443", 100, 10_000));
+        stats.record(&closed("[::ffff:192.168.1.2]:50001", "www.example.com:443", 50, 500));
+        stats.record(&closed("192.168.1.3:50000", "www.example.com:80", 10, 1000));
+
+        tokio::time::advance(Duration::from_secs(5 * 60)).await;
+        stats.record(&closed("192.168.1.3:50001", "www.example.com:443", 10, 100));
+
+        let top = stats.top(Duration::from_secs(600), 10);
+        assert_eq!(top.window_secs, 600);
+        assert_eq!(
+            keys(&top.destinations),
+            [("video.example.com", 1, 10_100), ("www.example.com", 3, 1670)]
+        );
+        assert_eq!(
+            keys(&top.sources),
+            [("192.168.1.2", 2, 10_650), ("192.168.1.3", 2, 1120)]
+        );
+
+        // the last minute only, limited
+        let top = stats.top(Duration::from_secs(30), 1);
+        assert_eq!(top.window_secs, 60);
+        assert_eq!(keys(&top.destinations), [("www.example.com", 1, 110)]);
+
+        // out of the longest window
+        tokio::time::advance(MAX_WINDOW).await;
+        stats.record(&closed("192.168.1.4:50000", "www.example.org:443", 1, 1));
+        let top = stats.top(Duration::from_secs(86400), 10);
+        assert_eq!(top.window_secs, MAX_WINDOW.as_secs());
+        assert_eq!(keys(&top.sources), [("192.168.1.4", 1, 2)]);
+        assert_eq!(stats.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_collector() {
+        let events = EventBus::default();
+        let stats = Arc::new(TalkerStats::default());
+        let task = stats.spawn_collector(&events);
+        events.publish(Event::ConfigReloaded);
+        events.publish(Event::ConnectionClosed(Arc::new(closed(
+            "127.0.0.1:50000",
+            "www.example.com:443",
+            1,
+            2,
+        ))));
+        drop(events);
+        task.await.unwrap();
+        assert_eq!(
+            keys(&stats.top(MAX_WINDOW, 10).destinations),
+            [("www.example.com", 1, 3)]
+        );
+    }
+}
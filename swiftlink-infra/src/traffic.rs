@@ -0,0 +1,143 @@
+//! Per-user traffic counters of multi-user server inbounds.
+//!
+//! An inbound resolves the authenticated user once per connection with [`UserTraffic::user`] and
+//! keeps the returned counters for the lifetime of the connection. Inbounds also count all their
+//! traffic in [`total`].
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+static TOTAL: TrafficCounter = TrafficCounter::new();
+
+/// The traffic of all inbounds since the start.
+#[inline]
+pub fn total() -> &'static TrafficCounter {
+    &TOTAL
+}
+
+#[derive(Debug, Default)]
+pub struct UserTraffic {
+    users: RwLock<HashMap<String, Arc<TrafficCounter>>>,
+}
+
+/// Traffic of a single user, shared by all its connections.
+#[derive(Debug, Default)]
+pub struct TrafficCounter {
+    upload: AtomicU64,
+    download: AtomicU64,
+    connections: AtomicU64,
+}
+
+/// Snapshot of a [`TrafficCounter`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    /// bytes sent by the user
+    pub upload: u64,
+    /// bytes received by the user
+    pub download: u64,
+    /// connections accepted so far
+    pub connections: u64,
+}
+
+impl UserTraffic {
+    /// Returns the counters of `user`, created on first use.
+    pub fn user(&self, user: &str) -> Arc<TrafficCounter> {
+        if let Some(counter) = self.users.read().unwrap().get(user) {
+            return counter.clone();
+        }
+
+        self.users.write().unwrap().entry(user.to_owned()).or_default().clone()
+    }
+
+    pub fn stats(&self, user: &str) -> Option<TrafficStats> {
+        self.users.read().unwrap().get(user).map(|c| c.stats())
+    }
+
+    /// Snapshot of every user, sorted by name.
+    pub fn snapshot(&self) -> Vec<(String, TrafficStats)> {
+        let mut users = self
+            .users
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(user, counter)| (user.clone(), counter.stats()))
+            .collect::<Vec<_>>();
+        users.sort_by(|a, b| a.0.cmp(&b.0));
+        users
+    }
+}
+
+impl TrafficCounter {
+    pub const fn new() -> Self {
+        Self {
+            upload: AtomicU64::new(0),
+            download: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn connected(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn add_upload(&self, n: u64) {
+        self.upload.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn add_download(&self, n: u64) {
+        self.download.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> TrafficStats {
+        TrafficStats {
+            upload: self.upload.load(Ordering::Relaxed),
+            download: self.download.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_traffic() {
+        let traffic = UserTraffic::default();
+
+        let alice = traffic.user("alice");
+        alice.connected();
+        alice.add_upload(100);
+        alice.add_download(1000);
+
+        let alice2 = traffic.user("alice");
+        alice2.connected();
+        alice2.add_upload(1);
+
+        traffic.user("bob").connected();
+
+        assert_eq!(
+            traffic.stats("alice"),
+            Some(TrafficStats {
+                upload: 101,
+                download: 1000,
+                connections: 2,
+            })
+        );
+        assert_eq!(traffic.stats("carol"), None);
+
+        let snapshot = traffic.snapshot();
+        assert_eq!(
+            snapshot.iter().map(|(u, _)| u.as_str()).collect::<Vec<_>>(),
+            vec!["alice", "bob"]
+        );
+    }
+}
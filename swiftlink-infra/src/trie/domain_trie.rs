@@ -1,3 +1,5 @@
+use std::io::{self, Read, Write};
+
 use super::TrieNode;
 use consts::*;
 
@@ -35,9 +37,7 @@ where
     T: Clone,
 {
     pub fn new() -> Self {
-        DomainTrie {
-            root: TrieNode::new(),
-        }
+        DomainTrie { root: TrieNode::new() }
     }
 
     /// adds a node to the domain trie.
@@ -64,10 +64,7 @@ where
         let mut current_node = &mut self.root;
 
         for part in parts.iter().rev() {
-            let next_node = current_node
-                .children
-                .entry(part.to_owned())
-                .or_insert(TrieNode::new());
+            let next_node = current_node.children.entry(part.to_owned()).or_insert(TrieNode::new());
             current_node = next_node;
         }
 
@@ -102,9 +99,85 @@ where
             }
         }
 
-        node.children
-            .get(DOT_WILDCARD)
-            .and_then(|node| node.data.clone())
+        node.children.get(DOT_WILDCARD).and_then(|node| node.data.clone())
+    }
+}
+
+/// Deepest label chain accepted when reading a compiled trie.
+const MAX_DEPTH: usize = 128;
+
+impl DomainTrie<()> {
+    /// Returns the number of domains (including the implicit `+.` parents) in the trie.
+    pub fn len(&self) -> usize {
+        fn count(node: &TrieNode<()>) -> usize {
+            node.data.is_some() as usize + node.children.values().map(count).sum::<usize>()
+        }
+        count(&self.root)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.children.is_empty()
+    }
+
+    /// Writes the trie nodes in pre-order, children sorted by label:
+    /// `[has data: u8][children: u32 le]` followed by `[label len: u8][label][child node]` each.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        fn write_node<W: Write>(node: &TrieNode<()>, w: &mut W) -> io::Result<()> {
+            w.write_all(&[node.data.is_some() as u8])?;
+            w.write_all(&(node.children.len() as u32).to_le_bytes())?;
+
+            let mut children = node.children.iter().collect::<Vec<_>>();
+            children.sort_unstable_by(|a, b| a.0.cmp(b.0));
+            for (label, child) in children {
+                let len = u8::try_from(label.len())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "domain label too long"))?;
+                w.write_all(&[len])?;
+                w.write_all(label.as_bytes())?;
+                write_node(child, w)?;
+            }
+            Ok(())
+        }
+
+        write_node(&self.root, w)
+    }
+
+    /// Reads a trie written by [`DomainTrie::write_to`].
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        fn invalid(msg: &str) -> io::Error {
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        }
+
+        fn read_node<R: Read>(r: &mut R, depth: usize) -> io::Result<TrieNode<()>> {
+            if depth > MAX_DEPTH {
+                return Err(invalid("domain trie too deep"));
+            }
+
+            let mut node = TrieNode::new();
+
+            let mut flag = [0u8; 1];
+            r.read_exact(&mut flag)?;
+            node.data = match flag[0] {
+                0 => None,
+                1 => Some(()),
+                _ => return Err(invalid("invalid domain trie node")),
+            };
+
+            let mut children = [0u8; 4];
+            r.read_exact(&mut children)?;
+            for _ in 0..u32::from_le_bytes(children) {
+                let mut len = [0u8; 1];
+                r.read_exact(&mut len)?;
+                let mut label = vec![0u8; len[0] as usize];
+                r.read_exact(&mut label)?;
+                let label = String::from_utf8(label).map_err(|_| invalid("invalid domain label"))?;
+                let child = read_node(r, depth + 1)?;
+                node.children.insert(label, child);
+            }
+
+            Ok(node)
+        }
+
+        Ok(DomainTrie { root: read_node(r, 0)? })
     }
 }
 
@@ -206,14 +279,8 @@ mod tests {
         let domains = vec![
             (".dev", "0.0.0.1".parse::<Ipv4Addr>().unwrap().into()),
             ("example.dev", "0.0.0.2".parse::<Ipv4Addr>().unwrap().into()),
-            (
-                "*.example.dev",
-                "0.0.0.3".parse::<Ipv4Addr>().unwrap().into(),
-            ),
-            (
-                "test.example.dev",
-                "0.0.0.4".parse::<Ipv4Addr>().unwrap().into(),
-            ),
+            ("*.example.dev", "0.0.0.3".parse::<Ipv4Addr>().unwrap().into()),
+            ("test.example.dev", "0.0.0.4".parse::<Ipv4Addr>().unwrap().into()),
         ];
 
         for (dn, val) in domains {
@@ -226,10 +293,7 @@ mod tests {
             assert_eq!(data.unwrap(), want);
         };
 
-        assert_fn(
-            String::from("test.dev"),
-            "0.0.0.1".parse::<Ipv4Addr>().unwrap().into(),
-        );
+        assert_fn(String::from("test.dev"), "0.0.0.1".parse::<Ipv4Addr>().unwrap().into());
         assert_fn(
             String::from("foo.bar.dev"),
             "0.0.0.1".parse::<Ipv4Addr>().unwrap().into(),
@@ -248,6 +312,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_domain_trie_binary() {
+        let mut tree = DomainTrie::<()>::new();
+        for dn in ["+.google.com", "*.example.com", ".org", "localhost"] {
+            assert_eq!(tree.insert(dn.to_string(), ()), Ok(()));
+        }
+        assert_eq!(tree.len(), 5);
+
+        let mut buf = vec![];
+        tree.write_to(&mut buf).unwrap();
+        let tree = DomainTrie::<()>::read_from(&mut buf.as_slice()).unwrap();
+
+        assert_eq!(tree.len(), 5);
+        assert_ne!(tree.search(String::from("google.com")), None);
+        assert_ne!(tree.search(String::from("www.google.com")), None);
+        assert_ne!(tree.search(String::from("www.example.com")), None);
+        assert_ne!(tree.search(String::from("test.org")), None);
+        assert_ne!(tree.search(String::from("localhost")), None);
+        assert_eq!(tree.search(String::from("example.com")), None);
+
+        assert!(DomainTrie::<()>::read_from(&mut &buf[..buf.len() - 1]).is_err());
+    }
+
     #[test]
     fn test_domain_trie_boundary() {
         let localhost: IpAddr = Ipv4Addr::LOCALHOST.into();
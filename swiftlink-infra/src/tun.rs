@@ -0,0 +1,164 @@
+//! TUN devices, the IP packets the system routes to an interface of swiftlink.
+//!
+//! Creating the interface requires `CAP_NET_ADMIN`. It is brought up with its IPv4 address, routes
+//! to it are left to the system configuration.
+
+use std::{
+    ffi::CString,
+    io, mem,
+    net::{Ipv4Addr, SocketAddrV4},
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use ipnet::Ipv4Net;
+use tokio::io::unix::AsyncFd;
+
+/// A TUN interface, reading and writing IP packets without packet information headers.
+#[derive(Debug)]
+pub struct Tun {
+    fd: AsyncFd<OwnedFd>,
+    name: String,
+}
+
+impl Tun {
+    /// Creates the interface `name`, or attaches to it if it exists and is persistent, assigns
+    /// `address` to it and brings it up.
+    pub fn open(name: &str, address: Ipv4Net, mtu: u16) -> io::Result<Tun> {
+        let name = CString::new(name).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        if name.as_bytes().len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "interface name too long"));
+        }
+
+        let fd = unsafe {
+            libc::open(
+                c"/dev/net/tun".as_ptr(),
+                libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut req = ifreq(&name);
+        req.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+        if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETIFF as _, &mut req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // the kernel may have completed a name pattern, e.g. `tun%d`
+        let name = unsafe { std::ffi::CStr::from_ptr(req.ifr_name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+
+        configure(&name, address, mtu)?;
+        Ok(Tun {
+            fd: AsyncFd::new(fd)?,
+            name,
+        })
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Receives a packet, `buf` should hold at least the MTU.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let read = guard.try_io(|fd| {
+                let n = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr() as *mut _, buf.len()) };
+                if n < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(n as usize)
+            });
+            if let Ok(result) = read {
+                return result;
+            }
+        }
+    }
+
+    /// Sends a packet, it's written whole or not at all.
+    pub async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        loop {
+            let mut guard = self.fd.writable().await?;
+            let written = guard.try_io(|fd| {
+                let n = unsafe { libc::write(fd.as_raw_fd(), packet.as_ptr() as *const _, packet.len()) };
+                if n < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+            if let Ok(result) = written {
+                return result;
+            }
+        }
+    }
+}
+
+fn ifreq(name: &std::ffi::CStr) -> libc::ifreq {
+    let mut req: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, src) in req.ifr_name.iter_mut().zip(name.to_bytes()) {
+        *dst = *src as libc::c_char;
+    }
+    req
+}
+
+fn sockaddr(ip: Ipv4Addr) -> libc::sockaddr {
+    let addr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr {
+            s_addr: u32::from_ne_bytes(ip.octets()),
+        },
+        sin_zero: [0; 8],
+    };
+    unsafe { mem::transmute::<libc::sockaddr_in, libc::sockaddr>(addr) }
+}
+
+/// Assigns the address and the MTU of the interface `name` and brings it up.
+fn configure(name: &str, address: Ipv4Net, mtu: u16) -> io::Result<()> {
+    let socket = std::net::UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+    let name = CString::new(name).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let ioctl = |request: libc::c_ulong, req: &mut libc::ifreq| {
+        if unsafe { libc::ioctl(socket.as_raw_fd(), request as _, req as *mut libc::ifreq) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    };
+
+    let mut req = ifreq(&name);
+    req.ifr_ifru.ifru_addr = sockaddr(address.addr());
+    ioctl(libc::SIOCSIFADDR, &mut req)?;
+    let mut req = ifreq(&name);
+    req.ifr_ifru.ifru_netmask = sockaddr(address.netmask());
+    ioctl(libc::SIOCSIFNETMASK, &mut req)?;
+    let mut req = ifreq(&name);
+    req.ifr_ifru.ifru_mtu = mtu as libc::c_int;
+    ioctl(libc::SIOCSIFMTU, &mut req)?;
+
+    let mut req = ifreq(&name);
+    ioctl(libc::SIOCGIFFLAGS, &mut req)?;
+    unsafe { req.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short };
+    ioctl(libc::SIOCSIFFLAGS, &mut req)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sockaddr() {
+        let addr = sockaddr(Ipv4Addr::new(198, 18, 0, 1));
+        let addr = unsafe { mem::transmute::<libc::sockaddr, libc::sockaddr_in>(addr) };
+        assert_eq!(addr.sin_family, libc::AF_INET as libc::sa_family_t);
+        assert_eq!(addr.sin_addr.s_addr.to_ne_bytes(), [198, 18, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_open_invalid_name() {
+        let err = Tun::open("a-name-much-too-long", "198.18.0.1/16".parse().unwrap(), 1500).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
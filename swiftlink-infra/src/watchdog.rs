@@ -0,0 +1,388 @@
+//! Resource watchdog.
+//!
+//! Periodically samples the open file descriptors, the tracked connections and the resident
+//! memory of the process. While any of them is above its threshold the watchdog reports itself
+//! as overloaded, servers should then refuse new work instead of running into `EMFILE` or the
+//! OOM killer.
+
+use std::{
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        RwLock,
+    },
+    time::Duration,
+};
+
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::log::*;
+
+static WATCHDOG: OnceCell<Watchdog> = OnceCell::new();
+
+/// Installs the process wide watchdog.
+///
+/// Installed before, e.g. by another instance, the watchdog keeps sampling at its interval and
+/// counting the connections, `thresholds` replace its thresholds. Must be called inside a tokio
+/// runtime, the sampling task is spawned on it.
+pub fn init(thresholds: Thresholds, interval: Duration) {
+    if let Some(watchdog) = watchdog() {
+        watchdog.set_thresholds(thresholds);
+        return;
+    }
+    if WATCHDOG.set(Watchdog::new(thresholds)).is_err() {
+        // installed concurrently
+        return init(thresholds, interval);
+    }
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            if let Some(watchdog) = watchdog() {
+                watchdog.sample();
+            }
+        }
+    });
+}
+
+/// Returns the process wide watchdog, if any.
+pub fn watchdog() -> Option<&'static Watchdog> {
+    WATCHDOG.get()
+}
+
+/// Returns `true` if the process wide watchdog is installed and overloaded.
+#[inline]
+pub fn is_overloaded() -> bool {
+    watchdog().map(|w| w.is_overloaded()).unwrap_or(false)
+}
+
+/// Tracks a connection against the process wide watchdog until the guard is dropped.
+pub fn track_connection() -> Option<ConnectionGuard> {
+    watchdog().map(|w| w.track_connection())
+}
+
+/// Admits the accepted connection `stream` against the process wide watchdog, see
+/// [`Watchdog::admit`]. Without a watchdog every connection is admitted untracked.
+pub fn admit<S, F, Fut>(
+    stream: S,
+    busy_replies: &'static Semaphore,
+    reply_busy: F,
+) -> Option<(S, Option<ConnectionGuard>)>
+where
+    F: FnOnce(S, SemaphorePermit<'static>) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    match watchdog() {
+        Some(watchdog) => watchdog
+            .admit(stream, busy_replies, reply_busy)
+            .map(|(stream, guard)| (stream, Some(guard))),
+        None => Some((stream, None)),
+    }
+}
+
+/// Limits the watchdog checks against, `None` disables the check.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Thresholds {
+    pub max_open_files: Option<usize>,
+    pub max_connections: Option<usize>,
+    pub max_memory: Option<u64>,
+}
+
+/// Snapshot of the watchdog state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct WatchdogState {
+    pub overloaded: bool,
+    /// open file descriptors at the last sample, `None` if not supported on this platform
+    pub open_files: Option<usize>,
+    pub connections: usize,
+    /// resident memory in bytes at the last sample, `None` if not supported on this platform
+    pub memory: Option<u64>,
+    /// work refused while overloaded
+    pub rejected: u64,
+    pub thresholds: Thresholds,
+}
+
+#[derive(Debug)]
+pub struct Watchdog {
+    thresholds: RwLock<Thresholds>,
+    overloaded: AtomicBool,
+    open_files: AtomicUsize,
+    connections: AtomicUsize,
+    memory: AtomicU64,
+    rejected: AtomicU64,
+}
+
+/// A tracked connection, released on drop.
+#[derive(Debug)]
+pub struct ConnectionGuard(&'static Watchdog);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Watchdog {
+    pub fn new(thresholds: Thresholds) -> Self {
+        Self {
+            thresholds: RwLock::new(thresholds),
+            overloaded: AtomicBool::new(false),
+            open_files: AtomicUsize::new(usize::MAX),
+            connections: AtomicUsize::new(0),
+            memory: AtomicU64::new(u64::MAX),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    pub fn track_connection(&'static self) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::AcqRel);
+        ConnectionGuard(self)
+    }
+
+    #[inline]
+    pub fn is_overloaded(&self) -> bool {
+        self.overloaded.load(Ordering::Acquire)
+    }
+
+    /// Admits the accepted connection `stream`, returns it with the guard tracking it.
+    ///
+    /// While overloaded the connection is refused and `None` returned. `reply_busy` is spawned
+    /// to tell the client if one of `busy_replies` is free, the permit is held until it
+    /// finishes. Unbounded, the replies would add to the overload they report.
+    pub fn admit<S, F, Fut>(
+        &'static self,
+        stream: S,
+        busy_replies: &'static Semaphore,
+        reply_busy: F,
+    ) -> Option<(S, ConnectionGuard)>
+    where
+        F: FnOnce(S, SemaphorePermit<'static>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        if !self.is_overloaded() {
+            return Some((stream, self.track_connection()));
+        }
+
+        self.reject();
+        if let Ok(permit) = busy_replies.try_acquire() {
+            tokio::spawn(reply_busy(stream, permit));
+        }
+        None
+    }
+
+    /// Records a piece of work refused because the watchdog is overloaded.
+    pub fn reject(&self) {
+        let rejected = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+        if rejected.is_power_of_two() {
+            warn!("resources exhausted, rejected {} requests so far", rejected);
+        }
+    }
+
+    pub fn thresholds(&self) -> Thresholds {
+        *self.thresholds.read().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Replaces the thresholds, they apply from the next sample on.
+    pub fn set_thresholds(&self, thresholds: Thresholds) {
+        *self.thresholds.write().unwrap_or_else(|err| err.into_inner()) = thresholds;
+    }
+
+    /// Samples the process resources and updates the overloaded flag.
+    pub fn sample(&self) {
+        let open_files = open_files().ok();
+        let memory = resident_memory().ok();
+        self.update(open_files, memory);
+    }
+
+    fn update(&self, open_files: Option<usize>, memory: Option<u64>) {
+        self.open_files
+            .store(open_files.unwrap_or(usize::MAX), Ordering::Release);
+        self.memory.store(memory.unwrap_or(u64::MAX), Ordering::Release);
+
+        let connections = self.connections.load(Ordering::Acquire);
+        let thresholds = self.thresholds();
+
+        let exceeded = |value: Option<u64>, max: Option<u64>| matches!((value, max), (Some(v), Some(m)) if v >= m);
+
+        let mut reasons = vec![];
+        if exceeded(
+            open_files.map(|n| n as u64),
+            thresholds.max_open_files.map(|n| n as u64),
+        ) {
+            reasons.push("open files");
+        }
+        if exceeded(Some(connections as u64), thresholds.max_connections.map(|n| n as u64)) {
+            reasons.push("connections");
+        }
+        if exceeded(memory, thresholds.max_memory) {
+            reasons.push("memory");
+        }
+
+        let overloaded = !reasons.is_empty();
+        if self.overloaded.swap(overloaded, Ordering::AcqRel) != overloaded {
+            if overloaded {
+                warn!(
+                    "watchdog overloaded by {}, open files: {:?}, connections: {}, memory: {:?}, \
+                     start rejecting new connections",
+                    reasons.join(", "),
+                    open_files,
+                    connections,
+                    memory
+                );
+            } else {
+                info!("watchdog recovered, accepting new connections again");
+            }
+        }
+    }
+
+    pub fn state(&self) -> WatchdogState {
+        let open_files = self.open_files.load(Ordering::Acquire);
+        let memory = self.memory.load(Ordering::Acquire);
+        WatchdogState {
+            overloaded: self.is_overloaded(),
+            open_files: (open_files != usize::MAX).then_some(open_files),
+            connections: self.connections.load(Ordering::Acquire),
+            memory: (memory != u64::MAX).then_some(memory),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            thresholds: self.thresholds(),
+        }
+    }
+}
+
+/// Returns the soft `RLIMIT_NOFILE` of the process.
+#[cfg(unix)]
+pub fn open_files_limit() -> io::Result<u64> {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)] // rlim_t is not u64 on every target
+    Ok(rlim.rlim_cur as u64)
+}
+
+#[cfg(not(unix))]
+pub fn open_files_limit() -> io::Result<u64> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Returns the number of file descriptors opened by the process.
+pub fn open_files() -> io::Result<usize> {
+    cfg_if::cfg_if! {
+        if #[cfg(any(target_os = "linux", target_os = "android"))] {
+            Ok(std::fs::read_dir("/proc/self/fd")?.count())
+        } else if #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))] {
+            Ok(std::fs::read_dir("/dev/fd")?.count())
+        } else {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+}
+
+/// Returns the resident memory of the process in bytes.
+pub fn resident_memory() -> io::Result<u64> {
+    cfg_if::cfg_if! {
+        if #[cfg(any(target_os = "linux", target_os = "android"))] {
+            let statm = std::fs::read_to_string("/proc/self/statm")?;
+            let pages = statm
+                .split_whitespace()
+                .nth(1)
+                .and_then(|s| s.parse::<u64>().ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed /proc/self/statm"))?;
+            let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+            Ok(pages * page_size.max(0) as u64)
+        } else {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_thresholds() {
+        let watchdog: &'static Watchdog = Box::leak(Box::new(Watchdog::new(Thresholds {
+            max_open_files: Some(100),
+            max_connections: Some(2),
+            max_memory: None,
+        })));
+
+        watchdog.update(Some(10), Some(1 << 30));
+        assert!(!watchdog.is_overloaded());
+
+        watchdog.update(Some(100), None);
+        assert!(watchdog.is_overloaded());
+        assert_eq!(watchdog.state().open_files, Some(100));
+        assert_eq!(watchdog.state().memory, None);
+
+        let a = watchdog.track_connection();
+        let b = watchdog.track_connection();
+        watchdog.update(Some(10), None);
+        assert!(watchdog.is_overloaded());
+        assert_eq!(watchdog.state().connections, 2);
+
+        drop((a, b));
+        watchdog.update(Some(10), None);
+        assert!(!watchdog.is_overloaded());
+        assert_eq!(watchdog.state().connections, 0);
+
+        watchdog.set_thresholds(Thresholds {
+            max_open_files: Some(10),
+            ..Default::default()
+        });
+        watchdog.update(Some(10), None);
+        assert!(watchdog.is_overloaded());
+        assert_eq!(watchdog.state().thresholds.max_connections, None);
+    }
+
+    #[tokio::test]
+    async fn test_watchdog_admit() {
+        static BUSY_REPLIES: Semaphore = Semaphore::const_new(1);
+        let watchdog: &'static Watchdog = Box::leak(Box::new(Watchdog::new(Thresholds {
+            max_connections: Some(1),
+            ..Default::default()
+        })));
+
+        let (stream, guard) = watchdog.admit(1, &BUSY_REPLIES, |_, _| async {}).unwrap();
+        assert_eq!(stream, 1);
+        assert_eq!(watchdog.state().connections, 1);
+
+        watchdog.update(None, None);
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let replied = |stream, permit| async move {
+            let _permit = permit;
+            tx.send(stream).unwrap();
+        };
+        assert!(watchdog.admit(2, &BUSY_REPLIES, replied).is_none());
+        assert_eq!(rx.await.unwrap(), 2);
+        assert_eq!(watchdog.state().rejected, 1);
+
+        // all replies are busy, refused without one
+        let _permit = BUSY_REPLIES.try_acquire().unwrap();
+        assert!(watchdog
+            .admit(3, &BUSY_REPLIES, |_, _| async { unreachable!() })
+            .is_none());
+        assert_eq!(watchdog.state().rejected, 2);
+
+        drop(guard);
+        watchdog.update(None, None);
+        assert!(watchdog.admit(4, &BUSY_REPLIES, |_, _| async {}).is_some());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_sample_process_resources() {
+        assert!(open_files().unwrap() > 0);
+        assert!(resident_memory().unwrap() > 0);
+        assert!(open_files_limit().unwrap() > 0);
+    }
+}
@@ -0,0 +1,186 @@
+//! Server side of the WebSocket protocol, RFC 6455, enough to push JSON to dashboards.
+//!
+//! Every message is a single frame, fragmented messages of the client are refused. Extensions,
+//! e.g. compression, aren't negotiated.
+
+use std::io;
+
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Appended to the key of the client for the accept key, RFC 6455 section 1.3
+const KEY_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest frame accepted from the client, clients only send control frames and small requests.
+pub const MAX_FRAME_LEN: usize = 64 * 1024;
+
+#[rustfmt::skip]
+mod opcode {
+    pub const CONTINUATION :u8 = 0x0;
+    pub const TEXT         :u8 = 0x1;
+    pub const BINARY       :u8 = 0x2;
+    pub const CLOSE        :u8 = 0x8;
+    pub const PING         :u8 = 0x9;
+    pub const PONG         :u8 = 0xa;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// the status code, if any
+    Close(Option<u16>),
+}
+
+/// The `Sec-WebSocket-Accept` answering the `Sec-WebSocket-Key` of the client.
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.trim().as_bytes());
+    sha1.update(KEY_GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(sha1.finalize())
+}
+
+/// Reads the next message of the client, whose frames must be masked.
+pub async fn read_message<R>(r: &mut R) -> io::Result<Message>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 2];
+    r.read_exact(&mut header).await?;
+    let fin = header[0] & 0x80 != 0;
+    let code = header[0] & 0x0f;
+    if header[1] & 0x80 == 0 {
+        return Err(invalid("unmasked client frame"));
+    }
+
+    let len = match header[1] & 0x7f {
+        126 => r.read_u16().await? as u64,
+        127 => r.read_u64().await?,
+        n => n as u64,
+    };
+    if len > MAX_FRAME_LEN as u64 {
+        return Err(invalid("frame too large"));
+    }
+    if !fin || code == opcode::CONTINUATION {
+        return Err(invalid("fragmented messages are not supported"));
+    }
+
+    let mut mask = [0u8; 4];
+    r.read_exact(&mut mask).await?;
+    let mut payload = vec![0u8; len as usize];
+    r.read_exact(&mut payload).await?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+
+    match code {
+        opcode::TEXT => String::from_utf8(payload)
+            .map(Message::Text)
+            .map_err(|_| invalid("text message is not utf-8")),
+        opcode::BINARY => Ok(Message::Binary(payload)),
+        opcode::PING => Ok(Message::Ping(payload)),
+        opcode::PONG => Ok(Message::Pong(payload)),
+        opcode::CLOSE => Ok(Message::Close(
+            payload.get(..2).map(|code| u16::from_be_bytes([code[0], code[1]])),
+        )),
+        _ => Err(invalid("unknown opcode")),
+    }
+}
+
+/// Writes `message` as a single unmasked frame.
+pub async fn write_message<W>(w: &mut W, message: &Message) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let (code, payload) = match message {
+        Message::Text(text) => (opcode::TEXT, text.as_bytes()),
+        Message::Binary(data) => (opcode::BINARY, data.as_slice()),
+        Message::Ping(data) => (opcode::PING, data.as_slice()),
+        Message::Pong(data) => (opcode::PONG, data.as_slice()),
+        Message::Close(code) => {
+            let payload = code.map(u16::to_be_bytes);
+            return write_frame(w, opcode::CLOSE, payload.as_ref().map_or(&[][..], |p| &p[..])).await;
+        }
+    };
+    write_frame(w, code, payload).await
+}
+
+async fn write_frame<W>(w: &mut W, code: u8, payload: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | code);
+    match payload.len() {
+        n if n < 126 => frame.push(n as u8),
+        n if n <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        n => {
+            frame.push(127);
+            frame.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    w.write_all(&frame).await?;
+    w.flush().await
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame of a client, masked.
+    fn client_frame(code: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![0x80 | code, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // RFC 6455 section 1.3
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[tokio::test]
+    async fn test_read_write_message() {
+        let mut frames = client_frame(opcode::TEXT, b"Hello");
+        frames.extend(client_frame(opcode::PING, b""));
+        frames.extend(client_frame(opcode::CLOSE, &1000u16.to_be_bytes()));
+        let mut r = frames.as_slice();
+        assert_eq!(read_message(&mut r).await.unwrap(), Message::Text("Hello".to_owned()));
+        assert_eq!(read_message(&mut r).await.unwrap(), Message::Ping(vec![]));
+        assert_eq!(read_message(&mut r).await.unwrap(), Message::Close(Some(1000)));
+
+        // unmasked, as servers send them
+        let mut w = vec![];
+        write_message(&mut w, &Message::Text("Hello".to_owned())).await.unwrap();
+        assert_eq!(w, b"\x81\x05Hello");
+        assert!(read_message(&mut w.as_slice()).await.is_err());
+
+        let mut w = vec![];
+        write_message(&mut w, &Message::Binary(vec![0; 300])).await.unwrap();
+        assert_eq!(&w[..4], &[0x82, 126, 0x01, 0x2c]);
+        assert_eq!(w.len(), 304);
+
+        let mut w = vec![];
+        write_message(&mut w, &Message::Close(None)).await.unwrap();
+        assert_eq!(w, [0x88, 0x00]);
+
+        // fragmented
+        let mut frame = client_frame(opcode::TEXT, b"Hel");
+        frame[0] &= 0x7f;
+        assert!(read_message(&mut frame.as_slice()).await.is_err());
+    }
+}
@@ -1,2 +1,3 @@
 pub mod socks4;
 pub mod socks5;
+pub mod vless;
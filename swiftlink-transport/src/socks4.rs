@@ -323,12 +323,20 @@ impl HandshakeRequest {
 #[derive(Debug, Clone)]
 pub struct HandshakeResponse {
     pub cd: ResultCode,
+    /// DSTPORT and DSTIP, zeros if `None`
+    pub dst: Option<SocketAddrV4>,
 }
 
 impl HandshakeResponse {
     /// Create a response with code
     pub fn new(code: ResultCode) -> HandshakeResponse {
-        HandshakeResponse { cd: code }
+        HandshakeResponse { cd: code, dst: None }
+    }
+
+    /// Sets DSTPORT and DSTIP, the address the server bound for the request
+    pub fn with_dst(mut self, dst: SocketAddrV4) -> HandshakeResponse {
+        self.dst = Some(dst);
+        self
     }
 
     /// Read from a reader
@@ -347,9 +355,11 @@ impl HandshakeResponse {
         let cd = buf[1];
         let result_code = ResultCode::from_u8(cd);
 
-        // DSTPORT, DSTIP are ignored
+        let port = BigEndian::read_u16(&buf[2..4]);
+        let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+        let dst = (port != 0 || !ip.is_unspecified()).then(|| SocketAddrV4::new(ip, port));
 
-        Ok(HandshakeResponse { cd: result_code })
+        Ok(HandshakeResponse { cd: result_code, dst })
     }
 
     /// Write data into a writer
@@ -364,22 +374,18 @@ impl HandshakeResponse {
 
     /// Writes to buffer
     pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) {
-        let HandshakeResponse { ref cd } = *self;
+        let HandshakeResponse { ref cd, ref dst } = *self;
 
         buf.put_slice(&[
             // VN: Result Code's version, must be 0
             0x00,
             // CD: Result Code
             cd.as_u8(),
-            // DSTPORT: Ignored
-            0x00,
-            0x00,
-            // DSTIP: Ignored
-            0x00,
-            0x00,
-            0x00,
-            0x00,
         ]);
+        let dst = dst.unwrap_or_else(|| SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+        // DSTPORT, DSTIP
+        buf.put_u16(dst.port());
+        buf.put_slice(&dst.ip().octets());
     }
 
     /// Length in bytes
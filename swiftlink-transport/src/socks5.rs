@@ -15,6 +15,9 @@ use std::{
 use bytes::{Buf, BufMut, BytesMut};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+pub mod auth;
+pub mod client;
+
 pub use self::consts::{
     SOCKS5_AUTH_METHOD_GSSAPI, SOCKS5_AUTH_METHOD_NONE, SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE, SOCKS5_AUTH_METHOD_PASSWORD,
 };
@@ -168,6 +171,14 @@ pub enum Error {
     UnsupportedPasswdAuthVersion(u8),
     #[error("username/password authentication invalid request")]
     PasswdAuthInvalidRequest,
+    #[error("authentication of user {0} failed")]
+    AuthFailed(String),
+    #[error("authentication method {0:#x} not supported")]
+    UnsupportedAuthMethod(u8),
+    #[error("no acceptable authentication method in {0:?}")]
+    NoAcceptableAuthMethod(Vec<u8>),
+    #[error("invalid authentication method, {0}")]
+    InvalidAuthMethod(String),
     #[error("{0}")]
     Reply(Reply),
 }
@@ -187,6 +198,9 @@ impl Error {
         match *self {
             Error::IoError(ref err) => match err.kind() {
                 ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+                ErrorKind::NetworkUnreachable => Reply::NetworkUnreachable,
+                ErrorKind::HostUnreachable | ErrorKind::TimedOut => Reply::HostUnreachable,
+                ErrorKind::PermissionDenied => Reply::ConnectionNotAllowed,
                 _ => Reply::GeneralFailure,
             },
             Error::AddressTypeNotSupported(..) => Reply::AddressTypeNotSupported,
@@ -195,6 +209,10 @@ impl Error {
             Error::UnsupportedCommand(..) => Reply::CommandNotSupported,
             Error::UnsupportedPasswdAuthVersion(..) => Reply::GeneralFailure,
             Error::PasswdAuthInvalidRequest => Reply::GeneralFailure,
+            Error::AuthFailed(..) => Reply::ConnectionNotAllowed,
+            Error::UnsupportedAuthMethod(..) => Reply::GeneralFailure,
+            Error::NoAcceptableAuthMethod(..) => Reply::GeneralFailure,
+            Error::InvalidAuthMethod(..) => Reply::GeneralFailure,
             Error::Reply(r) => r,
         }
     }
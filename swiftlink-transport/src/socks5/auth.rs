@@ -0,0 +1,296 @@
+//! SOCKS5 authentication methods negotiated by a server.
+//!
+//! Each method implements [`AuthMethod`], a listener accepts the methods registered in its
+//! [`AuthMethods`], in order of preference:
+//!
+//! ```ignore
+//! let methods = AuthMethods::from_names(&["password", "none"], Some(authenticator))?;
+//! let user = methods.negotiate(&mut stream).await?;
+//! ```
+
+use std::{fmt, sync::Arc};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use swiftlink_infra::auth::Authenticator;
+
+use super::{
+    Error, HandshakeRequest, HandshakeResponse, PasswdAuthRequest, PasswdAuthResponse, SOCKS5_AUTH_METHOD_GSSAPI,
+    SOCKS5_AUTH_METHOD_NONE, SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE, SOCKS5_AUTH_METHOD_PASSWORD,
+};
+
+/// A client stream the method specific sub-negotiation runs on.
+pub trait AuthStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AuthStream for T {}
+
+#[async_trait::async_trait]
+pub trait AuthMethod: Send + Sync {
+    /// METHOD code sent in the handshake, see RFC 1928 section 3
+    fn code(&self) -> u8;
+
+    fn name(&self) -> &'static str;
+
+    /// Runs the sub-negotiation after the method was chosen, returns the authenticated user.
+    async fn authenticate(&self, stream: &mut dyn AuthStream) -> Result<Option<String>, Error>;
+}
+
+/// `NO AUTHENTICATION REQUIRED`
+pub struct NoAuth;
+
+#[async_trait::async_trait]
+impl AuthMethod for NoAuth {
+    fn code(&self) -> u8 {
+        SOCKS5_AUTH_METHOD_NONE
+    }
+
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    async fn authenticate(&self, _stream: &mut dyn AuthStream) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+}
+
+/// `USERNAME/PASSWORD`, RFC 1929
+pub struct PasswordAuth {
+    authenticator: Arc<Authenticator>,
+}
+
+impl PasswordAuth {
+    pub fn new(authenticator: Arc<Authenticator>) -> Self {
+        Self { authenticator }
+    }
+}
+
+#[async_trait::async_trait]
+impl AuthMethod for PasswordAuth {
+    fn code(&self) -> u8 {
+        SOCKS5_AUTH_METHOD_PASSWORD
+    }
+
+    fn name(&self) -> &'static str {
+        "password"
+    }
+
+    async fn authenticate(&self, mut stream: &mut dyn AuthStream) -> Result<Option<String>, Error> {
+        let req = PasswdAuthRequest::read_from(&mut stream).await?;
+
+        let uname = String::from_utf8_lossy(&req.uname);
+        let passwd = String::from_utf8_lossy(&req.passwd);
+
+        if self.authenticator.verify(&uname, &passwd) {
+            PasswdAuthResponse::new(0x00).write_to(&mut stream).await?;
+            Ok(Some(uname.into_owned()))
+        } else {
+            PasswdAuthResponse::new(0x01).write_to(&mut stream).await?;
+            Err(Error::AuthFailed(uname.into_owned()))
+        }
+    }
+}
+
+/// `GSSAPI`, RFC 1961
+///
+/// Only the negotiation is implemented, the client's first token is always rejected with an
+/// abort message, so clients offering GSSAPI can fall back instead of hanging.
+pub struct GssApiAuth;
+
+#[async_trait::async_trait]
+impl AuthMethod for GssApiAuth {
+    fn code(&self) -> u8 {
+        SOCKS5_AUTH_METHOD_GSSAPI
+    }
+
+    fn name(&self) -> &'static str {
+        "gssapi"
+    }
+
+    async fn authenticate(&self, stream: &mut dyn AuthStream) -> Result<Option<String>, Error> {
+        // +------+------+------+.......................+
+        // + ver  | mtyp | len  |       token           |
+        // +------+------+------+.......................+
+        // + 0x01 | 0x01 | 0x02 | up to 2^16 - 1 octets |
+        // +------+------+------+.......................+
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await?;
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut token = vec![0u8; len];
+        stream.read_exact(&mut token).await?;
+
+        // abort the context establishment
+        stream.write_all(&[0x01, 0xff]).await?;
+        Err(Error::UnsupportedAuthMethod(SOCKS5_AUTH_METHOD_GSSAPI))
+    }
+}
+
+/// The authentication methods a listener accepts, in order of preference.
+#[derive(Clone, Default)]
+pub struct AuthMethods {
+    methods: Vec<Arc<dyn AuthMethod>>,
+}
+
+impl AuthMethods {
+    /// `password` when users are configured, `none` otherwise.
+    pub fn for_users(authenticator: Option<Arc<Authenticator>>) -> Self {
+        match authenticator {
+            Some(authenticator) => Self::default().with(PasswordAuth::new(authenticator)),
+            None => Self::default().with(NoAuth),
+        }
+    }
+
+    /// Registers `method`, replacing a registered method with the same code.
+    pub fn with<M: AuthMethod + 'static>(mut self, method: M) -> Self {
+        self.methods.retain(|m| m.code() != method.code());
+        self.methods.push(Arc::new(method));
+        self
+    }
+
+    /// Builds the methods from their config names, `none`, `password` or `gssapi`.
+    pub fn from_names<S: AsRef<str>>(names: &[S], authenticator: Option<Arc<Authenticator>>) -> Result<Self, Error> {
+        let mut methods = Self::default();
+        for name in names {
+            methods = match name.as_ref() {
+                "none" => methods.with(NoAuth),
+                "password" => match authenticator.clone() {
+                    Some(authenticator) => methods.with(PasswordAuth::new(authenticator)),
+                    None => return Err(Error::InvalidAuthMethod("password requires users".to_owned())),
+                },
+                "gssapi" => methods.with(GssApiAuth),
+                name => return Err(Error::InvalidAuthMethod(format!("unknown method {}", name))),
+            };
+        }
+        Ok(methods)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.methods.is_empty()
+    }
+
+    /// The most preferred registered method offered by the client.
+    pub fn select(&self, offered: &[u8]) -> Option<&Arc<dyn AuthMethod>> {
+        self.methods.iter().find(|m| offered.contains(&m.code()))
+    }
+
+    /// Reads the client handshake, replies with the chosen method and authenticates the client.
+    pub async fn negotiate<S: AuthStream>(&self, stream: &mut S) -> Result<Option<String>, Error> {
+        let req = HandshakeRequest::read_from(stream).await?;
+
+        let method = match self.select(&req.methods) {
+            Some(method) => method,
+            None => {
+                HandshakeResponse::new(SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE)
+                    .write_to(stream)
+                    .await?;
+                return Err(Error::NoAcceptableAuthMethod(req.methods));
+            }
+        };
+
+        HandshakeResponse::new(method.code()).write_to(stream).await?;
+        method.authenticate(stream).await
+    }
+}
+
+impl fmt::Debug for AuthMethods {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.methods.iter().map(|m| m.name())).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use swiftlink_infra::auth::AuthUser;
+
+    use super::*;
+
+    fn authenticator() -> Arc<Authenticator> {
+        Arc::new(Authenticator::new(vec![AuthUser::new("user", "secret")]))
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_password() {
+        let methods = AuthMethods::from_names(&["password", "none"], Some(authenticator())).unwrap();
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        let server = tokio::spawn(async move { methods.negotiate(&mut server).await });
+
+        HandshakeRequest::new(vec![SOCKS5_AUTH_METHOD_NONE, SOCKS5_AUTH_METHOD_PASSWORD])
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        let res = HandshakeResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(res.chosen_method, SOCKS5_AUTH_METHOD_PASSWORD);
+
+        PasswdAuthRequest::new("user", "secret")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        let res = PasswdAuthResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(res.status, 0);
+
+        assert_eq!(server.await.unwrap().unwrap(), Some("user".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_not_acceptable() {
+        let methods = AuthMethods::from_names(&["password"], Some(authenticator())).unwrap();
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        let server = tokio::spawn(async move { methods.negotiate(&mut server).await });
+
+        HandshakeRequest::new(vec![SOCKS5_AUTH_METHOD_NONE])
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        let res = HandshakeResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(res.chosen_method, SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
+
+        assert!(matches!(server.await.unwrap(), Err(Error::NoAcceptableAuthMethod(_))));
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_gssapi_stub() {
+        let methods = AuthMethods::from_names(&["gssapi"], None).unwrap();
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        let server = tokio::spawn(async move { methods.negotiate(&mut server).await });
+
+        HandshakeRequest::new(vec![SOCKS5_AUTH_METHOD_GSSAPI])
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        let res = HandshakeResponse::read_from(&mut client).await.unwrap();
+        assert_eq!(res.chosen_method, SOCKS5_AUTH_METHOD_GSSAPI);
+
+        client.write_all(&[0x01, 0x01, 0x00, 0x02, 0xaa, 0xbb]).await.unwrap();
+        let mut abort = [0u8; 2];
+        client.read_exact(&mut abort).await.unwrap();
+        assert_eq!(abort, [0x01, 0xff]);
+
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[test]
+    fn test_auth_methods_for_users() {
+        let methods = AuthMethods::for_users(None);
+        assert_eq!(methods.select(&[0x00, 0x02]).unwrap().code(), SOCKS5_AUTH_METHOD_NONE);
+
+        let methods = AuthMethods::for_users(Some(authenticator()));
+        assert_eq!(
+            methods.select(&[0x00, 0x02]).unwrap().code(),
+            SOCKS5_AUTH_METHOD_PASSWORD
+        );
+        assert!(methods.select(&[0x00]).is_none());
+    }
+
+    #[test]
+    fn test_auth_methods_from_names() {
+        assert!(AuthMethods::from_names(&["password"], None).is_err());
+        assert!(AuthMethods::from_names(&["kerberos"], None).is_err());
+
+        let methods = AuthMethods::from_names(&["none", "gssapi", "none"], None).unwrap();
+        assert_eq!(format!("{:?}", methods), r#"["gssapi", "none"]"#);
+        assert_eq!(methods.select(&[0x00, 0x01]).unwrap().code(), SOCKS5_AUTH_METHOD_GSSAPI);
+        assert!(methods.select(&[0x02]).is_none());
+    }
+}
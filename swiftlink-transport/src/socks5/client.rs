@@ -0,0 +1,301 @@
+//! SOCKS5 client, the CONNECT and UDP ASSOCIATE commands.
+//!
+//! ```ignore
+//! let mut stream = TcpStream::connect(proxy).await?;
+//! client::connect(&mut stream, &target, Some(("user", "secret"))).await?;
+//! // stream is now a tunnel to target
+//!
+//! let socket = UdpSocket::bind("0.0.0.0:0").await?;
+//! let udp = client::UdpAssociation::open(TcpStream::connect(proxy).await?, socket, None).await?;
+//! udp.send_to(&query, &target).await?;
+//! ```
+
+use std::{
+    io::{self, Cursor},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    task::{ready, Context, Poll},
+};
+
+use bytes::{BufMut, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, UdpSocket},
+};
+
+use super::{
+    consts, Address, Command, Error, HandshakeRequest, HandshakeResponse, PasswdAuthRequest, PasswdAuthResponse, Reply,
+    TcpRequestHeader, TcpResponseHeader, UdpAssociateHeader, SOCKS5_AUTH_METHOD_NONE, SOCKS5_AUTH_METHOD_PASSWORD,
+};
+
+/// The largest UDP datagram relayed, header included.
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+/// Negotiates the authentication with the server, username/password if `credentials` are given.
+pub async fn handshake<S>(stream: &mut S, credentials: Option<(&str, &str)>) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let method = match credentials {
+        Some(_) => SOCKS5_AUTH_METHOD_PASSWORD,
+        None => SOCKS5_AUTH_METHOD_NONE,
+    };
+    HandshakeRequest::new(vec![method]).write_to(stream).await?;
+
+    let res = HandshakeResponse::read_from(stream).await?;
+    if res.chosen_method != method {
+        return Err(Error::NoAcceptableAuthMethod(vec![method]));
+    }
+
+    if let Some((username, password)) = credentials {
+        let valid = |s: &str| !s.is_empty() && s.len() <= u8::MAX as usize;
+        if !valid(username) || !valid(password) {
+            return Err(Error::PasswdAuthInvalidRequest);
+        }
+        PasswdAuthRequest::new(username, password).write_to(stream).await?;
+        if PasswdAuthResponse::read_from(stream).await?.status != 0 {
+            return Err(Error::AuthFailed(username.to_owned()));
+        }
+    }
+    Ok(())
+}
+
+/// Sends `command` to `target` and returns the address the server bound for it.
+async fn request<S>(stream: &mut S, command: Command, target: Address) -> Result<Address, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    TcpRequestHeader::new(command, target).write_to(stream).await?;
+    let res = TcpResponseHeader::read_from(stream).await?;
+    match res.reply {
+        Reply::Succeeded => Ok(res.address),
+        reply => Err(Error::Reply(reply)),
+    }
+}
+
+/// Opens a tunnel to `target` on `stream`, a connection to the server. Returns the address the
+/// server connected from.
+pub async fn connect<S>(stream: &mut S, target: &Address, credentials: Option<(&str, &str)>) -> Result<Address, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    handshake(stream, credentials).await?;
+    request(stream, Command::TcpConnect, target.clone()).await
+}
+
+/// A UDP relay of a SOCKS5 server. It lives as long as the control connection, which is kept
+/// open by the association.
+#[derive(Debug)]
+pub struct UdpAssociation {
+    socket: UdpSocket,
+    relay: SocketAddr,
+    _control: TcpStream,
+}
+
+impl UdpAssociation {
+    /// Asks the server for a UDP relay on `control`, a connection to the server. `socket`, bound to
+    /// the address family of the server, sends to and receives from the relay.
+    pub async fn open(
+        mut control: TcpStream,
+        socket: UdpSocket,
+        credentials: Option<(&str, &str)>,
+    ) -> Result<Self, Error> {
+        let server = control.peer_addr()?;
+        handshake(&mut control, credentials).await?;
+
+        let unspecified = match server.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        // the client address isn't known before the first datagram, RFC 1928 section 6
+        let relay = request(
+            &mut control,
+            Command::UdpAssociate,
+            Address::SocketAddress(SocketAddr::new(unspecified, 0)),
+        )
+        .await?;
+
+        let relay = match relay {
+            // the relay is on the server itself
+            Address::SocketAddress(addr) if addr.ip().is_unspecified() => SocketAddr::new(server.ip(), addr.port()),
+            Address::SocketAddress(addr) => addr,
+            Address::DomainNameAddress(..) => {
+                return Err(Error::AddressTypeNotSupported(consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME))
+            }
+        };
+
+        socket.connect(relay).await?;
+
+        Ok(Self {
+            socket,
+            relay,
+            _control: control,
+        })
+    }
+
+    /// The address of the relay on the server.
+    #[inline]
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Sends `buf` to `target` through the relay.
+    pub async fn send_to(&self, buf: &[u8], target: &Address) -> io::Result<usize> {
+        std::future::poll_fn(|cx| self.poll_send_to(cx, buf, target)).await
+    }
+
+    /// Receives a datagram relayed from the returned address.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Address)> {
+        std::future::poll_fn(|cx| self.poll_recv_from(cx, buf)).await
+    }
+
+    pub fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: &Address) -> Poll<io::Result<usize>> {
+        let header = UdpAssociateHeader::new(0, target.clone());
+        let mut datagram = BytesMut::with_capacity(header.serialized_len() + buf.len());
+        header.write_to_buf(&mut datagram);
+        datagram.put_slice(buf);
+
+        ready!(self.socket.poll_send(cx, &datagram))?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    pub fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<(usize, Address)>> {
+        let mut datagram = [0u8; MAX_DATAGRAM_LEN];
+        loop {
+            let mut read = tokio::io::ReadBuf::new(&mut datagram);
+            ready!(self.socket.poll_recv(cx, &mut read))?;
+            let datagram = read.filled();
+
+            // RSV(2) FRAG(1) ATYP ADDR PORT DATA, fragments aren't supported and dropped
+            if datagram.len() < 3 || datagram[2] != 0 {
+                continue;
+            }
+            let mut cursor = Cursor::new(&datagram[3..]);
+            let Ok(address) = Address::read_cursor(&mut cursor) else {
+                continue;
+            };
+            let payload = &datagram[3 + cursor.position() as usize..];
+            let n = payload.len().min(buf.len());
+            buf[..n].copy_from_slice(&payload[..n]);
+            return Poll::Ready(Ok((n, address)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use swiftlink_infra::auth::{AuthUser, Authenticator};
+    use tokio::net::TcpListener;
+
+    use super::{super::auth::AuthMethods, *};
+
+    /// Serves one CONNECT or UDP ASSOCIATE on `listener`, replying `reply`.
+    async fn serve_one(listener: TcpListener, methods: AuthMethods, reply: Reply, relay: Option<SocketAddr>) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        if methods.negotiate(&mut stream).await.is_err() {
+            return;
+        }
+        let req = TcpRequestHeader::read_from(&mut stream).await.unwrap();
+        let bound = relay.unwrap_or_else(|| stream.local_addr().unwrap());
+        assert_eq!(matches!(req.command, Command::UdpAssociate), relay.is_some());
+        TcpResponseHeader::new(reply, bound.into())
+            .write_to(&mut stream)
+            .await
+            .unwrap();
+        // keep the control connection until the client closes it
+        let mut buf = [0u8; 1];
+        _ = tokio::io::AsyncReadExt::read(&mut stream, &mut buf).await;
+    }
+
+    #[tokio::test]
+    async fn test_client_connect() {
+        let authenticator = Arc::new(Authenticator::new(vec![AuthUser::new("user", "secret")]));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        tokio::spawn(serve_one(
+            listener,
+            AuthMethods::for_users(Some(authenticator.clone())),
+            Reply::Succeeded,
+            None,
+        ));
+
+        let target = Address::DomainNameAddress("example.com".to_owned(), 443);
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        let bound = connect(&mut stream, &target, Some(("user", "secret"))).await.unwrap();
+        assert_eq!(bound, Address::SocketAddress(server));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        tokio::spawn(serve_one(
+            listener,
+            AuthMethods::for_users(Some(authenticator)),
+            Reply::Succeeded,
+            None,
+        ));
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        let err = connect(&mut stream, &target, Some(("user", "wrong")))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::AuthFailed(user) if user == "user"));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        tokio::spawn(serve_one(
+            listener,
+            AuthMethods::for_users(None),
+            Reply::HostUnreachable,
+            None,
+        ));
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        let err = connect(&mut stream, &target, None).await.unwrap_err();
+        assert!(matches!(err, Error::Reply(Reply::HostUnreachable)));
+    }
+
+    #[tokio::test]
+    async fn test_client_udp_associate() {
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        // an unspecified relay address is on the server itself
+        let announced = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), relay_addr.port());
+        tokio::spawn(serve_one(
+            listener,
+            AuthMethods::for_users(None),
+            Reply::Succeeded,
+            Some(announced),
+        ));
+
+        let control = TcpStream::connect(server).await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp = UdpAssociation::open(control, socket, None).await.unwrap();
+        assert_eq!(udp.relay_addr(), relay_addr);
+
+        let target: Address = "8.8.8.8:53".parse().unwrap();
+        udp.send_to(b"query", &target).await.unwrap();
+
+        let mut buf = [0u8; 64];
+        let (n, client) = relay.recv_from(&mut buf).await.unwrap();
+        let header = UdpAssociateHeader::read_from(&mut &buf[..n]).await.unwrap();
+        assert_eq!(header.address, target);
+        assert_eq!(&buf[header.serialized_len()..n], b"query");
+
+        // answer with the header, a fragment is dropped
+        let mut fragment = BytesMut::new();
+        UdpAssociateHeader::new(1, target.clone()).write_to_buf(&mut fragment);
+        relay.send_to(&fragment, client).await.unwrap();
+        let mut answer = BytesMut::new();
+        UdpAssociateHeader::new(0, target.clone()).write_to_buf(&mut answer);
+        answer.put_slice(b"answer");
+        relay.send_to(&answer, client).await.unwrap();
+
+        let (n, from) = udp.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..n], from), (&b"answer"[..], target));
+    }
+}
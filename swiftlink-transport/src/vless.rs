@@ -0,0 +1,579 @@
+//! VLESS client, the protocol of the xray servers.
+//!
+//! The request header goes out with the first payload, the response header is stripped from the
+//! first bytes received, so opening a stream takes no round trip:
+//!
+//! ```ignore
+//! let user: UserId = "b831381d-6324-4d53-ad4f-8cda48b30811".parse()?;
+//! let mut stream = VlessStream::connect(TcpStream::connect(server).await?, &user, &target)?;
+//! stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await?;
+//! ```
+//!
+//! UDP goes through [`PacketStream`], one target per stream, or through [`xudp::XudpStream`],
+//! which carries the datagrams of any target on a single stream.
+//!
+//! VLESS doesn't encrypt, the stream to the server is expected to be TLS.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{self, Cursor},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::socks5::Address;
+
+pub mod xudp;
+
+#[rustfmt::skip]
+mod consts {
+    pub const VLESS_VERSION:               u8 = 0x00;
+
+    pub const VLESS_CMD_TCP:               u8 = 0x01;
+    pub const VLESS_CMD_UDP:               u8 = 0x02;
+    pub const VLESS_CMD_MUX:               u8 = 0x03;
+
+    pub const VLESS_ADDR_TYPE_IPV4:        u8 = 0x01;
+    pub const VLESS_ADDR_TYPE_DOMAIN_NAME: u8 = 0x02;
+    pub const VLESS_ADDR_TYPE_IPV6:        u8 = 0x03;
+}
+
+/// VLESS command
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub enum Command {
+    Tcp,
+    Udp,
+    /// Mux.Cool, carrying the XUDP frames
+    Mux,
+}
+
+impl Command {
+    #[inline]
+    fn as_u8(self) -> u8 {
+        match self {
+            Command::Tcp => consts::VLESS_CMD_TCP,
+            Command::Udp => consts::VLESS_CMD_UDP,
+            Command::Mux => consts::VLESS_CMD_MUX,
+        }
+    }
+}
+
+/// VLESS protocol error
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    IoError(#[from] io::Error),
+    #[error("invalid user id {0}, a UUID is expected")]
+    InvalidUserId(String),
+    #[error("domain name {0} is longer than 255 bytes")]
+    DomainTooLong(String),
+    #[error("unsupported vless version {0:#x}")]
+    UnsupportedVersion(u8),
+    #[error("address type {0:#x} not supported")]
+    AddressTypeNotSupported(u8),
+    #[error("address domain name must be UTF-8 encoding")]
+    AddressDomainInvalidEncoding,
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::IoError(err) => err,
+            e => io::Error::other(e),
+        }
+    }
+}
+
+/// The UUID a server knows its user by.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UserId([u8; 16]);
+
+impl UserId {
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl From<[u8; 16]> for UserId {
+    fn from(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl FromStr for UserId {
+    type Err = Error;
+
+    /// Parses a UUID, with or without its hyphens.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.replace('-', "");
+        let hyphens = s.len() - hex.len();
+        if hex.len() != 32 || (hyphens != 0 && hyphens != 4) || !hex.is_ascii() {
+            return Err(Error::InvalidUserId(s.to_owned()));
+        }
+        let mut bytes = [0u8; 16];
+        for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).map_err(|_| Error::InvalidUserId(s.to_owned()))?;
+            *byte = u8::from_str_radix(digits, 16).map_err(|_| Error::InvalidUserId(s.to_owned()))?;
+        }
+        Ok(Self(bytes))
+    }
+}
+
+impl Display for UserId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                f.write_str("-")?;
+            }
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for UserId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+/// VLESS request header
+///
+/// ```plain
+/// +-----+------+--------+-----+------+------+------+
+/// | VER | UUID | ADDONS | CMD | PORT | ATYP | ADDR |
+/// +-----+------+--------+-----+------+------+------+
+/// |  1  |  16  |   1    |  1  |  2   |  1   | Var. |
+/// +-----+------+--------+-----+------+------+------+
+/// ```
+///
+/// No addons are sent, `ADDONS` is their length. The mux command has no target.
+#[derive(Clone, Debug)]
+pub struct RequestHeader {
+    pub user: UserId,
+    pub command: Command,
+    pub target: Option<Address>,
+}
+
+impl RequestHeader {
+    pub fn new(user: UserId, command: Command, target: Option<Address>) -> Result<Self, Error> {
+        if let Some(Address::DomainNameAddress(domain, _)) = &target {
+            if domain.len() > u8::MAX as usize {
+                return Err(Error::DomainTooLong(domain.clone()));
+            }
+        }
+        Ok(Self { user, command, target })
+    }
+
+    pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u8(consts::VLESS_VERSION);
+        buf.put_slice(self.user.as_bytes());
+        buf.put_u8(0);
+        buf.put_u8(self.command.as_u8());
+        if let Some(target) = &self.target {
+            write_address(target, buf);
+        }
+    }
+
+    #[inline]
+    pub fn serialized_len(&self) -> usize {
+        1 + 16 + 1 + 1 + self.target.as_ref().map_or(0, address_len)
+    }
+}
+
+/// Writes `addr` port first, the way VLESS and Mux.Cool do.
+fn write_address<B: BufMut>(addr: &Address, buf: &mut B) {
+    buf.put_u16(addr.port());
+    match addr {
+        Address::SocketAddress(SocketAddr::V4(addr)) => {
+            buf.put_u8(consts::VLESS_ADDR_TYPE_IPV4);
+            buf.put_slice(&addr.ip().octets());
+        }
+        Address::SocketAddress(SocketAddr::V6(addr)) => {
+            buf.put_u8(consts::VLESS_ADDR_TYPE_IPV6);
+            buf.put_slice(&addr.ip().octets());
+        }
+        Address::DomainNameAddress(domain, _) => {
+            buf.put_u8(consts::VLESS_ADDR_TYPE_DOMAIN_NAME);
+            buf.put_u8(domain.len() as u8);
+            buf.put_slice(domain.as_bytes());
+        }
+    }
+}
+
+fn address_len(addr: &Address) -> usize {
+    2 + 1
+        + match addr {
+            Address::SocketAddress(SocketAddr::V4(_)) => 4,
+            Address::SocketAddress(SocketAddr::V6(_)) => 16,
+            Address::DomainNameAddress(domain, _) => 1 + domain.len(),
+        }
+}
+
+/// Reads an address written port first.
+fn read_address<T: AsRef<[u8]>>(cur: &mut Cursor<T>) -> Result<Address, Error> {
+    let truncated = || Error::IoError(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated address"));
+    if cur.remaining() < 3 {
+        return Err(truncated());
+    }
+    let port = cur.get_u16();
+    match cur.get_u8() {
+        consts::VLESS_ADDR_TYPE_IPV4 => {
+            if cur.remaining() < 4 {
+                return Err(truncated());
+            }
+            let ip = Ipv4Addr::from(cur.get_u32());
+            Ok(Address::SocketAddress(SocketAddr::V4(SocketAddrV4::new(ip, port))))
+        }
+        consts::VLESS_ADDR_TYPE_IPV6 => {
+            if cur.remaining() < 16 {
+                return Err(truncated());
+            }
+            let ip = Ipv6Addr::from(cur.get_u128());
+            Ok(Address::SocketAddress(SocketAddr::V6(SocketAddrV6::new(
+                ip, port, 0, 0,
+            ))))
+        }
+        consts::VLESS_ADDR_TYPE_DOMAIN_NAME => {
+            if cur.remaining() < 1 || cur.remaining() < 1 + cur.chunk()[0] as usize {
+                return Err(truncated());
+            }
+            let mut domain = vec![0u8; cur.get_u8() as usize];
+            cur.copy_to_slice(&mut domain);
+            let domain = String::from_utf8(domain).map_err(|_| Error::AddressDomainInvalidEncoding)?;
+            Ok(Address::DomainNameAddress(domain, port))
+        }
+        atyp => Err(Error::AddressTypeNotSupported(atyp)),
+    }
+}
+
+/// Where the response header is, in the first bytes received.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Response {
+    Version,
+    AddonsLen,
+    /// the addons left to skip
+    Addons(usize),
+    Done,
+}
+
+/// A stream to a target through a VLESS server.
+///
+/// The request header is sent with the first write, or by a flush if the target speaks first.
+#[derive(Debug)]
+pub struct VlessStream<S> {
+    inner: S,
+    /// the header, until the first write
+    request: Option<BytesMut>,
+    /// written to the stream but not yet to `inner`
+    pending: BytesMut,
+    response: Response,
+}
+
+impl<S> VlessStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Opens a stream to `target` on `stream`, a connection to the server.
+    pub fn connect(stream: S, user: &UserId, target: &Address) -> Result<Self, Error> {
+        let header = RequestHeader::new(*user, Command::Tcp, Some(target.clone()))?;
+        Ok(Self::with_header(stream, header))
+    }
+
+    fn with_header(stream: S, header: RequestHeader) -> Self {
+        let mut request = BytesMut::with_capacity(header.serialized_len());
+        header.write_to_buf(&mut request);
+        Self {
+            inner: stream,
+            request: Some(request),
+            pending: BytesMut::new(),
+            response: Response::Version,
+        }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Skips the bytes of the response header at the start of `data`, returns how many.
+    fn skip_response(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let mut consumed = 0;
+        while consumed < data.len() {
+            match self.response {
+                Response::Version => {
+                    if data[consumed] != consts::VLESS_VERSION {
+                        return Err(Error::UnsupportedVersion(data[consumed]));
+                    }
+                    self.response = Response::AddonsLen;
+                    consumed += 1;
+                }
+                Response::AddonsLen => {
+                    self.response = match data[consumed] {
+                        0 => Response::Done,
+                        len => Response::Addons(len as usize),
+                    };
+                    consumed += 1;
+                }
+                Response::Addons(left) => {
+                    let skipped = left.min(data.len() - consumed);
+                    self.response = match left - skipped {
+                        0 => Response::Done,
+                        left => Response::Addons(left),
+                    };
+                    consumed += skipped;
+                }
+                Response::Done => break,
+            }
+        }
+        Ok(consumed)
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncRead for VlessStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.response == Response::Done {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            }
+            let start = buf.filled().len();
+            ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            let end = buf.filled().len();
+            if end == start {
+                // the server closes the connection of an unknown user right away
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "vless server closed the connection before its response",
+                )));
+            }
+            let skipped = this.skip_response(&buf.filled()[start..])?;
+            buf.filled_mut().copy_within(start + skipped..end, start);
+            buf.set_filled(end - skipped);
+            if buf.filled().len() > start {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for VlessStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if let Some(mut request) = this.request.take() {
+            // the header and the first payload go out together
+            request.extend_from_slice(buf);
+            this.pending = request;
+            if let Poll::Ready(Err(err)) = this.poll_drain(cx) {
+                return Poll::Ready(Err(err));
+            }
+            return Poll::Ready(Ok(buf.len()));
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if let Some(request) = this.request.take() {
+            this.pending = request;
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// The datagrams to and from a single target, each prefixed with its length on the stream.
+#[derive(Debug)]
+pub struct PacketStream<S> {
+    stream: VlessStream<S>,
+}
+
+impl<S> PacketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Opens a UDP relay to `target` on `stream`, a connection to the server.
+    pub fn connect(stream: S, user: &UserId, target: &Address) -> Result<Self, Error> {
+        let header = RequestHeader::new(*user, Command::Udp, Some(target.clone()))?;
+        Ok(Self {
+            stream: VlessStream::with_header(stream, header),
+        })
+    }
+
+    /// Sends the datagram `packet` to the target.
+    pub async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        let len = u16::try_from(packet.len()).map_err(|_| io::Error::other("datagram too large"))?;
+        let mut buf = BytesMut::with_capacity(2 + packet.len());
+        buf.put_u16(len);
+        buf.put_slice(packet);
+        self.stream.write_all(&buf).await?;
+        self.stream.flush().await
+    }
+
+    /// Receives a datagram of the target, the part that doesn't fit in `buf` is discarded.
+    pub async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.stream.read_u16().await? as usize;
+        read_datagram(&mut self.stream, len, buf).await
+    }
+}
+
+/// Reads a datagram of `len` bytes into `buf`, truncated to its length.
+async fn read_datagram<R: AsyncRead + Unpin>(stream: &mut R, len: usize, buf: &mut [u8]) -> io::Result<usize> {
+    let kept = len.min(buf.len());
+    stream.read_exact(&mut buf[..kept]).await?;
+    if len > kept {
+        tokio::io::copy(&mut (&mut *stream).take((len - kept) as u64), &mut tokio::io::sink()).await?;
+    }
+    Ok(kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    const USER: &str = "b831381d-6324-4d53-ad4f-8cda48b30811";
+
+    #[test]
+    fn test_vless_user_id() {
+        let user: UserId = USER.parse().unwrap();
+        assert_eq!(user.as_bytes()[..4], [0xb8, 0x31, 0x38, 0x1d]);
+        assert_eq!(user.to_string(), USER);
+        assert_eq!(USER.replace('-', "").parse::<UserId>().unwrap(), user);
+
+        for invalid in [
+            "",
+            "b831381d",
+            "b831381d-6324-4d53-ad4f-8cda48b3081g",
+            "b831381d6324-4d53-ad4f-8cda48b30811",
+        ] {
+            assert!(
+                matches!(invalid.parse::<UserId>(), Err(Error::InvalidUserId(_))),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_vless_request_header() {
+        let user: UserId = USER.parse().unwrap();
+        let target = Address::DomainNameAddress("example.com".to_owned(), 443);
+        let header = RequestHeader::new(user, Command::Tcp, Some(target)).unwrap();
+        let mut buf = BytesMut::new();
+        header.write_to_buf(&mut buf);
+        assert_eq!(buf.len(), header.serialized_len());
+        assert_eq!(buf[0], 0);
+        assert_eq!(&buf[1..17], user.as_bytes());
+        assert_eq!(buf[17..23], [0, 1, 0x01, 0xbb, 2, 11]);
+        assert_eq!(&buf[23..], b"example.com");
+
+        let mut cur = Cursor::new(&buf[19..]);
+        assert_eq!(
+            read_address(&mut cur).unwrap(),
+            Address::DomainNameAddress("example.com".to_owned(), 443)
+        );
+
+        let header = RequestHeader::new(user, Command::Mux, None).unwrap();
+        assert_eq!(header.serialized_len(), 19);
+
+        let long = Address::DomainNameAddress("a".repeat(256), 443);
+        assert!(matches!(
+            RequestHeader::new(user, Command::Tcp, Some(long)),
+            Err(Error::DomainTooLong(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_vless_stream() {
+        let user: UserId = USER.parse().unwrap();
+        let target: Address = "127.0.0.1:80".parse::<SocketAddr>().unwrap().into();
+        let (client, mut server) = duplex(1024);
+        let mut stream = VlessStream::connect(client, &user, &target).unwrap();
+
+        stream.write_all(b"ping").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut request = [0u8; 1 + 16 + 1 + 1 + 2 + 1 + 4 + 4];
+        server.read_exact(&mut request).await.unwrap();
+        assert_eq!(request[18..26], [0x01, 0, 80, 0x01, 127, 0, 0, 1]);
+        assert_eq!(&request[26..], b"ping");
+
+        // the response header, with addons, split over two writes
+        server.write_all(&[0, 2, 0xff]).await.unwrap();
+        server.flush().await.unwrap();
+        let read = tokio::spawn(async move {
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        server.write_all(&[0xff]).await.unwrap();
+        server.write_all(b"pong").await.unwrap();
+        drop(server);
+        assert_eq!(read.await.unwrap(), b"pong");
+
+        // an unknown user is disconnected without response
+        let (client, server) = duplex(1024);
+        let mut stream = VlessStream::connect(client, &user, &target).unwrap();
+        drop(server);
+        let err = stream.read(&mut [0u8; 4]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_vless_packet_stream() {
+        let user: UserId = USER.parse().unwrap();
+        let target: Address = "127.0.0.1:53".parse::<SocketAddr>().unwrap().into();
+        let (client, mut server) = duplex(1024);
+        let mut stream = PacketStream::connect(client, &user, &target).unwrap();
+
+        stream.send(b"query").await.unwrap();
+        let mut request = [0u8; 26 + 2 + 5];
+        server.read_exact(&mut request).await.unwrap();
+        assert_eq!(request[18], consts::VLESS_CMD_UDP);
+        assert_eq!(request[26..], [0, 5, b'q', b'u', b'e', b'r', b'y']);
+
+        server.write_all(&[0, 0, 0, 6]).await.unwrap();
+        server.write_all(b"answer").await.unwrap();
+        server.write_all(&[0, 2]).await.unwrap();
+        server.write_all(b"ok").await.unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(stream.recv(&mut buf).await.unwrap(), 4);
+        assert_eq!(&buf, b"answ");
+        assert_eq!(stream.recv(&mut buf).await.unwrap(), 2);
+        assert_eq!(&buf[..2], b"ok");
+    }
+}
@@ -0,0 +1,194 @@
+//! XUDP, the datagrams of any target on a single VLESS stream.
+//!
+//! The stream carries Mux.Cool frames of a single session. The first frame opens it with the
+//! target of the first datagram and the global id, which lets the server keep the outbound socket
+//! of the client across streams, the following ones name the target of their datagram:
+//!
+//! ```plain
+//! +--------+-----+--------+--------+---------+------+------+-----------+----------+------+
+//! | META.L | ID  | STATUS | OPTION | NETWORK | PORT | ATYP | GLOBAL ID | DATA.LEN | DATA |
+//! +--------+-----+--------+--------+---------+------+------+-----------+----------+------+
+//! |   2    |  2  |   1    |   1    |    1    |  2   |  1   |     8     |    2     | Var. |
+//! +--------+-----+--------+--------+---------+------+------+-----------+----------+------+
+//! ```
+//!
+//! The global id is sent by the frame opening the session only.
+
+use std::io::{self, Cursor};
+
+use bytes::{BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{
+    address_len, read_address, read_datagram, write_address, Command, Error, RequestHeader, UserId, VlessStream,
+};
+use crate::socks5::Address;
+
+#[rustfmt::skip]
+mod consts {
+    pub const XUDP_STATUS_NEW:        u8 = 0x01;
+    pub const XUDP_STATUS_KEEP:       u8 = 0x02;
+    pub const XUDP_STATUS_END:        u8 = 0x03;
+    pub const XUDP_STATUS_KEEP_ALIVE: u8 = 0x04;
+
+    pub const XUDP_OPTION_DATA:       u8 = 0x01;
+
+    pub const XUDP_NETWORK_UDP:       u8 = 0x02;
+}
+
+/// The datagrams of a client through a VLESS server.
+#[derive(Debug)]
+pub struct XudpStream<S> {
+    stream: VlessStream<S>,
+    global_id: [u8; 8],
+    /// the target of the first datagram, once the session is opened
+    opened: Option<Address>,
+}
+
+impl<S> XudpStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Opens a UDP relay on `stream`, a connection to the server. Streams of the same `global_id`
+    /// share the outbound socket on the server, a zero id shares nothing.
+    pub fn connect(stream: S, user: &UserId, global_id: [u8; 8]) -> Self {
+        let header = RequestHeader {
+            user: *user,
+            command: Command::Mux,
+            target: None,
+        };
+        Self {
+            stream: VlessStream::with_header(stream, header),
+            global_id,
+            opened: None,
+        }
+    }
+
+    /// Sends the datagram `packet` to `target`.
+    pub async fn send_to(&mut self, packet: &[u8], target: &Address) -> io::Result<()> {
+        if let Address::DomainNameAddress(domain, _) = target {
+            if domain.len() > u8::MAX as usize {
+                return Err(Error::DomainTooLong(domain.clone()).into());
+            }
+        }
+        let len = u16::try_from(packet.len()).map_err(|_| io::Error::other("datagram too large"))?;
+
+        let open = self.opened.is_none();
+        let meta_len = 2 + 1 + 1 + 1 + address_len(target) + if open { 8 } else { 0 };
+        let mut buf = BytesMut::with_capacity(2 + meta_len + 2 + packet.len());
+        buf.put_u16(meta_len as u16);
+        buf.put_u16(0);
+        buf.put_u8(if open {
+            consts::XUDP_STATUS_NEW
+        } else {
+            consts::XUDP_STATUS_KEEP
+        });
+        buf.put_u8(consts::XUDP_OPTION_DATA);
+        buf.put_u8(consts::XUDP_NETWORK_UDP);
+        write_address(target, &mut buf);
+        if open {
+            buf.put_slice(&self.global_id);
+        }
+        buf.put_u16(len);
+        buf.put_slice(packet);
+
+        self.stream.write_all(&buf).await?;
+        self.stream.flush().await?;
+        if open {
+            self.opened = Some(target.clone());
+        }
+        Ok(())
+    }
+
+    /// Receives a datagram and the address it came from, the part that doesn't fit in `buf` is
+    /// discarded. Fails with `UnexpectedEof` once the server ended the session.
+    pub async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, Address)> {
+        loop {
+            let meta_len = self.stream.read_u16().await? as usize;
+            if meta_len < 4 {
+                return Err(ended());
+            }
+            let mut meta = vec![0u8; meta_len];
+            self.stream.read_exact(&mut meta).await?;
+
+            let source = match meta[2] {
+                consts::XUDP_STATUS_KEEP if meta_len > 4 && meta[4] == consts::XUDP_NETWORK_UDP => {
+                    Some(read_address(&mut Cursor::new(&meta[5..]))?)
+                }
+                consts::XUDP_STATUS_KEEP | consts::XUDP_STATUS_KEEP_ALIVE => None,
+                consts::XUDP_STATUS_END => return Err(ended()),
+                status => return Err(io::Error::other(format!("unexpected xudp frame status {status:#x}"))),
+            };
+            if meta[3] & consts::XUDP_OPTION_DATA == 0 {
+                continue;
+            }
+
+            let len = self.stream.read_u16().await? as usize;
+            let n = read_datagram(&mut self.stream, len, buf).await?;
+            if meta[2] == consts::XUDP_STATUS_KEEP_ALIVE {
+                continue;
+            }
+            // a frame without address comes from the target of the session
+            match source.or_else(|| self.opened.clone()) {
+                Some(source) => return Ok((n, source)),
+                None => continue,
+            }
+        }
+    }
+}
+
+fn ended() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "xudp session ended")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_xudp_stream() {
+        let user: UserId = "b831381d-6324-4d53-ad4f-8cda48b30811".parse().unwrap();
+        let first: Address = "127.0.0.1:53".parse::<SocketAddr>().unwrap().into();
+        let second = Address::DomainNameAddress("dns.example".to_owned(), 53);
+        let (client, mut server) = duplex(1024);
+        let mut stream = XudpStream::connect(client, &user, [7; 8]);
+
+        stream.send_to(b"a", &first).await.unwrap();
+        let mut request = [0u8; 19 + 2 + 20 + 2 + 1];
+        server.read_exact(&mut request).await.unwrap();
+        assert_eq!(request[18], super::super::consts::VLESS_CMD_MUX);
+        assert_eq!(request[19..33], [0, 20, 0, 0, 1, 1, 2, 0, 53, 1, 127, 0, 0, 1]);
+        assert_eq!(request[33..], [7, 7, 7, 7, 7, 7, 7, 7, 0, 1, b'a']);
+
+        stream.send_to(b"b", &second).await.unwrap();
+        let mut frame = [0u8; 2 + 20 + 2 + 1];
+        server.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame[..11], [0, 20, 0, 0, 2, 1, 2, 0, 53, 2, 11]);
+        assert_eq!(&frame[11..22], b"dns.example");
+        assert_eq!(frame[22..], [0, 1, b'b']);
+
+        // the response header, a keep alive, a datagram of the second target, one of the first
+        let mut response = BytesMut::new();
+        response.put_slice(&[0, 0]);
+        response.put_slice(&[0, 4, 0, 0, 4, 0]);
+        response.put_u16(20);
+        response.put_slice(&[0, 0, 2, 1, 2]);
+        write_address(&second, &mut response);
+        response.put_slice(&[0, 2, b'o', b'k']);
+        response.put_slice(&[0, 4, 0, 0, 2, 1, 0, 3, b'y', b'e', b's']);
+        response.put_slice(&[0, 4, 0, 0, 3, 0]);
+        server.write_all(&response).await.unwrap();
+
+        let mut buf = [0u8; 16];
+        let (n, from) = stream.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..n], from), (&b"ok"[..], second));
+        let (n, from) = stream.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..n], from), (&b"yes"[..], first));
+        let err = stream.recv_from(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
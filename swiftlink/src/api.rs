@@ -0,0 +1,1527 @@
+//! The external controller, the HTTP API of dashboards.
+//!
+//! With `external_controller` set, a listener answers:
+//!
+//! - `GET /`: `{"hello":"swiftlink"}`
+//! - `GET /version`: `{"version":"0.1.0"}`
+//! - `GET /configs`: the effective configuration, secrets redacted, as `config dump` prints it
+//! - `GET /proxies`: the `proxy_meta` of the configuration in selector order, then the other
+//!   proxies of `dns.proxy_servers` and `proxy_groups`, with the delays of their health checks,
+//!   `0` if one failed, and their latest speed test, groups with their type, member and proxies,
+//!   `{"proxies":[{"name":"HK","icon":"https://...","hidden":false,"order":1,
+//!   "history":[{"time":"2024-01-02T03:04:05.678+08:00","delay":120}],
+//!   "speed":{"time":"...","bytes":52428800,"duration_ms":10000,"bytes_per_sec":5242880}},
+//!   {"name":"auto","hidden":false,"history":[],"type":"url-test","now":"HK","all":["HK","JP"]}]}`
+//! - `PUT /proxies/{group}`: chooses the proxy of the `select` group of the body, kept across
+//!   restarts, `{"name":"JP"}`, `204 No Content`
+//! - `GET /proxies/{name}/speedtest`: downloads the `speedtest_url` through the proxy `name` of
+//!   `dns.proxy_servers`, or the member of the group `name`, and answers the result once done, as
+//!   `speed` of `/proxies`
+//! - `GET /rules`: the match counters of the rules in matching order,
+//!   `{"rules":[{"rule":"*.example.com","hits":42,"last_hit":1700000000000}]}`
+//! - `GET /stats/top?window=600&limit=10`: the destination hosts and clients with the most bytes
+//!   of the connections closed in the last `window` seconds, rounded up to minutes and at most an
+//!   hour, 10 of each by default,
+//!   `{"window_secs":600,"destinations":[{"key":"example.com","connections":3,"upload":512,...}],...}`
+//! - `GET /stats/users`: the traffic of the users the inbounds authenticated since the start,
+//!   `{"users":[{"user":"alice","upload":512,"download":4096,"connections":3}]}`
+//! - `GET /connections/closed?reason=dial_refused&destination=example.com&limit=20`: the recently
+//!   closed connections, most recent first, all of them or those of a close `reason`, of the
+//!   destinations containing `destination` and at most `limit`,
+//!   `{"connections":[{"id":42,"network":"tcp","inbound":"socks","destination":"example.com:443",
+//!   "reason":"dial_refused",...}]}`
+//! - `GET /watchdog`: the last sample of the resource watchdog and its thresholds,
+//!   `{"overloaded":false,"open_files":42,"connections":3,"memory":10485760,"rejected":0,...}`
+//! - `GET /dns/failures?name=example&limit=20`: the latest failed lookups of the dns servers, most
+//!   recent first, 20 by default, or those of the names containing `name`,
+//!   `{"failures":[{"at":1700000000000,"name":"example.com.","record_type":"A",
+//!   "upstream":"udp://8.8.8.8:53","error":"...","duration_ms":5000}]}`
+//! - `GET /providers/rules`: the rule sets of `[rule_providers]` by name, as Clash lists them,
+//!   `{"providers":{"ads":{"name":"ads","type":"Rule","vehicleType":"HTTP","behavior":"Domain",
+//!   "ruleCount":42,"updatedAt":"2024-01-02T03:04:05+08:00"}}}`, `GET /providers/rules/{name}`
+//!   the one of `name`
+//! - `GET /providers/proxies`: the proxy groups with the proxies they resolve to, then the proxies
+//!   of `dns.proxy_servers`, by name, in the `default` provider as Clash lists them,
+//!   `{"providers":{"default":{"name":"default","type":"Proxy","vehicleType":"Compatible",
+//!   "proxies":[{"name":"auto","type":"url-test","now":"HK","members":[{"name":"HK",
+//!   "type":"socks5"}]},{"name":"HK","type":"socks5"}]}}}`
+//! - `PUT /providers/rules/{name}`: downloads the rule set of `name` if it has a `url` and
+//!   reloads it, `204 No Content` once the rules have the new set
+//! - `GET /fakeip/mappings?offset=0&limit=100`: the fake ips handed out by the dns server, ordered
+//!   by ip, 100 and at most 1000 at a time, or those of `query=<ip|host>`,
+//!   `{"total":1,"offset":0,"mappings":[{"ip":"198.18.0.2","host":"example.com."}]}`
+//! - `GET /traffic`: websocket pushing the bytes sent and received in the last second,
+//!   `{"up":1024,"down":4096}`, every second
+//! - `GET /logs?level=info`: websocket pushing the log lines of `level`, `debug`, `info`,
+//!   `warning` or `error`, and above, `{"type":"info","payload":"..."}`
+//!
+//! Builds with the `chaos` feature also answer:
+//!
+//! - `GET /chaos`: the faults being injected, `{"faults":null}` if none
+//! - `PUT /chaos`: injects the faults of the body, e.g.
+//!   `{"latency_rate":0.5,"latency_ms":800,"drop_rate":0.1,"failure_rate":0.1}`, `204 No Content`
+//! - `DELETE /chaos`: stops injecting faults, `204 No Content`
+//!
+//! ```toml
+//! external_controller = "0.0.0.0:9090"
+//! secret = "s3cr3t"
+//!
+//! [external_controller_cors]
+//! allow_origins = ["https://dashboard.example.com"]
+//!
+//! [external_controller_tls]
+//! cert = "api.crt"
+//! key = "api.key"
+//! ```
+//!
+//! With `[external_controller_tls.client_auth]`, the handshake fails for clients without a
+//! certificate of its `ca`, or with one revoked by its `crl`. Connections of certificates without
+//! a subject alternative name in its `allow_names`, if any, are closed after the handshake.
+//!
+//! With a `secret`, requests must carry `Authorization: Bearer <secret>`. Browsers can't set
+//! headers on websockets, these may pass `?token=<secret>` instead. Dashboards served from
+//! another origin need it in `allow_origins`.
+//!
+//! The server pings each websocket every 30 seconds and closes it if the client didn't answer
+//! since the previous ping, so connections through NATs and proxies which dropped it don't pile
+//! up.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Local, SecondsFormat};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::{broadcast::error::RecvError, mpsc},
+    time::{Instant, Interval, MissedTickBehavior},
+};
+use tokio_rustls::{rustls, TlsAcceptor};
+
+use swiftlink_dns::ProxyConfig;
+#[cfg(feature = "chaos")]
+use swiftlink_infra::chaos;
+use swiftlink_infra::{
+    connection::{ConnectionHistory, HistoryQuery},
+    delay::DelayRecord,
+    dns_failures::DnsFailures,
+    fakedns::FakeDns,
+    log::*,
+    net::ConnectOpts,
+    proxy_stats::{ProxyStatsMap, SpeedRecord},
+    rule_hits::RuleHits,
+    talkers::TalkerStats,
+    traffic::{self, TrafficStats, UserTraffic},
+    watchdog,
+    websocket::{self, Message},
+};
+
+use crate::{
+    config::{ClientAuth, ControllerCors, ProxyGroupType, ProxyMeta, RuleProviderBehavior},
+    fakeip::{self, Mapping, MappingPage},
+    http_head,
+    proxy_group::{ProxyGroup, ProxyGroups},
+    proxy_health::SpeedTester,
+    proxy_tree,
+    rule_provider::RuleProvider,
+    Config,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// Websockets are pinged this often, and closed if the previous ping wasn't answered.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Mappings of a `/fakeip/mappings` page without a `limit`
+const FAKEIP_PAGE_LEN: usize = 100;
+
+/// Window of a `/stats/top` request without one, 10 minutes
+const TOP_TALKERS_WINDOW: Duration = Duration::from_secs(600);
+
+/// Destinations and clients of a `/stats/top` request without a `limit`
+const TOP_TALKERS_LEN: usize = 10;
+
+/// Failures of a `/dns/failures` request without a `limit`
+const DNS_FAILURES_LEN: usize = 20;
+
+/// Paths of a rule provider, followed by its name
+const RULE_PROVIDERS_PREFIX: &str = "/providers/rules/";
+
+/// Paths of a proxy or group, followed by its name, and [`SPEEDTEST_SUFFIX`] for a speed test
+const PROXIES_PREFIX: &str = "/proxies/";
+
+const SPEEDTEST_SUFFIX: &str = "/speedtest";
+
+/// Interval of the `/traffic` messages
+const TRAFFIC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The settings of the external controller.
+pub(crate) struct Api {
+    secret: Option<String>,
+    cors: ControllerCors,
+    tls: Option<TlsAcceptor>,
+    /// subject alternative names of the client certificates allowed, any if empty
+    client_names: Vec<String>,
+    /// the body of `/configs`
+    config: String,
+    /// the proxies and groups of `/proxies`, in selector order
+    proxies: Vec<(String, ProxyMeta)>,
+    proxy_stats: Arc<ProxyStatsMap>,
+    /// the proxies of `dns.proxy_servers`, for `/providers/proxies`
+    proxy_servers: Arc<HashMap<String, ProxyConfig>>,
+    proxy_groups: Arc<ProxyGroups>,
+    /// `None` if no proxy can be tested
+    speed_tester: Option<SpeedTester>,
+    connections: Arc<ConnectionHistory>,
+    rule_hits: Arc<RuleHits>,
+    talkers: Arc<TalkerStats>,
+    user_traffic: Arc<UserTraffic>,
+    dns_failures: Arc<DnsFailures>,
+    rule_providers: HashMap<String, Arc<RuleProvider>>,
+    /// the socket options of rule set downloads
+    connect_opts: ConnectOpts,
+    /// `None` without fake ips
+    fakedns: Option<Arc<Mutex<FakeDns>>>,
+}
+
+impl Api {
+    pub(crate) fn new(secret: Option<&str>, cors: ControllerCors) -> Self {
+        Self {
+            secret: secret.map(str::to_owned),
+            cors,
+            tls: None,
+            client_names: Vec::new(),
+            config: "{}".to_owned(),
+            proxies: Vec::new(),
+            proxy_stats: Arc::default(),
+            proxy_servers: Arc::default(),
+            proxy_groups: Arc::default(),
+            speed_tester: None,
+            connections: Arc::default(),
+            rule_hits: Arc::default(),
+            talkers: Arc::default(),
+            user_traffic: Arc::default(),
+            dns_failures: Arc::default(),
+            rule_providers: HashMap::new(),
+            connect_opts: ConnectOpts::default(),
+            fakedns: None,
+        }
+    }
+
+    /// Returns `config` on `/configs`.
+    pub(crate) fn with_config(mut self, config: &Config) -> anyhow::Result<Self> {
+        self.config = serde_json::to_string(config).context("Failed to serialize the config")?;
+        Ok(self)
+    }
+
+    /// Returns the delay histories and speed tests of `proxy_stats` on `/proxies`.
+    pub(crate) fn with_proxy_stats(mut self, proxy_stats: Arc<ProxyStatsMap>) -> Self {
+        self.proxy_stats = proxy_stats;
+        self
+    }
+
+    /// Returns the proxies of `proxy_servers` on `/providers/proxies`.
+    pub(crate) fn with_proxy_servers(mut self, proxy_servers: Arc<HashMap<String, ProxyConfig>>) -> Self {
+        self.proxy_servers = proxy_servers;
+        self
+    }
+
+    /// Returns the members of `proxy_groups` on `/proxies` and `/providers/proxies`, and lets
+    /// `select` groups be chosen.
+    pub(crate) fn with_proxy_groups(mut self, proxy_groups: Arc<ProxyGroups>) -> Self {
+        self.proxy_groups = proxy_groups;
+        self
+    }
+
+    /// Runs the speed tests of `/proxies/{name}/speedtest` with `tester`.
+    pub(crate) fn with_speed_tester(mut self, tester: SpeedTester) -> Self {
+        self.speed_tester = Some(tester);
+        self
+    }
+
+    /// Returns the closed connections of `connections` on `/connections/closed`.
+    pub(crate) fn with_connections(mut self, connections: Arc<ConnectionHistory>) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// Returns the counters of `user_traffic` on `/stats/users`.
+    pub(crate) fn with_user_traffic(mut self, user_traffic: Arc<UserTraffic>) -> Self {
+        self.user_traffic = user_traffic;
+        self
+    }
+
+    /// Returns the mappings of `fakedns` on `/fakeip/mappings`.
+    pub(crate) fn with_fakedns(mut self, fakedns: Arc<Mutex<FakeDns>>) -> Self {
+        self.fakedns = Some(fakedns);
+        self
+    }
+
+    /// Returns the counters of `rule_hits` on `/rules`.
+    pub(crate) fn with_rule_hits(mut self, rule_hits: Arc<RuleHits>) -> Self {
+        self.rule_hits = rule_hits;
+        self
+    }
+
+    /// Returns the top talkers of `talkers` on `/stats/top`.
+    pub(crate) fn with_talkers(mut self, talkers: Arc<TalkerStats>) -> Self {
+        self.talkers = talkers;
+        self
+    }
+
+    /// Returns the latest failures of `dns_failures` on `/dns/failures`.
+    pub(crate) fn with_dns_failures(mut self, dns_failures: Arc<DnsFailures>) -> Self {
+        self.dns_failures = dns_failures;
+        self
+    }
+
+    /// Returns `providers` on `/providers/rules`, and updates them on requests, downloading with
+    /// `connect_opts`.
+    pub(crate) fn with_rule_providers(
+        mut self,
+        providers: HashMap<String, Arc<RuleProvider>>,
+        connect_opts: ConnectOpts,
+    ) -> Self {
+        self.rule_providers = providers;
+        self.connect_opts = connect_opts;
+        self
+    }
+
+    /// Returns the metadata of proxies and groups, in this order, on `/proxies`.
+    pub(crate) fn with_proxies<'a, I>(mut self, proxies: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a str, &'a ProxyMeta)>,
+    {
+        self.proxies = proxies
+            .into_iter()
+            .map(|(name, meta)| (name.to_owned(), meta.clone()))
+            .collect();
+        self
+    }
+
+    /// The body of `/proxies`, with the latest measurements.
+    fn proxies(&self) -> String {
+        #[derive(Serialize)]
+        struct Entry<'a> {
+            name: &'a str,
+            #[serde(flatten)]
+            meta: &'a ProxyMeta,
+            history: Vec<DelayRecord>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            speed: Option<SpeedRecord>,
+            #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+            tp: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            now: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            all: Option<&'a [String]>,
+        }
+
+        let proxies: Vec<_> = self
+            .proxies
+            .iter()
+            .map(|(name, meta)| {
+                let stats = self.proxy_stats.get(name);
+                let group = self.proxy_groups.get(name);
+                Entry {
+                    name,
+                    meta,
+                    history: stats.as_ref().map(|stats| stats.history.history()).unwrap_or_default(),
+                    speed: stats.and_then(|stats| stats.speed()),
+                    tp: group.map(|group| group.tp().as_str()),
+                    now: group.and_then(|group| group.now()),
+                    all: group.map(|group| group.members()),
+                }
+            })
+            .collect();
+        serde_json::json!({ "proxies": proxies }).to_string()
+    }
+
+    /// Serves https with the PEM certificate chain `cert` and private key `key`, to the clients
+    /// with a certificate of `client_auth` if set.
+    pub(crate) fn with_tls(
+        mut self,
+        cert: &Path,
+        key: &Path,
+        client_auth: Option<&ClientAuth>,
+    ) -> anyhow::Result<Self> {
+        let certs = rustls_pemfile::certs(&mut io::BufReader::new(std::fs::File::open(cert)?))
+            .with_context(|| format!("Invalid certificate {:?}", cert))?;
+        let key = rustls_pemfile::read_all(&mut io::BufReader::new(std::fs::File::open(key)?))?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key) => Some(key),
+                _ => None,
+            })
+            .with_context(|| format!("No private key in {:?}", key))?;
+
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+        let builder = match client_auth {
+            Some(auth) => builder.with_client_cert_verifier(client_verifier(auth)?.boxed()),
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(
+                certs.into_iter().map(rustls::Certificate).collect(),
+                rustls::PrivateKey(key),
+            )
+            .context("Invalid certificate or private key")?;
+        self.tls = Some(TlsAcceptor::from(Arc::new(config)));
+        self.client_names = client_auth.map(|auth| auth.allow_names.clone()).unwrap_or_default();
+        Ok(self)
+    }
+
+    #[inline]
+    pub(crate) fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    /// Whether the client of `certs`, its chain verified by the handshake, is allowed.
+    fn allows_client(&self, certs: Option<&[rustls::Certificate]>) -> bool {
+        if self.client_names.is_empty() {
+            return true;
+        }
+        let Some(cert) = certs
+            .and_then(|certs| certs.first())
+            .and_then(|cert| webpki::EndEntityCert::try_from(cert.0.as_slice()).ok())
+        else {
+            return false;
+        };
+        self.client_names.iter().any(|name| {
+            webpki::SubjectNameRef::try_from_ascii_str(name)
+                .is_ok_and(|name| cert.verify_is_valid_for_subject_name(name).is_ok())
+        })
+    }
+
+    /// Whether `req` carries the secret, websockets may pass it in the query.
+    fn authorized(&self, req: &Request) -> bool {
+        let Some(secret) = self.secret.as_deref() else {
+            return true;
+        };
+        let bearer = req
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let token = req.websocket_key().and_then(|_| req.query("token"));
+        bearer.or(token).is_some_and(|token| constant_time_eq(token, secret))
+    }
+
+    /// The CORS headers of the response to `req`, empty unless its origin is allowed.
+    fn cors_headers(&self, req: &Request) -> String {
+        let Some(origin) = req.header("origin").filter(|origin| self.cors.allows(origin)) else {
+            return String::new();
+        };
+        let mut headers = format!("Access-Control-Allow-Origin: {}\r\nVary: Origin\r\n", origin);
+        if req.method == "OPTIONS" {
+            headers.push_str(
+                "Access-Control-Allow-Methods: GET, POST, PUT, PATCH, DELETE\r\n\
+                 Access-Control-Allow-Headers: Authorization, Content-Type\r\n\
+                 Access-Control-Max-Age: 600\r\n",
+            );
+            if self.cors.allow_private_network && req.header("access-control-request-private-network") == Some("true") {
+                headers.push_str("Access-Control-Allow-Private-Network: true\r\n");
+            }
+        }
+        headers
+    }
+}
+
+/// Serves the API until the task is aborted.
+pub(crate) async fn serve(listener: TcpListener, api: Arc<Api>) {
+    loop {
+        let (stream, source) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                debug!("api accept failed, {}", err);
+                continue;
+            }
+        };
+
+        let api = api.clone();
+        tokio::spawn(async move {
+            let result = match api.tls.as_ref() {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) if !api.allows_client(stream.get_ref().1.peer_certificates()) => Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "client certificate not allowed",
+                    )),
+                    Ok(stream) => handle(stream, source, &api).await,
+                    Err(err) => Err(err),
+                },
+                None => handle(stream, source, &api).await,
+            };
+            if let Err(err) = result {
+                debug!("api request of {} failed, {}", source, err);
+            }
+        });
+    }
+}
+
+/// The verifier of the client certificates issued by the CAs of `auth`.
+fn client_verifier(auth: &ClientAuth) -> anyhow::Result<rustls::server::AllowAnyAuthenticatedClient> {
+    let certs = rustls_pemfile::certs(&mut io::BufReader::new(std::fs::File::open(&auth.ca)?))
+        .with_context(|| format!("Invalid certificate {:?}", auth.ca))?;
+    let mut roots = rustls::RootCertStore::empty();
+    if roots.add_parsable_certificates(&certs).0 == 0 {
+        bail!("No CA certificate in {:?}", auth.ca);
+    }
+
+    let verifier = rustls::server::AllowAnyAuthenticatedClient::new(roots);
+    let Some(path) = auth.crl.as_ref() else {
+        return Ok(verifier);
+    };
+    let crl = std::fs::read(path)?;
+    let crls = if crl.starts_with(b"-----BEGIN") {
+        rustls_pemfile::crls(&mut crl.as_slice()).with_context(|| format!("Invalid revocation list {:?}", path))?
+    } else {
+        vec![crl]
+    };
+    if crls.is_empty() {
+        bail!("No revocation list in {:?}", path);
+    }
+    verifier
+        .with_crls(crls.into_iter().map(rustls::server::UnparsedCertRevocationList))
+        .map_err(rustls::Error::from)
+        .with_context(|| format!("Invalid revocation list {:?}", path))
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    /// names in lowercase
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn parse(head: &str) -> Option<Self> {
+        let mut lines = head.lines();
+        let mut request_line = lines.next()?.split_whitespace();
+        let method = request_line.next()?.to_owned();
+        let target = request_line.next()?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let headers = lines
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_owned()))
+            .collect();
+        Some(Self {
+            method,
+            path: path.to_owned(),
+            query: query.to_owned(),
+            headers,
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| value)
+    }
+
+    /// The `Sec-WebSocket-Key` of an upgrade to a websocket.
+    fn websocket_key(&self) -> Option<&str> {
+        let upgrade = self
+            .header("upgrade")
+            .is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+        upgrade.then(|| self.header("sec-websocket-key")).flatten()
+    }
+}
+
+/// What a websocket pushes.
+enum Feed {
+    Traffic {
+        interval: Interval,
+        last: TrafficStats,
+    },
+    Logs {
+        lines: tokio::sync::broadcast::Receiver<LogLine>,
+        level: Level,
+    },
+}
+
+impl Feed {
+    fn traffic() -> Self {
+        let mut interval = tokio::time::interval_at(Instant::now() + TRAFFIC_INTERVAL, TRAFFIC_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self::Traffic {
+            interval,
+            last: traffic::total().stats(),
+        }
+    }
+
+    fn logs(level: Level) -> Self {
+        Self::Logs {
+            lines: log_lines(),
+            level,
+        }
+    }
+
+    /// The next message, cancel safe.
+    async fn next(&mut self) -> String {
+        match self {
+            Self::Traffic { interval, last } => {
+                interval.tick().await;
+                let now = traffic::total().stats();
+                let message = serde_json::json!({
+                    "up": now.upload - last.upload,
+                    "down": now.download - last.download,
+                });
+                *last = now;
+                message.to_string()
+            }
+            Self::Logs { lines, level } => loop {
+                match lines.recv().await {
+                    Ok(line) if line.level <= *level => {
+                        let kind = match line.level {
+                            Level::ERROR => "error",
+                            Level::WARN => "warning",
+                            Level::INFO => "info",
+                            _ => "debug",
+                        };
+                        return serde_json::json!({"type": kind, "payload": line.message}).to_string();
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => std::future::pending().await,
+                }
+            },
+        }
+    }
+}
+
+async fn handle<S>(mut stream: S, source: SocketAddr, api: &Api) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut buf = Vec::with_capacity(512);
+    let head_len = tokio::time::timeout(REQUEST_TIMEOUT, http_head::read(&mut stream, &mut buf, MAX_REQUEST_LEN))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))??;
+    let Some(req) = Request::parse(&String::from_utf8_lossy(&buf[..head_len])) else {
+        return respond(&mut stream, "400 Bad Request", "", "").await;
+    };
+    let cors = api.cors_headers(&req);
+
+    // preflights carry no credentials
+    if req.method == "OPTIONS" {
+        return respond(&mut stream, "204 No Content", &cors, "").await;
+    }
+    if !api.authorized(&req) {
+        debug!("unauthorized api request of {}", source);
+        return respond(&mut stream, "401 Unauthorized", &cors, r#"{"message":"Unauthorized"}"#).await;
+    }
+
+    let feed = match (req.method.as_str(), req.path.as_str()) {
+        ("GET", "/") => return respond(&mut stream, "200 OK", &cors, r#"{"hello":"swiftlink"}"#).await,
+        ("GET", "/version") => {
+            let body = serde_json::json!({ "version": crate::version() }).to_string();
+            return respond(&mut stream, "200 OK", &cors, &body).await;
+        }
+        ("GET", "/configs") => return respond(&mut stream, "200 OK", &cors, &api.config).await,
+        ("GET", "/proxies") => return respond(&mut stream, "200 OK", &cors, &api.proxies()).await,
+        (method, path) if path.starts_with(PROXIES_PREFIX) && !path.ends_with(SPEEDTEST_SUFFIX) => {
+            let name = percent_decode(&path[PROXIES_PREFIX.len()..]);
+            let Some(group) = name.and_then(|name| api.proxy_groups.get(&name)) else {
+                let body = r#"{"message":"Proxy group not found"}"#;
+                return respond(&mut stream, "404 Not Found", &cors, body).await;
+            };
+            if method != "PUT" {
+                return respond(&mut stream, "405 Method Not Allowed", &cors, "").await;
+            }
+            let body = tokio::time::timeout(REQUEST_TIMEOUT, read_body(&mut stream, &req, buf.split_off(head_len)))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))??;
+            let selection = serde_json::from_slice::<Selection>(&body)
+                .map_err(|err| format!("Invalid selection, {}", err))
+                .and_then(|selection| select_proxy(group, &selection.name));
+            return match selection {
+                Ok(()) => respond(&mut stream, "204 No Content", &cors, "").await,
+                Err(message) => {
+                    let body = serde_json::json!({ "message": message }).to_string();
+                    respond(&mut stream, "400 Bad Request", &cors, &body).await
+                }
+            };
+        }
+        (method, path) if path.starts_with(PROXIES_PREFIX) => {
+            let name = path[PROXIES_PREFIX.len()..]
+                .strip_suffix(SPEEDTEST_SUFFIX)
+                .and_then(percent_decode);
+            let tester = api.speed_tester.as_ref();
+            let Some((tester, name)) = tester.zip(name).filter(|(tester, name)| tester.has_proxy(name)) else {
+                let body = r#"{"message":"Proxy not found"}"#;
+                return respond(&mut stream, "404 Not Found", &cors, body).await;
+            };
+            if method != "GET" {
+                return respond(&mut stream, "405 Method Not Allowed", &cors, "").await;
+            }
+            return match tester.run(&name).await {
+                Ok(record) => {
+                    let body = serde_json::to_string(&record).unwrap_or_default();
+                    respond(&mut stream, "200 OK", &cors, &body).await
+                }
+                Err(err) => {
+                    let body = serde_json::json!({ "message": format!("{:#}", err) }).to_string();
+                    respond(&mut stream, "503 Service Unavailable", &cors, &body).await
+                }
+            };
+        }
+        ("GET", "/rules") => {
+            let body = serde_json::json!({ "rules": api.rule_hits.snapshot() }).to_string();
+            return respond(&mut stream, "200 OK", &cors, &body).await;
+        }
+        ("GET", "/stats/top") => {
+            return match top_talkers(&api.talkers, &req) {
+                Ok(body) => respond(&mut stream, "200 OK", &cors, &body).await,
+                Err(message) => {
+                    let body = serde_json::json!({ "message": message }).to_string();
+                    respond(&mut stream, "400 Bad Request", &cors, &body).await
+                }
+            };
+        }
+        ("GET", "/stats/users") => {
+            let users: Vec<_> = api
+                .user_traffic
+                .snapshot()
+                .into_iter()
+                .map(|(user, stats)| {
+                    serde_json::json!({
+                        "user": user,
+                        "upload": stats.upload,
+                        "download": stats.download,
+                        "connections": stats.connections,
+                    })
+                })
+                .collect();
+            let body = serde_json::json!({ "users": users }).to_string();
+            return respond(&mut stream, "200 OK", &cors, &body).await;
+        }
+        ("GET", "/connections/closed") => {
+            return match closed_connections(&api.connections, &req) {
+                Ok(body) => respond(&mut stream, "200 OK", &cors, &body).await,
+                Err(message) => {
+                    let body = serde_json::json!({ "message": message }).to_string();
+                    respond(&mut stream, "400 Bad Request", &cors, &body).await
+                }
+            };
+        }
+        ("GET", "/watchdog") => {
+            let Some(watchdog) = watchdog::watchdog() else {
+                let body = r#"{"message":"Watchdog is disabled"}"#;
+                return respond(&mut stream, "404 Not Found", &cors, body).await;
+            };
+            let body = serde_json::to_string(&watchdog.state()).unwrap_or_default();
+            return respond(&mut stream, "200 OK", &cors, &body).await;
+        }
+        #[cfg(feature = "chaos")]
+        (method, "/chaos") => {
+            return match method {
+                "GET" => {
+                    let body = serde_json::json!({ "faults": chaos::current() }).to_string();
+                    respond(&mut stream, "200 OK", &cors, &body).await
+                }
+                "PUT" => {
+                    let body =
+                        tokio::time::timeout(REQUEST_TIMEOUT, read_body(&mut stream, &req, buf.split_off(head_len)))
+                            .await
+                            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))??;
+                    let faults = serde_json::from_slice(&body).map_err(|err| format!("Invalid faults, {}", err));
+                    match faults.and_then(|faults| chaos::set(faults).map_err(|err| err.to_string())) {
+                        Ok(()) => respond(&mut stream, "204 No Content", &cors, "").await,
+                        Err(message) => {
+                            let body = serde_json::json!({ "message": message }).to_string();
+                            respond(&mut stream, "400 Bad Request", &cors, &body).await
+                        }
+                    }
+                }
+                "DELETE" => {
+                    chaos::clear();
+                    respond(&mut stream, "204 No Content", &cors, "").await
+                }
+                _ => respond(&mut stream, "405 Method Not Allowed", &cors, "").await,
+            };
+        }
+        ("GET", "/dns/failures") => {
+            return match dns_failures(&api.dns_failures, &req) {
+                Ok(body) => respond(&mut stream, "200 OK", &cors, &body).await,
+                Err(message) => {
+                    let body = serde_json::json!({ "message": message }).to_string();
+                    respond(&mut stream, "400 Bad Request", &cors, &body).await
+                }
+            };
+        }
+        ("GET", "/fakeip/mappings") => {
+            let Some(fakedns) = api.fakedns.as_deref() else {
+                let body = r#"{"message":"Fake ip is disabled"}"#;
+                return respond(&mut stream, "404 Not Found", &cors, body).await;
+            };
+            return match fakeip_mappings(fakedns, &req) {
+                Ok(body) => respond(&mut stream, "200 OK", &cors, &body).await,
+                Err(message) => {
+                    let body = serde_json::json!({ "message": message }).to_string();
+                    respond(&mut stream, "400 Bad Request", &cors, &body).await
+                }
+            };
+        }
+        ("GET", "/providers/rules") => {
+            let providers: BTreeMap<_, _> = api
+                .rule_providers
+                .iter()
+                .map(|(name, provider)| (name, RuleProviderInfo::new(provider)))
+                .collect();
+            let body = serde_json::json!({ "providers": providers }).to_string();
+            return respond(&mut stream, "200 OK", &cors, &body).await;
+        }
+        ("GET", "/providers/proxies") => {
+            let provider = serde_json::json!({
+                "name": "default",
+                "type": "Proxy",
+                "vehicleType": "Compatible",
+                "proxies": proxy_tree::tree(&api.proxy_servers, &api.proxy_groups),
+            });
+            let body = serde_json::json!({ "providers": { "default": provider } }).to_string();
+            return respond(&mut stream, "200 OK", &cors, &body).await;
+        }
+        (method, path) if path.starts_with(RULE_PROVIDERS_PREFIX) => {
+            let name = percent_decode(&path[RULE_PROVIDERS_PREFIX.len()..]);
+            let Some(provider) = name.and_then(|name| api.rule_providers.get(&name)) else {
+                let body = r#"{"message":"Rule provider not found"}"#;
+                return respond(&mut stream, "404 Not Found", &cors, body).await;
+            };
+            return match method {
+                "GET" => {
+                    let body = serde_json::to_string(&RuleProviderInfo::new(provider)).unwrap_or_default();
+                    respond(&mut stream, "200 OK", &cors, &body).await
+                }
+                "PUT" => match provider.update(&api.connect_opts).await {
+                    Ok(_) => respond(&mut stream, "204 No Content", &cors, "").await,
+                    Err(err) => {
+                        warn!("rule provider {} update failed, {:#}", provider.name(), err);
+                        let body = serde_json::json!({ "message": format!("{:#}", err) }).to_string();
+                        respond(&mut stream, "503 Service Unavailable", &cors, &body).await
+                    }
+                },
+                _ => respond(&mut stream, "405 Method Not Allowed", &cors, "").await,
+            };
+        }
+        ("GET", "/traffic") => Feed::traffic(),
+        ("GET", "/logs") => {
+            let level = match req.query("level").unwrap_or("info") {
+                "debug" => Level::DEBUG,
+                "info" => Level::INFO,
+                "warning" => Level::WARN,
+                "error" => Level::ERROR,
+                _ => return respond(&mut stream, "400 Bad Request", &cors, r#"{"message":"Invalid level"}"#).await,
+            };
+            Feed::logs(level)
+        }
+        (
+            _,
+            "/"
+            | "/version"
+            | "/configs"
+            | "/proxies"
+            | "/rules"
+            | "/stats/top"
+            | "/stats/users"
+            | "/connections/closed"
+            | "/watchdog"
+            | "/dns/failures"
+            | "/providers/rules"
+            | "/fakeip/mappings"
+            | "/traffic"
+            | "/logs",
+        ) => return respond(&mut stream, "405 Method Not Allowed", &cors, "").await,
+        _ => return respond(&mut stream, "404 Not Found", &cors, r#"{"message":"Not Found"}"#).await,
+    };
+
+    let Some(key) = req.websocket_key() else {
+        return respond(&mut stream, "426 Upgrade Required", &cors, "").await;
+    };
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n{}\r\n",
+        websocket::accept_key(key),
+        cors
+    );
+    stream.write_all(response.as_bytes()).await?;
+    push(stream, feed).await
+}
+
+/// The body of `PUT /proxies/{group}`.
+#[derive(Deserialize)]
+struct Selection {
+    name: String,
+}
+
+/// Chooses `proxy` in `group`, or tells why it can't be.
+fn select_proxy(group: &ProxyGroup, proxy: &str) -> Result<(), String> {
+    if group.tp() != ProxyGroupType::Select {
+        return Err(format!("Proxy group {} is not a select group", group.name()));
+    }
+    if !group.select(proxy) {
+        return Err(format!("{} is not a proxy of group {}", proxy, group.name()));
+    }
+    Ok(())
+}
+
+/// The body of `/fakeip/mappings`, or why the query is invalid.
+fn top_talkers(talkers: &TalkerStats, req: &Request) -> Result<String, &'static str> {
+    let window = match req.query("window").map(str::parse::<u64>) {
+        None => TOP_TALKERS_WINDOW,
+        Some(Ok(secs)) if secs > 0 => Duration::from_secs(secs),
+        Some(_) => return Err("Invalid window"),
+    };
+    let limit = match req.query("limit").map(str::parse::<usize>) {
+        None => TOP_TALKERS_LEN,
+        Some(Ok(limit)) if limit > 0 => limit,
+        Some(_) => return Err("Invalid limit"),
+    };
+    Ok(serde_json::to_string(&talkers.top(window, limit)).unwrap_or_default())
+}
+
+/// The body of `/connections/closed`, or why the query is invalid.
+fn closed_connections(connections: &ConnectionHistory, req: &Request) -> Result<String, &'static str> {
+    let reason = req
+        .query("reason")
+        .map(str::parse)
+        .transpose()
+        .map_err(|_| "Invalid reason")?;
+    let limit = match req.query("limit").map(str::parse::<usize>) {
+        None => None,
+        Some(Ok(limit)) if limit > 0 => Some(limit),
+        Some(_) => return Err("Invalid limit"),
+    };
+    let query = HistoryQuery {
+        reason,
+        destination: req.query("destination").filter(|d| !d.is_empty()).map(str::to_owned),
+        limit,
+    };
+    Ok(serde_json::json!({ "connections": connections.query(&query) }).to_string())
+}
+
+/// The body of `/dns/failures`, or why the query is invalid.
+fn dns_failures(failures: &DnsFailures, req: &Request) -> Result<String, &'static str> {
+    let limit = match req.query("limit").map(str::parse::<usize>) {
+        None => DNS_FAILURES_LEN,
+        Some(Ok(limit)) if limit > 0 => limit,
+        Some(_) => return Err("Invalid limit"),
+    };
+    let failures = failures.recent(req.query("name").filter(|name| !name.is_empty()), limit);
+    Ok(serde_json::json!({ "failures": failures }).to_string())
+}
+
+/// A rule provider in the format of the Clash API.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RuleProviderInfo<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    tp: &'static str,
+    vehicle_type: &'static str,
+    behavior: &'static str,
+    rule_count: usize,
+    /// `None` while the file doesn't exist
+    #[serde(skip_serializing_if = "Option::is_none")]
+    updated_at: Option<String>,
+}
+
+impl<'a> RuleProviderInfo<'a> {
+    fn new(provider: &'a RuleProvider) -> Self {
+        Self {
+            name: provider.name(),
+            tp: "Rule",
+            vehicle_type: if provider.url().is_some() { "HTTP" } else { "File" },
+            behavior: match provider.behavior() {
+                RuleProviderBehavior::Domain => "Domain",
+                RuleProviderBehavior::IpCidr => "IPCIDR",
+                RuleProviderBehavior::Classical => "Classical",
+            },
+            rule_count: provider.rules().map_or(0, |rules| rules.len()),
+            updated_at: provider
+                .updated_at()
+                .map(|time| DateTime::<Local>::from(time).to_rfc3339_opts(SecondsFormat::Secs, false)),
+        }
+    }
+}
+
+/// Decodes the `%XX` escapes of a path segment, `None` if one is invalid or it's not UTF-8.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte != b'%' {
+            decoded.push(byte);
+            rest = tail;
+            continue;
+        }
+        let hex = tail.get(..2).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+        decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+        rest = &tail[2..];
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn fakeip_mappings(fakedns: &Mutex<FakeDns>, req: &Request) -> Result<String, &'static str> {
+    let offset = req
+        .query("offset")
+        .map_or(Ok(0), str::parse)
+        .map_err(|_| "Invalid offset")?;
+    let limit = match req.query("limit").map(str::parse::<usize>) {
+        None => FAKEIP_PAGE_LEN,
+        Some(Ok(limit)) if limit <= fakeip::MAX_PAGE_LEN => limit,
+        Some(_) => return Err("Invalid limit"),
+    };
+
+    let mappings = fakedns.lock().unwrap().mappings();
+    let mut mappings: Vec<_> = mappings.into_iter().map(|(ip, host)| Mapping { ip, host }).collect();
+    if let Some(query) = req.query("query") {
+        mappings.retain(|mapping| mapping.matches(query));
+    }
+    let page = MappingPage {
+        total: mappings.len(),
+        offset,
+        mappings: mappings.into_iter().skip(offset).take(limit).collect(),
+    };
+    Ok(serde_json::to_string(&page).unwrap_or_default())
+}
+
+/// Pushes the messages of `feed` to the websocket `stream` until either side closes it, answering
+/// and sending pings.
+async fn push<S>(stream: S, mut feed: Feed) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    // reading a frame isn't cancel safe, frames are passed on by a task of their own
+    let (frames_tx, mut frames) = mpsc::channel(8);
+    let read_task = tokio::spawn(async move {
+        loop {
+            let message = websocket::read_message(&mut reader).await;
+            let failed = message.is_err();
+            if frames_tx.send(message).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let mut ping = tokio::time::interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+    // whether the client sent anything since the last ping
+    let mut alive = true;
+    let result = loop {
+        tokio::select! {
+            message = frames.recv() => {
+                alive = true;
+                let reply = match message {
+                    Some(Ok(Message::Ping(payload))) => Message::Pong(payload),
+                    Some(Ok(Message::Close(_))) => {
+                        break websocket::write_message(&mut writer, &Message::Close(None)).await
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(err)) => break Err(err),
+                    None => break Ok(()),
+                };
+                if let Err(err) = websocket::write_message(&mut writer, &reply).await {
+                    break Err(err);
+                }
+            }
+            _ = ping.tick() => {
+                if !alive {
+                    break Err(io::Error::new(io::ErrorKind::TimedOut, "websocket ping timed out"));
+                }
+                alive = false;
+                if let Err(err) = websocket::write_message(&mut writer, &Message::Ping(vec![])).await {
+                    break Err(err);
+                }
+            }
+            message = feed.next() => {
+                if let Err(err) = websocket::write_message(&mut writer, &Message::Text(message)).await {
+                    break Err(err);
+                }
+            }
+        }
+    };
+    read_task.abort();
+    result
+}
+
+/// Reads the body of `req` as long as its `Content-Length`, empty without one. `buf` is what
+/// followed the head.
+async fn read_body<S>(stream: &mut S, req: &Request, mut buf: Vec<u8>) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let len = match req.header("content-length") {
+        Some(len) => len
+            .parse()
+            .ok()
+            .filter(|len| *len <= MAX_REQUEST_LEN)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid content length"))?,
+        None => 0,
+    };
+    let read = buf.len().min(len);
+    buf.resize(len, 0);
+    stream.read_exact(&mut buf[read..]).await?;
+    Ok(buf)
+}
+
+async fn respond<S>(stream: &mut S, status: &str, headers: &str, body: &str) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let content_type = match body {
+        "" => "",
+        _ => "Content-Type: application/json\r\n",
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\n{}{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        headers,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Compares the secret without an early exit, so response times don't tell how much matched.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use swiftlink_infra::connection::{CloseReason, ClosedConnection};
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    async fn request(addr: SocketAddr, head: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(head.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    /// A masked frame of a client.
+    fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![first, 0x80 | payload.len() as u8, 0, 0, 0, 0];
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_api_auth_and_cors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cors = ControllerCors {
+            allow_origins: vec!["https://dashboard.example.com".to_owned()],
+            allow_private_network: true,
+        };
+        let task = tokio::spawn(serve(listener, Arc::new(Api::new(Some("s3cr3t"), cors))));
+
+        let response = request(addr, "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        let response = request(addr, "GET / HTTP/1.1\r\nAuthorization: Bearer wrong\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        // the token is for websockets only
+        let response = request(addr, "GET /?token=s3cr3t HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+
+        let response = request(addr, "GET / HTTP/1.1\r\nauthorization: Bearer s3cr3t\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(r#"{"hello":"swiftlink"}"#));
+        assert!(!response.contains("Access-Control-Allow-Origin"));
+
+        let response = request(
+            addr,
+            "OPTIONS /traffic HTTP/1.1\r\nOrigin: https://dashboard.example.com\r\n\
+             Access-Control-Request-Private-Network: true\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(response.contains("Access-Control-Allow-Origin: https://dashboard.example.com\r\n"));
+        assert!(response.contains("Access-Control-Allow-Private-Network: true\r\n"));
+
+        let response = request(addr, "OPTIONS / HTTP/1.1\r\nOrigin: https://evil.example.com\r\n\r\n").await;
+        assert!(!response.contains("Access-Control-Allow-Origin"));
+
+        let response = request(addr, "GET /traffic HTTP/1.1\r\nAuthorization: Bearer s3cr3t\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        let response = request(
+            addr,
+            "GET /logs?level=trace HTTP/1.1\r\nAuthorization: Bearer s3cr3t\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        let response = request(addr, "DELETE /traffic HTTP/1.1\r\nAuthorization: Bearer s3cr3t\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405"));
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_api_websocket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(serve(
+            listener,
+            Arc::new(Api::new(Some("s3cr3t"), ControllerCors::default())),
+        ));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"GET /traffic?token=s3cr3t HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut head = vec![];
+        let head_len = http_head::read(&mut stream, &mut head, MAX_REQUEST_LEN).await.unwrap();
+        head.truncate(head_len);
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        // unmasked frames of the server
+        let mut frame = [0u8; 2];
+        stream.write_all(&client_frame(0x89, b"hi")).await.unwrap();
+        stream.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame, [0x8a, 2]);
+        stream.read_exact(&mut frame).await.unwrap();
+        assert_eq!(&frame, b"hi");
+
+        stream.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame[0], 0x81);
+        let mut text = vec![0u8; frame[1] as usize];
+        stream.read_exact(&mut text).await.unwrap();
+        let traffic: serde_json::Value = serde_json::from_slice(&text).unwrap();
+        assert!(traffic["up"].is_u64() && traffic["down"].is_u64());
+
+        stream.write_all(&client_frame(0x88, &[])).await.unwrap();
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.ends_with(&[0x88, 0]));
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_api_proxies() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let hk = ProxyMeta {
+            icon: Some("https://example.com/hk.png".to_owned()),
+            order: Some(1),
+            ..Default::default()
+        };
+        let hidden = ProxyMeta {
+            hidden: true,
+            ..Default::default()
+        };
+        let stats = Arc::new(ProxyStatsMap::default());
+        let history = stats.proxy("HK").history.record(None);
+        let speed = stats.proxy("HK").record_speed(1000, Duration::from_secs(1));
+        let config = Config::load(
+            r#"
+            [proxy_groups.api-test-auto]
+            type = "url-test"
+            proxies = ["HK", "JP"]
+
+            [proxy_groups.api-test-manual]
+            type = "select"
+            proxies = ["HK", "JP"]
+            "#,
+        )
+        .unwrap();
+        let groups = Arc::new(ProxyGroups::new(&config));
+        let api = Api::new(None, ControllerCors::default())
+            .with_proxies([
+                ("HK", &hk),
+                ("DIRECT", &hidden),
+                ("api-test-auto", &ProxyMeta::default()),
+            ])
+            .with_proxy_stats(stats)
+            .with_proxy_groups(groups.clone());
+        let task = tokio::spawn(serve(listener, Arc::new(api)));
+
+        let response = request(addr, "GET /proxies HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"proxies": [
+                {
+                    "name": "HK", "icon": "https://example.com/hk.png", "hidden": false, "order": 1,
+                    "history": [history], "speed": speed,
+                },
+                {"name": "DIRECT", "hidden": true, "history": []},
+                {
+                    "name": "api-test-auto", "hidden": false, "history": [],
+                    "type": "url-test", "now": "HK", "all": ["HK", "JP"],
+                },
+            ]})
+        );
+
+        let put = |name: &str, body: &str| {
+            format!(
+                "PUT /proxies/{} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                name,
+                body.len(),
+                body
+            )
+        };
+        let response = request(addr, &put("api-test-manual", r#"{"name":"JP"}"#)).await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", response);
+        assert_eq!(groups.get("api-test-manual").unwrap().now().as_deref(), Some("JP"));
+        for (name, body) in [
+            ("api-test-manual", r#"{"name":"SG"}"#),
+            ("api-test-manual", "{}"),
+            ("api-test-auto", r#"{"name":"JP"}"#),
+        ] {
+            let response = request(addr, &put(name, body)).await;
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+        }
+        let response = request(addr, &put("HK", r#"{"name":"JP"}"#)).await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = request(addr, "GET /proxies/api-test-manual HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+        // only the proxies of `dns.proxy_servers` can be tested
+        let response = request(addr, "GET /proxies/HK/speedtest HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_api_connections_and_users() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(ConnectionHistory::new(8));
+        for (id, destination, reason) in [
+            (1, "example.com:443", CloseReason::ClientEof),
+            (2, "example.com:80", CloseReason::DialRefused),
+            (3, "example.org:443", CloseReason::DialRefused),
+        ] {
+            connections.record(ClosedConnection {
+                id,
+                network: "tcp",
+                inbound: "socks".to_owned(),
+                source: "127.0.0.1:50000".parse().unwrap(),
+                destination: destination.to_owned(),
+                rule: None,
+                outbound: None,
+                dialed: None,
+                upload: 0,
+                download: 0,
+                started_at: 0,
+                duration_ms: 0,
+                reason,
+                error: None,
+            });
+        }
+        let user_traffic = Arc::new(UserTraffic::default());
+        let alice = user_traffic.user("alice");
+        alice.connected();
+        alice.add_upload(512);
+        alice.add_download(4096);
+        let api = Api::new(None, ControllerCors::default())
+            .with_connections(connections)
+            .with_user_traffic(user_traffic);
+        let task = tokio::spawn(serve(listener, Arc::new(api)));
+
+        let ids = |response: String| -> Vec<u64> {
+            let body = response.split_once("\r\n\r\n").unwrap().1;
+            let body: serde_json::Value = serde_json::from_str(body).unwrap();
+            let connections = body["connections"].as_array().unwrap();
+            connections.iter().map(|conn| conn["id"].as_u64().unwrap()).collect()
+        };
+        let response = request(addr, "GET /connections/closed HTTP/1.1\r\n\r\n").await;
+        assert_eq!(ids(response), [3, 2, 1]);
+        let head = "GET /connections/closed?reason=dial_refused&destination=example.com HTTP/1.1\r\n\r\n";
+        assert_eq!(ids(request(addr, head).await), [2]);
+        let head = "GET /connections/closed?reason=dial_refused&limit=1 HTTP/1.1\r\n\r\n";
+        assert_eq!(ids(request(addr, head).await), [3]);
+        for query in ["reason=refused", "limit=0"] {
+            let head = format!("GET /connections/closed?{} HTTP/1.1\r\n\r\n", query);
+            let response = request(addr, &head).await;
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", query);
+        }
+
+        let response = request(addr, "GET /stats/users HTTP/1.1\r\n\r\n").await;
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"users": [
+                {"user": "alice", "upload": 512, "download": 4096, "connections": 1},
+            ]})
+        );
+
+        task.abort();
+    }
+
+    #[cfg(feature = "chaos")]
+    #[tokio::test]
+    async fn test_api_chaos() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(serve(listener, Arc::new(Api::new(None, ControllerCors::default()))));
+
+        let body = r#"{"latency_rate":0.5,"latency_ms":800}"#;
+        let head = format!("PUT /chaos HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let response = request(addr, &head).await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        let response = request(addr, "GET /chaos HTTP/1.1\r\n\r\n").await;
+        let body: serde_json::Value = serde_json::from_str(response.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(body["faults"]["latency_ms"], 800);
+
+        let body = r#"{"drop_rate":2}"#;
+        let head = format!("PUT /chaos HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let response = request(addr, &head).await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let response = request(addr, "DELETE /chaos HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert_eq!(chaos::current(), None);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_api_rule_providers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let dir = std::env::temp_dir().join(format!("swiftlink-api-rule-providers-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("my ads.txt");
+        std::fs::write(&path, "ads.example.com\n").unwrap();
+        let provider = RuleProvider::new("my ads", RuleProviderBehavior::Domain, &path);
+        provider.reload().unwrap();
+        let providers = HashMap::from([("my ads".to_owned(), Arc::new(provider))]);
+        let api = Api::new(None, ControllerCors::default()).with_rule_providers(providers, ConnectOpts::default());
+        let task = tokio::spawn(serve(listener, Arc::new(api)));
+
+        let response = request(addr, "GET /providers/rules HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body: serde_json::Value = serde_json::from_str(response.split_once("\r\n\r\n").unwrap().1).unwrap();
+        let info = &body["providers"]["my ads"];
+        assert_eq!(info["type"], "Rule");
+        assert_eq!(info["vehicleType"], "File");
+        assert_eq!(info["behavior"], "Domain");
+        assert_eq!(info["ruleCount"], 1);
+        assert!(info["updatedAt"].is_string());
+
+        // the file was edited, an update loads it
+        std::fs::write(&path, "ads.example.com\ntracker.example.com\n").unwrap();
+        let response = request(addr, "PUT /providers/rules/my%20ads HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", response);
+        let response = request(addr, "GET /providers/rules/my%20ads HTTP/1.1\r\n\r\n").await;
+        let body: serde_json::Value = serde_json::from_str(response.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(body["ruleCount"], 2);
+
+        // an invalid set is an error, the loaded one is kept
+        std::fs::remove_file(&path).unwrap();
+        let response = request(addr, "PUT /providers/rules/my%20ads HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+        let response = request(addr, "GET /providers/rules/my%20ads HTTP/1.1\r\n\r\n").await;
+        assert!(response.contains(r#""ruleCount":2"#), "{}", response);
+
+        let response = request(addr, "PUT /providers/rules/ads HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = request(addr, "PUT /providers/rules/my%2 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = request(addr, "DELETE /providers/rules/my%20ads HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405"));
+        let response = request(addr, "POST /providers/rules HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405"));
+
+        task.abort();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("ads").as_deref(), Some("ads"));
+        assert_eq!(percent_decode("my%20ads%2Bmore").as_deref(), Some("my ads+more"));
+        assert_eq!(percent_decode("%E5%B9%BF%E5%91%8A").as_deref(), Some("广告"));
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%+1"), None);
+        assert_eq!(percent_decode("%FF"), None);
+    }
+
+    /// Self-signed, subject alternative names `dashboard.example.com` and `192.0.2.10`
+    const CLIENT_CERT: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIBuzCCAWGgAwIBAgIUDaO0c6Lyne3awPAh/JAGma9+oVQwCgYIKoZIzj0EAwIw
+FDESMBAGA1UEAwwJZGFzaGJvYXJkMCAXDTI2MTAxNjEyNDYzMFoYDzIxMjYwOTIy
+MTI0NjMwWjAUMRIwEAYDVQQDDAlkYXNoYm9hcmQwWTATBgcqhkjOPQIBBggqhkjO
+PQMBBwNCAAT1fHSONKBtDgr7P8fOwgsfywMBj6nvCldqEdTBVe1P6M4a9BGPzxTU
+I2sTkFlS3X53g3ji48q42VYePuLZNvJeo4GOMIGLMB0GA1UdDgQWBBQx2mBA+58H
+qKyKvLQLZTSkXpvJTzAfBgNVHSMEGDAWgBQx2mBA+58HqKyKvLQLZTSkXpvJTzAm
+BgNVHREEHzAdghVkYXNoYm9hcmQuZXhhbXBsZS5jb22HBMAAAgowEwYDVR0lBAww
+CgYIKwYBBQUHAwIwDAYDVR0TAQH/BAIwADAKBggqhkjOPQQDAgNIADBFAiEAjkAD
+xtnKtSRLtNd6T5KgtZc3hE9MfUXN03vr9WqV11wCICEhnfCm3ULq/yvudRzZwp5x
++9wx5yjWSihUD3T2XjCQ
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn test_api_client_auth() {
+        let dir = std::env::temp_dir().join("swiftlink-api-client-auth-test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca = dir.join("ca.crt");
+        std::fs::write(&ca, CLIENT_CERT).unwrap();
+        let mut auth = ClientAuth::new(&ca);
+        assert!(client_verifier(&auth).is_ok());
+        auth.crl = Some(ca.clone());
+        assert!(client_verifier(&auth).is_err());
+        assert!(client_verifier(&ClientAuth::new(dir.join("missing.crt"))).is_err());
+
+        let cert = rustls::Certificate(rustls_pemfile::certs(&mut CLIENT_CERT.as_bytes()).unwrap().remove(0));
+        let certs = Some(std::slice::from_ref(&cert));
+        let mut api = Api::new(None, ControllerCors::default());
+        assert!(api.allows_client(None));
+        api.client_names = vec!["other.example.com".to_owned()];
+        assert!(!api.allows_client(certs));
+        assert!(!api.allows_client(None));
+        api.client_names.push("192.0.2.10".to_owned());
+        assert!(api.allows_client(certs));
+        api.client_names = vec!["DASHBOARD.example.com".to_owned()];
+        assert!(api.allows_client(certs));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq("s3cr3t", "s3cr3t"));
+        assert!(!constant_time_eq("s3cr3t", "s3cr3T"));
+        assert!(!constant_time_eq("s3cr3t", "s3cr3"));
+    }
+}
@@ -1,45 +1,127 @@
-use std::{
-    collections::HashMap,
-    path::PathBuf,
-    sync::{Arc, Mutex},
-    time::Duration,
-};
+use std::{fmt, panic::AssertUnwindSafe, path::PathBuf, time::Duration};
 
 use anyhow::Context;
-use futures_util::future::join_all;
-use tokio::{runtime::Runtime, sync::RwLock};
-
-use swiftlink_dns::build_dns_resolver;
-use swiftlink_dns::{ServerHandle, ServerHandleBuilder};
-use swiftlink_infra::{
-    bind_to,
-    cachefile::CacheFile,
-    log::{self, *},
-    net::ConnectOpts,
-    udp, Listener,
+use tokio::runtime::Runtime;
+
+use swiftlink_infra::{log, sandbox::SandboxMode};
+
+use crate::{
+    error::Error,
+    instance::{Instance, TaskFailure},
+    layout, rt,
 };
 
-use crate::{config::Config, context::AppContext, rt};
+/// Why [`App::bootstrap`] returned, the process exits with [`ShutdownReason::exit_code`].
+#[derive(Debug)]
+pub enum ShutdownReason {
+    /// A termination signal
+    Signal,
+    /// A [`ShutdownTrigger`](crate::ShutdownTrigger) fired
+    Requested,
+    /// A new process took over the listeners
+    Upgraded,
+    /// The configuration or the paths it refers to are invalid
+    ConfigError(anyhow::Error),
+    /// A listener couldn't be bound
+    BindError(anyhow::Error),
+    /// A server task stopped on its own or panicked
+    TaskFailed(TaskFailure),
+    /// The main loop panicked
+    Panic(String),
+}
+
+impl ShutdownReason {
+    /// Classifies an error of [`App::new`] or [`Instance::start`].
+    pub fn from_start_error(err: anyhow::Error) -> Self {
+        match err.downcast_ref::<Error>() {
+            Some(Error::RegisterListenerFailed(..)) => Self::BindError(err),
+            _ => Self::ConfigError(err),
+        }
+    }
+
+    /// `0` for a requested shutdown, otherwise the `sysexits.h` code closest to the cause, and
+    /// `101` like an uncaught Rust panic.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Signal | Self::Requested | Self::Upgraded => 0,
+            // EX_CONFIG
+            Self::ConfigError(_) => 78,
+            // EX_OSERR
+            Self::BindError(_) => 71,
+            // EX_SOFTWARE
+            Self::TaskFailed(_) => 70,
+            Self::Panic(_) => 101,
+        }
+    }
+
+    /// A stable name for logs and monitoring.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Signal => "signal",
+            Self::Requested => "requested",
+            Self::Upgraded => "upgraded",
+            Self::ConfigError(_) => "config_error",
+            Self::BindError(_) => "bind_error",
+            Self::TaskFailed(_) => "task_failed",
+            Self::Panic(_) => "panic",
+        }
+    }
+}
+
+impl fmt::Display for ShutdownReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Signal => write!(f, "terminated by signal"),
+            Self::Requested => write!(f, "shutdown requested"),
+            Self::Upgraded => write!(f, "upgraded to a new process"),
+            Self::ConfigError(err) => write!(f, "configuration error: {:#}", err),
+            Self::BindError(err) => write!(f, "bind error: {:#}", err),
+            Self::TaskFailed(failure) => write!(f, "{}", failure),
+            Self::Panic(msg) => write!(f, "panicked: {}", msg),
+        }
+    }
+}
 
+/// The swiftlink binary: an [`Instance`] on its own runtime, stopped by a termination signal.
+///
+/// `SIGUSR2` hands the listening sockets over to a newly started process of the (replaced)
+/// executable and stops once it serves.
+///
+/// With `sandbox = true` file access is restricted before the runtime starts, syscalls once the
+/// instance serves. The process started on an upgrade keeps the sandbox it inherited.
 pub struct App {
-    config: Arc<Config>,
-    context: AppContext,
-    listener_map: Arc<RwLock<HashMap<Listener, ServerTasks>>>,
+    instance: Instance,
     runtime: Runtime,
+    sandbox: Option<SandboxMode>,
     guard: AppGuard,
 }
 
 impl App {
-    pub fn new(config_path: PathBuf, home_dir: PathBuf) -> anyhow::Result<Self> {
-        let config = Arc::new(
-            Config::load_from_file(&config_path)
-                .with_context(|| format!("Error while loading config file: {:?}", config_path))?,
-        );
+    /// Without `config_path` the configuration is taken from the `SWIFTLINK_*` environment
+    /// variables, see [`Config::load_from_env`](crate::Config::load_from_env).
+    pub fn new(config_path: Option<PathBuf>, home_dir: PathBuf) -> anyhow::Result<Self> {
+        let builder = match config_path {
+            Some(path) => Instance::builder().config_file(path),
+            None => Instance::builder().config(crate::Config::load_from_env()?),
+        };
+        let instance = builder.home_dir(home_dir).build()?;
+
+        let config = instance.config();
+        let home_dir = instance.home_dir();
+
+        if let Some(check) = layout::check_log(config, home_dir) {
+            // the default directory falls back to logging to the console
+            if config.log_file_configured() {
+                layout::verify([check])?;
+            } else if !check.is_ok() {
+                log::warn!("{}, logging to the console only", check);
+            }
+        }
 
         let guard = {
             let log_guard = if config.log_enabled() {
                 Some(log::init_global_default(
-                    config.log_file(),
+                    config.log_file(home_dir),
                     config.log_level(),
                     config.log_filter(),
                     config.log_size(),
@@ -55,125 +137,81 @@ impl App {
 
         config.summary();
 
-        // initialize cachefile
-        if let Err(err) = CacheFile::with_cache_dir(home_dir.join("cachedb")) {
-            warn!("Failed to initialize cachefile: {:?}", err);
+        // before the runtime spawns its threads, they inherit the restrictions
+        let sandbox = config.sandbox();
+        if let Some(mode) = sandbox {
+            #[cfg(target_os = "linux")]
+            swiftlink_infra::sandbox::restrict_fs(&config.sandbox_fs_rules(home_dir), mode)
+                .context("Failed to sandbox file access")?;
+            #[cfg(not(target_os = "linux"))]
+            log::warn!("sandbox ({:?}) is only supported on Linux", mode);
         }
 
-        let runtime = rt::build();
-        let listener_map: Arc<RwLock<HashMap<Listener, ServerTasks>>> = Default::default();
-        let mut context = AppContext::default();
-
-        let mut connect_opts: ConnectOpts = Default::default();
-        connect_opts.bind_interface = config.interface_name().map(|s| s.to_owned());
-
-        {
-            let dns = config.dns();
-            if dns.enabled() {
-                if dns.fakeip() {
-                    use swiftlink_infra::fakedns::{Config, FakeDns};
-
-                    let mut conf = Config::default();
-                    conf.persist = dns.fakeip_persist();
-
-                    // using memory cache
-                    if !dns.fakeip_persist() {
-                        conf.size = dns.fakeip_size().unwrap_or(2048);
-                    }
-
-                    let (ipv4_range, ipv6_range) = dns.fakeip_range();
-                    if let Some(ipv4_range) = ipv4_range {
-                        conf.ipnet = ipv4_range;
-                    }
-                    if let Some(ipv6_range) = ipv6_range {
-                        conf.ipnet6 = ipv6_range;
-                    }
-
-                    // TODO: fakeip filter
-                    let fakedns = Arc::new(Mutex::new(FakeDns::new(conf)));
-                    context.set_fakedns(fakedns);
-                }
-            }
-
-            runtime.block_on(async {
-                let dns_resolver = build_dns_resolver(&dns, &connect_opts).await;
-
-                // register local dns server
-                let listener = dns.listen();
-                let mut builder = ServerHandleBuilder::new(dns.clone(), dns_resolver.into());
-                if let Some(fakedns) = context.fakedns() {
-                    builder = builder.with_fakedns(fakedns);
-                }
-                let server_handle = builder.build();
-
-                let udp_socket = bind_to(udp, listener.sock_addr(), listener.device(), "UDP");
-                let mut server = swiftlink_dns::ServerFuture::new(server_handle);
-                server.register_socket(udp_socket);
-
-                if let Some(mut prev_server) = listener_map
-                    .write()
-                    .await
-                    .insert(listener.clone(), ServerTasks::Dns(server))
-                {
-                    tokio::spawn(async move {
-                        let _ = prev_server.shutdown(Duration::from_secs(5)).await;
-                    });
-                }
-            });
-        }
+        let runtime = rt::build(config.single_threaded());
 
         Ok(Self {
-            config,
-            context,
-            listener_map,
+            instance,
             runtime,
+            sandbox,
             guard,
         })
     }
 
-    pub fn bootstrap(self) {
-        // Raise `nofile` limit on Linux/MacOS
-        fdlimit::raise_fd_limit();
+    /// Serves until a signal, a shutdown request, an upgrade or a fatal error.
+    pub fn bootstrap(self) -> ShutdownReason {
+        let App {
+            instance,
+            runtime,
+            sandbox,
+            guard: _guard,
+        } = self;
 
-        info!("server starting up");
+        let shutdown_timeout = Duration::from_secs(5);
+        let serve = async move {
+            let handle = match instance.start().await {
+                Ok(handle) => handle,
+                Err(err) => return ShutdownReason::from_start_error(err),
+            };
 
-        let listeners = self.listener_map.clone();
+            #[cfg(target_os = "linux")]
+            if let Some(mode) = sandbox {
+                use swiftlink_infra::sandbox::restrict_syscalls;
 
-        let shutdown_timeout = Duration::from_secs(5);
-        self.runtime.block_on(async move {
-            let _ = swiftlink_infra::signal::shutdown().await;
-
-            let mut listeners = listeners.write().await;
-            let shutdown_tasks = listeners.iter_mut().map(|(_, server)| async move {
-                match server.shutdown(shutdown_timeout).await {
-                    Ok(_) => (),
-                    Err(err) => warn!("{:?}", err),
+                if let Err(err) = restrict_syscalls(mode).context("Failed to sandbox syscalls") {
+                    handle.shutdown(shutdown_timeout).await;
+                    return ShutdownReason::ConfigError(err);
+                }
+            }
+            #[cfg(not(target_os = "linux"))]
+            let _ = sandbox;
+
+            let reason = loop {
+                tokio::select! {
+                    _ = swiftlink_infra::signal::shutdown() => break ShutdownReason::Signal,
+                    _ = handle.wait_for_shutdown() => break ShutdownReason::Requested,
+                    failure = handle.wait_for_failure() => break ShutdownReason::TaskFailed(failure),
+                    _ = swiftlink_infra::signal::upgrade() => {
+                        #[cfg(unix)]
+                        match handle.upgrade().await {
+                            Ok(_) => break ShutdownReason::Upgraded,
+                            Err(err) => log::error!("Failed to upgrade: {:?}", err),
+                        }
+                    }
                 }
-            });
+            };
+
+            handle.shutdown(shutdown_timeout).await;
+            reason
+        };
 
-            join_all(shutdown_tasks).await;
-        });
+        let reason = std::panic::catch_unwind(AssertUnwindSafe(|| runtime.block_on(serve)))
+            .unwrap_or_else(|panic| ShutdownReason::Panic(crate::panic_message(&*panic).to_owned()));
 
-        self.runtime.shutdown_timeout(shutdown_timeout);
+        runtime.shutdown_timeout(shutdown_timeout);
+        reason
     }
 }
 
 struct AppGuard {
     log_guard: Option<tracing::dispatcher::DefaultGuard>,
 }
-
-enum ServerTasks {
-    Dns(swiftlink_dns::ServerFuture<ServerHandle>),
-    // Inbound(inbound::InboundServerHandle),
-}
-
-impl ServerTasks {
-    async fn shutdown(&mut self, _shutdown_timeout: Duration) -> Result<(), anyhow::Error> {
-        match self {
-            ServerTasks::Dns(s) => {
-                let _ = s.shutdown_gracefully().await;
-                Ok(())
-            } // _ => Ok(()),
-        }
-    }
-}
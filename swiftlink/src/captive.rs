@@ -0,0 +1,312 @@
+//! Captive portals, the login pages of hotel and airport networks.
+//!
+//! With `[captive_portal]` set, the check url is fetched directly whenever the default route
+//! changes, and again on each interval while a portal was found. A check answered by anything but
+//! `204` is a portal: it's logged as a warning with the page to log in at, which the `/logs`
+//! feed of the API shows, and published as a `captive_portal` event. Connections go directly to
+//! their destination, so the login page opens through the proxies as well.
+//!
+//! The `listen` address answers `204` to any `GET`, for the devices of the network which check
+//! their connectivity through it:
+//!
+//! ```toml
+//! [captive_portal]
+//! listen = "0.0.0.0:8204"
+//! check_url = "http://connectivitycheck.gstatic.com/generate_204"
+//! ```
+
+use std::{io, sync::Arc, time::Duration};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+
+use swiftlink_infra::{
+    event::{Event, EventBus},
+    log::*,
+    net::{self, AddrFamily, ConnectOpts},
+};
+
+use crate::{http_head, outbound};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+const MAX_HEAD_LEN: usize = 4096;
+
+/// A plain http url, portals can't intercept https without a certificate warning.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CheckUrl {
+    /// `host[:port]`, as sent in the `Host` header
+    authority: String,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl CheckUrl {
+    /// Parses `http://host[:port][/path]`, `None` if `url` isn't of that form.
+    pub(crate) fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("http://")?;
+        let (authority, path) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+        let (host, port) = match authority.rsplit_once(':') {
+            // the colons of a bracketed IPv6 address
+            Some((host, port)) if !port.contains(']') => (host, port.parse().ok()?),
+            _ => (authority, 80),
+        };
+        if host.is_empty() || host.contains(['@', ' ']) {
+            return None;
+        }
+        let path = match path {
+            "" => "/".to_owned(),
+            path if path.starts_with('?') => format!("/{}", path),
+            path => path.to_owned(),
+        };
+        Some(Self {
+            authority: authority.to_owned(),
+            host: host.to_owned(),
+            port,
+            path,
+        })
+    }
+
+    #[inline]
+    pub(crate) fn host(&self) -> &str {
+        &self.host
+    }
+}
+
+/// What a check found out about the network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Connectivity {
+    Online,
+    /// the check was answered by a portal, with the page it redirected to
+    Portal {
+        login_url: Option<String>,
+    },
+}
+
+/// Checks the network for a captive portal and reports the ones it finds.
+pub(crate) struct PortalDetector {
+    url: CheckUrl,
+    connect_opts: ConnectOpts,
+    events: Arc<EventBus>,
+    /// whether the last check found a portal
+    detected: bool,
+}
+
+impl PortalDetector {
+    pub(crate) fn new(url: CheckUrl, connect_opts: ConnectOpts, events: Arc<EventBus>) -> Self {
+        Self {
+            url,
+            connect_opts,
+            events,
+            detected: false,
+        }
+    }
+
+    /// Looks at the default route each `interval` and checks the network once it changed, until
+    /// the task is aborted. Where the route can't be read, each interval checks.
+    pub(crate) async fn run(mut self, interval: Duration) {
+        let mut route = None;
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let changed = match net::default_route(AddrFamily::IPv4) {
+                Ok(current) => route.replace(current.clone()) != Some(current),
+                Err(_) => true,
+            };
+            if changed || self.detected {
+                self.check().await;
+            }
+        }
+    }
+
+    /// Checks the network, reports a portal once it's found and once it's passed.
+    async fn check(&mut self) {
+        let connectivity = tokio::time::timeout(CHECK_TIMEOUT, probe(&self.url, &self.connect_opts))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "no response")));
+        match connectivity {
+            Ok(Connectivity::Online) if self.detected => {
+                info!("captive portal passed, the network is online");
+                self.detected = false;
+                self.events.publish(Event::CaptivePortal {
+                    detected: false,
+                    login_url: None,
+                });
+            }
+            Ok(Connectivity::Portal { login_url }) if !self.detected => {
+                warn!(
+                    "captive portal detected, log in at {}",
+                    login_url.as_deref().unwrap_or("the page the network redirects to")
+                );
+                self.detected = true;
+                self.events.publish(Event::CaptivePortal {
+                    detected: true,
+                    login_url,
+                });
+            }
+            Ok(_) => {}
+            // offline, or the check url is down, nothing is known about a portal
+            Err(err) => debug!("captive portal check of {} failed, {}", self.url.authority, err),
+        }
+    }
+}
+
+/// Fetches `url` directly, a `204` means no portal is in the way.
+async fn probe(url: &CheckUrl, connect_opts: &ConnectOpts) -> io::Result<Connectivity> {
+    let destination = format!("{}:{}", url.host, url.port);
+    let mut stream = outbound::dial(&destination, connect_opts).await?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}/{}\r\nConnection: close\r\n\r\n",
+        url.path,
+        url.authority,
+        crate::NAME,
+        crate::version()
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut buf = Vec::with_capacity(512);
+    http_head::read(&mut stream, &mut buf, MAX_HEAD_LEN).await?;
+    let head = String::from_utf8_lossy(&buf);
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .filter(|line| line.starts_with("HTTP/"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an http response"))?;
+    if status == "204" {
+        return Ok(Connectivity::Online);
+    }
+
+    let login_url = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("location"))
+        .map(|(_, value)| value.trim().to_owned());
+    Ok(Connectivity::Portal { login_url })
+}
+
+/// Serves the `204` generator until the task is aborted.
+pub(crate) async fn serve(listener: TcpListener) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                debug!("generate 204 accept failed, {}", err);
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream).await {
+                debug!("generate 204 request failed, {}", err);
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(256);
+    tokio::time::timeout(REQUEST_TIMEOUT, http_head::read(&mut stream, &mut buf, MAX_HEAD_LEN))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))??;
+
+    let head = String::from_utf8_lossy(&buf);
+    let method = head.split_whitespace().next().unwrap_or_default();
+    let status = match method {
+        "GET" | "HEAD" => "204 No Content",
+        _ => "405 Method Not Allowed",
+    };
+    let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_check_url() {
+        let url = CheckUrl::parse("http://connectivitycheck.gstatic.com/generate_204").unwrap();
+        assert_eq!(
+            (url.authority.as_str(), url.host.as_str(), url.port, url.path.as_str()),
+            (
+                "connectivitycheck.gstatic.com",
+                "connectivitycheck.gstatic.com",
+                80,
+                "/generate_204"
+            )
+        );
+        let url = CheckUrl::parse("http://[::1]:8204?probe").unwrap();
+        assert_eq!(
+            (url.host.as_str(), url.port, url.path.as_str()),
+            ("[::1]", 8204, "/?probe")
+        );
+        assert_eq!(CheckUrl::parse("http://[::1]").unwrap().port, 80);
+
+        assert_eq!(CheckUrl::parse("https://example.com/"), None);
+        assert_eq!(CheckUrl::parse("http://:80/"), None);
+        assert_eq!(CheckUrl::parse("http://example.com:http/"), None);
+    }
+
+    #[tokio::test]
+    async fn test_captive_portal() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let generator = tokio::spawn(serve(listener));
+
+        // a portal redirecting every request to its login page
+        let portal = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let portal_addr = portal.local_addr().unwrap();
+        let portal = tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = portal.accept().await.unwrap();
+                let mut buf = Vec::new();
+                http_head::read(&mut stream, &mut buf, MAX_HEAD_LEN).await.unwrap();
+                let response =
+                    "HTTP/1.1 302 Found\r\nLocation: http://login.example/?ap=7\r\nContent-Length: 0\r\n\r\n";
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let events = Arc::new(EventBus::default());
+        let mut rx = events.subscribe();
+        let portal_url = CheckUrl::parse(&format!("http://{}/generate_204", portal_addr)).unwrap();
+        let mut detector = PortalDetector::new(portal_url, ConnectOpts::default(), events.clone());
+        detector.check().await;
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            Event::CaptivePortal { detected: true, login_url: Some(url) } if url == "http://login.example/?ap=7"
+        ));
+        // reported once
+        detector.check().await;
+        assert!(rx.try_recv().is_err());
+
+        detector.url = CheckUrl::parse(&format!("http://{}/generate_204", addr)).unwrap();
+        detector.check().await;
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            Event::CaptivePortal {
+                detected: false,
+                login_url: None
+            }
+        ));
+        assert!(!detector.detected);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"POST /generate_204 HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 405 "));
+
+        generator.abort();
+        portal.abort();
+    }
+}
@@ -27,6 +27,261 @@ pub enum Commands {
         #[arg(long)]
         verbose: bool,
     },
+
+    /// Compile a text rule set into the binary rule set format
+    ConvertRuleset {
+        /// The rule set behavior, `domain` or `ipcidr`
+        #[arg(short = 'b', long)]
+        behavior: String,
+
+        /// The text rule set
+        input: PathBuf,
+
+        /// The binary rule set to write
+        output: PathBuf,
+    },
+
+    /// Measure the handshake and HTTP probe latency of a configured proxy
+    PingProxy {
+        /// The proxy name in `dns.proxy_servers`
+        tag: String,
+
+        /// The path to the configuration file
+        #[arg(short = 'c', long)]
+        conf: Option<PathBuf>,
+
+        /// The probe url, requested through the proxy
+        #[arg(short = 'u', long, default_value = "http://www.gstatic.com/generate_204")]
+        url: String,
+
+        /// The probe timeout in seconds
+        #[arg(short = 't', long, default_value_t = 5)]
+        timeout: u64,
+    },
+
+    /// Measure the download bandwidth through a configured proxy
+    SpeedtestProxy {
+        /// The proxy name in `dns.proxy_servers`
+        tag: String,
+
+        /// The path to the configuration file
+        #[arg(short = 'c', long)]
+        conf: Option<PathBuf>,
+
+        /// The url of a large file, default is `speedtest_url` of the configuration
+        #[arg(short = 'u', long)]
+        url: Option<String>,
+
+        /// The seconds to download at most, default is `speedtest_duration` of the configuration
+        #[arg(short = 'd', long)]
+        duration: Option<u64>,
+    },
+
+    /// Replace a running swiftlink with the current executable without dropping its listeners
+    Upgrade {
+        /// The process id of the running swiftlink
+        #[arg(short = 'p', long)]
+        pid: i32,
+    },
+
+    /// Route the decisions recorded by `record_decisions` again and report the changed ones
+    Replay {
+        /// The recorded decisions
+        decisions: PathBuf,
+
+        /// The path to the configuration file to route with
+        #[arg(short = 'c', long)]
+        conf: Option<PathBuf>,
+    },
+
+    /// Check the configuration and the host for problems and print how to fix them
+    Doctor {
+        /// The path to the configuration file
+        #[arg(short = 'c', long)]
+        conf: Option<PathBuf>,
+
+        /// The configuration directory
+        #[arg(short = 'd', long)]
+        home_dir: Option<PathBuf>,
+    },
+
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+
+    /// Inspect the proxies and groups of a running swiftlink
+    Proxies {
+        #[command(subcommand)]
+        command: ProxiesCommands,
+    },
+
+    /// Inspect the routing rules of a running swiftlink
+    Rules {
+        #[command(subcommand)]
+        command: RulesCommands,
+    },
+
+    /// Inspect the traffic of a running swiftlink
+    Stats {
+        #[command(subcommand)]
+        command: StatsCommands,
+    },
+
+    /// Inspect the dns server of a running swiftlink
+    Dns {
+        #[command(subcommand)]
+        command: DnsCommands,
+    },
+
+    /// Inspect the fake ips handed out by the dns server of a running swiftlink
+    Fakeip {
+        #[command(subcommand)]
+        command: FakeipCommands,
+    },
+
+    /// Inspect the connections of a running swiftlink
+    Connections {
+        #[command(subcommand)]
+        command: ConnectionsCommands,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Debug)]
+pub enum DnsCommands {
+    /// Print the latest lookups no upstream answered, with the upstream and the error, read from
+    /// the external controller
+    Failures {
+        /// The path to the configuration file
+        #[arg(short = 'c', long)]
+        conf: Option<PathBuf>,
+
+        /// Only the failures of the names containing this
+        #[arg(long)]
+        name: Option<String>,
+
+        /// The number of failures to print
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Debug)]
+pub enum ConnectionsCommands {
+    /// Print the recently closed connections, with why they closed, read from the external
+    /// controller
+    Closed {
+        /// The path to the configuration file
+        #[arg(short = 'c', long)]
+        conf: Option<PathBuf>,
+
+        /// Only the connections closed for this reason, e.g. `dial_refused` or `idle_timeout`
+        #[arg(long)]
+        reason: Option<String>,
+
+        /// Only the connections to the destinations containing this
+        #[arg(long)]
+        destination: Option<String>,
+
+        /// The number of connections to print
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Debug)]
+pub enum FakeipCommands {
+    /// Print the fake ips with their host, ordered by ip, read from the external controller
+    List {
+        /// The path to the configuration file
+        #[arg(short = 'c', long)]
+        conf: Option<PathBuf>,
+
+        /// The number of mappings to skip
+        #[arg(short = 'o', long, default_value_t = 0)]
+        offset: usize,
+
+        /// The number of mappings to print, all by default
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+    },
+
+    /// Print the host of a fake ip, or the fake ips of a host
+    Lookup {
+        /// The fake ip or the host
+        query: String,
+
+        /// The path to the configuration file
+        #[arg(short = 'c', long)]
+        conf: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Debug)]
+pub enum ProxiesCommands {
+    /// Print the proxy groups with the proxies they resolve to, the one each group is on marked
+    /// with `*`, read from the external controller
+    Tree {
+        /// The path to the configuration file
+        #[arg(short = 'c', long)]
+        conf: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Debug)]
+pub enum RulesCommands {
+    /// Print the rules which never matched and the ones matching most, read from the external
+    /// controller
+    Stats {
+        /// The path to the configuration file
+        #[arg(short = 'c', long)]
+        conf: Option<PathBuf>,
+
+        /// The number of hot rules to print
+        #[arg(short = 'n', long, default_value_t = 10)]
+        top: usize,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Debug)]
+pub enum StatsCommands {
+    /// Print the destinations and clients with the most traffic, read from the external
+    /// controller
+    Top {
+        /// The path to the configuration file
+        #[arg(short = 'c', long)]
+        conf: Option<PathBuf>,
+
+        /// The window in seconds, at most an hour
+        #[arg(short = 'w', long, default_value_t = 600)]
+        window: u64,
+
+        /// The number of destinations and clients to print
+        #[arg(short = 'n', long, default_value_t = 10)]
+        top: usize,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Debug)]
+pub enum ConfigCommands {
+    /// Print the effective configuration, secrets redacted
+    Dump {
+        /// The path to the configuration file
+        #[arg(short = 'c', long)]
+        conf: Option<PathBuf>,
+    },
+
+    /// Check that the files and directories the configuration refers to are accessible
+    Check {
+        /// The path to the configuration file
+        #[arg(short = 'c', long)]
+        conf: Option<PathBuf>,
+
+        /// The configuration directory
+        #[arg(short = 'd', long)]
+        home_dir: Option<PathBuf>,
+    },
 }
 
 #[cfg(test)]
@@ -57,6 +312,206 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_cli_args_parse_replay() {
+        let cli = Cli::parse_from(["swiftlink", "replay", "decisions.json", "-c", "new.toml"]);
+        assert_eq!(
+            cli.command,
+            Commands::Replay {
+                decisions: "decisions.json".into(),
+                conf: Some("new.toml".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_args_parse_doctor() {
+        let cli = Cli::parse_from(["swiftlink", "doctor", "-c", "/etc/swiftlink.conf"]);
+        assert_eq!(
+            cli.command,
+            Commands::Doctor {
+                conf: Some("/etc/swiftlink.conf".into()),
+                home_dir: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_args_parse_config_dump() {
+        let cli = Cli::parse_from(["swiftlink", "config", "dump", "-c", "/etc/swiftlink.conf"]);
+        assert!(matches!(
+            cli.command,
+            Commands::Config {
+                command: ConfigCommands::Dump { conf: Some(_) }
+            }
+        ));
+    }
+
+    #[test]
+    fn test_cli_args_parse_config_check() {
+        let cli = Cli::parse_from(["swiftlink", "config", "check", "-d", "/data"]);
+        assert_eq!(
+            cli.command,
+            Commands::Config {
+                command: ConfigCommands::Check {
+                    conf: None,
+                    home_dir: Some("/data".into()),
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_args_parse_proxies_tree() {
+        let cli = Cli::parse_from(["swiftlink", "proxies", "tree", "-c", "swiftlink.conf"]);
+        assert_eq!(
+            cli.command,
+            Commands::Proxies {
+                command: ProxiesCommands::Tree {
+                    conf: Some("swiftlink.conf".into()),
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_args_parse_rules_stats() {
+        let cli = Cli::parse_from(["swiftlink", "rules", "stats", "-n", "5"]);
+        assert_eq!(
+            cli.command,
+            Commands::Rules {
+                command: RulesCommands::Stats { conf: None, top: 5 }
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_args_parse_stats_top() {
+        let cli = Cli::parse_from(["swiftlink", "stats", "top", "-w", "3600"]);
+        assert_eq!(
+            cli.command,
+            Commands::Stats {
+                command: StatsCommands::Top {
+                    conf: None,
+                    window: 3600,
+                    top: 10,
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_args_parse_dns_failures() {
+        let cli = Cli::parse_from(["swiftlink", "dns", "failures", "--name", "example.com"]);
+        assert_eq!(
+            cli.command,
+            Commands::Dns {
+                command: DnsCommands::Failures {
+                    conf: None,
+                    name: Some("example.com".to_owned()),
+                    limit: 20,
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_args_parse_connections_closed() {
+        let cli = Cli::parse_from([
+            "swiftlink",
+            "connections",
+            "closed",
+            "--reason",
+            "dial_refused",
+            "-n",
+            "5",
+        ]);
+        assert_eq!(
+            cli.command,
+            Commands::Connections {
+                command: ConnectionsCommands::Closed {
+                    conf: None,
+                    reason: Some("dial_refused".to_owned()),
+                    destination: None,
+                    limit: 5,
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_args_parse_fakeip() {
+        let cli = Cli::parse_from(["swiftlink", "fakeip", "list", "-n", "20"]);
+        assert_eq!(
+            cli.command,
+            Commands::Fakeip {
+                command: FakeipCommands::List {
+                    conf: None,
+                    offset: 0,
+                    limit: Some(20),
+                }
+            }
+        );
+
+        let cli = Cli::parse_from(["swiftlink", "fakeip", "lookup", "198.18.0.2", "-c", "swiftlink.conf"]);
+        assert_eq!(
+            cli.command,
+            Commands::Fakeip {
+                command: FakeipCommands::Lookup {
+                    query: "198.18.0.2".to_string(),
+                    conf: Some("swiftlink.conf".into()),
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_args_parse_convert_ruleset() {
+        let cli = Cli::parse_from(["swiftlink", "convert-ruleset", "-b", "domain", "cn.txt", "cn.srs"]);
+        assert_eq!(
+            cli.command,
+            Commands::ConvertRuleset {
+                behavior: "domain".to_string(),
+                input: "cn.txt".into(),
+                output: "cn.srs".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_args_parse_ping_proxy() {
+        let cli = Cli::parse_from(["swiftlink", "ping-proxy", "mysocks5", "-t", "3"]);
+        assert_eq!(
+            cli.command,
+            Commands::PingProxy {
+                tag: "mysocks5".to_string(),
+                conf: None,
+                url: "http://www.gstatic.com/generate_204".to_string(),
+                timeout: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_args_parse_speedtest_proxy() {
+        let cli = Cli::parse_from(["swiftlink", "speedtest-proxy", "mysocks5", "-d", "5"]);
+        assert_eq!(
+            cli.command,
+            Commands::SpeedtestProxy {
+                tag: "mysocks5".to_string(),
+                conf: None,
+                url: None,
+                duration: Some(5),
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_args_parse_upgrade() {
+        let cli = Cli::parse_from(["swiftlink", "upgrade", "--pid", "1234"]);
+        assert_eq!(cli.command, Commands::Upgrade { pid: 1234 });
+    }
+
     #[test]
     fn test_cli_args_parse_start_debug_on() {
         let cli = Cli::parse_from(["swiftlink", "run", "-c", "/etc/swiftlink.conf", "--verbose"]);
@@ -0,0 +1,182 @@
+//! `swiftlink connections closed`, the connections the inbounds closed recently and why.
+//!
+//! The connections are read from `/connections/closed` of the external controller, which must be
+//! enabled. Only the latest closed connections are kept, the oldest are dropped first.
+
+use std::{
+    fmt,
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+
+use crate::{controller, dns_failures::ago, Config};
+
+/// A closed connection, as the external controller reports it.
+#[derive(Debug, Deserialize)]
+pub struct ClosedConnection {
+    pub id: u64,
+    pub network: String,
+    pub inbound: String,
+    pub source: SocketAddr,
+    pub destination: String,
+    pub outbound: Option<String>,
+    pub upload: u64,
+    pub download: u64,
+    /// milliseconds since the unix epoch
+    pub started_at: u64,
+    pub duration_ms: u64,
+    pub reason: String,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Connections {
+    connections: Vec<ClosedConnection>,
+}
+
+/// Reads the latest `limit` closed connections, of the close `reason` and of the destinations
+/// containing `destination` if they're set, from the external controller of `config`.
+pub fn fetch(
+    config: &Config,
+    reason: Option<&str>,
+    destination: Option<&str>,
+    limit: usize,
+) -> anyhow::Result<Vec<ClosedConnection>> {
+    let mut target = format!("/connections/closed?limit={}", limit);
+    if let Some(reason) = reason {
+        target.push_str("&reason=");
+        target.push_str(reason);
+    }
+    if let Some(destination) = destination {
+        target.push_str("&destination=");
+        target.push_str(destination);
+    }
+    let connections: Connections = controller::get(config, &target)?;
+    Ok(connections.connections)
+}
+
+/// The closed connections, most recent first, with how long ago they started.
+pub struct ConnectionReport<'a> {
+    connections: &'a [ClosedConnection],
+    /// milliseconds since the unix epoch
+    now: u64,
+}
+
+impl<'a> ConnectionReport<'a> {
+    pub fn new(connections: &'a [ClosedConnection]) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Self { connections, now }
+    }
+}
+
+impl fmt::Display for ConnectionReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.connections.is_empty() {
+            return writeln!(f, "no closed connections");
+        }
+        writeln!(f, "closed connections ({}):", self.connections.len())?;
+        for conn in self.connections {
+            write!(
+                f,
+                "  {:>8}  #{} {} {} -> {} via {}/{}, {} up {} down in {} ms: {}",
+                ago(self.now.saturating_sub(conn.started_at) / 1000),
+                conn.id,
+                conn.network,
+                conn.source,
+                conn.destination,
+                conn.inbound,
+                conn.outbound.as_deref().unwrap_or("-"),
+                conn.upload,
+                conn.download,
+                conn.duration_ms,
+                conn.reason
+            )?;
+            match &conn.error {
+                Some(error) => writeln!(f, ", {}", error)?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use swiftlink_infra::connection::{self, CloseReason, ConnectionHistory};
+
+    use crate::{api::Api, config::ControllerCors, controller::TestController};
+
+    use super::*;
+
+    #[test]
+    fn test_connection_report() {
+        let connections: Connections = serde_json::from_str(
+            r#"{"connections":[{
+                "id":42,"network":"tcp","inbound":"socks","source":"127.0.0.1:50000",
+                "destination":"example.com:443","rule":null,"outbound":"direct","dialed":null,
+                "upload":100,"download":1000,"started_at":1700000000000,"duration_ms":1500,
+                "reason":"dial_refused","error":"connection refused"
+            }]}"#,
+        )
+        .unwrap();
+        let report = ConnectionReport {
+            connections: &connections.connections,
+            now: 1_700_000_000_000 + 125_000,
+        };
+        assert_eq!(
+            report.to_string(),
+            "closed connections (1):\n    2m ago  #42 tcp 127.0.0.1:50000 -> example.com:443 via socks/direct, \
+             100 up 1000 down in 1500 ms: dial_refused, connection refused\n"
+        );
+        assert_eq!(ConnectionReport::new(&[]).to_string(), "no closed connections\n");
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let history = Arc::new(ConnectionHistory::default());
+        for (id, destination, reason) in [
+            (1, "example.com:443", CloseReason::ClientEof),
+            (2, "example.org:443", CloseReason::DialRefused),
+        ] {
+            history.record(connection::ClosedConnection {
+                id,
+                network: "tcp",
+                inbound: "http".to_owned(),
+                source: "127.0.0.1:50000".parse().unwrap(),
+                destination: destination.to_owned(),
+                rule: None,
+                outbound: Some("direct".to_owned()),
+                dialed: None,
+                upload: 0,
+                download: 0,
+                started_at: 0,
+                duration_ms: 0,
+                reason,
+                error: None,
+            });
+        }
+        let api = Api::new(None, ControllerCors::default()).with_connections(history);
+        let controller = TestController::start(api).await;
+
+        let (all, refused) = controller
+            .request(None, |config| {
+                let err = fetch(config, Some("refused"), None, 10).unwrap_err();
+                assert!(err.to_string().contains("400"), "{:?}", err);
+                (
+                    fetch(config, None, None, 10).unwrap(),
+                    fetch(config, Some("dial_refused"), Some("example"), 10).unwrap(),
+                )
+            })
+            .await;
+        assert_eq!(all.iter().map(|conn| conn.id).collect::<Vec<_>>(), [2, 1]);
+        assert_eq!(refused.len(), 1);
+        assert_eq!(refused[0].destination, "example.org:443");
+    }
+}
@@ -1,7 +1,23 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 // use swiftlink_dns::DnsResolver;
-use swiftlink_infra::fakedns::FakeDns;
+use swiftlink_infra::{
+    connection::ConnectionHistory,
+    dns_failures::DnsFailures,
+    event::EventBus,
+    fakedns::{BlockedDomains, FakeDns},
+    geoip::GeoIpDb,
+    geosite::GeoSite,
+    proxy_stats::ProxyStatsMap,
+    rule_hits::RuleHits,
+    talkers::TalkerStats,
+    traffic::UserTraffic,
+};
+
+use crate::{outbound::Outbounds, proxy_group::ProxyGroups, route::Router, rule_provider::RuleProvider};
 
 pub struct Context {
     // dns_resolver: Arc<DnsResolver>,
@@ -32,6 +48,21 @@ impl Context {
 pub struct AppContext {
     context: SharedContext,
     fakedns: Option<Arc<Mutex<FakeDns>>>,
+    blocked_domains: Arc<BlockedDomains>,
+    geoip: Option<Arc<GeoIpDb>>,
+    geoip_asn: Option<Arc<GeoIpDb>>,
+    geosite: Option<Arc<GeoSite>>,
+    rule_providers: HashMap<String, Arc<RuleProvider>>,
+    router: Arc<Router>,
+    outbounds: Arc<Outbounds>,
+    proxy_groups: Arc<ProxyGroups>,
+    connections: Arc<ConnectionHistory>,
+    proxy_stats: Arc<ProxyStatsMap>,
+    rule_hits: Arc<RuleHits>,
+    talkers: Arc<TalkerStats>,
+    user_traffic: Arc<UserTraffic>,
+    dns_failures: Arc<DnsFailures>,
+    events: Arc<EventBus>,
 }
 
 impl AppContext {
@@ -39,6 +70,21 @@ impl AppContext {
         Self {
             context: Context::new_shared(),
             fakedns: None,
+            blocked_domains: Arc::new(BlockedDomains::default()),
+            geoip: None,
+            geoip_asn: None,
+            geosite: None,
+            rule_providers: HashMap::new(),
+            router: Arc::new(Router::default()),
+            outbounds: Arc::new(Outbounds::default()),
+            proxy_groups: Arc::new(ProxyGroups::default()),
+            connections: Arc::new(ConnectionHistory::default()),
+            proxy_stats: Arc::new(ProxyStatsMap::default()),
+            rule_hits: Arc::new(RuleHits::default()),
+            talkers: Arc::new(TalkerStats::default()),
+            user_traffic: Arc::new(UserTraffic::default()),
+            dns_failures: Arc::new(DnsFailures::default()),
+            events: Arc::new(EventBus::default()),
         }
     }
 
@@ -49,6 +95,110 @@ impl AppContext {
     pub fn fakedns(&self) -> Option<Arc<Mutex<FakeDns>>> {
         self.fakedns.clone()
     }
+
+    /// The domains the router rejects, the dns server answers them without a fake ip.
+    pub fn blocked_domains(&self) -> Arc<BlockedDomains> {
+        self.blocked_domains.clone()
+    }
+
+    pub fn set_geoip(&mut self, geoip: Arc<GeoIpDb>) {
+        self.geoip = Some(geoip);
+    }
+
+    pub fn geoip(&self) -> Option<Arc<GeoIpDb>> {
+        self.geoip.clone()
+    }
+
+    pub fn set_geoip_asn(&mut self, geoip_asn: Arc<GeoIpDb>) {
+        self.geoip_asn = Some(geoip_asn);
+    }
+
+    pub fn geoip_asn(&self) -> Option<Arc<GeoIpDb>> {
+        self.geoip_asn.clone()
+    }
+
+    pub fn set_geosite(&mut self, geosite: Arc<GeoSite>) {
+        self.geosite = Some(geosite);
+    }
+
+    pub fn geosite(&self) -> Option<Arc<GeoSite>> {
+        self.geosite.clone()
+    }
+
+    pub(crate) fn add_rule_provider(&mut self, provider: Arc<RuleProvider>) {
+        self.rule_providers.insert(provider.name().to_owned(), provider);
+    }
+
+    /// The rule sets of `RULE-SET` rules, by name.
+    pub(crate) fn rule_providers(&self) -> HashMap<String, Arc<RuleProvider>> {
+        self.rule_providers.clone()
+    }
+
+    pub(crate) fn set_router(&mut self, router: Arc<Router>) {
+        self.router = router;
+    }
+
+    /// The routing rules, with the rule data of this context.
+    pub(crate) fn router(&self) -> Arc<Router> {
+        self.router.clone()
+    }
+
+    pub(crate) fn set_outbounds(&mut self, outbounds: Arc<Outbounds>) {
+        self.outbounds = outbounds;
+    }
+
+    /// The outbounds the targets of the rules name.
+    pub(crate) fn outbounds(&self) -> Arc<Outbounds> {
+        self.outbounds.clone()
+    }
+
+    pub(crate) fn set_proxy_groups(&mut self, proxy_groups: Arc<ProxyGroups>) {
+        self.proxy_groups = proxy_groups;
+    }
+
+    /// The groups of the proxies, fed by their health checks and speed tests.
+    pub(crate) fn proxy_groups(&self) -> Arc<ProxyGroups> {
+        self.proxy_groups.clone()
+    }
+
+    pub fn set_connections(&mut self, connections: Arc<ConnectionHistory>) {
+        self.connections = connections;
+    }
+
+    /// The recently closed connections, relays record each connection they close here.
+    pub fn connections(&self) -> Arc<ConnectionHistory> {
+        self.connections.clone()
+    }
+
+    /// The delay history and speed test results of the proxies.
+    pub fn proxy_stats(&self) -> Arc<ProxyStatsMap> {
+        self.proxy_stats.clone()
+    }
+
+    /// The match counters of the routing rules.
+    pub fn rule_hits(&self) -> Arc<RuleHits> {
+        self.rule_hits.clone()
+    }
+
+    /// The traffic of the destinations and clients, of the connections closed in the last hour.
+    pub fn talkers(&self) -> Arc<TalkerStats> {
+        self.talkers.clone()
+    }
+
+    /// The traffic of the users the inbounds authenticated, by name.
+    pub fn user_traffic(&self) -> Arc<UserTraffic> {
+        self.user_traffic.clone()
+    }
+
+    /// The latest failed lookups of the dns servers.
+    pub fn dns_failures(&self) -> Arc<DnsFailures> {
+        self.dns_failures.clone()
+    }
+
+    /// The bus subsystems publish their events to, instead of calling each other.
+    pub fn events(&self) -> Arc<EventBus> {
+        self.events.clone()
+    }
 }
 
 impl Default for AppContext {
@@ -0,0 +1,130 @@
+//! Requests of the commands inspecting a running swiftlink to its external controller, which
+//! must be enabled.
+
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use serde::de::DeserializeOwned;
+
+use crate::Config;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Requests `target`, a path with an optional query, from the external controller of `config`
+/// and parses the JSON answer.
+pub(crate) fn get<T: DeserializeOwned>(config: &Config, target: &str) -> anyhow::Result<T> {
+    let Some(mut addr) = config.external_controller() else {
+        bail!("external_controller is not configured");
+    };
+    // its certificate is usually issued for another name than the address connected to
+    if config.external_controller_tls(Path::new("")).is_some() {
+        bail!(
+            "external_controller_tls is not supported yet, read {} of the controller instead",
+            target.split('?').next().unwrap_or(target)
+        );
+    }
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)
+        .with_context(|| format!("Failed to connect to the external controller {}", addr))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let authorization = config
+        .secret()
+        .map(|secret| format!("Authorization: Bearer {}\r\n", secret))
+        .unwrap_or_default();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\n\r\n",
+        target, addr, authorization
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        bail!("external controller {} answered {}", addr, status);
+    }
+    serde_json::from_str(body).context("Invalid response of the external controller")
+}
+
+/// An external controller answering the requests of the commands under test.
+#[cfg(test)]
+pub(crate) struct TestController {
+    addr: SocketAddr,
+    task: tokio::task::JoinHandle<()>,
+}
+
+#[cfg(test)]
+impl TestController {
+    /// Serves `api` on a port of the loopback until dropped.
+    pub(crate) async fn start(api: crate::api::Api) -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(crate::api::serve(listener, std::sync::Arc::new(api)));
+        Self { addr, task }
+    }
+
+    /// Runs `request`, blocking as the commands are, with a configuration of this controller
+    /// whose secret is `secret`.
+    pub(crate) async fn request<T, F>(&self, secret: Option<&str>, request: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&Config) -> T + Send + 'static,
+    {
+        let mut config = Config::builder().external_controller(self.addr);
+        if let Some(secret) = secret {
+            config = config.secret(secret);
+        }
+        let config = config.build().unwrap();
+        tokio::task::spawn_blocking(move || request(&config)).await.unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestController {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{api::Api, config::ControllerCors};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_get() {
+        let err = get::<serde_json::Value>(&Config::builder().build().unwrap(), "/version").unwrap_err();
+        assert!(err.to_string().contains("not configured"), "{:?}", err);
+
+        let controller = TestController::start(Api::new(Some("s3cr3t"), ControllerCors::default())).await;
+        let version: serde_json::Value = controller
+            .request(Some("s3cr3t"), |config| get(config, "/version"))
+            .await
+            .unwrap();
+        assert_eq!(version["version"], crate::version());
+
+        let err = controller
+            .request(Some("wrong"), |config| get::<serde_json::Value>(config, "/version"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("401"), "{:?}", err);
+        let err = controller
+            .request(Some("s3cr3t"), |config| get::<serde_json::Value>(config, "/unknown"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("404"), "{:?}", err);
+    }
+}
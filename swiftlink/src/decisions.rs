@@ -0,0 +1,242 @@
+//! Record and replay of routing decisions.
+//!
+//! With `record_decisions` set, every connection routed by the TLS passthrough appends what it was
+//! routed by, the rule which matched and the backend it went to, one JSON object per line:
+//!
+//! ```text
+//! {"time":1700000000000,"inbound":"sni","server_name":"www.example.com","rule":"*.example.com","outbound":"127.0.0.1:9443"}
+//! ```
+//!
+//! `swiftlink replay <file> -c new.toml` routes the recorded connections again with the routes of
+//! another configuration and reports the ones which would be routed differently, so a large rule
+//! set can be refactored safely.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use swiftlink_infra::{connection::ClosedConnection, log::*};
+
+use crate::{sni_proxy, Config};
+
+/// How one connection was routed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Decision {
+    /// unix time in milliseconds
+    pub time: u64,
+    pub inbound: String,
+    /// the server name of the ClientHello, if any
+    pub server_name: Option<String>,
+    /// the matching rule, `None` if the connection was rejected
+    pub rule: Option<String>,
+    pub outbound: Option<String>,
+}
+
+impl Decision {
+    pub fn new(inbound: &str, server_name: Option<&str>, route: Option<(String, &str)>) -> Self {
+        let (rule, outbound) = match route {
+            Some((rule, outbound)) => (Some(rule), Some(outbound.to_owned())),
+            None => (None, None),
+        };
+        Self {
+            time: ClosedConnection::unix_millis(SystemTime::now()),
+            inbound: inbound.to_owned(),
+            server_name: server_name.map(str::to_owned),
+            rule,
+            outbound,
+        }
+    }
+}
+
+/// The file the decisions are appended to.
+#[derive(Debug)]
+pub struct DecisionLog {
+    file: Mutex<File>,
+}
+
+impl DecisionLog {
+    /// Opens `path` for appending, creating it if missing.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: Mutex::new(file) })
+    }
+
+    pub fn record(&self, decision: &Decision) {
+        let mut line = serde_json::to_vec(decision).expect("a decision serializes");
+        line.push(b'\n');
+        if let Err(err) = self.file.lock().unwrap().write_all(&line) {
+            warn!("failed to record a routing decision, {}", err);
+        }
+    }
+}
+
+/// Reads the decisions recorded in `path`.
+pub fn read(path: &Path) -> anyhow::Result<Vec<Decision>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let mut decisions = vec![];
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.with_context(|| format!("Failed to read {:?}", path))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let decision = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid decision at line {} of {:?}", index + 1, path))?;
+        decisions.push(decision);
+    }
+    Ok(decisions)
+}
+
+/// A rule and an outbound, or a rejection if both are `None`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Outcome {
+    pub rule: Option<String>,
+    pub outbound: Option<String>,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.rule.as_deref(), self.outbound.as_deref()) {
+            (Some(rule), Some(outbound)) => write!(f, "{} -> {}", rule, outbound),
+            _ => f.write_str("rejected"),
+        }
+    }
+}
+
+/// Recorded connections of one server name which the configuration routes differently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diff {
+    pub inbound: String,
+    pub server_name: Option<String>,
+    pub recorded: Outcome,
+    pub replayed: Outcome,
+    /// number of recorded connections
+    pub connections: usize,
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: {} => {} ({} connections)",
+            self.inbound,
+            self.server_name.as_deref().unwrap_or("<no server name>"),
+            self.recorded,
+            self.replayed,
+            self.connections
+        )
+    }
+}
+
+/// Routes the `decisions` again with the routes of `config`, returns the changed ones grouped by
+/// server name and recorded outcome. Decisions of unknown inbounds are skipped.
+pub fn replay(decisions: &[Decision], config: &Config) -> Vec<Diff> {
+    let routes = config.sni_routes();
+
+    let mut diffs = BTreeMap::new();
+    for decision in decisions.iter().filter(|d| d.inbound == sni_proxy::INBOUND_TAG) {
+        let recorded = Outcome {
+            rule: decision.rule.clone(),
+            outbound: decision.outbound.clone(),
+        };
+        let replayed = match routes.route(decision.server_name.as_deref()) {
+            Some((rule, outbound)) => Outcome {
+                rule: Some(rule),
+                outbound: Some(outbound.to_owned()),
+            },
+            None => Outcome {
+                rule: None,
+                outbound: None,
+            },
+        };
+        if recorded == replayed {
+            continue;
+        }
+        diffs
+            .entry((decision.server_name.clone(), recorded))
+            .or_insert((decision.inbound.clone(), replayed, 0))
+            .2 += 1;
+    }
+
+    diffs
+        .into_iter()
+        .map(|((server_name, recorded), (inbound, replayed, connections))| Diff {
+            inbound,
+            server_name,
+            recorded,
+            replayed,
+            connections,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_replay() {
+        let dir = std::env::temp_dir().join(format!("swiftlink-decisions-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("decisions.json");
+        _ = std::fs::remove_file(&path);
+
+        let log = DecisionLog::open(&path).unwrap();
+        let route = |rule: &str, outbound| Some((rule.to_owned(), outbound));
+        log.record(&Decision::new(
+            "sni",
+            Some("www.example.com"),
+            route("*.example.com", "127.0.0.1:9443"),
+        ));
+        log.record(&Decision::new(
+            "sni",
+            Some("www.example.com"),
+            route("*.example.com", "127.0.0.1:9443"),
+        ));
+        log.record(&Decision::new(
+            "sni",
+            Some("trojan.example.com"),
+            route("trojan.example.com", "127.0.0.1:8443"),
+        ));
+        log.record(&Decision::new("sni", None, None));
+        log.record(&Decision::new("http", Some("www.example.com"), None));
+
+        let decisions = read(&path).unwrap();
+        assert_eq!(decisions.len(), 5);
+        assert_eq!(decisions[2].server_name.as_deref(), Some("trojan.example.com"));
+
+        let config = Config::builder()
+            .sni_listen("127.0.0.1:443".parse().unwrap())
+            .sni_route("trojan.example.com", "127.0.0.1:8443")
+            .sni_route("*.example.com", "127.0.0.1:9443")
+            .build()
+            .unwrap();
+        assert!(replay(&decisions, &config).is_empty());
+
+        let config = Config::builder()
+            .sni_listen("127.0.0.1:443".parse().unwrap())
+            .sni_route("*.example.com", "127.0.0.1:9443")
+            .sni_route("*", "127.0.0.1:10443")
+            .build()
+            .unwrap();
+        let diffs = replay(&decisions, &config);
+        assert_eq!(diffs.len(), 2);
+        assert_eq!(
+            diffs[0].to_string(),
+            "sni <no server name>: rejected => * -> 127.0.0.1:10443 (1 connections)"
+        );
+        assert_eq!(diffs[1].server_name.as_deref(), Some("trojan.example.com"));
+        assert_eq!(diffs[1].replayed.to_string(), "*.example.com -> 127.0.0.1:9443");
+
+        std::fs::write(&path, "{\"time\":0}\n").unwrap();
+        assert!(read(&path).unwrap_err().to_string().contains("line 1"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+//! `swiftlink dns failures`, the latest lookups the dns servers failed to answer.
+//!
+//! The failures are read from `/dns/failures` of the external controller, which must be enabled.
+//! Only lookups without an answer are kept, e.g. timeouts or `SERVFAIL`s of the upstreams, names
+//! which don't exist aren't failures.
+
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+use swiftlink_infra::dns_failures::DnsFailure;
+
+use crate::{controller, Config};
+
+#[derive(Deserialize)]
+struct Failures {
+    failures: Vec<DnsFailure>,
+}
+
+/// Reads the latest `limit` failures, of the names containing `name` if it's set, from the
+/// external controller of `config`.
+pub fn fetch(config: &Config, name: Option<&str>, limit: usize) -> anyhow::Result<Vec<DnsFailure>> {
+    let mut target = format!("/dns/failures?limit={}", limit);
+    if let Some(name) = name {
+        target.push_str("&name=");
+        target.push_str(name);
+    }
+    let failures: Failures = controller::get(config, &target)?;
+    Ok(failures.failures)
+}
+
+/// The failures, most recent first, with how long ago they were.
+pub struct FailureReport<'a> {
+    failures: &'a [DnsFailure],
+    /// milliseconds since the unix epoch
+    now: u64,
+}
+
+impl<'a> FailureReport<'a> {
+    pub fn new(failures: &'a [DnsFailure]) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Self { failures, now }
+    }
+}
+
+impl fmt::Display for FailureReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.failures.is_empty() {
+            return writeln!(f, "no failed lookups");
+        }
+        writeln!(f, "failed lookups ({}):", self.failures.len())?;
+        for failure in self.failures {
+            writeln!(
+                f,
+                "  {:>8}  {} {}  via {}, after {} ms: {}",
+                ago(self.now.saturating_sub(failure.at) / 1000),
+                failure.name,
+                failure.record_type,
+                failure.upstream.as_deref().unwrap_or("-"),
+                failure.duration_ms,
+                failure.error
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn ago(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        86400.. => format!("{}d ago", secs / 86400),
+        _ => format!("{}h ago", secs / 3600),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use swiftlink_infra::dns_failures::DnsFailures;
+
+    use crate::{api::Api, config::ControllerCors, controller::TestController};
+
+    use super::*;
+
+    #[test]
+    fn test_failure_report() {
+        let failure = DnsFailure {
+            at: 1_700_000_000_000,
+            name: "www.example.com.".to_owned(),
+            record_type: "AAAA".to_owned(),
+            upstream: Some("udp://8.8.8.8:53".to_owned()),
+            error: "request timed out".to_owned(),
+            duration_ms: 5000,
+        };
+        let failures = [failure];
+        let report = FailureReport {
+            failures: &failures,
+            now: 1_700_000_000_000 + 125_000,
+        };
+        assert_eq!(
+            report.to_string(),
+            "failed lookups (1):\n    2m ago  www.example.com. AAAA  via udp://8.8.8.8:53, after 5000 ms: request timed out\n"
+        );
+        assert_eq!(FailureReport::new(&[]).to_string(), "no failed lookups\n");
+        assert_eq!(ago(7200), "2h ago");
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let failures = Arc::new(DnsFailures::default());
+        for name in ["www.example.com.", "www.example.org."] {
+            failures.record(DnsFailure::new(
+                name,
+                "A",
+                Some("udp://8.8.8.8:53"),
+                "request timed out",
+                Duration::from_secs(5),
+            ));
+        }
+        let api = Api::new(None, ControllerCors::default()).with_dns_failures(failures);
+        let controller = TestController::start(api).await;
+
+        let (all, found) = controller
+            .request(None, |config| {
+                let err = fetch(config, None, 0).unwrap_err();
+                assert!(err.to_string().contains("400"), "{:?}", err);
+                (
+                    fetch(config, None, 10).unwrap(),
+                    fetch(config, Some("example.com"), 10).unwrap(),
+                )
+            })
+            .await;
+        assert_eq!(
+            all.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(),
+            ["www.example.org.", "www.example.com."]
+        );
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].upstream.as_deref(), Some("udp://8.8.8.8:53"));
+    }
+}
@@ -0,0 +1,308 @@
+//! Self-test of the host and the configuration, `swiftlink doctor`.
+//!
+//! Checks what typically keeps swiftlink from starting or working once started: inaccessible
+//! paths, ports in use or privileged, missing capabilities, a missing `interface_name`,
+//! unreachable upstream nameservers and a wrong system clock. Each problem comes with a fix, errors first.
+
+use std::{
+    fmt, io,
+    net::{TcpListener, UdpSocket},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use swiftlink_dns::build_dns_resolver;
+use swiftlink_infra::net::{self, AddrFamily, ConnectOpts};
+
+use crate::{config::Config, layout};
+
+/// How long the upstream nameservers get to answer.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 2024-01-01T00:00:00Z, a clock before is certainly wrong.
+const MIN_SANE_TIME: Duration = Duration::from_secs(1_704_067_200);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// swiftlink doesn't start or the feature doesn't work
+    Error,
+    /// swiftlink works, possibly not as intended
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub severity: Severity,
+    pub problem: String,
+    pub fix: String,
+}
+
+impl Problem {
+    fn error<P: Into<String>, F: Into<String>>(problem: P, fix: F) -> Self {
+        Self {
+            severity: Severity::Error,
+            problem: problem.into(),
+            fix: fix.into(),
+        }
+    }
+
+    fn warning<P: Into<String>, F: Into<String>>(problem: P, fix: F) -> Self {
+        Self {
+            severity: Severity::Warning,
+            problem: problem.into(),
+            fix: fix.into(),
+        }
+    }
+
+    /// The problem of a configuration which doesn't load.
+    pub fn invalid_config(err: &anyhow::Error) -> Self {
+        Self::error(
+            format!("{:#}", err),
+            "fix the configuration, `swiftlink config dump` prints the effective one",
+        )
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{:<7}  {}\n         fix: {}", severity, self.problem, self.fix)
+    }
+}
+
+/// Runs all checks, the problems found are sorted by severity.
+pub async fn diagnose(config: &Config, home_dir: &Path) -> Vec<Problem> {
+    let mut problems = check_paths(config, home_dir);
+    problems.extend(check_ports(config));
+    problems.extend(check_capabilities(config));
+    problems.extend(check_interface(config));
+    problems.extend(check_upstream(config).await);
+    problems.extend(check_clock(SystemTime::now()));
+    // stable, problems of the same severity stay in the order of the checks
+    problems.sort_by_key(|problem| problem.severity);
+    problems
+}
+
+fn check_paths(config: &Config, home_dir: &Path) -> Vec<Problem> {
+    layout::check_instance(config, home_dir)
+        .into_iter()
+        .chain(layout::check_log(config, home_dir))
+        .filter(|check| !check.is_ok())
+        .map(|check| {
+            let fix = format!("create it or fix its permissions, or change {}", check.name);
+            Problem::error(check.to_string(), fix)
+        })
+        .collect()
+}
+
+/// Binds the configured listeners, swiftlink must not be running.
+fn check_ports(config: &Config) -> Vec<Problem> {
+    let mut listeners = vec![];
+    let dns = config.dns();
+    if dns.enabled() {
+        listeners.push(("dns.listen", dns.listen().sock_addr(), true));
+    }
+    if let Some(addr) = config.health_listen() {
+        listeners.push(("health_listen", addr, false));
+    }
+    if let Some(addr) = config.captive_portal().and_then(|captive_portal| captive_portal.listen) {
+        listeners.push(("captive_portal.listen", addr, false));
+    }
+    if let Some(addr) = config.http_listen() {
+        listeners.push(("http_listen", addr, false));
+    }
+    if let Some(addr) = config.socks_listen() {
+        listeners.push(("socks_listen", addr, false));
+    }
+    if let Some((addr, _)) = config.sni_proxy() {
+        listeners.push(("sni_listen", addr, false));
+    }
+    if let Some(addr) = config.external_controller() {
+        listeners.push(("external_controller", addr, false));
+    }
+
+    let mut problems = vec![];
+    for (name, addr, udp) in listeners {
+        let result = TcpListener::bind(addr).and_then(|_| match udp {
+            true => UdpSocket::bind(addr).map(|_| ()),
+            false => Ok(()),
+        });
+        let Err(err) = result else {
+            continue;
+        };
+
+        let problem = format!("{} {} can't be bound, {}", name, addr, err);
+        problems.push(match err.kind() {
+            io::ErrorKind::AddrInUse => Problem::error(
+                problem,
+                format!(
+                    "stop the process using the port, e.g. a running swiftlink, or change {}",
+                    name
+                ),
+            ),
+            io::ErrorKind::PermissionDenied if name == "dns.listen" && dns.listen_fallback_port().is_some() => {
+                Problem::warning(
+                    problem,
+                    "the dns server falls back to listen_fallback_port, run as root or grant \
+                     CAP_NET_BIND_SERVICE to serve the configured port",
+                )
+            }
+            io::ErrorKind::PermissionDenied => Problem::error(
+                problem,
+                format!(
+                    "run as root or grant CAP_NET_BIND_SERVICE, or change {} to a port above 1023",
+                    name
+                ),
+            ),
+            io::ErrorKind::AddrNotAvailable => Problem::error(
+                problem,
+                format!("the address isn't assigned to any interface, change {}", name),
+            ),
+            _ => Problem::error(problem, format!("check {}", name)),
+        });
+    }
+    problems
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn check_capabilities(config: &Config) -> Option<Problem> {
+    config.fwmark()?;
+    match swiftlink_infra::privilege::has_net_admin() {
+        Ok(true) => None,
+        Ok(false) => Some(Problem::error(
+            "fwmark requires CAP_NET_ADMIN, which the process lacks",
+            "run as root, or grant it with `setcap cap_net_admin+ep` on the executable or \
+             AmbientCapabilities=CAP_NET_ADMIN in the systemd unit",
+        )),
+        Err(err) => Some(Problem::warning(
+            format!("the capabilities of the process can't be read, {}", err),
+            "make sure the process has CAP_NET_ADMIN for fwmark",
+        )),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn check_capabilities(config: &Config) -> Option<Problem> {
+    config.fwmark().map(|_| {
+        Problem::warning(
+            "fwmark is only supported on Linux, it's ignored",
+            "remove fwmark from the configuration",
+        )
+    })
+}
+
+/// Outbound sockets are bound to `interface_name`, they all fail without it.
+fn check_interface(config: &Config) -> Option<Problem> {
+    let name = config.interface_name()?;
+    match net::interface(name) {
+        Ok(Some(iface)) if iface.up => None,
+        Ok(Some(_)) => Some(Problem::error(
+            format!("interface_name {} is down", name),
+            "bring the interface up, or change interface_name",
+        )),
+        Ok(None) => {
+            let fix = match net::default_route(AddrFamily::IPv4) {
+                Ok(Some(route)) => format!(
+                    "change interface_name, the default route goes out of {}",
+                    route.interface
+                ),
+                _ => "change interface_name to an interface of the host".to_owned(),
+            };
+            Some(Problem::error(format!("interface_name {} doesn't exist", name), fix))
+        }
+        Err(err) => Some(Problem::warning(
+            format!("the interfaces of the host can't be read, {}", err),
+            format!("make sure the interface {} of interface_name exists", name),
+        )),
+    }
+}
+
+async fn check_upstream(config: &Config) -> Option<Problem> {
+    let dns = config.dns();
+    if !dns.enabled() {
+        return None;
+    }
+
+    let connect_opts = ConnectOpts {
+        bind_interface: config.interface_name().map(|s| s.to_owned()),
+        ..Default::default()
+    };
+    let resolver = build_dns_resolver(&dns, &connect_opts, None).await;
+    match tokio::time::timeout(UPSTREAM_TIMEOUT, resolver.probe_upstream()).await {
+        Ok(true) => None,
+        _ => Some(Problem::error(
+            "no upstream nameserver answers",
+            "check the network, the nameserver entries of [dns] and the proxies they use",
+        )),
+    }
+}
+
+fn check_clock(now: SystemTime) -> Option<Problem> {
+    let since_epoch = now.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    // the executable was built, or at least installed, before now
+    let installed = std::env::current_exe()
+        .and_then(|exe| exe.metadata())
+        .and_then(|metadata| metadata.modified())
+        .ok();
+
+    if since_epoch < MIN_SANE_TIME || installed.is_some_and(|installed| now + Duration::from_secs(86400) < installed) {
+        return Some(Problem::error(
+            format!(
+                "the system clock is wrong, {} seconds since the epoch",
+                since_epoch.as_secs()
+            ),
+            "synchronize the clock, e.g. with NTP, certificates of TLS upstreams don't validate otherwise",
+        ));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_ports() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let config = Config::builder().health_listen(addr).build().unwrap();
+        let problems = check_ports(&config);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].severity, Severity::Error);
+        assert!(problems[0].problem.starts_with("health_listen"));
+
+        drop(listener);
+        assert!(check_ports(&config).is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_check_interface() {
+        assert!(check_interface(&Config::default()).is_none());
+        let config = Config::builder().interface_name("lo").build().unwrap();
+        assert!(check_interface(&config).is_none());
+
+        let config = Config::builder().interface_name("swiftlink-none0").build().unwrap();
+        let problem = check_interface(&config).unwrap();
+        assert_eq!(problem.severity, Severity::Error);
+        assert_eq!(problem.problem, "interface_name swiftlink-none0 doesn't exist");
+    }
+
+    #[test]
+    fn test_check_clock() {
+        assert!(check_clock(SystemTime::now()).is_none());
+        assert!(check_clock(SystemTime::UNIX_EPOCH + Duration::from_secs(86400)).is_some());
+    }
+
+    #[test]
+    fn test_problem_order() {
+        let mut problems = [Problem::warning("w", "fix w"), Problem::error("e", "fix e")];
+        problems.sort_by_key(|problem| problem.severity);
+        assert_eq!(problems[0].severity, Severity::Error);
+        assert_eq!(problems[0].to_string(), "error    e\n         fix: fix e");
+    }
+}
@@ -0,0 +1,333 @@
+//! Downloads of data files, e.g. the GeoIP database, over plain http or https.
+//!
+//! Files are fetched directly, like the captive portal checks, the proxies may need the very
+//! file to route. Redirects are followed, a response must be complete: a body shorter than its
+//! `Content-Length`, or a chunked body without its last chunk, is an error.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::{
+    rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
+    TlsConnector,
+};
+
+use swiftlink_infra::net::ConnectOpts;
+
+use crate::outbound;
+
+const MAX_REDIRECTS: usize = 5;
+
+const MAX_HEAD_LEN: usize = 8192;
+
+/// An `http` or `https` url.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Url {
+    tls: bool,
+    /// `host[:port]`, as sent in the `Host` header
+    authority: String,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    /// Parses `http[s]://host[:port][/path]`, `None` if `url` isn't of that form.
+    fn parse(url: &str) -> Option<Self> {
+        let (tls, rest) = match url.strip_prefix("https://") {
+            Some(rest) => (true, rest),
+            None => (false, url.strip_prefix("http://")?),
+        };
+        let (authority, path) = rest.split_at(rest.find(['/', '?', '#']).unwrap_or(rest.len()));
+        let (host, port) = match authority.rsplit_once(':') {
+            // the colons of a bracketed IPv6 address
+            Some((host, port)) if !port.contains(']') => (host, port.parse().ok()?),
+            _ => (authority, if tls { 443 } else { 80 }),
+        };
+        if host.is_empty() || host.contains(['@', ' ']) {
+            return None;
+        }
+        let path = path.split('#').next().unwrap_or_default();
+        let path = match path {
+            "" => "/".to_owned(),
+            path if path.starts_with('?') => format!("/{}", path),
+            path => path.to_owned(),
+        };
+        Some(Self {
+            tls,
+            authority: authority.to_owned(),
+            host: host.to_owned(),
+            port,
+            path,
+        })
+    }
+
+    /// The url `location` redirects to, an absolute url or a path on the same server.
+    fn redirect(&self, location: &str) -> Option<Self> {
+        if location.starts_with('/') && !location.starts_with("//") {
+            return Some(Self {
+                path: location.to_owned(),
+                ..self.clone()
+            });
+        }
+        Self::parse(location)
+    }
+}
+
+enum Response {
+    Body(Vec<u8>),
+    Redirect(String),
+}
+
+/// Downloads `url`, which must be `http` or `https`, a body over `max_len` bytes is an error.
+pub(crate) async fn download(url: &str, connect_opts: &ConnectOpts, max_len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut url = Url::parse(url).ok_or_else(|| anyhow!("{} is not an http or https url", url))?;
+    for _ in 0..=MAX_REDIRECTS {
+        let stream = outbound::dial(&format!("{}:{}", url.host, url.port), connect_opts)
+            .await
+            .with_context(|| format!("connect to {} failed", url.authority))?;
+        let response = if url.tls {
+            let name = url.host.trim_start_matches('[').trim_end_matches(']');
+            let name = ServerName::try_from(name).with_context(|| format!("invalid server name {}", name))?;
+            let stream = TlsConnector::from(tls_config())
+                .connect(name, stream)
+                .await
+                .with_context(|| format!("tls handshake with {} failed", url.authority))?;
+            get(stream, &url, max_len).await?
+        } else {
+            get(stream, &url, max_len).await?
+        };
+
+        match response {
+            Response::Body(body) => return Ok(body),
+            Response::Redirect(location) => {
+                url = url
+                    .redirect(&location)
+                    .ok_or_else(|| anyhow!("{} redirected to {}", url.authority, location))?;
+            }
+        }
+    }
+    bail!("more than {} redirects", MAX_REDIRECTS)
+}
+
+/// Requests `url` on `stream` and reads the response until the server closes the connection.
+async fn get<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, url: &Url, max_len: usize) -> anyhow::Result<Response> {
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}/{}\r\nAccept-Encoding: identity\r\nConnection: close\r\n\r\n",
+        url.path,
+        url.authority,
+        crate::NAME,
+        crate::version()
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut buf = Vec::with_capacity(64 * 1024);
+    let mut chunk = vec![0u8; 64 * 1024];
+    loop {
+        let n = match stream.read(&mut chunk).await {
+            Ok(n) => n,
+            // servers closing tls connections without `close_notify`, the length tells if it's complete
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => 0,
+            Err(err) => return Err(err.into()),
+        };
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() > max_len + MAX_HEAD_LEN {
+            bail!("{}{} is larger than {} bytes", url.authority, url.path, max_len);
+        }
+    }
+
+    let head_len = buf
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .filter(|&pos| pos < MAX_HEAD_LEN)
+        .ok_or_else(|| anyhow!("{} sent no http response", url.authority))?;
+    let head = String::from_utf8_lossy(&buf[..head_len]).into_owned();
+    let status_line = head
+        .lines()
+        .next()
+        .filter(|line| line.starts_with("HTTP/"))
+        .unwrap_or_default();
+    let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+    let header = |name: &str| {
+        head.lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    };
+
+    match status {
+        "200" => {}
+        "301" | "302" | "303" | "307" | "308" => {
+            let location = header("location").ok_or_else(|| anyhow!("{} redirected nowhere", url.authority))?;
+            return Ok(Response::Redirect(location.to_owned()));
+        }
+        _ => bail!("{}{} answered {}", url.authority, url.path, status_line),
+    }
+
+    let mut body = buf.split_off(head_len + 4);
+    let chunked = header("transfer-encoding").is_some_and(|value| value.eq_ignore_ascii_case("chunked"));
+    if chunked {
+        body =
+            dechunk(&body).ok_or_else(|| anyhow!("{}{} sent an incomplete chunked body", url.authority, url.path))?;
+    } else if let Some(len) = header("content-length") {
+        let len: usize = len.parse().context("invalid content-length")?;
+        if body.len() != len {
+            bail!("{}{} sent {} of {} bytes", url.authority, url.path, body.len(), len);
+        }
+    }
+    if body.len() > max_len {
+        bail!("{}{} is larger than {} bytes", url.authority, url.path, max_len);
+    }
+    Ok(Response::Body(body))
+}
+
+/// The file a download is written to before it replaces `path`, in the same directory so the
+/// rename is atomic.
+pub(crate) fn download_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".download");
+    path.with_file_name(name)
+}
+
+/// Joins the chunks of a chunked body, `None` if it's malformed or misses its last chunk.
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(body.len());
+    loop {
+        let line_len = body.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_len]).ok()?;
+        // chunk extensions are ignored
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).ok()?;
+        body = &body[line_len + 2..];
+        if size == 0 {
+            return Some(data);
+        }
+        if body.len() < size + 2 || &body[size..size + 2] != b"\r\n" {
+            return None;
+        }
+        data.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+/// Trusts the bundled Mozilla roots, http/1.1 only.
+fn tls_config() -> Arc<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(
+        webpki_roots::TLS_SERVER_ROOTS
+            .iter()
+            .map(|ta| OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)),
+    );
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Arc::new(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Serves a redirect from `/latest` and the chunked body at `/file`, plus a truncated one at
+    /// `/truncated`.
+    async fn file_server() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    let n = stream.read(&mut buf).await.unwrap();
+                    let request = String::from_utf8_lossy(&buf[..n]).into_owned();
+                    let response: &[u8] = if request.starts_with("GET /latest ") {
+                        b"HTTP/1.1 302 Found\r\nLocation: /file\r\nContent-Length: 0\r\n\r\n"
+                    } else if request.starts_with("GET /file ") {
+                        b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\n"
+                    } else if request.starts_with("GET /truncated ") {
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nhello"
+                    } else {
+                        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"
+                    };
+                    _ = stream.write_all(response).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_parse_url() {
+        let url = Url::parse("https://example.com/GeoLite2-Country.mmdb#latest").unwrap();
+        assert_eq!(
+            (url.tls, url.host.as_str(), url.port, url.path.as_str()),
+            (true, "example.com", 443, "/GeoLite2-Country.mmdb")
+        );
+        let url = Url::parse("http://[::1]:8080?download").unwrap();
+        assert_eq!(
+            (url.tls, url.host.as_str(), url.port, url.path.as_str()),
+            (false, "[::1]", 8080, "/?download")
+        );
+
+        let moved = url.redirect("/Country.mmdb").unwrap();
+        assert_eq!(
+            (moved.authority.as_str(), moved.path.as_str()),
+            ("[::1]:8080", "/Country.mmdb")
+        );
+        let moved = url.redirect("https://cdn.example.com/Country.mmdb").unwrap();
+        assert_eq!((moved.tls, moved.host.as_str()), (true, "cdn.example.com"));
+        assert_eq!(url.redirect("//cdn.example.com/Country.mmdb"), None);
+
+        assert_eq!(Url::parse("ftp://example.com/"), None);
+        assert_eq!(Url::parse("https://user@example.com/"), None);
+    }
+
+    #[test]
+    fn test_download_path() {
+        assert_eq!(
+            download_path(Path::new("/var/lib/swiftlink/Country.mmdb")),
+            Path::new("/var/lib/swiftlink/Country.mmdb.download")
+        );
+    }
+
+    #[test]
+    fn test_dechunk() {
+        assert_eq!(dechunk(b"3\r\nabc\r\n0\r\n\r\n").unwrap(), b"abc");
+        assert_eq!(dechunk(b"0\r\n\r\n").unwrap(), b"");
+        // the last chunk is missing
+        assert_eq!(dechunk(b"3\r\nabc\r\n"), None);
+        assert_eq!(dechunk(b"a\r\nabc\r\n0\r\n\r\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_download() {
+        let addr = file_server().await;
+        let opts = ConnectOpts::default();
+
+        let body = download(&format!("http://{}/latest", addr), &opts, 1024).await.unwrap();
+        assert_eq!(body, b"hello world");
+
+        let err = download(&format!("http://{}/file", addr), &opts, 4).await.unwrap_err();
+        assert!(err.to_string().contains("larger than 4 bytes"), "{}", err);
+        let err = download(&format!("http://{}/truncated", addr), &opts, 1024)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("5 of 100 bytes"), "{}", err);
+        let err = download(&format!("http://{}/missing", addr), &opts, 1024)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("404"), "{}", err);
+    }
+}
@@ -0,0 +1,120 @@
+//! `swiftlink fakeip`, the fake ips the dns server of a running swiftlink handed out.
+//!
+//! The mappings are read from `/fakeip/mappings` of the external controller, which must be
+//! enabled. They come from memory, or from the cache file with `fake_ip_persist`.
+
+use std::{fmt, net::IpAddr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{controller, Config};
+
+/// Mappings the controller returns at most per request.
+pub const MAX_PAGE_LEN: usize = 1000;
+
+/// A fake ip and the host it was handed out for.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Mapping {
+    pub ip: IpAddr,
+    pub host: String,
+}
+
+impl Mapping {
+    /// Whether `query`, an ip or a host, is one of the sides of the mapping.
+    pub fn matches(&self, query: &str) -> bool {
+        match query.parse::<IpAddr>() {
+            Ok(ip) => ip == self.ip,
+            Err(_) => self
+                .host
+                .trim_end_matches('.')
+                .eq_ignore_ascii_case(query.trim_end_matches('.')),
+        }
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<39}  {}", self.ip.to_string(), self.host)
+    }
+}
+
+/// The mappings from `offset` on, ordered by ip, and how many there are in total.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MappingPage {
+    pub total: usize,
+    pub offset: usize,
+    pub mappings: Vec<Mapping>,
+}
+
+/// Reads `limit` mappings from `offset` on, all of them without a limit, and the total.
+pub fn list(config: &Config, offset: usize, limit: Option<usize>) -> anyhow::Result<(Vec<Mapping>, usize)> {
+    let mut mappings = Vec::new();
+    loop {
+        let wanted = limit.map_or(MAX_PAGE_LEN, |limit| (limit - mappings.len()).min(MAX_PAGE_LEN));
+        let target = format!("/fakeip/mappings?offset={}&limit={}", offset + mappings.len(), wanted);
+        let page: MappingPage = controller::get(config, &target)?;
+        let done = page.mappings.len() < wanted || limit.is_some_and(|limit| mappings.len() + wanted >= limit);
+        mappings.extend(page.mappings);
+        if done {
+            return Ok((mappings, page.total));
+        }
+    }
+}
+
+/// Reads the mappings of `query`, a fake ip or a host with a fake ip4 and ip6.
+pub fn lookup(config: &Config, query: &str) -> anyhow::Result<Vec<Mapping>> {
+    let page: MappingPage = controller::get(config, &format!("/fakeip/mappings?query={}", query))?;
+    Ok(page.mappings)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use swiftlink_infra::fakedns::{self, FakeDns};
+
+    use crate::{api::Api, config::ControllerCors, controller::TestController};
+
+    use super::*;
+
+    #[test]
+    fn test_mapping_matches() {
+        let mapping = Mapping {
+            ip: "198.18.0.2".parse().unwrap(),
+            host: "www.example.com.".to_owned(),
+        };
+        assert!(mapping.matches("198.18.0.2"));
+        assert!(mapping.matches("WWW.example.com"));
+        assert!(mapping.matches("www.example.com."));
+        assert!(!mapping.matches("198.18.0.3"));
+        assert!(!mapping.matches("example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_list_and_lookup() {
+        let fakedns = Arc::new(Mutex::new(FakeDns::new(fakedns::Config::default())));
+        {
+            let mut fakedns = fakedns.lock().unwrap();
+            for n in 0..1200 {
+                fakedns.lookup_ip(&format!("host{}.example.com.", n), false);
+            }
+            fakedns.lookup_ip("host0.example.com.", true);
+        }
+        let controller = TestController::start(Api::new(None, ControllerCors::default()).with_fakedns(fakedns)).await;
+
+        let (mappings, total, found) = controller
+            .request(None, |config| {
+                let (all, total) = list(config, 0, None).unwrap();
+                assert_eq!(all.len(), total);
+                let (some, _) = list(config, 1000, Some(150)).unwrap();
+                assert_eq!(some, all[1000..1150]);
+                let found = lookup(config, "host0.example.com").unwrap();
+                (all, total, found)
+            })
+            .await;
+        assert_eq!(total, 1201);
+        assert!(mappings.windows(2).all(|pair| pair[0].ip < pair[1].ip));
+        assert_eq!(found.len(), 2);
+        assert!(found[0].ip.is_ipv4() && found[1].ip.is_ipv6());
+    }
+}
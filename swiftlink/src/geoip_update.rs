@@ -0,0 +1,142 @@
+//! Downloads of the country database of `GEOIP` rules.
+//!
+//! With `geoip_url` set, the database at `geoip_location` is downloaded on start when the file is
+//! missing, and again once it's older than `geoip_update_interval`, by the modification time of
+//! the file. A download replaces the file only once it opens as a database, the loaded one is
+//! then swapped by [`GeoIpDb::reload`]. A failed download is retried after an hour, meanwhile the
+//! rules keep the database they have.
+//!
+//! ```toml
+//! geoip_location = "Country.mmdb"
+//! geoip_url = "https://example.com/geoip/Country.mmdb"
+//! geoip_update_interval = 604800
+//! ```
+
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+
+use swiftlink_infra::{geoip::GeoIpDb, log::*, net::ConnectOpts};
+
+use crate::download::{self, download_path};
+
+/// Country databases are a few megabytes, city ones less than a hundred.
+const MAX_DATABASE_LEN: usize = 128 * 1024 * 1024;
+
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Downloads the database at `url` to `path`, which is left as it was if the download failed or
+/// isn't a database.
+pub(crate) async fn fetch(url: &str, path: &Path, connect_opts: &ConnectOpts) -> anyhow::Result<()> {
+    let data = tokio::time::timeout(
+        DOWNLOAD_TIMEOUT,
+        download::download(url, connect_opts, MAX_DATABASE_LEN),
+    )
+    .await
+    .with_context(|| format!("download of {} timed out", url))??;
+
+    let tmp = download_path(path);
+    tokio::fs::write(&tmp, &data)
+        .await
+        .with_context(|| format!("write {:?} failed", tmp))?;
+    if let Err(err) = GeoIpDb::check(&tmp) {
+        _ = tokio::fs::remove_file(&tmp).await;
+        return Err(err).with_context(|| format!("{} is not a geoip database", url));
+    }
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("replace {:?} failed", path))?;
+    info!("downloaded geoip database from {}, {} bytes", url, data.len());
+    Ok(())
+}
+
+/// Keeps the loaded country database up to date.
+pub(crate) struct GeoIpUpdater {
+    db: Arc<GeoIpDb>,
+    url: String,
+    interval: Duration,
+    connect_opts: ConnectOpts,
+}
+
+impl GeoIpUpdater {
+    pub(crate) fn new(db: Arc<GeoIpDb>, url: &str, interval: Duration, connect_opts: ConnectOpts) -> Self {
+        Self {
+            db,
+            url: url.to_owned(),
+            interval,
+            connect_opts,
+        }
+    }
+
+    /// Downloads the database each time it's older than the interval, until the task is aborted.
+    pub(crate) async fn run(self) {
+        loop {
+            tokio::time::sleep(self.due_in()).await;
+            if let Err(err) = self.update().await {
+                warn!("geoip database update failed, {:#}", err);
+                tokio::time::sleep(RETRY_INTERVAL.min(self.interval)).await;
+            }
+        }
+    }
+
+    /// How long until the database is older than the interval, a file without a modification
+    /// time is due right away.
+    fn due_in(&self) -> Duration {
+        let age = std::fs::metadata(self.db.path())
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+        match age {
+            Some(age) => self.interval.saturating_sub(age),
+            None => Duration::ZERO,
+        }
+    }
+
+    async fn update(&self) -> anyhow::Result<()> {
+        fetch(&self.url, self.db.path(), &self.connect_opts).await?;
+        self.db.reload()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fetch_invalid_database() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            _ = stream.read(&mut [0u8; 1024]).await;
+            let response = b"HTTP/1.1 200 OK\r\nContent-Length: 22\r\n\r\nnot a maxmind database";
+            _ = stream.write_all(response).await;
+        });
+
+        let dir = std::env::temp_dir().join(format!("swiftlink-geoip-update-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("Country.mmdb");
+        std::fs::write(&path, b"the loaded database").unwrap();
+
+        let url = format!("http://{}/Country.mmdb", addr);
+        let err = fetch(&url, &path, &ConnectOpts::default()).await.unwrap_err();
+        assert!(err.to_string().contains("not a geoip database"), "{:#}", err);
+        // the loaded one is kept
+        assert_eq!(std::fs::read(&path).unwrap(), b"the loaded database");
+        assert!(!download_path(&path).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+//! Liveness and readiness endpoints for container orchestration.
+//!
+//! With `health_listen` set, a plain HTTP listener answers:
+//!
+//! - `GET /healthz`: `200` while the process serves, for liveness probes
+//! - `GET /readyz`: `200` if the listeners are bound and, with the dns server enabled, an
+//!   upstream dns server answers, `503` listing the failed checks otherwise
+//!
+//! It is separate from any status API: no authentication, plain text, one request per
+//! connection.
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+
+use swiftlink_dns::DnsResolver;
+use swiftlink_infra::log::*;
+
+use crate::http_head;
+
+/// Probes of the upstream dns servers are cached for this long, probes come every few seconds.
+const UPSTREAM_PROBE_TTL: Duration = Duration::from_secs(10);
+
+const UPSTREAM_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+const MAX_REQUEST_LEN: usize = 2048;
+
+/// The state the readiness checks look at.
+pub(crate) struct Health {
+    /// `None` if the dns server is disabled
+    resolver: Option<DnsResolver>,
+    shutdown: watch::Receiver<bool>,
+    upstream: Mutex<Option<(Instant, bool)>>,
+}
+
+impl Health {
+    pub(crate) fn new(resolver: Option<DnsResolver>, shutdown: watch::Receiver<bool>) -> Self {
+        Self {
+            resolver,
+            shutdown,
+            upstream: Mutex::new(None),
+        }
+    }
+
+    /// Runs the readiness checks, name and result of each.
+    async fn readiness(&self) -> Vec<(&'static str, bool)> {
+        let mut checks = vec![("listening", !*self.shutdown.borrow())];
+        if let Some(resolver) = self.resolver.as_ref() {
+            checks.push(("dns_upstream", self.probe_upstream(resolver).await));
+        }
+        checks
+    }
+
+    async fn probe_upstream(&self, resolver: &DnsResolver) -> bool {
+        if let Some((at, reachable)) = *self.upstream.lock().unwrap() {
+            if at.elapsed() < UPSTREAM_PROBE_TTL {
+                return reachable;
+            }
+        }
+
+        let reachable = tokio::time::timeout(UPSTREAM_PROBE_TIMEOUT, resolver.probe_upstream())
+            .await
+            .unwrap_or(false);
+        if !reachable {
+            warn!("readiness check: no upstream dns server answers");
+        }
+        *self.upstream.lock().unwrap() = Some((Instant::now(), reachable));
+        reachable
+    }
+}
+
+/// Serves the health endpoints until the task is aborted.
+pub(crate) async fn serve(listener: TcpListener, health: Arc<Health>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                debug!("health check accept failed, {}", err);
+                continue;
+            }
+        };
+
+        let health = health.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, &health).await {
+                debug!("health check request failed, {}", err);
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, health: &Health) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(256);
+    tokio::time::timeout(REQUEST_TIMEOUT, http_head::read(&mut stream, &mut buf, MAX_REQUEST_LEN))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))??;
+
+    let head = String::from_utf8_lossy(&buf);
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+    let path = path.split('?').next().unwrap_or_default();
+
+    let (status, body) = match (method, path) {
+        ("GET" | "HEAD", "/healthz") => ("200 OK", "ok\n".to_string()),
+        ("GET" | "HEAD", "/readyz") => {
+            let checks = health.readiness().await;
+            let status = if checks.iter().all(|(_, ok)| *ok) {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            let body = checks
+                .iter()
+                .map(|(name, ok)| format!("{} {}\n", if *ok { "ok" } else { "fail" }, name))
+                .collect();
+            (status, body)
+        }
+        (_, "/healthz" | "/readyz") => ("405 Method Not Allowed", String::new()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    if method != "HEAD" {
+        response.push_str(&body);
+    }
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    async fn request(addr: std::net::SocketAddr, method: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_health_endpoints() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let task = tokio::spawn(serve(listener, Arc::new(Health::new(None, shutdown_rx))));
+
+        let response = request(addr, "GET", "/healthz").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nok\n"));
+
+        let response = request(addr, "GET", "/readyz?verbose").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("ok listening\n"));
+
+        let response = request(addr, "HEAD", "/readyz").await;
+        assert!(response.ends_with("\r\n\r\n"));
+
+        assert!(request(addr, "POST", "/healthz").await.starts_with("HTTP/1.1 405"));
+        assert!(request(addr, "GET", "/").await.starts_with("HTTP/1.1 404"));
+
+        shutdown_tx.send_replace(true);
+        let response = request(addr, "GET", "/readyz").await;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.ends_with("fail listening\n"));
+        assert!(request(addr, "GET", "/healthz").await.starts_with("HTTP/1.1 200"));
+
+        task.abort();
+    }
+}
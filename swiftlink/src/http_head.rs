@@ -0,0 +1,50 @@
+//! The head of an HTTP/1 message, read by the servers and clients which speak plain HTTP: the
+//! api, the health and `204` endpoints, the captive portal probe and the http proxy.
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Reads until the end of the message head, returns its length. What was read past it stays in
+/// `buf`. Fails if the head is longer than `max_len` or the stream ends before it does.
+pub(crate) async fn read<R>(reader: &mut R, buf: &mut Vec<u8>, max_len: usize) -> io::Result<usize>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut chunk = [0u8; 1024];
+    loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            return Ok(i + 4);
+        }
+        if buf.len() >= max_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "message head too long"));
+        }
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read() {
+        // the end of the head split across reads
+        let mut reader = (&b"GET / HTTP/1.1\r\nHost: a\r"[..]).chain(&b"\n\r\nbody"[..]);
+        let mut buf = Vec::new();
+        assert_eq!(read(&mut reader, &mut buf, 64).await.unwrap(), 27);
+        assert_eq!(&buf[27..], b"body");
+
+        let mut reader = &b"GET / HTTP/1.1\r\n"[..];
+        let err = read(&mut reader, &mut Vec::new(), 64).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut reader = &[b'a'; 64][..];
+        let err = read(&mut reader, &mut Vec::new(), 64).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,70 @@
+//! DNS forwarding, the queries of the `[dns]` server on further UDP addresses.
+//!
+//! With `dns_forward_listen` set, each address is answered by the handles of the main dns server,
+//! for clients which must use another port or an address of their own network:
+//!
+//! ```toml
+//! dns_forward_listen = ["0.0.0.0:5353", "192.168.10.1:53"]
+//! ```
+//!
+//! Queries are logged with the `dns-forward` tag. Unlike `[[dns.listeners]]`, the addresses have
+//! no options of their own.
+
+use std::io;
+
+use tokio::net::UdpSocket;
+
+use swiftlink_dns::{ServerFuture, ServerHandle};
+
+/// Tag of the inbound in the query log
+pub(crate) const INBOUND_TAG: &str = "dns-forward";
+
+/// Answers the queries received on `socket` with `handle` until the socket fails.
+pub(crate) async fn serve(socket: UdpSocket, handle: ServerHandle) -> io::Result<()> {
+    let mut server = ServerFuture::new(handle.with_tag(INBOUND_TAG));
+    server.register_socket(socket);
+    server.block_until_done().await.map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, sync::Arc};
+
+    use swiftlink_dns::{build_dns_resolver, DnsConfig, ServerHandleBuilder};
+    use swiftlink_infra::net::ConnectOpts;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dns_forward() {
+        let dns = Arc::new(
+            DnsConfig::builder()
+                .address("printer.lan", vec![Ipv4Addr::new(192, 168, 10, 9).into()])
+                .build()
+                .unwrap(),
+        );
+        let resolver = build_dns_resolver(&dns, &ConnectOpts::default(), None).await;
+        let handle = ServerHandleBuilder::new(dns, resolver.into()).build();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let task = tokio::spawn(serve(socket, handle));
+
+        // ID, RD, one question: printer.lan A IN
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x07printer\x03lan\x00\x00\x01\x00\x01");
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&query, addr).await.unwrap();
+
+        let mut buf = [0u8; 512];
+        let (n, _) = client.recv_from(&mut buf).await.unwrap();
+        let response = &buf[..n];
+        assert_eq!(response[..2], [0x12, 0x34]);
+        // NOERROR and a single answer, its RDATA last
+        assert_eq!(response[3] & 0x0f, 0);
+        assert_eq!(response[6..8], [0, 1]);
+        assert_eq!(response[n - 4..], [192, 168, 10, 9]);
+
+        task.abort();
+    }
+}
@@ -0,0 +1,1093 @@
+//! HTTP proxy, for browsers and the proxy settings of the system.
+//!
+//! With `http_listen` set, a listener accepts `CONNECT host:port` tunnels, e.g. for HTTPS, and
+//! forwards plain HTTP requests in absolute form, `GET http://example.com/ HTTP/1.1`:
+//!
+//! ```toml
+//! http_listen = "127.0.0.1:7890"
+//! ```
+//!
+//! Connections are routed by the `rules` to an [`outbound`](crate::outbound), `REJECT` answers
+//! `403 Forbidden`. Plain HTTP keeps the connections of both sides open for further requests: the
+//! client's if it asks for it, the origin's in a pool of idle connections shared by all clients,
+//! by outbound, see [`IdlePool`]. Clients aren't authenticated, keep the listener on loopback or a
+//! trusted network, or behind `[knock]`.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc, OnceLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{Semaphore, SemaphorePermit},
+};
+
+use super::{route, InboundContext};
+use crate::{
+    http_head,
+    sni_proxy::{self, NEXT_CONNECTION_ID},
+};
+use swiftlink_dns::ProxyStream;
+use swiftlink_infra::{
+    connection::{CloseReason, ClosedConnection, Dialed},
+    event::{Event, OpenedConnection},
+    knock::KnockGate,
+    log::*,
+    net::idle_pool::{self, IdlePool},
+    traffic, watchdog,
+};
+
+/// The client must send its request head within this time.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A keep-alive client must send its next request within this time.
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Expired idle connections to origins are closed at this interval.
+const PURGE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Overloaded, the request to answer with `503` must arrive within this time.
+const BUSY_REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+/// The longest chunk size or trailer line of a chunked body.
+const MAX_LINE_LEN: usize = 4 * 1024;
+
+/// Headers of the hop between client and proxy, not forwarded.
+const HOP_HEADERS: [&str; 4] = ["connection", "keep-alive", "proxy-connection", "proxy-authorization"];
+
+/// Connections answered with `503` at the same time, further ones are closed right away.
+static BUSY_REPLIES: Semaphore = Semaphore::const_new(64);
+
+/// The connections to origins between two plain HTTP requests, by `host:port`.
+static IDLE_POOL: OnceLock<IdlePool<ProxyStream>> = OnceLock::new();
+
+fn idle_pool() -> &'static IdlePool<ProxyStream> {
+    IDLE_POOL.get_or_init(IdlePool::default)
+}
+
+/// How the end of the body of a message is found, RFC 9112 section 6.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Body {
+    Empty,
+    /// `Content-Length`
+    Length(u64),
+    /// `Transfer-Encoding: chunked`
+    Chunked,
+    /// the end of the connection, of responses only
+    UntilClose,
+}
+
+/// A request of a client, parsed from its head.
+#[derive(Debug, PartialEq, Eq)]
+struct ProxyRequest {
+    /// `host:port`
+    destination: String,
+    /// the head to send to the destination, `None` for a `CONNECT` tunnel
+    forward_head: Option<String>,
+    /// the body following the head, sent to the destination
+    body: Body,
+    /// a `HEAD` request, its response has no body
+    is_head: bool,
+    /// the client keeps the connection open for another request
+    keep_alive: bool,
+}
+
+impl ProxyRequest {
+    fn parse(head: &str) -> Option<Self> {
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next()?.split_whitespace();
+        let (method, target, version) = (request_line.next()?, request_line.next()?, request_line.next()?);
+
+        if method.eq_ignore_ascii_case("CONNECT") {
+            let (host, port) = split_port(target)?;
+            return Some(Self {
+                destination: format!("{}:{}", host, port?),
+                forward_head: None,
+                body: Body::Empty,
+                is_head: false,
+                keep_alive: false,
+            });
+        }
+
+        // plain HTTP only, clients tunnel HTTPS with CONNECT
+        let scheme = target
+            .get(..7)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("http://"))?;
+        let rest = &target[scheme.len()..];
+        let (authority, path) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+        let path = match path {
+            "" => "/".to_owned(),
+            query if query.starts_with('?') => format!("/{}", query),
+            path => path.to_owned(),
+        };
+        let (host, port) = split_port(authority)?;
+
+        let mut forward_head = format!("{} {} {}\r\n", method, path, version);
+        let mut has_host = false;
+        let (mut chunked, mut length) = (false, None);
+        let mut keep_alive = !version.eq_ignore_ascii_case("HTTP/1.0");
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let (name, value) = split_header(line);
+            if name.eq_ignore_ascii_case("connection") || name.eq_ignore_ascii_case("proxy-connection") {
+                keep_alive = keep_alive_of(value, keep_alive);
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                // a request body of another coding has no end
+                if !is_chunked(value) {
+                    return None;
+                }
+                chunked = true;
+            } else if name.eq_ignore_ascii_case("content-length") {
+                length = Some(content_length(value, length)?);
+            }
+            if HOP_HEADERS.iter().any(|hop| name.eq_ignore_ascii_case(hop)) {
+                continue;
+            }
+            has_host |= name.eq_ignore_ascii_case("host");
+            forward_head.push_str(line);
+            forward_head.push_str("\r\n");
+        }
+        if !has_host {
+            forward_head.push_str(&format!("Host: {}\r\n", authority));
+        }
+        forward_head.push_str("\r\n");
+        // the proxy and the origin could tell different ends of the body, RFC 9112 6.3
+        let body = match (chunked, length) {
+            (true, Some(_)) => return None,
+            (true, None) => Body::Chunked,
+            (false, length) => length.map_or(Body::Empty, Body::Length),
+        };
+
+        Some(Self {
+            destination: format!("{}:{}", host, port.unwrap_or(80)),
+            forward_head: Some(forward_head),
+            body,
+            is_head: method.eq_ignore_ascii_case("HEAD"),
+            keep_alive,
+        })
+    }
+}
+
+/// A response of an origin, parsed from its head.
+#[derive(Debug, PartialEq, Eq)]
+struct Response {
+    status: u16,
+    /// the head to send to the client, without the hop headers and the final empty line
+    forward_head: String,
+    body: Body,
+    /// the origin closes the connection after the response
+    close: bool,
+}
+
+impl Response {
+    /// Parses the head of the response to a request, a `HEAD` request if `is_head`.
+    fn parse(head: &str, is_head: bool) -> Option<Self> {
+        let mut lines = head.split("\r\n");
+        let status_line = lines.next()?;
+        let mut parts = status_line.split(' ');
+        let version = parts.next().filter(|version| version.starts_with("HTTP/1."))?;
+        let status = parts.next()?.parse().ok()?;
+
+        let mut forward_head = format!("{}\r\n", status_line);
+        let (mut chunked, mut encoded, mut length) = (false, false, None);
+        let mut keep_alive = version != "HTTP/1.0";
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let (name, value) = split_header(line);
+            if name.eq_ignore_ascii_case("connection") {
+                keep_alive = keep_alive_of(value, keep_alive);
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                encoded = true;
+                chunked = is_chunked(value);
+            } else if name.eq_ignore_ascii_case("content-length") {
+                length = Some(content_length(value, length)?);
+            }
+            if HOP_HEADERS.iter().any(|hop| name.eq_ignore_ascii_case(hop)) {
+                continue;
+            }
+            forward_head.push_str(line);
+            forward_head.push_str("\r\n");
+        }
+
+        if encoded && length.is_some() {
+            return None;
+        }
+        let body = match status {
+            _ if is_head => Body::Empty,
+            100..=199 | 204 | 304 => Body::Empty,
+            _ if chunked => Body::Chunked,
+            _ if encoded => Body::UntilClose,
+            _ => length.map_or(Body::UntilClose, Body::Length),
+        };
+        Some(Self {
+            status,
+            forward_head,
+            body,
+            close: !keep_alive || body == Body::UntilClose,
+        })
+    }
+}
+
+/// Splits a header line into its name and value.
+fn split_header(line: &str) -> (&str, &str) {
+    let (name, value) = line.split_once(':').unwrap_or((line, ""));
+    (name.trim(), value.trim())
+}
+
+/// Whether the connection stays open after a `Connection` header of `value`, `otherwise` if it
+/// tells neither.
+fn keep_alive_of(value: &str, otherwise: bool) -> bool {
+    let has = |token: &str| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token));
+    if has("close") {
+        false
+    } else if has("keep-alive") {
+        true
+    } else {
+        otherwise
+    }
+}
+
+/// Whether a `Transfer-Encoding` of `value` ends with `chunked`, only then the body has an end.
+fn is_chunked(value: &str) -> bool {
+    value
+        .rsplit(',')
+        .next()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// The length of a `Content-Length` of `value`, a list of the same length or the same as a
+/// `previous` one, `None` if the lengths differ.
+fn content_length(value: &str, previous: Option<u64>) -> Option<u64> {
+    let mut lengths = value.split(',').map(|length| length.trim().parse::<u64>().ok());
+    let length = lengths.next()??;
+    (lengths.all(|other| other == Some(length)) && previous.unwrap_or(length) == length).then_some(length)
+}
+
+/// Splits `host[:port]`, IPv6 addresses in brackets.
+fn split_port(authority: &str) -> Option<(&str, Option<u16>)> {
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.ends_with(']') => (host, Some(port.parse().ok()?)),
+        _ => (authority, None),
+    };
+    let valid = match host.strip_prefix('[') {
+        Some(ip) => ip
+            .strip_suffix(']')
+            .is_some_and(|ip| ip.parse::<std::net::Ipv6Addr>().is_ok()),
+        None => !host.is_empty() && !host.contains([':', '/', '@']),
+    };
+    valid.then_some((host, port))
+}
+
+/// Serves the clients of `listener`, the ones `gate` admits if set, until the task is aborted.
+/// Opened and closed connections are published to the events of `context`.
+pub(crate) async fn serve(listener: TcpListener, context: Arc<InboundContext>, gate: Option<Arc<KnockGate>>) {
+    let mut purge = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = purge.tick() => {
+                idle_pool().purge();
+                continue;
+            }
+        };
+        let (stream, source) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                debug!("http proxy accept failed, {}", err);
+                continue;
+            }
+        };
+
+        if gate.as_ref().is_some_and(|gate| !gate.is_admitted(source.ip())) {
+            debug!("http proxy closed the connection of {}, it didn't knock", source);
+            continue;
+        }
+
+        let Some((stream, guard)) = watchdog::admit(stream, &BUSY_REPLIES, reply_busy) else {
+            continue;
+        };
+
+        let context = context.clone();
+        tokio::spawn(async move {
+            let _guard = guard;
+            handle(stream, source, &context).await;
+        });
+    }
+}
+
+/// Answers the request of `client` with `503`, the connection isn't forwarded.
+async fn reply_busy(mut client: TcpStream, _permit: SemaphorePermit<'static>) {
+    let result = async {
+        let mut buf = Vec::with_capacity(1024);
+        tokio::time::timeout(BUSY_REPLY_TIMEOUT, http_head::read(&mut client, &mut buf, MAX_HEAD_LEN)).await??;
+        respond(
+            &mut client,
+            "503 Service Unavailable",
+            "The proxy is overloaded, try again later.",
+        )
+        .await
+    };
+    if let Err(err) = result.await {
+        debug!("http proxy busy reply to {:?} failed, {}", client.peer_addr(), err);
+    }
+}
+
+/// Forwards the requests of `client`, each one recorded as a connection of its own.
+async fn handle(mut client: TcpStream, source: SocketAddr, context: &InboundContext) {
+    traffic::total().connected();
+    // what the client sent and wasn't forwarded yet
+    let mut buf = Vec::with_capacity(1024);
+    let mut keep_alive = false;
+    loop {
+        let mut started = Instant::now();
+        let head_len = if keep_alive {
+            // the client closes the connection or keeps it idle once it has no further request
+            match tokio::time::timeout(KEEP_ALIVE_TIMEOUT, http_head::read(&mut client, &mut buf, MAX_HEAD_LEN)).await {
+                Ok(Ok(head_len)) => {
+                    started = Instant::now();
+                    Ok(head_len)
+                }
+                _ => break,
+            }
+        } else {
+            match tokio::time::timeout(REQUEST_TIMEOUT, http_head::read(&mut client, &mut buf, MAX_HEAD_LEN)).await {
+                Ok(Ok(head_len)) => Ok(head_len),
+                Ok(Err(err)) => Err((CloseReason::from_relay_error(&err), Some(err))),
+                Err(_) => {
+                    let err = io::Error::new(io::ErrorKind::TimedOut, "no request");
+                    Err((CloseReason::HandshakeTimeout, Some(err)))
+                }
+            }
+        };
+
+        let mut conn = ClosedConnection {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            network: "tcp",
+            inbound: context.tag.clone(),
+            source,
+            destination: "-".to_owned(),
+            rule: None,
+            outbound: None,
+            dialed: None,
+            upload: 0,
+            download: 0,
+            started_at: ClosedConnection::unix_millis(SystemTime::now()),
+            duration_ms: 0,
+            reason: CloseReason::Error,
+            error: None,
+        };
+
+        let result = match head_len {
+            Ok(head_len) => forward(&mut client, &mut buf, head_len, context, &mut conn).await,
+            Err(err) => Err(err),
+        };
+        keep_alive = match result {
+            Ok(keep_alive) => keep_alive,
+            Err((reason, err)) => {
+                conn.reason = reason;
+                conn.error = err.map(|err| err.to_string());
+                false
+            }
+        };
+        conn.duration_ms = started.elapsed().as_millis() as u64;
+        if context.events.has_subscribers() {
+            context.events.publish(Event::ConnectionClosed(Arc::new(conn.clone())));
+        }
+        context.connections.record(conn);
+
+        if !keep_alive {
+            break;
+        }
+    }
+}
+
+/// Forwards the request whose head is the first `head_len` bytes of `buf` to its destination,
+/// filling in `conn` on the way. Returns whether the client may send another request.
+async fn forward(
+    client: &mut TcpStream,
+    buf: &mut Vec<u8>,
+    head_len: usize,
+    context: &InboundContext,
+    conn: &mut ClosedConnection,
+) -> Result<bool, (CloseReason, Option<io::Error>)> {
+    let request = std::str::from_utf8(&buf[..head_len]).ok().and_then(ProxyRequest::parse);
+    let Some(request) = request else {
+        _ = respond(client, "400 Bad Request", "The request isn't a proxy request.").await;
+        let err = io::Error::new(io::ErrorKind::InvalidData, "invalid proxy request");
+        return Err((CloseReason::Error, Some(err)));
+    };
+    buf.drain(..head_len);
+    conn.destination = request.destination.clone();
+    let routed = match route(&context.router, &context.rule_hits, &context.outbounds, conn).await {
+        Ok(routed) => routed,
+        Err(err) => return Err(respond_unreachable(client, &request, err).await),
+    };
+
+    // an idle connection to the origin of a plain HTTP request, through the same outbound, is
+    // used first
+    let origin = format!("{},{}", routed.outbound(), request.destination);
+    let mut idle = None;
+    if request.forward_head.is_some() {
+        idle = idle_pool().checkout(&origin, |remote| idle_pool::is_reusable(remote.tcp()));
+    }
+    let mut opened = false;
+    let (mut remote, exchanged) = loop {
+        let reused = idle.is_some();
+        let mut remote = match idle.take() {
+            Some(remote) => {
+                let peer = remote.peer_addr().map_err(|err| (CloseReason::Error, Some(err)))?;
+                conn.dialed = Some(Dialed::new(peer, Duration::ZERO, Duration::ZERO));
+                remote
+            }
+            None => match routed
+                .dial(&context.outbounds, &request.destination, &context.connect_opts)
+                .await
+            {
+                Ok((remote, dialed)) => {
+                    conn.dialed = Some(dialed);
+                    remote
+                }
+                Err(err) => return Err(respond_unreachable(client, &request, err).await),
+            },
+        };
+        if !opened {
+            opened = true;
+            context.events.publish(Event::ConnectionOpened(OpenedConnection {
+                id: conn.id,
+                network: conn.network,
+                inbound: conn.inbound.clone(),
+                source: conn.source,
+                destination: conn.destination.clone(),
+                rule: conn.rule.clone().unwrap_or_else(|| "-".to_owned()),
+                outbound: routed.outbound().to_owned(),
+            }));
+        }
+
+        let Some(head) = &request.forward_head else {
+            break (remote, None);
+        };
+        match exchange(client, &mut remote, &request, head, buf).await {
+            Ok(exchanged) => break (remote, Some(exchanged)),
+            // the origin closed the idle connection meanwhile, nothing was lost on it
+            Err((err, false)) if reused && request.body == Body::Empty => {
+                debug!(
+                    "http proxy idle connection to {} was closed, {}",
+                    request.destination, err
+                );
+            }
+            Err((err, _)) => return Err((CloseReason::from_relay_error(&err), Some(err))),
+        }
+    };
+
+    match exchanged {
+        Some(exchanged) => {
+            conn.upload = exchanged.upload;
+            conn.download = exchanged.download;
+            traffic::total().add_upload(exchanged.upload);
+            traffic::total().add_download(exchanged.download);
+            if exchanged.status != 101 {
+                conn.reason = CloseReason::ServerEof;
+                if exchanged.reuse_remote {
+                    idle_pool().checkin(&origin, remote);
+                }
+                return Ok(exchanged.keep_alive);
+            }
+        }
+        None => {
+            let established = b"HTTP/1.1 200 Connection established\r\n\r\n";
+            client
+                .write_all(established)
+                .await
+                .map_err(|err| (CloseReason::from_relay_error(&err), Some(err)))?;
+        }
+    }
+
+    // a tunnel, or a connection switched to another protocol. What the client sent after the
+    // head, e.g. a ClientHello, comes first.
+    remote
+        .write_all(buf)
+        .await
+        .map_err(|err| (CloseReason::from_relay_error(&err), Some(err)))?;
+    conn.upload += buf.len() as u64;
+    let (result, upload, download) = sni_proxy::relay(client, &mut remote).await;
+    conn.upload += upload;
+    conn.download += download;
+    match result {
+        Ok(reason) => {
+            conn.reason = reason;
+            Ok(false)
+        }
+        Err(err) => Err((CloseReason::from_relay_error(&err), Some(err))),
+    }
+}
+
+/// A request and its response, forwarded.
+struct Exchanged {
+    status: u16,
+    /// bytes sent to the origin and to the client
+    upload: u64,
+    download: u64,
+    /// the connection to the origin can carry another request
+    reuse_remote: bool,
+    /// the client may send another request
+    keep_alive: bool,
+}
+
+/// Sends the plain HTTP `request` with `head` and its body to the origin on `remote`, and the
+/// response to `client`. `buf` holds what the client sent after the head, and is left with what
+/// followed the body.
+///
+/// Fails with the error and whether the client got any of the response.
+async fn exchange(
+    client: &mut TcpStream,
+    remote: &mut ProxyStream,
+    request: &ProxyRequest,
+    head: &str,
+    buf: &mut Vec<u8>,
+) -> Result<Exchanged, (io::Error, bool)> {
+    let (mut client_read, mut client_write) = client.split();
+    let (mut remote_read, mut remote_write) = tokio::io::split(remote);
+    let mut responded = false;
+
+    let send = async {
+        remote_write.write_all(head.as_bytes()).await?;
+        let body = copy_body(&mut client_read, &mut remote_write, buf, request.body).await?;
+        io::Result::Ok(head.len() as u64 + body)
+    };
+    let receive = async {
+        let mut buf = Vec::with_capacity(1024);
+        let mut download = 0;
+        loop {
+            let head_len = http_head::read(&mut remote_read, &mut buf, MAX_HEAD_LEN).await?;
+            let response = std::str::from_utf8(&buf[..head_len])
+                .ok()
+                .and_then(|head| Response::parse(head, request.is_head))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid response"))?;
+
+            // interim responses, and the switch to another protocol as it is
+            if (100..200).contains(&response.status) {
+                responded = true;
+                client_write.write_all(&buf[..head_len]).await?;
+                download += head_len as u64;
+                buf.drain(..head_len);
+                if response.status != 101 {
+                    continue;
+                }
+                client_write.write_all(&buf).await?;
+                download += buf.len() as u64;
+                return io::Result::Ok((response, download, false, false));
+            }
+
+            let keep_alive = request.keep_alive && !response.close;
+            let mut forward_head = response.forward_head.clone();
+            if !keep_alive {
+                forward_head.push_str("Connection: close\r\n");
+            }
+            forward_head.push_str("\r\n");
+            responded = true;
+            client_write.write_all(forward_head.as_bytes()).await?;
+            buf.drain(..head_len);
+            download += forward_head.len() as u64;
+            download += copy_body(&mut remote_read, &mut client_write, &mut buf, response.body).await?;
+            // bytes after the response would be taken for the next one
+            let reuse_remote = !response.close && buf.is_empty();
+            return Ok((response, download, reuse_remote, keep_alive));
+        }
+    };
+
+    let result = async {
+        tokio::pin!(send, receive);
+        // the response may come before the whole body was sent, e.g. to refuse it
+        let mut upload = None;
+        let (response, download, reuse_remote, keep_alive) = loop {
+            tokio::select! {
+                sent = &mut send, if upload.is_none() => upload = Some(sent?),
+                received = &mut receive => break received?,
+            }
+        };
+        let sent = upload.is_some();
+        Ok(Exchanged {
+            status: response.status,
+            upload: upload.unwrap_or_default(),
+            download,
+            reuse_remote: reuse_remote && sent,
+            keep_alive: keep_alive && sent,
+        })
+    }
+    .await;
+    result.map_err(|err| (err, responded))
+}
+
+/// Copies a body delimited by `body` from `reader` to `writer`. `buf` holds what was read from
+/// `reader` already, and is left with what followed the body. Returns the bytes copied.
+async fn copy_body<R, W>(reader: &mut R, writer: &mut W, buf: &mut Vec<u8>, body: Body) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match body {
+        Body::Empty => Ok(0),
+        Body::Length(len) => copy_exact(reader, writer, buf, len).await,
+        Body::UntilClose => {
+            writer.write_all(buf).await?;
+            let copied = buf.len() as u64 + tokio::io::copy(reader, writer).await?;
+            buf.clear();
+            Ok(copied)
+        }
+        Body::Chunked => {
+            let mut copied = 0;
+            loop {
+                let line_len = read_line(reader, buf).await?;
+                let size = std::str::from_utf8(&buf[..line_len - 2])
+                    .ok()
+                    .and_then(|line| u64::from_str_radix(line.split(';').next()?.trim(), 16).ok())
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))?;
+                copied += copy_exact(reader, writer, buf, line_len as u64).await?;
+                if size == 0 {
+                    break;
+                }
+                // the chunk and its CRLF
+                copied += copy_exact(reader, writer, buf, size + 2).await?;
+            }
+            // the trailers, up to the empty line
+            loop {
+                let line_len = read_line(reader, buf).await?;
+                copied += copy_exact(reader, writer, buf, line_len as u64).await?;
+                if line_len == 2 {
+                    return Ok(copied);
+                }
+            }
+        }
+    }
+}
+
+/// Copies `len` bytes from `reader` to `writer`, the ones in `buf` first.
+async fn copy_exact<R, W>(reader: &mut R, writer: &mut W, buf: &mut Vec<u8>, len: u64) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let buffered = len.min(buf.len() as u64) as usize;
+    writer.write_all(&buf[..buffered]).await?;
+    buf.drain(..buffered);
+    let rest = len - buffered as u64;
+    if tokio::io::copy(&mut reader.take(rest), writer).await? < rest {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(len)
+}
+
+/// Reads until `buf` holds a line, returns its length with the CRLF.
+async fn read_line<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut Vec<u8>) -> io::Result<usize> {
+    let mut chunk = [0u8; 1024];
+    loop {
+        if let Some(i) = buf.windows(2).position(|w| w == b"\r\n") {
+            return Ok(i + 2);
+        }
+        if buf.len() >= MAX_LINE_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+        }
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Answers that the destination of `request` can't be connected to, or that the rules reject it.
+async fn respond_unreachable(
+    client: &mut TcpStream,
+    request: &ProxyRequest,
+    err: io::Error,
+) -> (CloseReason, Option<io::Error>) {
+    let reason = CloseReason::from_dial_error(&err);
+    let message = format!("{} can't be reached, {}.", request.destination, err);
+    _ = respond(client, dial_status(reason), &message).await;
+    (reason, Some(err))
+}
+
+/// The status answering a request whose destination couldn't be connected to.
+fn dial_status(reason: CloseReason) -> &'static str {
+    match reason {
+        CloseReason::PolicyReject | CloseReason::LoopDetected => "403 Forbidden",
+        CloseReason::HandshakeTimeout => "504 Gateway Timeout",
+        _ => "502 Bad Gateway",
+    }
+}
+
+/// Answers with `status` and a page telling `message`, then closes the connection.
+async fn respond(client: &mut TcpStream, status: &str, message: &str) -> io::Result<()> {
+    let body = format!(
+        "<html><head><title>{status}</title></head><body><h1>{status}</h1><p>{}</p></body></html>\n",
+        escape_html(message)
+    );
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    client.write_all(response.as_bytes()).await?;
+    client.shutdown().await
+}
+
+/// Escapes `text` for the content of an element.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use swiftlink_infra::{connection::ConnectionHistory, event::EventBus, net::ConnectOpts};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let request = ProxyRequest::parse("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n");
+        assert_eq!(
+            request,
+            Some(ProxyRequest {
+                destination: "example.com:443".to_owned(),
+                forward_head: None,
+                body: Body::Empty,
+                is_head: false,
+                keep_alive: false,
+            })
+        );
+        let request = ProxyRequest::parse("CONNECT [::1]:8443 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.destination, "[::1]:8443");
+
+        let request = ProxyRequest::parse(
+            "GET http://Example.com/a?b=c HTTP/1.1\r\nHost: Example.com\r\nProxy-Connection: keep-alive\r\n\
+             Accept: */*\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(request.destination, "Example.com:80");
+        assert_eq!(
+            request.forward_head.as_deref(),
+            Some("GET /a?b=c HTTP/1.1\r\nHost: Example.com\r\nAccept: */*\r\n\r\n")
+        );
+        assert_eq!((request.body, request.keep_alive), (Body::Empty, true));
+
+        let request = ProxyRequest::parse("GET http://[::1]:8080 HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(request.destination, "[::1]:8080");
+        assert_eq!(
+            request.forward_head.as_deref(),
+            Some("GET / HTTP/1.0\r\nHost: [::1]:8080\r\n\r\n")
+        );
+        assert!(!request.keep_alive);
+        let request =
+            ProxyRequest::parse("POST http://example.com/ HTTP/1.1\r\nContent-Length: 5\r\nConnection: close\r\n\r\n")
+                .unwrap();
+        assert_eq!((request.body, request.keep_alive), (Body::Length(5), false));
+        let request =
+            ProxyRequest::parse("POST http://example.com/ HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n")
+                .unwrap();
+        assert_eq!(request.body, Body::Chunked);
+        let request = ProxyRequest::parse(
+            "POST http://example.com/ HTTP/1.1\r\nContent-Length: 5, 5\r\nContent-Length: 5\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(request.body, Body::Length(5));
+        let request = ProxyRequest::parse("HEAD http://example.com/ HTTP/1.1\r\n\r\n").unwrap();
+        assert!(request.is_head);
+        let request = ProxyRequest::parse("GET http://example.com?a HTTP/1.1\r\n\r\n").unwrap();
+        assert!(request.forward_head.unwrap().starts_with("GET /?a HTTP/1.1\r\n"));
+
+        // not a proxy request
+        assert_eq!(ProxyRequest::parse("GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"), None);
+        assert_eq!(ProxyRequest::parse("GET https://example.com/ HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(ProxyRequest::parse("CONNECT example.com HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(
+            ProxyRequest::parse("CONNECT user@example.com:443 HTTP/1.1\r\n\r\n"),
+            None
+        );
+        assert_eq!(ProxyRequest::parse("CONNECT [::1 HTTP/1.1\r\n\r\n"), None);
+        // bodies without an end
+        assert_eq!(
+            ProxyRequest::parse("POST http://example.com/ HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"),
+            None
+        );
+        assert_eq!(
+            ProxyRequest::parse("POST http://example.com/ HTTP/1.1\r\nContent-Length: -1\r\n\r\n"),
+            None
+        );
+        // bodies of ambiguous ends
+        assert_eq!(
+            ProxyRequest::parse(
+                "POST http://example.com/ HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\nContent-Length: 5\r\n\r\n"
+            ),
+            None
+        );
+        assert_eq!(
+            ProxyRequest::parse("POST http://example.com/ HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n"),
+            None
+        );
+        assert_eq!(
+            ProxyRequest::parse("POST http://example.com/ HTTP/1.1\r\nContent-Length: 5, 6\r\n\r\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let response = Response::parse(
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nKeep-Alive: timeout=5\r\nServer: test\r\n\r\n",
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            response,
+            Response {
+                status: 200,
+                forward_head: "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nServer: test\r\n".to_owned(),
+                body: Body::Length(2),
+                close: false,
+            }
+        );
+        let parse =
+            |head: &str, is_head| Response::parse(head, is_head).map(|response| (response.body, response.close));
+        assert_eq!(
+            parse("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n", true),
+            Some((Body::Empty, false))
+        );
+        assert_eq!(
+            parse("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n", false),
+            Some((Body::Chunked, false))
+        );
+        assert_eq!(
+            parse("HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\n", false),
+            Some((Body::UntilClose, true))
+        );
+        assert_eq!(parse("HTTP/1.1 200 OK\r\n\r\n", false), Some((Body::UntilClose, true)));
+        assert_eq!(
+            parse("HTTP/1.1 304 Not Modified\r\n\r\n", false),
+            Some((Body::Empty, false))
+        );
+        assert_eq!(
+            parse("HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n", false),
+            Some((Body::Empty, true))
+        );
+        assert_eq!(
+            parse("HTTP/1.0 200 OK\r\nContent-Length: 0\r\n\r\n", false),
+            Some((Body::Length(0), true))
+        );
+        assert_eq!(
+            parse(
+                "HTTP/1.0 200 OK\r\nContent-Length: 0\r\nConnection: Keep-Alive\r\n\r\n",
+                false
+            ),
+            Some((Body::Length(0), false))
+        );
+        assert_eq!(parse("HTTP/2 200\r\n\r\n", false), None);
+        assert_eq!(parse("HTTP/1.1 OK\r\n\r\n", false), None);
+        assert_eq!(
+            parse(
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Length: 2\r\n\r\n",
+                false
+            ),
+            None
+        );
+        assert_eq!(
+            parse(
+                "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nContent-Length: 3\r\n\r\n",
+                false
+            ),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_copy_body() {
+        let copy = |input: &'static [u8], buffered: &'static [u8], body| async move {
+            let (mut reader, mut writer) = (input, Vec::new());
+            let mut buf = buffered.to_vec();
+            let copied = copy_body(&mut reader, &mut writer, &mut buf, body).await?;
+            assert_eq!(copied, writer.len() as u64);
+            io::Result::Ok((writer, buf, reader))
+        };
+
+        let (copied, buf, rest) = copy(b"lo, world", b"hel", Body::Length(5)).await.unwrap();
+        assert_eq!(
+            (&copied[..], &buf[..], rest),
+            (&b"hello"[..], &b""[..], &b", world"[..])
+        );
+        let (copied, buf, _) = copy(b"", b"hello", Body::Length(2)).await.unwrap();
+        assert_eq!((&copied[..], &buf[..]), (&b"he"[..], &b"llo"[..]));
+        assert!(copy(b"hel", b"", Body::Length(5)).await.is_err());
+
+        let chunked = b"5;ext=1\r\nhello\r\n6\r\n, worl\r\n0\r\nExpires: never\r\n\r\nGET";
+        let (copied, buf, _) = copy(&chunked[4..], &chunked[..4], Body::Chunked).await.unwrap();
+        assert_eq!(&copied[..], &chunked[..chunked.len() - 3]);
+        assert_eq!(&buf[..], b"GET");
+        assert!(copy(b"x\r\n", b"", Body::Chunked).await.is_err());
+        assert!(copy(b"5\r\nhel", b"", Body::Chunked).await.is_err());
+
+        let (copied, ..) = copy(b"lo", b"hel", Body::UntilClose).await.unwrap();
+        assert_eq!(&copied[..], b"hello");
+        let (copied, buf, _) = copy(b"hello", b"", Body::Empty).await.unwrap();
+        assert!(copied.is_empty() && buf.is_empty());
+    }
+
+    #[test]
+    fn test_dial_status() {
+        assert_eq!(dial_status(CloseReason::DialRefused), "502 Bad Gateway");
+        assert_eq!(dial_status(CloseReason::HandshakeTimeout), "504 Gateway Timeout");
+        assert_eq!(dial_status(CloseReason::LoopDetected), "403 Forbidden");
+        assert_eq!(dial_status(CloseReason::Error), "502 Bad Gateway");
+        assert_eq!(escape_html("<a href=\"x\">&"), "&lt;a href=\"x\"&gt;&amp;");
+    }
+
+    #[tokio::test]
+    async fn test_http_proxy() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(ConnectionHistory::new(8));
+        let context = Arc::new(InboundContext {
+            tag: "http-lan".to_owned(),
+            connect_opts: Arc::new(ConnectOpts::default()),
+            connections: connections.clone(),
+            events: Arc::new(EventBus::default()),
+            users: Arc::default(),
+            router: Arc::default(),
+            rule_hits: Arc::default(),
+            outbounds: Arc::default(),
+        });
+        let task = tokio::spawn(serve(listener, context, None));
+
+        // a tunnel, with data sent along with the request
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let request = format!("CONNECT {} HTTP/1.1\r\n\r\nping", origin_addr);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let (mut server, _) = origin.accept().await.unwrap();
+        let mut received = [0u8; 4];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+        server.write_all(b"pong").await.unwrap();
+        drop(server);
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"HTTP/1.1 200 Connection established\r\n\r\npong");
+        drop(client);
+
+        // plain HTTP, in origin form at the destination, on one connection to the origin
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET http://{}/index.html HTTP/1.1\r\nProxy-Connection: keep-alive\r\n\r\n",
+            origin_addr
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        let (mut server, _) = origin.accept().await.unwrap();
+        let mut buf = vec![];
+        let head_len = http_head::read(&mut server, &mut buf, MAX_HEAD_LEN).await.unwrap();
+        let expected = format!("GET /index.html HTTP/1.1\r\nHost: {}\r\n\r\n", origin_addr);
+        assert_eq!(std::str::from_utf8(&buf[..head_len]).unwrap(), expected);
+        buf.drain(..head_len);
+        server
+            .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = vec![];
+        let head_len = http_head::read(&mut client, &mut response, MAX_HEAD_LEN).await.unwrap();
+        assert_eq!(
+            &response[..head_len],
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n"
+        );
+        let body = b"2\r\nok\r\n0\r\n\r\n";
+        let mut rest = vec![0u8; head_len + body.len() - response.len()];
+        client.read_exact(&mut rest).await.unwrap();
+        response.extend_from_slice(&rest);
+        assert_eq!(&response[head_len..], body);
+        while idle_pool().is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // the next request of the client, from another one to the same origin
+        let request = format!(
+            "POST http://{}/form HTTP/1.1\r\nContent-Length: 4\r\nConnection: close\r\n\r\nname",
+            origin_addr
+        );
+        let mut other = TcpStream::connect(addr).await.unwrap();
+        other.write_all(request.as_bytes()).await.unwrap();
+        let head_len = http_head::read(&mut server, &mut buf, MAX_HEAD_LEN).await.unwrap();
+        assert!(buf[..head_len].starts_with(b"POST /form HTTP/1.1\r\n"));
+        let mut body = [0u8; 4];
+        buf.drain(..head_len);
+        server.read_exact(&mut body[buf.len()..]).await.unwrap();
+        body[..buf.len()].copy_from_slice(&buf);
+        assert_eq!(&body, b"name");
+        server
+            .write_all(b"HTTP/1.1 204 No Content\r\nConnection: keep-alive\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = vec![];
+        other.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+        drop(other);
+
+        // the origin closed the idle connection, a new one is dialed
+        drop(server);
+        let request = format!("GET http://{}/ HTTP/1.1\r\n\r\n", origin_addr);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let (mut server, _) = origin.accept().await.unwrap();
+        let mut buf = vec![];
+        http_head::read(&mut server, &mut buf, MAX_HEAD_LEN).await.unwrap();
+        server
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+            .await
+            .unwrap();
+        drop(server);
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(
+            response,
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+        );
+        drop(client);
+
+        // nothing listens at the destination
+        drop(origin);
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let request = format!("CONNECT {} HTTP/1.1\r\n\r\n", origin_addr);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        assert!(response.contains("Content-Type: text/html"));
+        assert!(response.contains(&format!("<p>{} can't be reached", origin_addr)));
+
+        while connections.recent().len() < 5 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let recent = connections.recent();
+        assert!(recent.iter().all(|conn| conn.inbound == "http-lan"));
+        assert!(recent.iter().any(|conn| conn.reason == CloseReason::DialRefused));
+        for conn in &recent {
+            let remote = conn.dialed.map(|dialed| dialed.remote);
+            match conn.reason {
+                CloseReason::DialRefused => assert_eq!(remote, None),
+                _ => assert_eq!(remote, Some(origin_addr)),
+            }
+        }
+
+        task.abort();
+    }
+}
@@ -0,0 +1,228 @@
+//! Inbounds, the listeners clients connect to with a proxy protocol.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use swiftlink_dns::ProxyStream;
+use swiftlink_infra::{
+    connection::{ClosedConnection, ConnectionHistory, Dialed, Rejected},
+    event::EventBus,
+    net::ConnectOpts,
+    process,
+    rule_hits::RuleHits,
+    traffic::UserTraffic,
+};
+
+use crate::{
+    outbound::{self, Outbounds},
+    route::{Metadata, Router, DIRECT, REJECT},
+};
+
+pub(crate) mod dns_forward;
+pub(crate) mod http;
+pub(crate) mod socks;
+#[cfg(target_os = "linux")]
+pub(crate) mod tun;
+
+/// What the connections of an inbound listener share.
+pub(crate) struct InboundContext {
+    /// tag of the inbound in the connection history, matched by `INBOUND` rules
+    pub(crate) tag: String,
+    pub(crate) connect_opts: Arc<ConnectOpts>,
+    pub(crate) connections: Arc<ConnectionHistory>,
+    pub(crate) events: Arc<EventBus>,
+    /// the traffic of the users the inbound authenticated
+    pub(crate) users: Arc<UserTraffic>,
+    pub(crate) router: Arc<Router>,
+    /// the match counters of the rules of `router`
+    pub(crate) rule_hits: Arc<RuleHits>,
+    /// the outbounds the targets of the rules name
+    pub(crate) outbounds: Arc<Outbounds>,
+}
+
+/// A connection the rules let through, with its outbound and the addresses of its destination if
+/// a rule needed them.
+pub(crate) struct Routed {
+    outbound: String,
+    resolved: Option<(Vec<SocketAddr>, Duration)>,
+}
+
+impl Routed {
+    /// Connects to `destination` through the outbound, `DIRECT` dials the addresses resolved for
+    /// routing if any.
+    pub(crate) async fn dial(
+        &self,
+        outbounds: &Outbounds,
+        destination: &str,
+        connect_opts: &ConnectOpts,
+    ) -> io::Result<(ProxyStream, Dialed)> {
+        outbounds
+            .dial(&self.outbound, destination, self.resolved.as_ref(), connect_opts)
+            .await
+    }
+
+    /// The tag of the outbound.
+    pub(crate) fn outbound(&self) -> &str {
+        &self.outbound
+    }
+}
+
+/// [`route`], then dials the destination of `conn` with [`Routed::dial`].
+pub(crate) async fn dial_routed(
+    router: &Router,
+    rule_hits: &RuleHits,
+    outbounds: &Outbounds,
+    connect_opts: &ConnectOpts,
+    conn: &mut ClosedConnection,
+) -> io::Result<(ProxyStream, Dialed)> {
+    let routed = route(router, rule_hits, outbounds, conn).await?;
+    routed.dial(outbounds, &conn.destination, connect_opts).await
+}
+
+/// Routes `conn` by the rules of `router`, the rule which matched and the outbound, its target or
+/// the proxy a group target picks of `outbounds`, are recorded in `conn`. A connection the rules
+/// reject fails with [`Rejected`], classified as a policy reject. The destinations of no rule go
+/// `DIRECT`.
+pub(crate) async fn route(
+    router: &Router,
+    rule_hits: &RuleHits,
+    outbounds: &Outbounds,
+    conn: &mut ClosedConnection,
+) -> io::Result<Routed> {
+    let (host, port) = outbound::split_destination(&conn.destination)?;
+    let ip = host.parse::<IpAddr>().ok();
+
+    let process = if router.needs_process() {
+        let (network, source) = (conn.network, conn.source);
+        tokio::task::spawn_blocking(move || process::find(network, source))
+            .await
+            .ok()
+            .flatten()
+    } else {
+        None
+    };
+    let mut meta = Metadata {
+        inbound: &conn.inbound,
+        source: conn.source.ip(),
+        domain: ip.is_none().then_some(host),
+        ip,
+        port,
+        process: process.as_ref(),
+    };
+    // the addresses an IP rule needed are the ones dialed
+    let mut resolved = None;
+    if ip.is_none() && router.needs_ip(&meta) {
+        let started = Instant::now();
+        let addrs = outbound::resolve(&conn.destination).await?;
+        meta.ip = addrs.first().map(SocketAddr::ip);
+        resolved = Some((addrs, started.elapsed()));
+    }
+
+    let mut outbound = DIRECT;
+    if let Some(route) = router.route(&meta) {
+        rule_hits.hit(route.rule);
+        conn.rule = Some(route.rule.to_owned());
+        if route.target == REJECT {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                Rejected(route.rule.to_owned()),
+            ));
+        }
+        outbound = route.target;
+    }
+    let outbound = outbounds.pick(outbound, host, conn.source.ip())?;
+    conn.outbound = Some(outbound.clone());
+    Ok(Routed { outbound, resolved })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use swiftlink_infra::connection::CloseReason;
+
+    use super::*;
+    use crate::config::Rule;
+
+    fn closed(destination: &str) -> ClosedConnection {
+        ClosedConnection {
+            id: 1,
+            network: "tcp",
+            inbound: "socks".to_owned(),
+            source: "127.0.0.1:50000".parse().unwrap(),
+            destination: destination.to_owned(),
+            rule: None,
+            outbound: None,
+            dialed: None,
+            upload: 0,
+            download: 0,
+            started_at: ClosedConnection::unix_millis(SystemTime::now()),
+            duration_ms: 0,
+            reason: CloseReason::Error,
+            error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_route() {
+        let rules: Vec<Rule> = [
+            "DOMAIN-SUFFIX,ads.example.com,REJECT",
+            "IP-CIDR,127.0.0.0/8,DIRECT",
+            "MATCH,PROXY",
+        ]
+        .iter()
+        .map(|rule| rule.parse().unwrap())
+        .collect();
+        let router = Router::new(&rules).unwrap();
+        let hits = RuleHits::default();
+        let outbounds = Outbounds::default();
+        hits.set_rules(router.rules().iter().map(String::as_str));
+
+        let mut conn = closed("x.ads.example.com:443");
+        let err = route(&router, &hits, &outbounds, &mut conn).await.err().unwrap();
+        assert_eq!(CloseReason::from_dial_error(&err), CloseReason::PolicyReject);
+        assert_eq!(conn.rule.as_deref(), Some("DOMAIN-SUFFIX,ads.example.com,REJECT"));
+        assert_eq!(conn.outbound, None);
+
+        let mut conn = closed("127.0.0.1:80");
+        let routed = route(&router, &hits, &outbounds, &mut conn).await.unwrap();
+        assert!(routed.resolved.is_none());
+        assert_eq!(conn.rule.as_deref(), Some("IP-CIDR,127.0.0.0/8,DIRECT"));
+        assert_eq!(conn.outbound.as_deref(), Some(DIRECT));
+
+        // the IP rule resolves the domain, its addresses are dialed
+        let mut conn = closed("localhost:80");
+        let routed = route(&router, &hits, &outbounds, &mut conn).await.unwrap();
+        assert!(routed.resolved.is_some());
+
+        // the target is the outbound, one which doesn't exist can't be dialed
+        let mut conn = closed("[::1]:80");
+        let routed = route(&router, &hits, &outbounds, &mut conn).await.unwrap();
+        assert_eq!(conn.rule.as_deref(), Some("MATCH,PROXY"));
+        assert_eq!(conn.outbound.as_deref(), Some("PROXY"));
+        let err = routed
+            .dial(&outbounds, &conn.destination, &ConnectOpts::default())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        // no rule, direct
+        let mut conn = closed("[::1]:80");
+        let routed = route(&Router::default(), &hits, &outbounds, &mut conn).await.unwrap();
+        assert_eq!(routed.outbound(), DIRECT);
+        assert_eq!(conn.rule, None);
+
+        let mut conn = closed("localhost");
+        let err = route(&router, &hits, &outbounds, &mut conn).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let counts: Vec<_> = hits.snapshot().iter().map(|hit| hit.hits).collect();
+        assert_eq!(counts[0], 1);
+        assert_eq!(counts.iter().sum::<u64>(), 4);
+    }
+}
@@ -0,0 +1,450 @@
+//! SOCKS5 proxy, RFC 1928, for applications with SOCKS support.
+//!
+//! With `socks_listen` set, a listener accepts the `CONNECT` and `UDP ASSOCIATE` commands, and the
+//! `CONNECT` of SOCKS4 and SOCKS4a clients:
+//!
+//! ```toml
+//! socks_listen = "127.0.0.1:1080"
+//! ```
+//!
+//! Clients aren't authenticated by default, keep the listener on loopback or a trusted network,
+//! behind `[knock]`, or require a password, SOCKS4 clients are refused then:
+//!
+//! ```toml
+//! socks_auth = ["password"]
+//! socks_users = { alice = "secret" }
+//! ```
+//!
+//! Connections are routed by the `rules` to an [`outbound`](crate::outbound), `REJECT` refuses
+//! them. The datagrams of `UDP ASSOCIATE` are routed by their targets, see [`udp`]. Each
+//! association relays its datagrams through a UDP socket bound on the address the client connected
+//! to, and sends them out through sockets allocated by `udp_nat_policy`. The traffic of the users
+//! who authenticated is counted per user.
+
+use std::sync::Arc;
+
+use tokio::{net::TcpListener, sync::Semaphore};
+
+use super::InboundContext;
+use swiftlink_infra::{knock::KnockGate, log::*, watchdog};
+use swiftlink_transport::socks5::auth::AuthMethods;
+
+mod tcp;
+mod udp;
+
+/// Connections answered with a general failure at the same time, further ones are closed right
+/// away.
+static BUSY_REPLIES: Semaphore = Semaphore::const_new(64);
+
+/// Serves the clients of `listener`, the ones `gate` admits if set, authenticated with `methods`,
+/// until the task is aborted. Opened and closed connections are published to the events of
+/// `context`.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    methods: Arc<AuthMethods>,
+    context: Arc<InboundContext>,
+    gate: Option<Arc<KnockGate>>,
+) {
+    loop {
+        let (stream, source) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                debug!("socks proxy accept failed, {}", err);
+                continue;
+            }
+        };
+
+        if gate.as_ref().is_some_and(|gate| !gate.is_admitted(source.ip())) {
+            debug!("socks proxy closed the connection of {}, it didn't knock", source);
+            continue;
+        }
+
+        let reply_busy = |stream, permit| tcp::reply_busy(stream, methods.clone(), permit);
+        let Some((stream, guard)) = watchdog::admit(stream, &BUSY_REPLIES, reply_busy) else {
+            continue;
+        };
+
+        let methods = methods.clone();
+        let context = context.clone();
+        tokio::spawn(async move {
+            let _guard = guard;
+            tcp::handle(stream, source, &methods, &context).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpStream, UdpSocket},
+    };
+
+    use swiftlink_infra::{
+        auth::{AuthUser, Authenticator},
+        connection::{CloseReason, ConnectionHistory},
+        event::EventBus,
+        net::ConnectOpts,
+        traffic::UserTraffic,
+    };
+    use swiftlink_transport::{
+        socks4,
+        socks5::{client, Address, Error, Reply},
+    };
+
+    use super::*;
+    use crate::{outbound::Outbounds, route::Router, Config};
+
+    async fn start() -> (SocketAddr, Arc<ConnectionHistory>, tokio::task::JoinHandle<()>) {
+        let (addr, connections, _, task) =
+            start_with(AuthMethods::for_users(None), Router::default(), Outbounds::default()).await;
+        (addr, connections, task)
+    }
+
+    async fn start_with(
+        methods: AuthMethods,
+        router: Router,
+        outbounds: Outbounds,
+    ) -> (
+        SocketAddr,
+        Arc<ConnectionHistory>,
+        Arc<UserTraffic>,
+        tokio::task::JoinHandle<()>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(ConnectionHistory::new(4));
+        let users = Arc::new(UserTraffic::default());
+        let context = Arc::new(InboundContext {
+            tag: "socks-lan".to_owned(),
+            connect_opts: Arc::new(ConnectOpts::default()),
+            connections: connections.clone(),
+            events: Arc::new(EventBus::default()),
+            users: users.clone(),
+            router: Arc::new(router),
+            rule_hits: Arc::default(),
+            outbounds: Arc::new(outbounds),
+        });
+        let task = tokio::spawn(serve(listener, Arc::new(methods), context, None));
+        (addr, connections, users, task)
+    }
+
+    #[tokio::test]
+    async fn test_socks_connect() {
+        let (addr, connections, task) = start().await;
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let bound = client::connect(&mut stream, &origin_addr.into(), None).await.unwrap();
+        let (mut server, peer) = origin.accept().await.unwrap();
+        // the address the destination sees
+        assert_eq!(bound, Address::SocketAddress(peer));
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut received = [0u8; 4];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+        drop(server);
+        assert_eq!(stream.read(&mut received).await.unwrap(), 0);
+        drop(stream);
+
+        // nothing listens at the destination
+        drop(origin);
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let err = client::connect(&mut stream, &origin_addr.into(), None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Reply(Reply::ConnectionRefused)));
+
+        while connections.recent().len() < 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        let recent = connections.recent();
+        assert!(recent.iter().all(|conn| conn.inbound == "socks-lan"));
+        assert!(recent.iter().any(|conn| conn.reason == CloseReason::DialRefused));
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_socks_rules() {
+        let router = Router::new(&["DOMAIN,blocked.example.com,REJECT".parse().unwrap()]).unwrap();
+        let (addr, connections, _, task) = start_with(AuthMethods::for_users(None), router, Outbounds::default()).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let destination = Address::DomainNameAddress("blocked.example.com".to_owned(), 443);
+        let err = client::connect(&mut stream, &destination, None).await.unwrap_err();
+        assert!(matches!(err, Error::Reply(Reply::ConnectionNotAllowed)), "{:?}", err);
+
+        while connections.recent().is_empty() {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        let conn = &connections.recent()[0];
+        assert_eq!(conn.reason, CloseReason::PolicyReject);
+        assert_eq!(conn.rule.as_deref(), Some("DOMAIN,blocked.example.com,REJECT"));
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_socks_password() {
+        let authenticator = Arc::new(Authenticator::new(vec![AuthUser::new("alice", "secret")]));
+        let methods = AuthMethods::from_names(&["password"], Some(authenticator)).unwrap();
+        let (addr, connections, users, task) = start_with(methods, Router::default(), Outbounds::default()).await;
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let err = client::connect(&mut stream, &origin_addr.into(), None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::NoAcceptableAuthMethod(_)), "{:?}", err);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert!(
+            client::connect(&mut stream, &origin_addr.into(), Some(("alice", "wrong")))
+                .await
+                .is_err()
+        );
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        client::connect(&mut stream, &origin_addr.into(), Some(("alice", "secret")))
+            .await
+            .unwrap();
+        let (mut server, _) = origin.accept().await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut received = [0u8; 4];
+        server.read_exact(&mut received).await.unwrap();
+        server.write_all(b"pong!").await.unwrap();
+        drop(server);
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"pong!");
+        drop(stream);
+
+        // SOCKS4 has no authentication
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        socks4::HandshakeRequest {
+            cd: socks4::Command::Connect,
+            dst: socks4::Address::DomainNameAddress("127.0.0.1".to_owned(), origin_addr.port()),
+            user_id: b"alice".to_vec(),
+        }
+        .write_to(&mut stream)
+        .await
+        .unwrap();
+        let response = socks4::HandshakeResponse::read_from(&mut stream).await.unwrap();
+        assert_eq!(response.cd, socks4::ResultCode::RequestRejectedOrFailed);
+
+        while connections.recent().len() < 4 {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        let alice = users.stats("alice").unwrap();
+        assert_eq!((alice.upload, alice.download, alice.connections), (4, 5, 1));
+        assert_eq!(users.snapshot().len(), 1);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_socks4_connect() {
+        let (addr, connections, task) = start().await;
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+
+        // SOCKS4a, with data sent before the request is granted
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut request = vec![0x04, 0x01];
+        request.extend_from_slice(&origin_addr.port().to_be_bytes());
+        request.extend_from_slice(b"\x00\x00\x00\x01user\x00127.0.0.1\x00ping");
+        stream.write_all(&request).await.unwrap();
+        let response = socks4::HandshakeResponse::read_from(&mut stream).await.unwrap();
+        let (mut server, peer) = origin.accept().await.unwrap();
+        assert_eq!(response.cd, socks4::ResultCode::RequestGranted);
+        assert_eq!(response.dst.map(SocketAddr::V4), Some(peer));
+        let mut received = [0u8; 4];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+        drop(server);
+        assert_eq!(stream.read(&mut received).await.unwrap(), 0);
+        drop(stream);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        socks4::HandshakeRequest {
+            cd: socks4::Command::Bind,
+            dst: socks4::Address::DomainNameAddress("localhost".to_owned(), 80),
+            user_id: Vec::new(),
+        }
+        .write_to(&mut stream)
+        .await
+        .unwrap();
+        let response = socks4::HandshakeResponse::read_from(&mut stream).await.unwrap();
+        assert_eq!(response.cd, socks4::ResultCode::RequestRejectedOrFailed);
+
+        while connections.recent().len() < 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        let recent = connections.recent();
+        let granted = recent
+            .iter()
+            .find(|conn| conn.destination == origin_addr.to_string())
+            .unwrap();
+        assert_eq!(granted.upload, 4);
+        assert!(recent.iter().any(|conn| conn.reason == CloseReason::PolicyReject));
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_socks_udp_associate() {
+        let (addr, connections, task) = start().await;
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            loop {
+                let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..n], from).await.unwrap();
+            }
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let control = TcpStream::connect(addr).await.unwrap();
+        let udp = client::UdpAssociation::open(control, socket, None).await.unwrap();
+        assert_eq!(udp.relay_addr().ip(), addr.ip());
+
+        // by address and by name, resolved by the relay
+        let mut buf = [0u8; 64];
+        for target in [
+            Address::from(echo_addr),
+            Address::from(("127.0.0.1".to_owned(), echo_addr.port())),
+        ] {
+            udp.send_to(b"ping", &target).await.unwrap();
+            let (n, from) = udp.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"ping");
+            assert_eq!(from, Address::SocketAddress(echo_addr));
+        }
+
+        // closing the control connection ends the association
+        drop(udp);
+        while connections.recent().is_empty() {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        let conn = &connections.recent()[0];
+        assert_eq!(conn.network, "udp");
+        assert_eq!(conn.destination, echo_addr.to_string());
+        assert_eq!((conn.upload, conn.download), (8, 8));
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_socks_udp_rules() {
+        let mut echoes = Vec::new();
+        for _ in 0..2 {
+            let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            echoes.push(echo.local_addr().unwrap());
+            tokio::spawn(async move {
+                let mut buf = [0u8; 64];
+                loop {
+                    let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+                    echo.send_to(&buf[..n], from).await.unwrap();
+                }
+            });
+        }
+        let (blocked, allowed) = (echoes[0], echoes[1]);
+        let rules = [
+            format!("DST-PORT,{},REJECT", blocked.port()),
+            "DOMAIN,proxied.example.com,PROXY".to_owned(),
+            "MATCH,DIRECT".to_owned(),
+        ];
+        let router = Router::new(&rules.map(|rule| rule.parse().unwrap())).unwrap();
+        let (addr, connections, _, task) = start_with(AuthMethods::for_users(None), router, Outbounds::default()).await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let control = TcpStream::connect(addr).await.unwrap();
+        let udp = client::UdpAssociation::open(control, socket, None).await.unwrap();
+
+        // the rejected and the proxied datagrams are dropped, only the allowed one is echoed
+        let proxied = Address::DomainNameAddress("proxied.example.com".to_owned(), 53);
+        udp.send_to(b"blocked", &blocked.into()).await.unwrap();
+        udp.send_to(b"proxied", &proxied).await.unwrap();
+        udp.send_to(b"allowed", &allowed.into()).await.unwrap();
+        let mut buf = [0u8; 64];
+        let (n, from) = udp.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"allowed");
+        assert_eq!(from, Address::SocketAddress(allowed));
+
+        drop(udp);
+        while connections.recent().is_empty() {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        let conn = &connections.recent()[0];
+        assert_eq!(conn.destination, allowed.to_string());
+        assert_eq!(conn.rule.as_deref(), Some("MATCH,DIRECT"));
+        assert_eq!(conn.upload, 7);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_socks_chained() {
+        let (upstream, upstream_connections, upstream_task) = start().await;
+        let config = Config::load(&format!(
+            r#"
+            [dns.proxy_servers]
+            upstream = "socks5://{}"
+            "#,
+            upstream
+        ))
+        .unwrap();
+        let router = Router::new(&["MATCH,upstream".parse().unwrap()]).unwrap();
+        let outbounds = Outbounds::new(&config, Default::default());
+        let (addr, connections, _, task) = start_with(AuthMethods::for_users(None), router, outbounds).await;
+
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        client::connect(&mut stream, &origin_addr.into(), None).await.unwrap();
+        let (mut server, _) = origin.accept().await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut received = [0u8; 4];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+        drop((stream, server));
+
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            loop {
+                let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..n], from).await.unwrap();
+            }
+        });
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let control = TcpStream::connect(addr).await.unwrap();
+        let udp = client::UdpAssociation::open(control, socket, None).await.unwrap();
+        let mut buf = [0u8; 64];
+        udp.send_to(b"ping", &echo_addr.into()).await.unwrap();
+        let (n, from) = udp.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        assert_eq!(from, Address::SocketAddress(echo_addr));
+        drop(udp);
+
+        // both went through the upstream proxy
+        while connections.recent().len() < 2 || upstream_connections.recent().len() < 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        let recent = connections.recent();
+        assert!(recent.iter().all(|conn| conn.outbound.as_deref() == Some("upstream")));
+        let mut networks: Vec<_> = upstream_connections.recent().iter().map(|conn| conn.network).collect();
+        networks.sort();
+        assert_eq!(networks, ["tcp", "udp"]);
+
+        task.abort();
+        upstream_task.abort();
+    }
+}
@@ -0,0 +1,306 @@
+//! The control connection of a client, its handshake and its command.
+//!
+//! SOCKS4 and SOCKS4a clients are served on the same listener, told apart by the version of their
+//! first byte. They can only `CONNECT`.
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::SemaphorePermit,
+};
+
+use super::udp;
+use crate::{
+    inbound::{dial_routed, InboundContext},
+    sni_proxy::{self, NEXT_CONNECTION_ID},
+};
+use swiftlink_infra::{
+    connection::{CloseReason, ClosedConnection},
+    event::{Event, OpenedConnection},
+    log::*,
+    traffic,
+};
+use swiftlink_transport::{
+    socks4,
+    socks5::{auth::AuthMethods, Command, Error, Reply, TcpRequestHeader, TcpResponseHeader, SOCKS5_AUTH_METHOD_NONE},
+};
+
+/// The client must complete its handshake and send its command within this time.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Overloaded, the command to answer with a general failure must arrive within this time.
+const BUSY_REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The longest SOCKS4 request, its user ID and SOCKS4a host included.
+const MAX_SOCKS4_REQUEST_LEN: u64 = 1024;
+
+/// Answers the command of `client` with a general failure, nothing is forwarded.
+pub(super) async fn reply_busy(mut client: TcpStream, methods: Arc<AuthMethods>, _permit: SemaphorePermit<'static>) {
+    let result = tokio::time::timeout(BUSY_REPLY_TIMEOUT, read_request(&mut client, &methods)).await;
+    if let Ok(Ok((request, _))) = result {
+        if let Err(err) = request.reply_failure(&mut client, Reply::GeneralFailure).await {
+            debug!("socks proxy busy reply to {:?} failed, {}", client.peer_addr(), err);
+        }
+    }
+}
+
+pub(super) async fn handle(mut client: TcpStream, source: SocketAddr, methods: &AuthMethods, context: &InboundContext) {
+    let started = Instant::now();
+    traffic::total().connected();
+    let mut conn = ClosedConnection {
+        id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+        network: "tcp",
+        inbound: context.tag.clone(),
+        source,
+        destination: "-".to_owned(),
+        rule: None,
+        outbound: None,
+        dialed: None,
+        upload: 0,
+        download: 0,
+        started_at: ClosedConnection::unix_millis(SystemTime::now()),
+        duration_ms: 0,
+        reason: CloseReason::Error,
+        error: None,
+    };
+
+    let events = &context.events;
+    let result = serve_command(&mut client, methods, context, &mut conn).await;
+    if let Err((reason, err)) = result {
+        conn.reason = reason;
+        conn.error = err.map(|err| err.to_string());
+    }
+    conn.duration_ms = started.elapsed().as_millis() as u64;
+    if events.has_subscribers() {
+        events.publish(Event::ConnectionClosed(Arc::new(conn.clone())));
+    }
+    context.connections.record(conn);
+}
+
+/// The command of a client.
+enum Request {
+    V5(TcpRequestHeader),
+    /// with the bytes which followed the request, sent before it was answered
+    V4(socks4::HandshakeRequest, Vec<u8>),
+}
+
+impl Request {
+    /// Answers that the command failed, with `code` to a SOCKS5 client.
+    async fn reply_failure(&self, client: &mut TcpStream, code: Reply) -> io::Result<()> {
+        match self {
+            Request::V5(_) => reply(client, code, None).await,
+            Request::V4(..) => reply_v4(client, socks4::ResultCode::RequestRejectedOrFailed, None).await,
+        }
+    }
+
+    /// Answers that the command succeeded, the server bound `bound` for it.
+    async fn reply_success(&self, client: &mut TcpStream, bound: Option<SocketAddr>) -> io::Result<()> {
+        match self {
+            Request::V5(_) => reply(client, Reply::Succeeded, bound).await,
+            Request::V4(..) => reply_v4(client, socks4::ResultCode::RequestGranted, bound).await,
+        }
+    }
+}
+
+/// Reads the command, after negotiating the authentication of a SOCKS5 client, with the user who
+/// authenticated. Invalid commands are answered, and SOCKS4 ones if clients must authenticate.
+async fn read_request(client: &mut TcpStream, methods: &AuthMethods) -> io::Result<(Request, Option<String>)> {
+    let mut version = [0u8; 1];
+    if client.peek(&mut version).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    match version[0] {
+        0x05 => {
+            let user = methods.negotiate(client).await?;
+            match TcpRequestHeader::read_from(client).await {
+                Ok(header) => Ok((Request::V5(header), user)),
+                Err(err) => {
+                    if !matches!(err, Error::IoError(_)) {
+                        _ = reply(client, err.as_reply(), None).await;
+                    }
+                    Err(err.into())
+                }
+            }
+        }
+        0x04 if methods.select(&[SOCKS5_AUTH_METHOD_NONE]).is_none() => {
+            _ = reply_v4(client, socks4::ResultCode::RequestRejectedOrFailed, None).await;
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "socks4 clients can't authenticate",
+            ))
+        }
+        0x04 => {
+            let mut reader = BufReader::new(&mut *client);
+            let result = socks4::HandshakeRequest::read_from(&mut (&mut reader).take(MAX_SOCKS4_REQUEST_LEN)).await;
+            let early_data = reader.buffer().to_vec();
+            match result {
+                Ok(request) => Ok((Request::V4(request, early_data), None)),
+                Err(err) => {
+                    if !matches!(err, socks4::Error::IoError(_)) {
+                        _ = reply_v4(client, socks4::ResultCode::RequestRejectedOrFailed, None).await;
+                    }
+                    Err(err.into())
+                }
+            }
+        }
+        version => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported socks version {:#x}", version),
+        )),
+    }
+}
+
+async fn serve_command(
+    client: &mut TcpStream,
+    methods: &AuthMethods,
+    context: &InboundContext,
+    conn: &mut ClosedConnection,
+) -> Result<(), (CloseReason, Option<io::Error>)> {
+    let (request, user) = tokio::time::timeout(HANDSHAKE_TIMEOUT, read_request(client, methods))
+        .await
+        .map_err(|_| {
+            let err = io::Error::new(io::ErrorKind::TimedOut, "no socks command");
+            (CloseReason::HandshakeTimeout, Some(err))
+        })?
+        .map_err(|err| (CloseReason::Error, Some(err)))?;
+
+    let user = user.map(|user| context.users.user(&user));
+    if let Some(user) = &user {
+        user.connected();
+    }
+    let result = run_command(client, &request, context, conn).await;
+    if let Some(user) = user {
+        user.add_upload(conn.upload);
+        user.add_download(conn.download);
+    }
+    result
+}
+
+async fn run_command(
+    client: &mut TcpStream,
+    request: &Request,
+    context: &InboundContext,
+    conn: &mut ClosedConnection,
+) -> Result<(), (CloseReason, Option<io::Error>)> {
+    match request {
+        Request::V5(header) => match header.command {
+            Command::TcpConnect => connect(client, request, header.address.to_string(), context, conn).await,
+            Command::UdpAssociate => {
+                conn.network = "udp";
+                udp::associate(client, &header.address, context, conn).await
+            }
+            Command::TcpBind => {
+                _ = reply(client, Reply::CommandNotSupported, None).await;
+                let err = io::Error::new(io::ErrorKind::Unsupported, "BIND is not supported");
+                Err((CloseReason::PolicyReject, Some(err)))
+            }
+        },
+        Request::V4(header, _) => match header.cd {
+            socks4::Command::Connect => connect(client, request, header.dst.to_string(), context, conn).await,
+            socks4::Command::Bind => {
+                _ = request.reply_failure(client, Reply::CommandNotSupported).await;
+                let err = io::Error::new(io::ErrorKind::Unsupported, "BIND is not supported");
+                Err((CloseReason::PolicyReject, Some(err)))
+            }
+        },
+    }
+}
+
+/// Opens a tunnel to `destination` as the rules route it, answered once the connection is
+/// established or failed.
+async fn connect(
+    client: &mut TcpStream,
+    request: &Request,
+    destination: String,
+    context: &InboundContext,
+    conn: &mut ClosedConnection,
+) -> Result<(), (CloseReason, Option<io::Error>)> {
+    conn.destination = destination;
+
+    let dialed = dial_routed(
+        &context.router,
+        &context.rule_hits,
+        &context.outbounds,
+        &context.connect_opts,
+        conn,
+    );
+    let mut remote = match dialed.await {
+        Ok((remote, dialed)) => {
+            conn.dialed = Some(dialed);
+            remote
+        }
+        Err(err) => {
+            _ = request.reply_failure(client, dial_reply(&err)).await;
+            return Err((CloseReason::from_dial_error(&err), Some(err)));
+        }
+    };
+    // the address the destination sees, RFC 1928 section 6
+    let bound = remote.local_addr().ok();
+    request
+        .reply_success(client, bound)
+        .await
+        .map_err(|err| (CloseReason::from_relay_error(&err), Some(err)))?;
+    if let Request::V4(_, early_data) = request {
+        if !early_data.is_empty() {
+            remote
+                .write_all(early_data)
+                .await
+                .map_err(|err| (CloseReason::from_relay_error(&err), Some(err)))?;
+            conn.upload = early_data.len() as u64;
+        }
+    }
+    context.events.publish(Event::ConnectionOpened(OpenedConnection {
+        id: conn.id,
+        network: conn.network,
+        inbound: conn.inbound.clone(),
+        source: conn.source,
+        destination: conn.destination.clone(),
+        rule: conn.rule.clone().unwrap_or_else(|| "-".to_owned()),
+        outbound: conn.outbound.clone().unwrap_or_default(),
+    }));
+
+    let (result, upload, download) = sni_proxy::relay(client, &mut remote).await;
+    conn.upload += upload;
+    conn.download = download;
+    match result {
+        Ok(reason) => {
+            conn.reason = reason;
+            Ok(())
+        }
+        Err(err) => Err((CloseReason::from_relay_error(&err), Some(err))),
+    }
+}
+
+/// The reply to a command whose destination couldn't be connected to.
+fn dial_reply(err: &io::Error) -> Reply {
+    // refused by the loop guard or the rules, not by the destination
+    if matches!(
+        CloseReason::from_dial_error(err),
+        CloseReason::LoopDetected | CloseReason::PolicyReject
+    ) {
+        return Reply::ConnectionNotAllowed;
+    }
+    Error::IoError(io::Error::from(err.kind())).as_reply()
+}
+
+/// Answers the command, with the unspecified address if `bound` is `None`.
+pub(super) async fn reply(client: &mut TcpStream, reply: Reply, bound: Option<SocketAddr>) -> io::Result<()> {
+    let bound = bound.unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+    TcpResponseHeader::new(reply, bound.into()).write_to(client).await
+}
+
+/// Answers a SOCKS4 request, with zeros if `bound` isn't an IPv4 address.
+async fn reply_v4(client: &mut TcpStream, code: socks4::ResultCode, bound: Option<SocketAddr>) -> io::Result<()> {
+    let mut response = socks4::HandshakeResponse::new(code);
+    if let Some(SocketAddr::V4(bound)) = bound {
+        response = response.with_dst(bound);
+    }
+    response.write_to(client).await
+}
@@ -0,0 +1,364 @@
+//! `UDP ASSOCIATE`, RFC 1928 section 7.
+//!
+//! An association lives as long as its control connection. Its relay only takes the datagrams of
+//! the client: the address of the control connection and the port of its first datagram, or the
+//! port it announced. Fragmented datagrams are dropped.
+//!
+//! The `rules` route each target once per association, the datagrams of targets they `REJECT`
+//! are dropped. The ones routed to a SOCKS5 proxy go through an association with the proxy, one
+//! per proxy, an HTTP proxy drops them.
+
+use std::{
+    collections::HashMap,
+    io::{self, Cursor},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpStream, UdpSocket},
+    task::JoinSet,
+};
+
+use super::tcp::reply;
+use crate::{
+    inbound::{route, InboundContext},
+    route::DIRECT,
+};
+use swiftlink_infra::{
+    connection::{CloseReason, ClosedConnection},
+    event::{Event, OpenedConnection},
+    log::*,
+    net::udp::UdpSessionSockets,
+    traffic,
+};
+use swiftlink_transport::socks5::{client::UdpAssociation, Address, Reply, UdpAssociateHeader};
+
+/// The largest UDP datagram relayed, header included.
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+/// Domain names an association remembers the address of, and targets it remembers the route of.
+const MAX_RESOLVED: usize = 256;
+
+/// Relays the datagrams of the client on `control` until it closes the control connection.
+pub(super) async fn associate(
+    control: &mut TcpStream,
+    requested: &Address,
+    context: &InboundContext,
+    conn: &mut ClosedConnection,
+) -> Result<(), (CloseReason, Option<io::Error>)> {
+    let relay = match bind_relay(control).await {
+        Ok(relay) => relay,
+        Err(err) => {
+            _ = reply(control, Reply::GeneralFailure, None).await;
+            return Err((CloseReason::Error, Some(err)));
+        }
+    };
+    reply(control, Reply::Succeeded, relay.local_addr().ok())
+        .await
+        .map_err(|err| (CloseReason::from_relay_error(&err), Some(err)))?;
+
+    let mut association = Association {
+        relay: Arc::new(relay),
+        client_ip: conn.source.ip().to_canonical(),
+        client_port: match requested {
+            Address::SocketAddress(addr) if addr.port() != 0 => Some(addr.port()),
+            _ => None,
+        },
+        sessions: UdpSessionSockets::new(context.connect_opts.as_ref().clone()),
+        resolved: HashMap::new(),
+        routes: HashMap::new(),
+        proxied: HashMap::new(),
+        receivers: JoinSet::new(),
+        download: Arc::new(AtomicU64::new(0)),
+    };
+
+    enum Next {
+        Control(io::Result<usize>),
+        Datagram(io::Result<(usize, SocketAddr)>),
+    }
+    let mut byte = [0u8; 1];
+    let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+    let result = loop {
+        let next = tokio::select! {
+            read = control.read(&mut byte) => Next::Control(read),
+            received = association.relay.recv_from(&mut buf) => Next::Datagram(received),
+        };
+        match next {
+            Next::Control(Ok(0)) => break Ok(CloseReason::ClientEof),
+            // nothing is expected on the control connection
+            Next::Control(Ok(_)) => continue,
+            Next::Control(Err(err)) => break Err(err),
+            Next::Datagram(Err(err)) => break Err(err),
+            Next::Datagram(Ok((n, from))) => match association.send(from, &buf[..n], context, conn).await {
+                Ok(Some((target, len))) => {
+                    // the association is recorded with its first target
+                    if conn.destination == "-" {
+                        let route = &association.routes[&target];
+                        conn.destination = target.to_string();
+                        conn.rule = route.rule.clone();
+                        conn.outbound = route.outbound.clone();
+                        context.events.publish(Event::ConnectionOpened(OpenedConnection {
+                            id: conn.id,
+                            network: conn.network,
+                            inbound: conn.inbound.clone(),
+                            source: conn.source,
+                            destination: conn.destination.clone(),
+                            rule: conn.rule.clone().unwrap_or_else(|| "-".to_owned()),
+                            outbound: conn.outbound.clone().unwrap_or_default(),
+                        }));
+                    }
+                    conn.upload += len as u64;
+                    traffic::total().add_upload(len as u64);
+                }
+                Ok(None) => {}
+                Err(err) => debug!("socks udp relay of {} failed, {}", conn.source, err),
+            },
+        }
+    };
+
+    conn.download = association.download.load(Ordering::Relaxed);
+    match result {
+        Ok(reason) => {
+            conn.reason = reason;
+            Ok(())
+        }
+        Err(err) => Err((CloseReason::from_relay_error(&err), Some(err))),
+    }
+}
+
+/// Binds the relay on the address the client connected to, so it's reachable the same way.
+async fn bind_relay(control: &TcpStream) -> io::Result<UdpSocket> {
+    let ip = control.local_addr()?.ip().to_canonical();
+    UdpSocket::bind(SocketAddr::new(ip, 0)).await
+}
+
+struct Association {
+    relay: Arc<UdpSocket>,
+    client_ip: std::net::IpAddr,
+    /// the port the client sends from, fixed by the first datagram if not announced
+    client_port: Option<u16>,
+    sessions: UdpSessionSockets,
+    resolved: HashMap<(String, u16), SocketAddr>,
+    routes: HashMap<Address, TargetRoute>,
+    /// the associations with the proxies by outbound
+    proxied: HashMap<String, Arc<UdpAssociation>>,
+    /// one per outbound socket and proxy, they relay the replies, aborted when dropped
+    receivers: JoinSet<()>,
+    download: Arc<AtomicU64>,
+}
+
+impl Association {
+    /// Sends the payload of `datagram`, received from `from`, to its target as the rules route it.
+    /// Returns the target and the length of the payload, `None` if the datagram was dropped.
+    async fn send(
+        &mut self,
+        from: SocketAddr,
+        datagram: &[u8],
+        context: &InboundContext,
+        conn: &ClosedConnection,
+    ) -> io::Result<Option<(Address, usize)>> {
+        if from.ip().to_canonical() != self.client_ip || self.client_port.is_some_and(|port| port != from.port()) {
+            return Ok(None);
+        }
+        // RSV(2) FRAG(1) ATYP ADDR PORT DATA
+        if datagram.len() < 3 || datagram[2] != 0 {
+            return Ok(None);
+        }
+        let mut cursor = Cursor::new(&datagram[3..]);
+        let Ok(target) = Address::read_cursor(&mut cursor) else {
+            return Ok(None);
+        };
+        let payload = &datagram[3 + cursor.position() as usize..];
+        let Some(outbound) = self.route(&target, from, context, conn).await.outbound.clone() else {
+            return Ok(None);
+        };
+        if outbound != DIRECT {
+            return self.send_proxied(from, target, payload, &outbound, context).await;
+        }
+        let Some(addr) = self.resolve(&target).await else {
+            return Ok(None);
+        };
+        self.client_port = Some(from.port());
+
+        let sockets = self.sessions.len();
+        let socket = self.sessions.socket_for(addr).await?;
+        if self.sessions.len() > sockets {
+            self.receivers
+                .spawn(receive(socket.clone(), self.relay.clone(), from, self.download.clone()));
+        }
+        socket.send_to(payload, addr).await?;
+        Ok(Some((target, payload.len())))
+    }
+
+    /// Sends `payload` to `target` through the association with the proxy `outbound`, opened by the
+    /// first datagram.
+    async fn send_proxied(
+        &mut self,
+        from: SocketAddr,
+        target: Address,
+        payload: &[u8],
+        outbound: &str,
+        context: &InboundContext,
+    ) -> io::Result<Option<(Address, usize)>> {
+        let sent_to = if context.outbounds.resolves_locally(outbound) {
+            match self.resolve(&target).await {
+                Some(addr) => Address::SocketAddress(addr),
+                None => return Ok(None),
+            }
+        } else {
+            target.clone()
+        };
+        let association = match self.proxied.get(outbound) {
+            Some(association) => association.clone(),
+            None => {
+                let association = Arc::new(context.outbounds.associate(outbound, &context.connect_opts).await?);
+                self.receivers.spawn(receive_proxied(
+                    association.clone(),
+                    self.relay.clone(),
+                    from,
+                    self.download.clone(),
+                ));
+                self.proxied.insert(outbound.to_owned(), association.clone());
+                association
+            }
+        };
+        self.client_port = Some(from.port());
+        association.send_to(payload, &sent_to).await?;
+        Ok(Some((target, payload.len())))
+    }
+
+    /// Routes `target` by the rules the first time the client sends to it.
+    async fn route(
+        &mut self,
+        target: &Address,
+        from: SocketAddr,
+        context: &InboundContext,
+        conn: &ClosedConnection,
+    ) -> &TargetRoute {
+        if !self.routes.contains_key(target) {
+            // process rules find the client by the address it sends from
+            let mut routed_conn = ClosedConnection {
+                source: from,
+                destination: target.to_string(),
+                ..conn.clone()
+            };
+            let routed = route(
+                &context.router,
+                &context.rule_hits,
+                &context.outbounds,
+                &mut routed_conn,
+            )
+            .await;
+            let outbound = match routed {
+                Ok(routed) => {
+                    // the addresses a rule needed are the ones sent to
+                    if let (Address::DomainNameAddress(host, port), Some((addrs, _))) = (target, &routed.resolved) {
+                        if let Some(addr) = addrs.first().filter(|_| self.resolved.len() < MAX_RESOLVED) {
+                            self.resolved.insert((host.clone(), *port), *addr);
+                        }
+                    }
+                    Some(routed.outbound().to_owned())
+                }
+                Err(err) => {
+                    debug!("socks udp datagrams of {} to {} dropped, {}", conn.source, target, err);
+                    None
+                }
+            };
+            let route = TargetRoute {
+                rule: routed_conn.rule,
+                outbound,
+            };
+            // past the limit the routes are forgotten, and routed again
+            if self.routes.len() >= MAX_RESOLVED {
+                self.routes.clear();
+            }
+            self.routes.insert(target.clone(), route);
+        }
+        &self.routes[target]
+    }
+
+    async fn resolve(&mut self, target: &Address) -> Option<SocketAddr> {
+        let (host, port) = match target {
+            Address::SocketAddress(addr) => return Some(*addr),
+            Address::DomainNameAddress(host, port) => (host, *port),
+        };
+        if let Some(addr) = self.resolved.get(&(host.clone(), port)) {
+            return Some(*addr);
+        }
+        let addr = match tokio::net::lookup_host((host.as_str(), port)).await {
+            Ok(mut addrs) => addrs.next()?,
+            Err(err) => {
+                debug!("socks udp target {} can't be resolved, {}", target, err);
+                return None;
+            }
+        };
+        if self.resolved.len() < MAX_RESOLVED {
+            self.resolved.insert((host.clone(), port), addr);
+        }
+        Some(addr)
+    }
+}
+
+/// What the rules decided for a target of an association.
+struct TargetRoute {
+    /// the rule which matched
+    rule: Option<String>,
+    /// the outbound, `None` if the target is rejected or can't be routed
+    outbound: Option<String>,
+}
+
+/// Relays the replies received on `socket` to `client`.
+async fn receive(socket: Arc<UdpSocket>, relay: Arc<UdpSocket>, client: SocketAddr, download: Arc<AtomicU64>) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+    loop {
+        let (n, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            // ICMP port unreachable of an earlier datagram, reported by some platforms
+            Err(err) if err.kind() == io::ErrorKind::ConnectionReset => continue,
+            Err(err) => {
+                debug!("socks udp relay to {} stopped, {}", client, err);
+                return;
+            }
+        };
+        reply_to(&relay, client, from.into(), &buf[..n], &download).await;
+    }
+}
+
+/// Relays the replies received through the association with a proxy to `client`.
+async fn receive_proxied(
+    association: Arc<UdpAssociation>,
+    relay: Arc<UdpSocket>,
+    client: SocketAddr,
+    download: Arc<AtomicU64>,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+    loop {
+        let (n, from) = match association.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) if err.kind() == io::ErrorKind::ConnectionReset => continue,
+            Err(err) => {
+                debug!("socks udp relay through proxy to {} stopped, {}", client, err);
+                return;
+            }
+        };
+        reply_to(&relay, client, from, &buf[..n], &download).await;
+    }
+}
+
+/// Sends `payload`, received from `from`, to `client` through the relay.
+async fn reply_to(relay: &UdpSocket, client: SocketAddr, from: Address, payload: &[u8], download: &AtomicU64) {
+    let header = UdpAssociateHeader::new(0, from);
+    let mut datagram = Vec::with_capacity(header.serialized_len() + payload.len());
+    header.write_to_buf(&mut datagram);
+    datagram.extend_from_slice(payload);
+    download.fetch_add(payload.len() as u64, Ordering::Relaxed);
+    traffic::total().add_download(payload.len() as u64);
+    if let Err(err) = relay.send_to(&datagram, client).await {
+        debug!("socks udp reply to {} failed, {}", client, err);
+    }
+}
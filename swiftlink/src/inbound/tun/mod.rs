@@ -0,0 +1,419 @@
+//! TUN inbound, for transparent proxying of the whole system (Linux only).
+//!
+//! With `[tun]` set, swiftlink creates the interface and terminates the TCP connections routed to
+//! it with a userspace TCP stack:
+//!
+//! ```toml
+//! [tun]
+//! address = "198.18.0.1/16"
+//! ```
+//!
+//! A connection to a fake IP of the dns server goes to its domain, other connections to their
+//! address. The `rules` route them to an [`outbound`](crate::outbound), `REJECT` resets them.
+//! Routing only the fake IP range to the interface, e.g. `ip route add 198.18.0.0/15 dev
+//! swiftlink0`, keeps the connections of swiftlink itself out of it; routing more requires
+//! `fwmark` or `interface_name` with matching policy routing. Other packets than TCP are dropped.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Instant, SystemTime},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc, Notify},
+};
+
+use super::dial_routed;
+use crate::{outbound::Outbounds, route::Router, sni_proxy::NEXT_CONNECTION_ID};
+use stack::{Download, Stream};
+use swiftlink_dns::ProxyStream;
+use swiftlink_infra::{
+    connection::{CloseReason, ClosedConnection, ConnectionHistory},
+    event::{Event, EventBus, OpenedConnection},
+    fakedns::FakeDns,
+    log::*,
+    net::ConnectOpts,
+    rule_hits::RuleHits,
+    traffic,
+    tun::Tun,
+    watchdog,
+};
+
+mod stack;
+
+/// Packets queued between the device and the stack, in each direction.
+const QUEUED_PACKETS: usize = 256;
+
+const RELAY_BUFFER_LEN: usize = 16 * 1024;
+
+/// What the connections of the interface share.
+pub(crate) struct TunContext {
+    /// tag of the interface in the connection history, matched by `INBOUND` rules
+    pub(crate) tag: String,
+    /// maps fake IPs back to their domain
+    pub(crate) fakedns: Option<Arc<Mutex<FakeDns>>>,
+    pub(crate) connect_opts: Arc<ConnectOpts>,
+    pub(crate) connections: Arc<ConnectionHistory>,
+    pub(crate) events: Arc<EventBus>,
+    pub(crate) router: Arc<Router>,
+    /// the match counters of the rules of `router`
+    pub(crate) rule_hits: Arc<RuleHits>,
+    /// the outbounds the targets of the rules name
+    pub(crate) outbounds: Arc<Outbounds>,
+}
+
+/// Serves the connections routed to `device` until reading from it fails.
+pub(crate) async fn serve(device: Arc<Tun>, mtu: u16, context: Arc<TunContext>) -> io::Result<()> {
+    let (packets_in, stack_in) = mpsc::channel(QUEUED_PACKETS);
+    let (stack_out, mut packets_out) = mpsc::channel::<Vec<u8>>(QUEUED_PACKETS);
+
+    let read = async {
+        let mut buf = vec![0u8; u16::MAX as usize];
+        loop {
+            let n = device.recv(&mut buf).await?;
+            if packets_in.send(buf[..n].to_vec()).await.is_err() {
+                return Ok(());
+            }
+        }
+    };
+    let write = async {
+        while let Some(packet) = packets_out.recv().await {
+            if let Err(err) = device.send(&packet).await {
+                debug!("tun {} dropped a packet, {}", device.name(), err);
+            }
+        }
+    };
+    tokio::select! {
+        result = read => result,
+        _ = write => Ok(()),
+        _ = stack::run(mtu as usize, stack_in, stack_out, context) => Ok(()),
+    }
+}
+
+/// Relays a connection of the stack, established with the client, to `destination`.
+async fn handle(source: SocketAddr, destination: SocketAddr, stream: Stream, context: Arc<TunContext>) {
+    let _guard = watchdog::track_connection();
+    let started = Instant::now();
+    traffic::total().connected();
+    let mut conn = ClosedConnection {
+        id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+        network: "tcp",
+        inbound: context.tag.clone(),
+        source,
+        destination: destination.to_string(),
+        rule: None,
+        outbound: None,
+        dialed: None,
+        upload: 0,
+        download: 0,
+        started_at: ClosedConnection::unix_millis(SystemTime::now()),
+        duration_ms: 0,
+        reason: CloseReason::Error,
+        error: None,
+    };
+
+    if let Err((reason, err)) = forward(stream, destination, &context, &mut conn).await {
+        conn.reason = reason;
+        conn.error = err.map(|err| err.to_string());
+    }
+    conn.duration_ms = started.elapsed().as_millis() as u64;
+    if context.events.has_subscribers() {
+        context.events.publish(Event::ConnectionClosed(Arc::new(conn.clone())));
+    }
+    context.connections.record(conn);
+}
+
+/// Connects to the destination of the client and relays, filling in `conn` on the way. The client
+/// is reset if the destination can't be connected to.
+async fn forward(
+    stream: Stream,
+    destination: SocketAddr,
+    context: &TunContext,
+    conn: &mut ClosedConnection,
+) -> Result<(), (CloseReason, Option<io::Error>)> {
+    let Stream { upload, download, wake } = stream;
+    let reset = || {
+        _ = download.try_send(Download::Reset);
+        wake.notify_one();
+    };
+
+    conn.destination = match target(destination, context.fakedns.as_deref()) {
+        Ok(target) => target,
+        Err(err) => {
+            reset();
+            return Err((CloseReason::Error, Some(err)));
+        }
+    };
+
+    let dialed = dial_routed(
+        &context.router,
+        &context.rule_hits,
+        &context.outbounds,
+        &context.connect_opts,
+        conn,
+    );
+    let mut remote = match dialed.await {
+        Ok((remote, dialed)) => {
+            conn.dialed = Some(dialed);
+            remote
+        }
+        Err(err) => {
+            reset();
+            return Err((CloseReason::from_dial_error(&err), Some(err)));
+        }
+    };
+    context.events.publish(Event::ConnectionOpened(OpenedConnection {
+        id: conn.id,
+        network: conn.network,
+        inbound: conn.inbound.clone(),
+        source: conn.source,
+        destination: conn.destination.clone(),
+        rule: conn.rule.clone().unwrap_or_else(|| "-".to_owned()),
+        outbound: conn.outbound.clone().unwrap_or_default(),
+    }));
+
+    let (result, upload, download) = relay(upload, download, &wake, &mut remote).await;
+    conn.upload = upload;
+    conn.download = download;
+    match result {
+        Ok(reason) => {
+            conn.reason = reason;
+            Ok(())
+        }
+        Err(err) => Err((CloseReason::from_relay_error(&err), Some(err))),
+    }
+}
+
+/// The `host:port` to connect to, with the domain of a fake IP.
+fn target(destination: SocketAddr, fakedns: Option<&Mutex<FakeDns>>) -> io::Result<String> {
+    let Some(fakedns) = fakedns else {
+        return Ok(destination.to_string());
+    };
+    let mut fakedns = fakedns.lock().unwrap();
+    if !fakedns.is_fake_ip(destination.ip()) {
+        return Ok(destination.to_string());
+    }
+    match fakedns.lookup_host(destination.ip()) {
+        Some(host) => Ok(format!("{}:{}", host.trim_end_matches('.'), destination.port())),
+        // e.g. evicted, or allocated before a restart without `persist`
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("fake ip {} maps to no domain", destination.ip()),
+        )),
+    }
+}
+
+/// Copies both directions until both sides closed, returns which closed first and the bytes sent
+/// by the client and by the remote.
+async fn relay(
+    mut upload: mpsc::Receiver<Vec<u8>>,
+    download: mpsc::Sender<Download>,
+    wake: &Notify,
+    remote: &mut ProxyStream,
+) -> (io::Result<CloseReason>, u64, u64) {
+    let (mut uploaded, mut downloaded) = (0, 0);
+    let result = {
+        let (mut remote_read, mut remote_write) = tokio::io::split(remote);
+        let client_to_remote = async {
+            while let Some(chunk) = upload.recv().await {
+                // the stack may read further from the client
+                wake.notify_one();
+                remote_write.write_all(&chunk).await?;
+                uploaded += chunk.len() as u64;
+                traffic::total().add_upload(chunk.len() as u64);
+            }
+            remote_write.shutdown().await
+        };
+        let remote_to_client = async {
+            let mut buf = vec![0u8; RELAY_BUFFER_LEN];
+            let result = loop {
+                let n = match remote_read.read(&mut buf).await {
+                    Ok(0) => break Ok(()),
+                    Ok(n) => n,
+                    Err(err) => {
+                        _ = download.try_send(Download::Reset);
+                        break Err(err);
+                    }
+                };
+                if download.send(Download::Data(buf[..n].to_vec())).await.is_err() {
+                    break Err(io::ErrorKind::ConnectionReset.into());
+                }
+                wake.notify_one();
+                downloaded += n as u64;
+                traffic::total().add_download(n as u64);
+            };
+            // closes the connection to the client once the queued payload is sent
+            drop(download);
+            wake.notify_one();
+            result
+        };
+        tokio::pin!(client_to_remote, remote_to_client);
+
+        let (reason, result) = tokio::select! {
+            result = &mut client_to_remote => (CloseReason::ClientEof, result),
+            result = &mut remote_to_client => (CloseReason::ServerEof, result),
+        };
+        match (result, reason) {
+            (Ok(()), CloseReason::ClientEof) => remote_to_client.await.map(|_| reason),
+            (Ok(()), _) => client_to_remote.await.map(|_| reason),
+            (Err(err), _) => Err(err),
+        }
+    };
+    (result, uploaded, downloaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use smoltcp::{
+        iface::{Config, Interface, SocketHandle, SocketSet},
+        socket::tcp::{self, State},
+        wire::{HardwareAddress, IpAddress, IpCidr, Ipv4Address},
+    };
+    use tokio::net::TcpListener;
+
+    use swiftlink_infra::fakedns;
+
+    use super::{stack::Queue, *};
+
+    /// A client with a stack of its own, on the other side of the device.
+    struct Client {
+        iface: Interface,
+        device: Queue,
+        sockets: SocketSet<'static>,
+        to_stack: mpsc::Sender<Vec<u8>>,
+        from_stack: mpsc::Receiver<Vec<u8>>,
+    }
+
+    impl Client {
+        fn new(to_stack: mpsc::Sender<Vec<u8>>, from_stack: mpsc::Receiver<Vec<u8>>) -> Self {
+            let mut device = Queue::new(1500);
+            let now = smoltcp::time::Instant::now();
+            let mut iface = Interface::new(Config::new(HardwareAddress::Ip), &mut device, now);
+            iface.update_ip_addrs(|addrs| addrs.push(IpCidr::new(IpAddress::v4(10, 0, 0, 2), 8)).unwrap());
+            iface
+                .routes_mut()
+                .add_default_ipv4_route(Ipv4Address::new(10, 0, 0, 1))
+                .unwrap();
+            Self {
+                iface,
+                device,
+                sockets: SocketSet::new(vec![]),
+                to_stack,
+                from_stack,
+            }
+        }
+
+        fn connect(&mut self, destination: SocketAddr, port: u16) -> SocketHandle {
+            let mut socket = tcp::Socket::new(
+                tcp::SocketBuffer::new(vec![0; 4096]),
+                tcp::SocketBuffer::new(vec![0; 4096]),
+            );
+            socket.connect(self.iface.context(), destination, port).unwrap();
+            self.sockets.add(socket)
+        }
+
+        /// Exchanges packets with the stack until `done` holds for the socket.
+        async fn poll_until(&mut self, handle: SocketHandle, mut done: impl FnMut(&mut tcp::Socket) -> bool) {
+            let poll = async {
+                loop {
+                    let now = smoltcp::time::Instant::now();
+                    self.iface.poll(now, &mut self.device, &mut self.sockets);
+                    for packet in self.device.tx.drain(..) {
+                        self.to_stack.send(packet).await.unwrap();
+                    }
+                    if done(self.sockets.get_mut(handle)) {
+                        return;
+                    }
+                    let received = tokio::time::timeout(Duration::from_millis(10), self.from_stack.recv()).await;
+                    if let Ok(Some(packet)) = received {
+                        self.device.rx.push_back(packet);
+                    }
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(10), poll).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tun_connect() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let fakedns = Arc::new(Mutex::new(FakeDns::new(fakedns::Config::default())));
+        let fake_ip = fakedns.lock().unwrap().lookup_ip("127.0.0.1", false).unwrap();
+        let connections = Arc::new(ConnectionHistory::new(4));
+        let context = Arc::new(TunContext {
+            tag: "tun-guests".to_owned(),
+            fakedns: Some(fakedns),
+            connect_opts: Arc::new(ConnectOpts::default()),
+            connections: connections.clone(),
+            events: Arc::new(EventBus::default()),
+            router: Arc::default(),
+            rule_hits: Arc::default(),
+            outbounds: Arc::default(),
+        });
+        let (to_stack, stack_in) = mpsc::channel(QUEUED_PACKETS);
+        let (stack_out, from_stack) = mpsc::channel(QUEUED_PACKETS);
+        let stack = tokio::spawn(stack::run(1500, stack_in, stack_out, context));
+        let mut client = Client::new(to_stack, from_stack);
+
+        // to the domain of the fake ip
+        let socket = client.connect(SocketAddr::new(fake_ip, origin_addr.port()), 40000);
+        client.poll_until(socket, |socket| socket.may_send()).await;
+        client
+            .sockets
+            .get_mut::<tcp::Socket>(socket)
+            .send_slice(b"ping")
+            .unwrap();
+        client.poll_until(socket, |socket| socket.send_queue() == 0).await;
+        let (mut server, _) = origin.accept().await.unwrap();
+        let mut received = [0u8; 4];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+        server.write_all(b"pong").await.unwrap();
+        drop(server);
+
+        let mut response = vec![];
+        client
+            .poll_until(socket, |socket| {
+                if socket.can_recv() {
+                    let chunk = socket.recv(|buf| (buf.len(), buf.to_vec())).unwrap();
+                    response.extend_from_slice(&chunk);
+                }
+                !socket.may_recv()
+            })
+            .await;
+        assert_eq!(response, b"pong");
+        client.sockets.get_mut::<tcp::Socket>(socket).close();
+        client
+            .poll_until(socket, |socket| socket.state() == State::Closed)
+            .await;
+
+        // a fake ip which maps to no domain is reset
+        let unmapped = SocketAddr::from(([198, 19, 255, 254], 80));
+        let socket = client.connect(unmapped, 40001);
+        client
+            .poll_until(socket, |socket| socket.state() == State::Closed)
+            .await;
+
+        while connections.recent().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let recent = connections.recent();
+        assert!(recent.iter().all(|conn| conn.inbound == "tun-guests"));
+        let relayed = recent
+            .iter()
+            .find(|conn| conn.destination == origin_addr.to_string())
+            .unwrap();
+        assert_eq!((relayed.upload, relayed.download), (4, 4));
+        assert_eq!(relayed.reason, CloseReason::ServerEof);
+        assert!(recent.iter().any(|conn| conn.destination == unmapped.to_string()
+            && conn.error.as_deref() == Some("fake ip 198.19.255.254 maps to no domain")));
+
+        stack.abort();
+    }
+}
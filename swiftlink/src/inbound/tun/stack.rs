@@ -0,0 +1,354 @@
+//! The userspace TCP stack terminating the connections routed to the interface.
+//!
+//! The stack accepts any destination: a socket listening on the destination of each SYN is added
+//! before the SYN is processed. Established connections are served by a task of their own, their
+//! payload goes through channels, a full channel stops reading from the socket and so shrinks the
+//! TCP window.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use smoltcp::{
+    iface::{Config, Interface, SocketHandle, SocketSet},
+    phy::{self, DeviceCapabilities, Medium},
+    socket::tcp::{self, State},
+    time::Instant,
+    wire::{
+        HardwareAddress, IpAddress, IpCidr, IpEndpoint, IpProtocol, Ipv4Address, Ipv4Packet, Ipv6Address, Ipv6Packet,
+        TcpPacket,
+    },
+};
+use tokio::sync::{
+    mpsc::{self, error::TryRecvError},
+    Notify,
+};
+
+use super::TunContext;
+use swiftlink_infra::log::*;
+
+/// Size of the receive and of the send buffer of a socket.
+const TCP_BUFFER_LEN: usize = 32 * 1024;
+
+/// Connections the stack holds at most, further SYNs are dropped.
+const MAX_CONNECTIONS: usize = 4096;
+
+/// Chunks queued in each direction of a connection.
+const QUEUED_CHUNKS: usize = 8;
+
+/// Longest wait without packets or payload, timers of the sockets shorten it.
+const MAX_POLL_DELAY: Duration = Duration::from_secs(1);
+
+/// What the task of a connection receives for the client.
+pub(super) enum Download {
+    Data(Vec<u8>),
+    /// resets the connection instead of closing it
+    Reset,
+}
+
+/// The end of a connection its task holds. Dropping `download` closes the connection.
+pub(super) struct Stream {
+    /// payload of the client, closed once the client closed its side
+    pub(super) upload: mpsc::Receiver<Vec<u8>>,
+    pub(super) download: mpsc::Sender<Download>,
+    /// wakes the stack, after taking an upload chunk or queueing a download one
+    pub(super) wake: Arc<Notify>,
+}
+
+/// A connection of the stack, from its SYN on.
+struct Connection {
+    source: IpEndpoint,
+    destination: IpEndpoint,
+    /// `None` until established
+    relay: Option<Relay>,
+}
+
+/// The end of an established connection the stack holds.
+struct Relay {
+    /// `None` once the client closed its side
+    upload: Option<mpsc::Sender<Vec<u8>>>,
+    download: mpsc::Receiver<Download>,
+    /// the chunk being sent to the client and how much of it was sent
+    pending: Option<(Vec<u8>, usize)>,
+}
+
+/// Processes the packets of `packets_in`, answering on `packets_out`, until either channel closes.
+pub(super) async fn run(
+    mtu: usize,
+    mut packets_in: mpsc::Receiver<Vec<u8>>,
+    packets_out: mpsc::Sender<Vec<u8>>,
+    context: Arc<TunContext>,
+) {
+    let mut device = Queue::new(mtu);
+    let mut iface = interface(&mut device);
+    let mut sockets = SocketSet::new(vec![]);
+    let mut connections: HashMap<SocketHandle, Connection> = HashMap::new();
+    let mut endpoints: HashSet<(IpEndpoint, IpEndpoint)> = HashSet::new();
+    let wake = Arc::new(Notify::new());
+
+    loop {
+        let delay = iface
+            .poll_delay(Instant::now(), &sockets)
+            .map_or(MAX_POLL_DELAY, |delay| Duration::from(delay).min(MAX_POLL_DELAY));
+        let mut received = tokio::select! {
+            packet = packets_in.recv() => match packet {
+                Some(packet) => Some(packet),
+                None => return,
+            },
+            _ = wake.notified() => None,
+            _ = tokio::time::sleep(delay) => None,
+        };
+
+        while let Some(packet) = received.take().or_else(|| packets_in.try_recv().ok()) {
+            if let Some(endpoints_of_syn) = syn_endpoints(&packet) {
+                if !endpoints.contains(&endpoints_of_syn) {
+                    if connections.len() >= MAX_CONNECTIONS {
+                        debug!("tun stack is full, SYN of {} dropped", endpoints_of_syn.0);
+                        continue;
+                    }
+                    let (source, destination) = endpoints_of_syn;
+                    let mut socket = tcp::Socket::new(
+                        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_LEN]),
+                        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_LEN]),
+                    );
+                    if socket.listen(destination).is_err() {
+                        continue;
+                    }
+                    let handle = sockets.add(socket);
+                    connections.insert(
+                        handle,
+                        Connection {
+                            source,
+                            destination,
+                            relay: None,
+                        },
+                    );
+                    endpoints.insert(endpoints_of_syn);
+                }
+            }
+            device.rx.push_back(packet);
+        }
+
+        iface.poll(Instant::now(), &mut device, &mut sockets);
+        for (handle, conn) in connections.iter_mut() {
+            let socket = sockets.get_mut::<tcp::Socket>(*handle);
+            exchange(socket, conn, &wake, &context);
+        }
+        iface.poll(Instant::now(), &mut device, &mut sockets);
+
+        connections.retain(|handle, conn| {
+            let state = sockets.get_mut::<tcp::Socket>(*handle).state();
+            // a listener left over didn't get its SYN, e.g. the checksum was wrong
+            let done = matches!(state, State::Closed | State::TimeWait | State::Listen);
+            if done {
+                sockets.remove(*handle);
+                endpoints.remove(&(conn.source, conn.destination));
+            }
+            !done
+        });
+
+        for packet in device.tx.drain(..) {
+            if packets_out.send(packet).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Moves the payload between the socket of `conn` and its task, which is spawned once the
+/// connection is established.
+fn exchange(socket: &mut tcp::Socket, conn: &mut Connection, wake: &Arc<Notify>, context: &Arc<TunContext>) {
+    if conn.relay.is_none() {
+        // the client may have sent its FIN right after the handshake
+        if !matches!(socket.state(), State::Established | State::CloseWait) {
+            return;
+        }
+        let (upload_tx, upload_rx) = mpsc::channel(QUEUED_CHUNKS);
+        let (download_tx, download_rx) = mpsc::channel(QUEUED_CHUNKS);
+        let stream = Stream {
+            upload: upload_rx,
+            download: download_tx,
+            wake: wake.clone(),
+        };
+        let source = socket_addr(conn.source);
+        let destination = socket_addr(conn.destination);
+        tokio::spawn(super::handle(source, destination, stream, context.clone()));
+        conn.relay = Some(Relay {
+            upload: Some(upload_tx),
+            download: download_rx,
+            pending: None,
+        });
+    }
+    let Some(relay) = conn.relay.as_mut() else {
+        return;
+    };
+
+    // from the client
+    while socket.can_recv() {
+        let Some(permit) = relay.upload.as_ref().and_then(|upload| upload.try_reserve().ok()) else {
+            break;
+        };
+        match socket.recv(|buf| (buf.len(), buf.to_vec())) {
+            Ok(chunk) => permit.send(chunk),
+            Err(_) => break,
+        }
+    }
+    if !socket.may_recv() && !socket.can_recv() {
+        relay.upload = None;
+    }
+
+    // to the client
+    loop {
+        if relay.pending.is_none() {
+            match relay.download.try_recv() {
+                Ok(Download::Data(chunk)) => relay.pending = Some((chunk, 0)),
+                Ok(Download::Reset) => {
+                    socket.abort();
+                    return;
+                }
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    socket.close();
+                    return;
+                }
+            }
+        }
+        let Some((chunk, sent)) = relay.pending.as_mut() else {
+            return;
+        };
+        match socket.send_slice(&chunk[*sent..]) {
+            Ok(n) => *sent += n,
+            // the client is gone, the socket closes
+            Err(_) => return,
+        }
+        if *sent < chunk.len() {
+            return;
+        }
+        relay.pending = None;
+    }
+}
+
+/// The source and destination of a SYN, `None` for other packets.
+fn syn_endpoints(packet: &[u8]) -> Option<(IpEndpoint, IpEndpoint)> {
+    let (source, destination, payload) = match packet.first()? >> 4 {
+        4 => {
+            let ip = Ipv4Packet::new_checked(packet).ok()?;
+            if ip.next_header() != IpProtocol::Tcp {
+                return None;
+            }
+            (
+                IpAddress::Ipv4(ip.src_addr()),
+                IpAddress::Ipv4(ip.dst_addr()),
+                ip.payload(),
+            )
+        }
+        6 => {
+            let ip = Ipv6Packet::new_checked(packet).ok()?;
+            if ip.next_header() != IpProtocol::Tcp {
+                return None;
+            }
+            (
+                IpAddress::Ipv6(ip.src_addr()),
+                IpAddress::Ipv6(ip.dst_addr()),
+                ip.payload(),
+            )
+        }
+        _ => return None,
+    };
+    let tcp = TcpPacket::new_checked(payload).ok()?;
+    (tcp.syn() && !tcp.ack()).then(|| {
+        (
+            IpEndpoint::new(source, tcp.src_port()),
+            IpEndpoint::new(destination, tcp.dst_port()),
+        )
+    })
+}
+
+fn socket_addr(endpoint: IpEndpoint) -> SocketAddr {
+    SocketAddr::new(endpoint.addr.into(), endpoint.port)
+}
+
+/// An interface which takes packets for any address.
+fn interface(device: &mut Queue) -> Interface {
+    let mut config = Config::new(HardwareAddress::Ip);
+    config.random_seed = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+    let mut iface = Interface::new(config, device, Instant::now());
+    // packets to other addresses are accepted if routed through an address of the interface
+    let (gateway4, gateway6) = (Ipv4Address::new(0, 0, 0, 1), Ipv6Address::new(0, 0, 0, 0, 0, 0, 0, 1));
+    iface.update_ip_addrs(|addrs| {
+        _ = addrs.push(IpCidr::new(gateway4.into(), 0));
+        _ = addrs.push(IpCidr::new(gateway6.into(), 0));
+    });
+    _ = iface.routes_mut().add_default_ipv4_route(gateway4);
+    _ = iface.routes_mut().add_default_ipv6_route(gateway6);
+    iface.set_any_ip(true);
+    iface
+}
+
+/// The packets between the TUN device and the interface.
+pub(super) struct Queue {
+    pub(super) rx: VecDeque<Vec<u8>>,
+    pub(super) tx: VecDeque<Vec<u8>>,
+    mtu: usize,
+}
+
+impl Queue {
+    pub(super) fn new(mtu: usize) -> Self {
+        Self {
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            mtu,
+        }
+    }
+}
+
+impl phy::Device for Queue {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let packet = self.rx.pop_front()?;
+        Some((RxToken(packet), TxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(TxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
+
+pub(super) struct RxToken(Vec<u8>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+pub(super) struct TxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0; len];
+        let result = f(&mut packet);
+        self.0.push_back(packet);
+        result
+    }
+}
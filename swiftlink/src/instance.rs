@@ -0,0 +1,1388 @@
+//! Embedding API.
+//!
+//! ```ignore
+//! let handle = swiftlink::Instance::builder()
+//!     .config_file("swiftlink.toml")
+//!     .build()?
+//!     .start()
+//!     .await?;
+//!
+//! println!("{:?}", handle.stats());
+//! handle.shutdown(Duration::from_secs(5)).await;
+//! ```
+
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use futures_util::{future::join_all, FutureExt};
+use tokio::{sync::watch, task::JoinHandle};
+
+use swiftlink_dns::{build_dns_resolver, build_nameserver_policy, DnsConfig, ServerHandleBuilder, StaticRecordsHandle};
+use swiftlink_infra::{
+    cachefile::CacheFile,
+    connection::{ClosedConnection, ConnectionHistory, HistoryQuery},
+    geoip::GeoIpDb,
+    geosite::GeoSite,
+    knock::KnockGate,
+    log::*,
+    net::{dial_limit, loop_guard, ConnectOpts},
+    proxy_stats::SpeedRecord,
+    tcp_reuse_port, udp, udp_reuse_port, watchdog, Listener,
+};
+
+use crate::{
+    api::{self, Api},
+    captive::{self, PortalDetector},
+    config::{Config, ProxyGroupType, ProxyMeta},
+    context::AppContext,
+    decisions::DecisionLog,
+    error::Error,
+    geoip_update::{self, GeoIpUpdater},
+    health::{self, Health},
+    inbound, layout,
+    outbound::Outbounds,
+    proxy_group::ProxyGroups,
+    proxy_health::{HealthChecker, SpeedTester},
+    route::Router,
+    rule_provider::{self, RuleProvider, RuleProviderUpdater},
+    sni_proxy,
+};
+
+#[derive(Default)]
+pub struct InstanceBuilder {
+    config: Option<Config>,
+    config_path: Option<PathBuf>,
+    home_dir: Option<PathBuf>,
+}
+
+impl InstanceBuilder {
+    /// Uses an already loaded configuration.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Loads the configuration from `path` when the instance is built.
+    pub fn config_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.config_path = Some(path.into());
+        self
+    }
+
+    /// The directory holding the cache database, default is `~/.config/swiftlink`.
+    ///
+    /// Relative paths in the configuration are resolved against it.
+    pub fn home_dir<P: Into<PathBuf>>(mut self, home_dir: P) -> Self {
+        self.home_dir = Some(home_dir.into());
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<Instance> {
+        let mut config = match (self.config, self.config_path) {
+            (Some(config), _) => config,
+            (None, Some(path)) => {
+                Config::load_from_file(&path).with_context(|| format!("Error while loading config file: {:?}", path))?
+            }
+            (None, None) => bail!("Either config or config_file is required"),
+        };
+
+        let mut home_dir = self.home_dir.unwrap_or_else(crate::default_home_dir);
+        if home_dir.is_relative() {
+            home_dir = std::env::current_dir()?.join(home_dir);
+        }
+
+        // fail now rather than on first use
+        layout::verify(layout::check_instance(&config, &home_dir))?;
+        config.import_dnsmasq(&home_dir)?;
+
+        Ok(Instance {
+            config: Arc::new(config),
+            home_dir,
+        })
+    }
+}
+
+/// A configured, not yet started swiftlink engine.
+pub struct Instance {
+    config: Arc<Config>,
+    home_dir: PathBuf,
+}
+
+impl Instance {
+    pub fn builder() -> InstanceBuilder {
+        InstanceBuilder::default()
+    }
+
+    #[inline]
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    #[inline]
+    pub fn home_dir(&self) -> &Path {
+        &self.home_dir
+    }
+
+    /// Binds the listeners and starts serving on the current tokio runtime.
+    pub async fn start(self) -> anyhow::Result<InstanceHandle> {
+        let Instance { config, home_dir } = self;
+
+        // Raise `nofile` limit on Linux/MacOS
+        #[cfg(unix)]
+        fdlimit::raise_fd_limit();
+
+        // initialize cachefile
+        if let Err(err) = CacheFile::with_cache_dir(home_dir.join("cachedb")) {
+            warn!("Failed to initialize cachefile: {:?}", err);
+        }
+
+        // both are process wide, the ones of an instance started before are replaced
+        let dial_limits = config.dial_limit();
+        if let Some((max_dials, max_queue)) = dial_limits {
+            info!("limit outbound dials to {}, with {} queued", max_dials, max_queue);
+        }
+        dial_limit::init(dial_limits);
+
+        let watchdog_thresholds = config.watchdog_thresholds();
+        debug!("watchdog thresholds: {:?}", watchdog_thresholds);
+        watchdog::init(watchdog_thresholds, Duration::from_secs(5));
+
+        // sockets of the process this one replaces on an upgrade, claimed like socket activation
+        #[cfg(unix)]
+        let predecessor = match swiftlink_infra::handover::Predecessor::take_from_env() {
+            Ok(Some((predecessor, sockets))) => {
+                swiftlink_infra::systemd::activated().adopt(sockets);
+                Some(predecessor)
+            }
+            Ok(None) => None,
+            Err(err) => {
+                warn!("Failed to take over sockets of the previous process: {}", err);
+                None
+            }
+        };
+
+        let (shutdown_tx, _) = watch::channel(false);
+        let mut servers = Servers::new();
+        let mut context = AppContext::default();
+        context.set_connections(Arc::new(ConnectionHistory::new(config.connection_history())));
+        context.events().spawn_logger();
+        context.talkers().spawn_collector(&context.events());
+
+        let connect_opts = ConnectOpts {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            fwmark: config.fwmark(),
+            bind_interface: config.interface_name().map(|s| s.to_owned()),
+            egress_pool: config.egress_pool(),
+            udp: config.udp_socket_opts(),
+            ..Default::default()
+        };
+
+        load_rule_data(&config, &home_dir, &mut context, &connect_opts, &shutdown_tx).await;
+        let router = Router::new(config.rules())
+            .map_err(anyhow::Error::msg)?
+            .with_geoip(context.geoip())
+            .with_geoip_asn(context.geoip_asn())
+            .with_geosite(context.geosite())
+            .with_rule_providers(context.rule_providers())
+            .with_excludes(config.proxy_excludes());
+        let blocked = router.blocked_domains();
+        for err in context.blocked_domains().replace(blocked.iter().map(String::as_str)) {
+            warn!("Failed to block a domain of the rules: {}", err);
+        }
+        if config.dns().fakeip_blocked().is_some() && blocked.is_empty() {
+            warn!("fake_ip_blocked is set, but no DOMAIN or DOMAIN-SUFFIX rule rejects a domain for it");
+        }
+        context.rule_hits().set_rules(router.rules().iter().map(String::as_str));
+        context.set_router(Arc::new(router));
+
+        let groups = ProxyGroups::new(&config);
+        if config.health_check().is_none() {
+            let checked = |tp| !matches!(tp, ProxyGroupType::Select | ProxyGroupType::LoadBalance);
+            for group in groups.iter().filter(|group| checked(group.tp())) {
+                warn!(
+                    "proxy group {} of type {} picks its proxy by the health checks, but health_check is not set",
+                    group.name(),
+                    group.tp().as_str()
+                );
+            }
+        }
+        context.set_proxy_groups(Arc::new(groups));
+        context.set_outbounds(Arc::new(Outbounds::new(&config, context.proxy_groups())));
+
+        let health_resolver = start_dns_servers(&config, &home_dir, &mut context, &connect_opts, &mut servers).await?;
+
+        if let Some(addr) = config.health_listen() {
+            let listener = bind_tcp_listener(addr, "HTTP")?;
+            info!("health checks on http://{}/healthz and /readyz", addr);
+            let health = Arc::new(Health::new(health_resolver, shutdown_tx.subscribe()));
+            let name = format!("health listener {}", addr);
+            servers.serve(name, addr, listener, move |listener| {
+                health::serve(listener, health.clone()).map(|_| "stopped accepting".to_owned())
+            })?;
+        }
+
+        if let Some(health_check) = config.health_check() {
+            let proxies = config.dns().proxies().clone();
+            if proxies.is_empty() {
+                warn!("health_check is set, but there are no proxies to check");
+            } else {
+                let restored = context.proxy_stats().warm_start(proxies.keys().map(String::as_str));
+                if restored > 0 {
+                    info!("restored the delay history of {} proxies", restored);
+                }
+                info!(
+                    "health checks of {} proxies every {}s",
+                    proxies.len(),
+                    health_check.interval().as_secs()
+                );
+                let checker = HealthChecker::new(
+                    proxies,
+                    health_check.url(),
+                    connect_opts.clone(),
+                    context.proxy_stats(),
+                    context.proxy_groups(),
+                    context.events(),
+                );
+                spawn_until_shutdown(&shutdown_tx, checker.run(health_check.interval()));
+            }
+        }
+
+        if let Some(captive_portal) = config.captive_portal() {
+            if let Some(addr) = captive_portal.listen {
+                let listener = bind_tcp_listener(addr, "HTTP")?;
+                info!("generate 204 on http://{}", addr);
+                let name = format!("generate 204 listener {}", addr);
+                servers.serve(name, addr, listener, |listener| {
+                    captive::serve(listener).map(|_| "stopped accepting".to_owned())
+                })?;
+            }
+
+            let url = captive_portal.check_url().context("Invalid captive portal check url")?;
+            let detector = PortalDetector::new(url, connect_opts.clone(), context.events());
+            spawn_until_shutdown(&shutdown_tx, detector.run(captive_portal.interval()));
+        }
+
+        if let Some(addr) = config.external_controller() {
+            // the proxies and groups without metadata follow those with, by name
+            let dns = config.dns();
+            let unlisted_meta = ProxyMeta::default();
+            let mut proxies = config.proxy_meta();
+            let mut unlisted: Vec<_> = dns
+                .proxies()
+                .keys()
+                .chain(config.proxy_groups().keys())
+                .filter(|name| !proxies.iter().any(|(listed, _)| listed == name))
+                .map(|name| (name.as_str(), &unlisted_meta))
+                .collect();
+            unlisted.sort_unstable_by_key(|(name, _)| *name);
+            proxies.extend(unlisted);
+
+            let mut api = Api::new(config.secret(), config.external_controller_cors().clone())
+                .with_config(&config)?
+                .with_proxies(proxies)
+                .with_proxy_stats(context.proxy_stats())
+                .with_proxy_servers(dns.proxies().clone())
+                .with_proxy_groups(context.proxy_groups())
+                .with_speed_tester(SpeedTester::new(
+                    &config,
+                    connect_opts.clone(),
+                    context.proxy_stats(),
+                    context.proxy_groups(),
+                ))
+                .with_connections(context.connections())
+                .with_user_traffic(context.user_traffic())
+                .with_rule_hits(context.rule_hits())
+                .with_talkers(context.talkers())
+                .with_dns_failures(context.dns_failures())
+                .with_rule_providers(context.rule_providers(), connect_opts.clone());
+            if let Some(fakedns) = context.fakedns() {
+                api = api.with_fakedns(fakedns);
+            }
+            if let Some((cert, key)) = config.external_controller_tls(&home_dir) {
+                api = api
+                    .with_tls(&cert, &key, config.external_controller_client_auth(&home_dir).as_ref())
+                    .with_context(|| format!("Failed to load the external controller certificate {:?}", cert))?;
+            }
+            let listener = bind_tcp_listener(addr, "HTTP")?;
+
+            if !addr.ip().is_loopback() && config.secret().is_none() {
+                warn!(
+                    "external controller {} is reachable from other hosts without a secret",
+                    addr
+                );
+            }
+            let scheme = if api.is_tls() { "https" } else { "http" };
+            info!("external controller on {}://{}", scheme, addr);
+            let api = Arc::new(api);
+            let name = format!("external controller {}", addr);
+            servers.serve(name, addr, listener, move |listener| {
+                api::serve(listener, api.clone()).map(|_| "stopped accepting".to_owned())
+            })?;
+        }
+
+        // the proxies let through the clients which knocked, if set
+        let mut knock_gate = None;
+        if let Some(knock) = config.knock() {
+            let gate = Arc::new(KnockGate::new(&knock.secret, knock.open_for())?);
+            let addr = knock.listen;
+            let socket = bind_udp_socket(addr)?;
+            info!("knocks on {}, admitted for {}s", addr, knock.open_for().as_secs());
+            knock_gate = Some(gate.clone());
+            let name = format!("knock listener {}", addr);
+            servers.serve(name, addr, socket, move |socket| {
+                let gate = gate.clone();
+                async move {
+                    let result = gate.serve(socket).await;
+                    result.map_or_else(|err| err.to_string(), |_| "stopped receiving".to_owned())
+                }
+            })?;
+        }
+
+        if let Some(addr) = config.http_listen() {
+            let listener = bind_tcp_listener(addr, "HTTP")?;
+            if !addr.ip().is_loopback() && knock_gate.is_none() {
+                warn!(
+                    "http proxy {} is reachable from other hosts without authentication",
+                    addr
+                );
+            }
+            info!("http proxy on {}", addr);
+            let inbound_context = Arc::new(inbound::InboundContext {
+                tag: config.http_tag().to_owned(),
+                connect_opts: Arc::new(connect_opts.clone()),
+                connections: context.connections(),
+                events: context.events(),
+                users: context.user_traffic(),
+                router: context.router(),
+                rule_hits: context.rule_hits(),
+                outbounds: context.outbounds(),
+            });
+            let gate = knock_gate.clone();
+            let name = format!("http proxy {}", addr);
+            servers.serve(name, addr, listener, move |listener| {
+                let serve = inbound::http::serve(listener, inbound_context.clone(), gate.clone());
+                serve.map(|_| "stopped accepting".to_owned())
+            })?;
+        }
+
+        if let Some(addr) = config.socks_listen() {
+            let listener = bind_tcp_listener(addr, "SOCKS")?;
+            let methods = config.socks_auth_methods()?;
+            if !addr.ip().is_loopback() && knock_gate.is_none() && config.socks_auth().contains(&"none") {
+                warn!(
+                    "socks proxy {} is reachable from other hosts without authentication",
+                    addr
+                );
+            }
+            info!("socks proxy on {}, authentication {:?}", addr, methods);
+            let inbound_context = Arc::new(inbound::InboundContext {
+                tag: config.socks_tag().to_owned(),
+                connect_opts: Arc::new(connect_opts.clone()),
+                connections: context.connections(),
+                events: context.events(),
+                users: context.user_traffic(),
+                router: context.router(),
+                rule_hits: context.rule_hits(),
+                outbounds: context.outbounds(),
+            });
+            let methods = Arc::new(methods);
+            let gate = knock_gate.clone();
+            let name = format!("socks proxy {}", addr);
+            servers.serve(name, addr, listener, move |listener| {
+                let serve = inbound::socks::serve(listener, methods.clone(), inbound_context.clone(), gate.clone());
+                serve.map(|_| "stopped accepting".to_owned())
+            })?;
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(tun) = config.tun() {
+            let device = swiftlink_infra::tun::Tun::open(tun.name(), tun.address, tun.mtu())
+                .with_context(|| format!("Failed to create the tun device {}", tun.name()))?;
+            info!("tun device {} on {}", device.name(), tun.address);
+            let listener = Listener::new((tun.address.addr(), 0).into(), Some(device.name().to_owned()));
+            let device = Arc::new(device);
+            let mtu = tun.mtu();
+            let tun_context = Arc::new(inbound::tun::TunContext {
+                tag: tun.tag().to_owned(),
+                fakedns: context.fakedns(),
+                connect_opts: Arc::new(connect_opts.clone()),
+                connections: context.connections(),
+                events: context.events(),
+                router: context.router(),
+                rule_hits: context.rule_hits(),
+                outbounds: context.outbounds(),
+            });
+
+            let name = format!("tun device {}", device.name());
+            servers.spawn(name, listener, move |mut stop| {
+                let device = device.clone();
+                let tun_context = tun_context.clone();
+                async move {
+                    tokio::select! {
+                        result = inbound::tun::serve(device, mtu, tun_context) => {
+                            Err(result.err().map_or("stopped reading".to_owned(), |err| err.to_string()))
+                        }
+                        _ = stop.wait_for(|stop| *stop) => Ok(()),
+                    }
+                }
+            });
+        }
+        #[cfg(not(target_os = "linux"))]
+        if config.tun().is_some() {
+            warn!("tun is only supported on Linux, ignored");
+        }
+
+        if let Some((addr, routes)) = config.sni_proxy() {
+            let sni_listeners = bind_sni_listeners(addr, config.sni_workers())?;
+            info!("forwarding TLS on {} by server name", addr);
+            let routes = Arc::new(routes);
+            let connections = context.connections();
+            let events = context.events();
+            let hits = context.rule_hits();
+            // after the rules of the inbounds
+            let rules: Vec<_> = context.router().rules().into_iter().chain(routes.rules()).collect();
+            hits.set_rules(rules.iter().map(String::as_str));
+            let decisions = match config.record_decisions(&home_dir) {
+                Some(path) => {
+                    let log = DecisionLog::open(&path)
+                        .with_context(|| format!("Failed to open the decision log {:?}", path))?;
+                    info!("recording routing decisions to {:?}", path);
+                    Some(Arc::new(log))
+                }
+                None => None,
+            };
+
+            let name = format!("sni listener {}", addr);
+            servers.serve(name, addr, sni_listeners, move |listeners| {
+                // an accept loop per shard, connections are handled by tasks of their own
+                let shards = listeners.into_iter().map(|listener| {
+                    let serve = sni_proxy::serve(
+                        listener,
+                        routes.clone(),
+                        connections.clone(),
+                        events.clone(),
+                        hits.clone(),
+                        decisions.clone(),
+                    );
+                    Box::pin(serve)
+                });
+                futures::future::select_all(shards).map(|_| "stopped accepting".to_owned())
+            })?;
+        }
+
+        #[cfg(unix)]
+        for addr in swiftlink_infra::systemd::activated().unclaimed() {
+            warn!("socket {} passed by systemd matches no listener", addr);
+        }
+
+        // all listeners are bound, root isn't needed anymore
+        #[cfg(unix)]
+        if let Some((user, group)) = config.run_as() {
+            use swiftlink_infra::privilege;
+
+            if privilege::is_root() {
+                let credentials = privilege::Credentials::lookup(user, group)
+                    .with_context(|| format!("Failed to look up user {}", user))?;
+                privilege::drop_privileges(credentials, config.fwmark().is_some())
+                    .with_context(|| format!("Failed to switch to user {}", user))?;
+            } else {
+                // e.g. the successor of an upgrade, which the previous process started as `user`
+                info!("not running as root, privileges are not dropped to user {}", user);
+            }
+        }
+
+        #[cfg(unix)]
+        if let Some(predecessor) = predecessor {
+            if let Err(err) = predecessor.ready() {
+                warn!("Failed to notify the previous process: {}", err);
+            }
+        }
+
+        info!("server starting up");
+
+        Ok(InstanceHandle {
+            config,
+            context,
+            connect_opts,
+            servers,
+            shutdown_tx: Arc::new(shutdown_tx),
+        })
+    }
+}
+
+/// Loads the GeoIP, GeoSite and rule provider data of the rules into `context`. Data which fails
+/// to load is logged, its rules don't match. Data with an url is refreshed until `shutdown`.
+async fn load_rule_data(
+    config: &Config,
+    home_dir: &Path,
+    context: &mut AppContext,
+    connect_opts: &ConnectOpts,
+    shutdown: &watch::Sender<bool>,
+) {
+    if let Some(path) = config.geoip_asn_location(home_dir) {
+        match GeoIpDb::open(path) {
+            Ok(db) => context.set_geoip_asn(Arc::new(db)),
+            Err(err) => warn!("Failed to load geoip asn database: {}", err),
+        }
+    }
+    if let Some(path) = config.geosite_location(home_dir) {
+        let categories = config.geosite_categories();
+        let categories: Vec<_> = categories.iter().map(String::as_str).collect();
+        match GeoSite::load(path, &categories) {
+            Ok(geosite) => {
+                for category in categories.iter().filter(|category| !geosite.contains(category)) {
+                    warn!("geosite category {} not found, its rules never match", category);
+                }
+                context.set_geosite(Arc::new(geosite));
+            }
+            Err(err) => warn!("Failed to load geosite domain lists: {}", err),
+        }
+    }
+
+    if let Some(path) = config.geoip_location(home_dir) {
+        if let Some(url) = config.geoip_url().filter(|_| !path.exists()) {
+            if let Err(err) = geoip_update::fetch(url, &path, connect_opts).await {
+                warn!("Failed to download geoip database: {:#}", err);
+            }
+        }
+        match GeoIpDb::open(path) {
+            Ok(db) => {
+                let db = Arc::new(db);
+                context.set_geoip(db.clone());
+                if let Some(url) = config.geoip_url() {
+                    let updater = GeoIpUpdater::new(db, url, config.geoip_update_interval(), connect_opts.clone());
+                    spawn_until_shutdown(shutdown, updater.run());
+                }
+            }
+            Err(err) => warn!("Failed to load geoip database: {}", err),
+        }
+    }
+
+    for (name, provider_config) in config.rule_providers() {
+        let path = provider_config.path(name, home_dir);
+        let url = provider_config.url.as_deref();
+        let provider = Arc::new(RuleProvider::new(name, provider_config.behavior, path).with_url(url));
+        if let Some(url) = provider.url().filter(|_| !provider.path().exists()) {
+            if let Err(err) = rule_provider::fetch(&provider, url, connect_opts).await {
+                warn!("Failed to download rule provider {}: {:#}", name, err);
+            }
+        }
+        if let Err(err) = provider.reload() {
+            warn!("{:#}, its rules never match until it's loaded", err);
+        }
+        if provider.url().is_some() {
+            let interval = provider_config.interval();
+            let updater = RuleProviderUpdater::new(provider.clone(), interval, connect_opts.clone());
+            spawn_until_shutdown(shutdown, updater.run());
+        }
+        context.add_rule_provider(provider);
+    }
+}
+
+/// Starts the local dns servers and the dns-forward inbound, returns the resolver of the main
+/// server for the readiness check if dns is enabled.
+async fn start_dns_servers(
+    config: &Config,
+    home_dir: &Path,
+    context: &mut AppContext,
+    connect_opts: &ConnectOpts,
+    servers: &mut Servers,
+) -> anyhow::Result<Option<swiftlink_dns::DnsResolver>> {
+    let dns = config.dns();
+    if dns.enabled() && dns.fakeip() {
+        use swiftlink_infra::fakedns::{Config, FakeDns};
+
+        let mut conf = Config {
+            persist: dns.fakeip_persist(),
+            ipv6: dns.fakeip6(),
+            ..Default::default()
+        };
+
+        // using memory cache
+        if !dns.fakeip_persist() {
+            conf.size = dns.fakeip_size().unwrap_or(2048);
+        }
+
+        let (ipv4_range, ipv6_range) = dns.fakeip_range();
+        if let Some(ipv4_range) = ipv4_range {
+            conf.ipnet = ipv4_range;
+        }
+        if let Some(ipv6_range) = ipv6_range {
+            conf.ipnet6 = ipv6_range;
+        }
+
+        // TODO: fakeip filter
+        let fakedns = Arc::new(Mutex::new(FakeDns::new(conf)));
+        context.set_fakedns(fakedns);
+    }
+
+    let dns_resolver = build_dns_resolver(&dns, connect_opts, None).await;
+    let health_resolver = dns.enabled().then(|| dns_resolver.clone());
+
+    // register local dns servers, `[[dns.listeners]]` with resolvers of their own
+    let mut dns_servers = vec![(dns.clone(), dns_resolver)];
+    for listener in dns.listeners() {
+        let dns = Arc::new(dns.for_listener(listener));
+        let resolver = build_dns_resolver(&dns, connect_opts, None).await;
+        dns_servers.push((dns, resolver));
+    }
+    let mut main_handle = None;
+    for (dns, dns_resolver) in dns_servers {
+        let listener = dns.listen();
+        let policy = build_nameserver_policy(&dns, connect_opts, None).await;
+        let mut builder = ServerHandleBuilder::new(dns.clone(), dns_resolver.into())
+            .with_nameserver_policy(policy)
+            .with_failures(context.dns_failures());
+        let zone_files = config.zone_files(home_dir);
+        if !zone_files.is_empty() {
+            let static_records = StaticRecordsHandle::from_zone_files(&zone_files)?;
+            info!("serving {} zone files as local records", zone_files.len());
+            builder = builder.with_static_records(static_records);
+        }
+        if let Some(fakedns) = context.fakedns().filter(|_| dns.fakeip()) {
+            builder = builder
+                .with_fakedns(fakedns)
+                .with_blocked_domains(context.blocked_domains());
+        }
+        let server_handle = builder.build();
+        main_handle.get_or_insert_with(|| server_handle.clone());
+
+        let udp_sockets = bind_dns_udp_sockets(&dns)?;
+        let name = format!("dns server {}", listener);
+        servers.spawn_on(name, listener, udp_sockets, move |sockets, mut stop| {
+            let mut server = swiftlink_dns::ServerFuture::new(server_handle.clone());
+            for socket in sockets {
+                server.register_socket(socket);
+            }
+            async move {
+                let done = tokio::select! {
+                    result = server.block_until_done() => Some(result),
+                    _ = stop.wait_for(|stop| *stop) => None,
+                };
+                match done {
+                    // e.g. the sockets failed with unrecoverable errors
+                    Some(result) => Err(result.err().map_or("all sockets closed".to_owned(), |e| e.to_string())),
+                    None => {
+                        if let Err(err) = server.shutdown_gracefully().await {
+                            warn!("dns server shutdown failed, {}", err);
+                        }
+                        Ok(())
+                    }
+                }
+            }
+        })?;
+    }
+
+    // the main server answers the dns-forward inbound too
+    for &addr in config.dns_forward_listen() {
+        let Some(server_handle) = main_handle.clone() else {
+            break;
+        };
+        let socket = bind_udp_socket(addr)?;
+        info!("dns forward on {}", addr);
+        let name = format!("dns forward {}", addr);
+        servers.serve(name, addr, socket, move |socket| {
+            inbound::dns_forward::serve(socket, server_handle.clone()).map(|result| {
+                result
+                    .err()
+                    .map_or("all sockets closed".to_owned(), |err| err.to_string())
+            })
+        })?;
+    }
+
+    Ok(health_resolver)
+}
+
+/// Spawns `task`, it's dropped on `shutdown` if it hasn't finished.
+fn spawn_until_shutdown<F>(shutdown: &watch::Sender<bool>, task: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let mut shutdown = shutdown.subscribe();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = task => {}
+            _ = shutdown.wait_for(|shutdown| *shutdown) => {}
+        }
+    });
+}
+
+/// A running swiftlink engine.
+pub struct InstanceHandle {
+    config: Arc<Config>,
+    context: AppContext,
+    /// options of the sockets to the internet, e.g. for proxy speed tests
+    connect_opts: ConnectOpts,
+    servers: Servers,
+    shutdown_tx: Arc<watch::Sender<bool>>,
+}
+
+/// A server task which stopped on its own or panicked, the instance doesn't fully serve anymore.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{task} failed: {reason}")]
+pub struct TaskFailure {
+    pub task: String,
+    pub reason: String,
+}
+
+/// Snapshot of the engine counters
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub listeners: Vec<Listener>,
+    /// `None` if outbound dials are not limited
+    pub dials: Option<dial_limit::DialStats>,
+    pub watchdog: Option<watchdog::WatchdogState>,
+}
+
+/// Requests the shutdown of an [`InstanceHandle`], cloneable and usable from any thread.
+#[derive(Clone)]
+pub struct ShutdownTrigger(Arc<watch::Sender<bool>>);
+
+impl ShutdownTrigger {
+    pub fn shutdown(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+impl InstanceHandle {
+    #[inline]
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    #[inline]
+    pub fn context(&self) -> &AppContext {
+        &self.context
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            listeners: self
+                .servers
+                .tasks
+                .iter()
+                .map(|(listener, _)| listener.clone())
+                .collect(),
+            dials: dial_limit::limiter().map(|l| l.stats()),
+            watchdog: watchdog::watchdog().map(|w| w.state()),
+        }
+    }
+
+    /// The recently closed connections `query` selects, most recently closed first.
+    pub fn closed_connections(&self, query: &HistoryQuery) -> Vec<ClosedConnection> {
+        self.context.connections().query(query)
+    }
+
+    /// Downloads the `speedtest_url` through the proxy `name` of `dns.proxy_servers`, or the
+    /// member of the group `name`, and records the result in the
+    /// [`ProxyStats`](swiftlink_infra::proxy_stats::ProxyStats) of the proxy.
+    pub async fn speedtest_proxy(&self, name: &str) -> anyhow::Result<SpeedRecord> {
+        let tester = SpeedTester::new(
+            &self.config,
+            self.connect_opts.clone(),
+            self.context.proxy_stats(),
+            self.context.proxy_groups(),
+        );
+        tester.run(name).await
+    }
+
+    pub fn shutdown_trigger(&self) -> ShutdownTrigger {
+        ShutdownTrigger(self.shutdown_tx.clone())
+    }
+
+    /// Waits until a [`ShutdownTrigger`] of this instance fires.
+    pub async fn wait_for_shutdown(&self) {
+        let mut rx = self.shutdown_tx.subscribe();
+        let _ = rx.wait_for(|shutdown| *shutdown).await;
+    }
+
+    /// Waits until a server task of this instance fails, see [`TaskFailure`].
+    pub async fn wait_for_failure(&self) -> TaskFailure {
+        let mut rx = self.servers.failures.subscribe();
+        let failure = match rx.wait_for(|failure| failure.is_some()).await {
+            Ok(failure) => failure.clone(),
+            // the sender lives as long as the handle
+            Err(_) => None,
+        };
+        match failure {
+            Some(failure) => failure,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Starts the current executable, which may have been replaced, on the listening sockets of
+    /// this instance.
+    ///
+    /// Returns once the new process serves, this instance should be shut down then. On error this
+    /// instance keeps serving.
+    #[cfg(unix)]
+    pub async fn upgrade(&self) -> anyhow::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let fds: Vec<_> = self.servers.fds.iter().map(|fd| fd.as_raw_fd()).collect();
+        let child = tokio::task::spawn_blocking(move || swiftlink_infra::handover::spawn_successor(&fds)).await??;
+
+        info!("upgraded, new process {} is serving", child.id());
+        Ok(())
+    }
+
+    /// Stops all listeners, waiting at most `timeout` for each of them.
+    pub async fn shutdown(mut self, timeout: Duration) {
+        // readiness checks fail from now on
+        self.shutdown_tx.send_replace(true);
+
+        let shutdown_tasks = self.servers.tasks.iter_mut().map(|(_, server)| async move {
+            match server.shutdown(timeout).await {
+                Ok(_) => (),
+                Err(err) => warn!("{:?}", err),
+            }
+        });
+
+        join_all(shutdown_tasks).await;
+    }
+}
+
+/// Binds the UDP sockets of the local dns server.
+///
+/// A matching socket passed by systemd socket activation is used as is. Otherwise more than one
+/// worker shards the listener with `SO_REUSEPORT`. If the configured (privileged) port can't be
+/// bound, `listen_fallback_port` is used instead when it is set.
+fn bind_dns_udp_sockets(dns: &DnsConfig) -> Result<Vec<tokio::net::UdpSocket>, Error> {
+    type BindUdp = fn(SocketAddr, Option<&str>, &str) -> io::Result<tokio::net::UdpSocket>;
+
+    let listener = dns.listen();
+    let workers = match dns.udp_workers() {
+        0 => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        n if n > 1 && !cfg!(unix) => {
+            warn!(
+                "SO_REUSEPORT is not supported on this platform, ignore udp_workers = {}",
+                n
+            );
+            1
+        }
+        n => n,
+    };
+
+    let bind: BindUdp = if workers > 1 { udp_reuse_port } else { udp };
+
+    let mut sock_addr = listener.sock_addr();
+
+    // the socket passed by systemd can't be sharded
+    #[cfg(unix)]
+    if let Some(socket) = swiftlink_infra::systemd::activated().take_udp(sock_addr) {
+        match socket
+            .set_nonblocking(true)
+            .and_then(|_| tokio::net::UdpSocket::from_std(socket))
+        {
+            Ok(socket) => {
+                info!("listening for UDP on {} passed by systemd", sock_addr);
+                return Ok(vec![socket]);
+            }
+            Err(err) => warn!("could not use UDP socket {} passed by systemd, {}", sock_addr, err),
+        }
+    }
+
+    let first = match bind(sock_addr, listener.device(), "UDP") {
+        Ok(socket) => socket,
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied && dns.listen_fallback_port().is_some() => {
+            let port = dns.listen_fallback_port().unwrap_or_default();
+            warn!(
+                "could not bind dns server to {}, {}, fallback to port {}",
+                sock_addr, err, port
+            );
+            sock_addr.set_port(port);
+            bind(sock_addr, listener.device(), "UDP")
+                .map_err(|err| Error::RegisterListenerFailed("UDP", sock_addr, err.to_string()))?
+        }
+        Err(err) => return Err(Error::RegisterListenerFailed("UDP", sock_addr, err.to_string())),
+    };
+
+    // all shards must share the address the first socket actually got
+    let sock_addr = first.local_addr().unwrap_or(sock_addr);
+
+    let mut sockets = vec![first];
+    for _ in 1..workers {
+        sockets.push(
+            bind(sock_addr, listener.device(), "UDP")
+                .map_err(|err| Error::RegisterListenerFailed("UDP", sock_addr, err.to_string()))?,
+        );
+    }
+
+    Ok(sockets)
+}
+
+/// Binds a UDP socket on `addr`, e.g. of `dns_forward_listen`, or takes the one passed by systemd.
+fn bind_udp_socket(addr: SocketAddr) -> Result<tokio::net::UdpSocket, Error> {
+    #[cfg(unix)]
+    if let Some(socket) = swiftlink_infra::systemd::activated().take_udp(addr) {
+        match socket
+            .set_nonblocking(true)
+            .and_then(|_| tokio::net::UdpSocket::from_std(socket))
+        {
+            Ok(socket) => return Ok(socket),
+            Err(err) => warn!("could not use UDP socket {} passed by systemd, {}", addr, err),
+        }
+    }
+    udp(addr, None, "UDP").map_err(|err| Error::RegisterListenerFailed("UDP", addr, err.to_string()))
+}
+
+/// Binds the listeners of the TLS passthrough.
+///
+/// More than one worker shards the listener with `SO_REUSEPORT`. A listener passed by systemd, or
+/// by the predecessor on upgrade, is used as the first shard. It can only be joined by further
+/// shards if it was bound with `SO_REUSEPORT` too, otherwise it's used alone.
+fn bind_sni_listeners(addr: SocketAddr, workers: usize) -> Result<Vec<tokio::net::TcpListener>, Error> {
+    let workers = match workers {
+        0 => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        n if n > 1 && !cfg!(unix) => {
+            warn!(
+                "SO_REUSEPORT is not supported on this platform, ignore sni_workers = {}",
+                n
+            );
+            1
+        }
+        n => n,
+    };
+    let failed = |err: io::Error| Error::RegisterListenerFailed("TLS", addr, err.to_string());
+
+    #[cfg(unix)]
+    let passed = swiftlink_infra::systemd::activated().take_tcp(addr);
+    #[cfg(not(unix))]
+    let passed = None;
+
+    let first = match passed {
+        Some(listener) => listener,
+        None if workers > 1 => tcp_reuse_port(addr, None, "TLS")
+            .and_then(|listener| listener.into_std())
+            .map_err(failed)?,
+        None => std::net::TcpListener::bind(addr).map_err(failed)?,
+    };
+    first.set_nonblocking(true).map_err(failed)?;
+    let first = tokio::net::TcpListener::from_std(first).map_err(failed)?;
+
+    // all shards must share the address the first listener actually got
+    let addr = first.local_addr().unwrap_or(addr);
+
+    let mut listeners = vec![first];
+    for _ in 1..workers {
+        match tcp_reuse_port(addr, None, "TLS") {
+            Ok(listener) => listeners.push(listener),
+            Err(err) => {
+                warn!("sni listener {} can't be sharded, {}", addr, err);
+                break;
+            }
+        }
+    }
+    Ok(listeners)
+}
+
+/// Binds a TCP listener of `protocol`, or takes the one passed by systemd.
+fn bind_tcp_listener(addr: SocketAddr, protocol: &'static str) -> Result<tokio::net::TcpListener, Error> {
+    let bind = || {
+        #[cfg(unix)]
+        let listener = swiftlink_infra::systemd::activated().take_tcp(addr);
+        #[cfg(not(unix))]
+        let listener = None;
+
+        let listener = match listener {
+            Some(listener) => listener,
+            None => std::net::TcpListener::bind(addr)?,
+        };
+        listener.set_nonblocking(true)?;
+        tokio::net::TcpListener::from_std(listener)
+    };
+    bind().map_err(|err| Error::RegisterListenerFailed(protocol, addr, err.to_string()))
+}
+
+/// The servers of an instance, with the listener each serves.
+struct Servers {
+    /// a TCP and a UDP server may serve the same listener
+    tasks: Vec<(Listener, ServerTask)>,
+    /// duplicated listener sockets, handed over on upgrade
+    #[cfg(unix)]
+    fds: Vec<std::os::unix::io::OwnedFd>,
+    /// the TCP listeners served, connections to them aren't proxied
+    loop_guards: Vec<loop_guard::Registration>,
+    /// the first server task which failed
+    failures: Arc<watch::Sender<Option<TaskFailure>>>,
+}
+
+impl Servers {
+    fn new() -> Self {
+        Self {
+            tasks: Vec::new(),
+            #[cfg(unix)]
+            fds: Vec::new(),
+            loop_guards: Vec::new(),
+            failures: Arc::new(watch::channel(None).0),
+        }
+    }
+
+    /// Spawns the [`ServerTask`] `name` serving `listener`.
+    fn spawn<F, Fut>(&mut self, name: String, listener: Listener, server: F)
+    where
+        F: FnMut(watch::Receiver<bool>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let task = ServerTask::spawn(name, RestartPolicy::default(), self.failures.clone(), server);
+        self.tasks.push((listener, task));
+    }
+
+    /// Spawns the [`ServerTask`] `name` serving the bound `sockets` of `listener`, `server` is
+    /// passed clones of them on each (re)start. The sockets are handed over on upgrade.
+    fn spawn_on<S, F, Fut>(&mut self, name: String, listener: Listener, sockets: S, mut server: F) -> io::Result<()>
+    where
+        S: ServerSockets,
+        F: FnMut(S, watch::Receiver<bool>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        #[cfg(unix)]
+        match sockets.handover() {
+            Ok(fd) => self.fds.push(fd),
+            Err(err) => warn!("{} can't be handed over on upgrade, {}", name, err),
+        }
+        self.loop_guards.extend(sockets.register_loop_guard());
+
+        let sockets = sockets.into_std()?;
+        self.spawn(name, listener, move |stop| {
+            let server = S::clone_std(&sockets).map(|sockets| server(sockets, stop));
+            async move { server.map_err(|err| err.to_string())?.await }
+        });
+        Ok(())
+    }
+
+    /// Like [`spawn_on`](Self::spawn_on) for the servers which run until they're stopped, the
+    /// future `serve` builds resolves to the reason it ended on its own.
+    fn serve<S, F, Fut>(&mut self, name: String, addr: SocketAddr, sockets: S, mut serve: F) -> io::Result<()>
+    where
+        S: ServerSockets,
+        F: FnMut(S) -> Fut + Send + 'static,
+        Fut: Future<Output = String> + Send + 'static,
+    {
+        self.spawn_on(name, Listener::new(addr, None), sockets, move |sockets, mut stop| {
+            let serve = serve(sockets);
+            async move {
+                tokio::select! {
+                    reason = serve => Err(reason),
+                    _ = stop.wait_for(|stop| *stop) => Ok(()),
+                }
+            }
+        })
+    }
+}
+
+/// The bound sockets of a [`ServerTask`]. They're kept as std sockets, a restarted task serves
+/// clones of them.
+trait ServerSockets: Sized {
+    type Std: Send + 'static;
+
+    /// Duplicates the socket handed over on upgrade. Of shards, which share the address, a
+    /// successor only needs one.
+    #[cfg(unix)]
+    fn handover(&self) -> io::Result<std::os::unix::io::OwnedFd>;
+
+    /// Registers the address of a TCP listener with the loop guard, for as long as it's served.
+    fn register_loop_guard(&self) -> Option<loop_guard::Registration> {
+        None
+    }
+
+    fn into_std(self) -> io::Result<Self::Std>;
+
+    fn clone_std(sockets: &Self::Std) -> io::Result<Self>;
+}
+
+impl ServerSockets for tokio::net::TcpListener {
+    type Std = std::net::TcpListener;
+
+    #[cfg(unix)]
+    fn handover(&self) -> io::Result<std::os::unix::io::OwnedFd> {
+        swiftlink_infra::handover::dup_listener(self)
+    }
+
+    fn register_loop_guard(&self) -> Option<loop_guard::Registration> {
+        self.local_addr().ok().map(loop_guard::register)
+    }
+
+    fn into_std(self) -> io::Result<Self::Std> {
+        tokio::net::TcpListener::into_std(self)
+    }
+
+    fn clone_std(listener: &Self::Std) -> io::Result<Self> {
+        listener.try_clone().and_then(tokio::net::TcpListener::from_std)
+    }
+}
+
+impl ServerSockets for tokio::net::UdpSocket {
+    type Std = std::net::UdpSocket;
+
+    #[cfg(unix)]
+    fn handover(&self) -> io::Result<std::os::unix::io::OwnedFd> {
+        swiftlink_infra::handover::dup_listener(self)
+    }
+
+    fn into_std(self) -> io::Result<Self::Std> {
+        tokio::net::UdpSocket::into_std(self)
+    }
+
+    fn clone_std(socket: &Self::Std) -> io::Result<Self> {
+        socket.try_clone().and_then(tokio::net::UdpSocket::from_std)
+    }
+}
+
+/// Shards sharing an address, never empty.
+impl<S: ServerSockets> ServerSockets for Vec<S> {
+    type Std = Vec<S::Std>;
+
+    #[cfg(unix)]
+    fn handover(&self) -> io::Result<std::os::unix::io::OwnedFd> {
+        self[0].handover()
+    }
+
+    fn register_loop_guard(&self) -> Option<loop_guard::Registration> {
+        self[0].register_loop_guard()
+    }
+
+    fn into_std(self) -> io::Result<Self::Std> {
+        self.into_iter().map(S::into_std).collect()
+    }
+
+    fn clone_std(shards: &Self::Std) -> io::Result<Self> {
+        shards.iter().map(S::clone_std).collect()
+    }
+}
+
+/// How a failed [`ServerTask`] is restarted.
+#[derive(Debug, Clone, Copy)]
+struct RestartPolicy {
+    /// delay of the first restart, doubled with each further one
+    min_backoff: Duration,
+    max_backoff: Duration,
+    /// restarts in a row before the task is given up and reported as failed
+    max_restarts: u32,
+    /// a task which ran at least this long before it failed starts over with `min_backoff`
+    reset_after: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            max_restarts: 5,
+            reset_after: Duration::from_secs(60),
+        }
+    }
+}
+
+/// A spawned server, restarted when it panics or stops before it is asked to.
+struct ServerTask {
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl ServerTask {
+    /// Spawns the futures `server` builds, one for each (re)start. Such a future resolves to `Ok`
+    /// once `stop` turns `true` and it has wound down, to `Err` with the reason if it ended on its
+    /// own.
+    ///
+    /// Failures and panics are logged and restarted with exponential backoff. Once `policy` gives
+    /// up, the failure is reported to `failures`.
+    fn spawn<F, Fut>(
+        name: String,
+        policy: RestartPolicy,
+        failures: Arc<watch::Sender<Option<TaskFailure>>>,
+        mut server: F,
+    ) -> Self
+    where
+        F: FnMut(watch::Receiver<bool>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let (stop, mut stop_rx) = watch::channel(false);
+        let task = tokio::spawn(async move {
+            let mut backoff = policy.min_backoff;
+            let mut restarts = 0;
+            let reason = loop {
+                let started = Instant::now();
+                let reason = match AssertUnwindSafe(server(stop_rx.clone())).catch_unwind().await {
+                    Ok(Ok(())) => return,
+                    Ok(Err(reason)) => reason,
+                    Err(panic) => format!("panicked, {}", crate::panic_message(&*panic)),
+                };
+                if *stop_rx.borrow() {
+                    return;
+                }
+
+                if started.elapsed() >= policy.reset_after {
+                    backoff = policy.min_backoff;
+                    restarts = 0;
+                }
+                if restarts == policy.max_restarts {
+                    error!("{} failed: {}, giving up after {} restarts", name, reason, restarts);
+                    break reason;
+                }
+                restarts += 1;
+
+                warn!(
+                    "{} failed: {}, restarting in {:?} ({}/{})",
+                    name, reason, backoff, restarts, policy.max_restarts
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = stop_rx.wait_for(|stop| *stop) => return,
+                }
+                backoff = (backoff * 2).min(policy.max_backoff);
+            };
+
+            // the first failure is the one the instance stops for
+            failures.send_if_modified(|failure| {
+                if failure.is_some() {
+                    return false;
+                }
+                *failure = Some(TaskFailure { task: name, reason });
+                true
+            });
+        });
+
+        Self { stop, task }
+    }
+
+    async fn shutdown(&mut self, shutdown_timeout: Duration) -> Result<(), anyhow::Error> {
+        self.stop.send_replace(true);
+        if tokio::time::timeout(shutdown_timeout, &mut self.task).await.is_err() {
+            self.task.abort();
+            bail!("server task did not stop within {:?}, aborted", shutdown_timeout);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instance_builder_requires_config() {
+        assert!(Instance::builder().build().is_err());
+        assert!(Instance::builder()
+            .config_file("/nonexistent/swiftlink.toml")
+            .build()
+            .is_err());
+
+        let config = Config::builder().geoip_location("Country.mmdb").build().unwrap();
+        assert!(Instance::builder()
+            .config(config)
+            .home_dir(std::env::temp_dir().join("swiftlink-instance-test"))
+            .build()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_instance_start_and_shutdown() {
+        let config = Config::load(
+            r#"
+            ipv6_first = false
+            rules = []
+
+            [dns]
+            listen = "127.0.0.1:0"
+            "#,
+        )
+        .unwrap();
+
+        let handle = Instance::builder()
+            .config(config)
+            .home_dir(std::env::temp_dir().join("swiftlink-instance-test"))
+            .build()
+            .unwrap()
+            .start()
+            .await
+            .unwrap();
+
+        assert_eq!(handle.stats().listeners.len(), 1);
+
+        let trigger = handle.shutdown_trigger();
+        tokio::spawn(async move { trigger.shutdown() });
+        handle.wait_for_shutdown().await;
+        handle.shutdown(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test]
+    async fn test_instance_servers_on_one_address() {
+        let config = Config::load(
+            r#"
+            rules = []
+            http_listen = "127.0.0.1:0"
+            socks_listen = "127.0.0.1:0"
+
+            [dns]
+            listen = "127.0.0.1:0"
+            "#,
+        )
+        .unwrap();
+
+        let handle = Instance::builder()
+            .config(config)
+            .home_dir(std::env::temp_dir().join("swiftlink-instance-test"))
+            .build()
+            .unwrap()
+            .start()
+            .await
+            .unwrap();
+
+        // the servers of an address are all kept, and all shut down
+        assert_eq!(handle.stats().listeners.len(), 3);
+        handle.shutdown(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test]
+    async fn test_server_task_restart() {
+        let policy = RestartPolicy {
+            min_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            max_restarts: 3,
+            reset_after: Duration::from_secs(60),
+        };
+        let failures = Arc::new(watch::channel(None).0);
+        let mut rx = failures.subscribe();
+
+        let mut task = ServerTask::spawn("stopping".to_owned(), policy, failures.clone(), |mut stop| async move {
+            let _ = stop.wait_for(|stop| *stop).await;
+            Ok(())
+        });
+        task.shutdown(Duration::from_secs(1)).await.unwrap();
+        assert!(rx.borrow().is_none());
+
+        // panics once, then serves
+        let runs = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let counter = runs.clone();
+        let mut task = ServerTask::spawn("recovering".to_owned(), policy, failures.clone(), move |mut stop| {
+            let run = counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async move {
+                if run == 0 {
+                    panic!("oops");
+                }
+                let _ = stop.wait_for(|stop| *stop).await;
+                Ok(())
+            }
+        });
+        while runs.load(std::sync::atomic::Ordering::SeqCst) < 2 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        task.shutdown(Duration::from_secs(1)).await.unwrap();
+        assert!(rx.borrow().is_none());
+
+        let _task = ServerTask::spawn("panicking".to_owned(), policy, failures.clone(), |_| async move {
+            panic!("oops");
+        });
+        let failure = rx.wait_for(|f| f.is_some()).await.unwrap().clone().unwrap();
+        assert_eq!(failure.task, "panicking");
+        assert_eq!(failure.reason, "panicked, oops");
+    }
+}
@@ -0,0 +1,221 @@
+//! Access checks of the files and directories the configuration refers to.
+//!
+//! Relative paths in the configuration are resolved against the home directory. They are all
+//! checked at startup, so a missing database or a read-only directory, typical for containers
+//! and chroots with a different layout than the host, fails the start instead of the first use.
+//! `swiftlink config check` runs the same checks.
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::bail;
+
+use crate::config::Config;
+
+/// The access a path needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// A file which is read
+    Read,
+    /// A directory files are created in, created if missing
+    Write,
+}
+
+/// The result of checking one configured path.
+#[derive(Debug)]
+pub struct PathCheck {
+    /// The configuration option
+    pub name: &'static str,
+    pub path: PathBuf,
+    pub access: Access,
+    pub result: io::Result<()>,
+}
+
+impl PathCheck {
+    pub fn new<P: Into<PathBuf>>(name: &'static str, path: P, access: Access) -> Self {
+        let path = path.into();
+        let result = match access {
+            Access::Read => check_readable(&path),
+            Access::Write => check_writable(&path),
+        };
+        Self {
+            name,
+            path,
+            access,
+            result,
+        }
+    }
+
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+impl fmt::Display for PathCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = match self.access {
+            Access::Read => "readable",
+            Access::Write => "writable",
+        };
+        match &self.result {
+            Ok(_) => write!(f, "{} {:?} is {}", self.name, self.path, access),
+            Err(err) => write!(f, "{} {:?} is not {}, {}", self.name, self.path, access, err),
+        }
+    }
+}
+
+/// Checks the paths an [`Instance`](crate::Instance) uses: the home directory, the GeoIP
+/// databases, the rule sets and the dns zone files and dnsmasq configurations.
+pub fn check_instance(config: &Config, home_dir: &Path) -> Vec<PathCheck> {
+    let mut checks = vec![PathCheck::new("home_dir", home_dir, Access::Write)];
+    if let Some(path) = config.geoip_location(home_dir) {
+        // a missing database is downloaded next to where it belongs
+        match path.parent().filter(|_| config.geoip_url().is_some()) {
+            Some(dir) => checks.push(PathCheck::new("geoip_location", dir, Access::Write)),
+            None => checks.push(PathCheck::new("geoip_location", path, Access::Read)),
+        }
+    }
+    if let Some(path) = config.geoip_asn_location(home_dir) {
+        checks.push(PathCheck::new("geoip_asn_location", path, Access::Read));
+    }
+    for (name, provider) in config.rule_providers() {
+        let path = provider.path(name, home_dir);
+        match path.parent().filter(|_| provider.url.is_some()) {
+            Some(dir) => checks.push(PathCheck::new("rule_providers", dir, Access::Write)),
+            None => checks.push(PathCheck::new("rule_providers", path, Access::Read)),
+        }
+    }
+    for path in config.zone_files(home_dir) {
+        checks.push(PathCheck::new("dns.zone_files", path, Access::Read));
+    }
+    for path in config.dnsmasq_conf(home_dir) {
+        checks.push(PathCheck::new("dns.dnsmasq_conf", path, Access::Read));
+    }
+    if let Some(dir) = config.record_decisions(home_dir).as_deref().and_then(Path::parent) {
+        checks.push(PathCheck::new("record_decisions", dir, Access::Write));
+    }
+    if let Some((cert, key)) = config.external_controller_tls(home_dir) {
+        checks.push(PathCheck::new("external_controller_tls.cert", cert, Access::Read));
+        checks.push(PathCheck::new("external_controller_tls.key", key, Access::Read));
+    }
+    if let Some(auth) = config.external_controller_client_auth(home_dir) {
+        checks.push(PathCheck::new(
+            "external_controller_tls.client_auth.ca",
+            auth.ca,
+            Access::Read,
+        ));
+        if let Some(crl) = auth.crl {
+            checks.push(PathCheck::new(
+                "external_controller_tls.client_auth.crl",
+                crl,
+                Access::Read,
+            ));
+        }
+    }
+    checks
+}
+
+/// Checks the directory of the log file, `None` if logging is disabled.
+pub fn check_log(config: &Config, home_dir: &Path) -> Option<PathCheck> {
+    if !config.log_enabled() {
+        return None;
+    }
+    let log_file = config.log_file(home_dir);
+    let dir = log_file.parent().unwrap_or(home_dir);
+    Some(PathCheck::new("log_file", dir, Access::Write))
+}
+
+/// Fails with all failed checks.
+pub fn verify<I: IntoIterator<Item = PathCheck>>(checks: I) -> anyhow::Result<()> {
+    let failed = checks
+        .into_iter()
+        .filter(|check| !check.is_ok())
+        .map(|check| check.to_string())
+        .collect::<Vec<_>>();
+
+    if !failed.is_empty() {
+        bail!("Inaccessible paths:\n  {}", failed.join("\n  "));
+    }
+    Ok(())
+}
+
+fn check_readable(path: &Path) -> io::Result<()> {
+    if fs::metadata(path)?.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "is a directory"));
+    }
+    fs::File::open(path).map(|_| ())
+}
+
+fn check_writable(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    // permission bits don't tell about read-only mounts or root, try it
+    let probe = dir.join(format!(".swiftlink-check-{}", std::process::id()));
+    fs::File::create(&probe)?;
+    fs::remove_file(&probe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_instance() {
+        let home_dir = std::env::temp_dir().join("swiftlink-layout-test");
+        let _ = fs::remove_dir_all(&home_dir);
+
+        let config = Config::builder()
+            .geoip_location("Country.mmdb")
+            .geoip_asn_location(home_dir.join("ASN.mmdb"))
+            .build()
+            .unwrap();
+
+        let checks = check_instance(&config, &home_dir);
+        assert_eq!(checks.len(), 3);
+        // created on the fly
+        assert!(checks[0].is_ok());
+        assert_eq!(checks[1].path, home_dir.join("Country.mmdb"));
+        assert!(!checks[1].is_ok());
+        assert!(verify(checks).is_err());
+
+        fs::write(home_dir.join("Country.mmdb"), b"").unwrap();
+        fs::write(home_dir.join("ASN.mmdb"), b"").unwrap();
+        assert!(verify(check_instance(&config, &home_dir)).is_ok());
+
+        let _ = fs::remove_dir_all(&home_dir);
+    }
+
+    #[test]
+    fn test_check_instance_geoip_url() {
+        let home_dir = std::env::temp_dir().join("swiftlink-layout-geoip-test");
+        let _ = fs::remove_dir_all(&home_dir);
+
+        let config = Config::builder()
+            .geoip_location("geoip/Country.mmdb")
+            .geoip_url("https://example.com/Country.mmdb")
+            .build()
+            .unwrap();
+        let checks = check_instance(&config, &home_dir);
+        assert_eq!(checks[1].path, home_dir.join("geoip"));
+        assert!(verify(checks).is_ok());
+
+        let _ = fs::remove_dir_all(&home_dir);
+    }
+
+    #[test]
+    fn test_check_log() {
+        let home_dir = std::env::temp_dir().join("swiftlink-layout-log-test");
+        let config = Config::builder().log_file("logs/swiftlink.log").build().unwrap();
+
+        let check = check_log(&config, &home_dir).unwrap();
+        assert_eq!(check.path, home_dir.join("logs"));
+        assert!(check.is_ok());
+
+        let config = Config::builder().log_files(0).build().unwrap();
+        assert!(check_log(&config, &home_dir).is_none());
+
+        let _ = fs::remove_dir_all(&home_dir);
+    }
+}
@@ -0,0 +1,59 @@
+#![allow(dead_code)]
+
+pub use config::Config;
+pub use instance::{Instance, InstanceBuilder, InstanceHandle, ShutdownTrigger, Stats};
+
+mod api;
+pub mod app;
+mod captive;
+pub mod config;
+pub mod connection_history;
+pub mod context;
+mod controller;
+pub mod decisions;
+pub mod dns_failures;
+pub mod doctor;
+mod download;
+mod error;
+pub mod fakeip;
+mod geoip_update;
+mod health;
+mod http_head;
+mod inbound;
+mod instance;
+pub mod layout;
+mod outbound;
+mod proxy_group;
+mod proxy_health;
+pub mod proxy_tree;
+mod route;
+mod rt;
+mod rule_provider;
+pub mod rule_stats;
+mod sni_proxy;
+pub mod talker_stats;
+
+/// The app name
+pub const NAME: &str = "swiftlink";
+
+/// Returns a version as specified in Cargo.toml
+pub fn version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
+/// Returns the default configuration directory, `~/.config/swiftlink`.
+pub fn default_home_dir() -> std::path::PathBuf {
+    dirs::home_dir()
+        .expect("Failed to get homedir")
+        .join(".config")
+        .join("swiftlink")
+}
+
+/// The message of a caught panic, `panic!` payloads are `&str` or `String`.
+pub(crate) fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
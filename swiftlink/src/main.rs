@@ -1,34 +1,35 @@
-#![allow(dead_code)]
-
-use std::{env, path::PathBuf};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+use anyhow::{bail, Context};
 use cli::*;
 
-use swiftlink_infra::log::{self, info};
-
-use crate::app::App;
+use swiftlink::{
+    app::{App, ShutdownReason},
+    connection_history::{self, ConnectionReport},
+    decisions,
+    dns_failures::{self, FailureReport},
+    doctor::{self, Problem, Severity},
+    fakeip, layout,
+    proxy_tree::{self, TreeReport},
+    rule_stats::{self, RuleReport},
+    talker_stats::{self, TopReport},
+    version, Config, NAME,
+};
+use swiftlink_dns::{probe_proxy, speedtest_proxy, ProxyLatency, ProxySpeed};
+use swiftlink_infra::{
+    log::{self, error, info},
+    net::ConnectOpts,
+    ruleset,
+};
 
 #[cfg(all(target_os = "linux", target_arch = "x86_64", target_env = "gnu"))]
 #[global_allocator]
 static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
-mod app;
 mod cli;
-mod config;
-mod context;
-mod error;
-// mod inbound;
-// mod outbound;
-// mod route;
-mod rt;
-
-/// The app name
-const NAME: &str = "swiftlink";
-
-/// Returns a version as specified in Cargo.toml
-pub fn version() -> &'static str {
-    env!("CARGO_PKG_VERSION")
-}
 
 fn main() {
     Cli::parse().run();
@@ -43,21 +44,389 @@ impl Cli {
             Commands::Run { conf, home_dir, .. } => {
                 // TODO: pid file
 
-                let home_dir = home_dir
-                    .unwrap_or(dirs::home_dir().expect("Failed to get homedir"))
-                    .join(".config")
-                    .join("swiftlink");
-
-                run_server(conf.unwrap_or(home_dir.join("swiftlink.toml")), home_dir);
+                let home_dir = resolve_home_dir(home_dir);
+                run_server(config_path(conf, &home_dir), home_dir);
+            }
+            Commands::ConvertRuleset {
+                behavior,
+                input,
+                output,
+            } => {
+                let converted = behavior
+                    .parse()
+                    .and_then(|behavior| ruleset::convert(&input, &output, behavior));
+                match converted {
+                    Ok(entries) => info!("converted {} entries from {:?} to {:?}", entries, input, output),
+                    Err(err) => {
+                        eprintln!("Failed to convert rule set {:?}: {}", input, err);
+                        std::process::exit(1);
+                    }
+                }
             }
+            Commands::PingProxy {
+                tag,
+                conf,
+                url,
+                timeout,
+            } => {
+                let conf = config_path(conf, &swiftlink::default_home_dir());
+                match ping_proxy(conf.as_deref(), &tag, &url, Duration::from_secs(timeout)) {
+                    Ok(latency) => println!(
+                        "{}: handshake {} ms, http {} ms, status {}",
+                        tag,
+                        latency.handshake.as_millis(),
+                        latency.http.as_millis(),
+                        latency.status
+                    ),
+                    Err(err) => {
+                        eprintln!("Failed to ping proxy {}: {:?}", tag, err);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            Commands::SpeedtestProxy {
+                tag,
+                conf,
+                url,
+                duration,
+            } => {
+                let conf = config_path(conf, &swiftlink::default_home_dir());
+                match speedtest(conf.as_deref(), &tag, url, duration) {
+                    Ok(speed) => println!(
+                        "{}: handshake {} ms, {} bytes in {} ms, {:.2} Mbit/s",
+                        tag,
+                        speed.handshake.as_millis(),
+                        speed.bytes,
+                        speed.duration.as_millis(),
+                        speed.bytes_per_sec() as f64 * 8.0 / 1_000_000.0
+                    ),
+                    Err(err) => {
+                        eprintln!("Failed to test the speed of proxy {}: {:?}", tag, err);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            Commands::Upgrade { pid } => {
+                if let Err(err) = request_upgrade(pid) {
+                    eprintln!("Failed to upgrade swiftlink {}: {}", pid, err);
+                    std::process::exit(1);
+                }
+            }
+            Commands::Replay { decisions, conf } => {
+                let conf = config_path(conf, &swiftlink::default_home_dir());
+                match replay(&decisions, conf.as_deref()) {
+                    Ok(true) => {}
+                    Ok(false) => std::process::exit(1),
+                    Err(err) => {
+                        eprintln!("{:?}", err);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            Commands::Doctor { conf, home_dir } => {
+                let home_dir = resolve_home_dir(home_dir);
+                let conf = config_path(conf, &home_dir);
+                match run_doctor(conf.as_deref(), &home_dir) {
+                    Ok(true) => {}
+                    Ok(false) => std::process::exit(1),
+                    Err(err) => {
+                        eprintln!("{:?}", err);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            Commands::Config { command } => match command {
+                ConfigCommands::Dump { conf } => {
+                    let conf = config_path(conf, &swiftlink::default_home_dir());
+                    match load_config(conf.as_deref()).and_then(|c| c.dump()) {
+                        Ok(dumped) => print!("{}", dumped),
+                        Err(err) => {
+                            eprintln!("{:?}", err);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                ConfigCommands::Check { conf, home_dir } => {
+                    let home_dir = resolve_home_dir(home_dir);
+                    let conf = config_path(conf, &home_dir);
+                    match check_layout(conf.as_deref(), &home_dir) {
+                        Ok(true) => {}
+                        Ok(false) => std::process::exit(1),
+                        Err(err) => {
+                            eprintln!("{:?}", err);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+            },
+            Commands::Proxies { command } => match command {
+                ProxiesCommands::Tree { conf } => {
+                    let conf = config_path(conf, &swiftlink::default_home_dir());
+                    match load_config(conf.as_deref()).and_then(|c| proxy_tree::fetch(&c)) {
+                        Ok(tree) => print!("{}", TreeReport(&tree)),
+                        Err(err) => {
+                            eprintln!("{:?}", err);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+            },
+            Commands::Rules { command } => match command {
+                RulesCommands::Stats { conf, top } => {
+                    let conf = config_path(conf, &swiftlink::default_home_dir());
+                    match load_config(conf.as_deref()).and_then(|c| rule_stats::fetch(&c)) {
+                        Ok(hits) => print!("{}", RuleReport::new(&hits, top)),
+                        Err(err) => {
+                            eprintln!("{:?}", err);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+            },
+            Commands::Stats { command } => match command {
+                StatsCommands::Top { conf, window, top } => {
+                    let conf = config_path(conf, &swiftlink::default_home_dir());
+                    match load_config(conf.as_deref()).and_then(|c| talker_stats::fetch(&c, window, top)) {
+                        Ok(talkers) => print!("{}", TopReport(&talkers)),
+                        Err(err) => {
+                            eprintln!("{:?}", err);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+            },
+            Commands::Dns { command } => match command {
+                DnsCommands::Failures { conf, name, limit } => {
+                    let conf = config_path(conf, &swiftlink::default_home_dir());
+                    match load_config(conf.as_deref()).and_then(|c| dns_failures::fetch(&c, name.as_deref(), limit)) {
+                        Ok(failures) => print!("{}", FailureReport::new(&failures)),
+                        Err(err) => {
+                            eprintln!("{:?}", err);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+            },
+            Commands::Connections { command } => match command {
+                ConnectionsCommands::Closed {
+                    conf,
+                    reason,
+                    destination,
+                    limit,
+                } => {
+                    let conf = config_path(conf, &swiftlink::default_home_dir());
+                    let fetch =
+                        |c: Config| connection_history::fetch(&c, reason.as_deref(), destination.as_deref(), limit);
+                    match load_config(conf.as_deref()).and_then(fetch) {
+                        Ok(connections) => print!("{}", ConnectionReport::new(&connections)),
+                        Err(err) => {
+                            eprintln!("{:?}", err);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+            },
+            Commands::Fakeip { command } => match command {
+                FakeipCommands::List { conf, offset, limit } => {
+                    let conf = config_path(conf, &swiftlink::default_home_dir());
+                    match load_config(conf.as_deref()).and_then(|c| fakeip::list(&c, offset, limit)) {
+                        Ok((mappings, total)) => {
+                            for mapping in mappings.iter() {
+                                println!("{}", mapping);
+                            }
+                            println!("{} of {} fake ips", mappings.len(), total);
+                        }
+                        Err(err) => {
+                            eprintln!("{:?}", err);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                FakeipCommands::Lookup { query, conf } => {
+                    let conf = config_path(conf, &swiftlink::default_home_dir());
+                    match load_config(conf.as_deref()).and_then(|c| fakeip::lookup(&c, &query)) {
+                        Ok(mappings) if mappings.is_empty() => {
+                            eprintln!("no fake ip of {}", query);
+                            std::process::exit(1);
+                        }
+                        Ok(mappings) => {
+                            for mapping in mappings.iter() {
+                                println!("{}", mapping);
+                            }
+                        }
+                        Err(err) => {
+                            eprintln!("{:?}", err);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// The `--home-dir` of the user, the configuration lives in its `.config/swiftlink`.
+fn resolve_home_dir(home_dir: Option<PathBuf>) -> PathBuf {
+    home_dir
+        .map(|d| d.join(".config").join("swiftlink"))
+        .unwrap_or_else(swiftlink::default_home_dir)
+}
+
+/// The configuration file, `None` if none is given and the default one doesn't exist.
+fn config_path(conf: Option<PathBuf>, home_dir: &Path) -> Option<PathBuf> {
+    conf.or_else(|| Some(home_dir.join("swiftlink.toml")).filter(|path| path.exists()))
+}
+
+/// Loads the configuration file, or the `SWIFTLINK_*` environment variables without one.
+fn load_config(conf: Option<&Path>) -> anyhow::Result<Config> {
+    match conf {
+        Some(path) => {
+            Config::load_from_file(path).with_context(|| format!("Error while loading config file: {:?}", path))
+        }
+        None => Config::load_from_env(),
+    }
+}
+
+/// Prints the access checks of the configured paths, `false` if any failed.
+fn check_layout(conf: Option<&Path>, home_dir: &Path) -> anyhow::Result<bool> {
+    let config = load_config(conf)?;
+
+    let checks = layout::check_instance(&config, home_dir)
+        .into_iter()
+        .chain(layout::check_log(&config, home_dir))
+        .collect::<Vec<_>>();
+    for check in checks.iter() {
+        println!("{} {}", if check.is_ok() { "ok  " } else { "FAIL" }, check);
+    }
+
+    Ok(checks.iter().all(|check| check.is_ok()))
+}
+
+/// Prints the problems found by the self-test, `false` if there are errors.
+fn run_doctor(conf: Option<&Path>, home_dir: &Path) -> anyhow::Result<bool> {
+    let problems = match load_config(conf) {
+        Ok(config) => {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+            runtime.block_on(doctor::diagnose(&config, home_dir))
         }
+        Err(err) => vec![Problem::invalid_config(&err)],
+    };
+
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    if problems.is_empty() {
+        println!("no problems found");
+    }
+
+    Ok(problems.iter().all(|problem| problem.severity != Severity::Error))
+}
+
+/// Prints the recorded decisions the configuration routes differently, `false` if there are any.
+fn replay(decisions: &Path, conf: Option<&Path>) -> anyhow::Result<bool> {
+    let config = load_config(conf)?;
+    let recorded = decisions::read(decisions)?;
+
+    let diffs = decisions::replay(&recorded, &config);
+    for diff in diffs.iter() {
+        println!("{}", diff);
+    }
+    let changed = diffs.iter().map(|diff| diff.connections).sum::<usize>();
+    println!(
+        "{} of {} recorded connections routed differently",
+        changed,
+        recorded.len()
+    );
+
+    Ok(diffs.is_empty())
+}
+
+fn ping_proxy(conf: Option<&Path>, tag: &str, url: &str, timeout: Duration) -> anyhow::Result<ProxyLatency> {
+    let config = load_config(conf)?;
+    let dns = config.dns();
+    let Some(proxy) = dns.proxies().get(tag) else {
+        bail!("proxy {} is not configured", tag);
+    };
+    let url = url.parse().with_context(|| format!("invalid probe url {}", url))?;
+
+    let connect_opts = ConnectOpts {
+        bind_interface: config.interface_name().map(|s| s.to_owned()),
+        ..Default::default()
+    };
+
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    let latency = runtime.block_on(tokio::time::timeout(timeout, probe_proxy(proxy, &url, &connect_opts)))??;
+    Ok(latency)
+}
+
+fn speedtest(conf: Option<&Path>, tag: &str, url: Option<String>, duration: Option<u64>) -> anyhow::Result<ProxySpeed> {
+    let config = load_config(conf)?;
+    let dns = config.dns();
+    let Some(proxy) = dns.proxies().get(tag) else {
+        bail!("proxy {} is not configured", tag);
+    };
+    let url = url.as_deref().unwrap_or(config.speedtest_url());
+    let url = url.parse().with_context(|| format!("invalid speed test url {}", url))?;
+    let duration = duration.map_or(config.speedtest_duration(), Duration::from_secs);
+
+    let connect_opts = ConnectOpts {
+        bind_interface: config.interface_name().map(|s| s.to_owned()),
+        ..Default::default()
+    };
+
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    // the download stops after `duration`, only connecting may hang
+    let speed = runtime.block_on(tokio::time::timeout(
+        duration + Duration::from_secs(10),
+        speedtest_proxy(proxy, &url, duration, &connect_opts),
+    ))??;
+    Ok(speed)
+}
+
+/// Asks the running process to hand its listeners over to a new process, see `App::bootstrap`.
+#[cfg(unix)]
+fn request_upgrade(pid: i32) -> std::io::Result<()> {
+    if unsafe { libc::kill(pid, libc::SIGUSR2) } != 0 {
+        return Err(std::io::Error::last_os_error());
     }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn request_upgrade(_pid: i32) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "upgrade is only supported on unix",
+    ))
 }
 
-fn run_server(conf: PathBuf, home_dir: PathBuf) {
-    App::new(conf, home_dir)
-        .expect("Failed to create swiftlink app")
-        .bootstrap();
+/// Runs the server and exits with the code of the shutdown reason.
+fn run_server(conf: Option<PathBuf>, home_dir: PathBuf) -> ! {
+    let reason = match App::new(conf, home_dir) {
+        Ok(app) => app.bootstrap(),
+        Err(err) => ShutdownReason::from_start_error(err),
+    };
+
+    let exit_code = reason.exit_code();
+    if exit_code == 0 {
+        info!(
+            reason = reason.kind(),
+            exit_code,
+            "{} {} shutdown, {}",
+            NAME,
+            version(),
+            reason
+        );
+    } else {
+        error!(
+            reason = reason.kind(),
+            exit_code,
+            "{} {} shutdown, {}",
+            NAME,
+            version(),
+            reason
+        );
+    }
 
-    info!("{} {} shutdown", NAME, version());
+    std::process::exit(exit_code)
 }
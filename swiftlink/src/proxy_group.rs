@@ -0,0 +1,292 @@
+//! Groups of the proxies of `dns.proxy_servers`, each picks a member by one of the strategies of
+//! [`group`](swiftlink_infra::group):
+//!
+//! ```toml
+//! [proxy_groups.manual]
+//! type = "select"
+//! proxies = ["HK", "JP"]
+//!
+//! [proxy_groups.auto]
+//! type = "url-test"
+//! proxies = ["HK", "JP"]
+//! tolerance = 50
+//!
+//! [proxy_groups.balanced]
+//! type = "load-balance"
+//! proxies = ["HK", "JP"]
+//! strategy = "round-robin"
+//! weights = { HK = 70, JP = 30 }
+//! sticky = "source-ip"
+//! ```
+//!
+//! The health checks of `[health_check]` feed the groups of each checked proxy, after each round
+//! the `url-test` and `adaptive` groups settle on a member. A speed test of a group tests its
+//! member, the throughput counts into the scores of `adaptive` groups. The member of a `select`
+//! group is chosen with `PUT /proxies/{group}` of the external controller. With `sticky` a
+//! `load-balance` group sends the connections of a client through the member it picked for the
+//! first one, see [`StickySessions`].
+//!
+//! The choice of a `select` group and the member an `url-test` or `adaptive` group settled on are
+//! kept in the cache file, a group starts with them after a restart. A rule targeting a group
+//! sends the connection through the member the group is on, or the one a `load-balance` group
+//! picks for its host.
+
+use std::{net::IpAddr, time::Duration};
+
+use swiftlink_infra::group::{
+    selection, AdaptiveParams, AdaptiveSelector, FallbackSelector, LoadBalancer, Selector, StickySessions,
+    UrlTestSelector,
+};
+
+use crate::config::{Config, ProxyGroupConfig, ProxyGroupType};
+
+enum Strategy {
+    Select(Selector),
+    Fallback(FallbackSelector),
+    UrlTest(UrlTestSelector),
+    LoadBalance(LoadBalancer),
+    Adaptive(AdaptiveSelector),
+}
+
+/// A group of proxies and how it picks one.
+pub(crate) struct ProxyGroup {
+    name: String,
+    tp: ProxyGroupType,
+    members: Vec<String>,
+    strategy: Strategy,
+    /// the members of the clients of a sticky `load-balance` group
+    sessions: Option<StickySessions>,
+}
+
+impl ProxyGroup {
+    /// The group `name` of `config`, with the member it had before a restart.
+    fn new(name: &str, config: &ProxyGroupConfig) -> Self {
+        let members = config.proxies.clone();
+        let strategy = match config.tp {
+            ProxyGroupType::Select => Strategy::Select(Selector::new(name, members.clone())),
+            ProxyGroupType::Fallback => Strategy::Fallback(FallbackSelector::new(members.clone())),
+            ProxyGroupType::UrlTest => {
+                let selector = UrlTestSelector::new(members.clone(), config.tolerance());
+                if let Some(member) = selection::recall(name) {
+                    selector.restore(&member);
+                }
+                Strategy::UrlTest(selector)
+            }
+            ProxyGroupType::LoadBalance => Strategy::LoadBalance(LoadBalancer::weighted(
+                config.weighted_proxies(),
+                config.strategy.unwrap_or_default(),
+            )),
+            ProxyGroupType::Adaptive => {
+                let selector = AdaptiveSelector::new(members.clone(), AdaptiveParams::default());
+                if let Some(member) = selection::recall(name) {
+                    selector.restore(&member);
+                }
+                Strategy::Adaptive(selector)
+            }
+        };
+        Self {
+            name: name.to_owned(),
+            tp: config.tp,
+            members,
+            strategy,
+            sessions: config.sticky.map(|_| StickySessions::default()),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub(crate) fn tp(&self) -> ProxyGroupType {
+        self.tp
+    }
+
+    /// The proxies of the group, in the configured order.
+    #[inline]
+    pub(crate) fn members(&self) -> &[String] {
+        &self.members
+    }
+
+    /// The member the group is on, `None` for `load-balance` groups, which pick one per host.
+    pub(crate) fn now(&self) -> Option<String> {
+        match &self.strategy {
+            Strategy::Select(selector) => selector.selected(),
+            Strategy::Fallback(selector) => selector.select(),
+            Strategy::UrlTest(selector) => selector.select(),
+            Strategy::LoadBalance(_) => None,
+            Strategy::Adaptive(selector) => selector.current().or_else(|| selector.select()),
+        }
+    }
+
+    /// The member for a connection to `host` from `source`, a sticky `load-balance` group keeps
+    /// the member of the client.
+    pub(crate) fn member(&self, host: &str, source: Option<IpAddr>) -> Option<String> {
+        match (&self.strategy, &self.sessions, source) {
+            (Strategy::LoadBalance(balancer), Some(sessions), Some(source)) => sessions.member(
+                source.to_canonical(),
+                |member| balancer.is_alive(member),
+                || balancer.select(host),
+            ),
+            (Strategy::LoadBalance(balancer), ..) => balancer.select(host),
+            _ => self.now(),
+        }
+    }
+
+    /// Chooses `member` in a `select` group, kept across restarts. Returns `false` if the group
+    /// isn't a `select` one or `member` isn't one of its proxies.
+    pub(crate) fn select(&self, member: &str) -> bool {
+        match &self.strategy {
+            Strategy::Select(selector) => selector.select(member),
+            _ => false,
+        }
+    }
+
+    fn record_check(&self, proxy: &str, delay: Option<Duration>) {
+        match &self.strategy {
+            Strategy::Select(_) => {}
+            Strategy::Fallback(selector) => selector.record_check(proxy, delay),
+            Strategy::UrlTest(selector) => selector.record_check(proxy, delay),
+            Strategy::LoadBalance(balancer) => balancer.record_check(proxy, delay),
+            Strategy::Adaptive(selector) => selector.record_check(proxy, delay),
+        }
+    }
+
+    /// Picks the member of an `url-test` or `adaptive` group by the latest measurements, and
+    /// keeps it across restarts.
+    fn settle(&self) {
+        let member = match &self.strategy {
+            Strategy::UrlTest(selector) => selector.select(),
+            Strategy::Adaptive(selector) => selector.select(),
+            _ => return,
+        };
+        if let Some(member) = member {
+            selection::remember(&self.name, &member);
+        }
+    }
+}
+
+/// The proxy groups of the configuration, by name.
+#[derive(Default)]
+pub(crate) struct ProxyGroups {
+    groups: Vec<ProxyGroup>,
+}
+
+impl ProxyGroups {
+    pub(crate) fn new(config: &Config) -> Self {
+        Self {
+            groups: config
+                .proxy_groups()
+                .iter()
+                .map(|(name, group)| ProxyGroup::new(name, group))
+                .collect(),
+        }
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&ProxyGroup> {
+        self.groups.iter().find(|group| group.name == name)
+    }
+
+    /// The groups, ordered by name.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &ProxyGroup> {
+        self.groups.iter()
+    }
+
+    /// Records a health check of `proxy` in its groups, `None` if it failed.
+    pub(crate) fn record_check(&self, proxy: &str, delay: Option<Duration>) {
+        for group in self
+            .groups
+            .iter()
+            .filter(|group| group.members.iter().any(|m| m == proxy))
+        {
+            group.record_check(proxy, delay);
+        }
+    }
+
+    /// Lets the groups settle on a member, once all proxies were checked.
+    pub(crate) fn settle(&self) {
+        self.groups.iter().for_each(ProxyGroup::settle);
+    }
+
+    /// Records the throughput of a speed test of `proxy` in its `adaptive` groups.
+    pub(crate) fn record_speed(&self, proxy: &str, bytes_per_sec: u64) {
+        for group in self.groups.iter() {
+            if let Strategy::Adaptive(selector) = &group.strategy {
+                selector.record_throughput(proxy, bytes_per_sec);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use swiftlink_infra::cachefile::CacheFile;
+
+    use super::*;
+
+    fn ms(ms: u64) -> Option<Duration> {
+        Some(Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn test_proxy_groups() {
+        CacheFile::with_cache_dir(std::env::temp_dir().join("swiftlink").join("cachedb")).unwrap();
+        let config = Config::load(
+            r#"
+            [proxy_groups.group-test-select]
+            type = "select"
+            proxies = ["hk", "jp"]
+
+            [proxy_groups.group-test-fallback]
+            type = "fallback"
+            proxies = ["hk", "jp"]
+
+            [proxy_groups.group-test-auto]
+            type = "url-test"
+            proxies = ["hk", "jp"]
+
+            [proxy_groups.group-test-balanced]
+            type = "load-balance"
+            proxies = ["hk", "jp"]
+            strategy = "round-robin"
+            weights = { hk = 3 }
+            "#,
+        )
+        .unwrap();
+        let groups = ProxyGroups::new(&config);
+        let names: Vec<_> = groups.iter().map(ProxyGroup::name).collect();
+        assert_eq!(
+            names,
+            [
+                "group-test-auto",
+                "group-test-balanced",
+                "group-test-fallback",
+                "group-test-select"
+            ]
+        );
+
+        let select = groups.get("group-test-select").unwrap();
+        assert!(select.select("jp"));
+        assert!(!select.select("sg"));
+        assert!(!groups.get("group-test-auto").unwrap().select("jp"));
+
+        groups.record_check("hk", None);
+        groups.record_check("jp", ms(100));
+        groups.settle();
+        assert_eq!(groups.get("group-test-fallback").unwrap().now().as_deref(), Some("jp"));
+        assert_eq!(groups.get("group-test-auto").unwrap().now().as_deref(), Some("jp"));
+        let balanced = groups.get("group-test-balanced").unwrap();
+        assert_eq!(balanced.now(), None);
+        assert_eq!(balanced.member("example.com", None).as_deref(), Some("jp"));
+
+        // restarted, the proxies are alive until checked
+        let groups = ProxyGroups::new(&config);
+        assert_eq!(groups.get("group-test-select").unwrap().now().as_deref(), Some("jp"));
+        assert_eq!(groups.get("group-test-auto").unwrap().now().as_deref(), Some("jp"));
+        assert_eq!(groups.get("group-test-fallback").unwrap().now().as_deref(), Some("hk"));
+        let balanced = groups.get("group-test-balanced").unwrap();
+        let members: Vec<_> = (0..4).filter_map(|_| balanced.member("example.com", None)).collect();
+        assert_eq!(members.iter().filter(|member| *member == "hk").count(), 3);
+    }
+}
@@ -0,0 +1,276 @@
+//! Health checks of the proxies of `dns.proxy_servers`.
+//!
+//! With `[health_check]` set, each proxy is sent a `HEAD` request of the check url through a
+//! tunnel on every interval. The delay, handshake and request together, goes to the delay history
+//! of the proxy, `0` if the check failed. A proxy going down or coming back is logged and
+//! published as a `proxy_health_changed` event, proxies count as alive until a check failed.
+//! The histories are saved to the cache file and restored on the next start, a proxy which was
+//! down before counts as down until it passes a check. The checks also feed the groups of the
+//! proxies, see [`proxy_group`](crate::proxy_group).
+//!
+//! ```toml
+//! [health_check]
+//! url = "http://www.gstatic.com/generate_204"
+//! interval = 300
+//! ```
+//!
+//! Speed tests download the `speedtest_url` through a proxy on request, e.g. of the API, for at
+//! most `speedtest_duration`, the result is kept next to the delay history. The speed test of a
+//! group tests its member.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context};
+use futures::future::join_all;
+
+use swiftlink_dns::{probe_proxy, speedtest_proxy, ProxyConfig};
+use swiftlink_infra::{
+    event::{Event, EventBus},
+    log::*,
+    net::ConnectOpts,
+    proxy_stats::{ProxyStatsMap, SpeedRecord},
+};
+
+use crate::{captive::CheckUrl, proxy_group::ProxyGroups, Config};
+
+/// A check which didn't finish by then failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connecting to a proxy and opening the tunnel of a speed test must not take longer.
+const SPEEDTEST_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Checks the proxies and records the results.
+pub(crate) struct HealthChecker {
+    proxies: Arc<HashMap<String, ProxyConfig>>,
+    /// plain http url, validated by the configuration
+    url: String,
+    connect_opts: ConnectOpts,
+    stats: Arc<ProxyStatsMap>,
+    groups: Arc<ProxyGroups>,
+    events: Arc<EventBus>,
+}
+
+impl HealthChecker {
+    pub(crate) fn new(
+        proxies: Arc<HashMap<String, ProxyConfig>>,
+        url: &str,
+        connect_opts: ConnectOpts,
+        stats: Arc<ProxyStatsMap>,
+        groups: Arc<ProxyGroups>,
+        events: Arc<EventBus>,
+    ) -> Self {
+        Self {
+            proxies,
+            url: url.to_owned(),
+            connect_opts,
+            stats,
+            groups,
+            events,
+        }
+    }
+
+    /// Checks all proxies each `interval`, the first time right away, until the task is aborted.
+    pub(crate) async fn run(self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            self.check_all().await;
+        }
+    }
+
+    /// Checks all proxies at the same time, then lets their groups settle.
+    async fn check_all(&self) {
+        join_all(self.proxies.iter().map(|(name, proxy)| self.check(name, proxy))).await;
+        self.groups.settle();
+    }
+
+    /// Checks `proxy` and records the delay, `None` if the check failed.
+    async fn check(&self, name: &str, proxy: &ProxyConfig) -> Option<Duration> {
+        let url = self.url.parse().ok()?;
+        let delay = match tokio::time::timeout(CHECK_TIMEOUT, probe_proxy(proxy, &url, &self.connect_opts)).await {
+            Ok(Ok(latency)) => Some(latency.handshake + latency.http),
+            Ok(Err(err)) => {
+                debug!("health check of proxy {} failed, {}", name, err);
+                None
+            }
+            Err(_) => {
+                debug!("health check of proxy {} timed out", name);
+                None
+            }
+        };
+
+        let stats = self.stats.proxy(name);
+        let was_alive = stats.history.last().is_none_or(|record| record.is_alive());
+        stats.history.record(delay);
+        self.stats.persist(name);
+        self.groups.record_check(name, delay);
+        match delay {
+            Some(delay) if !was_alive => info!("proxy {} is back, delay {} ms", name, delay.as_millis()),
+            None if was_alive => warn!("proxy {} is down, its health check failed", name),
+            _ => return delay,
+        }
+        self.events.publish(Event::proxy_health_changed(name, delay));
+        delay
+    }
+}
+
+/// Runs the speed tests of the proxies and records the results.
+pub(crate) struct SpeedTester {
+    proxies: Arc<HashMap<String, ProxyConfig>>,
+    url: String,
+    duration: Duration,
+    connect_opts: ConnectOpts,
+    stats: Arc<ProxyStatsMap>,
+    groups: Arc<ProxyGroups>,
+}
+
+impl SpeedTester {
+    /// Tests the proxies of `dns.proxy_servers` and of `groups` with the `speedtest_url` of
+    /// `config`.
+    pub(crate) fn new(
+        config: &Config,
+        connect_opts: ConnectOpts,
+        stats: Arc<ProxyStatsMap>,
+        groups: Arc<ProxyGroups>,
+    ) -> Self {
+        Self {
+            proxies: config.dns().proxies().clone(),
+            url: config.speedtest_url().to_owned(),
+            duration: config.speedtest_duration(),
+            connect_opts,
+            stats,
+            groups,
+        }
+    }
+
+    /// Whether `name` is a proxy or a group which can be tested.
+    pub(crate) fn has_proxy(&self, name: &str) -> bool {
+        self.proxies.contains_key(name) || self.groups.get(name).is_some()
+    }
+
+    /// Downloads the url through the proxy `name`, or the member of the group `name`, and records
+    /// the result in the stats of the proxy.
+    pub(crate) async fn run(&self, name: &str) -> anyhow::Result<SpeedRecord> {
+        let member;
+        let name = match self.groups.get(name) {
+            Some(group) => {
+                let host = CheckUrl::parse(&self.url)
+                    .map(|url| url.host().to_owned())
+                    .unwrap_or_default();
+                member = group
+                    .member(&host, None)
+                    .with_context(|| format!("proxy group {} has no proxy", name))?;
+                &member
+            }
+            None => name,
+        };
+        let Some(proxy) = self.proxies.get(name) else {
+            bail!("proxy {} is not configured", name);
+        };
+        let url = self
+            .url
+            .parse()
+            .with_context(|| format!("invalid speed test url {}", self.url))?;
+
+        let speed = tokio::time::timeout(
+            self.duration + SPEEDTEST_HANDSHAKE_TIMEOUT,
+            speedtest_proxy(proxy, &url, self.duration, &self.connect_opts),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("speed test of proxy {} timed out", name))??;
+
+        let record = self.stats.proxy(name).record_speed(speed.bytes, speed.duration);
+        self.groups.record_speed(name, record.bytes_per_sec);
+        info!(
+            "speed test of proxy {}: {} bytes in {} ms, {} bytes/s",
+            name, record.bytes, record.duration_ms, record.bytes_per_sec
+        );
+        Ok(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use swiftlink_dns::ProxyProtocol;
+
+    use super::*;
+
+    /// An http proxy answering `CONNECT` and the probe on the same connection.
+    async fn http_proxy() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    _ = stream.read(&mut buf).await;
+                    _ = stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await;
+                    _ = stream.read(&mut buf).await;
+                    _ = stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_health_check() {
+        let alive = ProxyConfig::new(ProxyProtocol::Http, http_proxy().await);
+        // nothing listens there once the listener is dropped
+        let dead_addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let dead = ProxyConfig::new(ProxyProtocol::Http, dead_addr);
+        let proxies = HashMap::from([("alive".to_owned(), alive.clone()), ("dead".to_owned(), dead)]);
+
+        let stats = Arc::new(ProxyStatsMap::default());
+        let config = Config::load(
+            r#"
+            [proxy_groups.fallback]
+            type = "fallback"
+            proxies = ["dead", "alive"]
+            "#,
+        )
+        .unwrap();
+        let groups = Arc::new(ProxyGroups::new(&config));
+        let events = Arc::new(EventBus::default());
+        let mut rx = events.subscribe();
+        let checker = HealthChecker::new(
+            Arc::new(proxies),
+            "http://www.example.com/generate_204",
+            ConnectOpts::default(),
+            stats.clone(),
+            groups.clone(),
+            events,
+        );
+
+        checker.check_all().await;
+        assert!(stats.get("alive").unwrap().history.is_alive());
+        assert!(!stats.get("dead").unwrap().history.is_alive());
+        assert_eq!(groups.get("fallback").unwrap().now().as_deref(), Some("alive"));
+        // the dead proxy went down, the alive one is as it's assumed to be
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            Event::ProxyHealthChanged { proxy, alive: false, delay_ms: None } if proxy == "dead"
+        ));
+        assert!(rx.try_recv().is_err());
+
+        checker.check_all().await;
+        assert!(rx.try_recv().is_err());
+        assert_eq!(stats.get("dead").unwrap().history.history().len(), 2);
+
+        // the dead one is back
+        assert!(checker.check("dead", &alive).await.is_some());
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            Event::ProxyHealthChanged { proxy, alive: true, .. } if proxy == "dead"
+        ));
+    }
+}
@@ -0,0 +1,154 @@
+//! `swiftlink proxies tree`, the proxy groups with the proxies they resolve to.
+//!
+//! The tree is read from `/providers/proxies` of the external controller, which must be enabled.
+//! All proxies come from `dns.proxy_servers`, the `default` provider as Clash names the proxies of
+//! the configuration, and the members of a group are proxies, so the tree is one level deep.
+
+use std::{collections::HashMap, fmt};
+
+use serde::{Deserialize, Serialize};
+use swiftlink_dns::{ProxyConfig, ProxyProtocol};
+
+use crate::{controller, proxy_group::ProxyGroups, Config};
+
+/// A proxy, or a group with its members.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ProxyNode {
+    pub name: String,
+    /// the protocol of a proxy, `socks5`, `http`, `https` or `vless`, the type of a group
+    #[serde(rename = "type")]
+    pub tp: String,
+    /// the member a group is on, `None` for proxies and `load-balance` groups
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub now: Option<String>,
+    /// the members of a group, in the configured order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<ProxyNode>,
+}
+
+/// The groups with their members, then the proxies, each by name.
+pub(crate) fn tree(proxies: &HashMap<String, ProxyConfig>, groups: &ProxyGroups) -> Vec<ProxyNode> {
+    let proxy = |name: &str| ProxyNode {
+        name: name.to_owned(),
+        tp: proxies.get(name).map_or("-", proxy_type).to_owned(),
+        now: None,
+        members: Vec::new(),
+    };
+    let mut names: Vec<_> = proxies.keys().collect();
+    names.sort_unstable();
+
+    groups
+        .iter()
+        .map(|group| ProxyNode {
+            name: group.name().to_owned(),
+            tp: group.tp().as_str().to_owned(),
+            now: group.now(),
+            members: group.members().iter().map(|member| proxy(member)).collect(),
+        })
+        .chain(names.into_iter().map(|name| proxy(name)))
+        .collect()
+}
+
+fn proxy_type(proxy: &ProxyConfig) -> &'static str {
+    match (proxy.proto, &proxy.tls) {
+        (ProxyProtocol::Socks5, _) => "socks5",
+        (ProxyProtocol::Http, None) => "http",
+        (ProxyProtocol::Http, Some(_)) => "https",
+        (ProxyProtocol::Vless, _) => "vless",
+    }
+}
+
+#[derive(Deserialize)]
+struct Providers {
+    providers: HashMap<String, Provider>,
+}
+
+#[derive(Deserialize)]
+struct Provider {
+    proxies: Vec<ProxyNode>,
+}
+
+/// Reads the tree of the proxies from the external controller of `config`.
+pub fn fetch(config: &Config) -> anyhow::Result<Vec<ProxyNode>> {
+    let providers: Providers = controller::get(config, "/providers/proxies")?;
+    Ok(providers
+        .providers
+        .into_values()
+        .flat_map(|provider| provider.proxies)
+        .collect())
+}
+
+/// The groups with their members below, the one a group is on marked with `*`, then the proxies.
+pub struct TreeReport<'a>(pub &'a [ProxyNode]);
+
+impl fmt::Display for TreeReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return writeln!(f, "no proxies");
+        }
+        for node in self.0 {
+            writeln!(f, "{} ({})", node.name, node.tp)?;
+            for (i, member) in node.members.iter().enumerate() {
+                let branch = if i + 1 < node.members.len() { "├─" } else { "└─" };
+                write!(f, "{} {} ({})", branch, member.name, member.tp)?;
+                if node.now.as_ref() == Some(&member.name) {
+                    f.write_str(" *")?;
+                }
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use swiftlink_infra::cachefile::CacheFile;
+
+    use crate::{api::Api, config::ControllerCors, controller::TestController};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fetch() {
+        CacheFile::with_cache_dir(std::env::temp_dir().join("swiftlink").join("cachedb")).unwrap();
+        let config = Config::load(
+            r#"
+            [dns.proxy_servers]
+            hk = "socks5://127.0.0.1:1080"
+            jp = "https://127.0.0.1:8443"
+
+            [proxy_groups.tree-test-manual]
+            type = "select"
+            proxies = ["hk", "jp"]
+
+            [proxy_groups.tree-test-balanced]
+            type = "load-balance"
+            proxies = ["jp"]
+            "#,
+        )
+        .unwrap();
+        let groups = Arc::new(ProxyGroups::new(&config));
+        assert!(groups.get("tree-test-manual").unwrap().select("jp"));
+        let api = Api::new(None, ControllerCors::default())
+            .with_proxy_servers(config.dns().proxies().clone())
+            .with_proxy_groups(groups);
+        let controller = TestController::start(api).await;
+
+        let tree = controller.request(None, |config| fetch(config).unwrap()).await;
+        let names: Vec<_> = tree.iter().map(|node| node.name.as_str()).collect();
+        assert_eq!(names, ["tree-test-balanced", "tree-test-manual", "hk", "jp"]);
+        assert_eq!(tree[1].now.as_deref(), Some("jp"));
+        assert_eq!(tree[2].tp, "socks5");
+        assert_eq!(
+            TreeReport(&tree).to_string(),
+            "tree-test-balanced (load-balance)\n└─ jp (https)\n\
+             tree-test-manual (select)\n├─ hk (socks5)\n└─ jp (https) *\n\
+             hk (socks5)\n\
+             jp (https)\n"
+        );
+        assert_eq!(TreeReport(&[]).to_string(), "no proxies\n");
+    }
+}
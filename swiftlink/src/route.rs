@@ -0,0 +1,831 @@
+//! The routing rules, which pick the outbound of a connection.
+//!
+//! Rules are matched in their order, the target of the first matching one carries the
+//! connection:
+//!
+//! ```toml
+//! rules = [
+//!     "DOMAIN,www.example.com,PROXY",
+//!     # the domain and its subdomains
+//!     "DOMAIN-SUFFIX,google.com,PROXY",
+//!     "DOMAIN-KEYWORD,ads,REJECT",
+//!     "IP-CIDR,10.0.0.0/8,DIRECT,no-resolve",
+//!     "IP-CIDR6,fd00::/8,DIRECT,no-resolve",
+//!     "GEOIP,CN,DIRECT",
+//!     # a category of domain-list-community, optionally of an attribute, e.g. `google@cn`
+//!     "GEOSITE,google,PROXY",
+//!     "IP-ASN,13335,PROXY",
+//!     # a port or a range of ports
+//!     "DST-PORT,6881-6889,DIRECT",
+//!     "SRC-IP-CIDR,192.168.1.100/32,DIRECT",
+//!     "INBOUND,socks-lan,PROXY",
+//!     # the executable of the local process which opened the connection, by name or path
+//!     "PROCESS-NAME,curl,DIRECT",
+//!     "PROCESS-PATH,/usr/bin/transmission-daemon,DIRECT",
+//!     # a rule set of `[rule_providers]`
+//!     "RULE-SET,ads,REJECT",
+//!     "MATCH,PROXY",
+//! ]
+//! ```
+//!
+//! The IP rules of a destination domain need its address, the domain is resolved once such a rule
+//! is reached, see [`Router::needs_ip`]. With `no-resolve` they match destination addresses only
+//! and skip domains, so do the IP entries of a `RULE-SET` with `no-resolve`. A rule whose target
+//! excludes the destination by `proxy_excludes` doesn't match, the next rule is tried.
+//!
+//! The rules are compiled when the configuration is loaded, an unknown type or an invalid payload
+//! fails the loading.
+//!
+//! The process of a connection is looked up only if a process rule is configured, see
+//! [`Router::needs_process`]. Only connections of this host have one, and only on Linux so far.
+
+use std::{collections::HashMap, net::IpAddr, path::PathBuf, sync::Arc};
+
+use ipnet::IpNet;
+use swiftlink_infra::{
+    geoip::{self, GeoIpDb},
+    geosite::GeoSite,
+    log::*,
+    net::PortRange,
+    process::Process,
+    ruleset::{Behavior, Exclusions},
+};
+
+use crate::{
+    config::Rule,
+    rule_provider::{ProviderRules, RuleProvider},
+};
+
+/// The parameter of IP rules which keeps destination domains from being resolved.
+const NO_RESOLVE: &str = "no-resolve";
+
+/// The target which connects to the destination itself.
+pub(crate) const DIRECT: &str = "DIRECT";
+
+/// The target which refuses the connection.
+pub(crate) const REJECT: &str = "REJECT";
+
+/// What a connection is routed by.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Metadata<'a> {
+    /// tag of the inbound the connection came in through
+    pub inbound: &'a str,
+    pub source: IpAddr,
+    /// domain of the destination, `None` if the destination is an address
+    pub domain: Option<&'a str>,
+    /// address of the destination, or the one `domain` resolved to
+    pub ip: Option<IpAddr>,
+    pub port: u16,
+    /// the local process which opened the connection, `None` if unknown or not looked up
+    pub process: Option<&'a Process>,
+}
+
+/// The rule which matched and its target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Route<'a> {
+    pub rule: &'a str,
+    pub target: &'a str,
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Domain(String),
+    /// `.example.com` of `example.com`
+    DomainSuffix(String),
+    DomainKeyword(String),
+    /// a category of the geosite domain lists
+    GeoSite(String),
+    IpCidr {
+        net: IpNet,
+        no_resolve: bool,
+    },
+    GeoIp {
+        country: String,
+        no_resolve: bool,
+    },
+    IpAsn {
+        asn: u32,
+        no_resolve: bool,
+    },
+    DstPort(PortRange),
+    SrcIpCidr(IpNet),
+    Inbound(String),
+    /// the file name of the executable
+    ProcessName(String),
+    ProcessPath(PathBuf),
+    /// a rule set of the rule providers
+    RuleSet {
+        provider: String,
+        no_resolve: bool,
+    },
+    Match,
+}
+
+impl Matcher {
+    fn parse(rule: &Rule) -> Result<Self, String> {
+        let invalid = || format!("invalid payload of rule {}", rule);
+        let payload = rule.payload.trim();
+        let no_resolve = rule.params.iter().any(|param| param == NO_RESOLVE);
+        let ip_rule = matches!(
+            rule.tp.as_str(),
+            "IP-CIDR" | "IP-CIDR6" | "GEOIP" | "IP-ASN" | "RULE-SET"
+        );
+        if let Some(param) = rule.params.iter().find(|param| !ip_rule || *param != NO_RESOLVE) {
+            return Err(format!("unknown parameter {} of rule {}", param, rule));
+        }
+
+        let domain = || {
+            let domain = payload.trim_end_matches('.').to_ascii_lowercase();
+            if domain.is_empty() {
+                return Err(invalid());
+            }
+            Ok(domain)
+        };
+        let matcher = match rule.tp.as_str() {
+            "DOMAIN" => Matcher::Domain(domain()?),
+            "DOMAIN-SUFFIX" => Matcher::DomainSuffix(format!(".{}", domain()?.trim_start_matches('.'))),
+            "DOMAIN-KEYWORD" => Matcher::DomainKeyword(domain()?),
+            "GEOSITE" => Matcher::GeoSite(geosite_category(payload).ok_or_else(invalid)?),
+            "IP-CIDR" | "IP-CIDR6" => {
+                let net: IpNet = payload.parse().map_err(|_| invalid())?;
+                if rule.tp == "IP-CIDR6" && !matches!(net, IpNet::V6(_)) {
+                    return Err(invalid());
+                }
+                Matcher::IpCidr { net, no_resolve }
+            }
+            "GEOIP" if !payload.is_empty() && payload.chars().all(|c| c.is_ascii_alphabetic()) => Matcher::GeoIp {
+                country: payload.to_ascii_uppercase(),
+                no_resolve,
+            },
+            "IP-ASN" => Matcher::IpAsn {
+                asn: geoip::parse_asn(payload).ok_or_else(invalid)?,
+                no_resolve,
+            },
+            "DST-PORT" => Matcher::DstPort(payload.parse().map_err(|_| invalid())?),
+            "SRC-IP-CIDR" => Matcher::SrcIpCidr(payload.parse().map_err(|_| invalid())?),
+            "INBOUND" if !payload.is_empty() => Matcher::Inbound(payload.to_owned()),
+            "PROCESS-NAME" if !payload.is_empty() => Matcher::ProcessName(payload.to_owned()),
+            "PROCESS-PATH" if !payload.is_empty() => Matcher::ProcessPath(payload.into()),
+            "RULE-SET" if !payload.is_empty() => Matcher::RuleSet {
+                provider: payload.to_owned(),
+                no_resolve,
+            },
+            "MATCH" => Matcher::Match,
+            "GEOIP" | "INBOUND" | "PROCESS-NAME" | "PROCESS-PATH" | "RULE-SET" => return Err(invalid()),
+            tp => return Err(format!("unknown type {} of rule {}", tp, rule)),
+        };
+        Ok(matcher)
+    }
+
+    /// The address the IP rules match against, `None` if the destination domain wasn't resolved
+    /// yet. `Some(None)` if the rule doesn't match without resolving.
+    fn ip(no_resolve: bool, meta: &Metadata) -> Option<Option<IpAddr>> {
+        match (meta.domain, meta.ip) {
+            (Some(_), _) if no_resolve => Some(None),
+            (_, Some(ip)) => Some(Some(ip)),
+            (Some(_), None) => None,
+            (None, None) => Some(None),
+        }
+    }
+}
+
+/// The rules of a `classical` rule set, rules without a target.
+#[derive(Debug, Default)]
+pub(crate) struct ClassicalRules(Vec<Matcher>);
+
+impl ClassicalRules {
+    /// Parses one rule per line, e.g. `DOMAIN-SUFFIX,google.com` or
+    /// `IP-CIDR,10.0.0.0/8,no-resolve`, `#` starts a comment. Invalid lines are skipped with a
+    /// warning, so are `RULE-SET` and `MATCH` rules.
+    pub(crate) fn parse(text: &str) -> Self {
+        let lines = text
+            .lines()
+            .map(|l| l.split('#').next().unwrap_or_default().trim())
+            .filter(|l| !l.is_empty());
+
+        let mut matchers = Vec::new();
+        for line in lines {
+            let mut parts = line.split(',').map(|part| part.trim().to_owned());
+            let rule = Rule {
+                tp: parts.next().unwrap_or_default(),
+                payload: parts.next().unwrap_or_default(),
+                target: String::new(),
+                params: parts.collect(),
+            };
+            match Matcher::parse(&rule) {
+                Ok(Matcher::RuleSet { .. } | Matcher::Match) => {
+                    warn!("ignore rule set line {}, not a rule of a set", line)
+                }
+                Ok(matcher) => matchers.push(matcher),
+                Err(err) => warn!("ignore rule set line {}, {}", line, err),
+            }
+        }
+        Self(matchers)
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether any of the rules matches by the process of the connection.
+    fn has_process(&self) -> bool {
+        self.0
+            .iter()
+            .any(|matcher| matches!(matcher, Matcher::ProcessName(_) | Matcher::ProcessPath(_)))
+    }
+}
+
+#[derive(Debug)]
+struct CompiledRule {
+    /// as configured, the key of its match counter
+    text: String,
+    matcher: Matcher,
+    target: String,
+}
+
+/// The routing rules, see the [module](self) documentation.
+#[derive(Default)]
+pub(crate) struct Router {
+    rules: Vec<CompiledRule>,
+    excludes: HashMap<String, Exclusions>,
+    geoip: Option<Arc<GeoIpDb>>,
+    geoip_asn: Option<Arc<GeoIpDb>>,
+    geosite: Option<Arc<GeoSite>>,
+    rule_providers: HashMap<String, Arc<RuleProvider>>,
+}
+
+impl Router {
+    /// Compiles `rules`, fails on the first invalid one.
+    pub(crate) fn new(rules: &[Rule]) -> Result<Self, String> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                Ok(CompiledRule {
+                    text: rule.to_string(),
+                    matcher: Matcher::parse(rule)?,
+                    target: rule.target.clone(),
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            rules,
+            ..Default::default()
+        })
+    }
+
+    /// Lets `GEOIP` rules look up the countries in `geoip`, without it they never match.
+    pub(crate) fn with_geoip(mut self, geoip: Option<Arc<GeoIpDb>>) -> Self {
+        self.geoip = geoip;
+        self
+    }
+
+    /// Lets `IP-ASN` rules look up the autonomous systems in `geoip_asn`, without it they never
+    /// match.
+    pub(crate) fn with_geoip_asn(mut self, geoip_asn: Option<Arc<GeoIpDb>>) -> Self {
+        self.geoip_asn = geoip_asn;
+        self
+    }
+
+    /// Lets `GEOSITE` rules match the categories of `geosite`, without it they never match.
+    pub(crate) fn with_geosite(mut self, geosite: Option<Arc<GeoSite>>) -> Self {
+        self.geosite = geosite;
+        self
+    }
+
+    /// Lets `RULE-SET` rules match the rule sets of `providers`, by name. The rules of a provider
+    /// which isn't loaded never match.
+    pub(crate) fn with_rule_providers(mut self, providers: HashMap<String, Arc<RuleProvider>>) -> Self {
+        self.rule_providers = providers;
+        self
+    }
+
+    /// Sets the destinations each target never handles, by target.
+    pub(crate) fn with_excludes(mut self, excludes: HashMap<String, Exclusions>) -> Self {
+        self.excludes = excludes;
+        self
+    }
+
+    /// Returns the rules in matching order, as [`Router::route`] returns them.
+    pub(crate) fn rules(&self) -> Vec<String> {
+        self.rules.iter().map(|rule| rule.text.clone()).collect()
+    }
+
+    /// Whether any rule matches by the process of the connection, which must then be looked up
+    /// before routing.
+    pub(crate) fn needs_process(&self) -> bool {
+        self.rules.iter().any(|rule| match &rule.matcher {
+            Matcher::ProcessName(_) | Matcher::ProcessPath(_) => true,
+            Matcher::RuleSet { provider, .. } => {
+                let rules = self.rule_providers.get(provider).and_then(|provider| provider.rules());
+                matches!(rules.as_deref(), Some(ProviderRules::Classical(rules)) if rules.has_process())
+            }
+            _ => false,
+        })
+    }
+
+    /// Whether the destination domain of `meta` must be resolved before routing, i.e. an IP rule
+    /// without `no-resolve` is reached before any other rule matches.
+    pub(crate) fn needs_ip(&self, meta: &Metadata) -> bool {
+        let domain = normalize(meta.domain);
+        for rule in self.rules.iter() {
+            match self.matches(rule, meta, domain.as_deref()) {
+                Some(true) => return false,
+                Some(false) => {}
+                None => return true,
+            }
+        }
+        false
+    }
+
+    /// The domains whose connections are all rejected, as patterns of
+    /// [`BlockedDomains`](swiftlink_infra::fakedns::BlockedDomains), so the dns server answers
+    /// them without a fake ip. These are the `DOMAIN` and `DOMAIN-SUFFIX` rules targeting `REJECT`,
+    /// up to the first rule of another target which may match a domain by more than its name,
+    /// e.g. `DST-PORT`. A rule which an earlier rule of another target partly matches is left out.
+    pub(crate) fn blocked_domains(&self) -> Vec<String> {
+        if self.excludes.contains_key(REJECT) {
+            return Vec::new();
+        }
+        let mut others: Vec<&Matcher> = Vec::new();
+        let mut blocked = Vec::new();
+        for rule in self.rules.iter() {
+            let is_name = matches!(rule.matcher, Matcher::Domain(_) | Matcher::DomainSuffix(_));
+            if rule.target == REJECT {
+                if is_name && !others.iter().any(|other| overlaps(&rule.matcher, other)) {
+                    blocked.push(match &rule.matcher {
+                        Matcher::DomainSuffix(suffix) => format!("+{}", suffix),
+                        matcher => name_of(matcher).to_owned(),
+                    });
+                }
+                continue;
+            }
+            match rule.matcher {
+                Matcher::Domain(_) | Matcher::DomainSuffix(_) => others.push(&rule.matcher),
+                // never match a destination domain
+                Matcher::IpCidr { no_resolve: true, .. }
+                | Matcher::GeoIp { no_resolve: true, .. }
+                | Matcher::IpAsn { no_resolve: true, .. } => {}
+                _ => break,
+            }
+        }
+        blocked
+    }
+
+    /// Returns the first rule matching `meta`, `None` if no rule matches.
+    pub(crate) fn route(&self, meta: &Metadata) -> Option<Route<'_>> {
+        let domain = normalize(meta.domain);
+        self.rules
+            .iter()
+            .find(|rule| self.matches(rule, meta, domain.as_deref()) == Some(true))
+            .map(|rule| Route {
+                rule: &rule.text,
+                target: &rule.target,
+            })
+    }
+
+    /// Whether `rule` matches `meta`, of the normalized `domain`. `None` if that depends on the
+    /// address the destination domain resolves to.
+    fn matches(&self, rule: &CompiledRule, meta: &Metadata, domain: Option<&str>) -> Option<bool> {
+        let matched = self.is_match(&rule.matcher, meta, domain)?;
+        Some(matched && !self.excludes(&rule.target, meta))
+    }
+
+    /// Whether `matcher` matches `meta`, regardless of the exclusions of the target.
+    fn is_match(&self, matcher: &Matcher, meta: &Metadata, domain: Option<&str>) -> Option<bool> {
+        let matched = match matcher {
+            Matcher::Domain(expected) => domain == Some(expected.as_str()),
+            Matcher::DomainSuffix(suffix) => {
+                domain.is_some_and(|domain| domain.ends_with(suffix.as_str()) || domain == &suffix[1..])
+            }
+            Matcher::DomainKeyword(keyword) => domain.is_some_and(|domain| domain.contains(keyword.as_str())),
+            Matcher::GeoSite(category) => domain.is_some_and(|domain| {
+                let geosite = self.geosite.as_ref();
+                geosite.is_some_and(|geosite| geosite.matches(category, domain))
+            }),
+            Matcher::IpCidr { net, no_resolve } => {
+                Matcher::ip(*no_resolve, meta)?.is_some_and(|ip| net.contains(&ip.to_canonical()))
+            }
+            Matcher::GeoIp { country, no_resolve } => Matcher::ip(*no_resolve, meta)?.is_some_and(|ip| {
+                let found = self.geoip.as_ref().and_then(|geoip| geoip.country(ip.to_canonical()));
+                found.is_some_and(|found| found.eq_ignore_ascii_case(country))
+            }),
+            Matcher::IpAsn { asn, no_resolve } => Matcher::ip(*no_resolve, meta)?.is_some_and(|ip| {
+                let found = self.geoip_asn.as_ref().and_then(|db| db.asn(ip.to_canonical()));
+                found == Some(*asn)
+            }),
+            Matcher::DstPort(ports) => ports.contains(meta.port),
+            Matcher::SrcIpCidr(net) => net.contains(&meta.source.to_canonical()),
+            Matcher::Inbound(tag) => meta.inbound == tag,
+            Matcher::ProcessName(name) => meta.process.is_some_and(|process| process.name == *name),
+            Matcher::ProcessPath(path) => meta.process.is_some_and(|process| process.path == *path),
+            Matcher::RuleSet { provider, no_resolve } => {
+                let rules = self.rule_providers.get(provider).and_then(|provider| provider.rules());
+                match rules.as_deref() {
+                    Some(ProviderRules::Set(set)) => match set.behavior() {
+                        Behavior::Domain => domain.is_some_and(|domain| set.contains_domain(domain)),
+                        Behavior::IpCidr => {
+                            Matcher::ip(*no_resolve, meta)?.is_some_and(|ip| set.contains_ip(ip.to_canonical()))
+                        }
+                    },
+                    Some(ProviderRules::Classical(rules)) => self.is_any_match(rules, *no_resolve, meta, domain)?,
+                    None => false,
+                }
+            }
+            Matcher::Match => true,
+        };
+        Some(matched)
+    }
+
+    /// Whether any of the classical `rules` matches `meta`. With `no_resolve`, their IP rules
+    /// skip domains as well.
+    fn is_any_match(
+        &self,
+        rules: &ClassicalRules,
+        no_resolve: bool,
+        meta: &Metadata,
+        domain: Option<&str>,
+    ) -> Option<bool> {
+        let meta = Metadata {
+            ip: meta.ip.filter(|_| !no_resolve || meta.domain.is_none()),
+            ..*meta
+        };
+        let mut unresolved = false;
+        for matcher in rules.0.iter() {
+            match self.is_match(matcher, &meta, domain) {
+                Some(true) => return Some(true),
+                Some(false) => {}
+                None => unresolved = true,
+            }
+        }
+        (!unresolved || no_resolve).then_some(false)
+    }
+
+    /// Whether `target` never handles the destination of `meta`.
+    fn excludes(&self, target: &str, meta: &Metadata) -> bool {
+        let Some(excludes) = self.excludes.get(target) else {
+            return false;
+        };
+        meta.domain.is_some_and(|domain| excludes.excludes(domain))
+            || meta.ip.is_some_and(|ip| excludes.excludes_ip(ip.to_canonical()))
+    }
+}
+
+/// The category of the payload of a `GEOSITE` rule, `google` of `google` or `geosite:google`,
+/// `None` if it's not a category name.
+pub(crate) fn geosite_category(payload: &str) -> Option<String> {
+    let payload = payload.trim().to_ascii_lowercase();
+    let category = payload.strip_prefix("geosite:").unwrap_or(&payload);
+    let valid = |s: &str| {
+        !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '!' | '.'))
+    };
+    match category.split_once('@') {
+        Some((name, attr)) if valid(name) && valid(attr) => Some(category.to_owned()),
+        None if valid(category) => Some(category.to_owned()),
+        _ => None,
+    }
+}
+
+/// The domain of a `DOMAIN` matcher or the suffix of a `DOMAIN-SUFFIX` one without the leading
+/// dot.
+fn name_of(matcher: &Matcher) -> &str {
+    match matcher {
+        Matcher::Domain(domain) => domain,
+        Matcher::DomainSuffix(suffix) => &suffix[1..],
+        _ => unreachable!("not a domain name matcher"),
+    }
+}
+
+/// Whether the `DOMAIN` or `DOMAIN-SUFFIX` matchers `a` and `b` match a domain in common.
+fn overlaps(a: &Matcher, b: &Matcher) -> bool {
+    let (name_a, name_b) = (name_of(a), name_of(b));
+    let is_under = |name: &str, suffix: &str| name == suffix || name.ends_with(&format!(".{}", suffix));
+    match (a, b) {
+        (Matcher::Domain(_), Matcher::Domain(_)) => name_a == name_b,
+        (Matcher::Domain(_), _) => is_under(name_a, name_b),
+        (_, Matcher::Domain(_)) => is_under(name_b, name_a),
+        _ => is_under(name_a, name_b) || is_under(name_b, name_a),
+    }
+}
+
+/// Lowercase and without the trailing dot, as the domains of the rules.
+fn normalize(domain: Option<&str>) -> Option<String> {
+    domain.map(|domain| domain.trim_end_matches('.').to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router(rules: &[&str]) -> Router {
+        let rules: Vec<Rule> = rules.iter().map(|rule| rule.parse().unwrap()).collect();
+        Router::new(&rules).unwrap()
+    }
+
+    fn meta<'a>(domain: Option<&'a str>, ip: Option<&str>, port: u16) -> Metadata<'a> {
+        Metadata {
+            inbound: "socks",
+            source: "192.168.1.2".parse().unwrap(),
+            domain,
+            ip: ip.map(|ip| ip.parse().unwrap()),
+            port,
+            process: None,
+        }
+    }
+
+    fn target<'a>(router: &'a Router, meta: &Metadata) -> Option<&'a str> {
+        router.route(meta).map(|route| route.target)
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let parse = |rule: &str| Router::new(&[rule.parse().unwrap()]);
+        for rule in [
+            "DOMAIN-SUFFIX,.example.com,PROXY",
+            "IP-CIDR,2001:db8::/32,PROXY",
+            "GEOIP,cn,DIRECT,no-resolve",
+            "GEOSITE,geosite:google@cn,DIRECT",
+            "GEOSITE,category-ads-all,REJECT",
+            "IP-ASN,AS13335,PROXY",
+            "DST-PORT,443,PROXY",
+            "DST-PORT,8000-9000,PROXY",
+            "SRC-IP-CIDR,192.168.1.0/24,DIRECT",
+            "RULE-SET,lan,DIRECT,no-resolve",
+            "PROCESS-NAME,curl,DIRECT",
+            "PROCESS-PATH,C:\\Program Files\\App\\app.exe,PROXY",
+        ] {
+            assert!(parse(rule).is_ok(), "{}", rule);
+        }
+        for rule in [
+            "DOMAIN,,PROXY",
+            "IP-CIDR,10.0.0.1,PROXY",
+            "IP-CIDR6,10.0.0.0/8,PROXY",
+            "GEOIP,,DIRECT",
+            "GEOSITE,,PROXY",
+            "GEOSITE,google@,PROXY",
+            "GEOSITE,google,PROXY,no-resolve",
+            "IP-ASN,cloudflare,PROXY",
+            "DST-PORT,https,PROXY",
+            "INBOUND,,DIRECT",
+            "DOMAIN,example.com,PROXY,no-resolve",
+            "IP-CIDR,10.0.0.0/8,DIRECT,no-resolv",
+            "PROCESS-NAME,,DIRECT",
+            "PROCESS-PATH,/usr/bin/curl,DIRECT,no-resolve",
+            "SCRIPT,quic,REJECT",
+            "RULE-SET,,REJECT",
+        ] {
+            assert!(parse(rule).is_err(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn test_route() {
+        let router = router(&[
+            "DOMAIN,www.example.com,EXACT",
+            "DOMAIN-SUFFIX,example.com,SUFFIX",
+            "DOMAIN-KEYWORD,tracker,REJECT",
+            "IP-CIDR,10.0.0.0/8,LAN,no-resolve",
+            "IP-CIDR6,fd00::/8,LAN6",
+            "DST-PORT,6881-6889,P2P",
+            "SRC-IP-CIDR,192.168.9.0/24,CLIENT",
+            "MATCH,FINAL",
+        ]);
+        assert_eq!(router.rules().len(), 8);
+
+        let route = router.route(&meta(Some("WWW.example.com."), None, 443)).unwrap();
+        assert_eq!(
+            route,
+            Route {
+                rule: "DOMAIN,www.example.com,EXACT",
+                target: "EXACT"
+            }
+        );
+        assert_eq!(target(&router, &meta(Some("example.com"), None, 443)), Some("SUFFIX"));
+        assert_eq!(
+            target(&router, &meta(Some("a.b.example.com"), None, 443)),
+            Some("SUFFIX")
+        );
+        assert_eq!(target(&router, &meta(Some("notexample.com"), None, 443)), Some("FINAL"));
+        assert_eq!(target(&router, &meta(Some("x.tracker.net"), None, 443)), Some("REJECT"));
+        assert_eq!(target(&router, &meta(None, Some("10.1.2.3"), 443)), Some("LAN"));
+        assert_eq!(target(&router, &meta(None, Some("::ffff:10.1.2.3"), 443)), Some("LAN"));
+        assert_eq!(target(&router, &meta(None, Some("fd00::1"), 443)), Some("LAN6"));
+        assert_eq!(target(&router, &meta(None, Some("203.0.113.1"), 6881)), Some("P2P"));
+        let mut client = meta(None, Some("203.0.113.1"), 443);
+        client.source = "::ffff:192.168.9.5".parse().unwrap();
+        assert_eq!(target(&router, &client), Some("CLIENT"));
+
+        // no-resolve rules skip domains, even resolved ones
+        assert_eq!(
+            target(&router, &meta(Some("nas.lan"), Some("10.0.0.2"), 443)),
+            Some("FINAL")
+        );
+    }
+
+    #[test]
+    fn test_needs_ip() {
+        let router = router(&[
+            "DOMAIN-SUFFIX,example.com,PROXY",
+            "IP-CIDR,10.0.0.0/8,DIRECT,no-resolve",
+            "IP-CIDR,172.16.0.0/12,DIRECT",
+            "MATCH,PROXY",
+        ]);
+        // matched before the first IP rule which resolves
+        assert!(!router.needs_ip(&meta(Some("www.example.com"), None, 443)));
+        assert!(router.needs_ip(&meta(Some("nas.lan"), None, 443)));
+        assert!(!router.needs_ip(&meta(Some("nas.lan"), Some("172.16.0.2"), 443)));
+        assert!(!router.needs_ip(&meta(None, Some("172.16.0.2"), 443)));
+
+        // unresolved, the IP rule can't match
+        assert_eq!(target(&router, &meta(Some("nas.lan"), None, 443)), Some("PROXY"));
+        assert_eq!(
+            target(&router, &meta(Some("nas.lan"), Some("172.16.0.2"), 443)),
+            Some("DIRECT")
+        );
+
+        assert!(!Router::default().needs_ip(&meta(Some("nas.lan"), None, 443)));
+        assert_eq!(Router::default().route(&meta(Some("nas.lan"), None, 443)), None);
+    }
+
+    #[test]
+    fn test_route_excludes_and_lookups() {
+        let excludes = HashMap::from([(
+            "PROXY".to_owned(),
+            Exclusions::parse(["proxy.example.com", "203.0.113.0/24"]).unwrap(),
+        )]);
+        let router = router(&[
+            "INBOUND,socks,PROXY",
+            "GEOIP,CN,DIRECT",
+            "IP-ASN,13335,CLOUDFLARE",
+            "MATCH,FALLBACK",
+        ])
+        .with_excludes(excludes);
+
+        assert_eq!(
+            target(&router, &meta(Some("www.example.com"), None, 443)),
+            Some("PROXY")
+        );
+        // the proxy server itself doesn't go through the proxy
+        assert_eq!(
+            target(&router, &meta(Some("proxy.example.com"), None, 443)),
+            Some("FALLBACK")
+        );
+        assert_eq!(target(&router, &meta(None, Some("203.0.113.1"), 443)), Some("FALLBACK"));
+
+        // without the databases, GEOIP and IP-ASN rules never match
+        let mut other = meta(None, Some("1.1.1.1"), 443);
+        other.inbound = "http";
+        assert_eq!(target(&router, &other), Some("FALLBACK"));
+    }
+
+    #[test]
+    fn test_blocked_domains() {
+        let router = router(&[
+            "IP-CIDR,10.0.0.0/8,DIRECT,no-resolve",
+            "DOMAIN-SUFFIX,ads.example.com,REJECT",
+            "DOMAIN,cdn.tracker.net,DIRECT",
+            "DOMAIN-SUFFIX,tracker.net,REJECT",
+            "DOMAIN-SUFFIX,example.org,PROXY",
+            "DOMAIN,ads.example.org,REJECT",
+            "DOMAIN,Pixel.Example.net.,REJECT",
+            "DOMAIN-KEYWORD,beacon,REJECT",
+            "DST-PORT,443,DIRECT",
+            "DOMAIN,late.example.com,REJECT",
+            "MATCH,PROXY",
+        ]);
+        assert_eq!(router.blocked_domains(), ["+.ads.example.com", "pixel.example.net"]);
+
+        let excludes = HashMap::from([(REJECT.to_owned(), Exclusions::parse(["a.example.com"]).unwrap())]);
+        let router = router.with_excludes(excludes);
+        assert!(router.blocked_domains().is_empty());
+    }
+
+    #[test]
+    fn test_route_geosite() {
+        // the GOOGLE category with the root domain google.com
+        let data = b"\x0a\x18\x0a\x06GOOGLE\x12\x0e\x08\x02\x12\x0agoogle.com";
+        let geosite = GeoSite::parse(data, &["google"]).unwrap();
+        let router = router(&["GEOSITE,geosite:Google,PROXY", "MATCH,DIRECT"]);
+        // without the domain lists, GEOSITE rules never match
+        assert_eq!(
+            target(&router, &meta(Some("www.google.com"), None, 443)),
+            Some("DIRECT")
+        );
+
+        let router = router.with_geosite(Some(Arc::new(geosite)));
+        assert_eq!(
+            target(&router, &meta(Some("www.Google.com."), None, 443)),
+            Some("PROXY")
+        );
+        assert_eq!(target(&router, &meta(Some("example.com"), None, 443)), Some("DIRECT"));
+        assert_eq!(target(&router, &meta(None, Some("8.8.8.8"), 443)), Some("DIRECT"));
+    }
+
+    #[test]
+    fn test_route_rule_set() {
+        use crate::config::RuleProviderBehavior;
+
+        let dir = std::env::temp_dir().join(format!("swiftlink-route-rule-set-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut providers = HashMap::new();
+        for (name, behavior, text) in [
+            ("ads", RuleProviderBehavior::Domain, "+.doubleclick.net\n"),
+            ("lan", RuleProviderBehavior::IpCidr, "10.0.0.0/8\n"),
+            (
+                "media",
+                RuleProviderBehavior::Classical,
+                "# streaming\nDOMAIN-SUFFIX,example.tv\nIP-CIDR,203.0.113.0/24\nMATCH\nINVALID,x\n",
+            ),
+        ] {
+            let path = dir.join(name);
+            std::fs::write(&path, text).unwrap();
+            let provider = RuleProvider::new(name, behavior, path);
+            provider.reload().unwrap();
+            providers.insert(name.to_owned(), Arc::new(provider));
+        }
+        providers.insert(
+            "missing".to_owned(),
+            Arc::new(RuleProvider::new(
+                "missing",
+                RuleProviderBehavior::Domain,
+                dir.join("missing"),
+            )),
+        );
+
+        let router = router(&[
+            "RULE-SET,missing,NEVER",
+            "RULE-SET,ads,REJECT",
+            "RULE-SET,lan,DIRECT,no-resolve",
+            "RULE-SET,media,MEDIA",
+            "MATCH,PROXY",
+        ])
+        .with_rule_providers(providers);
+        let domain = |domain| meta(Some(domain), None, 443);
+        assert_eq!(target(&router, &domain("ad.doubleclick.net")), Some("REJECT"));
+        assert_eq!(target(&router, &meta(None, Some("10.1.2.3"), 443)), Some("DIRECT"));
+        assert_eq!(
+            target(&router, &meta(Some("nas.lan"), Some("10.1.2.3"), 443)),
+            Some("PROXY")
+        );
+        assert_eq!(target(&router, &domain("www.example.tv")), Some("MEDIA"));
+        assert_eq!(target(&router, &meta(None, Some("203.0.113.9"), 443)), Some("MEDIA"));
+
+        // the IP rule of the classical set resolves domains
+        assert!(router.needs_ip(&domain("www.example.com")));
+        assert!(!router.needs_ip(&domain("www.example.tv")));
+        assert_eq!(
+            target(&router, &meta(Some("www.example.com"), Some("203.0.113.9"), 443)),
+            Some("MEDIA")
+        );
+        assert_eq!(target(&router, &domain("www.example.com")), Some("PROXY"));
+        assert!(!router.needs_process());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_route_process() {
+        let router = router(&[
+            "PROCESS-NAME,curl,DIRECT",
+            "PROCESS-PATH,/opt/app/bin/app,APP",
+            "MATCH,PROXY",
+        ]);
+        assert!(router.needs_process());
+        assert!(!Router::default().needs_process());
+
+        let curl = Process {
+            pid: 4242,
+            name: "curl".to_owned(),
+            path: "/usr/bin/curl".into(),
+        };
+        let app = Process {
+            pid: 4343,
+            name: "app".to_owned(),
+            path: "/opt/app/bin/app".into(),
+        };
+        let other = Process {
+            pid: 4444,
+            name: "app".to_owned(),
+            path: "/usr/local/bin/app".into(),
+        };
+        let with_process = |process| Metadata {
+            process: Some(process),
+            ..meta(Some("www.example.com"), None, 443)
+        };
+        assert_eq!(target(&router, &with_process(&curl)), Some("DIRECT"));
+        assert_eq!(target(&router, &with_process(&app)), Some("APP"));
+        assert_eq!(target(&router, &with_process(&other)), Some("PROXY"));
+        // not looked up, or not a local process
+        assert_eq!(
+            target(&router, &meta(Some("www.example.com"), None, 443)),
+            Some("PROXY")
+        );
+    }
+}
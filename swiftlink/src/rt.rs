@@ -1,8 +1,9 @@
 use swiftlink_infra::log::{info, warn};
 use tokio::runtime::{Builder, Runtime};
 
+/// Builds the runtime, `single_threaded` forces a current-thread runtime.
 #[cfg(feature = "multicore")]
-pub(crate) fn build() -> Runtime {
+pub(crate) fn build(single_threaded: bool) -> Runtime {
     let mut cores = std::env::var("SWIFTLINK_CORES")
         .ok()
         .and_then(|v| {
@@ -25,6 +26,11 @@ pub(crate) fn build() -> Runtime {
         cores = cpus;
     }
 
+    if single_threaded && cores > 1 {
+        info!("Privileges are dropped with CAP_NET_ADMIN retained, which is per thread");
+        cores = 1;
+    }
+
     match cores {
         // `0` is unexpected, but it's a wild world out there.
         0 | 1 => {
@@ -49,7 +55,7 @@ pub(crate) fn build() -> Runtime {
 }
 
 #[cfg(not(feature = "multicore"))]
-pub(crate) fn build() -> Runtime {
+pub(crate) fn build(_single_threaded: bool) -> Runtime {
     Builder::new()
         .enable_all()
         .basic_scheduler()
@@ -0,0 +1,319 @@
+//! Rule sets of `RULE-SET` rules, by the name of their provider in `[rule_providers]`.
+//!
+//! A provider is a local rule set file, or one downloaded from its `url` and cached in the file.
+//! A missing file is downloaded on start, and again once it's older than `interval`, by the
+//! modification time of the file. A download replaces the file only once it parses into rules
+//! of the behavior, the rules then see the new set. A failed download is retried after an hour,
+//! meanwhile the rules keep the set they have.
+//!
+//! The behavior says what the lines of the set are:
+//!
+//! - `domain`: `google.com`, `+.google.com` for the domain and its subdomains, `*.example.com`
+//! - `ipcidr`: `10.0.0.0/8` or addresses
+//! - `classical`: rules without a target, `DOMAIN-SUFFIX,google.com` or
+//!   `IP-CIDR,10.0.0.0/8,no-resolve`
+//!
+//! `domain` and `ipcidr` sets may be binary rule sets of `swiftlink convert-ruleset` as well.
+//!
+//! ```toml
+//! rules = ["RULE-SET,ads,REJECT", "RULE-SET,lan,DIRECT,no-resolve", "MATCH,PROXY"]
+//!
+//! [rule_providers.ads]
+//! behavior = "domain"
+//! url = "https://example.com/rules/ads.txt"
+//! interval = 86400
+//!
+//! [rule_providers.lan]
+//! behavior = "ipcidr"
+//! path = "lan.txt"
+//! ```
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context};
+
+use swiftlink_infra::{
+    log::*,
+    net::ConnectOpts,
+    ruleset::{Behavior, RuleSet},
+};
+
+use crate::{
+    config::RuleProviderBehavior,
+    download::{self, download_path},
+    route::ClassicalRules,
+};
+
+/// Rule sets of a few hundred thousand lines are a few megabytes.
+const MAX_RULE_SET_LEN: usize = 64 * 1024 * 1024;
+
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(120);
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// The rules of a provider.
+#[derive(Debug)]
+pub(crate) enum ProviderRules {
+    Set(RuleSet),
+    Classical(ClassicalRules),
+}
+
+impl ProviderRules {
+    /// Loads the rule set file at `path`.
+    fn load(path: &Path, behavior: RuleProviderBehavior) -> anyhow::Result<Self> {
+        let rules = match behavior {
+            RuleProviderBehavior::Domain => Self::Set(RuleSet::load(path, Behavior::Domain)?),
+            RuleProviderBehavior::IpCidr => Self::Set(RuleSet::load(path, Behavior::IpCidr)?),
+            RuleProviderBehavior::Classical => Self::Classical(ClassicalRules::parse(&std::fs::read_to_string(path)?)),
+        };
+        Ok(rules)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Self::Set(set) => set.len(),
+            Self::Classical(rules) => rules.len(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A named rule set, empty until it's loaded.
+pub(crate) struct RuleProvider {
+    name: String,
+    behavior: RuleProviderBehavior,
+    path: PathBuf,
+    /// where the set is downloaded from, `None` for a local file
+    url: Option<String>,
+    rules: RwLock<Option<Arc<ProviderRules>>>,
+    /// held while updating, so the updater and the API don't download at once
+    updating: tokio::sync::Mutex<()>,
+}
+
+impl RuleProvider {
+    pub(crate) fn new<P: Into<PathBuf>>(name: &str, behavior: RuleProviderBehavior, path: P) -> Self {
+        Self {
+            name: name.to_owned(),
+            behavior,
+            path: path.into(),
+            url: None,
+            rules: RwLock::new(None),
+            updating: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Downloads the set from `url` on updates.
+    pub(crate) fn with_url(mut self, url: Option<&str>) -> Self {
+        self.url = url.map(str::to_owned);
+        self
+    }
+
+    #[inline]
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub(crate) fn behavior(&self) -> RuleProviderBehavior {
+        self.behavior
+    }
+
+    #[inline]
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[inline]
+    pub(crate) fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    /// The modification time of the file, when the set was last downloaded or edited.
+    pub(crate) fn updated_at(&self) -> Option<SystemTime> {
+        std::fs::metadata(&self.path).and_then(|meta| meta.modified()).ok()
+    }
+
+    /// The rules of the set, `None` if it was never loaded.
+    pub(crate) fn rules(&self) -> Option<Arc<ProviderRules>> {
+        self.rules.read().unwrap().clone()
+    }
+
+    /// Loads the set from its file, replacing the loaded one. Returns the number of rules.
+    pub(crate) fn reload(&self) -> anyhow::Result<usize> {
+        let rules = ProviderRules::load(&self.path, self.behavior)
+            .with_context(|| format!("load rule provider {} from {:?} failed", self.name, self.path))?;
+        let len = rules.len();
+        *self.rules.write().unwrap() = Some(Arc::new(rules));
+        info!("loaded {} rules of rule provider {}", len, self.name);
+        Ok(len)
+    }
+
+    /// Downloads the set if it has a url, then loads it. Returns the number of rules.
+    pub(crate) async fn update(&self, connect_opts: &ConnectOpts) -> anyhow::Result<usize> {
+        let _updating = self.updating.lock().await;
+        if let Some(url) = self.url.as_deref() {
+            fetch(self, url, connect_opts).await?;
+        }
+        self.reload()
+    }
+}
+
+/// Downloads the rule set of `provider` from `url` to its file, which is left as it was if the
+/// download failed or has no rules of the behavior.
+pub(crate) async fn fetch(provider: &RuleProvider, url: &str, connect_opts: &ConnectOpts) -> anyhow::Result<()> {
+    let data = tokio::time::timeout(
+        DOWNLOAD_TIMEOUT,
+        download::download(url, connect_opts, MAX_RULE_SET_LEN),
+    )
+    .await
+    .with_context(|| format!("download of {} timed out", url))??;
+    // error and login pages of servers answering 200, their lines would pass as domains
+    if data.trim_ascii_start().starts_with(b"<") {
+        bail!("{} is not a {} rule set, it's markup", url, provider.behavior);
+    }
+
+    if let Some(dir) = provider.path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("create {:?} failed", dir))?;
+    }
+    let tmp = download_path(&provider.path);
+    tokio::fs::write(&tmp, &data)
+        .await
+        .with_context(|| format!("write {:?} failed", tmp))?;
+    let checked = match ProviderRules::load(&tmp, provider.behavior) {
+        Ok(rules) if rules.is_empty() => Err(anyhow::anyhow!("no rules")),
+        Ok(_) => Ok(()),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = checked {
+        _ = tokio::fs::remove_file(&tmp).await;
+        bail!("{} is not a {} rule set, {:#}", url, provider.behavior, err);
+    }
+    tokio::fs::rename(&tmp, &provider.path)
+        .await
+        .with_context(|| format!("replace {:?} failed", provider.path))?;
+    info!(
+        "downloaded rule provider {} from {}, {} bytes",
+        provider.name,
+        url,
+        data.len()
+    );
+    Ok(())
+}
+
+/// Keeps a downloaded rule set up to date.
+pub(crate) struct RuleProviderUpdater {
+    provider: Arc<RuleProvider>,
+    interval: Duration,
+    connect_opts: ConnectOpts,
+}
+
+impl RuleProviderUpdater {
+    /// Updates `provider`, which must have a url.
+    pub(crate) fn new(provider: Arc<RuleProvider>, interval: Duration, connect_opts: ConnectOpts) -> Self {
+        Self {
+            provider,
+            interval,
+            connect_opts,
+        }
+    }
+
+    /// Downloads the rule set each time it's older than the interval, until the task is aborted.
+    pub(crate) async fn run(self) {
+        loop {
+            tokio::time::sleep(self.due_in()).await;
+            if let Err(err) = self.provider.update(&self.connect_opts).await {
+                warn!("rule provider {} update failed, {:#}", self.provider.name, err);
+                tokio::time::sleep(RETRY_INTERVAL.min(self.interval)).await;
+            }
+        }
+    }
+
+    /// How long until the rule set is older than the interval, a file without a modification
+    /// time is due right away.
+    fn due_in(&self) -> Duration {
+        let age = std::fs::metadata(&self.provider.path)
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+        match age {
+            Some(age) => self.interval.saturating_sub(age),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// Serves a domain list at `/ads.txt` and an html page at `/error.html`.
+    async fn rules_server() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    let n = stream.read(&mut buf).await.unwrap();
+                    let request = String::from_utf8_lossy(&buf[..n]).into_owned();
+                    let body = if request.starts_with("GET /ads.txt ") {
+                        "# ads\n+.doubleclick.net\nads.example.com\n"
+                    } else {
+                        "<html><body>rate limited</body></html>"
+                    };
+                    let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                    _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let addr = rules_server().await;
+        let dir = std::env::temp_dir().join(format!("swiftlink-rule-provider-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let provider = RuleProvider::new("ads", RuleProviderBehavior::Domain, dir.join("rule_providers/ads.txt"));
+        assert!(provider.rules().is_none());
+        assert!(provider.reload().is_err());
+
+        let opts = ConnectOpts::default();
+        fetch(&provider, &format!("http://{}/ads.txt", addr), &opts)
+            .await
+            .unwrap();
+        // `+.` is the domain and its subdomains, two entries
+        assert_eq!(provider.reload().unwrap(), 3);
+        let rules = provider.rules().unwrap();
+        let ProviderRules::Set(set) = rules.as_ref() else {
+            panic!("a domain rule set is a set");
+        };
+        assert!(set.contains_domain("ad.doubleclick.net"));
+        assert!(set.contains_domain("ads.example.com"));
+
+        // an error page is no rule set, the loaded one is kept
+        let err = fetch(&provider, &format!("http://{}/error.html", addr), &opts)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("is not a domain rule set"), "{:#}", err);
+        assert!(!download_path(provider.path()).exists());
+        assert_eq!(provider.reload().unwrap(), 3);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,110 @@
+//! `swiftlink rules stats`, the rule match counters of a running swiftlink.
+//!
+//! The counters are read from `/rules` of the external controller, which must be enabled.
+//! Rules which never matched are candidates for removal, the hot ones for moving up the list.
+
+use std::fmt;
+
+use serde::Deserialize;
+use swiftlink_infra::rule_hits::RuleHit;
+
+use crate::{controller, Config};
+
+/// Reads the counters from the external controller of `config`.
+pub fn fetch(config: &Config) -> anyhow::Result<Vec<RuleHit>> {
+    #[derive(Deserialize)]
+    struct Rules {
+        rules: Vec<RuleHit>,
+    }
+    let rules: Rules = controller::get(config, "/rules")?;
+    Ok(rules.rules)
+}
+
+/// The rules which never matched and the `top` rules with the most matches.
+pub struct RuleReport<'a> {
+    rules: usize,
+    matches: u64,
+    unused: Vec<&'a RuleHit>,
+    hot: Vec<&'a RuleHit>,
+}
+
+impl<'a> RuleReport<'a> {
+    pub fn new(hits: &'a [RuleHit], top: usize) -> Self {
+        let mut hot: Vec<_> = hits.iter().filter(|hit| hit.hits > 0).collect();
+        // stable, rules with as many matches stay in matching order
+        hot.sort_by_key(|hit| std::cmp::Reverse(hit.hits));
+        hot.truncate(top);
+        Self {
+            rules: hits.len(),
+            matches: hits.iter().map(|hit| hit.hits).sum(),
+            unused: hits.iter().filter(|hit| hit.hits == 0).collect(),
+            hot,
+        }
+    }
+}
+
+impl fmt::Display for RuleReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} rules, {} matches", self.rules, self.matches)?;
+        if !self.unused.is_empty() {
+            writeln!(f, "\nnever matched ({}):", self.unused.len())?;
+            for hit in self.unused.iter() {
+                writeln!(f, "  {}", hit.rule)?;
+            }
+        }
+        if !self.hot.is_empty() {
+            writeln!(f, "\nhot ({}):", self.hot.len())?;
+            for hit in self.hot.iter() {
+                let share = hit.hits as f64 * 100.0 / self.matches as f64;
+                writeln!(f, "  {:>10}  {:>5.1}%  {}", hit.hits, share, hit.rule)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use swiftlink_infra::rule_hits::RuleHits;
+
+    use crate::{api::Api, config::ControllerCors, controller::TestController};
+
+    use super::*;
+
+    fn hit(rule: &str, hits: u64) -> RuleHit {
+        RuleHit {
+            rule: rule.to_owned(),
+            hits,
+            last_hit: None,
+        }
+    }
+
+    #[test]
+    fn test_rule_report() {
+        let hits = [hit("www.example.com", 0), hit("*.example.com", 3), hit("*", 1)];
+        let report = RuleReport::new(&hits, 1).to_string();
+        assert_eq!(
+            report,
+            "3 rules, 4 matches\n\
+             \n\
+             never matched (1):\n  www.example.com\n\
+             \n\
+             hot (1):\n           3   75.0%  *.example.com\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let rule_hits = Arc::new(RuleHits::default());
+        rule_hits.set_rules(["*.example.com", "*"]);
+        rule_hits.hit("*");
+        let api = Api::new(None, ControllerCors::default()).with_rule_hits(rule_hits);
+        let controller = TestController::start(api).await;
+
+        let hits = controller.request(None, fetch).await.unwrap();
+        assert_eq!(hits.len(), 2);
+        assert_eq!((hits[1].rule.as_str(), hits[1].hits), ("*", 1));
+    }
+}
@@ -0,0 +1,555 @@
+//! TLS passthrough on a shared port, dispatched by the server name of the ClientHello.
+//!
+//! The TLS stream isn't terminated, each backend keeps its own certificates. With `sni_listen`
+//! set, connections are forwarded to the backend of the first matching `sni_routes` pattern:
+//!
+//! ```toml
+//! sni_listen = "0.0.0.0:443"
+//!
+//! [sni_routes]
+//! "trojan.example.com" = "127.0.0.1:8443"
+//! # any subdomain, the most specific pattern wins
+//! "*.example.com" = "127.0.0.1:9443"
+//! # everything else, also ClientHellos without a server name
+//! "*" = "127.0.0.1:10443"
+//! ```
+//!
+//! Connections nothing matches, or which aren't TLS, are closed. While the process is overloaded
+//! new connections get a fatal `internal_error` alert, clients fail at once instead of waiting
+//! for their handshake timeout.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Instant, SystemTime},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{Semaphore, SemaphorePermit},
+};
+
+use crate::decisions::{Decision, DecisionLog};
+use swiftlink_infra::{
+    connection::{CloseReason, ClosedConnection, ConnectionHistory, Dialed},
+    event::{Event, EventBus, OpenedConnection},
+    log::*,
+    net::{dial_cache::DialCache, ConnectOpts},
+    rule_hits::RuleHits,
+    sni::{self, SniError},
+    traffic::{self, TrafficCounter},
+    watchdog,
+};
+
+/// Tag of the inbound in the connection history
+pub(crate) const INBOUND_TAG: &str = "sni";
+
+/// The client must send its ClientHello within this time.
+const CLIENT_HELLO_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+const RELAY_BUFFER_LEN: usize = 16 * 1024;
+
+/// Overloaded, the ClientHello to answer with an alert must arrive within this time.
+const BUSY_REPLY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Connections answered with an alert at the same time, further ones are closed right away.
+/// Replies hold a connection open for up to [`BUSY_REPLY_TIMEOUT`], unbounded they'd add to the
+/// overload they report.
+static BUSY_REPLIES: Semaphore = Semaphore::const_new(64);
+
+pub(crate) static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The addresses of the backends which connected last.
+static DIAL_CACHE: OnceLock<DialCache> = OnceLock::new();
+
+/// The backends of the server names, see the [module](self) documentation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SniRoutes {
+    exact: HashMap<String, String>,
+    /// `(".example.com", backend)` of `*.example.com`, the longest suffix first
+    wildcard: Vec<(String, String)>,
+    default: Option<String>,
+}
+
+impl SniRoutes {
+    /// Builds the routes of `pattern = backend` pairs, backends are `host:port`.
+    pub fn new(routes: &BTreeMap<String, String>) -> Result<Self, String> {
+        let mut this = Self::default();
+        for (pattern, backend) in routes {
+            let valid_backend = backend
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+            if !valid_backend {
+                return Err(format!("backend {} of sni route {} is not host:port", backend, pattern));
+            }
+
+            let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+            if pattern == "*" {
+                this.default = Some(backend.clone());
+            } else if let Some(suffix) = pattern.strip_prefix("*.") {
+                this.wildcard.push((format!(".{}", suffix), backend.clone()));
+            } else if !pattern.is_empty() && !pattern.contains('*') {
+                this.exact.insert(pattern, backend.clone());
+            } else {
+                return Err(format!("invalid sni route {}", pattern));
+            }
+        }
+        this.wildcard.sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+        Ok(this)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcard.is_empty() && self.default.is_none()
+    }
+
+    /// Returns the patterns in matching order, as [`SniRoutes::route`] returns them.
+    pub fn rules(&self) -> Vec<String> {
+        let mut exact: Vec<_> = self.exact.keys().cloned().collect();
+        exact.sort();
+        exact
+            .into_iter()
+            .chain(self.wildcard.iter().map(|(suffix, _)| format!("*{}", suffix)))
+            .chain(self.default.as_ref().map(|_| "*".to_owned()))
+            .collect()
+    }
+
+    /// Returns the pattern and the backend `server_name` is forwarded to.
+    pub fn route(&self, server_name: Option<&str>) -> Option<(String, &str)> {
+        if let Some(name) = server_name {
+            let name = name.trim_end_matches('.').to_ascii_lowercase();
+            if let Some(backend) = self.exact.get(&name) {
+                return Some((name, backend));
+            }
+            if let Some((suffix, backend)) = self.wildcard.iter().find(|(suffix, _)| name.ends_with(suffix.as_str())) {
+                return Some((format!("*{}", suffix), backend));
+            }
+        }
+        self.default.as_deref().map(|backend| ("*".to_owned(), backend))
+    }
+}
+
+/// Forwards the connections of `listener` until the task is aborted, recording the routing
+/// decisions to `decisions` if given. Opened and closed connections are published to `events`,
+/// the routes which matched are counted in `hits`.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    routes: Arc<SniRoutes>,
+    connections: Arc<ConnectionHistory>,
+    events: Arc<EventBus>,
+    hits: Arc<RuleHits>,
+    decisions: Option<Arc<DecisionLog>>,
+) {
+    loop {
+        let (stream, source) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                debug!("sni proxy accept failed, {}", err);
+                continue;
+            }
+        };
+
+        let Some((stream, guard)) = watchdog::admit(stream, &BUSY_REPLIES, reply_busy) else {
+            continue;
+        };
+
+        let routes = routes.clone();
+        let connections = connections.clone();
+        let events = events.clone();
+        let hits = hits.clone();
+        let decisions = decisions.clone();
+        tokio::spawn(async move {
+            let _guard = guard;
+            handle(
+                stream,
+                source,
+                &routes,
+                &connections,
+                &events,
+                &hits,
+                decisions.as_deref(),
+            )
+            .await;
+        });
+    }
+}
+
+async fn handle(
+    mut client: TcpStream,
+    source: SocketAddr,
+    routes: &SniRoutes,
+    connections: &ConnectionHistory,
+    events: &EventBus,
+    hits: &RuleHits,
+    decisions: Option<&DecisionLog>,
+) {
+    let started = Instant::now();
+    traffic::total().connected();
+    let mut conn = ClosedConnection {
+        id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+        network: "tcp",
+        inbound: INBOUND_TAG.to_owned(),
+        source,
+        destination: "-".to_owned(),
+        rule: None,
+        outbound: None,
+        dialed: None,
+        upload: 0,
+        download: 0,
+        started_at: ClosedConnection::unix_millis(SystemTime::now()),
+        duration_ms: 0,
+        reason: CloseReason::Error,
+        error: None,
+    };
+
+    if let Err((reason, err)) = forward(&mut client, routes, events, hits, decisions, &mut conn).await {
+        conn.reason = reason;
+        conn.error = err.map(|err| err.to_string());
+    }
+    conn.duration_ms = started.elapsed().as_millis() as u64;
+    if events.has_subscribers() {
+        events.publish(Event::ConnectionClosed(Arc::new(conn.clone())));
+    }
+    connections.record(conn);
+}
+
+/// Answers the ClientHello of `client` with a fatal alert, the connection isn't forwarded.
+async fn reply_busy(mut client: TcpStream, _permit: SemaphorePermit<'static>) {
+    let result = async {
+        let hello = tokio::time::timeout(BUSY_REPLY_TIMEOUT, read_client_hello(&mut client)).await??;
+        // not TLS, an alert means nothing to the client
+        if sni::server_name(&hello) == Err(SniError::NotTls) {
+            return Ok(());
+        }
+        client.write_all(&sni::fatal_alert(sni::ALERT_INTERNAL_ERROR)).await?;
+        client.shutdown().await
+    };
+    if let Err(err) = result.await {
+        debug!("sni proxy busy reply to {:?} failed, {}", client.peer_addr(), err);
+    }
+}
+
+/// Forwards `client` to its backend, filling in `conn` on the way.
+async fn forward(
+    client: &mut TcpStream,
+    routes: &SniRoutes,
+    events: &EventBus,
+    hits: &RuleHits,
+    decisions: Option<&DecisionLog>,
+    conn: &mut ClosedConnection,
+) -> Result<(), (CloseReason, Option<io::Error>)> {
+    let hello = tokio::time::timeout(CLIENT_HELLO_TIMEOUT, read_client_hello(client))
+        .await
+        .map_err(|_| {
+            let err = io::Error::new(io::ErrorKind::TimedOut, "no ClientHello");
+            (CloseReason::HandshakeTimeout, Some(err))
+        })?
+        .map_err(|err| (CloseReason::from_relay_error(&err), Some(err)))?;
+
+    let server_name = match sni::server_name(&hello) {
+        Ok(name) => name,
+        Err(SniError::Malformed) => None,
+        Err(err) => {
+            return Err((
+                CloseReason::Error,
+                Some(io::Error::new(io::ErrorKind::InvalidData, err)),
+            ))
+        }
+    };
+    if let Some(name) = server_name {
+        let port = client.local_addr().map_or(443, |addr| addr.port());
+        conn.destination = format!("{}:{}", name, port);
+    }
+
+    let route = routes.route(server_name);
+    if let Some(decisions) = decisions {
+        decisions.record(&Decision::new(INBOUND_TAG, server_name, route.clone()));
+    }
+    let Some((rule, backend)) = route else {
+        return Err((CloseReason::PolicyReject, None));
+    };
+    hits.hit(&rule);
+    conn.rule = Some(rule.clone());
+    conn.outbound = Some(backend.to_owned());
+
+    let (mut remote, dialed) = dial(backend)
+        .await
+        .map_err(|err| (CloseReason::from_dial_error(&err), Some(err)))?;
+    conn.dialed = Some(dialed);
+    events.publish(Event::ConnectionOpened(OpenedConnection {
+        id: conn.id,
+        network: conn.network,
+        inbound: conn.inbound.clone(),
+        source: conn.source,
+        destination: conn.destination.clone(),
+        rule,
+        outbound: backend.to_owned(),
+    }));
+    remote
+        .write_all(&hello)
+        .await
+        .map_err(|err| (CloseReason::from_relay_error(&err), Some(err)))?;
+    conn.upload = hello.len() as u64;
+
+    let (result, upload, download) = relay(client, &mut remote).await;
+    conn.upload += upload;
+    conn.download = download;
+    match result {
+        Ok(reason) => {
+            conn.reason = reason;
+            Ok(())
+        }
+        Err(err) => Err((CloseReason::from_relay_error(&err), Some(err))),
+    }
+}
+
+/// Reads until the first TLS record is complete, at most one record.
+async fn read_client_hello(client: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        match sni::server_name(&buf) {
+            Err(SniError::Incomplete) => {}
+            _ => return Ok(buf),
+        }
+
+        let mut chunk = [0u8; 1024];
+        let max = (sni::RECORD_HEADER_LEN + sni::MAX_RECORD_LEN - buf.len()).min(chunk.len());
+        let n = client.read(&mut chunk[..max]).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Dials the addresses of the `host:port` backend, the one which connected last time first.
+/// Returns the stream with the address which connected and how long resolving and connecting
+/// took.
+///
+/// Backends are local servers or reachable through the default route, the outbound socket
+/// options, e.g. `interface_name`, don't apply.
+async fn dial(backend: &str) -> io::Result<(TcpStream, Dialed)> {
+    let started = Instant::now();
+    let addrs = tokio::net::lookup_host(backend).await?.collect();
+    let resolved = Instant::now();
+    let stream = DIAL_CACHE
+        .get_or_init(DialCache::default)
+        .dial(INBOUND_TAG, backend, addrs, &ConnectOpts::default())
+        .await?;
+    let dialed = Dialed::new(stream.peer_addr()?, resolved - started, resolved.elapsed());
+    Ok((stream, dialed))
+}
+
+/// Copies both directions until both sides closed, returns which closed first and the bytes sent
+/// by the client and by the remote. The remote may be a stream through a proxy.
+pub(crate) async fn relay<R>(client: &mut TcpStream, remote: &mut R) -> (io::Result<CloseReason>, u64, u64)
+where
+    R: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut upload, mut download) = (0, 0);
+    let result = {
+        let (mut client_read, mut client_write) = client.split();
+        let (mut remote_read, mut remote_write) = tokio::io::split(remote);
+        let client_to_remote = copy_half(
+            &mut client_read,
+            &mut remote_write,
+            &mut upload,
+            TrafficCounter::add_upload,
+        );
+        let remote_to_client = copy_half(
+            &mut remote_read,
+            &mut client_write,
+            &mut download,
+            TrafficCounter::add_download,
+        );
+        tokio::pin!(client_to_remote, remote_to_client);
+
+        let (reason, result) = tokio::select! {
+            result = &mut client_to_remote => (CloseReason::ClientEof, result),
+            result = &mut remote_to_client => (CloseReason::ServerEof, result),
+        };
+        match (result, reason) {
+            (Ok(()), CloseReason::ClientEof) => remote_to_client.await.map(|_| reason),
+            (Ok(()), _) => client_to_remote.await.map(|_| reason),
+            (Err(err), _) => Err(err),
+        }
+    };
+    (result, upload, download)
+}
+
+/// Copies until EOF, counting the bytes in `copied` and with `count` in the total traffic.
+async fn copy_half<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    copied: &mut u64,
+    count: fn(&TrafficCounter, u64),
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; RELAY_BUFFER_LEN];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return writer.shutdown().await;
+        }
+        writer.write_all(&buf[..n]).await?;
+        *copied += n as u64;
+        count(traffic::total(), n as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes(routes: &[(&str, &str)]) -> Result<SniRoutes, String> {
+        SniRoutes::new(
+            &routes
+                .iter()
+                .map(|(pattern, backend)| (pattern.to_string(), backend.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_sni_routes() {
+        let sni_routes = routes(&[
+            ("trojan.example.com", "127.0.0.1:8443"),
+            ("*.example.com", "127.0.0.1:9443"),
+            ("*.cdn.example.com.", "cdn.internal:443"),
+        ])
+        .unwrap();
+
+        let backend = |name| sni_routes.route(name).map(|(_, backend)| backend);
+        assert_eq!(backend(Some("Trojan.Example.com")), Some("127.0.0.1:8443"));
+        assert_eq!(backend(Some("www.example.com")), Some("127.0.0.1:9443"));
+        assert_eq!(backend(Some("img.cdn.example.com")), Some("cdn.internal:443"));
+        assert_eq!(
+            sni_routes.route(Some("a.cdn.example.com")).unwrap().0,
+            "*.cdn.example.com"
+        );
+        assert_eq!(backend(Some("example.com")), None);
+        assert_eq!(backend(None), None);
+        assert_eq!(
+            sni_routes.rules(),
+            ["trojan.example.com", "*.cdn.example.com", "*.example.com"]
+        );
+
+        let sni_routes = routes(&[("*", "127.0.0.1:10443")]).unwrap();
+        assert_eq!(sni_routes.route(None), Some(("*".to_owned(), "127.0.0.1:10443")));
+
+        assert!(routes(&[]).unwrap().is_empty());
+        assert!(routes(&[("www.*.com", "127.0.0.1:443")]).is_err());
+        assert!(routes(&[("example.com", "127.0.0.1")]).is_err());
+        assert!(routes(&[("example.com", ":443")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sni_proxy_forward() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let sni_routes = routes(&[("*", &backend_addr.to_string())]).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(ConnectionHistory::new(4));
+        let decisions_path = std::env::temp_dir().join(format!("swiftlink-sni-decisions-{}", std::process::id()));
+        _ = std::fs::remove_file(&decisions_path);
+        let decisions = Arc::new(DecisionLog::open(&decisions_path).unwrap());
+        let events = Arc::new(EventBus::default());
+        let mut events_rx = events.subscribe();
+        let hits = Arc::new(RuleHits::default());
+        hits.set_rules(["*"]);
+        let task = tokio::spawn(serve(
+            listener,
+            Arc::new(sni_routes),
+            connections.clone(),
+            events,
+            hits.clone(),
+            Some(decisions),
+        ));
+
+        // a ClientHello without extensions, the backend sees it unchanged
+        let mut hello = vec![0x16, 0x03, 0x01, 0x00, 0x2d, 0x01, 0x00, 0x00, 0x29, 0x03, 0x03];
+        hello.extend_from_slice(&[0x5a; 32]);
+        hello.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&hello).await.unwrap();
+        let (mut server, _) = backend.accept().await.unwrap();
+        let mut received = vec![0u8; hello.len()];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, hello);
+
+        server.write_all(b"server hello").await.unwrap();
+        drop(server);
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"server hello");
+        drop(client);
+
+        while connections.recent().is_empty() {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        let conn = &connections.recent()[0];
+        assert_eq!(conn.reason, CloseReason::ServerEof);
+        assert_eq!(conn.rule.as_deref(), Some("*"));
+        assert_eq!(conn.dialed.map(|dialed| dialed.remote), Some(backend_addr));
+        assert_eq!(hits.snapshot()[0].hits, 1);
+        assert_eq!((conn.upload, conn.download), (hello.len() as u64, 12));
+        assert!(matches!(
+            events_rx.recv().await.unwrap(),
+            Event::ConnectionOpened(opened) if opened.id == conn.id && opened.outbound == backend_addr.to_string()
+        ));
+        assert!(matches!(
+            events_rx.recv().await.unwrap(),
+            Event::ConnectionClosed(closed) if closed.reason == CloseReason::ServerEof
+        ));
+
+        let recorded = crate::decisions::read(&decisions_path).unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].server_name, None);
+        assert_eq!(recorded[0].outbound, Some(backend_addr.to_string()));
+        std::fs::remove_file(&decisions_path).unwrap();
+
+        // not TLS
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(client.read(&mut [0u8; 16]).await.unwrap(), 0);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_reply_busy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut hello = vec![0x16, 0x03, 0x01, 0x00, 0x2d, 0x01, 0x00, 0x00, 0x29, 0x03, 0x03];
+        hello.extend_from_slice(&[0x5a; 32]);
+        hello.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&hello).await.unwrap();
+
+        let (stream, _) = listener.accept().await.unwrap();
+        reply_busy(stream, BUSY_REPLIES.try_acquire().unwrap()).await;
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, sni::fatal_alert(sni::ALERT_INTERNAL_ERROR));
+
+        // not TLS, closed without an answer
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        reply_busy(stream, BUSY_REPLIES.try_acquire().unwrap()).await;
+        assert_eq!(client.read(&mut [0u8; 16]).await.unwrap(), 0);
+    }
+}
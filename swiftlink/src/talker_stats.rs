@@ -0,0 +1,129 @@
+//! `swiftlink stats top`, the destinations and clients with the most traffic.
+//!
+//! The totals are read from `/stats/top` of the external controller, which must be enabled. They
+//! cover the connections closed in the window, what eats the bandwidth right now shows up once its
+//! connections close.
+
+use std::fmt;
+
+use byte_unit::Byte;
+use swiftlink_infra::talkers::{Talker, TopTalkers};
+
+use crate::{controller, Config};
+
+/// Reads the `top` destinations and clients of the last `window_secs` from the external
+/// controller of `config`.
+pub fn fetch(config: &Config, window_secs: u64, top: usize) -> anyhow::Result<TopTalkers> {
+    controller::get(config, &format!("/stats/top?window={}&limit={}", window_secs, top))
+}
+
+/// The top talkers as two tables.
+pub struct TopReport<'a>(pub &'a TopTalkers);
+
+impl fmt::Display for TopReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let top = self.0;
+        writeln!(f, "last {} minutes", top.window_secs / 60)?;
+        for (title, talkers) in [("destinations", &top.destinations), ("clients", &top.sources)] {
+            writeln!(f, "\n{} ({}):", title, talkers.len())?;
+            if talkers.is_empty() {
+                writeln!(f, "  none")?;
+            }
+            for talker in talkers.iter() {
+                writeln!(f, "  {}", Row(talker))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Row<'a>(&'a Talker);
+
+impl fmt::Display for Row<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let talker = self.0;
+        write!(
+            f,
+            "{:>12} {:>12} {:>6}  {}",
+            format!("↑ {}", human(talker.upload)),
+            format!("↓ {}", human(talker.download)),
+            talker.connections,
+            talker.key
+        )
+    }
+}
+
+fn human(bytes: u64) -> String {
+    Byte::from_bytes(bytes).get_appropriate_unit(true).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use swiftlink_infra::{
+        connection::{CloseReason, ClosedConnection},
+        talkers::TalkerStats,
+    };
+
+    use crate::{api::Api, config::ControllerCors, controller::TestController};
+
+    use super::*;
+
+    #[test]
+    fn test_top_report() {
+        let top = TopTalkers {
+            window_secs: 600,
+            destinations: vec![Talker {
+                key: "video.example.com".to_owned(),
+                connections: 2,
+                upload: 1024,
+                download: 3 * 1024 * 1024,
+            }],
+            sources: vec![],
+        };
+        assert_eq!(
+            TopReport(&top).to_string(),
+            "last 10 minutes\n\
+             \n\
+             destinations (1):\n      ↑ 1024 B   ↓ 3.00 MiB      2  video.example.com\n\
+             \n\
+             clients (0):\n  none\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let talkers = Arc::new(TalkerStats::default());
+        talkers.record(&ClosedConnection {
+            id: 1,
+            network: "tcp",
+            inbound: "http".to_owned(),
+            source: "192.168.1.2:50000".parse().unwrap(),
+            destination: "www.example.com:443".to_owned(),
+            rule: None,
+            outbound: Some("direct".to_owned()),
+            dialed: None,
+            upload: 100,
+            download: 1000,
+            started_at: 0,
+            duration_ms: 10,
+            reason: CloseReason::ServerEof,
+            error: None,
+        });
+        let controller = TestController::start(Api::new(None, ControllerCors::default()).with_talkers(talkers)).await;
+
+        let top = controller
+            .request(None, |config| {
+                let err = fetch(config, 0, 10).unwrap_err();
+                assert!(err.to_string().contains("400"), "{:?}", err);
+                fetch(config, 300, 10)
+            })
+            .await
+            .unwrap();
+        assert_eq!(top.window_secs, 300);
+        assert_eq!(top.destinations[0].key, "www.example.com");
+        assert_eq!(top.sources[0].key, "192.168.1.2");
+        assert_eq!(top.sources[0].bytes(), 1100);
+    }
+}